@@ -1,11 +1,128 @@
+use rosenpass_sodium::aead::{aes256gcm, chacha20poly1305_ietf, xchacha20poly1305_ietf};
+
+/// FIPS-constrained mode: AES-256-GCM in place of (X)ChaCha20-Poly1305 and
+/// HMAC-SHA256 in place of the BLAKE2b based HMAC
+///
+/// The mode is process wide and has to be selected before any of the
+/// primitives are used; it can not be changed afterwards.
+pub mod fips {
+    use std::sync::OnceLock;
+
+    static MODE: OnceLock<bool> = OnceLock::new();
+
+    /// Enter FIPS-constrained mode or stay out of it for good
+    pub fn select(enable: bool) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            !enable || super::aes256gcm::is_available(),
+            "fips mode needs AES-256-GCM, which this CPU does not support"
+        );
+        let mode = *MODE.get_or_init(|| enable);
+        anyhow::ensure!(
+            mode == enable,
+            "fips mode is {} for the whole process already",
+            if mode { "on" } else { "off" }
+        );
+        Ok(())
+    }
+
+    #[inline]
+    pub fn enabled() -> bool {
+        MODE.get().copied().unwrap_or(false)
+    }
+}
+
 pub mod aead {
-    pub use rosenpass_sodium::aead::chacha20poly1305_ietf::{
-        decrypt, encrypt, KEY_LEN, NONCE_LEN, TAG_LEN,
-    };
+    use super::{aes256gcm, chacha20poly1305_ietf as chacha, fips};
+
+    pub use chacha::{KEY_LEN, NONCE_LEN, TAG_LEN};
+
+    // the message formats stay the same in FIPS-constrained mode
+    const _: () = assert!(
+        KEY_LEN == aes256gcm::KEY_LEN
+            && NONCE_LEN == aes256gcm::NONCE_LEN
+            && TAG_LEN == aes256gcm::TAG_LEN
+    );
+
+    #[inline]
+    pub fn encrypt(
+        ciphertext: &mut [u8],
+        key: &[u8],
+        nonce: &[u8],
+        ad: &[u8],
+        plaintext: &[u8],
+    ) -> anyhow::Result<()> {
+        match fips::enabled() {
+            true => aes256gcm::encrypt(ciphertext, key, nonce, ad, plaintext),
+            false => chacha::encrypt(ciphertext, key, nonce, ad, plaintext),
+        }
+    }
+
+    #[inline]
+    pub fn decrypt(
+        plaintext: &mut [u8],
+        key: &[u8],
+        nonce: &[u8],
+        ad: &[u8],
+        ciphertext: &[u8],
+    ) -> anyhow::Result<()> {
+        match fips::enabled() {
+            true => aes256gcm::decrypt(plaintext, key, nonce, ad, ciphertext),
+            false => chacha::decrypt(plaintext, key, nonce, ad, ciphertext),
+        }
+    }
 }
 
+/// AEAD with random nonces; the nonce is put in front of the ciphertext
+///
+/// In FIPS-constrained mode, the last [aes256gcm::NONCE_LEN] bytes of the
+/// nonce serve as the nonce of AES-256-GCM and the others are authenticated
+/// along with the additional data, which keeps the message formats unchanged.
+/// Callers draw all of the nonce at random, so the 96 bits used by
+/// AES-256-GCM are just as unlikely to repeat as with any random nonce of
+/// that size.
 pub mod xaead {
-    pub use rosenpass_sodium::aead::xchacha20poly1305_ietf::{
-        decrypt, encrypt, KEY_LEN, NONCE_LEN, TAG_LEN,
-    };
+    use super::{aes256gcm, fips, xchacha20poly1305_ietf as xchacha};
+
+    pub use xchacha::{KEY_LEN, NONCE_LEN, TAG_LEN};
+
+    const _: () = assert!(KEY_LEN == aes256gcm::KEY_LEN && TAG_LEN == aes256gcm::TAG_LEN);
+
+    #[inline]
+    pub fn encrypt(
+        ciphertext: &mut [u8],
+        key: &[u8],
+        nonce: &[u8],
+        ad: &[u8],
+        plaintext: &[u8],
+    ) -> anyhow::Result<()> {
+        if !fips::enabled() {
+            return xchacha::encrypt(ciphertext, key, nonce, ad, plaintext);
+        }
+        assert!(ciphertext.len() == plaintext.len() + NONCE_LEN + TAG_LEN);
+        let (n, ct) = ciphertext.split_at_mut(NONCE_LEN);
+        n.copy_from_slice(nonce);
+        let (rest, gcm_nonce) = split_nonce(nonce);
+        aes256gcm::encrypt(ct, key, gcm_nonce, &[ad, rest].concat(), plaintext)
+    }
+
+    #[inline]
+    pub fn decrypt(
+        plaintext: &mut [u8],
+        key: &[u8],
+        ad: &[u8],
+        ciphertext: &[u8],
+    ) -> anyhow::Result<()> {
+        if !fips::enabled() {
+            return xchacha::decrypt(plaintext, key, ad, ciphertext);
+        }
+        assert!(ciphertext.len() == plaintext.len() + NONCE_LEN + TAG_LEN);
+        let (n, ct) = ciphertext.split_at(NONCE_LEN);
+        let (rest, gcm_nonce) = split_nonce(n);
+        aes256gcm::decrypt(plaintext, key, gcm_nonce, &[ad, rest].concat(), ct)
+    }
+
+    /// The bytes of `nonce` AES-256-GCM does not take, and those it does
+    pub fn split_nonce(nonce: &[u8]) -> (&[u8], &[u8]) {
+        nonce.split_at(NONCE_LEN - aes256gcm::NONCE_LEN)
+    }
 }
@@ -16,3 +16,123 @@ pub fn xor_into(a: &mut [u8], b: &[u8]) {
         *av ^= *bv;
     }
 }
+
+/// Whether a and b hold the same bytes, taking time that depends only on
+/// their lengths.
+///
+/// # Examples
+///
+/// ```
+/// use rosenpass_constant_time::memeq;
+/// assert!(memeq(b"hello", b"hello"));
+/// assert!(!memeq(b"hello", b"hella"));
+/// assert!(!memeq(b"hello", b"hell"));
+/// ```
+#[inline]
+pub fn memeq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let diff = a
+        .iter()
+        .zip(b.iter())
+        .fold(0u8, |acc, (av, bv)| std::hint::black_box(acc | (*av ^ *bv)));
+    diff == 0
+}
+
+/// Whether an authentication tag received as `tag` matches `expected`, of
+/// which only the first `tag.len()` bytes are sent; false for empty tags
+/// and for tags longer than `expected`.
+///
+/// # Examples
+///
+/// ```
+/// use rosenpass_constant_time::tag_eq;
+/// let expected = [7u8; 32];
+/// assert!(tag_eq(&[7u8; 16], &expected));
+/// assert!(!tag_eq(&[8u8; 16], &expected));
+/// assert!(!tag_eq(&[7u8; 33], &expected));
+/// assert!(!tag_eq(&[], &expected));
+/// ```
+#[inline]
+pub fn tag_eq(tag: &[u8], expected: &[u8]) -> bool {
+    !tag.is_empty() && tag.len() <= expected.len() && memeq(tag, &expected[..tag.len()])
+}
+
+/// Compares a and b as little endian numbers; returns 1 if a is greater, 0
+/// if they are equal and -1 if b is greater, like libsodium's
+/// `sodium_compare`, in time that depends only on their length.
+///
+/// # Examples
+///
+/// ```
+/// use rosenpass_constant_time::compare;
+/// assert_eq!(compare(&[2, 1], &[1, 1]), 1);
+/// assert_eq!(compare(&[2, 1], &[1, 2]), -1);
+/// assert_eq!(compare(&[1, 2], &[1, 2]), 0);
+/// ```
+#[inline]
+pub fn compare(a: &[u8], b: &[u8]) -> i32 {
+    assert!(a.len() == b.len());
+    let (mut gt, mut eq) = (0u32, 1u32);
+    for (av, bv) in a.iter().zip(b.iter()).rev() {
+        let (x, y) = (*av as u32, *bv as u32);
+        // y - x wraps iff x > y, and (x ^ y) - 1 iff x == y
+        gt |= (y.wrapping_sub(x) >> 8) & eq;
+        eq &= (x ^ y).wrapping_sub(1) >> 8;
+        (gt, eq) = std::hint::black_box((gt, eq));
+    }
+    (gt + gt + eq) as i32 - 1
+}
+
+/// Overwrites a with b if `choice` is set, leaving it unchanged otherwise,
+/// taking the same time either way.
+///
+/// # Examples
+///
+/// ```
+/// use rosenpass_constant_time::select_into;
+/// let mut a = *b"hello";
+/// select_into(&mut a, b"world", false);
+/// assert_eq!(&a, b"hello");
+/// select_into(&mut a, b"world", true);
+/// assert_eq!(&a, b"world");
+/// ```
+#[inline]
+pub fn select_into(a: &mut [u8], b: &[u8], choice: bool) {
+    assert!(a.len() == b.len());
+    let mask = mask(choice);
+    for (av, bv) in a.iter_mut().zip(b.iter()) {
+        *av ^= (*av ^ *bv) & mask;
+    }
+}
+
+/// Swaps the contents of a and b if `choice` is set, taking the same time
+/// either way.
+///
+/// # Examples
+///
+/// ```
+/// use rosenpass_constant_time::swap;
+/// let (mut a, mut b) = (*b"hello", *b"world");
+/// swap(&mut a, &mut b, true);
+/// assert_eq!((&a, &b), (b"world", b"hello"));
+/// swap(&mut a, &mut b, false);
+/// assert_eq!((&a, &b), (b"world", b"hello"));
+/// ```
+#[inline]
+pub fn swap(a: &mut [u8], b: &mut [u8], choice: bool) {
+    assert!(a.len() == b.len());
+    let mask = mask(choice);
+    for (av, bv) in a.iter_mut().zip(b.iter_mut()) {
+        let t = (*av ^ *bv) & mask;
+        *av ^= t;
+        *bv ^= t;
+    }
+}
+
+/// All ones if `choice` is set, all zeros otherwise
+#[inline]
+fn mask(choice: bool) -> u8 {
+    std::hint::black_box(0u8.wrapping_sub(choice as u8))
+}
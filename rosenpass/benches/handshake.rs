@@ -18,6 +18,7 @@ fn handle(
     let HandleMsgResult {
         exchanged_with: xch,
         resp,
+        ..
     } = rx.handle_msg(&msgb[..msgl], &mut **resb)?;
     assert!(matches!(xch, None | Some(PeerPtr(0))));
 
@@ -0,0 +1,85 @@
+//! Configuration files encrypted with age
+//!
+//! The peers and key paths in a config file tell a lot about a network, so
+//! on shared hosts the config file can be kept encrypted with
+//! [age](https://age-encryption.org):
+//!
+//! ```text
+//! age -r age1… -o rosenpass.toml.age rosenpass.toml
+//! ROSENPASS_AGE_IDENTITY=/root/age-key.txt rosenpass exchange-config rosenpass.toml.age
+//! ```
+//!
+//! Config files starting like the output of age, binary or armored, are
+//! decrypted with `age --decrypt` and the identity file named by
+//! [IDENTITY_ENV], then parsed in memory; the plain text is never written to
+//! disk and is erased once parsed. Identities of age plugins, keeping the key
+//! in a TPM, a YubiKey or a cloud KMS, work just the same as long as the
+//! plugin is installed. Encrypted configs are never written back, since that
+//! would store them in plain text.
+
+use anyhow::{ensure, Context, Result};
+use std::{
+    io::Write,
+    path::Path,
+    process::{Command, Stdio},
+    thread,
+};
+
+/// Environment variable naming the identity file encrypted configs are
+/// decrypted with
+pub const IDENTITY_ENV: &str = "ROSENPASS_AGE_IDENTITY";
+
+/// How the binary and the armored format of age start
+const HEADERS: [&[u8]; 2] = [
+    b"age-encryption.org/v1\n",
+    b"-----BEGIN AGE ENCRYPTED FILE-----",
+];
+
+/// Whether `data` looks like it was encrypted with age
+pub fn is_encrypted(data: &[u8]) -> bool {
+    HEADERS.iter().any(|h| data.starts_with(h))
+}
+
+/// Decrypt `data`, read from `path`; the caller is to erase the plain text
+pub fn decrypt(path: &Path, data: &[u8]) -> Result<Vec<u8>> {
+    let identity = std::env::var_os(IDENTITY_ENV).with_context(|| {
+        format!("{path:?} is encrypted with age; set {IDENTITY_ENV} to the identity file")
+    })?;
+    let mut child = Command::new("age")
+        .arg("--decrypt")
+        .arg("--identity")
+        .arg(&identity)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("could not run age")?;
+    // age may start writing before it read everything
+    let mut stdin = child.stdin.take().unwrap();
+    let data = data.to_vec();
+    let writer = thread::spawn(move || stdin.write_all(&data));
+    let out = child.wait_with_output()?;
+    let written = writer.join().unwrap();
+    ensure!(
+        out.status.success(),
+        "could not decrypt {path:?}: {}",
+        String::from_utf8_lossy(&out.stderr).trim()
+    );
+    written?;
+    Ok(out.stdout)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn detects_age_files() {
+        assert!(is_encrypted(b"age-encryption.org/v1\n-> X25519 abc\n"));
+        assert!(is_encrypted(
+            b"-----BEGIN AGE ENCRYPTED FILE-----\nYWdlLWVuY3J5cHRpb24ub3JnL3YxCi0+\n"
+        ));
+        assert!(!is_encrypted(b"public_key = \"rp-public-key\"\n"));
+        assert!(!is_encrypted(b"age-encryption.org/v2\n"));
+    }
+}
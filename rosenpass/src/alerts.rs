@@ -0,0 +1,312 @@
+//! Security events sent to webhooks
+//!
+//! Small deployments get alerted about trouble without running a metrics
+//! stack: with `[alerts]` configured, the following events are POSTed as JSON
+//! to each of the configured webhooks, or to those listing them in `events`:
+//!
+//! - `handshake-failures`: handshakes with a peer failed
+//!   [Alerts::failure_streak] times in a row, sent once per streak
+//! - `broken-biscuit`: an InitConf carried a biscuit we could not decrypt; as
+//!   these come from anywhere, they are sent at most once per
+//!   [BROKEN_BISCUIT_INTERVAL] with the number of failures since the last
+//! - `key-rotated`: a new key was exchanged with a peer
+//! - `peer-dead` and `peer-alive`, see [crate::liveness]
+//! - `peer-unreachable` and `peer-reachable`, see [crate::breaker]
+//!
+//! ```json
+//! {"event":"peer-dead","time":1760432000,"peer":"…","peer_id":"…","name":"lab-1","tags":["lab"]}
+//! ```
+//!
+//! Webhooks with a `secret_file` get the hex encoded HMAC-SHA256 of the body
+//! under that secret in the `X-Rosenpass-Signature: sha256=…` header; the
+//! time in the body lets receivers reject replayed requests. Requests are
+//! sent one after the other from a background thread, using `curl`, and
+//! retried [RETRIES] times with growing pauses while the hook fails.
+
+use anyhow::{ensure, Context, Result};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::{
+    io::Write,
+    path::PathBuf,
+    process::{Command, Stdio},
+    sync::mpsc::{self, SyncSender, TrySendError},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{protocol::Timing, sodium};
+
+/// Default of [Alerts::failure_streak]
+pub const DEFAULT_FAILURE_STREAK: u32 = 5;
+
+/// Seconds between two `broken-biscuit` alerts, at least
+pub const BROKEN_BISCUIT_INTERVAL: Timing = 60.0;
+
+/// How often a failed request is repeated
+pub const RETRIES: u32 = 4;
+
+/// Requests waiting to be sent; more are dropped
+const QUEUE_LEN: usize = 256;
+
+/// Settings of the webhooks; no alerts are sent without them
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Alerts {
+    /// Failed handshakes in a row with a peer before it is reported;
+    /// defaults to [DEFAULT_FAILURE_STREAK]
+    #[serde(default)]
+    pub failure_streak: Option<u32>,
+
+    pub webhooks: Vec<Webhook>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Webhook {
+    pub url: String,
+
+    /// File holding the secret requests are signed with
+    #[serde(default)]
+    pub secret_file: Option<PathBuf>,
+
+    /// The events to send; all of them if empty
+    #[serde(default)]
+    pub events: Vec<AlertKind>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AlertKind {
+    HandshakeFailures,
+    BrokenBiscuit,
+    KeyRotated,
+    PeerDead,
+    PeerAlive,
+    PeerUnreachable,
+    PeerReachable,
+}
+
+/// The body of a webhook request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Alert {
+    pub event: AlertKind,
+    /// Seconds since the Unix epoch
+    pub time: u64,
+    /// Fingerprint of the peer concerned, if known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peer: Option<String>,
+    /// Base64 encoded id of the peer concerned, if known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peer_id: Option<String>,
+    /// Name of the peer concerned, if it has one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// How often the event happened, for events counted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub count: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl Alerts {
+    pub fn validate(&self) -> Result<()> {
+        ensure!(
+            self.failure_streak != Some(0),
+            "alerts.failure_streak must be at least 1"
+        );
+        for hook in self.webhooks.iter() {
+            ensure!(
+                hook.url.starts_with("http://") || hook.url.starts_with("https://"),
+                "webhook URL {:?} is not an HTTP URL",
+                hook.url
+            );
+            if let Some(f) = hook.secret_file.as_ref() {
+                ensure!(f.is_file(), "webhook secret file {f:?} does not exist");
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Alert {
+    pub fn new(event: AlertKind) -> Self {
+        Self {
+            event,
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            peer: None,
+            peer_id: None,
+            name: None,
+            tags: Vec::new(),
+            count: None,
+            detail: None,
+        }
+    }
+}
+
+struct Hook {
+    url: String,
+    secret: Option<Vec<u8>>,
+    events: Vec<AlertKind>,
+}
+
+/// Sends [Alert]s to the webhooks
+#[derive(Debug)]
+pub struct Alerter {
+    pub failure_streak: u32,
+    /// Broken biscuits not reported yet
+    pub broken_biscuits: u64,
+    /// When the next `broken-biscuit` alert may be sent
+    pub broken_biscuit_at: Timing,
+    queue: SyncSender<Alert>,
+}
+
+impl Alerter {
+    /// Read the secrets and start the thread sending the requests
+    pub fn start(cfg: &Alerts) -> Result<Self> {
+        let mut hooks = Vec::new();
+        for hook in cfg.webhooks.iter() {
+            let secret = match hook.secret_file.as_ref() {
+                Some(f) => {
+                    let s = std::fs::read(f)
+                        .with_context(|| format!("could not read webhook secret {f:?}"))?;
+                    Some(s.trim_ascii_end().to_vec())
+                }
+                None => None,
+            };
+            hooks.push(Hook {
+                url: hook.url.clone(),
+                secret,
+                events: hook.events.clone(),
+            });
+        }
+        let (queue, rx) = mpsc::sync_channel::<Alert>(QUEUE_LEN);
+        thread::Builder::new()
+            .name("alerts".into())
+            .spawn(move || {
+                for alert in rx {
+                    for hook in hooks.iter() {
+                        if hook.events.is_empty() || hook.events.contains(&alert.event) {
+                            hook.deliver(&alert);
+                        }
+                    }
+                }
+            })?;
+        Ok(Self {
+            failure_streak: cfg.failure_streak.unwrap_or(DEFAULT_FAILURE_STREAK),
+            broken_biscuits: 0,
+            broken_biscuit_at: 0.0,
+            queue,
+        })
+    }
+
+    /// Have `alert` sent in the background
+    pub fn send(&self, alert: Alert) {
+        match self.queue.try_send(alert) {
+            Ok(()) => {}
+            Err(TrySendError::Full(alert)) => {
+                warn!("dropping {:?} alert, the webhooks are behind", alert.event)
+            }
+            Err(TrySendError::Disconnected(_)) => warn!("the alert thread stopped"),
+        }
+    }
+
+    /// Count a broken biscuit at `now`; returns the broken biscuits to report
+    /// if an alert is due
+    pub fn broken_biscuit(&mut self, now: Timing) -> Option<u64> {
+        self.broken_biscuits += 1;
+        if now < self.broken_biscuit_at {
+            return None;
+        }
+        self.broken_biscuit_at = now + BROKEN_BISCUIT_INTERVAL;
+        Some(std::mem::take(&mut self.broken_biscuits))
+    }
+}
+
+impl Hook {
+    /// POST `alert`, retrying while it fails
+    fn deliver(&self, alert: &Alert) {
+        let body = match serde_json::to_vec(alert) {
+            Ok(body) => body,
+            Err(e) => return warn!("could not encode alert: {e}"),
+        };
+        let mut pause = Duration::from_secs(1);
+        for attempt in 0..=RETRIES {
+            match self.post(&body) {
+                Ok(()) => return,
+                Err(e) if attempt == RETRIES => {
+                    warn!("giving up on webhook {}: {e:#}", self.url)
+                }
+                Err(e) => {
+                    warn!("webhook {} failed, retrying in {pause:?}: {e:#}", self.url);
+                    thread::sleep(pause);
+                    pause *= 2;
+                }
+            }
+        }
+    }
+
+    fn post(&self, body: &[u8]) -> Result<()> {
+        let mut cmd = Command::new("curl");
+        cmd.args(["-fsS", "-m", "10", "-H", "Content-Type: application/json"]);
+        if let Some(secret) = self.secret.as_ref() {
+            cmd.args([
+                "-H",
+                &format!("X-Rosenpass-Signature: {}", sign(secret, body)?),
+            ]);
+        }
+        let mut child = cmd
+            .args(["--data-binary", "@-", &self.url])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn()
+            .context("could not run curl")?;
+        child.stdin.take().unwrap().write_all(body)?;
+        let status = child.wait()?;
+        ensure!(status.success(), "curl failed with {status}");
+        Ok(())
+    }
+}
+
+/// The signature header value of `body`
+fn sign(secret: &[u8], body: &[u8]) -> Result<String> {
+    let mac = sodium::hmac_sha256(secret, body)?;
+    let hex = mac.iter().map(|b| format!("{b:02x}")).collect::<String>();
+    Ok(format!("sha256={hex}"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn hmac_signature() {
+        rosenpass_sodium::init().unwrap();
+        // RFC 4231, test case 2
+        assert_eq!(
+            sign(b"Jefe", b"what do ya want for nothing?").unwrap(),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn broken_biscuits_are_rate_limited() {
+        let (queue, _rx) = mpsc::sync_channel(1);
+        let mut alerter = Alerter {
+            failure_streak: DEFAULT_FAILURE_STREAK,
+            broken_biscuits: 0,
+            broken_biscuit_at: 0.0,
+            queue,
+        };
+        assert_eq!(alerter.broken_biscuit(10.0), Some(1));
+        assert_eq!(alerter.broken_biscuit(11.0), None);
+        assert_eq!(alerter.broken_biscuit(12.0), None);
+        assert_eq!(
+            alerter.broken_biscuit(10.0 + BROKEN_BISCUIT_INTERVAL),
+            Some(3)
+        );
+    }
+}
@@ -0,0 +1,513 @@
+//! HTTP API for provisioning peers
+//!
+//! Self-service portals can create, change and delete peers of a running
+//! gateway over HTTP, rather than writing config files on it:
+//!
+//! ```toml
+//! [api]
+//! listen = "127.0.0.1:8484"
+//! token = "/etc/rosenpass/api-token"
+//! directory = "/var/lib/rosenpass/api"
+//! ```
+//!
+//! Every request carries the token from the `token` file as `Authorization:
+//! Bearer <token>`. The token grants as much as editing the config does, and
+//! the API speaks plain HTTP, so it should be bound to localhost or put
+//! behind a reverse proxy terminating TLS. Requests and replies are JSON:
+//!
+//! - `GET /peers` and `GET /peers/<fingerprint>` describe the peers just like
+//!   the `status` command of the control socket, and `GET /health` is its
+//!   `healthcheck`.
+//! - `POST /peers` adds a peer. The body is a JSON object of the settings of
+//!   a `[[peers]]` table, with the base64 encoded public key itself as its
+//!   `public_key`.
+//! - `PUT /peers/<fingerprint>` replaces the settings of a peer added through
+//!   the API; it is removed and added again, so it starts over with a
+//!   handshake. The `public_key` may be left out.
+//! - `DELETE /peers/<fingerprint>` removes a peer added through the API,
+//!   just like a revoked one.
+//!
+//! The peers are kept in the `directory`, as `<fingerprint>.pqpk` with the
+//! public key and `<fingerprint>.json` with the settings, and taken after the
+//! peers of the config file on every start. Changes are checked by loading
+//! the config again, just like a change to the [crate::peer_store]; so the
+//! API is only available with a config file, and a change which does not
+//! load is refused and leaves the peers as they are.
+//!
+//! Each connection is served on a thread of its own, which has to receive
+//! the request within [API_TIMEOUT], and whose lines are at most [MAX_LINE]
+//! bytes long; only then the thread exchanging keys answers it, see
+//! [crate::serve].
+
+use anyhow::{bail, ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, BufRead, BufReader, ErrorKind, Read, Write},
+    net::{SocketAddr, TcpStream},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use rosenpass_constant_time::memeq;
+use rosenpass_util::b64::b64_reader;
+
+use crate::{
+    app_server::AppPeerPtr,
+    config::RosenpassPeer,
+    fingerprint::Fingerprint,
+    pqkem::{StaticKEM, KEM},
+    serve::{self, Connections},
+};
+
+/// How long a client may take to send its request, or to receive the reply
+pub const API_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Longest request line or header line accepted
+pub const MAX_LINE: usize = 8192;
+
+/// Largest request body accepted, enough for a base64 encoded public key
+pub const MAX_BODY: usize = 1 << 20;
+
+/// Most header lines accepted in a request
+const MAX_HEADERS: usize = 64;
+
+/// Settings of the API; disabled without them
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Api {
+    pub listen: SocketAddr,
+    /// File with the token clients authenticate with
+    pub token: PathBuf,
+    /// Where the peers added through the API are kept
+    pub directory: PathBuf,
+}
+
+impl Api {
+    pub fn validate(&self) -> Result<()> {
+        ensure!(
+            self.token.is_file(),
+            "api token file {:?} does not exist",
+            self.token
+        );
+        Ok(())
+    }
+}
+
+/// What a request asks for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Route {
+    Health,
+    ListPeers,
+    GetPeer(Fingerprint),
+    CreatePeer,
+    UpdatePeer(Fingerprint),
+    DeletePeer(Fingerprint),
+}
+
+impl Route {
+    pub fn of(method: &str, path: &str) -> Result<Self, Response> {
+        let path = path.split('?').next().unwrap_or_default();
+        let segments = path.trim_end_matches('/').split('/').collect::<Vec<_>>();
+        let fingerprint = |s: &str| {
+            s.parse::<Fingerprint>()
+                .map_err(|e| Response::error(400, format!("{e:#}")))
+        };
+        Ok(match (method, segments.as_slice()) {
+            ("GET", ["", "health"]) => Self::Health,
+            ("GET", ["", "peers"]) => Self::ListPeers,
+            ("POST", ["", "peers"]) => Self::CreatePeer,
+            ("GET", ["", "peers", fp]) => Self::GetPeer(fingerprint(fp)?),
+            ("PUT", ["", "peers", fp]) => Self::UpdatePeer(fingerprint(fp)?),
+            ("DELETE", ["", "peers", fp]) => Self::DeletePeer(fingerprint(fp)?),
+            (_, ["", "health"] | ["", "peers"] | ["", "peers", _]) => {
+                return Err(Response::error(
+                    405,
+                    format!("{method} is not allowed here"),
+                ))
+            }
+            _ => return Err(Response::error(404, format!("no such resource {path:?}"))),
+        })
+    }
+}
+
+/// A request which passed authentication
+#[derive(Debug)]
+pub struct Request {
+    pub route: Route,
+    pub body: Vec<u8>,
+}
+
+/// The reply to a request
+#[derive(Debug, Clone, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub body: Option<Value>,
+}
+
+impl Response {
+    pub fn json<T: Serialize>(status: u16, body: &T) -> Self {
+        match serde_json::to_value(body) {
+            Ok(body) => Self {
+                status,
+                body: Some(body),
+            },
+            Err(e) => Self::error(500, e.to_string()),
+        }
+    }
+
+    pub fn empty(status: u16) -> Self {
+        Self { status, body: None }
+    }
+
+    pub fn error(status: u16, msg: impl Into<String>) -> Self {
+        Self::json(status, &serde_json::json!({ "error": msg.into() }))
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            201 => "Created",
+            204 => "No Content",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            409 => "Conflict",
+            411 => "Length Required",
+            413 => "Payload Too Large",
+            _ => "Internal Server Error",
+        }
+    }
+
+    pub fn write_to<W: Write>(&self, mut w: W) -> io::Result<()> {
+        let body = self
+            .body
+            .as_ref()
+            .map(|b| format!("{b}\n"))
+            .unwrap_or_default();
+        write!(w, "HTTP/1.1 {} {}\r\n", self.status, self.reason())?;
+        if self.status == 401 {
+            write!(w, "WWW-Authenticate: Bearer\r\n")?;
+        }
+        if self.body.is_some() {
+            write!(w, "Content-Type: application/json\r\n")?;
+        }
+        write!(
+            w,
+            "Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        )?;
+        w.flush()
+    }
+}
+
+/// The settings of a peer, the keys of a `[[peers]]` table
+pub type Settings = Map<String, Value>;
+
+/// Loads the config again and returns the peers of one kind, like those
+/// added through the API
+pub type Reload = Box<dyn FnMut() -> Result<Vec<RosenpassPeer>> + Send>;
+
+/// The listening side of the API, driven by [crate::app_server::AppServer]
+pub struct ApiServer {
+    pub listener: mio::net::TcpListener,
+    token: Arc<[u8]>,
+    /// The clients being served
+    pub connections: Connections<Request, Response>,
+    pub directory: PathBuf,
+    /// The peers added through the API
+    pub peers: HashMap<Fingerprint, AppPeerPtr>,
+    pub reload: Reload,
+}
+
+impl std::fmt::Debug for ApiServer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ApiServer")
+            .field("listener", &self.listener)
+            .field("directory", &self.directory)
+            .field("peers", &self.peers)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ApiServer {
+    /// Listen as configured by `cfg`; `waker` wakes the event loop once a
+    /// request was read
+    pub fn bind(cfg: &Api, reload: Reload, waker: Arc<mio::Waker>) -> Result<Self> {
+        let token = fs::read_to_string(&cfg.token)
+            .with_context(|| format!("could not read api token {:?}", cfg.token))?;
+        let token = token.trim().as_bytes().to_vec();
+        ensure!(!token.is_empty(), "api token {:?} is empty", cfg.token);
+        fs::create_dir_all(&cfg.directory)
+            .with_context(|| format!("could not create api directory {:?}", cfg.directory))?;
+        let listener = mio::net::TcpListener::bind(cfg.listen)
+            .with_context(|| format!("could not listen for api requests on {}", cfg.listen))?;
+        Ok(Self {
+            listener,
+            token: token.into(),
+            connections: Connections::new("api request", API_TIMEOUT, waker),
+            directory: cfg.directory.clone(),
+            peers: HashMap::new(),
+            reload,
+        })
+    }
+
+    /// Accept a pending connection, if any; as blocking with [API_TIMEOUT]
+    pub fn accept(&self) -> Result<Option<TcpStream>> {
+        serve::accept(&self.listener, API_TIMEOUT)
+    }
+
+    /// Serve the client of `stream` off the event loop; its request is
+    /// answered through [Connections::try_next]
+    pub fn serve(&self, stream: TcpStream) {
+        let token = self.token.clone();
+        self.connections.serve(stream, move |stream, event_loop| {
+            let res = match read_request(&mut *stream, &token) {
+                Ok(req) => event_loop
+                    .ask(req)
+                    .unwrap_or_else(|| Response::error(500, "the server is shutting down")),
+                Err(res) => res,
+            };
+            stream.renew();
+            res.write_to(stream)
+                .context("could not answer the api request")
+        });
+    }
+
+    /// The files a peer is kept in: its settings and its public key
+    pub fn files(&self, fp: &Fingerprint) -> (PathBuf, PathBuf) {
+        files(&self.directory, fp)
+    }
+}
+
+/// Read a line of at most [MAX_LINE] bytes into `line`
+fn read_line<R: BufRead>(r: &mut R, line: &mut String) -> io::Result<()> {
+    line.clear();
+    let len = r.by_ref().take(MAX_LINE as u64).read_line(line)?;
+    match len == MAX_LINE && !line.ends_with('\n') {
+        true => Err(io::Error::new(ErrorKind::InvalidData, "line is too long")),
+        false => Ok(()),
+    }
+}
+
+/// Read the request sent by a client, and check it carries `token`
+pub fn read_request<R: Read>(stream: R, token: &[u8]) -> Result<Request, Response> {
+    let bad = |msg: &str| Response::error(400, msg);
+    let mut r = BufReader::new(stream);
+    let mut line = String::new();
+    read_line(&mut r, &mut line).map_err(|_| bad("could not read the request"))?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(path), Some(_version)) = (parts.next(), parts.next(), parts.next())
+    else {
+        return Err(bad("malformed request line"));
+    };
+    let (method, path) = (method.to_string(), path.to_string());
+
+    let (mut authorized, mut len) = (false, None);
+    for _ in 0..MAX_HEADERS {
+        read_line(&mut r, &mut line).map_err(|_| bad("could not read the headers"))?;
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let Some((name, value)) = header.split_once(':') else {
+            return Err(bad("malformed header"));
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("authorization") {
+            // only the length may leak
+            authorized = value
+                .strip_prefix("Bearer ")
+                .is_some_and(|t| memeq(t.trim().as_bytes(), token));
+        } else if name.eq_ignore_ascii_case("content-length") {
+            len = Some(
+                value
+                    .parse::<usize>()
+                    .map_err(|_| bad("bad Content-Length"))?,
+            );
+        }
+    }
+    if !line.trim_end().is_empty() {
+        return Err(bad("too many headers"));
+    }
+    // only authenticated clients learn which resources there are
+    if !authorized {
+        return Err(Response::error(401, "missing or wrong token"));
+    }
+    let route = Route::of(&method, &path)?;
+
+    let mut body = vec![];
+    match (route, len) {
+        (Route::CreatePeer | Route::UpdatePeer(_), None) => {
+            return Err(Response::error(411, "the request needs a Content-Length"))
+        }
+        (_, Some(len)) if len > MAX_BODY => {
+            return Err(Response::error(
+                413,
+                format!("bodies are at most {MAX_BODY} bytes"),
+            ))
+        }
+        (_, Some(len)) => {
+            body.resize(len, 0);
+            r.read_exact(&mut body)
+                .map_err(|_| bad("could not read the body"))?;
+        }
+        (_, None) => {}
+    }
+    Ok(Request { route, body })
+}
+
+/// The files a peer kept in `directory` is kept in
+pub fn files(directory: &Path, fp: &Fingerprint) -> (PathBuf, PathBuf) {
+    let name = fp.to_string().replace(':', "");
+    (
+        directory.join(format!("{name}.json")),
+        directory.join(format!("{name}.pqpk")),
+    )
+}
+
+/// Split the body of a request into the settings of a peer and the public
+/// key, if there is one
+pub fn parse_peer(body: &[u8]) -> Result<(Settings, Option<Vec<u8>>)> {
+    let mut settings: Settings =
+        serde_json::from_slice(body).context("the body is not a JSON object")?;
+    for key in ["public_key_vault", "ca", "old_public_key"] {
+        ensure!(
+            !settings.contains_key(key),
+            "{key} can not be set through the api"
+        );
+    }
+    let pk = match settings.remove("public_key") {
+        None => None,
+        Some(Value::String(b64)) => {
+            let mut pk = vec![];
+            b64_reader(b64.trim().as_bytes())
+                .read_to_end(&mut pk)
+                .context("public_key is not base64 encoded")?;
+            ensure!(
+                pk.len() == StaticKEM::PK_LEN,
+                "public_key has {} bytes instead of {}",
+                pk.len(),
+                StaticKEM::PK_LEN
+            );
+            Some(pk)
+        }
+        Some(_) => bail!("public_key must be a base64 encoded string"),
+    };
+    Ok((settings, pk))
+}
+
+/// Write `settings` as the settings file `json` of the peer with the public
+/// key in `pqpk`, in a way a crash does not leave half of it behind
+pub fn write_settings(json: &Path, pqpk: &Path, mut settings: Settings) -> Result<()> {
+    settings.insert("public_key".into(), pqpk.to_string_lossy().into());
+    let tmp = json.with_extension("tmp");
+    fs::write(&tmp, Value::Object(settings).to_string())
+        .and_then(|()| fs::rename(&tmp, json))
+        .with_context(|| format!("could not write api peer {json:?}"))
+}
+
+/// Read the peers kept in `directory`; also used by [crate::enrollment]
+pub fn load(directory: &Path) -> Result<Vec<RosenpassPeer>> {
+    let entries = match fs::read_dir(directory) {
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        res => res.with_context(|| format!("could not read api directory {directory:?}"))?,
+    };
+    let mut paths = vec![];
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "json") {
+            paths.push(path);
+        }
+    }
+    // in a stable order, like the peers of a config file
+    paths.sort();
+    paths
+        .into_iter()
+        .map(|path| {
+            let text = fs::read(&path).with_context(|| format!("could not read {path:?}"))?;
+            serde_json::from_slice(&text).with_context(|| format!("peer {path:?} is invalid"))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn routes() {
+        let fp = "00112233445566778899aabbccddeeff";
+        let parsed: Fingerprint = fp.parse().unwrap();
+        assert_eq!(Route::of("GET", "/peers"), Ok(Route::ListPeers));
+        assert_eq!(Route::of("GET", "/peers/"), Ok(Route::ListPeers));
+        assert_eq!(Route::of("POST", "/peers"), Ok(Route::CreatePeer));
+        assert_eq!(Route::of("GET", "/health?x=1"), Ok(Route::Health));
+        assert_eq!(
+            Route::of("DELETE", &format!("/peers/{parsed}")),
+            Ok(Route::DeletePeer(parsed))
+        );
+        assert_eq!(
+            Route::of("PUT", &format!("/peers/{fp}")),
+            Ok(Route::UpdatePeer(parsed))
+        );
+        assert_eq!(Route::of("DELETE", "/peers").unwrap_err().status, 405);
+        assert_eq!(Route::of("GET", "/peers/nope").unwrap_err().status, 400);
+        assert_eq!(Route::of("GET", "/admin").unwrap_err().status, 404);
+    }
+
+    #[test]
+    fn requests_are_authenticated() {
+        let req = |text: &str| read_request(text.as_bytes(), b"sesame");
+
+        let ok = req(
+            "POST /peers HTTP/1.1\r\nAuthorization: Bearer sesame\r\nContent-Length: 2\r\n\r\n{}",
+        )
+        .unwrap();
+        assert_eq!(ok.route, Route::CreatePeer);
+        assert_eq!(ok.body, b"{}");
+
+        let status = |text: &str| req(text).unwrap_err().status;
+        assert_eq!(status("GET /peers HTTP/1.1\r\n\r\n"), 401);
+        assert_eq!(
+            status("GET /peers HTTP/1.1\r\nAuthorization: Bearer sesam\r\n\r\n"),
+            401
+        );
+        // unauthenticated clients do not learn about routes
+        assert_eq!(status("GET /admin HTTP/1.1\r\n\r\n"), 401);
+        assert_eq!(
+            status("POST /peers HTTP/1.1\r\nAuthorization: Bearer sesame\r\n\r\n"),
+            411
+        );
+        assert_eq!(
+            status("POST /peers HTTP/1.1\r\nAuthorization: Bearer sesame\r\nContent-Length: 9999999\r\n\r\n"),
+            413
+        );
+        assert_eq!(status("nonsense\r\n\r\n"), 400);
+        let long = format!("GET /peers HTTP/1.1\r\nX: {}\r\n\r\n", "a".repeat(MAX_LINE));
+        assert_eq!(status(&long), 400);
+        let many = format!(
+            "GET /peers HTTP/1.1\r\n{}\r\n",
+            "X: a\r\n".repeat(MAX_HEADERS + 1)
+        );
+        assert_eq!(status(&many), 400);
+    }
+
+    #[test]
+    fn peer_bodies() {
+        let (settings, pk) = parse_peer(br#"{"endpoint": "192.0.2.1:9999"}"#).unwrap();
+        assert_eq!(settings["endpoint"], "192.0.2.1:9999");
+        assert_eq!(pk, None);
+
+        let b64 = rosenpass_util::b64::fmt_b64(&[7u8; StaticKEM::PK_LEN]).to_string();
+        let (_, pk) = parse_peer(format!(r#"{{"public_key": "{b64}"}}"#).as_bytes()).unwrap();
+        assert_eq!(pk, Some(vec![7u8; StaticKEM::PK_LEN]));
+
+        assert!(parse_peer(br#"{"public_key": "AAAA"}"#).is_err());
+        assert!(parse_peer(br#"{"ca": "/etc/ca"}"#).is_err());
+        assert!(parse_peer(b"[]").is_err());
+    }
+}
@@ -1,12 +1,13 @@
-use anyhow::bail;
+use anyhow::{bail, ensure, Context};
 
 use anyhow::Result;
 use log::{debug, error, info, warn};
 use mio::Interest;
 use mio::Token;
-use rosenpass_util::file::fopen_w;
+use rosenpass_util::file::{fopen_w, LoadValue, LoadValueB64};
 
 use std::cell::Cell;
+use std::fs;
 use std::io::Write;
 
 use std::io::ErrorKind;
@@ -16,20 +17,115 @@ use std::net::SocketAddr;
 use std::net::SocketAddrV4;
 use std::net::SocketAddrV6;
 use std::net::ToSocketAddrs;
-use std::path::PathBuf;
+use std::os::fd::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::process::Stdio;
 use std::slice;
+use std::sync::atomic::Ordering::SeqCst;
+use std::sync::mpsc::{Receiver, TryRecvError};
+use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
 
+#[cfg(feature = "quic")]
+use crate::quic::{self, QuicSocket};
 use crate::{
-    config::Verbosity,
-    protocol::{CryptoServer, MsgBuf, PeerPtr, SPk, SSk, SymKey, Timing},
+    alerts::{Alert, AlertKind, Alerter, Alerts},
+    api::{self, ApiServer, Response, Route},
+    audit::{Audit, AuditEvent, AuditLog},
+    breaker::CircuitBreaker,
+    config::{FreshKeys, HealthcheckPolicy, RosenpassPeer, Verbosity},
+    consumer::{Consumer, KeyConsumer},
+    container,
+    control::{
+        self, ControlCommand, ControlSocket, Device, DeviceChecks, HealthReport, PeerStatus,
+        RekeyReport,
+    },
+    dns,
+    enrollment::{self, Credential, EnrollmentServer, Proof, Verdict},
+    events::{Event, EventStream, Subscribers},
+    exit::{Failure, ResultExt as _},
+    exporter::Export,
+    failover,
+    fifo::{self, FifoOut},
+    fingerprint::Fingerprint,
+    fragment::{self, Reassembler},
+    ha::{self, Ha, HighAvailability, PeerState, SyncMsg},
+    interface,
+    keystream::{self, KeyEvent},
+    liveness::{DeadPeerPolicy, Liveness, PeerEvent},
+    lockdown::{self, IpPrefix},
+    mdns::{self, Mdns},
+    memnet::{MemNet, MemSocket},
+    msgs::{MsgType, FRAGMENT_DATA_LEN, RELAY_DATA_LEN, RENDEZVOUS_PAYLOAD_LEN},
+    nat::{self, Stun},
+    netns,
+    peer_store::{StoredPeers, Update},
+    protocol::{
+        has_happened, peer_id, CryptoServer, MsgBuf, PeerPtr, PollResult, Pollable, SPk, SSk,
+        SymKey, Timing, REJECT_AFTER_TIME, RETRANSMIT_DELAY_JITTER, UNENDING,
+    },
+    quota::{self, Quotas},
+    rekey, relay,
+    rendezvous::{self, Registration, RendezvousMsg},
+    revocation::{Revocation, RevocationList, Revocations},
+    sched::ThreadScheduling,
+    shared_pk::SharedPk,
+    shed::{self, Shedder},
+    sockopt::SocketOptions,
+    stagger,
+    stale::StaleKeyPolicy,
+    stats::{FailureCause, FailureCounts, PeerStats, PeerStatsEntry, StatsReport},
+    timers::{ClockChanges, TimerWheel},
+    uapi,
+    unix::{self, UnixSocket},
+    upgrade,
+    watch::Trigger,
+    workers::{Done, HandshakeWorkers, PeerChange},
 };
 use rosenpass_util::attempt;
 use rosenpass_util::b64::{b64_writer, fmt_b64};
 
+/// mio token of the control socket; the UDP sockets use their index as token
+const CONTROL_SOCKET_TOKEN: Token = Token(usize::MAX - 1);
+/// mio token threads working for the event loop wake it with, like the
+/// handshake workers
+const WAKER_TOKEN: Token = Token(usize::MAX - 2);
+/// mio token of the multicast DNS socket
+const MDNS_TOKEN: Token = Token(usize::MAX - 3);
+
+/// Token of the socket receiving the messages of the other instance, see
+/// [crate::ha]
+const HA_TOKEN: Token = Token(usize::MAX - 4);
+
+/// Token of the listener of the provisioning API, see [crate::api]
+const API_TOKEN: Token = Token(usize::MAX - 5);
+
+/// Token of the listener new peers enroll on, see [crate::enrollment]
+const ENROLLMENT_TOKEN: Token = Token(usize::MAX - 6);
+
+/// Token of the timer telling of changes of the clocks, see [ClockChanges]
+const CLOCK_TOKEN: Token = Token(usize::MAX - 7);
+
+/// In low power mode, timers are delayed to the next multiple of this many
+/// seconds, so timers of different peers expire in a single wakeup
+const LOW_POWER_TIMER_GRANULARITY: Timing = 2.0;
+/// [CryptoServer::retransmit_jitter] in low power mode; retransmissions are
+/// spread over 50% to 200% of the regular delay
+const LOW_POWER_RETRANSMIT_JITTER: Timing = 1.5;
+
+/// Longest the event loop waits without looking at the clocks where it is
+/// not told of changes of the clocks, so a resume from suspend is noticed
+/// within this many seconds
+const CLOCK_CHECK_INTERVAL: Timing = 10.0;
+/// Smallest suspend or step of the wall clock in seconds that is reported
+const CLOCK_JUMP_THRESHOLD: Timing = 2.0;
+
+/// How long the old public key of a peer in a key rollover is accepted by
+/// default, in seconds
+pub const ROLLOVER_WINDOW: u64 = 7 * 24 * 60 * 60;
+
 const IPV4_ANY_ADDR: Ipv4Addr = Ipv4Addr::new(0, 0, 0, 0);
 const IPV6_ANY_ADDR: Ipv6Addr = Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0);
 
@@ -46,9 +142,85 @@ fn ipv6_any_binding() -> SocketAddr {
 #[derive(Default, Debug)]
 pub struct AppPeer {
     pub outfile: Option<PathBuf>,
-    pub outwg: Option<WireguardOut>, // TODO make this a generic command
+    /// Set if [AppPeer::outfile] is a named pipe, see [crate::fifo]
+    pub fifo: Option<FifoOut>,
+    /// Print the keys on stdout, see [crate::keystream]
+    pub stream_keys: bool,
+    /// Further keys written along with the key, see [crate::exporter]
+    pub exports: Vec<Export>,
+    pub outwg: Option<WireguardOut>,
+    /// What the keys are handed to, [AppPeer::outwg] among them, see [crate::consumer]
+    pub consumers: Vec<Consumer>,
     pub initial_endpoint: Option<Endpoint>,
     pub current_endpoint: Option<Endpoint>,
+    /// Name given to the peer, see [crate::fingerprint]
+    pub name: Option<String>,
+    pub tags: Vec<String>,
+    pub stats: PeerStats,
+    /// When the InitHello of the handshake in progress was first sent
+    pub handshake_started: Option<Timing>,
+    /// Send large handshake messages to this peer in fragments
+    pub fragment: bool,
+    /// Seconds between keepalives sent to this peer, if any
+    pub keepalive: Option<Timing>,
+    /// When the next keepalive is due
+    pub keepalive_at: Timing,
+    /// When the last key was exchanged with this peer
+    pub last_exchange: Option<Timing>,
+    /// The peer missed its rekey window, see [crate::liveness]
+    pub dead: bool,
+    /// Handshakes failed since the last one which went through
+    pub failure_streak: u32,
+    /// Accept the peer from the addresses of [AppPeer::initial_endpoint],
+    /// see [crate::lockdown]
+    pub lock_endpoint: bool,
+    /// Accept the peer from these networks
+    pub allowed_sources: Vec<IpPrefix>,
+    /// Name whose SRV records list the endpoints of the peer, see [crate::dns]
+    pub srv_name: Option<String>,
+    /// When the SRV records are to be looked up next
+    pub resolve_at: Timing,
+    /// The lookup in progress, if any
+    pub resolving: Option<Receiver<anyhow::Result<Vec<SocketAddr>>>>,
+    /// Align handshakes with those of the peer, see [crate::nat]
+    pub hole_punching: bool,
+    /// The handshake waiting for the next punch slot, if any
+    pub punch_at: Option<Timing>,
+    /// The peer is a rendezvous server to register with and ask for the
+    /// endpoints of other peers, see [crate::rendezvous]
+    pub rendezvous: bool,
+    /// When to register with the peer next, if it is a rendezvous server
+    pub rendezvous_at: Timing,
+    /// Where the peer last registered with us, if we are a rendezvous server
+    pub registration: Option<Registration>,
+    /// The peer is a relay server to send messages to unreachable peers
+    /// through, see [crate::relay]
+    pub relay: bool,
+    /// The relay messages to this peer are sent through, if any
+    pub via_relay: Option<AppPeerPtr>,
+    /// The socket bound to the interface of the peer, which all messages to
+    /// the peer are sent through; see [crate::interface]
+    pub socket: Option<SocketPtr>,
+    /// The public key of the peer was revoked, see [crate::revocation]
+    pub revoked: bool,
+    /// The peer was removed while running, from the [crate::peer_store] or
+    /// through the [crate::api]
+    pub removed: bool,
+    /// Handshakes we initiated which went unanswered since the last one
+    /// which went through
+    pub unanswered: u32,
+    /// When to probe the peer next, if it is unreachable; see
+    /// [crate::breaker]
+    pub probe_at: Option<Timing>,
+    /// What becomes of a key which was not renewed in time
+    pub stale_key: StaleKeyPolicy,
+    /// When the expired key kept for the peer is erased, see [crate::stale]
+    pub erase_at: Option<Timing>,
+    /// When to try again to initiate a handshake which was over quota or
+    /// shed under load, see [crate::quota] and [crate::shed]
+    pub quota_at: Option<Timing>,
+    /// When the first handshake is started, see [crate::stagger]
+    pub start_at: Option<Timing>,
 }
 
 impl AppPeer {
@@ -57,29 +229,201 @@ impl AppPeer {
             .as_ref()
             .or(self.initial_endpoint.as_ref())
     }
+
+    /// Whether the peer is only accepted from certain addresses
+    pub fn locked(&self) -> bool {
+        self.lock_endpoint || !self.allowed_sources.is_empty()
+    }
+
+    /// Whether handshake messages of the peer may come from `addr`
+    pub fn accepts_source(&self, addr: &SocketAddr) -> bool {
+        if !self.locked() {
+            return true;
+        }
+        let ip = lockdown::canonical(addr.ip());
+        let endpoint = self.lock_endpoint.then_some(self.initial_endpoint.as_ref());
+        let pinned = endpoint
+            .flatten()
+            .map(Endpoint::addresses)
+            .unwrap_or_default();
+        pinned.iter().any(|a| lockdown::canonical(a.ip()) == ip)
+            || self.allowed_sources.iter().any(|p| p.contains(ip))
+    }
 }
 
-#[derive(Default, Debug)]
+#[derive(Default, Debug, Clone)]
 pub struct WireguardOut {
     // impl KeyOutput
     pub dev: String,
     pub pk: String,
     pub extra_params: Vec<String>,
+    pub uapi_socket: Option<PathBuf>,
+    /// Namespace to run `wg` in, see [crate::netns]
+    pub netns: Option<String>,
+}
+
+impl WireguardOut {
+    /// Set the base64 encoded `key` as preshared key and read it back to make
+    /// sure it arrived
+    fn apply(&self, key: &str) -> anyhow::Result<()> {
+        if let Some(socket) = self.uapi_socket.as_ref() {
+            uapi::set_psk(socket, &self.pk, key)?;
+            let applied = uapi::psk_of(socket, &self.pk)?;
+            ensure!(
+                applied.as_deref() == Some(key),
+                "wireguard reports a different preshared key after setting it"
+            );
+            return Ok(());
+        }
+        netns::within(self.netns.as_deref(), || self.apply_with_wg(key))
+    }
+
+    fn apply_with_wg(&self, key: &str) -> anyhow::Result<()> {
+        let mut child = Command::new("wg")
+            .arg("set")
+            .arg(&self.dev)
+            .arg("peer")
+            .arg(&self.pk)
+            .arg("preshared-key")
+            .arg("/dev/stdin")
+            .stdin(Stdio::piped())
+            .args(&self.extra_params)
+            .spawn()?;
+        child.stdin.take().unwrap().write_all(key.as_bytes())?;
+        let status = child.wait()?;
+        ensure!(status.success(), "wg set failed with {status}");
+
+        let out = Command::new("wg")
+            .args(["show", &self.dev, "preshared-keys"])
+            .stderr(Stdio::null())
+            .output()?;
+        ensure!(out.status.success(), "wg show failed with {}", out.status);
+        let applied = String::from_utf8_lossy(&out.stdout).lines().any(|line| {
+            let mut fields = line.split_whitespace();
+            fields.next() == Some(self.pk.as_str()) && fields.next() == Some(key)
+        });
+        ensure!(
+            applied,
+            "wg reports a different preshared key after setting it"
+        );
+        Ok(())
+    }
+}
+
+impl KeyConsumer for WireguardOut {
+    fn target(&self) -> String {
+        format!("wg peer {} on device {}", self.pk, self.dev)
+    }
+
+    fn install(&self, key: &SymKey, _exported: &[SymKey]) -> anyhow::Result<()> {
+        // like the key file, this copy of the key is not erased; see output_key
+        self.apply(&fmt_b64(key.secret()).to_string())
+    }
 }
 
 /// Holds the state of the application, namely the external IO
 ///
-/// Responsible for file IO, network IO
-// TODO add user control via unix domain socket and stdin/stdout
+/// Responsible for file IO, network IO and the control socket
+// TODO add user control via stdin/stdout
 #[derive(Debug)]
 pub struct AppServer {
     pub crypt: CryptoServer,
-    pub sockets: Vec<mio::net::UdpSocket>,
+    pub sockets: Vec<Socket>,
+    /// Number of [AppServer::sockets] listened on; those after them are
+    /// bound to the interfaces of peers, see [crate::interface]
+    pub listen_sockets: usize,
+    /// The interfaces of peers and the sockets bound to them
+    pub interface_sockets: Vec<(String, SocketPtr)>,
+    /// Set on all [AppServer::sockets], see [crate::sockopt]
+    pub socket_options: SocketOptions,
     pub events: mio::Events,
     pub mio_poll: mio::Poll,
     pub peers: Vec<AppPeer>,
     pub verbosity: Verbosity,
     pub all_sockets_drained: bool,
+    pub control: Option<ControlSocket>,
+    pub health_policy: HealthcheckPolicy,
+    /// Reachability of the WireGuard devices, for [Self::healthcheck]
+    pub device_checks: DeviceChecks,
+    pub unattributed_failures: FailureCounts,
+    pub reassembler: Reassembler,
+    pub workers: Option<HandshakeWorkers>,
+    /// Wakes the event loop from other threads
+    pub waker: Arc<mio::Waker>,
+    /// Peer discovery on the local network, if enabled
+    pub mdns: Option<Mdns>,
+    /// Learning our reflexive addresses, if enabled
+    pub stun: Option<Stun>,
+    /// Keep track of the endpoints of our peers for each other, see
+    /// [crate::rendezvous]
+    pub rendezvous_server: bool,
+    /// Relay messages between our peers, see [crate::relay]
+    pub relay_server: bool,
+    /// Replication to or from another instance, if enabled
+    pub ha: Option<Ha>,
+    /// Signed log of security events, if enabled
+    pub audit: Option<AuditLog>,
+    /// Webhooks told about security events, if enabled
+    pub alerts: Option<Alerter>,
+    /// The revoked public keys, if a revocation list is configured
+    pub revocations: Option<Revocations>,
+    /// The peers taken from the peer store, if one is configured
+    pub stored_peers: Option<StoredPeers>,
+    /// The provisioning API, if enabled
+    pub api: Option<ApiServer>,
+    /// Where new peers enroll, if enabled
+    pub enrollment: Option<EnrollmentServer>,
+    /// Network namespace sockets are opened in, see [crate::netns]
+    pub netns: Option<String>,
+    /// Report written keys through the logger instead of on stdout, which
+    /// carries the logs in container mode
+    pub key_output_to_log: bool,
+    /// Print the keys of all peers on stdout, see [crate::keystream]
+    pub stream_keys: bool,
+    /// Those embedding rosenpass following its events, see [crate::events]
+    pub subscribers: Subscribers,
+    /// Limits of the handshakes done for the peers, see [crate::quota]
+    pub quotas: Quotas,
+    /// Turns away new handshakes under load, see [crate::shed]
+    pub shedder: Option<Shedder>,
+    /// Coalesce timers to save power, see [AppServer::set_low_power]
+    pub low_power: bool,
+    /// How often the event loop woke up from waiting
+    pub wakeups: u64,
+    /// Dead peer detection, if enabled
+    pub dead_peer: Option<DeadPeerPolicy>,
+    /// Window the first handshakes are spread over, see [crate::stagger]
+    pub startup_jitter: Option<Timing>,
+    pub circuit_breaker: CircuitBreaker,
+    /// [rosenpass_util::time::Timebase::suspended] when the clocks were last
+    /// checked, see [AppServer::check_clock]
+    pub suspended: Timing,
+    /// [rosenpass_util::time::Timebase::wall_clock_offset] when the clocks
+    /// were last checked
+    pub wall_clock_offset: Timing,
+    /// When the system last resumed from a suspend
+    pub resumed_at: Timing,
+    /// How often the system was suspended or the wall clock was set
+    pub clock_jumps: u64,
+    /// Peers to initiate a handshake with before handling any timers
+    pub pending_initiations: Vec<AppPeerPtr>,
+    /// [rekey::signals] when they were last checked
+    pub rekey_signals: u64,
+    /// Whether this instance may execute another binary in its place, see
+    /// [crate::upgrade]
+    pub upgrades: bool,
+    /// [upgrade::signals] when they were last checked
+    pub upgrade_signals: u64,
+    /// The binary to execute in place of ours, once the event loop comes by
+    pub upgrade_to: Option<PathBuf>,
+    /// Takes up a [ControlCommand::Reload]
+    pub reload: Option<Trigger>,
+    /// When the timers of each peer may be due next, see [crate::timers]
+    pub timers: TimerWheel,
+    /// Wakes the event loop when the clocks changed, where it can
+    pub clock_changes: Option<ClockChanges>,
+    /// Peers whose SRV records are being looked up
+    pub lookups: Vec<AppPeerPtr>,
 }
 
 /// A socket pointer is an index assigned to a socket;
@@ -88,15 +432,85 @@ pub struct AppServer {
 /// Holding this as a reference instead of an &mut UdpSocket is useful
 /// to deal with the borrow checker, because otherwise we could not refer
 /// to a socket and another member of AppServer at the same time.
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct SocketPtr(pub usize);
 
+/// A socket of the server, on the host or on a [MemNet] for tests; unix
+/// sockets and QUIC connections reach peers under the addresses
+/// [crate::unix] and `crate::quic` give them
+#[derive(Debug)]
+pub enum Socket {
+    Udp(mio::net::UdpSocket),
+    Unix(UnixSocket),
+    #[cfg(feature = "quic")]
+    Quic(Box<QuicSocket>),
+    Memory(MemSocket),
+}
+
+impl Socket {
+    pub fn send_to(&self, buf: &[u8], addr: SocketAddr) -> std::io::Result<usize> {
+        match self {
+            Socket::Udp(_) if unix::is_unix(&addr) => Err(unix::wrong_kind()),
+            #[cfg(feature = "quic")]
+            Socket::Udp(_) if quic::is_quic(&addr) => Err(quic::wrong_kind()),
+            Socket::Udp(sock) => sock.send_to(buf, addr),
+            Socket::Unix(sock) => sock.send_to(buf, addr),
+            #[cfg(feature = "quic")]
+            Socket::Quic(sock) => sock.send_to(buf, addr),
+            Socket::Memory(sock) => sock.send_to(buf, addr),
+        }
+    }
+
+    pub fn recv_from(&self, buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr)> {
+        match self {
+            Socket::Udp(sock) => sock.recv_from(buf),
+            Socket::Unix(sock) => sock.recv_from(buf),
+            #[cfg(feature = "quic")]
+            Socket::Quic(sock) => sock.recv_from(buf),
+            Socket::Memory(sock) => sock.recv_from(buf),
+        }
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        match self {
+            Socket::Udp(sock) => sock.local_addr(),
+            Socket::Unix(sock) => Ok(sock.local_addr()),
+            #[cfg(feature = "quic")]
+            Socket::Quic(sock) => sock.local_addr(),
+            Socket::Memory(sock) => sock.local_addr(),
+        }
+    }
+
+    /// The socket on the host, for setting its options
+    pub fn udp(&self) -> anyhow::Result<&mio::net::UdpSocket> {
+        match self {
+            Socket::Udp(sock) => Ok(sock),
+            Socket::Unix(_) => bail!("unix sockets have no options"),
+            #[cfg(feature = "quic")]
+            Socket::Quic(sock) => Ok(&sock.socket),
+            Socket::Memory(_) => bail!("sockets of an in-memory network have no options"),
+        }
+    }
+
+    pub fn is_unix(&self) -> bool {
+        matches!(self, Socket::Unix(_))
+    }
+
+    pub fn is_quic(&self) -> bool {
+        match self {
+            #[cfg(feature = "quic")]
+            Socket::Quic(_) => true,
+            _ => false,
+        }
+    }
+}
+
 impl SocketPtr {
-    pub fn get<'a>(&self, srv: &'a AppServer) -> &'a mio::net::UdpSocket {
+    pub fn get<'a>(&self, srv: &'a AppServer) -> &'a Socket {
         &srv.sockets[self.0]
     }
 
-    pub fn get_mut<'a>(&self, srv: &'a mut AppServer) -> &'a mut mio::net::UdpSocket {
+    pub fn get_mut<'a>(&self, srv: &'a mut AppServer) -> &'a mut Socket {
         &mut srv.sockets[self.0]
     }
 
@@ -135,7 +549,35 @@ pub enum AppPollResult {
     DeleteKey(AppPeerPtr),
     SendInitiation(AppPeerPtr),
     SendRetransmission(AppPeerPtr),
+    SendKeepalive(AppPeerPtr),
+    PeerDead(AppPeerPtr),
+    /// The expired key kept for the peer is to be erased now
+    EraseKey(AppPeerPtr),
+    /// Time to look up the SRV records of the peer
+    ResolveEndpoint(AppPeerPtr),
+    /// Time to announce ourselves on the local network
+    MdnsAnnounce,
+    /// Time to ask the STUN servers for our reflexive addresses
+    StunRequest,
+    /// Timers of the QUIC connections are due
+    QuicTimeout,
+    /// Time to register with the rendezvous server and ask it for peers
+    RendezvousUpdate(AppPeerPtr),
+    /// Time to send our state to the standby instance
+    HaSync,
+    /// The active instance went silent
+    HaTakeover,
+    /// The SRV records of the peer were looked up
+    EndpointResolved(AppPeerPtr, anyhow::Result<Vec<SocketAddr>>),
+    /// The revocation list was loaded again
+    RevocationsRefreshed(anyhow::Result<RevocationList>),
+    /// The peer store or the LDAP directory changed, see [crate::peer_store]
+    PeerStoreChanged(anyhow::Result<Update>),
     ReceivedMessage(usize, Endpoint),
+    /// A handshake worker answered an InitHello
+    HandshakeDone(Done),
+    /// A termination signal arrived, see [AppServer::terminate_on_signal]
+    Terminate,
 }
 
 #[derive(Debug)]
@@ -221,6 +663,21 @@ impl Endpoint {
         }
     }
 
+    /// Send through `socket` instead of the socket of the endpoint, if given
+    pub fn send_via(
+        &self,
+        srv: &AppServer,
+        socket: Option<SocketPtr>,
+        buf: &[u8],
+    ) -> anyhow::Result<()> {
+        use Endpoint::*;
+        match (self, socket) {
+            (_, None) => self.send(srv, buf),
+            (SocketBoundAddress { addr, .. }, Some(socket)) => socket.send_to(srv, buf, *addr),
+            (Discovery(host), Some(socket)) => host.send_scouting_via(srv, socket, buf),
+        }
+    }
+
     fn addresses(&self) -> &[SocketAddr] {
         use Endpoint::*;
         match self {
@@ -286,7 +743,7 @@ impl HostPathDiscoveryEndpoint {
     fn insert_next_scout_offset(&self, srv: &AppServer, addr_no: usize, sock_no: usize) {
         self.scouting_state.set((
             (addr_no + 1) % self.addresses.len(),
-            (sock_no + 1) % srv.sockets.len(),
+            (sock_no + 1) % srv.listen_sockets,
         ));
     }
 
@@ -302,12 +759,13 @@ impl HostPathDiscoveryEndpoint {
             .cycle()
             .skip(addr_off)
             .take(self.addresses.len());
-        let mut sockets = (srv.sockets)
+        // sockets bound to the interfaces of peers are for those peers only
+        let mut sockets = (srv.sockets[..srv.listen_sockets])
             .iter()
             .enumerate()
             .cycle()
             .skip(sock_off)
-            .take(srv.sockets.len());
+            .take(srv.listen_sockets);
 
         for (addr_no, addr) in addrs.by_ref() {
             for (sock_no, sock) in sockets.by_ref() {
@@ -325,7 +783,8 @@ impl HostPathDiscoveryEndpoint {
                 // once https://github.com/rust-lang/rust/issues/86442 lands
                 let ignore = err
                     .to_string()
-                    .starts_with("Address family not supported by protocol");
+                    .starts_with("Address family not supported by protocol")
+                    || err.kind() == ErrorKind::Unsupported;
                 if !ignore {
                     warn!("Socket #{} refusing to send to {}: ", sock_no, addr);
                 }
@@ -334,6 +793,38 @@ impl HostPathDiscoveryEndpoint {
 
         bail!("Unable to send message: All sockets returned errors.")
     }
+
+    /// Attempt to reach the host through `socket` only
+    ///
+    /// Will round-robin-try the different ips on each call.
+    pub fn send_scouting_via(
+        &self,
+        srv: &AppServer,
+        socket: SocketPtr,
+        buf: &[u8],
+    ) -> anyhow::Result<()> {
+        let (addr_off, sock_off) = self.scouting_state.get();
+        let addrs = (self.addresses)
+            .iter()
+            .enumerate()
+            .cycle()
+            .skip(addr_off)
+            .take(self.addresses.len());
+        for (addr_no, addr) in addrs {
+            match socket.get(srv).send_to(buf, *addr) {
+                Ok(_) => {
+                    let next = (addr_no + 1) % self.addresses.len();
+                    self.scouting_state.set((next, sock_off));
+                    return Ok(());
+                }
+                Err(e) => debug!("Socket #{} refusing to send to {}: {}", socket.0, addr, e),
+            }
+        }
+        bail!(
+            "Unable to send message: Socket #{} returned errors.",
+            socket.0
+        )
+    }
 }
 
 impl AppServer {
@@ -345,11 +836,9 @@ impl AppServer {
     ) -> anyhow::Result<Self> {
         // setup mio
         let mio_poll = mio::Poll::new()?;
-        let events = mio::Events::with_capacity(8);
 
         // bind each SocketAddr to a socket
-        let maybe_sockets: Result<Vec<_>, _> =
-            addrs.into_iter().map(mio::net::UdpSocket::bind).collect();
+        let maybe_sockets: Result<Vec<_>, _> = addrs.into_iter().map(upgrade::bind_udp).collect();
         let mut sockets = maybe_sockets?;
 
         // When no socket is specified, rosenpass should open one port on all
@@ -384,7 +873,7 @@ impl AppServer {
         if sockets.is_empty() {
             macro_rules! try_register_socket {
                 ($title:expr, $binding:expr) => {{
-                    let r = mio::net::UdpSocket::bind($binding);
+                    let r = upgrade::bind_udp($binding);
                     match r {
                         Ok(sock) => {
                             sockets.push(sock);
@@ -427,14 +916,153 @@ impl AppServer {
 
         // TODO use mio::net::UnixStream together with std::os::unix::net::UnixStream for Linux
 
+        let sockets = sockets.into_iter().map(Socket::Udp).collect();
+        Self::with_sockets(sk, pk, sockets, mio_poll, verbosity)
+    }
+
+    /// A server listening on the unix sockets at `paths` only, see
+    /// [crate::unix]
+    pub fn on_unix_sockets(
+        sk: SSk,
+        pk: SPk,
+        paths: &[PathBuf],
+        verbosity: Verbosity,
+    ) -> anyhow::Result<Self> {
+        ensure!(!paths.is_empty(), "No sockets to listen on!");
+        let mut srv = Self::with_sockets(sk, pk, Vec::new(), mio::Poll::new()?, verbosity)?;
+        for path in paths {
+            srv.listen_unix(path)?;
+        }
+        Ok(srv)
+    }
+
+    /// Listen on the unix socket at `path` too, see [crate::unix]
+    pub fn listen_unix(&mut self, path: &Path) -> anyhow::Result<()> {
+        ensure!(
+            self.interface_sockets.is_empty(),
+            "unix sockets are added before the sockets of peers"
+        );
+        let mut sock = UnixSocket::bind(path)?;
+        let no = self.sockets.len();
+        self.mio_poll
+            .registry()
+            .register(&mut sock.socket, Token(no), Interest::READABLE)?;
+        self.sockets.push(Socket::Unix(sock));
+        self.listen_sockets = self.sockets.len();
+        Ok(())
+    }
+
+    /// Carry handshakes over QUIC on a socket at `addr` too, accepting
+    /// connections with `accept`; see `crate::quic`
+    #[cfg(feature = "quic")]
+    pub fn listen_quic(&mut self, addr: SocketAddr, accept: bool) -> anyhow::Result<()> {
+        ensure!(
+            self.interface_sockets.is_empty(),
+            "QUIC sockets are added before the sockets of peers"
+        );
+        let mut sock = QuicSocket::bind(addr, accept, self.crypt.timebase.clone())?;
+        let no = self.sockets.len();
+        self.mio_poll
+            .registry()
+            .register(&mut sock.socket, Token(no), Interest::READABLE)?;
+        self.socket_options.apply(&sock.socket)?;
+        self.sockets.push(Socket::Quic(Box::new(sock)));
+        self.listen_sockets = self.sockets.len();
+        Ok(())
+    }
+
+    /// A server listening on `addrs` of `net` rather than on the host, see
+    /// [crate::memnet]
+    pub fn in_memory(
+        sk: SSk,
+        pk: SPk,
+        net: &MemNet,
+        addrs: Vec<SocketAddr>,
+        verbosity: Verbosity,
+    ) -> anyhow::Result<Self> {
+        ensure!(!addrs.is_empty(), "No sockets to listen on!");
+        let mut srv = Self::with_sockets(sk, pk, Vec::new(), mio::Poll::new()?, verbosity)?;
+        for addr in addrs {
+            // arriving datagrams wake the poll just like signals do
+            let sock = net.bind(addr, srv.waker.clone())?;
+            srv.sockets.push(Socket::Memory(sock));
+        }
+        srv.listen_sockets = srv.sockets.len();
+        srv.crypt.timebase = net.timebase();
+        srv.wall_clock_offset = srv.crypt.timebase.wall_clock_offset();
+        Ok(srv)
+    }
+
+    fn with_sockets(
+        sk: SSk,
+        pk: SPk,
+        sockets: Vec<Socket>,
+        mio_poll: mio::Poll,
+        verbosity: Verbosity,
+    ) -> anyhow::Result<Self> {
+        let events = mio::Events::with_capacity(8);
+        let waker = Arc::new(mio::Waker::new(mio_poll.registry(), WAKER_TOKEN)?);
+        let crypt = CryptoServer::new(sk, pk);
+        let clock_changes = ClockChanges::new()
+            .and_then(|c| c.register(mio_poll.registry(), CLOCK_TOKEN).map(|()| c))
+            .map_err(|e| {
+                warn!("Looking at the clocks every so often, can not be told of changes: {e}")
+            })
+            .ok();
+        let wall_clock_offset = crypt.timebase.wall_clock_offset();
         Ok(Self {
-            crypt: CryptoServer::new(sk, pk),
+            crypt,
             peers: Vec::new(),
             verbosity,
+            listen_sockets: sockets.len(),
+            interface_sockets: Vec::new(),
+            socket_options: SocketOptions::default(),
             sockets,
             events,
             mio_poll,
             all_sockets_drained: false,
+            control: None,
+            health_policy: HealthcheckPolicy::default(),
+            device_checks: DeviceChecks::default(),
+            unattributed_failures: FailureCounts::new(),
+            reassembler: Reassembler::default(),
+            workers: None,
+            waker,
+            mdns: None,
+            stun: None,
+            rendezvous_server: false,
+            relay_server: false,
+            ha: None,
+            audit: None,
+            alerts: None,
+            revocations: None,
+            stored_peers: None,
+            api: None,
+            enrollment: None,
+            netns: None,
+            key_output_to_log: false,
+            stream_keys: false,
+            subscribers: Subscribers::default(),
+            quotas: Quotas::default(),
+            shedder: None,
+            low_power: false,
+            wakeups: 0,
+            dead_peer: None,
+            startup_jitter: None,
+            circuit_breaker: CircuitBreaker::default(),
+            suspended: 0.0,
+            wall_clock_offset,
+            resumed_at: 0.0,
+            clock_jumps: 0,
+            pending_initiations: Vec::new(),
+            rekey_signals: rekey::signals(),
+            upgrades: false,
+            upgrade_signals: upgrade::signals(),
+            upgrade_to: None,
+            reload: None,
+            timers: TimerWheel::new(),
+            clock_changes,
+            lookups: Vec::new(),
         })
     }
 
@@ -442,237 +1070,2599 @@ impl AppServer {
         matches!(self.verbosity, Verbosity::Verbose)
     }
 
-    pub fn add_peer(
-        &mut self,
-        psk: Option<SymKey>,
-        pk: SPk,
-        outfile: Option<PathBuf>,
-        outwg: Option<WireguardOut>,
-        hostname: Option<String>,
-    ) -> anyhow::Result<AppPeerPtr> {
-        let PeerPtr(pn) = self.crypt.add_peer(psk, pk)?;
-        assert!(pn == self.peers.len());
-        let initial_endpoint = hostname
-            .map(Endpoint::discovery_from_hostname)
-            .transpose()?;
-        let current_endpoint = None;
-        self.peers.push(AppPeer {
-            outfile,
-            outwg,
-            initial_endpoint,
-            current_endpoint,
-        });
-        Ok(AppPeerPtr(pn))
+    /// Leave the event loop on SIGTERM and SIGINT, see [crate::container]
+    pub fn terminate_on_signal(&self) -> anyhow::Result<()> {
+        container::handle_termination(self.waker.clone())
     }
 
-    pub fn listen_loop(&mut self) -> anyhow::Result<()> {
-        const INIT_SLEEP: f64 = 0.01;
-        const MAX_FAILURES: i32 = 10;
-        let mut failure_cnt = 0;
+    /// Rekey all peers on SIGUSR1, see [crate::rekey]
+    pub fn rekey_on_signal(&self) -> anyhow::Result<()> {
+        rekey::handle_signal(self.waker.clone())
+    }
 
-        loop {
-            let msgs_processed = 0usize;
-            let err = match self.event_loop() {
-                Ok(()) => return Ok(()),
-                Err(e) => e,
-            };
+    /// Allow upgrades, and upgrade on SIGUSR2; see [crate::upgrade]
+    pub fn upgrade_on_signal(&mut self) -> anyhow::Result<()> {
+        ensure!(
+            upgrade::started_from().is_some(),
+            "the binary we were started from is unknown"
+        );
+        self.upgrades = true;
+        upgrade::handle_signal(self.waker.clone())
+    }
 
-            // This should not happen…
-            failure_cnt = if msgs_processed > 0 {
-                0
-            } else {
-                failure_cnt + 1
-            };
-            let sleep = INIT_SLEEP * 2.0f64.powf(f64::from(failure_cnt - 1));
-            let tries_left = MAX_FAILURES - (failure_cnt - 1);
-            error!(
-                "unexpected error after processing {} messages: {:?} {}",
-                msgs_processed,
-                err,
-                err.backtrace()
-            );
-            if tries_left > 0 {
-                error!("re-initializing networking in {sleep}! {tries_left} tries left.");
-                std::thread::sleep(self.crypt.timebase.dur(sleep));
+    /// Send and receive on the interface `name` only, see [crate::interface]
+    pub fn bind_to_interface(&mut self, name: &str) -> anyhow::Result<()> {
+        for sock in self.sockets[..self.listen_sockets].iter() {
+            if sock.is_unix() {
                 continue;
             }
+            interface::bind_to_device(sock.udp()?, name)?;
+        }
+        Ok(())
+    }
 
-            bail!("too many network failures");
+    /// Set `options` on all sockets, including those opened later; see
+    /// [crate::sockopt]
+    pub fn set_socket_options(&mut self, options: SocketOptions) -> anyhow::Result<()> {
+        for sock in self.sockets.iter().filter(|sock| !sock.is_unix()) {
+            options.apply(sock.udp()?)?;
         }
+        self.socket_options = options;
+        Ok(())
     }
 
-    pub fn event_loop(&mut self) -> anyhow::Result<()> {
-        let (mut rx, mut tx) = (MsgBuf::zero(), MsgBuf::zero());
+    /// Send all messages to `peer` through the interface `name`, see
+    /// [crate::interface]
+    pub fn set_peer_interface(&mut self, peer: AppPeerPtr, name: &str) -> anyhow::Result<()> {
+        let known = self.interface_sockets.iter().find(|(n, _)| n == name);
+        let socket = match known {
+            Some(&(_, socket)) => socket,
+            None => {
+                let mut sock = interface::device_socket(name)?;
+                self.socket_options.apply(&sock)?;
+                let socket = SocketPtr(self.sockets.len());
+                self.mio_poll.registry().register(
+                    &mut sock,
+                    Token(socket.0),
+                    Interest::READABLE,
+                )?;
+                self.sockets.push(Socket::Udp(sock));
+                self.interface_sockets.push((name.to_owned(), socket));
+                socket
+            }
+        };
+        peer.get_app_mut(self).socket = Some(socket);
+        Ok(())
+    }
 
-        /// if socket address for peer is known, call closure
-        /// assumes that closure leaves a message in `tx`
-        /// assumes that closure returns the length of message in bytes
-        macro_rules! tx_maybe_with {
-            ($peer:expr, $fn:expr) => {
-                attempt!({
-                    let p = $peer;
-                    if p.get_app(self).endpoint().is_some() {
-                        let len = $fn()?;
-                        let ep: &Endpoint = p.get_app(self).endpoint().unwrap();
-                        ep.send(self, &tx[..len])?;
-                    }
-                    Ok(())
-                })
-            };
-        }
+    /// Start listening for control commands on a unix domain socket
+    pub fn listen_control_socket(&mut self, path: PathBuf) -> anyhow::Result<()> {
+        let mut ctl = ControlSocket::bind(path)?;
+        self.mio_poll.registry().register(
+            &mut ctl.listener,
+            CONTROL_SOCKET_TOKEN,
+            Interest::READABLE,
+        )?;
+        self.control = Some(ctl);
+        Ok(())
+    }
 
-        loop {
-            use crate::protocol::HandleMsgResult;
-            use AppPollResult::*;
-            use KeyOutputReason::*;
-            match self.poll(&mut *rx)? {
-                #[allow(clippy::redundant_closure_call)]
-                SendInitiation(peer) => tx_maybe_with!(peer, || self
-                    .crypt
-                    .initiate_handshake(peer.lower(), &mut *tx))?,
-                #[allow(clippy::redundant_closure_call)]
-                SendRetransmission(peer) => tx_maybe_with!(peer, || self
-                    .crypt
-                    .retransmit_handshake(peer.lower(), &mut *tx))?,
-                DeleteKey(peer) => {
-                    self.output_key(peer, Stale, &SymKey::random())?;
+    /// Serve the provisioning API, see [crate::api]
+    pub fn enable_api(&mut self, mut api: ApiServer) -> anyhow::Result<()> {
+        self.mio_poll
+            .registry()
+            .register(&mut api.listener, API_TOKEN, Interest::READABLE)?;
+        self.api = Some(api);
+        Ok(())
+    }
 
-                    // There was a loss of connection apparently; restart host discovery
-                    // starting from the last used address but including all the initially
-                    // specified addresses
-                    // TODO: We could do this preemptively, before any connection loss actually occurs.
-                    let p = peer.get_app_mut(self);
-                    p.current_endpoint = Endpoint::discovery_from_multiple_sources(
-                        p.current_endpoint.as_ref(),
-                        p.initial_endpoint.as_ref(),
-                    );
-                }
+    /// Let new peers enroll, see [crate::enrollment]
+    pub fn enable_enrollment(&mut self, mut enrollment: EnrollmentServer) -> anyhow::Result<()> {
+        self.mio_poll.registry().register(
+            &mut enrollment.listener,
+            ENROLLMENT_TOKEN,
+            Interest::READABLE,
+        )?;
+        self.enrollment = Some(enrollment);
+        Ok(())
+    }
 
-                ReceivedMessage(len, endpoint) => {
-                    match self.crypt.handle_msg(&rx[..len], &mut *tx) {
-                        Err(ref e) => {
-                            self.verbose().then(|| {
-                                info!(
-                                    "error processing incoming message from {:?}: {:?} {}",
-                                    endpoint,
-                                    e,
-                                    e.backtrace()
-                                );
-                            });
-                        }
+    /// Replicate our state to or from another instance, see [crate::ha]
+    pub fn enable_ha(&mut self, cfg: &HighAvailability) -> anyhow::Result<()> {
+        let mut ha = Ha::new(cfg, self.crypt.timebase.now())?;
+        self.mio_poll
+            .registry()
+            .register(&mut ha.socket, HA_TOKEN, Interest::READABLE)?;
+        info!(
+            "Standing by for {} seconds before taking over from {}",
+            ha.takeover_after, ha.partner
+        );
+        self.ha = Some(ha);
+        self.audit(AuditEvent::HaStandby, None, None);
+        Ok(())
+    }
 
-                        Ok(HandleMsgResult {
-                            resp,
-                            exchanged_with,
-                            ..
-                        }) => {
-                            if let Some(len) = resp {
-                                endpoint.send(self, &tx[0..len])?;
-                            }
+    /// Keep a signed log of security events, see [crate::audit]
+    pub fn enable_audit(&mut self, cfg: &Audit) -> anyhow::Result<()> {
+        let log = AuditLog::open(cfg)?;
+        let (uid, euid, gid, egid) = unsafe {
+            (
+                libc::getuid(),
+                libc::geteuid(),
+                libc::getgid(),
+                libc::getegid(),
+            )
+        };
+        let ids = format!("uid {uid} euid {euid} gid {gid} egid {egid}");
+        log.record(AuditEvent::Started, None, Some(ids))?;
+        self.audit = Some(log);
+        Ok(())
+    }
 
-                            if let Some(p) = exchanged_with {
-                                let ap = AppPeerPtr::lift(p);
-                                ap.get_app_mut(self).current_endpoint = Some(endpoint);
+    /// Send security events to webhooks, see [crate::alerts]
+    pub fn enable_alerts(&mut self, cfg: &Alerts) -> anyhow::Result<()> {
+        self.alerts = Some(Alerter::start(cfg)?);
+        Ok(())
+    }
 
-                                // TODO: Maybe we should rather call the key "rosenpass output"?
-                                self.output_key(ap, Exchanged, &self.crypt.osk(p)?)?;
-                            }
-                        }
-                    }
-                }
-            };
-        }
+    /// Refuse the peers on the revocation list, see [crate::revocation]
+    ///
+    /// Must be called after all peers were added.
+    pub fn enable_revocation(&mut self, cfg: &Revocation) -> anyhow::Result<()> {
+        self.revocations = Some(Revocations::start(cfg, self.waker.clone())?);
+        self.apply_revocations()
     }
 
-    pub fn output_key(
-        &self,
-        peer: AppPeerPtr,
-        why: KeyOutputReason,
-        key: &SymKey,
-    ) -> anyhow::Result<()> {
-        let peerid = peer.lower().get(&self.crypt).pidt()?;
-        let ap = peer.get_app(self);
+    /// Revoke the peers whose keys are on the revocation list
+    ///
+    /// If just the old key of a peer in a key rollover is on it, only the
+    /// rollover ends; should the old key be in use, the session on it is
+    /// erased, just as if the peer was revoked.
+    fn apply_revocations(&mut self) -> anyhow::Result<()> {
+        let Some(revocations) = self.revocations.as_ref() else {
+            return Ok(());
+        };
+        let revoked =
+            |pk| peer_id(pk).map(|id| revocations.list.contains(&Fingerprint::from_peer_id(&id)));
+        let (mut peers, mut old_keys) = (Vec::new(), Vec::new());
+        for (no, ap) in self.peers.iter().enumerate() {
+            let (new, old) = PeerPtr(no).get(&self.crypt).rollover_keys();
+            if ap.revoked || ap.removed {
+                continue;
+            } else if revoked(new)? {
+                peers.push(AppPeerPtr(no));
+            } else if old.map(revoked).transpose()? == Some(true) {
+                old_keys.push(AppPeerPtr(no));
+            }
+        }
 
-        if self.verbose() {
-            let msg = match why {
-                KeyOutputReason::Exchanged => "Exchanged key with peer",
-                KeyOutputReason::Stale => "Erasing outdated key from peer",
-            };
-            info!("{} {}", msg, fmt_b64(&*peerid));
+        for peer in old_keys {
+            let p = peer.lower();
+            let in_use = p
+                .get(&self.crypt)
+                .rollover
+                .as_ref()
+                .is_some_and(|r| r.using_old);
+            warn!(
+                "The old public key of peer {} was revoked, dropping it",
+                Fingerprint::from_peer_id(&p.get(&self.crypt).pidt()?)
+            );
+            self.audit(AuditEvent::PeerRevoked, Some(peer), Some("old key".into()));
+            p.end_rollover(&mut self.crypt)?;
+            self.replicate(PeerChange::RolloverEnded(p))?;
+            if in_use {
+                p.session().take(&mut self.crypt);
+                p.hs().take(&mut self.crypt);
+                self.output_key(peer, KeyOutputReason::Stale, &SymKey::random())?;
+            }
+            self.reschedule(peer);
         }
 
-        if let Some(of) = ap.outfile.as_ref() {
-            // This might leave some fragments of the secret on the stack;
-            // in practice this is likely not a problem because the stack likely
-            // will be overwritten by something else soon but this is not exactly
-            // guaranteed. It would be possible to remedy this, but since the secret
-            // data will linger in the linux page cache anyways with the current
-            // implementation, going to great length to erase the secret here is
-            // not worth it right now.
-            b64_writer(fopen_w(of)?).write_all(key.secret())?;
-            let why = match why {
-                KeyOutputReason::Exchanged => "exchanged",
-                KeyOutputReason::Stale => "stale",
-            };
+        for peer in peers {
+            warn!(
+                "The public key of peer {} was revoked, refusing it from now on",
+                Fingerprint::from_peer_id(&peer.lower().get(&self.crypt).pidt()?)
+            );
+            self.audit(AuditEvent::PeerRevoked, Some(peer), None);
+            self.crypt.forget_peer(peer.lower())?;
+            self.replicate(PeerChange::Forgotten(peer.lower()))?;
+            let ap = peer.get_app_mut(self);
+            ap.revoked = true;
+            ap.erase_at = None;
+            self.output_key(peer, KeyOutputReason::Stale, &SymKey::random())?;
+            self.reschedule(peer);
+        }
+        Ok(())
+    }
 
-            // this is intentionally writing to stdout instead of stderr, because
-            // it is meant to allow external detection of a successful key-exchange
-            println!(
-                "output-key peer {} key-file {of:?} {why}",
-                fmt_b64(&*peerid)
+    /// Remove and add the stored peers as the peer store, or another source
+    /// of them, changed
+    fn apply_peer_store(&mut self, update: Update) -> anyhow::Result<()> {
+        let Some(stored) = self.stored_peers.as_mut() else {
+            return Ok(());
+        };
+        let removed = update
+            .removed
+            .iter()
+            .filter_map(|path| Some((path, stored.peers.remove(path)?)))
+            .collect::<Vec<_>>();
+        for (path, peer) in removed {
+            info!(
+                "Removing peer {path:?}, its entry in {} changed or is gone",
+                update.source
             );
+            self.remove_peer(peer)?;
         }
 
-        if let Some(owg) = ap.outwg.as_ref() {
-            let mut child = Command::new("wg")
-                .arg("set")
-                .arg(&owg.dev)
-                .arg("peer")
-                .arg(&owg.pk)
-                .arg("preshared-key")
-                .arg("/dev/stdin")
-                .stdin(Stdio::piped())
-                .args(&owg.extra_params)
-                .spawn()?;
-            b64_writer(child.stdin.take().unwrap()).write_all(key.secret())?;
-
-            thread::spawn(move || {
-                let status = child.wait();
-
-                if let Ok(status) = status {
-                    if status.success() {
-                        debug!("successfully passed psk to wg")
-                    } else {
-                        error!("could not pass psk to wg {:?}", status)
-                    }
-                } else {
-                    error!("wait failed: {:?}", status)
+        for (cfg_peer, pk) in update.added {
+            let path = cfg_peer.public_key.clone();
+            match self.add_configured_peer(cfg_peer, pk) {
+                Ok(peer) => {
+                    info!("Added peer {path:?} from {}", update.source);
+                    let stored = self.stored_peers.as_mut().unwrap();
+                    stored.peers.insert(path, peer);
                 }
-            });
+                Err(e) => warn!("Could not add peer {path:?} from {}: {e:#}", update.source),
+            }
         }
-
         Ok(())
     }
 
-    pub fn poll(&mut self, rx_buf: &mut [u8]) -> anyhow::Result<AppPollResult> {
-        use crate::protocol::PollResult as C;
-        use AppPollResult as A;
-        loop {
-            return Ok(match self.crypt.poll()? {
-                C::DeleteKey(PeerPtr(no)) => A::DeleteKey(AppPeerPtr(no)),
-                C::SendInitiation(PeerPtr(no)) => A::SendInitiation(AppPeerPtr(no)),
-                C::SendRetransmission(PeerPtr(no)) => A::SendRetransmission(AppPeerPtr(no)),
-                C::Sleep(timeout) => match self.try_recv(rx_buf, timeout)? {
-                    Some((len, addr)) => A::ReceivedMessage(len, addr),
-                    None => continue,
-                },
-            });
-        }
+    /// Forget `peer` for good: its handshakes are refused and its key is
+    /// erased, just like with a revoked peer
+    fn remove_peer(&mut self, peer: AppPeerPtr) -> anyhow::Result<()> {
+        self.audit(AuditEvent::PeerRemoved, Some(peer), None);
+        self.crypt.forget_peer(peer.lower())?;
+        self.replicate(PeerChange::Forgotten(peer.lower()))?;
+        let ap = peer.get_app_mut(self);
+        ap.removed = true;
+        ap.erase_at = None;
+        self.output_key(peer, KeyOutputReason::Stale, &SymKey::random())?;
+        self.reschedule(peer);
+        Ok(())
     }
 
-    /// Tries to receive a new message
+    /// Hand all clients currently waiting on the provisioning API to
+    /// threads of their own, and answer the requests they read
     ///
-    /// - might wait for an duration up to `timeout`
+    /// The listener is drained just like in [Self::handle_control_connections].
+    pub fn handle_api_connections(&mut self) -> anyhow::Result<()> {
+        let Some(api) = self.api.as_ref() else {
+            return Ok(());
+        };
+        while let Some(stream) = api.accept()? {
+            api.serve(stream);
+        }
+        while let Some(pending) = self.api.as_ref().and_then(|api| api.connections.try_next()) {
+            let req = &pending.request;
+            let res = self
+                .api_request(req.route, &req.body)
+                .unwrap_or_else(|e| Response::error(500, format!("{e:#}")));
+            pending.answer(res);
+        }
+        Ok(())
+    }
+
+    fn api_request(&mut self, route: Route, body: &[u8]) -> anyhow::Result<Response> {
+        let bad = |e: anyhow::Error| Response::error(400, format!("{e:#}"));
+        Ok(match route {
+            Route::Health => Response::json(200, &self.healthcheck()),
+            Route::ListPeers => Response::json(200, &self.status(None)?),
+            Route::GetPeer(fp) => match self.peer_status(fp)? {
+                Some(status) => Response::json(200, &status),
+                None => Response::error(404, format!("no peer {fp}")),
+            },
+            Route::CreatePeer => {
+                let (settings, pk) = match api::parse_peer(body) {
+                    Ok((settings, Some(pk))) => (settings, pk),
+                    Ok((_, None)) => return Ok(Response::error(400, "public_key is missing")),
+                    Err(e) => return Ok(bad(e)),
+                };
+                let fp = Fingerprint::of_public_key(&SPk::from_slice(&pk))?;
+                if self.peer_status(fp)?.is_some() {
+                    return Ok(Response::error(409, format!("peer {fp} exists already")));
+                }
+                let (json, pqpk) = self.api.as_ref().unwrap().files(&fp);
+                fs::write(&pqpk, &pk).with_context(|| format!("could not write {pqpk:?}"))?;
+                api::write_settings(&json, &pqpk, settings)?;
+                match self.add_api_peer(fp, &pqpk) {
+                    Ok(()) => Response::json(201, &self.peer_status(fp)?),
+                    Err(e) => {
+                        let _ = fs::remove_file(&json);
+                        let _ = fs::remove_file(&pqpk);
+                        bad(e)
+                    }
+                }
+            }
+            Route::UpdatePeer(fp) => {
+                let peer = match self.api_peer(fp)? {
+                    Ok(peer) => peer,
+                    Err(res) => return Ok(res),
+                };
+                let settings = match api::parse_peer(body) {
+                    Ok((_, Some(pk)))
+                        if Fingerprint::of_public_key(&SPk::from_slice(&pk))? != fp =>
+                    {
+                        return Ok(Response::error(
+                            400,
+                            format!("public_key is not the one of {fp}"),
+                        ))
+                    }
+                    Ok((settings, _)) => settings,
+                    Err(e) => return Ok(bad(e)),
+                };
+                let (json, pqpk) = self.api.as_ref().unwrap().files(&fp);
+                let old = fs::read(&json).with_context(|| format!("could not read {json:?}"))?;
+                api::write_settings(&json, &pqpk, settings)?;
+                if let Err(e) = self.reload_api_peer(&pqpk) {
+                    fs::write(&json, old).with_context(|| format!("could not restore {json:?}"))?;
+                    return Ok(bad(e));
+                }
+                info!("Replacing peer {fp}, its settings were changed through the api");
+                self.api.as_mut().unwrap().peers.remove(&fp);
+                self.remove_peer(peer)?;
+                match self.add_api_peer(fp, &pqpk) {
+                    Ok(()) => Response::json(200, &self.peer_status(fp)?),
+                    Err(e) => Response::error(500, format!("peer {fp} was removed: {e:#}")),
+                }
+            }
+            Route::DeletePeer(fp) => {
+                let peer = match self.api_peer(fp)? {
+                    Ok(peer) => peer,
+                    Err(res) => return Ok(res),
+                };
+                let (json, pqpk) = self.api.as_ref().unwrap().files(&fp);
+                fs::remove_file(&json).with_context(|| format!("could not remove {json:?}"))?;
+                let _ = fs::remove_file(&pqpk);
+                info!("Removing peer {fp} through the api");
+                self.api.as_mut().unwrap().peers.remove(&fp);
+                self.remove_peer(peer)?;
+                Response::empty(204)
+            }
+        })
+    }
+
+    /// Hand all clients currently waiting to enroll to threads of their
+    /// own, and decide on the requests they read
+    ///
+    /// The listener is drained just like in [Self::handle_control_connections].
+    pub fn handle_enrollment_connections(&mut self) -> anyhow::Result<()> {
+        let Some(enrollment) = self.enrollment.as_mut() else {
+            return Ok(());
+        };
+        while let Some(stream) = enrollment.accept()? {
+            enrollment.serve(stream, &self.crypt.sskm);
+        }
+        while let Some(pending) = self
+            .enrollment
+            .as_ref()
+            .and_then(|enrollment| enrollment.connections.try_next())
+        {
+            let verdict = self.decide_enrollment(&pending.request);
+            if let Verdict::Refused(why) = &verdict {
+                warn!("Enrollment from {} failed: {why}", pending.request.from);
+            }
+            pending.answer(verdict);
+        }
+        Ok(())
+    }
+
+    fn decide_enrollment(&mut self, request: &enrollment::Request) -> Verdict {
+        let enrollment = self.enrollment.as_ref().unwrap();
+        let pk = request.pk.clone();
+        let res = match &request.proof {
+            Proof::Token(token) => match enrollment.credential(token) {
+                Ok(Some(kind)) => self.enroll(pk).and_then(|fp| {
+                    let tokens = self.enrollment.as_ref().unwrap().tokens.as_ref();
+                    if let (Credential::Token, Some(tokens)) = (kind, tokens) {
+                        enrollment::consume(tokens, token)?;
+                    }
+                    Ok(fp)
+                }),
+                Ok(None) => Err(anyhow::anyhow!("the enrollment token is not valid")),
+                Err(e) => Err(e),
+            },
+            // the thread serving the client checked the challenge
+            Proof::Dns(_) => self.enroll(pk),
+        };
+        let enrollment = self.enrollment.as_ref().unwrap();
+        match res.map(|fp| (fp, enrollment.bundle())) {
+            Ok((_, Ok(bundle))) => Verdict::Enrolled(bundle),
+            Ok((fp, Err(e))) => Verdict::Refused(format!("{fp} was enrolled, but {e:#}")),
+            Err(e) => Verdict::Refused(format!("{e:#}")),
+        }
+    }
+
+    /// Add the peer with the public key `pk`, which enrolled
+    fn enroll(&mut self, pk: SPk) -> anyhow::Result<Fingerprint> {
+        let fp = Fingerprint::of_public_key(&pk)?;
+        ensure!(self.peer_status(fp)?.is_none(), "peer {fp} exists already");
+        let enrollment = self.enrollment.as_mut().unwrap();
+        let (json, pqpk) = api::files(&enrollment.directory, &fp);
+        fs::write(&pqpk, pk.secret()).with_context(|| format!("could not write {pqpk:?}"))?;
+        api::write_settings(&json, &pqpk, Default::default())?;
+        let res = (enrollment.reload)().and_then(|peers| {
+            let cfg_peer = peers
+                .into_iter()
+                .find(|peer| peer.public_key == pqpk)
+                .with_context(|| format!("the config no longer loads enrolled peer {pqpk:?}"))?;
+            self.add_configured_peer(cfg_peer, pk)
+        });
+        if let Err(e) = res {
+            let _ = fs::remove_file(&json);
+            let _ = fs::remove_file(&pqpk);
+            return Err(e);
+        }
+        info!("Peer {fp} enrolled");
+        Ok(fp)
+    }
+
+    /// The status of the peer with the fingerprint `fp`, if there is one
+    fn peer_status(&self, fp: Fingerprint) -> anyhow::Result<Option<PeerStatus>> {
+        let fp = fp.to_string();
+        Ok(self.status(None)?.into_iter().find(|s| s.fingerprint == fp))
+    }
+
+    /// The peer added through the api with the fingerprint `fp`, or the
+    /// reply telling there is none
+    fn api_peer(&self, fp: Fingerprint) -> anyhow::Result<Result<AppPeerPtr, Response>> {
+        Ok(match self.api.as_ref().unwrap().peers.get(&fp) {
+            Some(&peer) => Ok(peer),
+            None if self.peer_status(fp)?.is_some() => Err(Response::error(
+                409,
+                format!("peer {fp} was not added through the api"),
+            )),
+            None => Err(Response::error(404, format!("no peer {fp}"))),
+        })
+    }
+
+    /// Load the config again and return the settings of the api peer whose
+    /// public key is kept in `pqpk`
+    fn reload_api_peer(&mut self, pqpk: &Path) -> anyhow::Result<RosenpassPeer> {
+        let api = self.api.as_mut().unwrap();
+        let peers = (api.reload)()?;
+        peers
+            .into_iter()
+            .find(|peer| peer.public_key == pqpk)
+            .with_context(|| format!("the config no longer loads api peer {pqpk:?}"))
+    }
+
+    /// Add the api peer with the fingerprint `fp` as the config has it
+    fn add_api_peer(&mut self, fp: Fingerprint, pqpk: &Path) -> anyhow::Result<()> {
+        let cfg_peer = self.reload_api_peer(pqpk)?;
+        let peer = self.add_configured_peer(cfg_peer, SPk::load(pqpk)?)?;
+        info!("Added peer {fp} through the api");
+        self.api.as_mut().unwrap().peers.insert(fp, peer);
+        Ok(())
+    }
+
+    /// What `peer` is called in logs and reports: its name if it has one,
+    /// else its fingerprint; see [crate::fingerprint]
+    pub fn peer_name(&self, peer: AppPeerPtr) -> String {
+        if let Some(name) = peer.get_app(self).name.as_ref() {
+            return name.clone();
+        }
+        peer.lower()
+            .get(&self.crypt)
+            .pidt()
+            .map_or("?".to_string(), |id| {
+                Fingerprint::from_peer_id(&id).to_string()
+            })
+    }
+
+    /// Send `alert` about `peer` to the webhooks, if there are any
+    fn alert(&self, mut alert: Alert, peer: Option<AppPeerPtr>) {
+        let Some(alerter) = self.alerts.as_ref() else {
+            return;
+        };
+        if let Some(peer) = peer {
+            if let Ok(id) = peer.lower().get(&self.crypt).pidt() {
+                alert.peer = Some(Fingerprint::from_peer_id(&id).to_string());
+                alert.peer_id = Some(fmt_b64(&*id).to_string());
+            }
+            alert.name = peer.get_app(self).name.clone();
+            alert.tags = peer.get_app(self).tags.clone();
+        }
+        alerter.send(alert);
+    }
+
+    /// Append `event` to the audit log, if there is one
+    fn audit(&self, event: AuditEvent, peer: Option<AppPeerPtr>, detail: Option<String>) {
+        let Some(log) = self.audit.as_ref() else {
+            return;
+        };
+        let peer = peer
+            .and_then(|p| p.lower().get(&self.crypt).pidt().ok())
+            .map(|id| Fingerprint::from_peer_id(&id).to_string());
+        if let Err(e) = log.record(event, peer, detail) {
+            error!("could not write the audit log: {e:#}");
+        }
+    }
+
+    /// Follow the events of the peers, see [crate::events]
+    pub fn subscribe(&self) -> EventStream {
+        self.subscribers.subscribe()
+    }
+
+    /// Whether another instance is active in our place
+    pub fn standby(&self) -> bool {
+        self.ha.as_ref().is_some_and(|ha| !ha.active())
+    }
+
+    /// Announce ourselves and look for peers on the local network, see
+    /// [crate::mdns]
+    pub fn enable_mdns(&mut self) -> anyhow::Result<()> {
+        let mut mdns = Mdns::bind()?;
+        self.mio_poll
+            .registry()
+            .register(&mut mdns.socket, MDNS_TOKEN, Interest::READABLE)?;
+        self.mdns = Some(mdns);
+        Ok(())
+    }
+
+    /// Ask `servers` for the reflexive addresses of our sockets, see
+    /// [crate::nat]
+    pub fn enable_stun(&mut self, servers: Vec<SocketAddr>) {
+        self.stun = Some(Stun::new(servers, self.listen_sockets));
+    }
+
+    /// Answer InitHello messages on `count` worker threads instead of the event loop
+    ///
+    /// The workers work with copies of the peers, which [Self::replicate]
+    /// keeps up to date.
+    pub fn start_handshake_workers(
+        &mut self,
+        count: usize,
+        sched: &ThreadScheduling,
+    ) -> anyhow::Result<()> {
+        let waker = self.waker.clone();
+        self.workers = Some(HandshakeWorkers::spawn(count, &self.crypt, waker, sched)?);
+        Ok(())
+    }
+
+    /// Make `change`, just made to the peers of [Self::crypt], to those of
+    /// the handshake workers too
+    fn replicate(&self, change: PeerChange) -> anyhow::Result<()> {
+        match self.workers.as_ref() {
+            Some(workers) => workers.replicate(change),
+            None => Ok(()),
+        }
+    }
+
+    /// Trade timer accuracy for fewer wakeups, for devices running on battery
+    ///
+    /// Timers are rounded up to [LOW_POWER_TIMER_GRANULARITY], so those due
+    /// around the same time are handled together, and retransmissions are
+    /// spread over a wider window to give them more chances to coincide.
+    pub fn set_low_power(&mut self, low_power: bool) {
+        self.low_power = low_power;
+        self.crypt.retransmit_jitter = match low_power {
+            true => LOW_POWER_RETRANSMIT_JITTER,
+            false => RETRANSMIT_DELAY_JITTER,
+        };
+    }
+
+    /// Serve all clients currently waiting on the control socket
+    ///
+    /// Errors are confined to the connection they occurred on, so a
+    /// misbehaving client cannot take down the key exchange.
+    pub fn handle_control_connections(&mut self) -> anyhow::Result<()> {
+        while let Some(stream) = match self.control.as_ref() {
+            Some(ctl) => ctl.accept()?,
+            None => None,
+        } {
+            let reply = match control::read_command(&stream) {
+                Ok(ControlCommand::Healthcheck) => serde_json::to_string(&self.healthcheck())?,
+                Ok(ControlCommand::Status { tag }) => {
+                    serde_json::to_string(&self.status(tag.as_deref())?)?
+                }
+                Ok(ControlCommand::Stats { tag }) => {
+                    self.settle_quotas();
+                    serde_json::to_string(&self.stats(tag.as_deref())?)?
+                }
+                Ok(ControlCommand::Rekey { peer }) => match self.rekey(&peer, "control socket") {
+                    Ok(report) => serde_json::to_string(&report)?,
+                    Err(e) => serde_json::json!({ "error": e.to_string() }).to_string(),
+                },
+                Ok(ControlCommand::Upgrade { binary }) => {
+                    match binary.or_else(|| upgrade::started_from().map(Path::to_owned)) {
+                        Some(binary) if self.upgrades => {
+                            let reply = serde_json::json!({ "upgrading": binary }).to_string();
+                            self.upgrade_to = Some(binary);
+                            reply
+                        }
+                        _ => serde_json::json!({ "error": "this instance can not be upgraded" })
+                            .to_string(),
+                    }
+                }
+                Ok(ControlCommand::Reload) => match self.reload.as_ref().map(Trigger::pull) {
+                    Some(Ok(())) => serde_json::json!({ "reloading": true }).to_string(),
+                    Some(Err(e)) => serde_json::json!({ "error": e.to_string() }).to_string(),
+                    None => serde_json::json!({
+                        "error": "this instance can only load a config file again"
+                    })
+                    .to_string(),
+                },
+                Err(e) => serde_json::json!({ "error": e.to_string() }).to_string(),
+            };
+            if let Err(e) = writeln!(&stream, "{reply}") {
+                warn!("could not answer on the control socket: {e}");
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the state of this instance against [Self::health_policy]
+    pub fn healthcheck(&self) -> HealthReport {
+        let mut problems = vec![];
+
+        let now = self.crypt.timebase.now();
+        let max_age = self.health_policy.max_key_age;
+        // removed peers are gone for good
+        let present = (0..self.peers.len()).filter(|&no| !self.peers[no].removed);
+        let fresh = present
+            .clone()
+            .filter_map(|no| PeerPtr(no).session().get(&self.crypt).as_ref())
+            .filter(|ses| !matches!(max_age, Some(age) if now - ses.created_at > age))
+            .count();
+        let total = present.count();
+        match self.health_policy.fresh_keys {
+            FreshKeys::Any if total > 0 && fresh == 0 => {
+                problems.push("no peer has a fresh key".to_string())
+            }
+            FreshKeys::All if fresh < total => problems.push(format!(
+                "{} of {total} peers have no fresh key",
+                total - fresh
+            )),
+            _ => {}
+        }
+
+        for (no, ap) in self.peers.iter().enumerate() {
+            for c in ap.consumers.iter().filter(|c| c.state.failed.load(SeqCst)) {
+                problems.push(format!(
+                    "the key of peer {} could not be passed to {}",
+                    self.peer_name(AppPeerPtr(no)),
+                    c.consumer.target()
+                ));
+            }
+        }
+
+        let mut devices: Vec<Device> = self
+            .peers
+            .iter()
+            .filter_map(|p| p.outwg.as_ref())
+            .map(|wg| Device {
+                dev: wg.dev.clone(),
+                uapi_socket: wg.uapi_socket.clone(),
+                netns: wg.netns.clone(),
+            })
+            .collect();
+        devices.sort_unstable();
+        devices.dedup();
+        for device in self.device_checks.unreachable(devices) {
+            problems.push(format!("wireguard device {} is not reachable", device.dev));
+        }
+
+        HealthReport::from_problems(problems)
+    }
+
+    /// Initiate handshakes right away with the peers `selector` names, see
+    /// [crate::rekey]; `why` is recorded in the audit log
+    pub fn rekey(&mut self, selector: &str, why: &str) -> anyhow::Result<RekeyReport> {
+        ensure!(
+            !self.standby(),
+            "standing by, the active instance has to rekey"
+        );
+        let mut report = RekeyReport::default();
+        for no in 0..self.peers.len() {
+            let peer = AppPeerPtr(no);
+            let id = peer.lower().get(&self.crypt).pidt()?;
+            let ap = peer.get_app(self);
+            if !rekey::selects(selector, &id, ap.name.as_deref()) {
+                continue;
+            }
+            let fingerprint = self.peer_name(peer);
+            if ap.endpoint().is_none() && ap.via_relay.is_none() {
+                report.skipped.push(fingerprint);
+                continue;
+            }
+            if !self.pending_initiations.iter().any(|p| p.0 == no) {
+                self.pending_initiations.push(peer);
+            }
+            self.audit(AuditEvent::RekeyRequested, Some(peer), Some(why.to_owned()));
+            report.initiated.push(fingerprint);
+        }
+        ensure!(
+            !report.initiated.is_empty() || !report.skipped.is_empty(),
+            "no peer {selector:?}"
+        );
+        Ok(report)
+    }
+
+    /// Describe every peer, or only those tagged with `tag`
+    pub fn status(&self, tag: Option<&str>) -> anyhow::Result<Vec<PeerStatus>> {
+        let now = self.crypt.timebase.now();
+        let mut status = vec![];
+        for (no, ap) in self.peers.iter().enumerate() {
+            if matches!(tag, Some(tag) if !ap.tags.iter().any(|t| t == tag)) {
+                continue;
+            }
+            // removed peers are gone but for their slot
+            if ap.removed {
+                continue;
+            }
+            let peer = PeerPtr(no);
+            let peer_id = peer.get(&self.crypt).pidt()?;
+            status.push(PeerStatus {
+                peer_id: fmt_b64(&*peer_id).to_string(),
+                fingerprint: Fingerprint::from_peer_id(&peer_id).to_string(),
+                name: ap.name.clone(),
+                tags: ap.tags.clone(),
+                endpoints: ap
+                    .endpoint()
+                    .map(|ep| ep.addresses().to_vec())
+                    .unwrap_or_default(),
+                key_age: peer
+                    .session()
+                    .get(&self.crypt)
+                    .as_ref()
+                    .map(|ses| now - ses.created_at),
+                dead: ap.dead,
+                unreachable: ap.probe_at.is_some(),
+                relayed: ap.via_relay.is_some(),
+            });
+        }
+        Ok(status)
+    }
+
+    /// Statistics of every peer, or only of those tagged with `tag`
+    pub fn stats(&self, tag: Option<&str>) -> anyhow::Result<StatsReport> {
+        let mut peers = vec![];
+        for (no, ap) in self.peers.iter().enumerate() {
+            if matches!(tag, Some(tag) if !ap.tags.iter().any(|t| t == tag)) {
+                continue;
+            }
+            let peer_id = PeerPtr(no).get(&self.crypt).pidt()?;
+            peers.push(PeerStatsEntry {
+                peer_id: fmt_b64(&*peer_id).to_string(),
+                fingerprint: Fingerprint::from_peer_id(&peer_id).to_string(),
+                name: ap.name.clone(),
+                tags: ap.tags.clone(),
+                stats: PeerStats {
+                    psk_apply_failures: ap
+                        .consumers
+                        .iter()
+                        .map(|c| c.state.failures.load(SeqCst))
+                        .sum(),
+                    ..ap.stats.clone()
+                },
+            });
+        }
+        Ok(StatsReport {
+            peers,
+            unattributed_failures: self.unattributed_failures.clone(),
+            wakeups: self.wakeups,
+            clock_jumps: self.clock_jumps,
+            reflexive_addresses: self
+                .stun
+                .iter()
+                .flat_map(|s| s.reflexive.iter().flatten().copied())
+                .collect(),
+            quotas: self
+                .quotas
+                .usage()
+                .into_iter()
+                .filter(|q| tag.is_none() || q.tag.as_deref() == tag)
+                .collect(),
+            shed: self.shedder.as_ref().map(|s| s.counts).unwrap_or_default(),
+        })
+    }
+
+    /// The peer currently sending from or being looked for at `addr`
+    fn peer_at(&self, addr: &SocketAddr) -> Option<AppPeerPtr> {
+        self.peers
+            .iter()
+            .position(|p| matches!(p.endpoint(), Some(ep) if ep.addresses().contains(addr)))
+            .map(AppPeerPtr)
+    }
+
+    pub fn add_peer(
+        &mut self,
+        psk: Option<SymKey>,
+        pk: impl Into<SharedPk>,
+        outfile: Option<PathBuf>,
+        outwg: Option<WireguardOut>,
+        hostname: Option<String>,
+        tags: Vec<String>,
+    ) -> anyhow::Result<AppPeerPtr> {
+        let PeerPtr(pn) = self.crypt.add_peer(psk, pk)?;
+        assert!(pn == self.peers.len());
+        self.replicate(PeerChange::added(&self.crypt, PeerPtr(pn)))?;
+        // SRV records are looked up in the background, starting right away
+        let (hostname, srv_name) = match hostname {
+            Some(h) if h.starts_with(dns::PREFIX) => {
+                (None, Some(h[dns::PREFIX.len()..].to_owned()))
+            }
+            h => (h, None),
+        };
+        let initial_endpoint = match hostname {
+            Some(h) if h.starts_with(unix::PREFIX) => {
+                let path = Path::new(&h[unix::PREFIX.len()..]);
+                Some(Endpoint::discovery_from_addresses(vec![unix::address_of(
+                    path,
+                )]))
+            }
+            #[cfg(feature = "quic")]
+            Some(h) if h.starts_with(quic::PREFIX) => Some(Endpoint::discovery_from_addresses(
+                vec![quic::address_of(&h[quic::PREFIX.len()..])?],
+            )),
+            h => h.map(Endpoint::discovery_from_hostname).transpose()?,
+        };
+        let current_endpoint = None;
+        let fifo = outfile
+            .as_ref()
+            .filter(|of| fifo::is_fifo(of))
+            .map(|of| FifoOut::start(of.clone()));
+        let consumers = outwg.iter().cloned().map(Consumer::new).collect();
+        self.peers.push(AppPeer {
+            outfile,
+            fifo,
+            outwg,
+            consumers,
+            initial_endpoint,
+            current_endpoint,
+            tags,
+            srv_name,
+            ..Default::default()
+        });
+        self.audit(AuditEvent::PeerAdded, Some(AppPeerPtr(pn)), None);
+        self.reschedule(AppPeerPtr(pn));
+        Ok(AppPeerPtr(pn))
+    }
+
+    /// Add a peer with the settings it has in the config, and its public key
+    pub fn add_configured_peer(
+        &mut self,
+        cfg_peer: RosenpassPeer,
+        pk: impl Into<SharedPk>,
+    ) -> anyhow::Result<AppPeerPtr> {
+        let (key_out, stream_keys) = match cfg_peer.key_out {
+            Some(of) if of == Path::new(keystream::STDOUT) => (None, true),
+            of => (of, false),
+        };
+        let fallbacks = failover::resolve(&cfg_peer.fallback_endpoints)?;
+        let psk = cfg_peer
+            .pre_shared_key
+            .map(SymKey::load_b64)
+            .transpose()
+            .failure(Failure::Key)?;
+        let peer = self.add_peer(
+            psk,
+            pk,
+            key_out,
+            cfg_peer.wg.map(|cfg| WireguardOut {
+                dev: cfg.device,
+                pk: cfg.peer,
+                extra_params: cfg.extra_params,
+                uapi_socket: cfg.uapi_socket,
+                netns: cfg.device_netns,
+            }),
+            cfg_peer.endpoint,
+            cfg_peer.tags,
+        )?;
+        if let Some(old) = cfg_peer.old_public_key.as_ref() {
+            let window = cfg_peer.rollover_window.unwrap_or(ROLLOVER_WINDOW) as f64;
+            let old = SharedPk::from(SPk::load(old).failure(Failure::Key)?);
+            self.crypt
+                .add_rollover_key(peer.lower(), old.clone(), window)?;
+            self.replicate(PeerChange::Rollover {
+                peer: peer.lower(),
+                old,
+                window,
+            })?;
+        }
+        let ap = peer.get_app_mut(self);
+        if !fallbacks.is_empty() || cfg_peer.happy_eyeballs {
+            let mut addrs = ap
+                .initial_endpoint
+                .as_ref()
+                .map_or(vec![], |e| e.addresses().to_vec());
+            addrs.extend(fallbacks);
+            let addrs = failover::order(addrs, cfg_peer.happy_eyeballs);
+            ap.initial_endpoint = Some(Endpoint::discovery_from_addresses(addrs));
+        }
+        ap.consumers.extend(cfg_peer.xfrm.map(Consumer::new));
+        ap.name = cfg_peer.name;
+        ap.stream_keys = stream_keys;
+        ap.exports = cfg_peer.exports;
+        ap.fragment = cfg_peer.fragment;
+        ap.keepalive = cfg_peer.keepalive.map(|secs| secs as f64);
+        ap.lock_endpoint = cfg_peer.lock_endpoint;
+        ap.stale_key = cfg_peer.stale_key;
+        ap.hole_punching = cfg_peer.hole_punching;
+        ap.rendezvous = cfg_peer.rendezvous;
+        ap.relay = cfg_peer.relay;
+        ap.allowed_sources = cfg_peer
+            .allowed_sources
+            .iter()
+            .map(|src| src.parse())
+            .collect::<anyhow::Result<_>>()?;
+        if let Some(name) = cfg_peer.interface.as_ref() {
+            let netns = self.netns.clone();
+            netns::within(netns.as_deref(), || self.set_peer_interface(peer, name))
+                .failure(Failure::Bind)?;
+        }
+        // stdout carries the keys alone
+        if stream_keys {
+            self.key_output_to_log = true;
+        }
+        Ok(peer)
+    }
+
+    pub fn listen_loop(&mut self) -> anyhow::Result<()> {
+        const INIT_SLEEP: f64 = 0.01;
+        const MAX_FAILURES: i32 = 10;
+        let mut failure_cnt = 0;
+
+        loop {
+            let msgs_processed = 0usize;
+            let err = match self.event_loop() {
+                Ok(()) => return Ok(()),
+                Err(e) => e,
+            };
+
+            // This should not happen…
+            failure_cnt = if msgs_processed > 0 {
+                0
+            } else {
+                failure_cnt + 1
+            };
+            let sleep = INIT_SLEEP * 2.0f64.powf(f64::from(failure_cnt - 1));
+            let tries_left = MAX_FAILURES - (failure_cnt - 1);
+            error!(
+                "unexpected error after processing {} messages: {:?} {}",
+                msgs_processed,
+                err,
+                err.backtrace()
+            );
+            if tries_left > 0 {
+                error!("re-initializing networking in {sleep}! {tries_left} tries left.");
+                std::thread::sleep(self.crypt.timebase.dur(sleep));
+                continue;
+            }
+
+            bail!("too many network failures");
+        }
+    }
+
+    pub fn event_loop(&mut self) -> anyhow::Result<()> {
+        let (mut rx, mut tx) = (MsgBuf::zero(), MsgBuf::zero());
+        loop {
+            let ev = self.poll(&mut *rx)?;
+            if !self.process_event(ev, &mut rx, &mut tx)? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Act on `ev`, as returned by [AppServer::poll] with the message in `rx`,
+    /// using `tx` for the messages sent in turn; returns false once the event
+    /// loop is to be left
+    pub fn process_event(
+        &mut self,
+        ev: AppPollResult,
+        rx: &mut MsgBuf,
+        tx: &mut MsgBuf,
+    ) -> anyhow::Result<bool> {
+        /// if socket address for peer is known, call closure
+        /// assumes that closure leaves a message in `tx`
+        /// assumes that closure returns the length of message in bytes
+        macro_rules! tx_maybe_with {
+            ($peer:expr, $fn:expr) => {
+                attempt!({
+                    let p = $peer;
+                    let ap = p.get_app(self);
+                    if ap.endpoint().is_some() || ap.via_relay.is_some() {
+                        let len = $fn()?;
+                        self.send_to_peer(p, &tx[..len])?;
+                    }
+                    Ok(())
+                })
+            };
+        }
+
+        use crate::protocol::HandleMsgResult;
+        use AppPollResult::*;
+        use KeyOutputReason::*;
+        match ev {
+            // the active instance handles the peers
+            SendInitiation(_) | SendRetransmission(_) | ReceivedMessage(..) | HandshakeDone(_)
+                if self.standby() => {}
+
+            // revoked and removed peers are left alone
+            SendInitiation(peer) | SendRetransmission(peer)
+                if peer.get_app(self).revoked || peer.get_app(self).removed => {}
+            // unreachable peers are only probed with single InitHellos
+            SendRetransmission(peer) if peer.get_app(self).probe_at.is_some() => {}
+
+            SendInitiation(peer) => {
+                let now = self.crypt.timebase.now();
+                let wall = now + self.crypt.timebase.wall_clock_offset();
+                let has_session = peer.lower().session().get(&self.crypt).is_some();
+                let breaker = self.circuit_breaker.clone();
+                let ap = peer.get_app_mut(self);
+                match ap.start_at {
+                    Some(at) if !has_happened(at, now) => return Ok(true),
+                    _ => ap.start_at = None,
+                }
+                // with a key, the mappings of the NATs on the way are open already
+                match ap.punch_at {
+                    _ if !ap.hole_punching || has_session => ap.punch_at = None,
+                    Some(at) if has_happened(at, now) => ap.punch_at = None,
+                    Some(_) => return Ok(true),
+                    None => {
+                        ap.punch_at = Some(now + nat::punch_delay(wall));
+                        return Ok(true);
+                    }
+                }
+                match ap.probe_at {
+                    Some(at) if !has_happened(at, now) => return Ok(true),
+                    Some(_) => ap.probe_at = Some(now + breaker.probe_interval()),
+                    None => {}
+                }
+                // initiations over quota wait for their turn
+                if ap.quota_at.is_some_and(|at| !has_happened(at, now)) {
+                    return Ok(true);
+                }
+                ap.quota_at = None;
+                if !has_session && self.under_load(now) {
+                    let name = self.peer_name(peer);
+                    debug!("Deferring a handshake with peer {name} under load");
+                    peer.get_app_mut(self).quota_at = Some(now + shed::RETRY_INTERVAL);
+                    if let Some(shedder) = self.shedder.as_mut() {
+                        shedder.counts.initiations += 1;
+                    }
+                    return Ok(true);
+                }
+                let ap = peer.get_app_mut(self);
+                let tags = ap.tags.clone();
+                self.settle_quotas();
+                if let Err(resource) = self.quotas.admit_initiation(peer, &tags, now) {
+                    let name = self.peer_name(peer);
+                    debug!("Deferring a handshake with peer {name} over the {resource} quota");
+                    peer.get_app_mut(self).quota_at = Some(now + quota::RETRY_INTERVAL);
+                    return Ok(true);
+                }
+                let ap = peer.get_app_mut(self);
+                // peers without an endpoint are not sent anything to answer
+                let sent = ap.endpoint().is_some() || ap.via_relay.is_some();
+                if ap.handshake_started.is_some() && sent {
+                    ap.unanswered += 1;
+                    // try the other endpoints again, starting from the one which worked last
+                    if let Some(Endpoint::SocketBoundAddress { .. }) = ap.current_endpoint {
+                        if ap
+                            .initial_endpoint
+                            .as_ref()
+                            .is_some_and(|e| e.addresses().len() > 1)
+                        {
+                            ap.current_endpoint = Endpoint::discovery_from_multiple_sources(
+                                ap.current_endpoint.as_ref(),
+                                ap.initial_endpoint.as_ref(),
+                            );
+                        }
+                    }
+                    if ap.probe_at.is_none() && breaker.trips(ap.unanswered) {
+                        ap.probe_at = Some(now + breaker.probe_interval());
+                        let last_exchange = ap.last_exchange;
+                        self.notify_liveness(peer, Liveness::Unreachable, last_exchange)?;
+                        return Ok(true);
+                    }
+                }
+                self.consider_relay(peer, now)?;
+                let ap = peer.get_app_mut(self);
+                ap.stats.handshakes_initiated += 1;
+                ap.handshake_started = Some(now);
+                #[allow(clippy::redundant_closure_call)]
+                tx_maybe_with!(peer, || self
+                    .crypt
+                    .initiate_handshake(peer.lower(), &mut **tx))?
+            }
+            SendRetransmission(peer) => {
+                let now = self.crypt.timebase.now();
+                self.consider_relay(peer, now)?;
+                peer.get_app_mut(self).stats.retransmissions += 1;
+                #[allow(clippy::redundant_closure_call)]
+                tx_maybe_with!(peer, || self
+                    .crypt
+                    .retransmit_handshake(peer.lower(), &mut **tx))?
+            }
+            SendKeepalive(peer) => {
+                let now = self.crypt.timebase.now();
+                let ap = peer.get_app_mut(self);
+                ap.keepalive_at = now + ap.keepalive.unwrap_or(UNENDING);
+                // keepalives are authenticated with the session, so there must be one
+                if peer.lower().session().get(&self.crypt).is_some() {
+                    #[allow(clippy::redundant_closure_call)]
+                    tx_maybe_with!(peer, || self.crypt.keepalive(peer.lower(), &mut **tx))?
+                }
+            }
+            PeerDead(peer) => {
+                let ap = peer.get_app_mut(self);
+                ap.dead = true;
+                let last_exchange = ap.last_exchange;
+                self.notify_liveness(peer, Liveness::Dead, last_exchange)?;
+                if self.dead_peer.as_ref().is_some_and(|p| p.reset_psk) {
+                    self.output_key(peer, Stale, &SymKey::random())?;
+                }
+            }
+            EraseKey(peer) => {
+                peer.get_app_mut(self).erase_at = None;
+                self.output_key(peer, Stale, &SymKey::random())?;
+            }
+            DeleteKey(peer) => {
+                let now = self.crypt.timebase.now();
+                let name = self.peer_name(peer);
+                match peer.get_app(self).stale_key.grace(self.crypt.rekey_after()) {
+                    Some(grace) if grace <= 0.0 => {
+                        self.output_key(peer, Stale, &SymKey::random())?
+                    }
+                    Some(grace) => {
+                        warn!("The key of peer {name} expired, keeping it for another {grace}s");
+                        peer.get_app_mut(self).erase_at = Some(now + grace);
+                    }
+                    None => warn!("The key of peer {name} expired, keeping it until the next one"),
+                }
+                peer.get_app_mut(self)
+                    .stats
+                    .failure(FailureCause::KeyExpired);
+
+                // There was a loss of connection apparently; restart host discovery
+                // starting from the last used address but including all the initially
+                // specified addresses
+                // TODO: We could do this preemptively, before any connection loss actually occurs.
+                let p = peer.get_app_mut(self);
+                p.current_endpoint = Endpoint::discovery_from_multiple_sources(
+                    p.current_endpoint.as_ref(),
+                    p.initial_endpoint.as_ref(),
+                );
+                // the responder pool may have changed as well
+                p.resolve_at = now;
+                // and the peer may be reachable directly again
+                p.via_relay = None;
+            }
+
+            MdnsAnnounce => {
+                let now = self.crypt.timebase.now();
+                if let Some(mdns) = self.mdns.as_mut() {
+                    mdns.announce_at = now + mdns::ANNOUNCE_INTERVAL;
+                }
+                let me = Fingerprint::from_peer_id(&self.crypt.pidm()?);
+                let port = self.sockets[0].local_addr()?.port();
+                let wall = now + self.crypt.timebase.wall_clock_offset();
+                let psks: Vec<&SymKey> = (0..self.peers.len())
+                    .filter(|&no| !self.peers[no].removed && !self.peers[no].revoked)
+                    .filter_map(|no| self.psk_of(AppPeerPtr(no)))
+                    .collect();
+                if let Some(mdns) = self.mdns.as_ref() {
+                    if let Err(e) = mdns.announce(&me, port, &psks, wall) {
+                        warn!("could not announce ourselves with multicast DNS: {e:#}");
+                    }
+                }
+            }
+
+            StunRequest => {
+                let now = self.crypt.timebase.now();
+                if let Some(stun) = self.stun.as_mut() {
+                    stun.request_at = now + nat::STUN_INTERVAL;
+                    // answers to the previous requests are not waited for any longer
+                    stun.pending.clear();
+                    for (no, sock) in self.sockets[..self.listen_sockets].iter().enumerate() {
+                        if sock.is_unix() || sock.is_quic() {
+                            continue;
+                        }
+                        let v6 = sock.local_addr()?.is_ipv6();
+                        for server in stun.servers.iter().filter(|s| s.is_ipv6() == v6) {
+                            let mut txid = [0u8; 12];
+                            rosenpass_sodium::helpers::randombytes_buf(&mut txid);
+                            let req = nat::encode_binding_request(&txid);
+                            match sock.send_to(&req, *server) {
+                                Ok(_) => stun.pending.push((txid, no)),
+                                Err(e) => warn!("could not reach STUN server {server}: {e}"),
+                            }
+                        }
+                    }
+                }
+            }
+
+            #[cfg(feature = "quic")]
+            QuicTimeout => {
+                for sock in self.sockets.iter() {
+                    if let Socket::Quic(sock) = sock {
+                        sock.on_timeout();
+                    }
+                }
+            }
+            #[cfg(not(feature = "quic"))]
+            QuicTimeout => {}
+
+            RendezvousUpdate(server) => {
+                let now = self.crypt.timebase.now();
+                server.get_app_mut(self).rendezvous_at = now + rendezvous::REGISTER_INTERVAL;
+                // the server only listens to peers it shares a session with
+                if server.lower().session().get(&self.crypt).is_some() {
+                    self.send_rendezvous(server, RendezvousMsg::Register, None)?;
+                    for no in 0..self.peers.len() {
+                        let (peer, ap) = (AppPeerPtr(no), &self.peers[no]);
+                        // peers we have a key with can be reached already
+                        let lost = !ap.rendezvous
+                            && !ap.locked()
+                            && peer.lower().session().get(&self.crypt).is_none();
+                        if lost {
+                            let id = peer.lower().get(&self.crypt).pidt()?.value;
+                            self.send_rendezvous(server, RendezvousMsg::Query(id), None)?;
+                        }
+                    }
+                }
+            }
+
+            ResolveEndpoint(peer) => {
+                let name = peer.get_app(self).srv_name.clone().unwrap_or_default();
+                let (tx, rx) = std::sync::mpsc::channel();
+                let waker = self.waker.clone();
+                thread::spawn(move || {
+                    let _ = tx.send(dns::resolve(&name));
+                    let _ = waker.wake();
+                });
+                peer.get_app_mut(self).resolving = Some(rx);
+                self.lookups.push(peer);
+            }
+
+            RevocationsRefreshed(res) => {
+                let revocations = self.revocations.as_mut().unwrap();
+                match res.and_then(|list| revocations.update(list)) {
+                    Ok(()) => self.apply_revocations()?,
+                    Err(e) => warn!("Keeping the revocation list in use: {e:#}"),
+                }
+            }
+
+            PeerStoreChanged(res) => match res {
+                Ok(update) => self.apply_peer_store(update)?,
+                Err(e) => warn!("Keeping the stored peers as they are: {e:#}"),
+            },
+
+            EndpointResolved(peer, res) => {
+                let now = self.crypt.timebase.now();
+                let p = peer.get_app_mut(self);
+                let name = p.srv_name.as_deref().unwrap_or_default();
+                match res {
+                    Ok(addrs) => {
+                        debug!("SRV records of {name} point to {addrs:?}");
+                        p.initial_endpoint = Some(Endpoint::discovery_from_addresses(addrs));
+                        p.current_endpoint = Endpoint::discovery_from_multiple_sources(
+                            p.current_endpoint.as_ref(),
+                            p.initial_endpoint.as_ref(),
+                        );
+                        p.resolve_at = now + UNENDING;
+                        // initiations requested while no endpoint was known went nowhere
+                        if peer.lower().hs().get(&self.crypt).is_none() {
+                            self.pending_initiations.push(peer);
+                        }
+                    }
+                    Err(e) => {
+                        warn!("{e:#}; retrying in {} seconds", dns::RETRY_INTERVAL);
+                        p.resolve_at = now + dns::RETRY_INTERVAL;
+                    }
+                }
+            }
+
+            ReceivedMessage(len, ref endpoint) if nat::is_stun(&rx[..len]) => {
+                let Endpoint::SocketBoundAddress { socket, addr } = endpoint else {
+                    return Ok(true);
+                };
+                let Some(stun) = self.stun.as_mut() else {
+                    return Ok(true);
+                };
+                match stun.handle_response(&rx[..len], socket.0) {
+                    Ok(Some(reflexive)) => {
+                        info!("Reachable at {reflexive} from outside, according to {addr}")
+                    }
+                    Ok(None) => {}
+                    Err(e) => debug!("ignoring STUN message from {addr}: {e:#}"),
+                }
+            }
+
+            ReceivedMessage(mut len, endpoint) => {
+                // relayed fragments are handled like those received directly
+                let relayed = match rx[..len].first() == Some(&(MsgType::Relay as u8)) {
+                    false => None,
+                    true => match self.handle_relay(rx, &mut len) {
+                        Ok(Some(relay)) => Some(relay),
+                        Ok(None) => return Ok(true),
+                        Err(e) => {
+                            self.record_failure(&rx[..len], &e, &endpoint);
+                            return Ok(true);
+                        }
+                    },
+                };
+                // fragments are collected until the message they belong to is complete
+                let fragmented = rx[..len].first() == Some(&(MsgType::Fragment as u8));
+                let res = match fragmented {
+                    false => Ok(true),
+                    true => self.reassemble(rx, &mut len, &endpoint),
+                };
+                let res = match res {
+                    // messages from elsewhere are dropped before changing any state
+                    Ok(true) if !self.check_msg_source(&rx[..len], &endpoint) => Ok(None),
+                    Ok(true)
+                        if rx[0] == MsgType::InitHello as u8 && !self.admit_response(&endpoint) =>
+                    {
+                        Ok(None)
+                    }
+                    Ok(true) if rx[0] == MsgType::Rendezvous as u8 => {
+                        self.handle_rendezvous(&rx[..len], &endpoint).map(|_| None)
+                    }
+                    Ok(true) => match (&mut self.workers, &endpoint) {
+                        // answering an InitHello is expensive; let the workers do it
+                        (Some(workers), Endpoint::SocketBoundAddress { socket, addr })
+                            if rx[0] == MsgType::InitHello as u8 && relayed.is_none() =>
+                        {
+                            workers
+                                .submit(&mut self.crypt, &rx[..len], socket.0, *addr, fragmented)
+                                .map(|_| None)
+                        }
+                        _ => self.crypt.handle_msg(&rx[..len], &mut **tx).map(Some),
+                    },
+                    Ok(false) => Ok(None),
+                    Err(e) => Err(e),
+                };
+                if let Ok(Some(r)) = &res {
+                    self.reschedule(AppPeerPtr::lift(r.peer));
+                }
+                match res {
+                    Ok(None) => {}
+                    Err(ref e) => self.record_failure(&rx[..len], e, &endpoint),
+
+                    // the sender of an InitHello is only known after handling it
+                    Ok(Some(HandleMsgResult { peer, .. }))
+                        if !self.check_source(peer, &endpoint) => {}
+
+                    Ok(Some(HandleMsgResult {
+                        peer,
+                        resp,
+                        exchanged_with,
+                        ..
+                    })) => {
+                        if let Some(len) = resp {
+                            let peer = AppPeerPtr::lift(peer);
+                            match relayed {
+                                Some(relay) => self.send_relayed(relay, peer, &tx[0..len])?,
+                                None => self.send_maybe_fragmented(
+                                    &endpoint,
+                                    &tx[0..len],
+                                    peer,
+                                    fragmented,
+                                )?,
+                            }
+                        }
+
+                        if let Some(p) = exchanged_with {
+                            let ap = AppPeerPtr::lift(p);
+                            let now = self.crypt.timebase.now();
+                            // the responder learns of the exchange from the InitConf,
+                            // the initiator from what the responder sends after it
+                            let initiator = rx[0] != MsgType::InitConf as u8;
+                            let app = ap.get_app_mut(self);
+                            // register right away, which also tells the server where we are
+                            if app.rendezvous {
+                                app.rendezvous_at = now;
+                            }
+                            // later messages take the same way, and the
+                            // relay is not where the peer is
+                            app.via_relay = relayed;
+                            // locked down peers do not roam
+                            let roams = !app.locked() || app.initial_endpoint.is_none();
+                            let from = endpoint.addresses().first().copied();
+                            let moved = app
+                                .current_endpoint
+                                .as_ref()
+                                .is_some_and(|ep| ep.addresses().first().copied() != from);
+                            if relayed.is_none() && roams {
+                                app.current_endpoint = Some(endpoint);
+                            }
+                            app.stats.handshakes_completed += 1;
+                            app.failure_streak = 0;
+                            app.unanswered = 0;
+                            app.erase_at = None;
+                            let last_exchange = app.last_exchange.replace(now);
+                            let revived = std::mem::take(&mut app.dead);
+                            let reachable = app.probe_at.take().is_some();
+                            // a completed handshake supersedes our own attempt either way
+                            match app.handshake_started.take() {
+                                Some(started) if initiator => {
+                                    app.stats.handshake_latency.observe(now - started)
+                                }
+                                _ => {}
+                            }
+
+                            let role = match initiator {
+                                true => "initiator",
+                                false => "responder",
+                            };
+                            let via = match (relayed, from) {
+                                (Some(_), _) => " via relay".to_owned(),
+                                (None, Some(addr)) => format!(" from {addr}"),
+                                (None, None) => String::new(),
+                            };
+                            self.audit(
+                                AuditEvent::HandshakeCompleted,
+                                Some(ap),
+                                Some(format!("{role}{via}")),
+                            );
+                            if moved && relayed.is_none() && roams {
+                                let to = from.map(|a| a.to_string());
+                                self.audit(AuditEvent::EndpointChanged, Some(ap), to);
+                            }
+
+                            // TODO: Maybe we should rather call the key "rosenpass output"?
+                            self.output_key(ap, Exchanged, &self.crypt.osk(p)?)?;
+                            // the standby learns about the key right away
+                            if let Err(e) = self.send_ha_sync(&[ap]) {
+                                warn!("could not replicate the key exchange: {e:#}");
+                            }
+                            if revived {
+                                self.notify_liveness(ap, Liveness::Alive, last_exchange)?;
+                            }
+                            if reachable {
+                                self.notify_liveness(ap, Liveness::Reachable, last_exchange)?;
+                            }
+                        }
+                    }
+                }
+            }
+
+            Terminate => {
+                info!("Terminating on signal");
+                return Ok(false);
+            }
+
+            HaSync => {
+                let now = self.crypt.timebase.now();
+                let peers = self.peers.len();
+                let Some(ha) = self.ha.as_mut() else {
+                    return Ok(true);
+                };
+                ha.sync_at = now + ha::SYNC_INTERVAL;
+                // every peer is sent within a full sync interval
+                let per_sync =
+                    peers.div_ceil((ha::FULL_SYNC_INTERVAL / ha::SYNC_INTERVAL) as usize);
+                let first = ha.next_peer;
+                ha.next_peer = (first + per_sync) % peers.max(1);
+                let batch = (first..first + per_sync)
+                    .map(|no| AppPeerPtr(no % peers))
+                    .collect::<Vec<_>>();
+                self.send_ha_sync(&batch)?;
+            }
+
+            HaTakeover => {
+                let now = self.crypt.timebase.now();
+                if let Some(ha) = self.ha.as_mut() {
+                    warn!(
+                        "Taking over, nothing heard from {} in {} seconds",
+                        ha.partner, ha.takeover_after
+                    );
+                    ha.active_since = Some(now);
+                    ha.sync_at = now;
+                    ha::take_over(&mut self.crypt);
+                    self.audit(AuditEvent::HaActive, None, None);
+                    self.reschedule_all();
+                }
+            }
+
+            HandshakeDone(done) => {
+                let endpoint = Endpoint::SocketBoundAddress {
+                    socket: SocketPtr(done.socket),
+                    addr: done.addr,
+                };
+                if let Ok((peer, _)) = &done.result {
+                    self.reschedule(AppPeerPtr::lift(*peer));
+                }
+                match done.result {
+                    // answered before the revocation reached the workers
+                    Ok((peer, _)) if self.peers[peer.0].revoked || self.peers[peer.0].removed => {}
+                    Ok((peer, _)) if !self.check_source(peer, &endpoint) => {}
+                    Ok((peer, resp)) => self.send_maybe_fragmented(
+                        &endpoint,
+                        &resp,
+                        AppPeerPtr::lift(peer),
+                        done.fragmented,
+                    )?,
+                    Err(ref e) => self.record_failure(&done.msg, e, &endpoint),
+                }
+            }
+        };
+        Ok(true)
+    }
+
+    /// The next event kept track of by the application for `peer` rather
+    /// than by the protocol, and when it is due
+    fn next_peer_event(&self, peer: AppPeerPtr) -> Option<(AppPollResult, Timing)> {
+        use AppPollResult::*;
+        // a standby only waits for the active instance to go silent
+        if self.standby() {
+            return None;
+        }
+        let ap = peer.get_app(self);
+        let keepalive = ap.keepalive.map(|_| (SendKeepalive(peer), ap.keepalive_at));
+        // peers which never exchanged a key are counted from the start, and
+        // none of them from before the last resume, as the peers could not
+        // reach us while the system was suspended
+        let death = self.dead_peer.as_ref().filter(|_| !ap.dead).map(|policy| {
+            let since = ap.last_exchange.unwrap_or(0.0).max(self.resumed_at);
+            let at = since + policy.deadline(self.crypt.rekey_after());
+            (PeerDead(peer), at)
+        });
+        let lookup = (ap.srv_name.is_some() && ap.resolving.is_none())
+            .then_some((ResolveEndpoint(peer), ap.resolve_at));
+        let start = ap.start_at.map(|at| (SendInitiation(peer), at));
+        let punch = ap.punch_at.map(|at| (SendInitiation(peer), at));
+        let probe = ap.probe_at.map(|at| (SendInitiation(peer), at));
+        let quota = ap.quota_at.map(|at| (SendInitiation(peer), at));
+        let erase = ap.erase_at.map(|at| (EraseKey(peer), at));
+        let registration = ap
+            .rendezvous
+            .then_some((RendezvousUpdate(peer), ap.rendezvous_at));
+        keepalive
+            .into_iter()
+            .chain(death)
+            .chain(lookup)
+            .chain(start)
+            .chain(punch)
+            .chain(probe)
+            .chain(quota)
+            .chain(erase)
+            .chain(registration)
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+    }
+
+    /// The next event kept track of by the application for all peers at
+    /// once, and when it is due
+    fn next_app_event(&self) -> Option<(AppPollResult, Timing)> {
+        // a standby only waits for the active instance to go silent
+        if let Some(ha) = self.ha.as_ref().filter(|ha| !ha.active()) {
+            return Some((AppPollResult::HaTakeover, ha.takeover_at));
+        }
+        let announcement = self
+            .mdns
+            .as_ref()
+            .map(|m| (AppPollResult::MdnsAnnounce, m.announce_at));
+        let stun = self
+            .stun
+            .as_ref()
+            .map(|s| (AppPollResult::StunRequest, s.request_at));
+        let sync = self
+            .ha
+            .as_ref()
+            .map(|ha| (AppPollResult::HaSync, ha.sync_at));
+        #[cfg(feature = "quic")]
+        let quic = self
+            .sockets
+            .iter()
+            .filter_map(|sock| match sock {
+                Socket::Quic(sock) => sock.next_timeout(),
+                _ => None,
+            })
+            .min_by(|a, b| a.total_cmp(b))
+            .map(|at| (AppPollResult::QuicTimeout, at));
+        #[cfg(not(feature = "quic"))]
+        let quic = None;
+        announcement
+            .into_iter()
+            .chain(stun)
+            .chain(sync)
+            .chain(quic)
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+    }
+
+    /// Report a change of the [Liveness] of `peer`, which last exchanged a
+    /// key at `last_exchange`
+    fn notify_liveness(
+        &self,
+        peer: AppPeerPtr,
+        event: Liveness,
+        last_exchange: Option<Timing>,
+    ) -> anyhow::Result<()> {
+        let audited = match event {
+            Liveness::Dead => AuditEvent::PeerDead,
+            Liveness::Alive => AuditEvent::PeerAlive,
+            Liveness::Unreachable => AuditEvent::PeerUnreachable,
+            Liveness::Reachable => AuditEvent::PeerReachable,
+        };
+        self.audit(audited, Some(peer), None);
+        let kind = match event {
+            Liveness::Dead => AlertKind::PeerDead,
+            Liveness::Alive => AlertKind::PeerAlive,
+            Liveness::Unreachable => AlertKind::PeerUnreachable,
+            Liveness::Reachable => AlertKind::PeerReachable,
+        };
+        self.alert(Alert::new(kind), Some(peer));
+        match event {
+            Liveness::Unreachable => self.subscribers.send(Event::PeerUnreachable { peer }),
+            Liveness::Reachable => self.subscribers.send(Event::PeerReachable { peer }),
+            Liveness::Dead | Liveness::Alive => {}
+        }
+        let peer_id = peer.lower().get(&self.crypt).pidt()?;
+        let fingerprint = Fingerprint::from_peer_id(&peer_id).to_string();
+        event.log(&self.peer_name(peer));
+        let Some(policy) = self.dead_peer.as_ref() else {
+            return Ok(());
+        };
+        let ap = peer.get_app(self);
+        policy.notify(PeerEvent {
+            event,
+            peer_id: fmt_b64(&*peer_id).to_string(),
+            fingerprint,
+            name: ap.name.clone(),
+            tags: ap.tags.clone(),
+            last_exchange_age: last_exchange.map(|t| self.crypt.timebase.now() - t),
+        });
+        Ok(())
+    }
+
+    /// Count the failure to process `msg` from `endpoint` towards the peer
+    /// sending from there, if any
+    fn record_failure(&mut self, msg: &[u8], e: &anyhow::Error, endpoint: &Endpoint) {
+        self.verbose().then(|| {
+            info!(
+                "error processing incoming message from {:?}: {:?} {}",
+                endpoint,
+                e,
+                e.backtrace()
+            );
+        });
+        let cause = FailureCause::of_rejected(msg, e);
+        let addr = endpoint.addresses().first().copied();
+        self.subscribers.send(Event::HandshakeFailed {
+            peer: addr.and_then(|a| self.peer_at(&a)),
+            from: addr,
+            cause,
+        });
+        match addr.and_then(|a| Some((a, self.peer_at(&a)?))) {
+            Some((addr, ap)) => {
+                let app = ap.get_app_mut(self);
+                app.stats.failure(cause);
+                app.failure_streak += 1;
+                let streak = app.failure_streak;
+                let detail = format!("{cause} from {addr}");
+                self.audit(AuditEvent::HandshakeFailed, Some(ap), Some(detail));
+                if self.alerts.as_ref().map(|a| a.failure_streak) == Some(streak) {
+                    let mut alert = Alert::new(AlertKind::HandshakeFailures);
+                    alert.count = Some(streak.into());
+                    alert.detail = Some(format!("last {cause} from {addr}"));
+                    self.alert(alert, Some(ap));
+                }
+            }
+            None => *self.unattributed_failures.entry(cause).or_default() += 1,
+        }
+        if cause == FailureCause::BrokenBiscuit {
+            let now = self.crypt.timebase.now();
+            let count = self.alerts.as_mut().and_then(|a| a.broken_biscuit(now));
+            if let Some(count) = count {
+                let mut alert = Alert::new(AlertKind::BrokenBiscuit);
+                alert.count = Some(count);
+                alert.detail = addr.map(|a| format!("last from {a}"));
+                self.alert(alert, None);
+            }
+        }
+    }
+
+    /// Whether `peer` may send handshake messages from `endpoint`; counts a
+    /// failure otherwise
+    fn check_source(&mut self, peer: PeerPtr, endpoint: &Endpoint) -> bool {
+        let ap = AppPeerPtr::lift(peer);
+        let allowed = match endpoint.addresses().first() {
+            Some(addr) => ap.get_app(self).accepts_source(addr),
+            None => true,
+        };
+        if !allowed {
+            debug!("Dropping handshake message of a locked down peer from {endpoint:?}");
+            ap.get_app_mut(self)
+                .stats
+                .failure(FailureCause::DisallowedSource);
+            self.subscribers.send(Event::HandshakeFailed {
+                peer: Some(ap),
+                from: endpoint.addresses().first().copied(),
+                cause: FailureCause::DisallowedSource,
+            });
+        }
+        allowed
+    }
+
+    /// Like [AppServer::check_source], for a message whose peer is not known
+    /// yet; see [CryptoServer::peer_of]
+    fn check_msg_source(&mut self, msg: &[u8], endpoint: &Endpoint) -> bool {
+        if !self.peers.iter().any(AppPeer::locked) {
+            return true;
+        }
+        match self.crypt.peer_of(msg) {
+            Some(peer) => self.check_source(peer, endpoint),
+            None => true,
+        }
+    }
+
+    /// Give back the quotas held by handshakes which completed or expired,
+    /// see [crate::quota]
+    fn settle_quotas(&mut self) {
+        let ended: Vec<_> = self
+            .quotas
+            .holders()
+            .filter(|p| p.lower().hs().get(&self.crypt).is_none())
+            .collect();
+        for peer in ended {
+            self.quotas.release(peer);
+        }
+    }
+
+    /// Whether rosenpass is under load, see [crate::shed]
+    fn under_load(&mut self, now: Timing) -> bool {
+        let threads = 1 + self.workers.as_ref().map_or(0, |w| w.count());
+        match self.shedder.as_mut() {
+            Some(shedder) => shedder.under_load(now, threads),
+            None => false,
+        }
+    }
+
+    /// Whether answering an InitHello from `endpoint` fits the quotas and
+    /// the load; the sender is taken to be the peer last seen at its address
+    fn admit_response(&mut self, endpoint: &Endpoint) -> bool {
+        let now = self.crypt.timebase.now();
+        let peer = endpoint.addresses().first().and_then(|a| self.peer_at(a));
+        let has_session = peer.is_some_and(|p| p.lower().session().get(&self.crypt).is_some());
+        if !has_session && self.under_load(now) {
+            debug!("Dropping an InitHello from {endpoint:?} under load");
+            if let Some(shedder) = self.shedder.as_mut() {
+                shedder.counts.responses += 1;
+            }
+            return false;
+        }
+        let tags = peer.map(|p| p.get_app(self).tags.clone());
+        match self
+            .quotas
+            .admit(tags.as_deref().unwrap_or_default(), quota::RESPONSE, now)
+        {
+            Ok(()) => true,
+            Err(resource) => {
+                debug!("Dropping an InitHello from {endpoint:?} over the {resource} quota");
+                false
+            }
+        }
+    }
+
+    /// Reassemble the fragment in `rx`; once its message is complete, the
+    /// message replaces the fragment and true is returned
+    fn reassemble(
+        &mut self,
+        rx: &mut MsgBuf,
+        len: &mut usize,
+        endpoint: &Endpoint,
+    ) -> anyhow::Result<bool> {
+        let Some(&from) = endpoint.addresses().first() else {
+            return Ok(false);
+        };
+        let now = self.crypt.timebase.now();
+        let Some(msg) = self.reassembler.add(&self.crypt, from, &rx[..*len], now)? else {
+            return Ok(false);
+        };
+        rx[..msg.len()].copy_from_slice(&msg);
+        *len = msg.len();
+        Ok(true)
+    }
+
+    /// Send `buf` to `ep`, split into fragments addressed to `peer` if
+    /// `fragment` is set and the message is large enough to need it
+    fn send_maybe_fragmented(
+        &self,
+        ep: &Endpoint,
+        buf: &[u8],
+        peer: AppPeerPtr,
+        fragment: bool,
+    ) -> anyhow::Result<()> {
+        let socket = peer.get_app(self).socket;
+        if !fragment || buf.len() <= FRAGMENT_DATA_LEN {
+            return ep.send_via(self, socket, buf);
+        }
+        for frag in fragment::split(&self.crypt, peer.lower(), buf)? {
+            ep.send_via(self, socket, &frag)?;
+        }
+        Ok(())
+    }
+
+    pub fn output_key(
+        &self,
+        peer: AppPeerPtr,
+        why: KeyOutputReason,
+        key: &SymKey,
+    ) -> anyhow::Result<()> {
+        let peerid = peer.lower().get(&self.crypt).pidt()?;
+        let ap = peer.get_app(self);
+        let event = match why {
+            KeyOutputReason::Exchanged => AuditEvent::KeyRotated,
+            KeyOutputReason::Stale => AuditEvent::KeyErased,
+        };
+        self.audit(event, Some(peer), None);
+        self.subscribers.send(match why {
+            KeyOutputReason::Exchanged => Event::KeyEstablished {
+                peer,
+                key: key.clone(),
+            },
+            KeyOutputReason::Stale => Event::KeyExpired { peer },
+        });
+        if matches!(why, KeyOutputReason::Exchanged) {
+            self.alert(Alert::new(AlertKind::KeyRotated), Some(peer));
+            if let Some(r) = peer.lower().get(&self.crypt).rollover.as_ref() {
+                let which = match r.using_old {
+                    true => "old",
+                    false => "new",
+                };
+                info!(
+                    "peer {} exchanged the key using its {which} public key",
+                    self.peer_name(peer)
+                );
+            }
+        }
+
+        if self.verbose() {
+            let msg = match why {
+                KeyOutputReason::Exchanged => "Exchanged key with peer",
+                KeyOutputReason::Stale => "Erasing outdated key from peer",
+            };
+            let fp = Fingerprint::from_peer_id(&peerid);
+            match ap.name.as_ref() {
+                Some(name) => info!("{msg} {name} (fingerprint {fp})"),
+                None => info!("{msg} {} (fingerprint {fp})", fmt_b64(&*peerid)),
+            }
+        }
+
+        if let Some(of) = ap.outfile.as_ref() {
+            // This might leave some fragments of the secret on the stack;
+            // in practice this is likely not a problem because the stack likely
+            // will be overwritten by something else soon but this is not exactly
+            // guaranteed. It would be possible to remedy this, but since the secret
+            // data will linger in the linux page cache anyways with the current
+            // implementation, going to great length to erase the secret here is
+            // not worth it right now.
+            match ap.fifo.as_ref() {
+                Some(fifo) => fifo.send(key),
+                None => b64_writer(fopen_w(of).failure(Failure::Broker)?)
+                    .write_all(key.secret())
+                    .failure(Failure::Broker)?,
+            }
+            let why = match why {
+                KeyOutputReason::Exchanged => "exchanged",
+                KeyOutputReason::Stale => "stale",
+            };
+
+            // this is intentionally writing to stdout instead of stderr, because
+            // it is meant to allow external detection of a successful key-exchange
+            let line = format!(
+                "output-key peer {} key-file {of:?} {why}",
+                fmt_b64(&*peerid)
+            );
+            match self.key_output_to_log {
+                true => info!("{line}"),
+                false => println!("{line}"),
+            }
+        }
+
+        for export in ap.exports.iter() {
+            let key = match why {
+                KeyOutputReason::Exchanged => {
+                    self.crypt.export(peer.lower(), export.label.as_bytes())?
+                }
+                KeyOutputReason::Stale => SymKey::random(),
+            };
+            b64_writer(fopen_w(&export.key_out).failure(Failure::Broker)?)
+                .write_all(key.secret())
+                .failure(Failure::Broker)?;
+        }
+
+        if self.stream_keys || ap.stream_keys {
+            let expires = match why {
+                KeyOutputReason::Exchanged => peer.lower().session().get(&self.crypt).as_ref(),
+                KeyOutputReason::Stale => None,
+            }
+            .map(|ses| {
+                let wall = ses.created_at + self.crypt.timebase.wall_clock_offset();
+                (wall + REJECT_AFTER_TIME) as u64
+            });
+            KeyEvent {
+                peer_id: fmt_b64(&*peerid).to_string(),
+                fingerprint: Fingerprint::from_peer_id(&peerid).to_string(),
+                name: ap.name.clone(),
+                key: fmt_b64(key.secret()).to_string(),
+                event: match why {
+                    KeyOutputReason::Exchanged => "exchanged",
+                    KeyOutputReason::Stale => "stale",
+                },
+                expires,
+            }
+            .emit()
+            .failure(Failure::Broker)?;
+        }
+
+        for c in ap.consumers.iter() {
+            let exported = c
+                .consumer
+                .labels()
+                .iter()
+                .map(|label| match why {
+                    KeyOutputReason::Exchanged => self.crypt.export(peer.lower(), label.as_bytes()),
+                    KeyOutputReason::Stale => Ok(SymKey::random()),
+                })
+                .collect::<anyhow::Result<_>>()?;
+            c.apply_in_background(key, exported);
+        }
+
+        Ok(())
+    }
+
+    pub fn poll(&mut self, rx_buf: &mut [u8]) -> anyhow::Result<AppPollResult> {
+        match self.poll_for(rx_buf, true)? {
+            Ok(ev) => Ok(ev),
+            Err(_) => unreachable!("waiting for messages only ends with an event"),
+        }
+    }
+
+    /// Like [AppServer::poll], but unless `wait` is set, only messages which
+    /// arrived already are received; if there is nothing to do, returns the
+    /// seconds until there may be
+    pub fn poll_for(
+        &mut self,
+        rx_buf: &mut [u8],
+        wait: bool,
+    ) -> anyhow::Result<Result<AppPollResult, Timing>> {
+        use AppPollResult as A;
+        loop {
+            if container::terminating() {
+                return Ok(Ok(A::Terminate));
+            }
+            self.check_clock();
+            if self.rekey_signals != rekey::signals() {
+                self.rekey_signals = rekey::signals();
+                match self.rekey("all", "signal") {
+                    Ok(report) => info!(
+                        "Rekeying {} peers on signal, {} without endpoint skipped",
+                        report.initiated.len(),
+                        report.skipped.len()
+                    ),
+                    Err(e) => warn!("Not rekeying on signal: {e:#}"),
+                }
+            }
+            if self.upgrade_signals != upgrade::signals() {
+                self.upgrade_signals = upgrade::signals();
+                self.upgrade_to = upgrade::started_from().map(Path::to_owned);
+            }
+            if let Some(binary) = self.upgrade_to.take() {
+                info!("Upgrading to {binary:?}");
+                let e = self.upgrade(&binary);
+                warn!("Not upgrading: {e:#}");
+            }
+            if let Some(peer) = self.pending_initiations.pop() {
+                self.reschedule(peer);
+                return Ok(Ok(A::SendInitiation(peer)));
+            }
+            let now = self.crypt.timebase.now();
+            if let Some(ev) = self.poll_peers(now)? {
+                return Ok(Ok(ev));
+            }
+            return Ok(Ok(match lift_poll_result(self.crypt.poll_biscuits()?) {
+                Ok(ev) => ev,
+                Err(timeout) => {
+                    let timeout = match self.timers.next_at() {
+                        Some(at) => timeout.min(at - now),
+                        None => timeout,
+                    };
+                    let timeout = match self.next_app_event() {
+                        Some((ev, at)) if has_happened(at, now) => return Ok(Ok(ev)),
+                        Some((_, at)) => timeout.min(at - now),
+                        None => timeout,
+                    };
+                    let timeout = match self.clock_changes {
+                        Some(_) => timeout,
+                        None => timeout.min(CLOCK_CHECK_INTERVAL),
+                    };
+                    let timeout = match self.low_power {
+                        true => coalesce(self.crypt.timebase.now(), timeout),
+                        false => timeout,
+                    };
+                    if let Some(Some(done)) =
+                        self.workers.as_ref().map(|w| w.try_done()).transpose()?
+                    {
+                        return Ok(Ok(A::HandshakeDone(done)));
+                    }
+                    if let Some(resolved) = self.try_resolved() {
+                        return Ok(Ok(resolved));
+                    }
+                    if let Some(res) = self.revocations.as_ref().and_then(Revocations::try_refresh)
+                    {
+                        return Ok(Ok(A::RevocationsRefreshed(res)));
+                    }
+                    if let Some(res) = self.stored_peers.as_ref().and_then(StoredPeers::try_update)
+                    {
+                        return Ok(Ok(A::PeerStoreChanged(res)));
+                    }
+                    let received = match wait {
+                        true => self.try_recv(rx_buf, timeout)?,
+                        false => self.try_recv_now(rx_buf)?,
+                    };
+                    match received {
+                        Some((len, addr)) => A::ReceivedMessage(len, addr),
+                        None if wait || timeout <= 0.0 => continue,
+                        None => return Ok(Err(timeout)),
+                    }
+                }
+            }));
+        }
+    }
+
+    /// Poll the protocol and the application timers of the peers which may
+    /// be due at `now`, see [crate::timers]; returns the first event found
+    fn poll_peers(&mut self, now: Timing) -> anyhow::Result<Option<AppPollResult>> {
+        while let Some(no) = self.timers.pop_due(now) {
+            let peer = AppPeerPtr(no);
+            // polled again on errors, as all peers were before
+            self.reschedule(peer);
+            let timeout = match lift_poll_result(peer.lower().poll(&mut self.crypt)?) {
+                Ok(ev) => return Ok(Some(ev)),
+                Err(timeout) => timeout,
+            };
+            // after an event, the peer is polled again once it is handled
+            match self.next_peer_event(peer) {
+                Some((ev, at)) if has_happened(at, now) => return Ok(Some(ev)),
+                Some((_, at)) => self.timers.schedule(no, at.min(now + timeout)),
+                None => self.timers.schedule(no, now + timeout),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Work out the timers of `peer` anew on the next poll, after its state
+    /// changed
+    fn reschedule(&mut self, peer: AppPeerPtr) {
+        self.timers.schedule(peer.0, 0.0);
+    }
+
+    /// Spread the first handshakes with `peers` over the
+    /// [AppServer::startup_jitter]
+    fn stagger(&mut self, peers: Vec<AppPeerPtr>) {
+        let now = self.crypt.timebase.now();
+        let window = stagger::window(self.startup_jitter, peers.len());
+        if window <= 0.0 {
+            return;
+        }
+        for peer in peers {
+            peer.get_app_mut(self).start_at = Some(now + stagger::delay(window));
+            self.reschedule(peer);
+        }
+    }
+
+    /// Spread the handshakes with the peers which have no key yet, see
+    /// [crate::stagger]
+    pub fn stagger_startup(&mut self) {
+        let peers = (0..self.peers.len())
+            .map(AppPeerPtr)
+            .filter(|p| p.get_app(self).endpoint().is_some())
+            .filter(|p| p.lower().session().get(&self.crypt).is_none())
+            .collect();
+        self.stagger(peers);
+    }
+
+    fn reschedule_all(&mut self) {
+        for no in 0..self.peers.len() {
+            self.reschedule(AppPeerPtr(no));
+        }
+    }
+
+    /// Take note of the peers announced on the local network since the last
+    /// call
+    fn handle_mdns(&mut self) -> anyhow::Result<()> {
+        let Some(found) = self.mdns.as_ref().map(Mdns::receive).transpose()? else {
+            return Ok(());
+        };
+        let wall = self.crypt.timebase.now() + self.crypt.timebase.wall_clock_offset();
+        for announcement in found {
+            let (name, addr) = (&announcement.instance, announcement.addr);
+            let Some(peer) = self.peer_by_instance(name)? else {
+                continue;
+            };
+            match self.psk_of(peer) {
+                Some(psk) if announcement.authentic(psk, wall) => {}
+                Some(_) => {
+                    debug!("Ignoring unauthenticated announcement of {name} from {addr}");
+                    continue;
+                }
+                None => {
+                    debug!("Ignoring announcement of {name}, which has no preshared key");
+                    continue;
+                }
+            }
+            let ap = peer.get_app(self);
+            let known = ap.endpoint().is_some_and(|e| e.addresses().contains(&addr));
+            if ap.locked() || known {
+                continue;
+            }
+            info!("Found peer {name} at {addr} on the local network");
+            self.look_for_peer_at(peer, addr);
+            // the peer may have just started, so let it know about us right away
+            if let Some(mdns) = self.mdns.as_mut() {
+                mdns.announce_at = self.crypt.timebase.now();
+            }
+        }
+        Ok(())
+    }
+
+    /// Send the state of `peers` to the standby instance, if we are active;
+    /// see [crate::ha]
+    fn send_ha_sync(&mut self, peers: &[AppPeerPtr]) -> anyhow::Result<()> {
+        let Some(since) = self.ha.as_ref().and_then(|ha| ha.active_since) else {
+            return Ok(());
+        };
+        let now = self.crypt.timebase.now();
+        let wall = now + self.crypt.timebase.wall_clock_offset();
+        // a heartbeat without any peers still carries the biscuit keys
+        for batch in peers
+            .chunks(ha::PEERS_PER_MSG)
+            .chain(peers.is_empty().then_some(&[][..]))
+        {
+            let mut msg = SyncMsg::new(&self.crypt, now, now - since);
+            for peer in batch {
+                let addr = peer
+                    .get_app(self)
+                    .endpoint()
+                    .and_then(|e| e.addresses().first());
+                msg.peers.push(PeerState::new(
+                    &self.crypt,
+                    peer.lower(),
+                    addr.copied(),
+                    now,
+                )?);
+            }
+            let Some(ha) = self.ha.as_mut() else {
+                return Ok(());
+            };
+            let buf = ha.seal(&msg, wall)?;
+            ha.socket.send_to(&buf, ha.partner)?;
+        }
+        Ok(())
+    }
+
+    /// Apply the messages of the active instance received since the last
+    /// call, see [crate::ha]
+    fn handle_ha(&mut self) -> anyhow::Result<()> {
+        let mut buf = vec![0u8; 1 << 16];
+        loop {
+            let Some(ha) = self.ha.as_mut() else {
+                return Ok(());
+            };
+            let (len, from) = match ha.socket.recv_from(&mut buf) {
+                Ok(r) => r,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e.into()),
+            };
+            let now = self.crypt.timebase.now();
+            let wall = now + self.crypt.timebase.wall_clock_offset();
+            let msg = match ha.open(&buf[..len], wall) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    warn!("Ignoring high availability message from {from}: {e:#}");
+                    continue;
+                }
+            };
+            if let Some(since) = ha.active_since {
+                // both of us are active; the one active for longer stays so
+                if now - since >= msg.active_for {
+                    continue;
+                }
+                warn!("Standing by, {from} has been active for longer");
+                ha.active_since = None;
+                ha::stand_by(&mut self.crypt);
+                ha.takeover_at = now + ha.takeover_after;
+                let detail = format!("{from} is active");
+                self.audit(AuditEvent::HaStandby, None, Some(detail));
+                self.reschedule_all();
+            } else {
+                ha.takeover_at = now + ha.takeover_after;
+            }
+            self.apply_ha_sync(&msg, now)?;
+        }
+    }
+
+    /// Adopt the state sent by the active instance at `now`
+    fn apply_ha_sync(&mut self, msg: &SyncMsg, now: Timing) -> anyhow::Result<()> {
+        for peer in self.adopt(msg, now, "the active instance") {
+            peer.get_app_mut(self).last_exchange = Some(now);
+            self.output_key(
+                peer,
+                KeyOutputReason::Exchanged,
+                &self.crypt.osk(peer.lower())?,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Adopt the biscuit state and the peer states of `msg` at `now`, sent
+    /// by `from`; returns the peers which got a new session
+    fn adopt(&mut self, msg: &SyncMsg, now: Timing, from: &str) -> Vec<AppPeerPtr> {
+        msg.apply_biscuits(&mut self.crypt, now);
+        let mut adopted = Vec::new();
+        for state in msg.peers.iter() {
+            let peer = match state.apply(&mut self.crypt, now) {
+                Ok(Some(peer)) => AppPeerPtr::lift(peer),
+                Ok(None) => continue,
+                Err(e) => {
+                    warn!("Could not adopt peer state of {from}: {e:#}");
+                    continue;
+                }
+            };
+            self.reschedule(peer);
+            let ap = peer.get_app_mut(self);
+            ap.dead = false;
+            ap.erase_at = None;
+            if let Some(addr) = state.endpoint.filter(|_| !ap.locked()) {
+                ap.current_endpoint = Some(Endpoint::discovery_from_addresses(vec![addr]));
+            }
+            adopted.push(peer);
+        }
+        adopted
+    }
+
+    /// Adopt the state handed over by the binary we were executed by, if we
+    /// were; the keys it output are in place already. See [crate::upgrade]
+    pub fn adopt_upgrade(&mut self) {
+        let now = self.crypt.timebase.now();
+        for msg in upgrade::take_state() {
+            for peer in self.adopt(&msg, now, "the previous binary") {
+                let session = peer.lower().session().get(&self.crypt).as_ref();
+                let last_exchange = session.map(|s| s.created_at);
+                peer.get_app_mut(self).last_exchange = last_exchange;
+            }
+        }
+    }
+
+    /// Execute `binary` in place of ours, handing over the listening sockets
+    /// and the state of all peers; only returns if that failed. See
+    /// [crate::upgrade]
+    fn upgrade(&mut self, binary: &Path) -> anyhow::Error {
+        let state = match self.upgrade_state() {
+            Ok(state) => state,
+            Err(e) => return e,
+        };
+        let mut fds: Vec<RawFd> = self.sockets[..self.listen_sockets]
+            .iter()
+            .filter_map(|sock| match sock {
+                Socket::Udp(sock) => Some(sock.as_raw_fd()),
+                Socket::Unix(sock) => Some(sock.socket.as_raw_fd()),
+                // connections are not handed over, clients open them again
+                #[cfg(feature = "quic")]
+                Socket::Quic(sock) if sock.accept => Some(sock.socket.as_raw_fd()),
+                #[cfg(feature = "quic")]
+                Socket::Quic(_) => None,
+                Socket::Memory(_) => None,
+            })
+            .collect();
+        fds.extend(self.control.as_ref().map(|ctl| ctl.listener.as_raw_fd()));
+        upgrade::exec(binary, &fds, &state)
+    }
+
+    fn upgrade_state(&self) -> anyhow::Result<Vec<SyncMsg>> {
+        let now = self.crypt.timebase.now();
+        let peers: Vec<AppPeerPtr> = (0..self.peers.len())
+            .filter(|&no| !self.peers[no].removed)
+            .map(AppPeerPtr)
+            .collect();
+        let mut state = Vec::new();
+        for batch in peers
+            .chunks(ha::PEERS_PER_MSG)
+            .chain(peers.is_empty().then_some(&[][..]))
+        {
+            let mut msg = SyncMsg::new(&self.crypt, now, 0.0);
+            for peer in batch {
+                let addr = peer
+                    .get_app(self)
+                    .endpoint()
+                    .and_then(|e| e.addresses().first());
+                msg.peers.push(PeerState::new(
+                    &self.crypt,
+                    peer.lower(),
+                    addr.copied(),
+                    now,
+                )?);
+            }
+            state.push(msg);
+        }
+        Ok(state)
+    }
+
+    /// Try `addr` before the other endpoints of `peer`, starting a handshake
+    /// unless one is in progress already
+    fn look_for_peer_at(&mut self, peer: AppPeerPtr, addr: SocketAddr) {
+        let found = Endpoint::discovery_from_addresses(vec![addr]);
+        self.reschedule(peer);
+        let ap = peer.get_app_mut(self);
+        ap.current_endpoint =
+            Endpoint::discovery_from_multiple_sources(Some(&found), ap.endpoint());
+        if peer.lower().hs().get(&self.crypt).is_none() {
+            self.pending_initiations.push(peer);
+        }
+    }
+
+    /// Handle a [MsgType::Rendezvous] message in `rx` received from
+    /// `endpoint`, see [crate::rendezvous]
+    fn handle_rendezvous(&mut self, rx: &[u8], endpoint: &Endpoint) -> anyhow::Result<()> {
+        let mut payload = [0u8; RENDEZVOUS_PAYLOAD_LEN];
+        let peer = AppPeerPtr::lift(self.crypt.open_rendezvous(rx, &mut payload)?);
+        let msg = RendezvousMsg::decode(&payload)?;
+        let now = self.crypt.timebase.now();
+        let Endpoint::SocketBoundAddress { socket, addr } = endpoint else {
+            bail!("Rendezvous message without a source address");
+        };
+        let server = self.rendezvous_server;
+        match msg {
+            RendezvousMsg::Register | RendezvousMsg::Query(_) if !server => {
+                bail!("Not a rendezvous server")
+            }
+            RendezvousMsg::Register => {
+                peer.get_app_mut(self).registration = Some(Registration {
+                    socket: socket.0,
+                    addr: *addr,
+                    at: now,
+                });
+            }
+            RendezvousMsg::Query(id) => {
+                let target = self.peer_by_id(&id)?;
+                let reg = target
+                    .and_then(|t| t.get_app(self).registration)
+                    .filter(|r| r.fresh(now));
+                let answer = RendezvousMsg::Answer(id, reg.map(|r| r.addr));
+                self.send_rendezvous(peer, answer, Some(endpoint))?;
+
+                // let the peer asked for know in turn, so both start a handshake
+                let (Some(target), Some(reg)) = (target, reg) else {
+                    return Ok(());
+                };
+                if target.lower().session().get(&self.crypt).is_none() {
+                    return Ok(());
+                }
+                let me = peer.lower().get(&self.crypt).pidt()?.value;
+                let to = Endpoint::SocketBoundAddress {
+                    socket: SocketPtr(reg.socket),
+                    addr: reg.addr,
+                };
+                self.send_rendezvous(target, RendezvousMsg::Answer(me, Some(*addr)), Some(&to))?;
+            }
+            RendezvousMsg::Answer(id, addr) => {
+                ensure!(
+                    peer.get_app(self).rendezvous,
+                    "Rendezvous answer from a peer which is no rendezvous server"
+                );
+                let Some(target) = self.peer_by_id(&id)? else {
+                    return Ok(());
+                };
+                let name = self.peer_name(target);
+                let Some(addr) = addr else {
+                    debug!("Rendezvous server does not know where peer {name} is");
+                    return Ok(());
+                };
+                let ap = target.get_app(self);
+                let known = ap.endpoint().is_some_and(|e| e.addresses().contains(&addr));
+                if ap.locked() || ap.rendezvous || known {
+                    return Ok(());
+                }
+                info!("Rendezvous server says peer {name} is at {addr}");
+                self.look_for_peer_at(target, addr);
+            }
+        }
+        Ok(())
+    }
+
+    /// Send `msg` to `peer`, at `to` or at the endpoint of the peer
+    fn send_rendezvous(
+        &mut self,
+        peer: AppPeerPtr,
+        msg: RendezvousMsg,
+        to: Option<&Endpoint>,
+    ) -> anyhow::Result<()> {
+        let mut buf = MsgBuf::zero();
+        let len = self
+            .crypt
+            .seal_rendezvous(peer.lower(), &msg.encode(), &mut *buf)?;
+        let ap = peer.get_app(self);
+        match to.or(ap.endpoint()) {
+            Some(ep) => ep.send_via(self, ap.socket, &buf[..len]),
+            None => Ok(()),
+        }
+    }
+
+    /// Send `buf` to `peer`, through its relay if it has one
+    fn send_to_peer(&mut self, peer: AppPeerPtr, buf: &[u8]) -> anyhow::Result<()> {
+        if let Some(relay) = peer.get_app(self).via_relay {
+            return self.send_relayed(relay, peer, buf);
+        }
+        let ap = peer.get_app(self);
+        match ap.endpoint() {
+            Some(ep) => self.send_maybe_fragmented(ep, buf, peer, ap.fragment),
+            None => Ok(()),
+        }
+    }
+
+    /// Send `buf` to `peer` through `relay`, see [crate::relay]
+    fn send_relayed(
+        &mut self,
+        relay: AppPeerPtr,
+        peer: AppPeerPtr,
+        buf: &[u8],
+    ) -> anyhow::Result<()> {
+        let id = peer.lower().get(&self.crypt).pidt()?.value;
+        let mut out = MsgBuf::zero();
+        for frag in fragment::split(&self.crypt, peer.lower(), buf)? {
+            let len = self
+                .crypt
+                .seal_relay(relay.lower(), &id, &frag, &mut *out)?;
+            let ap = relay.get_app(self);
+            if let Some(ep) = ap.endpoint() {
+                ep.send_via(self, ap.socket, &out[..len])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Handle the [MsgType::Relay] message in `rx`; returns the relay if the
+    /// message was relayed to us, leaving the fragment it carried in `rx`
+    fn handle_relay(
+        &mut self,
+        rx: &mut MsgBuf,
+        len: &mut usize,
+    ) -> anyhow::Result<Option<AppPeerPtr>> {
+        let (mut id, mut data) = ([0u8; 32], [0u8; RELAY_DATA_LEN]);
+        let from = AppPeerPtr::lift(self.crypt.open_relay(&rx[..*len], &mut id, &mut data)?);
+        let other = self
+            .peer_by_id(&id)?
+            .context("Relay message for or from an unknown peer")?;
+        if from.get_app(self).relay {
+            rx[..data.len()].copy_from_slice(&data);
+            *len = data.len();
+            return Ok(Some(from));
+        }
+
+        ensure!(self.relay_server, "Not a relay server");
+        ensure!(other.0 != from.0, "Relay message to its own sender");
+        let from_id = from.lower().get(&self.crypt).pidt()?.value;
+        let mut out = MsgBuf::zero();
+        let len = self
+            .crypt
+            .seal_relay(other.lower(), &from_id, &data, &mut *out)?;
+        let ap = other.get_app(self);
+        if let Some(ep) = ap.endpoint() {
+            ep.send_via(self, ap.socket, &out[..len])?;
+        }
+        Ok(None)
+    }
+
+    /// Send the messages to `peer` through a relay from now on if the peer
+    /// seems unreachable, see [crate::relay]
+    fn consider_relay(&mut self, peer: AppPeerPtr, now: Timing) -> anyhow::Result<()> {
+        let ap = peer.get_app(self);
+        let unanswered = ap
+            .handshake_started
+            .is_some_and(|t| now - t >= relay::RELAY_AFTER);
+        if ap.via_relay.is_some() || ap.relay || ap.locked() {
+            return Ok(());
+        }
+        if !unanswered && ap.endpoint().is_some() {
+            return Ok(());
+        }
+        let usable = |r: &AppPeerPtr| {
+            r.get_app(self).relay && r.lower().session().get(&self.crypt).is_some()
+        };
+        let Some(relay) = (0..self.peers.len()).map(AppPeerPtr).find(usable) else {
+            return Ok(());
+        };
+        let fp = |p: AppPeerPtr| -> anyhow::Result<Fingerprint> {
+            Ok(Fingerprint::from_peer_id(
+                &p.lower().get(&self.crypt).pidt()?,
+            ))
+        };
+        info!(
+            "Relaying messages to peer {} through {}",
+            fp(peer)?,
+            fp(relay)?
+        );
+        peer.get_app_mut(self).via_relay = Some(relay);
+        Ok(())
+    }
+
+    /// The peer with the id `id`, if it is one of ours
+    fn peer_by_id(&self, id: &[u8; 32]) -> anyhow::Result<Option<AppPeerPtr>> {
+        for no in 0..self.peers.len() {
+            if PeerPtr(no).get(&self.crypt).pidt()?.value == *id {
+                return Ok(Some(AppPeerPtr(no)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The peer announced as the multicast DNS instance `name`, if it is one
+    /// of ours
+    /// The preshared key of `peer`, if one is configured
+    fn psk_of(&self, peer: AppPeerPtr) -> Option<&SymKey> {
+        let psk = &peer.lower().get(&self.crypt).psk;
+        psk.secret().iter().any(|&b| b != 0).then_some(psk)
+    }
+
+    fn peer_by_instance(&self, name: &str) -> anyhow::Result<Option<AppPeerPtr>> {
+        for no in 0..self.peers.len() {
+            let id = PeerPtr(no).get(&self.crypt).pidt()?;
+            if mdns::instance(&Fingerprint::from_peer_id(&id)) == name {
+                return Ok(Some(AppPeerPtr(no)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The result of a finished SRV lookup, if there is one
+    fn try_resolved(&mut self) -> Option<AppPollResult> {
+        for i in 0..self.lookups.len() {
+            let peer = self.lookups[i];
+            let ap = &mut self.peers[peer.0];
+            let res = match ap.resolving.as_ref().map(Receiver::try_recv) {
+                Some(Ok(res)) => res,
+                Some(Err(TryRecvError::Disconnected)) => Err(anyhow::anyhow!("SRV lookup failed")),
+                Some(Err(TryRecvError::Empty)) => continue,
+                None => Err(anyhow::anyhow!("SRV lookup went missing")),
+            };
+            ap.resolving = None;
+            self.lookups.swap_remove(i);
+            self.reschedule(peer);
+            return Some(AppPollResult::EndpointResolved(peer, res));
+        }
+        None
+    }
+
+    /// Look for suspends of the system and steps of the wall clock since the
+    /// last call
+    ///
+    /// Timers run on a clock which keeps counting during a suspend, so after
+    /// a resume they are simply overdue and fire on the next poll. Handshakes
+    /// with all peers we know an endpoint of are initiated right away anyway,
+    /// as the network has likely changed while the system slept. The wall
+    /// clock is not used for timers; setting it is merely reported.
+    fn check_clock(&mut self) {
+        let tb = &self.crypt.timebase;
+        let (suspended, offset) = (tb.suspended(), tb.wall_clock_offset());
+        let slept = suspended - self.suspended;
+        let step = offset - self.wall_clock_offset;
+        (self.suspended, self.wall_clock_offset) = (suspended, offset);
+
+        if slept >= CLOCK_JUMP_THRESHOLD {
+            info!("System resumed after being suspended for {slept:.0} seconds; renewing keys");
+            self.clock_jumps += 1;
+            self.resumed_at = tb.now();
+            self.reschedule_all();
+            self.pending_initiations = (0..self.peers.len())
+                .map(AppPeerPtr)
+                .filter(|p| p.get_app(self).endpoint().is_some())
+                .collect();
+            self.stagger(self.pending_initiations.clone());
+        }
+        if step.abs() >= CLOCK_JUMP_THRESHOLD {
+            let dir = if step > 0.0 { "forward" } else { "back" };
+            info!("Wall clock was set {dir} by {:.0} seconds", step.abs());
+            self.clock_jumps += 1;
+        }
+    }
+
+    /// Tries to receive a new message
+    ///
+    /// - might wait for an duration up to `timeout`
     /// - returns immediately if an error occurs
     /// - returns immediately if a new message is received
     pub fn try_recv(
@@ -706,7 +3696,41 @@ impl AppServer {
 
         // only poll if we drained all sockets before
         if self.all_sockets_drained {
-            self.mio_poll.poll(&mut self.events, Some(timeout))?;
+            match self.mio_poll.poll(&mut self.events, Some(timeout)) {
+                // a signal, which may have asked us to terminate
+                Err(e) if e.kind() == ErrorKind::Interrupted => return Ok(None),
+                res => res?,
+            }
+            self.wakeups += 1;
+        }
+        self.recv_ready(buf)
+    }
+
+    /// Receives a message if one arrived already
+    pub fn try_recv_now(&mut self, buf: &mut [u8]) -> anyhow::Result<Option<(usize, Endpoint)>> {
+        // takes the readiness events, so whoever polls the poll of this
+        // server, like the [crate::supervisor], is woken by new ones only
+        match self.mio_poll.poll(&mut self.events, Some(Duration::ZERO)) {
+            Err(e) if e.kind() == ErrorKind::Interrupted => return Ok(None),
+            res => res?,
+        }
+        self.recv_ready(buf)
+    }
+
+    /// Receive from whichever socket has a message
+    fn recv_ready(&mut self, buf: &mut [u8]) -> anyhow::Result<Option<(usize, Endpoint)>> {
+        // the listener is edge triggered too, hence it is drained on every call
+        self.handle_control_connections()?;
+        // and the provisioning api, and enrollment
+        self.handle_api_connections()?;
+        self.handle_enrollment_connections()?;
+        // just like the multicast DNS socket
+        self.handle_mdns()?;
+        // and the socket of high availability
+        self.handle_ha()?;
+        // the clocks are looked at on the next turn of the event loop
+        if let Some(changes) = self.clock_changes.as_ref() {
+            changes.changed()?;
         }
 
         let mut would_block_count = 0;
@@ -737,3 +3761,22 @@ impl AppServer {
         Ok(None)
     }
 }
+
+/// The event a protocol poll found, or the seconds until it may find one
+fn lift_poll_result(res: PollResult) -> Result<AppPollResult, Timing> {
+    use AppPollResult as A;
+    use PollResult as C;
+    match res {
+        C::DeleteKey(PeerPtr(no)) => Ok(A::DeleteKey(AppPeerPtr(no))),
+        C::SendInitiation(PeerPtr(no)) => Ok(A::SendInitiation(AppPeerPtr(no))),
+        C::SendRetransmission(PeerPtr(no)) => Ok(A::SendRetransmission(AppPeerPtr(no))),
+        C::Sleep(timeout) => Err(timeout),
+    }
+}
+
+/// Time to wait instead of `timeout` so the wakeup happens at a multiple of
+/// [LOW_POWER_TIMER_GRANULARITY]
+fn coalesce(now: Timing, timeout: Timing) -> Timing {
+    let at = ((now + timeout) / LOW_POWER_TIMER_GRANULARITY).ceil() * LOW_POWER_TIMER_GRANULARITY;
+    at - now
+}
@@ -0,0 +1,337 @@
+//! Signed, append-only audit log of security events
+//!
+//! With `[audit]` configured, security relevant events are appended to a log
+//! file, one JSON object per line:
+//!
+//! ```text
+//! {"seq":7,"time_ms":1760432000123,"event":"handshake-completed","peer":"…","detail":"initiator via 192.0.2.1:9999","prev":"…","sig":"…"}
+//! ```
+//!
+//! Every record carries its sequence number and the BLAKE2b hash of the line
+//! before it, and is signed with an Ed25519 key of its own, generated with
+//! `rosenpass gen-audit-keys`. `rosenpass verify-audit` checks the chain and
+//! the signatures with the public key only, so logs can be verified offline,
+//! away from the host that wrote them. Changing, inserting or removing a
+//! record breaks the chain; records cut off from the end can only be noticed
+//! against a sequence number remembered elsewhere, which is why
+//! `verify-audit` prints the last one.
+//!
+//! Rosenpass never changes its privileges while running; the `started`
+//! record names the user and group ids it runs with. Handshake failures are
+//! only logged if they can be attributed to a peer, so traffic from
+//! elsewhere can not flood the log.
+
+use anyhow::{bail, ensure, Context, Result};
+use rosenpass_util::{
+    b64::{b64_reader, fmt_b64},
+    file::LoadValue,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Read, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    coloring::Secret,
+    sodium::{self, KEY_SIZE, SIGNATURE_SIZE, SIGN_PK_SIZE, SIGN_SK_SIZE},
+};
+
+/// Settings of the audit log; no log is kept without them
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Audit {
+    /// The file records are appended to
+    pub log: PathBuf,
+
+    /// The Ed25519 secret key records are signed with
+    pub signing_key: PathBuf,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuditEvent {
+    Started,
+    Stopped,
+    PeerAdded,
+    /// The peer was removed from the peer store, see [crate::peer_store]
+    PeerRemoved,
+    HandshakeCompleted,
+    HandshakeFailed,
+    /// A handshake was asked for, see [crate::rekey]
+    RekeyRequested,
+    /// A new key was written for a peer
+    KeyRotated,
+    /// The key of a peer was replaced by a random one
+    KeyErased,
+    EndpointChanged,
+    PeerDead,
+    PeerAlive,
+    /// See [crate::breaker]
+    PeerUnreachable,
+    PeerReachable,
+    /// The public key of a peer is on the revocation list, see
+    /// [crate::revocation]
+    PeerRevoked,
+    /// This instance took over from its high availability partner
+    HaActive,
+    /// This instance stands by for its high availability partner
+    HaStandby,
+}
+
+/// One line of the audit log, without its signature
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    pub seq: u64,
+    /// Milliseconds since the Unix epoch
+    pub time_ms: u64,
+    pub event: AuditEvent,
+    /// Fingerprint of the peer concerned
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peer: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    /// Hash of the previous line; all zeros for the first
+    pub prev: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    #[serde(flatten)]
+    record: Record,
+    sig: String,
+}
+
+impl Audit {
+    pub fn validate(&self) -> Result<()> {
+        ensure!(
+            !self.log.as_os_str().is_empty(),
+            "the audit log file is empty"
+        );
+        ensure!(
+            self.signing_key.is_file(),
+            "audit signing key {:?} does not exist",
+            self.signing_key
+        );
+        Ok(())
+    }
+}
+
+/// Write a new signing key pair to `pkf` and `skf`
+pub fn generate_keys(pkf: &Path, skf: &Path) -> Result<()> {
+    let mut pk = [0u8; SIGN_PK_SIZE];
+    let mut sk = Secret::<SIGN_SK_SIZE>::zero();
+    sodium::sign_keypair(&mut pk, sk.secret_mut())?;
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(skf)?
+        .write_all(sk.secret())?;
+    std::fs::write(pkf, pk)?;
+    Ok(())
+}
+
+/// The audit log being written; see the [module documentation](self)
+#[derive(Debug)]
+pub struct AuditLog {
+    sk: Secret<SIGN_SK_SIZE>,
+    chain: Mutex<Chain>,
+}
+
+#[derive(Debug)]
+struct Chain {
+    file: File,
+    seq: u64,
+    prev: [u8; KEY_SIZE],
+}
+
+impl AuditLog {
+    /// Continue the log configured in `cfg`, or start it
+    pub fn open(cfg: &Audit) -> Result<Self> {
+        let sk = Secret::<SIGN_SK_SIZE>::load(&cfg.signing_key)?;
+        let (seq, prev) = match std::fs::read(&cfg.log) {
+            Ok(log) => match continue_chain(&log, public_key(&sk)) {
+                Ok(next) => next,
+                Err(e) => bail!("can not continue audit log {:?}: {e:#}", cfg.log),
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (0, [0u8; KEY_SIZE]),
+            Err(e) => return Err(e.into()),
+        };
+        let file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .mode(0o600)
+            .open(&cfg.log)
+            .with_context(|| format!("could not open audit log {:?}", cfg.log))?;
+        Ok(Self {
+            sk,
+            chain: Mutex::new(Chain { file, seq, prev }),
+        })
+    }
+
+    /// Append a signed record of `event`, concerning the peer with the
+    /// fingerprint `peer`, if any
+    pub fn record(
+        &self,
+        event: AuditEvent,
+        peer: Option<String>,
+        detail: Option<String>,
+    ) -> Result<()> {
+        let mut chain = self.chain.lock().unwrap();
+        let record = Record {
+            seq: chain.seq,
+            time_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            event,
+            peer,
+            detail,
+            prev: fmt_b64(&chain.prev).to_string(),
+        };
+        let mut sig = [0u8; SIGNATURE_SIZE];
+        sodium::sign_detached(&mut sig, &serde_json::to_vec(&record)?, self.sk.secret())?;
+        let entry = Entry {
+            record,
+            sig: fmt_b64(&sig).to_string(),
+        };
+        let mut line = serde_json::to_string(&entry)?;
+        chain.prev = sodium::hash(line.as_bytes())?;
+        line.push('\n');
+        chain.file.write_all(line.as_bytes())?;
+        chain.file.sync_data()?;
+        chain.seq += 1;
+        Ok(())
+    }
+}
+
+impl Drop for AuditLog {
+    fn drop(&mut self) {
+        let _ = self.record(AuditEvent::Stopped, None, None);
+    }
+}
+
+/// The public half of an Ed25519 secret key in libsodium's format
+fn public_key(sk: &Secret<SIGN_SK_SIZE>) -> &[u8] {
+    &sk.secret()[SIGN_SK_SIZE - SIGN_PK_SIZE..]
+}
+
+/// Sequence number and hash the record after the end of `log` continues
+/// with; the last record must be one of ours
+fn continue_chain(log: &[u8], pk: &[u8]) -> Result<(u64, [u8; KEY_SIZE])> {
+    if log.is_empty() {
+        return Ok((0, [0u8; KEY_SIZE]));
+    }
+    ensure!(log.ends_with(b"\n"), "the last record is incomplete");
+    let line = log[..log.len() - 1]
+        .rsplit(|&c| c == b'\n')
+        .next()
+        .unwrap_or_default();
+    let line = std::str::from_utf8(line)?;
+    let record = check_entry(line, pk).context("the last record is invalid")?;
+    Ok((record.seq + 1, sodium::hash(line.as_bytes())?))
+}
+
+/// Parse `line` and check its signature
+fn check_entry(line: &str, pk: &[u8]) -> Result<Record> {
+    let entry: Entry = serde_json::from_str(line)?;
+    let sig: [u8; SIGNATURE_SIZE] = unb64(&entry.sig)?;
+    let signed = serde_json::to_vec(&entry.record)?;
+    ensure!(
+        sodium::sign_verify_detached(&sig, &signed, pk),
+        "the signature is invalid"
+    );
+    Ok(entry.record)
+}
+
+fn unb64<const N: usize>(s: &str) -> Result<[u8; N]> {
+    let mut buf = [0u8; N];
+    let mut r = b64_reader(s.as_bytes());
+    r.read_exact(&mut buf)?;
+    ensure!(r.read(&mut [0u8; 1])? == 0, "{s:?} is too long");
+    Ok(buf)
+}
+
+/// Check the chain and all signatures of the log read from `log`, signed
+/// with the secret key belonging to `pk`; returns the last record
+pub fn verify(log: impl Read, pk: &[u8]) -> Result<Option<Record>> {
+    ensure!(
+        pk.len() == SIGN_PK_SIZE,
+        "the public key is not an audit key"
+    );
+    let mut prev = [0u8; KEY_SIZE];
+    let mut last: Option<Record> = None;
+    for (no, line) in BufReader::new(log).lines().enumerate() {
+        let line = line?;
+        let seq = last.as_ref().map_or(0, |r| r.seq + 1);
+        let record =
+            check_entry(&line, pk).with_context(|| format!("record {seq} (line {})", no + 1))?;
+        if record.seq != seq {
+            bail!(
+                "line {}: expected record {seq}, found {}",
+                no + 1,
+                record.seq
+            );
+        }
+        if unb64::<KEY_SIZE>(&record.prev)? != prev {
+            bail!("record {seq}: the record before it was changed or removed");
+        }
+        prev = sodium::hash(line.as_bytes())?;
+        last = Some(record);
+    }
+    Ok(last)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sign_and_verify() {
+        rosenpass_sodium::init().unwrap();
+        let dir = std::env::temp_dir().join(format!("rp-audit-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (pkf, skf) = (dir.join("audit.pk"), dir.join("audit.sk"));
+        generate_keys(&pkf, &skf).unwrap();
+        let cfg = Audit {
+            log: dir.join("audit.log"),
+            signing_key: skf,
+        };
+        let pk = std::fs::read(&pkf).unwrap();
+
+        let log = AuditLog::open(&cfg).unwrap();
+        log.record(AuditEvent::Started, None, None).unwrap();
+        log.record(AuditEvent::PeerAdded, Some("peer".into()), None)
+            .unwrap();
+        drop(log);
+        // a restart continues the chain
+        let log = AuditLog::open(&cfg).unwrap();
+        log.record(AuditEvent::Started, None, Some("again".into()))
+            .unwrap();
+        drop(log);
+
+        let text = std::fs::read_to_string(&cfg.log).unwrap();
+        let last = verify(text.as_bytes(), &pk).unwrap().unwrap();
+        assert_eq!((last.seq, last.event), (4, AuditEvent::Stopped));
+
+        let lines = text.lines().collect::<Vec<_>>();
+        let changed = text.replacen("\"peer\"", "\"other\"", 1);
+        assert!(verify(changed.as_bytes(), &pk).is_err());
+        let removed = [&lines[..1], &lines[2..]].concat().join("\n");
+        assert!(verify(removed.as_bytes(), &pk).is_err());
+        let reordered = [lines[1], lines[0]].join("\n");
+        assert!(verify(reordered.as_bytes(), &pk).is_err());
+
+        let mut other = [0u8; SIGN_PK_SIZE];
+        let mut sk = [0u8; SIGN_SK_SIZE];
+        sodium::sign_keypair(&mut other, &mut sk).unwrap();
+        assert!(verify(text.as_bytes(), &other).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
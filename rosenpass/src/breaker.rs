@@ -0,0 +1,89 @@
+//! Backing off from peers which do not answer
+//!
+//! A handshake we initiate is retransmitted until it expires after
+//! [crate::protocol::REJECT_AFTER_TIME] seconds, and the next one starts right
+//! after, so a peer which is offline for good would be sent an InitHello
+//! every few seconds forever. Once [CircuitBreaker::failures] handshakes in a
+//! row went unanswered, the peer is considered unreachable instead: a single
+//! InitHello without retransmissions is sent every
+//! [CircuitBreaker::probe_interval] seconds, until a handshake with the peer
+//! completes again, whoever started it.
+//!
+//! Both transitions are reported once, as `peer-unreachable` and
+//! `peer-reachable`, to the log, the audit log, the alert webhooks and the
+//! dead peer hooks (see [crate::liveness]). The breaker is on by default:
+//!
+//! ```toml
+//! [circuit_breaker]
+//! failures = 5 # 0 turns it off
+//! probe_interval = 300
+//! ```
+
+use anyhow::{ensure, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::Timing;
+
+/// Default of [CircuitBreaker::failures]
+pub const DEFAULT_FAILURES: u32 = 5;
+
+/// Default of [CircuitBreaker::probe_interval]
+pub const DEFAULT_PROBE_INTERVAL: Timing = 300.0;
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CircuitBreaker {
+    /// Unanswered handshakes in a row before a peer is considered
+    /// unreachable; defaults to [DEFAULT_FAILURES], zero never does
+    #[serde(default)]
+    pub failures: Option<u32>,
+
+    /// Seconds between two probes of an unreachable peer; defaults to
+    /// [DEFAULT_PROBE_INTERVAL]
+    #[serde(default)]
+    pub probe_interval: Option<f64>,
+}
+
+impl CircuitBreaker {
+    pub fn validate(&self) -> Result<()> {
+        ensure!(
+            self.probe_interval() > 0.0,
+            "circuit_breaker.probe_interval must be positive"
+        );
+        Ok(())
+    }
+
+    /// Whether `unanswered` handshakes in a row make a peer unreachable
+    pub fn trips(&self, unanswered: u32) -> bool {
+        let failures = self.failures.unwrap_or(DEFAULT_FAILURES);
+        failures != 0 && unanswered >= failures
+    }
+
+    pub fn probe_interval(&self) -> Timing {
+        self.probe_interval.unwrap_or(DEFAULT_PROBE_INTERVAL)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn trips_after_failures() {
+        let breaker = CircuitBreaker::default();
+        assert!(!breaker.trips(DEFAULT_FAILURES - 1));
+        assert!(breaker.trips(DEFAULT_FAILURES));
+
+        let off = CircuitBreaker {
+            failures: Some(0),
+            ..breaker
+        };
+        assert!(!off.trips(1000));
+        assert!(off.validate().is_ok());
+        assert!(CircuitBreaker {
+            probe_interval: Some(0.0),
+            ..off
+        }
+        .validate()
+        .is_err());
+    }
+}
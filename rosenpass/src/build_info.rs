@@ -0,0 +1,147 @@
+//! What this build of rosenpass is made of and supports
+//!
+//! `rosenpass --version --json` prints a [BuildInfo], so support tooling and
+//! the operators of peers can tell what a binary speaks without running a
+//! handshake against it:
+//!
+//! ```json
+//! {
+//!   "name": "rosenpass",
+//!   "version": "0.2.1",
+//!   "crypto": {
+//!     "backends": [{ "name": "libsodium", "version": "1.0.19" }, { "name": "liboqs" }],
+//!     "kems": { "static": "Classic McEliece 460896", "ephemeral": "Kyber512" },
+//!     "aeads": ["ChaCha20-Poly1305", "XChaCha20-Poly1305", "AES-256-GCM"],
+//!     "hashes": ["BLAKE2b", "HMAC-SHA256"],
+//!     "fips": true
+//!   },
+//!   "protocol": {
+//!     "versions": [1],
+//!     "labels": ["Rosenpass v1 mceliece460896 Kyber512 ChaChaPoly1305 BLAKE2s", "..."],
+//!     "extensions": ["extensions", "identity_hiding"]
+//!   },
+//!   "transports": ["udp", "unix", "relay"],
+//!   "key_outputs": ["key_out", "fifo", "wireguard", "wireguard_uapi", "..."]
+//! }
+//! ```
+//!
+//! AES-256-GCM is only listed where the CPU supports it, and so FIPS mode,
+//! which needs it. Peers can only exchange keys if they share a protocol
+//! label; those in FIPS mode use the second one. Fields may be added, but
+//! none are removed or change their meaning.
+
+use serde::Serialize;
+
+use crate::{
+    extensions::{Features, EXTENSIONS, IDENTITY_HIDING, SUPPORTED},
+    labeled_prf,
+};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BuildInfo {
+    pub name: &'static str,
+    pub version: &'static str,
+    pub crypto: Crypto,
+    pub protocol: Protocol,
+    /// How handshakes can travel
+    pub transports: Vec<&'static str>,
+    /// Where exchanged keys can go
+    pub key_outputs: Vec<&'static str>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Crypto {
+    /// The libraries implementing the primitives
+    pub backends: Vec<Backend>,
+    pub kems: Kems,
+    pub aeads: Vec<&'static str>,
+    pub hashes: Vec<&'static str>,
+    /// Whether FIPS-constrained mode is available
+    pub fips: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Backend {
+    pub name: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<&'static str>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Kems {
+    /// For the static keys of the peers, see [crate::pqkem::StaticKEM]
+    #[serde(rename = "static")]
+    pub static_: &'static str,
+    /// For the keys of a single handshake, see [crate::pqkem::EphemeralKEM]
+    pub ephemeral: &'static str,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Protocol {
+    pub versions: Vec<u8>,
+    /// The labels keys are derived under, see [labeled_prf::PROTOCOL]
+    pub labels: Vec<&'static str>,
+    /// The optional parts of the protocol understood, see [crate::extensions]
+    pub extensions: Vec<&'static str>,
+}
+
+impl BuildInfo {
+    /// The report for this binary on this CPU; libsodium must be initialized
+    pub fn current() -> Self {
+        let fips = rosenpass_sodium::aead::aes256gcm::is_available();
+        let mut aeads = vec!["ChaCha20-Poly1305", "XChaCha20-Poly1305"];
+        let mut labels = vec![labeled_prf::PROTOCOL];
+        if fips {
+            aeads.push("AES-256-GCM");
+            labels.push(labeled_prf::FIPS_PROTOCOL);
+        }
+        let extensions: [(Features, &str); 2] = [
+            (EXTENSIONS, "extensions"),
+            (IDENTITY_HIDING, "identity_hiding"),
+        ];
+        Self {
+            name: env!("CARGO_PKG_NAME"),
+            version: env!("CARGO_PKG_VERSION"),
+            crypto: Crypto {
+                backends: vec![
+                    Backend {
+                        name: "libsodium",
+                        version: Some(rosenpass_sodium::version()),
+                    },
+                    Backend {
+                        name: "liboqs",
+                        version: None,
+                    },
+                ],
+                kems: Kems {
+                    static_: "Classic McEliece 460896",
+                    ephemeral: "Kyber512",
+                },
+                aeads,
+                hashes: vec!["BLAKE2b", "HMAC-SHA256"],
+                fips,
+            },
+            protocol: Protocol {
+                versions: vec![1],
+                labels,
+                extensions: extensions
+                    .into_iter()
+                    .filter(|(f, _)| SUPPORTED & f != 0)
+                    .map(|(_, name)| name)
+                    .collect(),
+            },
+            transports: vec!["udp", "unix", "relay"],
+            key_outputs: vec![
+                "key_out",
+                "fifo",
+                "wireguard",
+                "wireguard_uapi",
+                "stream_keys",
+                "exports",
+                "exchange_command",
+                "xfrm",
+                "networkmanager",
+            ],
+        }
+    }
+}
@@ -0,0 +1,249 @@
+//! Peer public keys vouched for by an operator CA
+//!
+//! Pinning the public key of every peer on every node means editing all the
+//! configs whenever a node is enrolled. Instead, a peer entry can name the
+//! key of a certificate authority with `ca = "ca.pk"`; its `public_key` file
+//! is then a bundle holding the peer's public key, signed with the CA key,
+//! and the public key in it is used once the signature checks out:
+//!
+//! ```text
+//! rosenpass gen-ca-keys --public-key ca.pk --secret-key ca.sk
+//! rosenpass sign-key --ca-secret-key ca.sk --name node-7 --valid-days 365 node-7.pk node-7.bundle
+//! ```
+//!
+//! The bundle takes the place of the public key file and can be handed out
+//! through any channel; nodes only need the CA public key in advance. Bundles carry the
+//! name of the peer and optionally the time they expire after; an expired
+//! bundle is refused when the configuration is loaded.
+//!
+//! Signatures are made with Dilithium3, the variant of ML-DSA-65 liboqs
+//! provides, so bundles are as safe from quantum computers as the key
+//! exchange.
+//!
+//! A bundle is laid out as follows, all numbers big endian:
+//!
+//! | bytes            | content                                      |
+//! |------------------|----------------------------------------------|
+//! | 8                | `RPBNDL01`                                   |
+//! | 8                | seconds since the Unix epoch it expires after, zero for never |
+//! | 2                | length of the name                           |
+//! | name length      | name of the peer, UTF-8                      |
+//! | [StaticKEM::PK_LEN] | public key of the peer                    |
+//! | [SIG_LEN]        | signature over all of the above              |
+
+use anyhow::{anyhow, ensure, Context, Result};
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    coloring::Secret,
+    pqkem::{StaticKEM, KEM},
+};
+
+/// Length of a CA public key
+pub const PK_LEN: usize = oqs_sys::sig::OQS_SIG_dilithium_3_length_public_key as usize;
+
+/// Length of a CA secret key
+pub const SK_LEN: usize = oqs_sys::sig::OQS_SIG_dilithium_3_length_secret_key as usize;
+
+/// Length of the signature of a bundle
+pub const SIG_LEN: usize = oqs_sys::sig::OQS_SIG_dilithium_3_length_signature as usize;
+
+const MAGIC: &[u8; 8] = b"RPBNDL01";
+
+/// The contents of a bundle
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bundle {
+    pub name: String,
+    /// Seconds since the Unix epoch the bundle expires after
+    pub not_after: Option<u64>,
+    pub public_key: Vec<u8>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Write a new CA key pair to `pkf` and `skf`
+pub fn generate_keys(pkf: &Path, skf: &Path) -> Result<()> {
+    let mut pk = vec![0u8; PK_LEN];
+    let mut sk = Secret::<SK_LEN>::zero();
+    let res = unsafe {
+        oqs_sys::sig::OQS_SIG_dilithium_3_keypair(pk.as_mut_ptr(), sk.secret_mut().as_mut_ptr())
+    };
+    ensure!(
+        res == oqs_sys::common::OQS_STATUS::OQS_SUCCESS,
+        "could not generate a CA key pair"
+    );
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(skf)?
+        .write_all(sk.secret())?;
+    std::fs::write(pkf, pk)?;
+    Ok(())
+}
+
+/// Sign `msg` with the CA secret key `sk`
+pub fn sign(msg: &[u8], sk: &Secret<SK_LEN>) -> Result<Vec<u8>> {
+    let mut sig = vec![0u8; SIG_LEN];
+    let mut sig_len = 0usize;
+    let res = unsafe {
+        oqs_sys::sig::OQS_SIG_dilithium_3_sign(
+            sig.as_mut_ptr(),
+            &mut sig_len,
+            msg.as_ptr(),
+            msg.len(),
+            sk.secret().as_ptr(),
+        )
+    };
+    ensure!(
+        res == oqs_sys::common::OQS_STATUS::OQS_SUCCESS && sig_len == SIG_LEN,
+        "could not sign"
+    );
+    Ok(sig)
+}
+
+/// Check that `sig` is a signature of `msg` by the CA with public key `ca`
+pub fn verify(msg: &[u8], sig: &[u8], ca: &[u8]) -> Result<()> {
+    ensure!(ca.len() == PK_LEN, "the CA public key is not a CA key");
+    let res = unsafe {
+        oqs_sys::sig::OQS_SIG_dilithium_3_verify(
+            msg.as_ptr(),
+            msg.len(),
+            sig.as_ptr(),
+            sig.len(),
+            ca.as_ptr(),
+        )
+    };
+    ensure!(
+        res == oqs_sys::common::OQS_STATUS::OQS_SUCCESS,
+        "bad signature"
+    );
+    Ok(())
+}
+
+impl Bundle {
+    /// A bundle for `public_key`, valid for `valid_days` from now if given
+    pub fn new(name: String, public_key: Vec<u8>, valid_days: Option<u64>) -> Self {
+        Self {
+            name,
+            not_after: valid_days.map(|d| now() + d * 24 * 60 * 60),
+            public_key,
+        }
+    }
+
+    /// The bytes covered by the signature
+    fn signed_part(&self) -> Result<Vec<u8>> {
+        let name_len = u16::try_from(self.name.len()).context("the name is too long")?;
+        let mut buf = MAGIC.to_vec();
+        buf.extend_from_slice(&self.not_after.unwrap_or(0).to_be_bytes());
+        buf.extend_from_slice(&name_len.to_be_bytes());
+        buf.extend_from_slice(self.name.as_bytes());
+        buf.extend_from_slice(&self.public_key);
+        Ok(buf)
+    }
+
+    /// Sign the bundle with the CA secret key `sk`
+    pub fn sign(&self, sk: &Secret<SK_LEN>) -> Result<Vec<u8>> {
+        ensure!(
+            self.public_key.len() == StaticKEM::PK_LEN,
+            "the public key has {} bytes instead of {}",
+            self.public_key.len(),
+            StaticKEM::PK_LEN
+        );
+        let mut buf = self.signed_part()?;
+        let sig = sign(&buf, sk).context("could not sign the bundle")?;
+        buf.extend_from_slice(&sig);
+        Ok(buf)
+    }
+
+    /// Check the signature of `bundle` against the CA public key `ca` and
+    /// that it did not expire
+    pub fn open(bundle: &[u8], ca: &[u8]) -> Result<Self> {
+        ensure!(ca.len() == PK_LEN, "the CA public key is not a CA key");
+        ensure!(bundle.starts_with(MAGIC), "not a bundle");
+        let min_len = MAGIC.len() + 8 + 2 + StaticKEM::PK_LEN + SIG_LEN;
+        ensure!(bundle.len() >= min_len, "the bundle is truncated");
+        let (signed, sig) = bundle.split_at(bundle.len() - SIG_LEN);
+        verify(signed, sig, ca).context("the bundle is not signed by this CA")?;
+
+        let rest = &signed[MAGIC.len()..];
+        let (not_after, rest) = rest.split_at(8);
+        let (name_len, rest) = rest.split_at(2);
+        let name_len = u16::from_be_bytes([name_len[0], name_len[1]]) as usize;
+        ensure!(
+            rest.len() == name_len + StaticKEM::PK_LEN,
+            "the bundle is malformed"
+        );
+        let (name, public_key) = rest.split_at(name_len);
+        let not_after = match u64::from_be_bytes(not_after.try_into()?) {
+            0 => None,
+            t => Some(t),
+        };
+
+        let bundle = Self {
+            name: String::from_utf8(name.to_vec()).context("the name is not UTF-8")?,
+            not_after,
+            public_key: public_key.to_vec(),
+        };
+        if let Some(t) = bundle.not_after {
+            ensure!(now() <= t, "the bundle of {:?} expired", bundle.name);
+        }
+        Ok(bundle)
+    }
+
+    /// Read and [open](Self::open) the bundle in `path`, signed with the CA
+    /// key in `ca_path`
+    pub fn load(path: &Path, ca_path: &Path) -> Result<Self> {
+        let ca = std::fs::read(ca_path)
+            .with_context(|| format!("could not read CA public key {ca_path:?}"))?;
+        let bundle =
+            std::fs::read(path).with_context(|| format!("could not read bundle {path:?}"))?;
+        Self::open(&bundle, &ca).map_err(|e| anyhow!("rejecting bundle {path:?}: {e:#}"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sign_and_open() {
+        rosenpass_sodium::init().unwrap();
+        let mut ca = vec![0u8; PK_LEN];
+        let mut sk = Secret::<SK_LEN>::zero();
+        unsafe {
+            oqs_sys::sig::OQS_SIG_dilithium_3_keypair(
+                ca.as_mut_ptr(),
+                sk.secret_mut().as_mut_ptr(),
+            );
+        }
+        let pk = vec![7u8; StaticKEM::PK_LEN];
+
+        let bundle = Bundle::new("node-7".into(), pk.clone(), Some(1));
+        let signed = bundle.sign(&sk).unwrap();
+        assert_eq!(Bundle::open(&signed, &ca).unwrap(), bundle);
+
+        let mut forged = signed.clone();
+        forged[MAGIC.len() + 8 + 2] ^= 1;
+        assert!(Bundle::open(&forged, &ca).is_err());
+        assert!(Bundle::open(&signed[1..], &ca).is_err());
+
+        let expired = Bundle {
+            not_after: Some(1),
+            ..bundle
+        };
+        assert!(Bundle::open(&expired.sign(&sk).unwrap(), &ca).is_err());
+    }
+}
@@ -1,21 +1,113 @@
-use anyhow::{bail, ensure};
-use clap::Parser;
+use anyhow::{anyhow, bail, ensure, Context};
+use clap::{CommandFactory, Parser, Subcommand};
+use rosenpass_ciphers::fips;
+use rosenpass_util::attempt;
+use rosenpass_util::b64::fmt_b64;
 use rosenpass_util::file::{LoadValue, LoadValueB64};
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::net::ToSocketAddrs;
+use std::os::unix::fs::OpenOptionsExt;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-use crate::app_server;
 use crate::app_server::AppServer;
 use crate::{
     // app_server::{AppServer, LoadValue, LoadValueB64},
+    api,
+    audit,
+    build_info::BuildInfo,
+    ca,
     coloring::Secret,
-    pqkem::{StaticKEM, KEM},
-    protocol::{SPk, SSk, SymKey},
+    config_edit::{ConfigEditor, DropIns, PeerArgs, Target},
+    config_schema,
+    container,
+    control::{self, HealthReport, PeerStatus, RekeyReport},
+    doctor,
+    enrollment,
+    exit::{ErrorFormat, Failure, ResultExt as _},
+    fingerprint,
+    hedged,
+    interface,
+    keygen,
+    keyshare,
+    keywrap::KeyWrap,
+    labeled_prf as lprf,
+    lazy_keys,
+    ldap,
+    msgs,
+    netns,
+    nm,
+    peer_store,
+    pqkem::{StaticKEM, KEM, KEYGEN_SEED_LEN},
+    protocol::{peer_id, SPk, SSk, SymKey},
+    quota::Quotas,
+    reuseport,
+    revocation::RevocationList,
+    selftest,
+    shared_pk::SharedPk,
+    shed::Shedder,
+    sockopt::SocketOptions,
+    sodium::KEY_SIZE,
+    stats::{FailureCounts, StatsReport},
+    supervisor::Supervisor,
+    tofu,
+    vault,
+    watch,
+    wg_import::WgConfig,
+    wizard::WizardArgs,
 };
 
 use super::config;
 
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about)]
+#[command(author, version, about, long_about, disable_version_flag = true)]
+pub struct Args {
+    /// How to print an error the command fails with
+    // see crate::exit
+    #[arg(long, global = true, value_enum, default_value_t)]
+    pub error_format: ErrorFormat,
+
+    /// Print version
+    #[arg(short = 'V', long)]
+    pub version: bool,
+
+    /// With --version, print what this build supports as JSON
+    // see crate::build_info
+    #[arg(long, requires = "version")]
+    pub json: bool,
+
+    /// Always given, unless --version is
+    #[command(subcommand)]
+    pub command: Option<Cli>,
+}
+
+impl Args {
+    /// Parse the command line, which has to give a command unless it asks
+    /// for the version
+    pub fn try_parse_checked() -> Result<Self, clap::Error> {
+        let args = Self::try_parse()?;
+        match args.version || args.command.is_some() {
+            true => Ok(args),
+            false => Err(Self::command().error(
+                clap::error::ErrorKind::MissingSubcommand,
+                "a subcommand is required but one was not provided",
+            )),
+        }
+    }
+
+    /// Print the version, or with `json` what this build supports
+    pub fn print_version(json: bool) -> anyhow::Result<()> {
+        match json {
+            true => println!("{}", serde_json::to_string(&BuildInfo::current())?),
+            false => println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+        }
+        Ok(())
+    }
+}
+
+#[derive(Subcommand, Debug)]
 pub enum Cli {
     /// Start Rosenpass in server mode and carry on with the key exchange
     ///
@@ -23,7 +115,32 @@ pub enum Cli {
     /// with the specified peers. If a peer's endpoint is specified, this
     /// Rosenpass instance will try to initiate a key exchange with the peer,
     /// otherwise only initiation attempts from the peer will be responded to.
-    ExchangeConfig { config_file: PathBuf },
+    ///
+    /// With `--container`, rosenpass runs as the only process of a container,
+    /// which changes where keys are read from, logging, signal handling and
+    /// what may be written.
+    ///
+    /// Given several configuration files, each is run as an independent
+    /// instance, all sharing one event loop. A
+    /// configuration file of `-` is read from stdin, in TOML or in JSON.
+    // see crate::container and crate::supervisor
+    ExchangeConfig {
+        #[clap(required = true)]
+        config_files: Vec<PathBuf>,
+
+        /// Operate as the main process of a container
+        #[clap(long)]
+        container: bool,
+
+        /// Print every key as a line of JSON on stdout
+        #[clap(long)]
+        stream_keys: bool,
+
+        /// Load all keys and resolve all endpoints, then print the
+        /// configuration as it would be run instead of running it
+        #[clap(long)]
+        dry_run: bool,
+    },
 
     /// Start in daemon mode, performing key exchanges
     ///
@@ -38,7 +155,7 @@ pub enum Cli {
     #[allow(rustdoc::broken_intra_doc_links)]
     #[allow(rustdoc::invalid_html_tags)]
     Exchange {
-        /// public-key <PATH> secret-key <PATH> [listen <ADDR>:<PORT>]... [verbose]
+        /// public-key <PATH> secret-key <PATH> [listen <ADDR>:<PORT>]... [control-socket <PATH>] [verbose]
         #[clap(value_name = "OWN_CONFIG")]
         first_arg: String,
 
@@ -57,15 +174,58 @@ pub enum Cli {
         /// Save the parsed configuration to a file before starting the daemon
         #[clap(short, long)]
         config_file: Option<PathBuf>,
+
+        /// Print every key as a line of JSON on stdout
+        #[clap(long)]
+        stream_keys: bool,
+
+        /// Load all keys and resolve all endpoints, then print the
+        /// configuration as it would be run instead of running it
+        #[clap(long)]
+        dry_run: bool,
     },
 
     /// Generate a demo config file
+    ///
+    /// With any of the wizard options, a complete working configuration is
+    /// generated instead, including keys; with `--peer-config` also the one
+    /// for the other end of a point-to-point link. Settings not given as
+    /// options are asked for with `--interactive`, or have defaults.
     GenConfig {
         config_file: PathBuf,
 
         /// Forcefully overwrite existing config file
         #[clap(short, long)]
         force: bool,
+
+        #[command(flatten)]
+        wizard: WizardArgs,
+    },
+
+    /// Create a rosenpass config from an existing WireGuard config
+    ///
+    /// Generates rosenpass keys and a config with one peer per WireGuard
+    /// peer, handing keys to the given WireGuard device. Rosenpass endpoints
+    /// use the hosts of the WireGuard endpoints. The rosenpass public keys
+    /// of the peers have to be put where the generated config expects them.
+    ImportWg {
+        /// WireGuard configuration to import
+        wg_config: PathBuf,
+
+        /// Rosenpass configuration to write
+        config_file: PathBuf,
+
+        /// WireGuard device [default: name of the WireGuard config file]
+        #[clap(short, long)]
+        device: Option<String>,
+
+        /// UDP port rosenpass listens on, at every peer
+        #[clap(short, long, default_value_t = 9999)]
+        port: u16,
+
+        /// Forcefully overwrite existing config and key files
+        #[clap(short, long)]
+        force: bool,
     },
 
     /// Generate the keys mentioned in a configFile
@@ -87,22 +247,552 @@ pub enum Cli {
         /// Forcefully overwrite public- & secret-key file
         #[clap(short, long)]
         force: bool,
+
+        /// Derive the keys from the base64 encoded 32 byte master seed in
+        /// this file and the label, instead of generating them at random
+        #[clap(long, requires = "label")]
+        from_seed: Option<PathBuf>,
+
+        /// Name of the device the keys are for; each label yields other keys
+        #[clap(long, requires = "from_seed")]
+        label: Option<String>,
+
+        /// Also derive a WireGuard secret key from the seed, written to this file
+        #[clap(long, requires = "from_seed")]
+        wireguard_secret_key: Option<PathBuf>,
+
+        /// Key generations to run at once, the first to finish is taken; one
+        /// for each core by default
+        #[clap(short, long, conflicts_with = "from_seed")]
+        jobs: Option<usize>,
+
+        /// Do not report progress while generating the keys
+        #[clap(short, long)]
+        quiet: bool,
+
+        /// Also write the seed the key pair is generated from to this file,
+        /// so pubkey can derive the public key again should it get lost
+        #[clap(long)]
+        seed_file: Option<PathBuf>,
+    },
+
+    /// Derive the public key from a secret key
+    ///
+    /// Only works for secret keys generated by gen-keys with --seed-file,
+    /// given the seed file it wrote.
+    Pubkey {
+        /// secret-key file to derive the public key from
+        #[clap(default_value = "/dev/stdin")]
+        secret_key: PathBuf,
+
+        /// The seed file gen-keys wrote along with the secret key
+        #[clap(long)]
+        seed_file: Option<PathBuf>,
+
+        /// where to write the public-key to
+        #[clap(short, long, default_value = "/dev/stdout")]
+        public_key: PathBuf,
+    },
+
+    /// Wrap an existing secret key with the method configured in a config file
+    ///
+    /// Reads the unwrapped secret key and writes it, wrapped as specified by
+    /// `secret_key_wrap`, to the config file's `secret_key` file.
+    WrapKey {
+        config_file: PathBuf,
+
+        /// The secret key to wrap
+        plain_secret_key: PathBuf,
+
+        /// Forcefully overwrite the secret-key file
+        #[clap(short, long)]
+        force: bool,
+    },
+
+    /// Split a secret key into shares, any threshold of which recover it
+    ///
+    /// Writes the shares to share-1 to share-N in the output directory, one
+    /// for every custodian; fewer shares than the threshold reveal nothing
+    /// about the key.
+    // see crate::keyshare
+    SplitKey {
+        /// secret-key file to split; wrapped keys have to be unwrapped first
+        secret_key: PathBuf,
+
+        /// How many shares recover the key
+        #[clap(short, long)]
+        threshold: u8,
+
+        /// How many shares to make
+        #[clap(short = 'n', long)]
+        shares: u8,
+
+        /// Directory to write the shares to
+        #[clap(short, long)]
+        out_dir: PathBuf,
+
+        /// Also split this WireGuard secret-key file, to recover it along
+        #[clap(long)]
+        wireguard_secret_key: Option<PathBuf>,
+
+        /// Forcefully overwrite existing shares
+        #[clap(short, long)]
+        force: bool,
+    },
+
+    /// Recover a secret key from the shares made by `split-key`
+    ///
+    /// Every share is checked against the commitments of the split, and the
+    /// recovered key against the commitment to it and, if given, against the
+    /// public key.
+    RecoverKey {
+        /// share files
+        #[clap(required = true)]
+        shares: Vec<PathBuf>,
+
+        /// where to write the recovered secret key to
+        #[clap(short, long, required_unless_present = "check")]
+        secret_key: Option<PathBuf>,
+
+        /// where to write the recovered WireGuard secret key to, if it was
+        /// split along
+        #[clap(long)]
+        wireguard_secret_key: Option<PathBuf>,
+
+        /// public-key file the recovered secret key has to belong to
+        #[clap(short, long)]
+        public_key: Option<PathBuf>,
+
+        /// Only check the shares given, each on its own, and recover nothing
+        #[clap(long, conflicts_with_all = ["secret_key", "wireguard_secret_key"])]
+        check: bool,
+
+        /// Forcefully overwrite the secret-key files
+        #[clap(short, long)]
+        force: bool,
+    },
+
+    /// Print the fingerprint of a public key
+    ///
+    /// The fingerprint is short enough to be compared over the phone or
+    /// pasted into a ticket; it also appears in the logs and in `status`.
+    Fingerprint {
+        public_key: PathBuf,
+
+        /// Also draw the fingerprint as randomart
+        #[clap(short, long)]
+        randomart: bool,
+
+        /// Print the fingerprint the key has in fips mode, which differs
+        #[clap(long)]
+        fips: bool,
+    },
+
+    /// Generate the key pair of a CA vouching for peer public keys
+    ///
+    /// Peers configured with `ca` pointing to the public key accept bundles
+    /// made by `sign-key` with the secret key in place of public keys.
+    GenCaKeys {
+        /// where to write the public key to
+        #[clap(short, long)]
+        public_key: PathBuf,
+
+        /// where to write the secret key to
+        #[clap(short, long)]
+        secret_key: PathBuf,
+
+        /// Forcefully overwrite existing key files
+        #[clap(short, long)]
+        force: bool,
+    },
+
+    /// Bundle a public key with the signature of a CA
+    SignKey {
+        /// The secret key of the CA, made by gen-ca-keys
+        #[clap(long)]
+        ca_secret_key: PathBuf,
+
+        /// Name of the peer the key belongs to
+        #[clap(long)]
+        name: String,
+
+        /// Days until the bundle expires; it never does without
+        #[clap(long)]
+        valid_days: Option<u64>,
+
+        /// The public key to vouch for
+        public_key: PathBuf,
+
+        /// Where to write the bundle to
+        bundle: PathBuf,
+    },
+
+    /// Sign a list of revoked public keys with the secret key of a CA
+    ///
+    /// Peers with `[revocation]` configured refuse the keys on the list.
+    SignRevocations {
+        /// The secret key of the CA, made by gen-ca-keys
+        #[clap(long)]
+        ca_secret_key: PathBuf,
+
+        /// The fingerprints of the revoked keys, one per line
+        fingerprints: PathBuf,
+
+        /// Where to write the list to
+        list: PathBuf,
+    },
+
+    /// Show or remove the public keys pinned on first use
+    ///
+    /// See `pin_store` in the config; the store is read from the config file
+    /// or given directly.
+    Pins {
+        /// Configuration file naming the pin store
+        #[clap(short, long)]
+        config_file: Option<PathBuf>,
+
+        /// Path of the pin store; overrides the one from the config file
+        #[clap(short, long)]
+        store: Option<PathBuf>,
+
+        #[clap(subcommand)]
+        action: PinsAction,
+    },
+
+    /// Add, remove or show the peers of a config file
+    ///
+    /// The file is edited in place, keeping everything else in it as it is,
+    /// and only replaced if the edited config passes all checks. With --dir,
+    /// the peers are the drop-ins of one of its peer directories instead.
+    /// Running instances watching their files pick up the change by
+    /// themselves, those asked to with --reload right away, and others when
+    /// they are started again.
+    // see crate::config_edit
+    Peer {
+        /// The TOML config file to edit
+        #[clap(short, long)]
+        config_file: PathBuf,
+
+        /// Edit the drop-ins of this one of the peer_dirs of the config
+        #[clap(short, long)]
+        dir: Option<PathBuf>,
+
+        /// Make the running instance take up the change, over the control
+        /// socket of the config
+        #[clap(short, long)]
+        reload: bool,
+
+        #[clap(subcommand)]
+        action: PeerAction,
+    },
+
+    /// Generate the key pair an audit log is signed with
+    ///
+    /// The secret key goes into the `audit.signing_key` file of the config;
+    /// the public key is all `verify-audit` needs and can be kept elsewhere.
+    GenAuditKeys {
+        /// where to write the public key to
+        #[clap(short, long)]
+        public_key: PathBuf,
+
+        /// where to write the secret key to
+        #[clap(short, long)]
+        secret_key: PathBuf,
+
+        /// Forcefully overwrite existing key files
+        #[clap(short, long)]
+        force: bool,
+    },
+
+    /// Check the signatures and the hash chain of an audit log
+    ///
+    /// Fails at the first record which was changed, inserted or removed, and
+    /// prints the sequence number and time of the last record otherwise.
+    // see crate::audit
+    VerifyAudit {
+        log: PathBuf,
+
+        /// public key of the audit log
+        #[clap(short, long)]
+        public_key: PathBuf,
+    },
+
+    /// Check that a public and a secret key belong together
+    ///
+    /// Checks the key file sizes and runs one encapsulation with the public
+    /// key and decapsulation with the secret key, catching key files mixed up
+    /// between hosts before they are deployed. Like with `gen-keys`, the key
+    /// files are taken from the config file if one is given.
+    VerifyKeys {
+        config_file: Option<PathBuf>,
+
+        /// public-key file to check
+        #[clap(short, long)]
+        public_key: Option<PathBuf>,
+
+        /// secret-key file to check
+        #[clap(short, long)]
+        secret_key: Option<PathBuf>,
+    },
+
+    /// Check the environment a configuration is run in
+    ///
+    /// Goes through the keys, WireGuard, the key outputs, the endpoints of
+    /// the peers and the locked memory limit, printing a fix for every
+    /// problem. Fails if any check failed.
+    // see crate::doctor
+    Doctor { config_file: PathBuf },
+
+    /// Test this build of rosenpass on this machine
+    ///
+    /// Meant for packagers validating their builds of the cryptographic
+    /// libraries; fails if a test does.
+    Selftest {
+        #[clap(subcommand)]
+        test: SelftestKind,
     },
 
     /// Validate a configuration
     Validate { config_files: Vec<PathBuf> },
 
+    /// Check whether a running rosenpass instance is healthy
+    ///
+    /// Asks the instance over its control socket, so the exchange must have
+    /// been started with a control socket configured. Succeeds only if the
+    /// instance answers, enough peers have a fresh key according to the
+    /// configured healthcheck policy, all WireGuard devices keys are written
+    /// to are reachable and no key failed to reach WireGuard after retrying.
+    /// Suitable as a container liveness probe; without arguments, the control
+    /// socket opened in container mode is asked.
+    Healthcheck {
+        /// Configuration file of the instance to check
+        config_file: Option<PathBuf>,
+
+        /// Path of the control socket; overrides the one from the config file
+        #[clap(short = 's', long)]
+        control_socket: Option<PathBuf>,
+    },
+
+    /// Show the peers of a running rosenpass instance
+    ///
+    /// Prints one line per peer with its id, fingerprint, tags, endpoint addresses and the
+    /// age of its current key. Requires a control socket, like `healthcheck`.
+    Status {
+        /// Configuration file of the instance to query
+        config_file: Option<PathBuf>,
+
+        /// Path of the control socket; overrides the one from the config file
+        #[clap(short = 's', long)]
+        control_socket: Option<PathBuf>,
+
+        /// Only show peers carrying this tag
+        #[clap(short, long)]
+        tag: Option<String>,
+    },
+
+    /// Show handshake statistics of a running rosenpass instance
+    ///
+    /// Prints one line per peer with its handshake counters, the average
+    /// latency of handshakes it initiated and the causes of failed ones.
+    /// Requires a control socket, like `healthcheck`.
+    Stats {
+        /// Configuration file of the instance to query
+        config_file: Option<PathBuf>,
+
+        /// Path of the control socket; overrides the one from the config file
+        #[clap(short = 's', long)]
+        control_socket: Option<PathBuf>,
+
+        /// Only show peers carrying this tag
+        #[clap(short, long)]
+        tag: Option<String>,
+
+        /// Print in the Prometheus text format, e.g. for the textfile collector
+        #[clap(short, long)]
+        prometheus: bool,
+    },
+
+    /// Renew the keys of a running rosenpass instance right away
+    ///
+    /// Initiates a handshake with the peer given by its fingerprint or id, or
+    /// with all peers, and replaces the output key once it completes; for
+    /// responding to a suspected key compromise and for testing. Requires a
+    /// control socket, like `healthcheck`; SIGUSR1 rekeys all peers without.
+    Rekey {
+        /// Fingerprint or id of the peer, or `all`
+        peer: String,
+
+        /// Configuration file of the instance
+        config_file: Option<PathBuf>,
+
+        /// Path of the control socket; overrides the one from the config file
+        #[clap(short = 's', long)]
+        control_socket: Option<PathBuf>,
+    },
+
+    /// Replace the binary of a running rosenpass instance without a restart
+    ///
+    /// Executes the binary it was started from, or the given one, in the
+    /// same process, which takes over the sockets, sessions and biscuit keys,
+    /// so peers keep their keys. Requires a control socket, like
+    /// `healthcheck`; SIGUSR2 does the same without.
+    // see crate::upgrade
+    Upgrade {
+        /// Configuration file of the instance
+        config_file: Option<PathBuf>,
+
+        /// Path of the control socket; overrides the one from the config file
+        #[clap(short = 's', long)]
+        control_socket: Option<PathBuf>,
+
+        /// The binary to execute; defaults to the one the instance was
+        /// started from
+        #[clap(long)]
+        binary: Option<PathBuf>,
+    },
+
+    /// Register our public key with a hub, using a token or a DNS name
+    ///
+    /// The hub needs `[enrollment]` configured and adds us as a peer right
+    /// away. The hub still has to be configured as our peer, with the bundle
+    /// it hands out, if it has one.
+    // see crate::enrollment
+    Enroll {
+        /// Address of the enrollment listener of the hub
+        #[clap(long)]
+        hub: String,
+
+        /// Public key of the hub
+        #[clap(long)]
+        hub_public_key: PathBuf,
+
+        /// Our public key
+        #[clap(short, long)]
+        public_key: PathBuf,
+
+        /// File with the token or secret, as handed out by the hub
+        #[clap(required_unless_present = "dns_name", conflicts_with = "dns_name")]
+        token_file: Option<PathBuf>,
+
+        /// Enroll by proving that we control this DNS name
+        #[clap(long)]
+        dns_name: Option<String>,
+
+        /// Program publishing the challenge, called with the name of the TXT
+        /// record and its value; without it, the record is to be published
+        /// by hand
+        #[clap(long, requires = "dns_name")]
+        dns_hook: Option<PathBuf>,
+
+        /// Seconds to wait for the hub to find the challenge
+        #[clap(long, default_value_t = 300)]
+        wait: u64,
+
+        /// Where to write the bundle of the hub
+        #[clap(long)]
+        bundle_out: Option<PathBuf>,
+
+        /// CA public key the bundle of the hub is to be signed with
+        #[clap(long, requires = "bundle_out")]
+        ca: Option<PathBuf>,
+    },
+
+    /// Serve NetworkManager as the service of a VPN plugin
+    ///
+    /// Started by NetworkManager for connections of the rosenpass VPN type,
+    /// see `config-examples/networkmanager`. Each connection gets a service
+    /// of its own, which exits once the connection is down again.
+    NmVpnService {
+        /// Bus name to serve under; NetworkManager passes a name of its own
+        /// when it runs several connections at once
+        #[clap(long, default_value = crate::nm::SERVICE_TYPE)]
+        bus_name: String,
+
+        /// Directory to keep the files of the connection in
+        #[clap(long, default_value = crate::nm::RUNTIME_DIR)]
+        runtime_dir: PathBuf,
+    },
+
+    /// Print a machine readable description of the wire format
+    ///
+    /// The description is a JSON document listing the layout of every
+    /// message with field offsets and lengths, together with the protocol
+    /// constants. It is meant to keep packet dissectors and third party
+    /// implementations in sync with this implementation.
+    Schema,
+
+    /// Print a JSON Schema of the configuration file
+    ///
+    /// The schema is traced from the types the config is read into, so it
+    /// always matches this version of rosenpass. Editors, CI pipelines and
+    /// provisioning tools can check configs with it without running
+    /// rosenpass. Unknown keys, which rosenpass ignores, are rejected by it.
+    ConfigSchema,
+
     /// Show the rosenpass manpage
     // TODO make this the default, but only after the manpage has been adjusted once the CLI stabilizes
     Man,
 }
 
+#[derive(clap::Subcommand, Debug)]
+pub enum SelftestKind {
+    /// Test whether secret-dependent operations take the same time on
+    /// every input
+    // see crate::selftest
+    Timing {
+        /// Measurements of each operation
+        #[clap(long, default_value_t = selftest::DEFAULT_SAMPLES)]
+        samples: usize,
+    },
+}
+
+#[derive(clap::Subcommand, Debug)]
+pub enum PinsAction {
+    /// Print the fingerprint and name of each pinned peer
+    List,
+
+    /// Forget the key pinned for a peer, accepting the next one
+    Remove {
+        /// The peer as listed, its endpoint or public-key path
+        peer: String,
+    },
+}
+
+#[derive(clap::Subcommand, Debug)]
+pub enum PeerAction {
+    /// Add a peer, printing the block added to the config
+    Add {
+        #[command(flatten)]
+        peer: PeerArgs,
+    },
+
+    /// Remove a peer
+    Remove {
+        /// The peer by fingerprint, endpoint or public-key path
+        peer: String,
+    },
+
+    /// Print the block of every peer, or of one, with its fingerprint
+    Show {
+        /// The peer by fingerprint, endpoint or public-key path
+        peer: Option<String>,
+    },
+}
+
 impl Cli {
-    pub fn run() -> anyhow::Result<()> {
-        let cli = Self::parse();
+    /// Set up the logger; container mode logs JSON lines to stdout
+    pub fn init_logging(&self) {
+        match self {
+            Cli::ExchangeConfig {
+                container: true, ..
+            } => container::init_logging(),
+            _ => env_logger::init(),
+        }
+    }
 
+    pub fn run(self) -> anyhow::Result<()> {
         use Cli::*;
-        match cli {
+        match self {
             Man => {
                 let man_cmd = std::process::Command::new("man")
                     .args(["1", "rosenpass"])
@@ -112,13 +802,70 @@ impl Cli {
                     println!(include_str!(env!("ROSENPASS_MAN")));
                 }
             }
-            GenConfig { config_file, force } => {
-                ensure!(
-                    force || !config_file.exists(),
-                    "config file {config_file:?} already exists"
-                );
+            GenConfig {
+                config_file,
+                force,
+                wizard,
+            } => {
+                if !wizard.requested() {
+                    ensure!(
+                        force || !config_file.exists(),
+                        "config file {config_file:?} already exists"
+                    );
+
+                    config::Rosenpass::example_config().store(config_file)?;
+                    return Ok(());
+                }
+
+                let configs = wizard.build(&config_file)?;
+                let problems: Vec<String> = configs
+                    .iter()
+                    .flat_map(|(path, config)| [path, &config.public_key, &config.secret_key])
+                    .filter(|f| !force && f.exists())
+                    .map(|f| format!("{f:?} already exists"))
+                    .collect();
+                if !problems.is_empty() {
+                    bail!(problems.join("\n"));
+                }
+
+                for (path, config) in configs {
+                    Self::generate_keys(&config.public_key, &config.secret_key, None)?;
+                    config.store(&path)?;
+                    eprintln!("wrote {path:?} and its keys");
+                }
+            }
+
+            ImportWg {
+                wg_config,
+                config_file,
+                device,
+                port,
+                force,
+            } => {
+                let wg = WgConfig::parse(&std::fs::read_to_string(&wg_config)?)
+                    .with_context(|| format!("could not parse {wg_config:?}"))?;
+                let device = match device {
+                    Some(d) => d,
+                    None => wg_config
+                        .file_stem()
+                        .with_context(|| format!("no device name in {wg_config:?}"))?
+                        .to_string_lossy()
+                        .into_owned(),
+                };
+                let config = wg.to_rosenpass(&config_file, &device, port)?;
 
-                config::Rosenpass::example_config().store(config_file)?;
+                for f in [&config_file, &config.public_key, &config.secret_key] {
+                    ensure!(force || !f.exists(), "{f:?} already exists");
+                }
+                Self::generate_keys(&config.public_key, &config.secret_key, None)?;
+                config.store(&config_file)?;
+
+                for (wg_peer, peer) in wg.peers.iter().zip(config.peers.iter()) {
+                    eprintln!(
+                        "put the rosenpass public key of WireGuard peer {} at {:?}",
+                        wg_peer.public_key, peer.public_key
+                    );
+                }
             }
 
             GenKeys {
@@ -126,20 +873,28 @@ impl Cli {
                 public_key,
                 secret_key,
                 force,
+                from_seed,
+                label,
+                wireguard_secret_key,
+                jobs,
+                quiet,
+                seed_file,
             } => {
                 // figure out where the key file is specified, in the config file or directly as flag?
-                let (pkf, skf) = match (config_file, public_key, secret_key) {
+                let (pkf, skf, wrap) = match (config_file, public_key, secret_key) {
                     (Some(config_file), _, _) => {
                         ensure!(
                             config_file.exists(),
                             "config file {config_file:?} does not exist"
                         );
 
-                        let config = config::Rosenpass::load(config_file)?;
+                        let config =
+                            config::Rosenpass::load(config_file).failure(Failure::Config)?;
+                        Self::mix_random_seed(&config);
 
-                        (config.public_key, config.secret_key)
+                        (config.public_key, config.secret_key, config.secret_key_wrap)
                     }
-                    (_, Some(pkf), Some(skf)) => (pkf, skf),
+                    (_, Some(pkf), Some(skf)) => (pkf, skf, None),
                     _ => {
                         bail!("either a config-file or both public-key and secret-key file are required")
                     }
@@ -157,53 +912,624 @@ impl Cli {
                         "secret-key file {skf:?} exist, refusing to overwrite it"
                     ));
                 }
+                if let Some(f) = seed_file.as_ref().filter(|f| !force && f.exists()) {
+                    problems.push(format!("seed file {f:?} exist, refusing to overwrite it"));
+                }
+                if seed_file.is_some() && wrap.is_some() {
+                    problems
+                        .push("a seed file would leave the wrapped secret key unprotected".into());
+                }
+                if let Some(f) = wireguard_secret_key.as_ref().filter(|f| f.is_file()) {
+                    if !force {
+                        problems.push(format!(
+                            "WireGuard secret-key file {f:?} exist, refusing to overwrite it"
+                        ));
+                    }
+                }
                 if !problems.is_empty() {
                     bail!(problems.join("\n"));
                 }
 
-                // generate the keys and store them in files
-                let mut ssk = crate::protocol::SSk::random();
-                let mut spk = crate::protocol::SPk::random();
-                StaticKEM::keygen(ssk.secret_mut(), spk.secret_mut())?;
+                let progress = |elapsed: std::time::Duration| {
+                    if !quiet {
+                        eprintln!(
+                            "still generating the key pair, {}s so far",
+                            elapsed.as_secs()
+                        );
+                    }
+                };
+                let Some(from_seed) = from_seed else {
+                    let jobs = jobs.unwrap_or_else(keygen::default_jobs);
+                    let pair = keygen::generate(jobs, progress)?;
+                    return Self::store_keys(&pkf, &skf, seed_file.as_deref(), wrap.as_ref(), pair);
+                };
+                let master = Secret::<KEY_SIZE>::load_b64(from_seed)?;
+                let label = label.as_deref().unwrap_or_default();
+                let seed = lprf::device_seed()?
+                    .mix_secret(master.clone())?
+                    .mix(label.as_bytes())?
+                    .into_secret();
+                let pair = keygen::race(vec![seed], progress)?;
+                Self::store_keys(&pkf, &skf, seed_file.as_deref(), wrap.as_ref(), pair)?;
 
-                ssk.store_secret(skf)?;
-                spk.store_secret(pkf)?;
+                if let Some(wgsk) = wireguard_secret_key {
+                    let mut key = lprf::wireguard_key()?
+                        .mix_secret(master)?
+                        .mix(label.as_bytes())?
+                        .into_secret();
+                    // clamped like `wg genkey` does
+                    key.secret_mut()[0] &= 248;
+                    key.secret_mut()[31] = (key.secret()[31] & 127) | 64;
+                    let mut file = OpenOptions::new()
+                        .write(true)
+                        .create(true)
+                        .truncate(true)
+                        .mode(0o600)
+                        .open(&wgsk)
+                        .with_context(|| format!("could not create {wgsk:?}"))?;
+                    writeln!(file, "{}", fmt_b64(key.secret()))?;
+                }
             }
 
-            ExchangeConfig { config_file } => {
-                ensure!(
-                    config_file.exists(),
-                    "config file '{config_file:?}' does not exist"
-                );
-
-                let config = config::Rosenpass::load(config_file)?;
-                config.validate()?;
-                Self::event_loop(config)?;
-            }
+            Pubkey {
+                secret_key,
+                seed_file,
+                public_key,
+            } => {
+                let (ssk, seed) = load_secret_key_file(&secret_key)?;
+                let seed = match (seed_file, seed) {
+                    (Some(f), _) => load_seed_file(&f)?,
+                    (None, Some(seed)) => seed,
+                    (None, None) => bail!(
+                        "{secret_key:?} contains no key generation seed, give the --seed-file gen-keys wrote along with it"
+                    ),
+                };
+
+                let mut sk = crate::protocol::SSk::zero();
+                let mut spk = crate::protocol::SPk::zero();
+                StaticKEM::keygen_from_seed(seed.secret(), sk.secret_mut(), spk.secret_mut())?;
+                ensure!(
+                    rosenpass_constant_time::memeq(sk.secret(), ssk.secret()),
+                    "the seed in {secret_key:?} does not yield its secret key, was it generated by a different version?"
+                );
+                spk.store_secret(public_key)?;
+            }
+
+            WrapKey {
+                config_file,
+                plain_secret_key,
+                force,
+            } => {
+                let config = config::Rosenpass::load(config_file).failure(Failure::Config)?;
+                let wrap = config
+                    .secret_key_wrap
+                    .context("the config file specifies no secret_key_wrap")?;
+                ensure!(
+                    force || !config.secret_key.exists(),
+                    "secret-key file {:?} exist, refusing to overwrite it",
+                    config.secret_key
+                );
+
+                let (ssk, _) = load_secret_key_file(&plain_secret_key)?;
+                std::fs::write(&config.secret_key, wrap.seal(ssk.secret())?)?;
+            }
+
+            SplitKey {
+                secret_key,
+                threshold,
+                shares,
+                out_dir,
+                wireguard_secret_key,
+                force,
+            } => {
+                // only plain secret keys can be recovered into something usable
+                load_secret_key_file(&secret_key)?;
+                let path = |index: u8| out_dir.join(format!("share-{index}"));
+                if let Some(f) = (1..=shares).map(path).find(|f| !force && f.exists()) {
+                    bail!("share {f:?} exists, refusing to overwrite it");
+                }
+                let mut sk = std::fs::read(&secret_key)?;
+                let mut wgsk = wireguard_secret_key
+                    .as_ref()
+                    .map(|f| std::fs::read(f).with_context(|| format!("could not read {f:?}")))
+                    .transpose()?;
+                let mut secret = keyshare::bundle(&sk, wgsk.as_deref());
+                let shares = keyshare::split(&secret, threshold, shares);
+                for buf in [&mut sk, &mut secret].into_iter().chain(wgsk.as_mut()) {
+                    rosenpass_sodium::helpers::memzero(buf);
+                }
+
+                std::fs::create_dir_all(&out_dir)
+                    .with_context(|| format!("could not create {out_dir:?}"))?;
+                for share in shares? {
+                    write!(create_secret_file(&path(share.index), force)?, "{share}")?;
+                }
+            }
+
+            RecoverKey {
+                shares: share_files,
+                secret_key,
+                wireguard_secret_key,
+                public_key,
+                check,
+                force,
+            } => {
+                let shares = share_files
+                    .iter()
+                    .map(|path| {
+                        let text = std::fs::read_to_string(path)?;
+                        keyshare::Share::parse(&text)
+                            .with_context(|| format!("could not load key share {path:?}"))
+                    })
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                if check {
+                    for (share, path) in shares.iter().zip(share_files.iter()) {
+                        share
+                            .verify()
+                            .with_context(|| format!("key share {path:?}"))?;
+                        let n = share.commitments.len();
+                        println!("{path:?}: share {} of {n}, intact", share.index);
+                    }
+                    return Ok(());
+                }
+
+                let mut secret = keyshare::recover(&shares)?;
+                let res = (|| {
+                    let (sk, wgsk) = keyshare::unbundle(&secret)?;
+                    ensure!(
+                        wgsk.is_none() || wireguard_secret_key.is_some(),
+                        "the shares hold a WireGuard secret key too, give --wireguard-secret-key to recover it"
+                    );
+                    ensure!(
+                        wgsk.is_some() || wireguard_secret_key.is_none(),
+                        "the shares hold no WireGuard secret key"
+                    );
+                    // split-key only splits secret-key files
+                    ensure!(
+                        sk.len() >= StaticKEM::SK_LEN,
+                        "the shares hold no secret key"
+                    );
+                    if let Some(pkf) = public_key.as_ref() {
+                        let ssk = SSk::from_slice(&sk[..StaticKEM::SK_LEN]);
+                        Self::verify_keypair(&ssk, &SPk::load(pkf)?)?;
+                    }
+                    // checked by clap
+                    let skf = secret_key.unwrap();
+                    create_secret_file(&skf, force)?.write_all(sk)?;
+                    if let (Some(f), Some(key)) = (wireguard_secret_key, wgsk) {
+                        create_secret_file(&f, force)?.write_all(key)?;
+                    }
+                    Ok(())
+                })();
+                rosenpass_sodium::helpers::memzero(&mut secret);
+                res?;
+            }
+
+            ExchangeConfig {
+                config_files,
+                container,
+                stream_keys,
+                dry_run,
+            } => {
+                let stdin = Path::new(config::STDIN);
+                ensure!(
+                    config_files.iter().filter(|f| *f == stdin).count() <= 1,
+                    "the config can only be read from stdin once"
+                );
+                let mut configs = Vec::new();
+                for config_file in config_files {
+                    let config = Self::load_config(&config_file, container, stream_keys)
+                        .failure(Failure::Config)?;
+                    configs.push(config);
+                }
+
+                if dry_run {
+                    for config in configs.iter() {
+                        if configs.len() > 1 {
+                            println!("# {:?}", config.config_file_path);
+                        }
+                        Self::dry_run(config)?;
+                    }
+                    return Ok(());
+                }
+                if reuseport::worker().is_none() {
+                    if let Some(config) = configs.iter().find(|c| c.reuseport.is_some()) {
+                        ensure!(
+                            configs.len() == 1 && config.config_file_path != stdin,
+                            "reuseport needs a config file of its own"
+                        );
+                        return reuseport::run(config);
+                    }
+                }
+                // the peer store and the api are followed by loading the config again
+                let reload = |config: &config::Rosenpass| -> Option<Reload> {
+                    let file = config.config_file_path.clone();
+                    (file != stdin).then(|| {
+                        Arc::new(move || Self::load_config(&file, container, stream_keys)) as Reload
+                    })
+                };
+                if configs.len() == 1 {
+                    let reload = reload(&configs[0]);
+                    Self::event_loop(configs.remove(0), reload, container)?;
+                    return Ok(());
+                }
+                // binding the control socket would take it from the other instance
+                for (i, a) in configs.iter().enumerate() {
+                    for b in configs[i + 1..].iter() {
+                        if a.control_socket.is_some() && a.control_socket == b.control_socket {
+                            return Err(Failure::Config.wrap(anyhow!(
+                                "config files {:?} and {:?} use the same control socket",
+                                a.config_file_path,
+                                b.config_file_path
+                            )));
+                        }
+                    }
+                }
+                let mut supervisor = Supervisor::new()?;
+                for config in configs {
+                    let name = config.config_file_path.clone();
+                    let reload = reload(&config);
+                    let mut srv = Self::build_server(config, reload)?;
+                    srv.key_output_to_log |= container;
+                    supervisor.add(name, srv)?;
+                }
+                if container {
+                    supervisor.terminate_on_signal()?;
+                }
+                supervisor.rekey_on_signal()?;
+                supervisor.run()?;
+            }
 
             Exchange {
                 first_arg,
                 mut rest_of_args,
                 config_file,
+                stream_keys,
+                dry_run,
             } => {
                 rest_of_args.insert(0, first_arg);
                 let args = rest_of_args;
-                let mut config = config::Rosenpass::parse_args(args)?;
+                let mut config = config::Rosenpass::parse_args(args).failure(Failure::Config)?;
+                config.stream_keys = stream_keys;
 
                 if let Some(p) = config_file {
-                    config.store(&p)?;
+                    config.store(&p).failure(Failure::Config)?;
                     config.config_file_path = p;
                 }
-                config.validate()?;
-                Self::event_loop(config)?;
+                config.validate().failure(Failure::Config)?;
+                if dry_run {
+                    return Self::dry_run(&config);
+                }
+                Self::event_loop(config, None, false)?;
+            }
+
+            Fingerprint {
+                public_key,
+                randomart,
+                fips,
+            } => {
+                fips::select(fips)?;
+                let fp = fingerprint::Fingerprint::of_public_key(&SPk::load(public_key)?)?;
+                println!("{fp}");
+                if randomart {
+                    println!("{}", fp.randomart());
+                }
+            }
+
+            GenCaKeys {
+                public_key,
+                secret_key,
+                force,
+            } => {
+                for f in [&public_key, &secret_key] {
+                    ensure!(
+                        force || !f.exists(),
+                        "{f:?} exists, refusing to overwrite it"
+                    );
+                }
+                ca::generate_keys(&public_key, &secret_key)?;
+            }
+
+            SignKey {
+                ca_secret_key,
+                name,
+                valid_days,
+                public_key,
+                bundle,
+            } => {
+                let sk = Secret::<{ ca::SK_LEN }>::load(&ca_secret_key)?;
+                let pk = std::fs::read(&public_key)
+                    .with_context(|| format!("could not read public key {public_key:?}"))?;
+                let signed = ca::Bundle::new(name, pk, valid_days).sign(&sk)?;
+                std::fs::write(&bundle, signed)?;
+            }
+
+            SignRevocations {
+                ca_secret_key,
+                fingerprints,
+                list,
+            } => {
+                let sk = Secret::<{ ca::SK_LEN }>::load(&ca_secret_key)?;
+                let revoked = RevocationList::read_fingerprints(&fingerprints)?;
+                std::fs::write(&list, RevocationList::new(revoked).sign(&sk)?)?;
             }
 
+            Pins {
+                config_file,
+                store,
+                action,
+            } => {
+                let path = match (store, config_file) {
+                    (Some(store), _) => store,
+                    (None, Some(config_file)) => config::Rosenpass::load(&config_file)
+                        .failure(Failure::Config)?
+                        .pin_store
+                        .with_context(|| {
+                            format!("config file {config_file:?} specifies no pin store")
+                        })?,
+                    (None, None) => bail!("either a config-file or a store is required"),
+                };
+                let mut pins = tofu::PinStore::open(&path)?;
+                match action {
+                    PinsAction::List => {
+                        for (name, fp) in pins.pins() {
+                            println!("{fp} {name}");
+                        }
+                    }
+                    PinsAction::Remove { peer } => {
+                        ensure!(pins.remove(&peer), "no key is pinned for {peer}");
+                        pins.store()?;
+                    }
+                }
+            }
+
+            Peer {
+                config_file,
+                dir,
+                reload,
+                action,
+            } => {
+                ensure!(
+                    !(reload && matches!(action, PeerAction::Show { .. })),
+                    "--reload only goes with add and remove"
+                );
+                let editor = ConfigEditor::open(&config_file).failure(Failure::Config)?;
+                let config = editor.config().failure(Failure::Config)?;
+                let vault = config.vault.clone().unwrap_or_default();
+                let mut target = match dir {
+                    Some(dir) => Target::Dir(DropIns::open(editor, &dir).failure(Failure::Config)?),
+                    None => Target::Config(editor),
+                };
+                let peers = target.peers().failure(Failure::Config)?;
+                let fingerprints = peers
+                    .iter()
+                    .map(|cfg_peer| {
+                        Self::load_peer_key(cfg_peer, &vault)
+                            .and_then(|pk| fingerprint::Fingerprint::of_public_key(&pk))
+                            .ok()
+                    })
+                    .collect::<Vec<_>>();
+                let find = |sel: &str| -> anyhow::Result<usize> {
+                    let matches = (peers.iter().zip(fingerprints.iter()))
+                        .enumerate()
+                        .filter(|(_, (cfg_peer, fp))| {
+                            cfg_peer.endpoint.as_deref() == Some(sel)
+                                || cfg_peer.public_key == Path::new(sel)
+                                || fp.is_some_and(|fp| fp.to_string() == sel)
+                        })
+                        .map(|(no, _)| no)
+                        .collect::<Vec<_>>();
+                    match matches[..] {
+                        [no] => Ok(no),
+                        [] => bail!("no peer is {sel}"),
+                        _ => bail!("{} peers are {sel}, use the fingerprint", matches.len()),
+                    }
+                };
+
+                match action {
+                    PeerAction::Add { peer } => {
+                        let cfg_peer = config::RosenpassPeer {
+                            public_key: peer.public_key.clone(),
+                            ca: peer.ca.clone(),
+                            ..Default::default()
+                        };
+                        let pk = Self::load_peer_key(&cfg_peer, &vault)
+                            .with_context(|| format!("could not load {:?}", peer.public_key))
+                            .failure(Failure::Key)?;
+                        let fp = fingerprint::Fingerprint::of_public_key(&pk)?;
+                        ensure!(
+                            !fingerprints.contains(&Some(fp)),
+                            "a peer with the fingerprint {fp} is configured already"
+                        );
+                        let name = fp.to_string().replace(':', "");
+                        let no = target.add(&name, peer.table())?;
+                        target.commit().failure(Failure::Config)?;
+                        print!("# fingerprint {fp}\n{}", target.block(no)?);
+                    }
+                    PeerAction::Remove { peer } => {
+                        let no = find(&peer)?;
+                        let block = target.block(no)?;
+                        target.remove(no)?;
+                        target.commit().failure(Failure::Config)?;
+                        eprint!("removed\n{block}");
+                    }
+                    PeerAction::Show { peer } => {
+                        let selected = match peer {
+                            Some(sel) => vec![find(&sel)?],
+                            None => (0..peers.len()).collect(),
+                        };
+                        for no in selected {
+                            let fp = match fingerprints[no] {
+                                Some(fp) => fp.to_string(),
+                                None => "unknown, the public key does not load".into(),
+                            };
+                            println!("# fingerprint {fp}\n{}", target.block(no)?);
+                        }
+                    }
+                }
+                if reload {
+                    let socket = Self::control_socket_path(None, Some(config_file))?;
+                    let reply = control::request(socket, "reload")?;
+                    match serde_json::from_str::<serde_json::Value>(&reply) {
+                        Ok(v) if v["reloading"] == true => {
+                            eprintln!("the running instance is taking up the change")
+                        }
+                        Ok(v) if v["error"].is_string() => {
+                            bail!("the change is made, but {}", v["error"].as_str().unwrap())
+                        }
+                        _ => bail!("unexpected reply {:?}", reply.trim()),
+                    }
+                }
+            }
+
+            GenAuditKeys {
+                public_key,
+                secret_key,
+                force,
+            } => {
+                for f in [&public_key, &secret_key] {
+                    ensure!(
+                        force || !f.exists(),
+                        "{f:?} exists, refusing to overwrite it"
+                    );
+                }
+                audit::generate_keys(&public_key, &secret_key)?;
+            }
+
+            VerifyAudit { log, public_key } => {
+                let pk = std::fs::read(&public_key)?;
+                let file = std::fs::File::open(&log)
+                    .with_context(|| format!("could not open audit log {log:?}"))?;
+                let last = match audit::verify(file, &pk) {
+                    Ok(last) => last,
+                    Err(e) => bail!("{log:?} failed verification: {e:#}"),
+                };
+                match last {
+                    Some(last) => println!(
+                        "{log:?} is intact, last record {} of {} ms after the epoch",
+                        last.seq, last.time_ms
+                    ),
+                    None => println!("{log:?} is empty"),
+                }
+            }
+
+            VerifyKeys {
+                config_file,
+                public_key,
+                secret_key,
+            } => {
+                let (pkf, sk) = match (config_file, public_key, secret_key) {
+                    (Some(config_file), _, _) => {
+                        let mut config =
+                            config::Rosenpass::load(config_file).failure(Failure::Config)?;
+                        config.resolve_credentials()?;
+                        (config.public_key.clone(), Self::load_secret_key(&config)?)
+                    }
+                    (_, Some(pkf), Some(skf)) => (pkf, load_secret_key_file(&skf)?.0),
+                    _ => {
+                        bail!("either a config-file or both public-key and secret-key file are required")
+                    }
+                };
+
+                let len = std::fs::metadata(&pkf)?.len() as usize;
+                if len != StaticKEM::PK_LEN {
+                    let hint = match len == StaticKEM::SK_LEN {
+                        true => ", it looks like a secret key",
+                        false => "",
+                    };
+                    bail!(
+                        "public-key file {pkf:?} has {len} bytes instead of {}{hint}",
+                        StaticKEM::PK_LEN
+                    );
+                }
+                Self::verify_keypair(&sk, &SPk::load(&pkf)?).failure(Failure::Key)?;
+                eprintln!("{pkf:?} and the secret key form a key pair");
+            }
+
+            Doctor { config_file } => {
+                let mut config = attempt!({
+                    let mut config = config::Rosenpass::load(&config_file)?;
+                    config.load_external_peers()?;
+                    config.resolve_credentials()?;
+                    config.resolve_groups()?;
+                    config.validate()?;
+                    Ok(config)
+                })
+                .failure(Failure::Config)?;
+                fips::select(config.fips)?;
+
+                let mut doctor = doctor::Doctor::default();
+                doctor.check(
+                    "key pair",
+                    attempt!({
+                        let sk = Self::load_secret_key(&config)?;
+                        Self::verify_keypair(&sk, &SPk::load(&config.public_key)?)?;
+                        Ok(format!(
+                            "{:?} and the secret key belong together",
+                            config.public_key
+                        ))
+                    }),
+                    || {
+                        "generate a new key pair with gen-keys, or fix public_key and secret_key"
+                            .into()
+                    },
+                );
+                let vault = config.vault.take().unwrap_or_default();
+                for (i, cfg_peer) in config.peers.iter().enumerate() {
+                    doctor.check(
+                        &format!("peers[{i}].public_key"),
+                        Self::load_peer_key(cfg_peer, &vault).and_then(|pk| {
+                            let fp = fingerprint::Fingerprint::of_public_key(&pk)?;
+                            Ok(format!("{:?} loads, fingerprint {fp}", cfg_peer.public_key))
+                        }),
+                        || {
+                            format!(
+                                "copy the public key of the peer to {:?}",
+                                cfg_peer.public_key
+                            )
+                        },
+                    );
+                    if let Some(psk) = cfg_peer.pre_shared_key.as_ref() {
+                        doctor.check(
+                            &format!("peers[{i}].pre_shared_key"),
+                            SymKey::load_b64(psk).map(|_| format!("{psk:?} loads")),
+                            || "the preshared key must be 32 bytes, base64 encoded".into(),
+                        );
+                    }
+                }
+                doctor.examine(&config);
+
+                for finding in doctor.findings.iter() {
+                    println!("{finding}");
+                }
+                let failures = doctor.failures();
+                ensure!(failures == 0, "{failures} checks failed");
+            }
+
+            Selftest { test } => match test {
+                SelftestKind::Timing { samples } => {
+                    ensure!(samples >= 100, "at least 100 samples are needed");
+                    let mut failures = 0;
+                    for (operation, t) in selftest::timing(samples)? {
+                        let finding = selftest::finding(operation, samples, t);
+                        failures += (finding.verdict == doctor::Verdict::Fail) as usize;
+                        println!("{finding}");
+                    }
+                    ensure!(failures == 0, "{failures} operations leak timing");
+                }
+            },
+
             Validate { config_files } => {
                 for file in config_files {
                     match config::Rosenpass::load(&file) {
-                        Ok(config) => {
+                        Ok(mut config) => {
                             eprintln!("{file:?} is valid TOML and conforms to the expected schema");
-                            match config.validate() {
+                            match config
+                                .load_external_peers()
+                                .and_then(|_| config.resolve_credentials())
+                                .and_then(|_| config.resolve_groups())
+                                .and_then(|_| config.validate())
+                            {
                                 Ok(_) => eprintln!("{file:?} is passed all logical checks"),
                                 Err(_) => eprintln!("{file:?} contains logical errors"),
                             }
@@ -212,43 +1538,826 @@ impl Cli {
                     }
                 }
             }
+
+            Healthcheck {
+                config_file,
+                control_socket,
+            } => {
+                let socket = Self::control_socket_path(control_socket, config_file)?;
+                let reply = control::request(socket, "healthcheck")?;
+                let report: HealthReport = serde_json::from_str(&reply)
+                    .with_context(|| format!("unexpected reply {:?}", reply.trim()))?;
+                for problem in report.problems.iter() {
+                    eprintln!("{problem}");
+                }
+                ensure!(report.healthy, "rosenpass instance is unhealthy");
+            }
+
+            Status {
+                config_file,
+                control_socket,
+                tag,
+            } => {
+                let socket = Self::control_socket_path(control_socket, config_file)?;
+                let cmd = match tag {
+                    Some(tag) => format!("status {tag}"),
+                    None => "status".to_string(),
+                };
+                let reply = control::request(socket, &cmd)?;
+                let peers: Vec<PeerStatus> = serde_json::from_str(&reply)
+                    .with_context(|| format!("unexpected reply {:?}", reply.trim()))?;
+                for peer in peers {
+                    let endpoints: Vec<String> =
+                        peer.endpoints.iter().map(|a| a.to_string()).collect();
+                    let mut key = match peer.key_age {
+                        Some(age) => format!("key-age {age:.0}s"),
+                        None => "no-key".to_string(),
+                    };
+                    if peer.dead {
+                        key.push_str(" dead");
+                    }
+                    if peer.unreachable {
+                        key.push_str(" unreachable");
+                    }
+                    if peer.relayed {
+                        key.push_str(" relayed");
+                    }
+                    let name = peer
+                        .name
+                        .as_ref()
+                        .map_or(String::new(), |name| format!(" name {name}"));
+                    println!(
+                        "peer {}{name} fingerprint {} tags [{}] endpoints [{}] {key}",
+                        peer.peer_id,
+                        peer.fingerprint,
+                        peer.tags.join(","),
+                        endpoints.join(",")
+                    );
+                }
+            }
+
+            Rekey {
+                peer,
+                config_file,
+                control_socket,
+            } => {
+                let socket = Self::control_socket_path(control_socket, config_file)?;
+                let reply = control::request(socket, &format!("rekey {peer}"))?;
+                let report: RekeyReport = match serde_json::from_str(&reply) {
+                    Ok(report) => report,
+                    Err(_) => match serde_json::from_str::<serde_json::Value>(&reply) {
+                        Ok(v) if v["error"].is_string() => {
+                            bail!("{}", v["error"].as_str().unwrap())
+                        }
+                        _ => bail!("unexpected reply {:?}", reply.trim()),
+                    },
+                };
+                for fp in report.initiated.iter() {
+                    println!("peer {fp} rekeying");
+                }
+                for fp in report.skipped.iter() {
+                    println!("peer {fp} skipped, no endpoint known; rekey it from its side");
+                }
+                ensure!(!report.initiated.is_empty(), "no peer could be rekeyed");
+            }
+
+            Upgrade {
+                config_file,
+                control_socket,
+                binary,
+            } => {
+                let socket = Self::control_socket_path(control_socket, config_file)?;
+                let command = match binary {
+                    Some(binary) => {
+                        let binary = std::path::absolute(binary)?;
+                        let binary = binary.to_str().context("binary path is not UTF-8")?;
+                        ensure!(
+                            !binary.contains(char::is_whitespace),
+                            "the control socket takes no binary paths with whitespace"
+                        );
+                        format!("upgrade {binary}")
+                    }
+                    None => "upgrade".to_string(),
+                };
+                let reply = control::request(socket, &command)?;
+                let reply: serde_json::Value = serde_json::from_str(&reply)
+                    .with_context(|| format!("unexpected reply {:?}", reply.trim()))?;
+                if let Some(e) = reply["error"].as_str() {
+                    bail!("{e}");
+                }
+                println!("upgrading to {}", reply["upgrading"]);
+            }
+
+            Enroll {
+                hub,
+                hub_public_key,
+                public_key,
+                token_file,
+                dns_name,
+                dns_hook,
+                wait,
+                bundle_out,
+                ca,
+            } => {
+                let proof = match (token_file, dns_name) {
+                    (Some(file), _) => {
+                        let token = std::fs::read_to_string(&file)
+                            .with_context(|| format!("could not read token {file:?}"))?;
+                        enrollment::Proof::Token(token.trim().as_bytes().to_vec())
+                    }
+                    (None, name) => enrollment::Proof::Dns(name.unwrap()),
+                };
+                let hub_pk = SPk::load(&hub_public_key).failure(Failure::Key)?;
+                let pk = SPk::load(&public_key).failure(Failure::Key)?;
+                let publish = |record: &str, value: &str| match dns_hook.as_ref() {
+                    Some(hook) => {
+                        let status = std::process::Command::new(hook)
+                            .args([record, value])
+                            .status()
+                            .with_context(|| format!("could not run {hook:?}"))?;
+                        ensure!(status.success(), "{hook:?} failed with {status}");
+                        Ok(())
+                    }
+                    None => {
+                        eprintln!("publish the TXT record {record} with the value \"{value}\"");
+                        Ok(())
+                    }
+                };
+                let wait = std::time::Duration::from_secs(wait);
+                let bundle = enrollment::enroll(&hub, &hub_pk, &proof, &pk, wait, publish)?;
+                let fp = fingerprint::Fingerprint::of_public_key(&pk)?;
+                println!("enrolled as peer {fp} at {hub}");
+
+                if let Some(out) = bundle_out {
+                    ensure!(!bundle.is_empty(), "the hub hands out no bundle");
+                    if let Some(ca) = ca {
+                        let ca_pk = std::fs::read(&ca)
+                            .with_context(|| format!("could not read CA public key {ca:?}"))?;
+                        let opened = ca::Bundle::open(&bundle, &ca_pk)
+                            .context("rejecting the bundle of the hub")?;
+                        ensure!(
+                            opened.public_key == hub_pk.secret(),
+                            "the bundle of the hub is for another public key"
+                        );
+                    }
+                    std::fs::write(&out, &bundle)
+                        .with_context(|| format!("could not write bundle {out:?}"))?;
+                    println!("bundle of the hub written to {out:?}");
+                }
+            }
+
+            Stats {
+                config_file,
+                control_socket,
+                tag,
+                prometheus,
+            } => {
+                let socket = Self::control_socket_path(control_socket, config_file)?;
+                let cmd = match tag {
+                    Some(tag) => format!("stats {tag}"),
+                    None => "stats".to_string(),
+                };
+                let reply = control::request(socket, &cmd)?;
+                let report: StatsReport = serde_json::from_str(&reply)
+                    .with_context(|| format!("unexpected reply {:?}", reply.trim()))?;
+                if prometheus {
+                    print!("{}", report.to_prometheus());
+                    return Ok(());
+                }
+                let fmt_failures = |failures: &FailureCounts| -> String {
+                    let f: Vec<String> = failures.iter().map(|(c, n)| format!("{c}={n}")).collect();
+                    f.join(",")
+                };
+                for peer in report.peers.iter() {
+                    let s = &peer.stats;
+                    let latency = match s.handshake_latency.count {
+                        0 => "latency -".to_string(),
+                        n => format!(
+                            "latency {:.1}ms",
+                            1000.0 * s.handshake_latency.sum / n as f64
+                        ),
+                    };
+                    println!(
+                        "peer {} initiated {} completed {} retransmissions {} psk-failures {} {latency} failures [{}]",
+                        peer.label(),
+                        s.handshakes_initiated,
+                        s.handshakes_completed,
+                        s.retransmissions,
+                        s.psk_apply_failures,
+                        fmt_failures(&s.failures)
+                    );
+                }
+                if !report.unattributed_failures.is_empty() {
+                    println!(
+                        "unattributed failures [{}]",
+                        fmt_failures(&report.unattributed_failures)
+                    );
+                }
+                println!("wakeups {}", report.wakeups);
+                println!("clock jumps {}", report.clock_jumps);
+                for addr in report.reflexive_addresses.iter() {
+                    println!("reflexive address {addr}");
+                }
+                for q in report.quotas.iter() {
+                    let rejections: Vec<String> = q
+                        .rejections
+                        .iter()
+                        .map(|(r, n)| format!("{r}={n}"))
+                        .collect();
+                    println!(
+                        "quota {} handshakes {} memory {} rejections [{}]",
+                        q.tag.as_deref().unwrap_or("global"),
+                        q.handshakes,
+                        q.memory,
+                        rejections.join(",")
+                    );
+                }
+                let shed = report.shed;
+                println!(
+                    "shed responses {} initiations {}{}",
+                    shed.responses,
+                    shed.initiations,
+                    if shed.under_load { " under load" } else { "" }
+                );
+            }
+
+            NmVpnService {
+                bus_name,
+                runtime_dir,
+            } => {
+                nm::Service::run(&bus_name, &runtime_dir)?;
+            }
+
+            Schema => {
+                println!("{}", serde_json::to_string_pretty(&msgs::wire_schema())?);
+            }
+
+            ConfigSchema => {
+                let schema = config_schema::config_schema()?;
+                println!("{}", serde_json::to_string_pretty(&schema)?);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Find the control socket, either given directly or taken from a config file
+    fn control_socket_path(
+        control_socket: Option<PathBuf>,
+        config_file: Option<PathBuf>,
+    ) -> anyhow::Result<PathBuf> {
+        Ok(match (control_socket, config_file) {
+            (Some(socket), _) => socket,
+            (None, Some(config_file)) => config::Rosenpass::load(&config_file)
+                .failure(Failure::Config)?
+                .control_socket
+                .with_context(|| {
+                    format!("config file {config_file:?} specifies no control socket")
+                })?,
+            // as set up in container mode
+            (None, None) if Path::new(container::CONTROL_SOCKET).exists() => {
+                container::CONTROL_SOCKET.into()
+            }
+            (None, None) => bail!("either a config-file or a control-socket is required"),
+        })
+    }
+
+    /// Generate a key pair and store it in files
+    fn generate_keys(pkf: &Path, skf: &Path, wrap: Option<&KeyWrap>) -> anyhow::Result<()> {
+        let pair = keygen::generate(keygen::default_jobs(), |_| {})?;
+        Self::store_keys(pkf, skf, None, wrap, pair)
+    }
+
+    /// Store a key pair in files, and the seed it was generated from in
+    /// `seed_file`, if given
+    fn store_keys(
+        pkf: &Path,
+        skf: &Path,
+        seed_file: Option<&Path>,
+        wrap: Option<&KeyWrap>,
+        keygen::KeyPair {
+            seed,
+            sk: ssk,
+            pk: spk,
+        }: keygen::KeyPair,
+    ) -> anyhow::Result<()> {
+        match wrap {
+            Some(wrap) => std::fs::write(skf, wrap.seal(ssk.secret())?)?,
+            None => ssk.store_secret(skf)?,
+        }
+        if let Some(f) = seed_file {
+            let mut file = create_secret_file(f, true)?;
+            file.write_all(SEED_MAGIC)?;
+            file.write_all(seed.secret())?;
+        }
+        spk.store_secret(pkf)
+    }
+
+    /// Open the QUIC sockets at `addrs`, or one on an ephemeral port if
+    /// only `peers` are reached over QUIC; see `crate::quic`
+    #[cfg(feature = "quic")]
+    fn listen_quic(
+        srv: &mut AppServer,
+        addrs: &[std::net::SocketAddr],
+        peers: &[config::RosenpassPeer],
+    ) -> anyhow::Result<()> {
+        for addr in addrs.iter() {
+            srv.listen_quic(*addr, true)?;
+        }
+        let quic_peers = peers.iter().any(|peer| {
+            peer.endpoint
+                .as_ref()
+                .is_some_and(|e| e.starts_with(config::QUIC_PREFIX))
+        });
+        if addrs.is_empty() && quic_peers {
+            // where IPv6 sockets are not dual-stack, peers are reached over IPv4
+            let v6 = srv.listen_quic("[::]:0".parse()?, false);
+            if v6.is_err() {
+                srv.listen_quic("0.0.0.0:0".parse()?, false)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Load the secret key from wherever the config says it is stored
+    fn load_secret_key(config: &config::Rosenpass) -> anyhow::Result<SSk> {
+        Ok(match (&config.secret_key_vault, &config.secret_key_wrap) {
+            (Some(secret), _) => {
+                let vault = config.vault.clone().unwrap_or_default();
+                vault.fetch(secret)?
+            }
+            (None, Some(wrap)) => {
+                let mut sk = SSk::zero();
+                let file = std::fs::read(&config.secret_key)?;
+                wrap.open(&file, sk.secret_mut())
+                    .with_context(|| format!("could not unwrap {:?}", config.secret_key))?;
+                sk
+            }
+            (None, None) => load_secret_key_file(&config.secret_key)?.0,
+        })
+    }
+
+    /// Check that `sk` and `pk` belong together by running the KEM once
+    fn verify_keypair(sk: &SSk, pk: &SPk) -> anyhow::Result<()> {
+        let mut ct = vec![0u8; StaticKEM::CT_LEN];
+        let (mut shk_enc, mut shk_dec) = (SymKey::zero(), SymKey::zero());
+        StaticKEM::encaps(shk_enc.secret_mut(), &mut ct, pk.secret())?;
+        StaticKEM::decaps(shk_dec.secret_mut(), sk.secret(), &ct)?;
+        ensure!(
+            rosenpass_constant_time::memeq(shk_enc.secret(), shk_dec.secret()),
+            "the secret key does not belong to the public key"
+        );
+        Ok(())
+    }
+
+    /// Load the public key of a peer from wherever the config says it is stored
+    fn load_peer_key(
+        cfg_peer: &config::RosenpassPeer,
+        vault: &vault::VaultConfig,
+    ) -> anyhow::Result<SPk> {
+        Ok(
+            match (cfg_peer.public_key_vault.as_ref(), cfg_peer.ca.as_ref()) {
+                (Some(secret), _) => vault.fetch(secret)?,
+                (None, Some(ca)) => {
+                    let bundle = ca::Bundle::load(&cfg_peer.public_key, ca)?;
+                    log::info!("peer {:?} is vouched for by CA {ca:?}", bundle.name);
+                    SPk::from_slice(&bundle.public_key)
+                }
+                (None, None) => SPk::load(&cfg_peer.public_key)?,
+            },
+        )
+    }
+
+    /// Load all keys and resolve all endpoints of `config` without starting
+    /// anything, then print the configuration as it would be run
+    fn dry_run(config: &config::Rosenpass) -> anyhow::Result<()> {
+        fips::select(config.fips)?;
+        attempt!({
+            let sk = Self::load_secret_key(config)?;
+            Self::verify_keypair(&sk, &SPk::load(&config.public_key)?)
+        })
+        .failure(Failure::Key)?;
+
+        let vault = config.vault.clone().unwrap_or_default();
+        let mut endpoints = String::new();
+        for (i, cfg_peer) in config.peers.iter().enumerate() {
+            attempt!({
+                Self::load_peer_key(cfg_peer, &vault)?;
+                for old in cfg_peer.old_public_key.iter() {
+                    SPk::load(old)?;
+                }
+                for psk in cfg_peer.pre_shared_key.iter() {
+                    SymKey::load_b64(psk)?;
+                }
+                Ok(())
+            })
+            .failure(Failure::Key)?;
+            if let Some(endpoint) = cfg_peer.endpoint.as_ref() {
+                let addrs = endpoint
+                    .to_socket_addrs()
+                    .with_context(|| format!("could not resolve the endpoint {endpoint:?}"))?
+                    .map(|addr| addr.to_string())
+                    .collect::<Vec<_>>();
+                endpoints += &format!(
+                    "# peers[{i}].endpoint {endpoint} resolves to {}\n",
+                    addrs.join(", ")
+                );
+            }
         }
 
+        // the keys are referred to by path, so this holds no secrets
+        print!("{}{endpoints}", toml::to_string_pretty(config)?);
         Ok(())
     }
 
-    fn event_loop(config: config::Rosenpass) -> anyhow::Result<()> {
+    /// Load `config_file` and all it refers to as exchange-config runs it
+    fn load_config(
+        config_file: &Path,
+        container: bool,
+        stream_keys: bool,
+    ) -> anyhow::Result<config::Rosenpass> {
+        ensure!(
+            config_file == Path::new(config::STDIN) || config_file.exists(),
+            "config file '{config_file:?}' does not exist"
+        );
+
+        let mut config = config::Rosenpass::load(config_file)?;
+        config.load_external_peers()?;
+        config.resolve_credentials()?;
+        config.resolve_groups()?;
+        if container {
+            container::apply(&mut config)?;
+        }
+        if let Some(worker) = reuseport::worker() {
+            reuseport::apply(&mut config, worker)?;
+        }
+        config.stream_keys |= stream_keys;
+        ensure!(
+            !(container && config.stream_keys),
+            "keys can not be streamed on stdout in container mode, which logs there"
+        );
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn event_loop(
+        config: config::Rosenpass,
+        reload: Option<Reload>,
+        container: bool,
+    ) -> anyhow::Result<()> {
+        let mut srv = Self::build_server(config, reload)?;
+        if container {
+            srv.terminate_on_signal()?;
+            srv.key_output_to_log = true;
+        }
+        srv.rekey_on_signal()?;
+        srv.upgrade_on_signal()?;
+        srv.event_loop()
+    }
+
+    /// Mix the seed file of `config` into the randomness, if it has one
+    fn mix_random_seed(config: &config::Rosenpass) {
+        if let Some(path) = config.random_seed.as_ref() {
+            if let Err(e) = hedged::mix_seed_file(path) {
+                log::warn!("{e:?}");
+            }
+        }
+    }
+
+    /// Set up an application server as configured by `config`; `reload`
+    /// loads it again, see `peer_store` and `api`
+    fn build_server(
+        config: config::Rosenpass,
+        reload: Option<Reload>,
+    ) -> anyhow::Result<Box<AppServer>> {
+        // the primitives are chosen before any of them is used
+        fips::select(config.fips)?;
+        Self::mix_random_seed(&config);
+        let watcher = match config.watch.as_ref() {
+            Some(watch) => Some(watch.start(&config)),
+            // for the reload command
+            None if reload.is_some() => Some(watch::Watcher::on_request(&config)),
+            None => None,
+        }
+        .transpose()
+        .failure(Failure::Config)?;
+
         // load own keys
-        let sk = SSk::load(&config.secret_key)?;
-        let pk = SPk::load(&config.public_key)?;
+        let sk = Self::load_secret_key(&config).failure(Failure::Key)?;
+        let pk = SPk::load(&config.public_key).failure(Failure::Key)?;
 
         // start an application server
-        let mut srv = std::boxed::Box::<AppServer>::new(AppServer::new(
-            sk,
-            pk,
-            config.listen,
-            config.verbosity,
-        )?);
+        let unix_only = config.listen.is_empty() && !config.listen_unix.is_empty();
+        let mut srv = std::boxed::Box::<AppServer>::new(
+            netns::within(config.netns.as_deref(), || match unix_only {
+                true => AppServer::on_unix_sockets(sk, pk, &config.listen_unix, config.verbosity),
+                false => AppServer::new(sk, pk, config.listen, config.verbosity),
+            })
+            .failure(Failure::Bind)?,
+        );
+        if !unix_only {
+            for path in config.listen_unix.iter() {
+                srv.listen_unix(path).failure(Failure::Bind)?;
+            }
+        }
+        #[cfg(feature = "quic")]
+        netns::within(config.netns.as_deref(), || {
+            Self::listen_quic(&mut srv, &config.listen_quic, &config.peers)
+        })
+        .failure(Failure::Bind)?;
+        if let Some(audit) = config.audit.as_ref() {
+            srv.enable_audit(audit)?;
+        }
+        if let Some(name) = config.interface.as_ref() {
+            srv.bind_to_interface(name).failure(Failure::Bind)?;
+        }
+        if let Some(vrf) = config.vrf.as_ref() {
+            netns::within(config.netns.as_deref(), || interface::ensure_vrf(vrf))
+                .failure(Failure::Bind)?;
+            srv.bind_to_interface(vrf).failure(Failure::Bind)?;
+        }
+        srv.set_socket_options(SocketOptions {
+            fwmark: config.fwmark,
+            dscp: config.dscp,
+            recv_buffer: config.recv_buffer,
+            send_buffer: config.send_buffer,
+        })
+        .failure(Failure::Bind)?;
+        srv.health_policy = config.healthcheck;
+        srv.set_low_power(config.low_power);
+        srv.dead_peer = config.dead_peer;
+        srv.startup_jitter = config.startup_jitter;
+        srv.circuit_breaker = config.circuit_breaker;
+        srv.quotas = Quotas::new(&config.quota);
+        srv.shedder = config.load_shedding.map(Shedder::new);
+        let params = config.profile.params();
+        srv.crypt.rekey_margin = config.rekey_margin.unwrap_or(params.rekey_margin);
+        srv.crypt.biscuit_epoch = config.replay_window.unwrap_or(params.replay_window);
+        srv.crypt.replay_mode = config.replay_mode.unwrap_or(params.replay_mode);
+        srv.crypt.identity_hiding = config.identity_hiding;
+        srv.rendezvous_server = config.rendezvous_server;
+        srv.relay_server = config.relay_server;
+        if let Some(path) = config.control_socket {
+            srv.listen_control_socket(path).failure(Failure::Bind)?;
+        }
+        if config.mdns {
+            netns::within(config.netns.as_deref(), || srv.enable_mdns())?;
+        }
+        if let Some(ha) = config.high_availability.as_ref() {
+            srv.enable_ha(ha)?;
+        }
+        if let Some(alerts) = config.alerts.as_ref() {
+            srv.enable_alerts(alerts)?;
+        }
+        if !config.stun_servers.is_empty() {
+            let mut servers = Vec::new();
+            for server in config.stun_servers.iter() {
+                servers.extend(server.to_socket_addrs()?);
+            }
+            srv.enable_stun(servers);
+        }
 
+        let vault = config.vault.unwrap_or_default();
+        let mut pins = config
+            .pin_store
+            .as_deref()
+            .map(tofu::PinStore::open)
+            .transpose()?;
+        srv.netns = config.netns.clone();
+        let current = |kind: fn(&config::RosenpassPeer) -> bool| -> Vec<_> {
+            config.peers.iter().filter(|p| kind(p)).cloned().collect()
+        };
+        let (current_stored, current_ldap) = (current(|p| p.stored), current(|p| p.from_ldap));
+        let (mut stored, mut from_ldap) = (HashMap::new(), HashMap::new());
+        let (mut provisioned, mut from_files) = (HashMap::new(), HashMap::new());
+        let mut lazy_keys = match config.lazy_keys.as_ref() {
+            Some(lazy) => {
+                srv.crypt.key_cache = lazy.cache.unwrap_or(lazy_keys::DEFAULT_CACHE);
+                Some(lazy_keys::Index::open(lazy.index.as_deref()).failure(Failure::Key)?)
+            }
+            None => None,
+        };
         for cfg_peer in config.peers {
-            srv.add_peer(
-                // psk, pk, outfile, outwg, tx_addr
-                cfg_peer.pre_shared_key.map(SymKey::load_b64).transpose()?,
-                SPk::load(&cfg_peer.public_key)?,
-                cfg_peer.key_out,
-                cfg_peer.wg.map(|cfg| app_server::WireguardOut {
-                    dev: cfg.device,
-                    pk: cfg.peer,
-                    extra_params: cfg.extra_params,
-                }),
-                cfg_peer.endpoint.clone(),
+            let peer_pk = match lazy_keys.as_mut() {
+                Some(index) if cfg_peer.public_key_vault.is_none() && cfg_peer.ca.is_none() => {
+                    index.key(&cfg_peer.public_key)
+                }
+                _ => Self::load_peer_key(&cfg_peer, &vault).map(SharedPk::from),
+            }
+            .failure(Failure::Key)?;
+            let fp = fingerprint::Fingerprint::from_peer_id(&peer_id(&peer_pk)?);
+            // the peer store, the directory, the api and enrolled peers are
+            // trusted like the config file
+            let external = cfg_peer.stored || cfg_peer.from_ldap;
+            if let Some(pins) = pins
+                .as_mut()
+                .filter(|_| !external && !cfg_peer.provisioned && !cfg_peer.enrolled)
+            {
+                let name = tofu::pin_name(&cfg_peer);
+                if pins.check(&name, &fp).failure(Failure::Key)? {
+                    log::warn!("pinning public key {fp} of peer {name}, seen for the first time");
+                }
+            }
+            let path = cfg_peer.public_key.clone();
+            let (from_store, from_api) = (cfg_peer.stored, cfg_peer.provisioned);
+            let in_ldap = cfg_peer.from_ldap;
+            let in_files = watch::watched(&cfg_peer);
+            let peer = srv.add_configured_peer(cfg_peer, peer_pk)?;
+            if from_store {
+                stored.insert(path, peer);
+            } else if in_ldap {
+                from_ldap.insert(path, peer);
+            } else if from_api {
+                provisioned.insert(fp, peer);
+            } else if in_files {
+                from_files.insert(path, peer);
+            }
+        }
+        if let Some(pins) = pins {
+            pins.store()?;
+        }
+        if let Some(Err(e)) = lazy_keys.map(|index| index.store()) {
+            log::warn!("{e:?}");
+        }
+        // the peers of either kind, as they are loaded again
+        let reload_peers = |kind: fn(&config::RosenpassPeer) -> bool| {
+            let reload = reload.clone()?;
+            Some(move || Ok(reload()?.peers.into_iter().filter(kind).collect()))
+        };
+        let load_key = |vault: &vault::VaultConfig| {
+            let vault = vault.clone();
+            move |peer: &config::RosenpassPeer| Self::load_peer_key(peer, &vault)
+        };
+        if let Some(watcher) = watcher {
+            let updates = match reload.clone() {
+                Some(reload) => {
+                    srv.reload = Some(watcher.trigger());
+                    watcher.spawn(
+                        move || reload(),
+                        load_key(&vault),
+                        |config| {
+                            Self::load_secret_key(config)?;
+                            SPk::load(&config.public_key)?;
+                            Ok(())
+                        },
+                        srv.waker.clone(),
+                    )
+                }
+                None => {
+                    log::warn!(
+                        "changes to the watched files are only picked up with a config file"
+                    );
+                    std::sync::mpsc::channel().1
+                }
+            };
+            let peers = srv.stored_peers.get_or_insert_with(Default::default);
+            peers.add(from_files, updates);
+        }
+        if let Some(store) = config.peer_store.as_ref() {
+            let updates = match reload_peers(|p| p.stored) {
+                Some(reload) => {
+                    store.watch(current_stored, reload, load_key(&vault), srv.waker.clone())
+                }
+                None => {
+                    log::warn!("changes to the peer store are only picked up with a config file");
+                    std::sync::mpsc::channel().1
+                }
+            };
+            let peers = srv.stored_peers.get_or_insert_with(Default::default);
+            peers.add(stored, updates);
+        }
+        if let Some(ldap) = config.ldap.as_ref() {
+            let updates = match reload_peers(|p| p.from_ldap) {
+                Some(reload) => peer_store::watch(
+                    "the LDAP directory",
+                    ldap.refresh.unwrap_or(ldap::DEFAULT_REFRESH),
+                    || true,
+                    current_ldap,
+                    reload,
+                    load_key(&vault),
+                    srv.waker.clone(),
+                ),
+                None => {
+                    log::warn!(
+                        "changes to the LDAP directory are only picked up with a config file"
+                    );
+                    std::sync::mpsc::channel().1
+                }
+            };
+            let peers = srv.stored_peers.get_or_insert_with(Default::default);
+            peers.add(from_ldap, updates);
+        }
+        if let Some(cfg) = config.api.as_ref() {
+            let Some(reload) = reload_peers(|p| p.provisioned) else {
+                return Err(
+                    Failure::Config.wrap(anyhow!("the api is only available with a config file"))
+                );
+            };
+            let mut api = api::ApiServer::bind(cfg, Box::new(reload), srv.waker.clone())
+                .failure(Failure::Bind)?;
+            api.peers = provisioned;
+            srv.enable_api(api)?;
+        }
+        if let Some(cfg) = config.enrollment.as_ref() {
+            let Some(reload) = reload_peers(|p| p.enrolled) else {
+                return Err(Failure::Config
+                    .wrap(anyhow!("enrollment is only available with a config file")));
+            };
+            let enrollment =
+                enrollment::EnrollmentServer::bind(cfg, Box::new(reload), srv.waker.clone())
+                    .failure(Failure::Bind)?;
+            srv.enable_enrollment(enrollment)?;
+        }
+        // stdout carries the keys alone
+        srv.stream_keys = config.stream_keys;
+        if srv.stream_keys || srv.peers.iter().any(|ap| ap.stream_keys) {
+            srv.key_output_to_log = true;
+        }
+        if let Some(revocation) = config.revocation.as_ref() {
+            srv.enable_revocation(revocation)?;
+        }
+
+        // before the workers copy the biscuit keys
+        srv.adopt_upgrade();
+        // peers whose sessions were handed over are not due yet
+        srv.stagger_startup();
+
+        if config.handshake_workers > 0 {
+            srv.start_handshake_workers(
+                config.handshake_workers,
+                &config.scheduling.handshake_workers,
             )?;
         }
+        // only now, so the workers do not inherit the settings of the event loop
+        config.scheduling.event_loop.apply()?;
 
-        srv.event_loop()
+        vault.spawn_token_renewal()?;
+        Ok(srv)
     }
 }
 
+/// Loads a config again, see `Cli::build_server`
+type Reload = Arc<dyn Fn() -> anyhow::Result<config::Rosenpass> + Send + Sync>;
+
+/// Marks the key generation seed in the seed files of gen-keys, and in
+/// secret-key files it used to append the seed to
+const SEED_MAGIC: &[u8; 8] = b"RPSEED01";
+
+/// Load a seed file written by gen-keys
+fn load_seed_file(path: &Path) -> anyhow::Result<Secret<KEYGEN_SEED_LEN>> {
+    let mut file = std::fs::read(path).with_context(|| format!("Could not load file {path:?}"))?;
+    let res = match file.strip_prefix(SEED_MAGIC) {
+        Some(seed) if seed.len() == KEYGEN_SEED_LEN => Ok(Secret::from_slice(seed)),
+        _ => Err(anyhow::anyhow!(
+            "{path:?} is no seed file written by gen-keys"
+        )),
+    };
+    rosenpass_sodium::helpers::memzero(&mut file);
+    res
+}
+
+/// Load a secret-key file, together with the key generation seed if it has
+/// one appended
+fn load_secret_key_file(path: &Path) -> anyhow::Result<(SSk, Option<Secret<KEYGEN_SEED_LEN>>)> {
+    let mut file = std::fs::read(path).with_context(|| format!("Could not load file {path:?}"))?;
+    let res = match file.len() {
+        len if len == StaticKEM::SK_LEN => Ok((SSk::from_slice(&file), None)),
+        len if len == StaticKEM::SK_LEN + SEED_MAGIC.len() + KEYGEN_SEED_LEN
+            && file[StaticKEM::SK_LEN..].starts_with(SEED_MAGIC) =>
+        {
+            let (sk, seed) = file.split_at(StaticKEM::SK_LEN);
+            Ok((
+                SSk::from_slice(sk),
+                Some(Secret::from_slice(&seed[SEED_MAGIC.len()..])),
+            ))
+        }
+        len => {
+            let hint = match len == StaticKEM::PK_LEN {
+                true => ", it looks like a public key",
+                false => "",
+            };
+            Err(anyhow::anyhow!(
+                "secret-key file {path:?} has {len} bytes instead of {}{hint}",
+                StaticKEM::SK_LEN
+            ))
+        }
+    };
+    rosenpass_sodium::helpers::memzero(&mut file);
+    res
+}
+
+/// Create a file only its owner can access, for secrets
+fn create_secret_file(path: &Path, force: bool) -> anyhow::Result<std::fs::File> {
+    OpenOptions::new()
+        .write(true)
+        .create(force)
+        .create_new(!force)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .with_context(|| format!("could not create {path:?}"))
+}
+
 trait StoreSecret {
     fn store_secret<P: AsRef<Path>>(&self, path: P) -> anyhow::Result<()>;
 }
@@ -4,7 +4,15 @@
 //! [Secret] is special:
 //! - as it is heap allocated, we can actively zeroize the memory before freeing it.
 //! - guard pages before and after each allocation trap accidential sequential reads that creep towards our secrets
+//! - a canary in front of each secret is checked whenever the secret is released, aborting the process if it was overwritten
 //! - the memory is mlocked, e.g. it is never swapped
+//! - with [zeroize_on_panic], all secrets are zeroized before a panic ends the process
+//!
+//! Locking is left to libsodium, which uses `mlock` on Unix and `VirtualLock`
+//! on Windows, along with `VirtualProtect` for the guard pages; there is no
+//! separate backend per platform. Rosenpass as a whole does not build on
+//! Windows yet, as the event loop, the control socket and the key output
+//! rely on Unix APIs.
 
 use anyhow::Context;
 use lazy_static::lazy_static;
@@ -24,7 +32,7 @@ use std::{
     os::raw::c_void,
     path::Path,
     ptr::null_mut,
-    sync::Mutex,
+    sync::{Mutex, MutexGuard, PoisonError, TryLockError},
 };
 
 // This might become a problem in library usage; it's effectively a memory
@@ -32,8 +40,15 @@ use std::{
 // be reused…
 lazy_static! {
     static ref SECRET_CACHE: Mutex<SecretMemoryPool> = Mutex::new(SecretMemoryPool::new());
+    /// Random value written in front of every secret, see [SecretMemoryPool]
+    static ref CANARY: [u8; CANARY_LEN] = mutating([0u8; CANARY_LEN], |c| {
+        rosenpass_sodium::helpers::randombytes_buf(c)
+    });
 }
 
+/// Length of the canary in front of each secret
+const CANARY_LEN: usize = 16;
+
 /// Pool that stores secret memory allocations
 ///
 /// Allocation of secret memory is expensive. Thus, this struct provides a
@@ -42,9 +57,18 @@ lazy_static! {
 ///
 /// Further information about the protection in place can be found in in the
 /// [libsodium documentation](https://libsodium.gitbook.io/doc/memory_management#guarded-heap-allocations)
+///
+/// The end of each secret is flush with the guard page after it, so reads
+/// and writes past the end fault right away. In front of it lies a canary
+/// of our own, then the canary of libsodium, which is only checked once the
+/// memory is freed, and then the guard page. As memory in the pool is reused
+/// rather than freed, our canary is checked each time a secret is released
+/// instead; the process is aborted if it was overwritten.
 #[derive(Debug)] // TODO check on Debug derive, is that clever
 pub struct SecretMemoryPool {
     pool: HashMap<usize, Vec<*mut c_void>>,
+    /// Secrets taken and not released yet, with their length
+    live: HashMap<*mut c_void, usize>,
 }
 
 impl SecretMemoryPool {
@@ -52,8 +76,9 @@ impl SecretMemoryPool {
     #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
         let pool = HashMap::new();
+        let live = HashMap::new();
 
-        Self { pool }
+        Self { pool, live }
     }
 
     /// Return secrete back to the pool for future re-use
@@ -73,8 +98,15 @@ impl SecretMemoryPool {
     /// After calling this function on a [Secret], the secret must never be
     /// used again for anything.
     unsafe fn release_by_ref<const N: usize>(&mut self, s: &mut Secret<N>) {
+        if !canary_intact(s) {
+            // someone wrote to memory right in front of a secret; better not
+            // go on with a corrupted heap
+            eprintln!("Memory in front of a secret was overwritten; aborting");
+            std::process::abort();
+        }
         s.zeroize();
         let Secret { ptr: secret } = s;
+        self.live.remove(secret);
         // don't call Secret::drop, that could cause a double free
         self.pool.entry(N).or_default().push(*secret);
     }
@@ -94,14 +126,18 @@ impl SecretMemoryPool {
     pub fn take<const N: usize>(&mut self) -> Secret<N> {
         let entry = self.pool.entry(N).or_default();
         let secret = entry.pop().unwrap_or_else(|| {
-            let ptr = unsafe { libsodium::sodium_malloc(N) };
+            let ptr = unsafe { libsodium::sodium_malloc(CANARY_LEN + N) };
             assert!(
                 !ptr.is_null(),
                 "libsodium::sodium_mallloc() returned a null ptr"
             );
-            ptr
+            unsafe {
+                std::ptr::copy_nonoverlapping(CANARY.as_ptr(), ptr as *mut u8, CANARY_LEN);
+                (ptr as *mut u8).add(CANARY_LEN) as *mut c_void
+            }
         });
 
+        self.live.insert(secret, N);
         let mut s = Secret { ptr: secret };
         s.zeroize();
         s
@@ -117,12 +153,61 @@ impl Drop for SecretMemoryPool {
     fn drop(&mut self) {
         for ptr in self.pool.drain().flat_map(|(_, x)| x.into_iter()) {
             unsafe {
-                libsodium::sodium_free(ptr);
+                libsodium::sodium_free((ptr as *mut u8).sub(CANARY_LEN) as *mut c_void);
+            }
+        }
+    }
+}
+
+/// The pool of all secrets; still usable after a panic while it was locked
+fn secret_cache() -> MutexGuard<'static, SecretMemoryPool> {
+    SECRET_CACHE.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Zeroize all secrets when a thread panics, then abort the process
+///
+/// Without this, a panic unwinds with the keys still in memory, where a core
+/// dump would find them. As the secrets of all threads are zeroized, the
+/// process can not go on afterwards, not even if the panic is caught.
+pub fn zeroize_on_panic() {
+    let prev = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        zeroize_all();
+        prev(info);
+        std::process::abort();
+    }));
+}
+
+/// Overwrite all secrets in use with zeros
+fn zeroize_all() {
+    // another thread may hold the lock for a moment; if the panic happened
+    // while this one held it, it never becomes free, so only try a while
+    for _ in 0..1000 {
+        let pool = match SECRET_CACHE.try_lock() {
+            Ok(pool) => pool,
+            Err(TryLockError::Poisoned(e)) => e.into_inner(),
+            Err(TryLockError::WouldBlock) => {
+                std::thread::yield_now();
+                continue;
             }
+        };
+        for (&ptr, &len) in pool.live.iter() {
+            rosenpass_sodium::helpers::memzero(unsafe {
+                std::slice::from_raw_parts_mut(ptr as *mut u8, len)
+            });
         }
+        return;
     }
 }
 
+/// Whether the canary in front of `s` is unchanged
+fn canary_intact<const N: usize>(s: &Secret<N>) -> bool {
+    // the canary lies in the same allocation, right before the secret
+    let canary =
+        unsafe { std::slice::from_raw_parts((s.ptr as *const u8).sub(CANARY_LEN), CANARY_LEN) };
+    rosenpass_constant_time::memeq(canary, &*CANARY)
+}
+
 /// # Safety
 ///
 /// No safety implications are known, since the `*mut c_void` in
@@ -145,12 +230,19 @@ impl<const N: usize> Clone for Secret<N> {
     }
 }
 
+/// # Safety
+///
+/// A [Secret] exclusively owns the memory behind its `*mut c_void`, just like a
+/// `Box<[u8; N]>` would; the memory is returned to the [SecretMemoryPool],
+/// which is [Send] itself and guarded by a mutex.
+unsafe impl<const N: usize> Send for Secret<N> {}
+
 impl<const N: usize> Drop for Secret<N> {
     fn drop(&mut self) {
         self.zeroize();
         // the invariant that the [Secret] is not used after the
         // `release_by_ref` call is guaranteed, since this is a drop implementation
-        unsafe { SECRET_CACHE.lock().unwrap().release_by_ref(self) };
+        unsafe { secret_cache().release_by_ref(self) };
         self.ptr = null_mut();
     }
 }
@@ -166,7 +258,7 @@ impl<const N: usize> Secret<N> {
     pub fn zero() -> Self {
         // Using [SecretMemoryPool] here because this operation is expensive,
         // yet it is used in hot loops
-        let s = SECRET_CACHE.lock().unwrap().take();
+        let s = secret_cache().take();
         assert_eq!(s.secret(), &[0u8; N]);
         s
     }
@@ -363,6 +455,33 @@ mod test {
         // and that the secret was zeroized
         assert_eq!(new_secret.secret(), &[0; N]);
     }
+
+    /// check that the secrets in use are known, so they can be zeroized
+    #[test]
+    fn secret_memory_pool_live() {
+        init();
+        const N: usize = 0x30;
+        let mut pool = SecretMemoryPool::new();
+        let secret: Secret<N> = pool.take();
+        assert_eq!(pool.live.get(&secret.ptr), Some(&N));
+        let ptr = secret.ptr;
+        pool.release(secret);
+        assert!(!pool.live.contains_key(&ptr));
+    }
+
+    /// check that writing right in front of a secret is noticed
+    #[test]
+    fn secret_memory_canary() {
+        init();
+        let secret = Secret::<32>::random();
+        assert!(canary_intact(&secret));
+        let before = unsafe { (secret.ptr as *mut u8).sub(1) };
+        unsafe { *before ^= 1 };
+        assert!(!canary_intact(&secret));
+        // restore it, or dropping the secret aborts the tests
+        unsafe { *before ^= 1 };
+        assert!(canary_intact(&secret));
+    }
 }
 
 trait StoreSecret {
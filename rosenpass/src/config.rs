@@ -1,29 +1,303 @@
 use std::{
-    collections::HashSet,
+    collections::{BTreeMap, HashSet},
     fs,
-    io::Write,
+    io::{Read, Write},
     net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, ToSocketAddrs},
     path::{Path, PathBuf},
 };
 
-use anyhow::{bail, ensure};
+use anyhow::{bail, ensure, Context};
+use rosenpass_sodium::helpers::memzero;
 use rosenpass_util::file::fopen_w;
 use serde::{Deserialize, Serialize};
 
+use crate::{
+    age,
+    alerts::Alerts,
+    api::{self, Api},
+    audit::Audit,
+    breaker::CircuitBreaker,
+    credential, dns,
+    enrollment::Enrollment,
+    exporter::{self, Export},
+    failover, fingerprint,
+    ha::HighAvailability,
+    interface,
+    keywrap::KeyWrap,
+    lazy_keys::LazyKeys,
+    ldap::Ldap,
+    liveness::DeadPeerPolicy,
+    lockdown::IpPrefix,
+    netns,
+    peer_store::PeerStore,
+    profile::Profile,
+    protocol::{ReplayMode, Timing, REJECT_AFTER_TIME, REKEY_STAGGER, UNENDING},
+    quota::QuotaConfig,
+    reuseport::ReusePort,
+    revocation::Revocation,
+    sched::Scheduling,
+    shed::LoadShedding,
+    sockopt::{MAX_BUFFER, MAX_DSCP},
+    stagger,
+    stale::StaleKeyPolicy,
+    unix,
+    vault::{VaultConfig, VaultSecret},
+    watch::{self, Watch},
+    xfrm::Xfrm,
+};
+
+/// Config path standing for stdin, see [Rosenpass::load]
+pub const STDIN: &str = "-";
+
+/// Peer endpoints starting with this are reached over QUIC; here rather
+/// than in `crate::quic`, which is only built with the `quic` feature
+pub const QUIC_PREFIX: &str = "quic:";
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Rosenpass {
     pub public_key: PathBuf,
 
+    #[serde(default)]
     pub secret_key: PathBuf,
 
+    /// Fetch the secret key from Vault instead of reading `secret_key`
+    #[serde(default)]
+    pub secret_key_vault: Option<VaultSecret>,
+
+    /// The `secret_key` file is wrapped, see [crate::keywrap]
+    #[serde(default)]
+    pub secret_key_wrap: Option<KeyWrap>,
+
+    /// Vault connection, used by all keys fetched from Vault
+    #[serde(default)]
+    pub vault: Option<VaultConfig>,
+
+    #[serde(default)]
     pub listen: Vec<SocketAddr>,
 
+    /// Unix domain datagram sockets to listen on, see [crate::unix]
+    #[serde(default)]
+    pub listen_unix: Vec<PathBuf>,
+
+    /// UDP sockets to accept QUIC connections on, see `crate::quic`
+    #[serde(default)]
+    pub listen_quic: Vec<SocketAddr>,
+
+    /// Network interface to bind the listen sockets to, so handshakes are
+    /// only sent and received through it; see [crate::interface]
+    #[serde(default)]
+    pub interface: Option<String>,
+
+    /// VRF device to bind the listen sockets to, so handshakes are routed by
+    /// the table of the VRF; see [crate::interface]
+    #[serde(default)]
+    pub vrf: Option<String>,
+
+    /// Network namespace to open the handshake sockets in, by the name `ip
+    /// netns` knows it by; see [crate::netns]
+    #[serde(default)]
+    pub netns: Option<String>,
+
+    /// Firewall mark of the handshake sockets, for policy routing to keep
+    /// handshakes out of the tunnel they key; see [crate::sockopt]
+    #[serde(default)]
+    pub fwmark: Option<u32>,
+
+    /// DSCP of the handshake datagrams sent, so QoS can prioritize them; see
+    /// [crate::sockopt]
+    #[serde(default)]
+    pub dscp: Option<u8>,
+
+    /// Size of the receive buffers of the handshake sockets in bytes, so
+    /// bursts of handshakes are not dropped; see [crate::sockopt]
+    #[serde(default)]
+    pub recv_buffer: Option<usize>,
+
+    /// Size of the send buffers of the handshake sockets in bytes; see
+    /// [crate::sockopt]
+    #[serde(default)]
+    pub send_buffer: Option<usize>,
+
     #[serde(default)]
     pub verbosity: Verbosity,
+
+    /// Unix domain socket to listen on for control commands
+    #[serde(default)]
+    pub control_socket: Option<PathBuf>,
+
+    #[serde(default)]
+    pub healthcheck: HealthcheckPolicy,
+
+    /// Number of threads answering InitHello messages; with none, they are
+    /// answered on the event loop, see [crate::workers]
+    #[serde(default)]
+    pub handshake_workers: usize,
+
+    /// CPU affinity and priority of the event loop and the handshake workers
+    #[serde(default)]
+    pub scheduling: Scheduling,
+
+    /// Coalesce timers into fewer wakeups, at the cost of handling them up to
+    /// two seconds late; meant for laptops and mobile devices
+    #[serde(default)]
+    pub low_power: bool,
+
+    /// Print the keys of all peers on stdout, see [crate::keystream]
+    #[serde(default)]
+    pub stream_keys: bool,
+
+    /// Announce ourselves and find configured peers on the local network
+    /// with multicast DNS, authenticated with their preshared keys; see
+    /// [crate::mdns]
+    #[serde(default)]
+    pub mdns: bool,
+
+    /// STUN servers like `stun.example.org:3478` to learn the address this
+    /// host is reachable at from outside its NAT from, see [crate::nat]
+    #[serde(default)]
+    pub stun_servers: Vec<String>,
+
+    /// Keep track of the endpoints of all peers and tell them each other's,
+    /// see [crate::rendezvous]
+    #[serde(default)]
+    pub rendezvous_server: bool,
+
+    /// Relay handshakes between peers which can not reach each other, see
+    /// [crate::relay]
+    #[serde(default)]
+    pub relay_server: bool,
+
+    /// Only use FIPS-approved symmetric primitives, see
+    /// [rosenpass_ciphers::fips]; peers in this mode can only exchange keys
+    /// with peers in this mode, and WireGuard still uses its own primitives
+    /// for the keys we hand it
+    #[serde(default)]
+    pub fips: bool,
+
+    /// Defaults of the settings below, see [crate::profile]
+    #[serde(default)]
+    pub profile: Profile,
+
+    /// Seconds before a key expires at which the next handshake is started,
+    /// leaving time for retransmissions on slow or lossy links; defaults to
+    /// that of the [Rosenpass::profile]
+    #[serde(default)]
+    pub rekey_margin: Option<f64>,
+
+    /// Seconds InitConf messages are accepted for after their RespHello was
+    /// sent, at least; they may be accepted for up to twice as long. Defaults
+    /// to that of the [Rosenpass::profile]
+    #[serde(default)]
+    pub replay_window: Option<f64>,
+
+    /// How strictly InitConf messages are checked for being replayed;
+    /// defaults to that of the [Rosenpass::profile]
+    #[serde(default)]
+    pub replay_mode: Option<ReplayMode>,
+
+    /// Ask responders to seal their messages to us with their own public
+    /// key, so observers who know ours can not tell who started a handshake;
+    /// see [crate::extensions]
+    #[serde(default)]
+    pub identity_hiding: bool,
+
+    /// Report peers which stop exchanging keys, see [crate::liveness]
+    #[serde(default)]
+    pub dead_peer: Option<DeadPeerPolicy>,
+
+    /// Back off from peers which stop answering, see [crate::breaker]
+    #[serde(default)]
+    pub circuit_breaker: CircuitBreaker,
+
+    /// Seconds the first handshakes with the peers are spread over, see
+    /// [crate::stagger]
+    #[serde(default)]
+    pub startup_jitter: Option<Timing>,
+
+    /// File kept across restarts whose seed is mixed into all randomness,
+    /// see [crate::hedged]
+    #[serde(default)]
+    pub random_seed: Option<PathBuf>,
+
+    /// Limits of the handshakes done for all peers and those of each tag,
+    /// see [crate::quota]
+    #[serde(default)]
+    pub quota: QuotaConfig,
+
+    /// Turn away new handshakes first under load, see [crate::shed]
+    #[serde(default)]
+    pub load_shedding: Option<LoadShedding>,
+
+    /// Read the public keys of peers only once they are needed, see
+    /// [crate::lazy_keys]
+    #[serde(default)]
+    pub lazy_keys: Option<LazyKeys>,
+
+    /// Stand by for, or replicate to, another instance serving the same
+    /// peers, see [crate::ha]
+    #[serde(default)]
+    pub high_availability: Option<HighAvailability>,
+
+    /// Spread the handshakes over worker processes sharing the listen
+    /// ports, see [crate::reuseport]
+    #[serde(default)]
+    pub reuseport: Option<ReusePort>,
+
+    /// Keep a signed log of security events, see [crate::audit]
+    #[serde(default)]
+    pub audit: Option<Audit>,
+
+    /// Pin peer public keys on first use in this file, see [crate::tofu]
+    #[serde(default)]
+    pub pin_store: Option<PathBuf>,
+
+    /// Send security events to webhooks, see [crate::alerts]
+    #[serde(default)]
+    pub alerts: Option<Alerts>,
+
+    /// Refuse peers on a signed list of revoked keys, see
+    /// [crate::revocation]
+    #[serde(default)]
+    pub revocation: Option<Revocation>,
+
+    /// Defaults shared by all peers referring to a group by name
+    #[serde(default)]
+    pub groups: BTreeMap<String, PeerGroup>,
+
     pub peers: Vec<RosenpassPeer>,
 
+    /// Directories of further peers, one file each, see [crate::watch]
+    #[serde(default)]
+    pub peer_dirs: Vec<PathBuf>,
+
+    /// Apply changes to the key files and the peer drop-ins as they are
+    /// made, see [crate::watch]
+    #[serde(default)]
+    pub watch: Option<Watch>,
+
+    /// Take further peers from a database, see [crate::peer_store]
+    #[serde(default)]
+    pub peer_store: Option<PeerStore>,
+
+    /// Take further peers from an LDAP directory, see [crate::ldap]
+    #[serde(default)]
+    pub ldap: Option<Ldap>,
+
+    /// Let peers be provisioned over HTTP, see [crate::api]
+    #[serde(default)]
+    pub api: Option<Api>,
+
+    /// Let new peers enroll themselves with one-time tokens, see
+    /// [crate::enrollment]
+    #[serde(default)]
+    pub enrollment: Option<Enrollment>,
+
     #[serde(skip)]
     pub config_file_path: PathBuf,
+
+    /// Whether the config file was encrypted, see [crate::age]
+    #[serde(skip)]
+    pub encrypted: bool,
 }
 
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
@@ -32,15 +306,150 @@ pub enum Verbosity {
     Verbose,
 }
 
+/// Conditions under which the healthcheck reports success
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct HealthcheckPolicy {
+    #[serde(default)]
+    pub fresh_keys: FreshKeys,
+
+    /// Maximum age in seconds of a key to be considered fresh; by default
+    /// every key that has not expired yet is fresh
+    #[serde(default)]
+    pub max_key_age: Option<f64>,
+}
+
+/// How many peers are required to have a fresh key for the instance to be healthy
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum FreshKeys {
+    /// Peers do not need to have fresh keys at all
+    None,
+    /// At least one peer needs to have a fresh key
+    #[default]
+    Any,
+    /// Every peer needs to have a fresh key
+    All,
+}
+
+/// Settings applied to every member of a peer group, see [Rosenpass::resolve_groups]
 #[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerGroup {
+    /// Tags given to every member in addition to its own
+    #[serde(default)]
+    pub tags: Vec<String>,
+
+    /// Domain appended to unqualified endpoint host names of members
+    #[serde(default)]
+    pub endpoint_domain: Option<String>,
+
+    /// Directory members without a `key_out` write their key to; the file is
+    /// named after the peer's public-key file with `.osk` appended
+    #[serde(default)]
+    pub key_out_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct RosenpassPeer {
+    #[serde(default)]
     pub public_key: PathBuf,
+
+    /// Name to tell the peer by, see [crate::fingerprint]
+    #[serde(default)]
+    pub name: Option<String>,
+
+    /// Fetch the public key from Vault instead of reading `public_key`
+    #[serde(default)]
+    pub public_key_vault: Option<VaultSecret>,
+
+    /// Public key of the CA `public_key` is a bundle signed by, instead of
+    /// the public key itself; see [crate::ca]
+    #[serde(default)]
+    pub ca: Option<PathBuf>,
+
+    /// Public key the peer had before rotating to `public_key`, accepted as
+    /// well for `rollover_window`; see [crate::protocol::Rollover]
+    #[serde(default)]
+    pub old_public_key: Option<PathBuf>,
+
+    /// Seconds after startup `old_public_key` is dropped after, a week by
+    /// default
+    #[serde(default)]
+    pub rollover_window: Option<u64>,
+
     pub endpoint: Option<String>,
+
+    /// Endpoints tried when `endpoint` does not answer; see [crate::failover]
+    #[serde(default)]
+    pub fallback_endpoints: Vec<String>,
+
+    /// Alternate between IPv6 and IPv4 addresses of the endpoints
+    #[serde(default)]
+    pub happy_eyeballs: bool,
+
     pub pre_shared_key: Option<PathBuf>,
 
     #[serde(default)]
     pub key_out: Option<PathBuf>,
 
+    /// Further keys derived from each handshake, see [crate::exporter]
+    #[serde(default)]
+    pub exports: Vec<Export>,
+
+    /// Name of the entry in [Rosenpass::groups] this peer belongs to
+    #[serde(default)]
+    pub group: Option<String>,
+
+    /// Free form labels, used to select peers in `status`
+    #[serde(default)]
+    pub tags: Vec<String>,
+
+    /// Send large handshake messages to this peer in fragments, for paths
+    /// with a small MTU; the peer answers fragmented messages in kind
+    #[serde(default)]
+    pub fragment: bool,
+
+    /// Seconds between keepalives sent to this peer while a key is
+    /// established, so NAT mappings on the way stay open until the next
+    /// handshake; none are sent by default
+    #[serde(default)]
+    pub keepalive: Option<u64>,
+
+    /// Only accept this peer from the addresses `endpoint` resolves to, and
+    /// keep sending to them even if the peer shows up elsewhere; see
+    /// [crate::lockdown]
+    #[serde(default)]
+    pub lock_endpoint: bool,
+
+    /// Networks this peer is accepted from, like `192.0.2.0/24`; listing any
+    /// locks the peer down just like `lock_endpoint`
+    #[serde(default)]
+    pub allowed_sources: Vec<String>,
+
+    /// Start handshakes with this peer in time slots shared with it, so
+    /// both can get through NAT on their side; needs synchronized clocks and
+    /// the peer setting this as well, see [crate::nat]
+    #[serde(default)]
+    pub hole_punching: bool,
+
+    /// The peer is a rendezvous server, to register with and to ask for the
+    /// endpoints of the other peers; see [crate::rendezvous]
+    #[serde(default)]
+    pub rendezvous: bool,
+
+    /// The peer is a relay server, to send handshakes with unreachable peers
+    /// through; see [crate::relay]
+    #[serde(default)]
+    pub relay: bool,
+
+    /// Network interface to send all messages to this peer through, instead
+    /// of the one the routing table picks; see [crate::interface]
+    #[serde(default)]
+    pub interface: Option<String>,
+
+    /// What becomes of the key once it is not renewed in time; see
+    /// [crate::stale]
+    #[serde(default)]
+    pub stale_key: StaleKeyPolicy,
+
     // TODO make sure failure does not crash but is logged
     #[serde(default)]
     pub exchange_command: Vec<String>,
@@ -48,30 +457,110 @@ pub struct RosenpassPeer {
     // TODO make this field only available on binary builds, not on library builds
     #[serde(flatten)]
     pub wg: Option<WireGuard>,
+
+    /// IPsec tunnel to key with the keys; see [crate::xfrm]
+    #[serde(default)]
+    pub xfrm: Option<Xfrm>,
+
+    /// The peer was taken from the [Rosenpass::peer_store]
+    #[serde(skip)]
+    pub stored: bool,
+
+    /// The peer was added through the [Rosenpass::api]
+    #[serde(skip)]
+    pub provisioned: bool,
+
+    /// The peer enrolled itself, see [Rosenpass::enrollment]
+    #[serde(skip)]
+    pub enrolled: bool,
+
+    /// The peer was found in the [Rosenpass::ldap] directory
+    #[serde(skip)]
+    pub from_ldap: bool,
+
+    /// The peer was read from one of the [Rosenpass::peer_dirs]
+    #[serde(skip)]
+    pub from_dir: bool,
 }
 
-#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct WireGuard {
     pub device: String,
     pub peer: String,
 
     #[serde(default)]
     pub extra_params: Vec<String>,
+
+    /// Set the key through the UAPI socket of a userspace WireGuard
+    /// implementation, usually `/var/run/wireguard/<device>.sock`, instead of
+    /// invoking `wg`
+    #[serde(default)]
+    pub uapi_socket: Option<PathBuf>,
+
+    /// Network namespace the device is in, to run `wg` inside of; see
+    /// [crate::netns]
+    #[serde(default)]
+    pub device_netns: Option<String>,
 }
 
 impl Rosenpass {
     /// Load a config file from a file path
     ///
     /// no validation is conducted
+    ///
+    /// A path of [STDIN] reads the config from stdin. Configs may be given
+    /// in TOML or in JSON, and may be encrypted, see [crate::age].
     pub fn load<P: AsRef<Path>>(p: P) -> anyhow::Result<Self> {
-        let mut config: Self = toml::from_str(&fs::read_to_string(&p)?)?;
+        let path = p.as_ref();
+        let data = match path == Path::new(STDIN) {
+            true => {
+                let mut data = Vec::new();
+                std::io::stdin()
+                    .read_to_end(&mut data)
+                    .context("could not read the config from stdin")?;
+                data
+            }
+            false => fs::read(path)?,
+        };
+        let mut config = match age::is_encrypted(&data) {
+            false => Self::parse(std::str::from_utf8(&data)?, false)?,
+            true => {
+                let mut plain = age::decrypt(path, &data)?;
+                let config = std::str::from_utf8(&plain)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|s| Self::parse(s, true));
+                memzero(&mut plain);
+                let mut config =
+                    config.with_context(|| format!("could not parse encrypted config {path:?}"))?;
+                config.encrypted = true;
+                config
+            }
+        };
 
-        config.config_file_path = p.as_ref().to_owned();
+        config.config_file_path = path.to_owned();
         Ok(config)
     }
 
+    /// Parse a config in TOML, or in JSON if it starts with `{`; with
+    /// `quiet`, errors do not quote the offending line
+    fn parse(text: &str, quiet: bool) -> anyhow::Result<Self> {
+        if text.trim_start().starts_with('{') {
+            // only ever reports the position of an error
+            return Ok(serde_json::from_str(text)?);
+        }
+        toml::from_str(text).map_err(|e| match quiet {
+            true => anyhow::anyhow!("{}", e.message()),
+            false => e.into(),
+        })
+    }
+
     /// Write a config to a file
     pub fn store<P: AsRef<Path>>(&self, p: P) -> anyhow::Result<()> {
+        ensure!(
+            !self.encrypted,
+            "not writing the encrypted config {:?} back in plain text",
+            self.config_file_path
+        );
         let serialized_config =
             toml::to_string_pretty(&self).expect("unable to serialize the default config");
         fs::write(p, serialized_config)?;
@@ -80,6 +569,15 @@ impl Rosenpass {
 
     /// Commit the configuration to where it came from, overwriting the original file
     pub fn commit(&self) -> anyhow::Result<()> {
+        ensure!(
+            !self.encrypted,
+            "not writing the encrypted config {:?} back in plain text",
+            self.config_file_path
+        );
+        ensure!(
+            self.config_file_path != Path::new(STDIN),
+            "the config was read from stdin and can not be written back"
+        );
         let mut f = fopen_w(&self.config_file_path)?;
         f.write_all(toml::to_string_pretty(&self)?.as_bytes())?;
 
@@ -95,23 +593,94 @@ impl Rosenpass {
             self.public_key
         );
 
-        // check the secret-key file exists
+        // check the secret-key file exists; it may also be a pipe, like an
+        // inherited file descriptor in container mode
+        let readable = |p: &Path| p.metadata().is_ok_and(|m| !m.is_dir());
         ensure!(
-            self.secret_key.is_file(),
+            self.secret_key_vault.is_some() || readable(&self.secret_key),
             "secret-key file {:?} does not exist",
             self.secret_key
         );
 
+        let mut names = HashSet::new();
         for (i, peer) in self.peers.iter().enumerate() {
+            if let Some(name) = peer.name.as_ref() {
+                fingerprint::validate_name(name)?;
+                ensure!(
+                    names.insert(name),
+                    "peer {i} is named {name:?} like another peer"
+                );
+            }
+
+            // check the peer's group is defined
+            if let Some(group) = peer.group.as_ref() {
+                ensure!(
+                    self.groups.contains_key(group),
+                    "peer {i} refers to undefined group {group:?}"
+                );
+            }
+
             // check peer's public-key file exists
             ensure!(
-                peer.public_key.is_file(),
+                peer.public_key_vault.is_some() || peer.public_key.is_file(),
                 "peer {i} public-key file {:?} does not exist",
                 peer.public_key
             );
+            if let Some(ca) = peer.ca.as_ref() {
+                ensure!(
+                    peer.public_key_vault.is_none(),
+                    "peer {i} can not take its public key from both Vault and a CA bundle"
+                );
+                ensure!(ca.is_file(), "peer {i} CA public key {ca:?} does not exist");
+            }
+            if let Some(old) = peer.old_public_key.as_ref() {
+                ensure!(
+                    old.is_file(),
+                    "peer {i} old public-key file {old:?} does not exist"
+                );
+            }
 
             // check endpoint is usable
-            if let Some(addr) = peer.endpoint.as_ref() {
+            if let Some(name) = peer
+                .endpoint
+                .as_ref()
+                .and_then(|e| e.strip_prefix(dns::PREFIX))
+            {
+                ensure!(
+                    !name.is_empty(),
+                    "peer {i} endpoint {} lacks the name to look up",
+                    dns::PREFIX
+                );
+            } else if let Some(path) = peer
+                .endpoint
+                .as_ref()
+                .and_then(|e| e.strip_prefix(unix::PREFIX))
+            {
+                ensure!(
+                    !path.is_empty(),
+                    "peer {i} endpoint {} lacks the path of the socket",
+                    unix::PREFIX
+                );
+                ensure!(
+                    !self.listen_unix.is_empty(),
+                    "peer {i} endpoint {} needs a unix socket in listen_unix",
+                    unix::PREFIX
+                );
+            } else if let Some(addr) = peer
+                .endpoint
+                .as_ref()
+                .and_then(|e| e.strip_prefix(QUIC_PREFIX))
+            {
+                ensure!(
+                    cfg!(feature = "quic"),
+                    "peer {i} endpoint {QUIC_PREFIX} needs rosenpass built with the quic feature"
+                );
+                ensure!(
+                    addr.rsplit_once(':')
+                        .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok()),
+                    "peer {i} endpoint {QUIC_PREFIX} needs a host and a port"
+                );
+            } else if let Some(addr) = peer.endpoint.as_ref() {
                 ensure!(
                     addr.to_socket_addrs().is_ok(),
                     "peer {i} endpoint {} can not be parsed to a socket address",
@@ -119,9 +688,232 @@ impl Rosenpass {
                 );
             }
 
+            if let Err(e) = failover::validate(peer.endpoint.as_deref(), &peer.fallback_endpoints) {
+                bail!("peer {i} {e}");
+            }
+
+            ensure!(
+                peer.keepalive != Some(0),
+                "peer {i} keepalive interval must be at least one second"
+            );
+
+            ensure!(
+                !peer.lock_endpoint || peer.endpoint.is_some(),
+                "peer {i} can not lock_endpoint without an endpoint"
+            );
+            ensure!(
+                !peer.hole_punching || peer.endpoint.is_some(),
+                "peer {i} can not use hole_punching without an endpoint"
+            );
+            ensure!(
+                !peer.rendezvous || peer.endpoint.is_some(),
+                "peer {i} can not be a rendezvous server without an endpoint"
+            );
+            ensure!(
+                !peer.relay || peer.endpoint.is_some(),
+                "peer {i} can not be a relay server without an endpoint"
+            );
+            for src in peer.allowed_sources.iter() {
+                src.parse::<IpPrefix>()
+                    .with_context(|| format!("peer {i} allowed_sources"))?;
+            }
+            if let Some(name) = peer.interface.as_ref() {
+                if let Err(e) = interface::validate_name(name) {
+                    bail!("peer {i} {e}");
+                }
+            }
+            if let Err(e) = peer.stale_key.validate() {
+                bail!("peer {i} {e}");
+            }
+            if let Err(e) = exporter::validate(&peer.exports) {
+                bail!("peer {i} {e}");
+            }
+            if let Some(key_out) = peer.key_out.as_ref() {
+                ensure!(
+                    peer.exports.iter().all(|ex| ex.key_out != *key_out),
+                    "peer {i} exports a key to its key_out {key_out:?}"
+                );
+            }
+
+            // extra parameters are passed to `wg set`, which is not used with a UAPI socket
+            if let Some(wg) = peer.wg.as_ref() {
+                ensure!(
+                    wg.uapi_socket.is_none() || wg.extra_params.is_empty(),
+                    "peer {i} can not use extra_params together with uapi_socket"
+                );
+                if let Some(name) = wg.device_netns.as_ref() {
+                    if let Err(e) = netns::validate_name(name) {
+                        bail!("peer {i} {e}");
+                    }
+                }
+            }
+
+            if let Some(xfrm) = peer.xfrm.as_ref() {
+                if let Err(e) = xfrm.validate() {
+                    bail!("peer {i} {e}");
+                }
+            }
+
             // TODO warn if neither out_key nor exchange_command is defined
         }
 
+        for server in self.stun_servers.iter() {
+            ensure!(
+                server.to_socket_addrs().is_ok(),
+                "STUN server {server} can not be parsed to a socket address"
+            );
+        }
+        ensure!(
+            cfg!(feature = "quic") || self.listen_quic.is_empty(),
+            "listen_quic needs rosenpass built with the quic feature"
+        );
+        // without listen, there only are unix sockets
+        if self.listen.is_empty() && !self.listen_unix.is_empty() {
+            ensure!(
+                !self.mdns && self.stun_servers.is_empty(),
+                "mdns and stun_servers need UDP sockets to listen on"
+            );
+            ensure!(
+                self.interface.is_none() && self.vrf.is_none(),
+                "unix sockets can not be bound to an interface"
+            );
+        }
+
+        if let Some(name) = self.interface.as_ref() {
+            interface::validate_name(name)?;
+        }
+        if let Some(name) = self.vrf.as_ref() {
+            interface::validate_name(name)?;
+            ensure!(
+                self.interface.is_none(),
+                "interface and vrf can not both be set, a socket is bound to one device only"
+            );
+        }
+        if let Some(name) = self.netns.as_ref() {
+            netns::validate_name(name)?;
+        }
+        if let Some(dscp) = self.dscp {
+            ensure!(dscp <= MAX_DSCP, "dscp must be at most {MAX_DSCP}");
+        }
+        for (name, size) in [
+            ("recv_buffer", self.recv_buffer),
+            ("send_buffer", self.send_buffer),
+        ] {
+            if let Some(size) = size {
+                ensure!(
+                    (1..=MAX_BUFFER).contains(&size),
+                    "{name} must be between 1 and {MAX_BUFFER}"
+                );
+            }
+        }
+        self.scheduling.validate()?;
+        if let Some(margin) = self.rekey_margin {
+            // both sides must start a handshake before the key expires, and not
+            // right after exchanging one
+            let (min, max) = (REKEY_STAGGER, REJECT_AFTER_TIME - REKEY_STAGGER);
+            ensure!(
+                min < margin && margin < max,
+                "rekey_margin must be between {min} and {max} seconds"
+            );
+        }
+        if let Some(window) = self.replay_window {
+            // the initiator must be able to retransmit its InitConf
+            const MIN: Timing = 10.0;
+            ensure!(
+                (MIN..=UNENDING).contains(&window),
+                "replay_window must be between {MIN} and {UNENDING} seconds"
+            );
+        }
+        if let Some(policy) = self.dead_peer.as_ref() {
+            policy.validate()?;
+        }
+        self.circuit_breaker.validate()?;
+        if let Some(jitter) = self.startup_jitter {
+            stagger::validate(jitter)?;
+        }
+        self.quota.validate()?;
+        if let Some(shedding) = self.load_shedding.as_ref() {
+            shedding.validate()?;
+        }
+        if let Some(lazy) = self.lazy_keys.as_ref() {
+            lazy.validate()?;
+            ensure!(
+                !self.identity_hiding,
+                "lazy_keys can not be used with identity_hiding"
+            );
+        }
+        if let Some(ha) = self.high_availability.as_ref() {
+            ha.validate()?;
+        }
+        if let Some(reuseport) = self.reuseport.as_ref() {
+            reuseport.validate()?;
+            ensure!(
+                !self.listen.is_empty() && self.listen.iter().all(|addr| addr.port() != 0),
+                "reuseport needs listen addresses with a port"
+            );
+            ensure!(
+                self.listen_unix.is_empty()
+                    && self.listen_quic.is_empty()
+                    && !self.mdns
+                    && !self.rendezvous_server
+                    && !self.relay_server
+                    && self.api.is_none()
+                    && self.enrollment.is_none()
+                    && self.high_availability.is_none(),
+                "reuseport can not be used with listen_unix, listen_quic, mdns, \
+                 rendezvous_server, relay_server, api, enrollment or high_availability"
+            );
+        }
+        if let Some(audit) = self.audit.as_ref() {
+            audit.validate()?;
+        }
+        if let Some(alerts) = self.alerts.as_ref() {
+            alerts.validate()?;
+        }
+        if let Some(revocation) = self.revocation.as_ref() {
+            revocation.validate()?;
+        }
+        if let Some(watch) = self.watch.as_ref() {
+            watch.validate()?;
+        }
+        if let Some(store) = self.peer_store.as_ref() {
+            ensure!(
+                store.refresh != Some(0),
+                "peer_store refresh interval must be at least one second"
+            );
+        }
+        if let Some(api) = self.api.as_ref() {
+            api.validate()?;
+        }
+        if let Some(ldap) = self.ldap.as_ref() {
+            ldap.validate()?;
+            if let Some(group) = ldap.group.as_ref() {
+                ensure!(
+                    self.groups.contains_key(group),
+                    "ldap refers to undefined group {group:?}"
+                );
+            }
+        }
+        if let Some(enrollment) = self.enrollment.as_ref() {
+            enrollment.validate()?;
+            if let Some(group) = enrollment.group.as_ref() {
+                ensure!(
+                    self.groups.contains_key(group),
+                    "enrollment refers to undefined group {group:?}"
+                );
+            }
+        }
+        if self.fips {
+            ensure!(
+                self.secret_key_wrap.is_none(),
+                "wrapped secret keys are encrypted with XChaCha20-Poly1305, which fips mode does not allow"
+            );
+            ensure!(
+                self.audit.is_none(),
+                "the audit log is chained with BLAKE2b, which fips mode does not allow"
+            );
+        }
+
         Ok(())
     }
 
@@ -130,11 +922,220 @@ impl Rosenpass {
         Self {
             public_key: PathBuf::from(public_key.as_ref()),
             secret_key: PathBuf::from(secret_key.as_ref()),
+            secret_key_vault: None,
+            secret_key_wrap: None,
+            vault: None,
             listen: vec![],
+            listen_unix: vec![],
+            listen_quic: vec![],
+            interface: None,
+            vrf: None,
+            netns: None,
+            fwmark: None,
+            dscp: None,
+            recv_buffer: None,
+            send_buffer: None,
             verbosity: Verbosity::Quiet,
+            control_socket: None,
+            healthcheck: HealthcheckPolicy::default(),
+            handshake_workers: 0,
+            scheduling: Scheduling::default(),
+            low_power: false,
+            stream_keys: false,
+            mdns: false,
+            stun_servers: vec![],
+            rendezvous_server: false,
+            relay_server: false,
+            fips: false,
+            profile: Profile::default(),
+            rekey_margin: None,
+            replay_window: None,
+            replay_mode: None,
+            identity_hiding: false,
+            dead_peer: None,
+            circuit_breaker: CircuitBreaker::default(),
+            startup_jitter: None,
+            random_seed: None,
+            quota: QuotaConfig::default(),
+            load_shedding: None,
+            lazy_keys: None,
+            high_availability: None,
+            reuseport: None,
+            audit: None,
+            pin_store: None,
+            alerts: None,
+            revocation: None,
+            groups: BTreeMap::new(),
             peers: vec![],
+            peer_dirs: vec![],
+            watch: None,
+            peer_store: None,
+            api: None,
+            enrollment: None,
+            ldap: None,
             config_file_path: PathBuf::new(),
+            encrypted: false,
+        }
+    }
+
+    /// Append the peers kept outside of the config file: those of the
+    /// [Rosenpass::peer_dirs], of the [Rosenpass::peer_store] and the
+    /// [Rosenpass::ldap] directory, those added through the [Rosenpass::api]
+    /// and the [Rosenpass::enrollment] ones, in this order
+    pub fn load_external_peers(&mut self) -> anyhow::Result<()> {
+        self.load_peer_dirs()?;
+        self.load_peer_store()?;
+        self.load_ldap_peers()?;
+        self.load_api_peers()?;
+        self.load_enrolled_peers()
+    }
+
+    /// Append the peers of the [Rosenpass::peer_store], if there is one
+    ///
+    /// Like the peers of the config file, they are neither resolved nor
+    /// validated yet. Loading them more than once has no further effect.
+    pub fn load_peer_store(&mut self) -> anyhow::Result<()> {
+        let Some(store) = self.peer_store.as_ref() else {
+            return Ok(());
+        };
+        self.peers.retain(|peer| !peer.stored);
+        let peers = store
+            .load()
+            .with_context(|| format!("could not load the peer store {:?}", store.database))?;
+        self.peers.extend(peers);
+        Ok(())
+    }
+
+    /// Append the peers of the drop-ins in the [Rosenpass::peer_dirs]
+    ///
+    /// Just like [Self::load_peer_store], which they are taken before.
+    pub fn load_peer_dirs(&mut self) -> anyhow::Result<()> {
+        self.peers.retain(|peer| !peer.from_dir);
+        for dir in self.peer_dirs.iter() {
+            self.peers.extend(watch::load_dir(dir)?);
         }
+        Ok(())
+    }
+
+    /// Append the peers of the [Rosenpass::ldap] directory, if there is one
+    ///
+    /// Just like [Self::load_peer_store].
+    pub fn load_ldap_peers(&mut self) -> anyhow::Result<()> {
+        let Some(ldap) = self.ldap.as_ref() else {
+            return Ok(());
+        };
+        self.peers.retain(|peer| !peer.from_ldap);
+        let peers = ldap
+            .load()
+            .with_context(|| format!("could not load the peers of {:?}", ldap.uri))?;
+        self.peers.extend(peers);
+        Ok(())
+    }
+
+    /// Append the peers added through the [Rosenpass::api], if it is enabled
+    ///
+    /// Just like [Self::load_peer_store], which they are taken after.
+    pub fn load_api_peers(&mut self) -> anyhow::Result<()> {
+        let Some(api) = self.api.as_ref() else {
+            return Ok(());
+        };
+        self.peers.retain(|peer| !peer.provisioned);
+        let mut peers = api::load(&api.directory)?;
+        for peer in peers.iter_mut() {
+            peer.provisioned = true;
+        }
+        self.peers.extend(peers);
+        Ok(())
+    }
+
+    /// Append the peers which enrolled themselves, members of the group of
+    /// the [Rosenpass::enrollment] unless they name one of their own
+    ///
+    /// Just like [Self::load_peer_store].
+    pub fn load_enrolled_peers(&mut self) -> anyhow::Result<()> {
+        let Some(enrollment) = self.enrollment.as_ref() else {
+            return Ok(());
+        };
+        self.peers.retain(|peer| !peer.enrolled);
+        let mut peers = api::load(&enrollment.directory)?;
+        for peer in peers.iter_mut() {
+            peer.enrolled = true;
+            if peer.group.is_none() {
+                peer.group = enrollment.group.clone();
+            }
+        }
+        self.peers.extend(peers);
+        Ok(())
+    }
+
+    /// Apply the settings of each peer's group to the peer itself
+    ///
+    /// Settings of the peer take precedence; group tags are added to the
+    /// peer's own. Applying the groups more than once has no further effect.
+    pub fn resolve_groups(&mut self) -> anyhow::Result<()> {
+        for (i, peer) in self.peers.iter_mut().enumerate() {
+            let Some(name) = peer.group.as_ref() else {
+                continue;
+            };
+            let Some(group) = self.groups.get(name) else {
+                bail!("peer {i} refers to undefined group {name:?}");
+            };
+
+            for tag in group.tags.iter() {
+                if !peer.tags.contains(tag) {
+                    peer.tags.push(tag.clone());
+                }
+            }
+
+            if let (Some(domain), Some(endpoint)) =
+                (group.endpoint_domain.as_ref(), peer.endpoint.as_mut())
+            {
+                *endpoint = qualify_endpoint(endpoint, domain);
+            }
+
+            if let (Some(dir), None) = (group.key_out_dir.as_ref(), peer.key_out.as_ref()) {
+                let Some(name) = peer.public_key.file_name() else {
+                    bail!("peer {i} public-key {:?} has no file name", peer.public_key);
+                };
+                let mut name = name.to_owned();
+                name.push(".osk");
+                peer.key_out = Some(dir.join(name));
+            }
+        }
+        Ok(())
+    }
+
+    /// Replace `credential:<name>` key paths by the paths of the systemd
+    /// credentials, see [crate::credential]
+    pub fn resolve_credentials(&mut self) -> anyhow::Result<()> {
+        credential::resolve(&mut self.public_key)?;
+        credential::resolve(&mut self.secret_key)?;
+        if let Some(KeyWrap::Age {
+            identity: Some(identity),
+            ..
+        }) = self.secret_key_wrap.as_mut()
+        {
+            credential::resolve(identity)?;
+        }
+        if let Some(revocation) = self.revocation.as_mut() {
+            credential::resolve(&mut revocation.ca)?;
+        }
+        if let Some(api) = self.api.as_mut() {
+            credential::resolve(&mut api.token)?;
+        }
+        for peer in self.peers.iter_mut() {
+            credential::resolve(&mut peer.public_key)?;
+            for path in [
+                &mut peer.ca,
+                &mut peer.old_public_key,
+                &mut peer.pre_shared_key,
+            ] {
+                if let Some(path) = path.as_mut() {
+                    credential::resolve(path)?;
+                }
+            }
+        }
+        Ok(())
     }
 
     /// Add IPv4 __and__ IPv6 IF_ANY address to the listen interfaces
@@ -161,6 +1162,8 @@ impl Rosenpass {
             OwnPublicKey,
             OwnSecretKey,
             OwnListen,
+            OwnControlSocket,
+            OwnPinStore,
             Peer,
             PeerPsk,
             PeerPublicKey,
@@ -190,6 +1193,8 @@ impl Rosenpass {
                     OwnSecretKey
                 }
                 (Own, "listen", None) => OwnListen,
+                (Own, "control-socket", None) => OwnControlSocket,
+                (Own, "pin-store", None) => OwnPinStore,
                 (Own, "verbose", None) => {
                     config.verbosity = Verbosity::Verbose;
                     Own
@@ -227,12 +1232,31 @@ impl Rosenpass {
                 }
                 (OwnListen, l, None) => {
                     already_set.insert(OwnListen); // multiple listen directives are allowed
-                    for socket_addr in l.to_socket_addrs()? {
-                        config.listen.push(socket_addr);
+                    if let Some(path) = l.strip_prefix(unix::PREFIX) {
+                        config.listen_unix.push(path.into());
+                    } else if let Some(addr) = l.strip_prefix(QUIC_PREFIX) {
+                        config.listen_quic.extend(addr.to_socket_addrs()?);
+                    } else {
+                        for socket_addr in l.to_socket_addrs()? {
+                            config.listen.push(socket_addr);
+                        }
                     }
 
                     Own
                 }
+                (OwnControlSocket, path, None) => {
+                    ensure!(
+                        already_set.insert(OwnControlSocket),
+                        "control-socket was already set"
+                    );
+                    config.control_socket = Some(path.into());
+                    Own
+                }
+                (OwnPinStore, path, None) => {
+                    ensure!(already_set.insert(OwnPinStore), "pin-store was already set");
+                    config.pin_store = Some(path.into());
+                    Own
+                }
                 (Peer | PeerWireguardExtraArgs, "peer", maybe_peer @ Some(_)) => {
                     // TODO check current peer
                     // commit current peer, create a new one
@@ -308,7 +1332,11 @@ impl Rosenpass {
                 (Own, x, None) => {
                     bail!("unrecognised argument {x}");
                 }
-                (Own | OwnPublicKey | OwnSecretKey | OwnListen, _, Some(_)) => {
+                (
+                    Own | OwnPublicKey | OwnSecretKey | OwnListen | OwnControlSocket | OwnPinStore,
+                    _,
+                    Some(_),
+                ) => {
                     panic!("current_peer is not None while in Own* state, this must never happen")
                 }
 
@@ -346,7 +1374,14 @@ impl Rosenpass {
     pub fn example_config() -> Self {
         let peer = RosenpassPeer {
             public_key: "rp-peer-public-key".into(),
+            name: None,
+            public_key_vault: None,
+            ca: None,
+            old_public_key: None,
+            rollover_window: None,
             endpoint: Some("my-peer.test:9999".into()),
+            fallback_endpoints: vec![],
+            happy_eyeballs: false,
             exchange_command: [
                 "wg",
                 "set",
@@ -360,8 +1395,26 @@ impl Rosenpass {
             .map(|x| x.to_string())
             .collect(),
             key_out: Some("rp-key-out".into()),
+            exports: vec![],
+            stored: false,
+            provisioned: false,
+            enrolled: false,
+            from_ldap: false,
+            from_dir: false,
             pre_shared_key: None,
+            group: None,
+            tags: vec![],
+            fragment: false,
+            keepalive: None,
+            lock_endpoint: false,
+            allowed_sources: vec![],
+            hole_punching: false,
+            rendezvous: false,
+            relay: false,
+            interface: None,
+            stale_key: StaleKeyPolicy::default(),
             wg: None,
+            xfrm: None,
         };
 
         Self {
@@ -373,6 +1426,30 @@ impl Rosenpass {
     }
 }
 
+/// Append `domain` to the host of `endpoint` unless the host is an IP
+/// address or already contains a dot
+///
+/// SRV names are qualified if they consist of service and protocol only, as
+/// in `dns-srv:_rosenpass._udp`.
+fn qualify_endpoint(endpoint: &str, domain: &str) -> String {
+    if let Some(name) = endpoint.strip_prefix(dns::PREFIX) {
+        return match name.split('.').all(|label| label.starts_with('_')) {
+            true => format!("{}{name}.{}", dns::PREFIX, domain.trim_start_matches('.')),
+            false => endpoint.to_owned(),
+        };
+    }
+    match endpoint.rsplit_once(':') {
+        Some((host, port))
+            if !host.contains('.')
+                && !host.starts_with('[')
+                && host.parse::<Ipv4Addr>().is_err() =>
+        {
+            format!("{host}.{}:{port}", domain.trim_start_matches('.'))
+        }
+        _ => endpoint.to_owned(),
+    }
+}
+
 impl Default for Verbosity {
     fn default() -> Self {
         Self::Quiet
@@ -418,6 +1495,102 @@ mod test {
         )
     }
 
+    #[test]
+    fn test_cli_parse_control_socket() {
+        let args = split_str(
+            "public-key /my/public-key secret-key /my/secret-key \
+                control-socket /run/rosenpass.sock peer public-key /peer/public-key",
+        );
+
+        let config = Rosenpass::parse_args(args).unwrap();
+        assert_eq!(
+            config.control_socket,
+            Some(PathBuf::from("/run/rosenpass.sock"))
+        );
+        assert_eq!(config.peers.len(), 1);
+    }
+
+    #[test]
+    fn test_parse_json_and_toml() {
+        let toml = r#"
+            public_key = "/my/public-key"
+            listen = ["0.0.0.0:9999"]
+            verbosity = "Quiet"
+            [[peers]]
+            public_key = "/peer/public-key"
+            key_out = "stdout"
+        "#;
+        let json = r#" {"public_key": "/my/public-key", "listen": ["0.0.0.0:9999"],
+            "verbosity": "Quiet", "peers": [{"public_key": "/peer/public-key",
+            "key_out": "stdout"}]} "#;
+        for text in [toml, json] {
+            let config = Rosenpass::parse(text, false).unwrap();
+            assert_eq!(config.public_key, PathBuf::from("/my/public-key"));
+            assert_eq!(config.peers[0].key_out, Some(PathBuf::from("stdout")));
+        }
+
+        let quiet = Rosenpass::parse("public_key = 3 # secret", true).unwrap_err();
+        assert!(!quiet.to_string().contains("secret"));
+    }
+
+    #[test]
+    fn test_resolve_groups() {
+        let mut config = Rosenpass::new("", "");
+        config.groups.insert(
+            "office".into(),
+            PeerGroup {
+                tags: vec!["office".into(), "berlin".into()],
+                endpoint_domain: Some("vpn.example.org".into()),
+                key_out_dir: Some("/run/rosenpass".into()),
+            },
+        );
+        config.peers = vec![
+            RosenpassPeer {
+                public_key: "/peers/alice.pk".into(),
+                endpoint: Some("alice:9999".into()),
+                group: Some("office".into()),
+                tags: vec!["berlin".into()],
+                ..Default::default()
+            },
+            RosenpassPeer {
+                public_key: "/peers/bob.pk".into(),
+                endpoint: Some("10.0.0.2:9999".into()),
+                key_out: Some("/bob/rp-out".into()),
+                group: Some("office".into()),
+                ..Default::default()
+            },
+            RosenpassPeer {
+                public_key: "/peers/pool.pk".into(),
+                endpoint: Some("dns-srv:_rosenpass._udp".into()),
+                group: Some("office".into()),
+                ..Default::default()
+            },
+        ];
+
+        config.resolve_groups().unwrap();
+        config.resolve_groups().unwrap();
+
+        let (alice, bob) = (&config.peers[0], &config.peers[1]);
+        assert_eq!(alice.tags, vec!["berlin".to_string(), "office".to_string()]);
+        assert_eq!(
+            alice.endpoint.as_deref(),
+            Some("alice.vpn.example.org:9999")
+        );
+        assert_eq!(
+            alice.key_out,
+            Some(PathBuf::from("/run/rosenpass/alice.pk.osk"))
+        );
+        assert_eq!(bob.endpoint.as_deref(), Some("10.0.0.2:9999"));
+        assert_eq!(bob.key_out, Some(PathBuf::from("/bob/rp-out")));
+        assert_eq!(
+            config.peers[2].endpoint.as_deref(),
+            Some("dns-srv:_rosenpass._udp.vpn.example.org")
+        );
+
+        config.peers[1].group = Some("home".into());
+        assert!(config.resolve_groups().is_err());
+    }
+
     #[test]
     fn test_cli_parse_multiple_peers() {
         let args = split_str(
@@ -0,0 +1,498 @@
+//! Editing the peers of a config file in place
+//!
+//! `rosenpass peer -c CONFIG_FILE add|remove|show` changes the `[[peers]]` of
+//! a TOML config file without touching anything else in it, comments and
+//! formatting included. A peer is added with just the settings given on the
+//! command line:
+//!
+//! ```text
+//! rosenpass peer -c rp.toml add peer.rosenpass-public/pqpk \
+//!     --endpoint peer.example.com:9999 --wg-device rp0 --wg-peer <WG_PUBLIC_KEY>
+//! ```
+//!
+//! With `--dir`, the peers are those of one of the `peer_dirs` of the config
+//! instead, and a peer is added as a drop-in of its own, named after its
+//! fingerprint; see [crate::watch].
+//!
+//! Before the file is replaced, the edited config has to pass the same
+//! checks as on startup; it is written to a temporary file next to it first,
+//! which is renamed into place, so a running instance or a crash never sees
+//! half of it. Instances watching their files take up the change by
+//! themselves; with `--reload`, the instance is asked to over its control
+//! socket, and otherwise the change is picked up on the next start.
+//! Encrypted configs, JSON configs and stdin can not be edited.
+
+use anyhow::{bail, ensure, Context, Result};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+use toml_edit::{value, Array, ArrayOfTables, Document, Item, Table};
+
+use crate::{age, config, watch};
+
+/// The settings of a peer to add, as given on the command line
+#[derive(clap::Args, Debug, Default)]
+pub struct PeerArgs {
+    /// public-key file of the peer, or its CA bundle with --ca
+    pub public_key: PathBuf,
+
+    /// Public key of the CA which signed the bundle given as public key
+    #[clap(long)]
+    pub ca: Option<PathBuf>,
+
+    /// Address to initiate handshakes with, as <HOST>:<PORT>
+    #[clap(long)]
+    pub endpoint: Option<String>,
+
+    /// File with the base64 encoded preshared key
+    #[clap(long)]
+    pub pre_shared_key: Option<PathBuf>,
+
+    /// File the exchanged keys are written to
+    #[clap(long)]
+    pub key_out: Option<PathBuf>,
+
+    /// Group of the config the peer belongs to
+    #[clap(long)]
+    pub group: Option<String>,
+
+    /// Label of the peer; may be given several times
+    #[clap(long = "tag")]
+    pub tags: Vec<String>,
+
+    /// WireGuard device the keys are set on
+    #[clap(long, requires = "wg_peer")]
+    pub wg_device: Option<String>,
+
+    /// WireGuard public key of the peer on that device
+    #[clap(long, requires = "wg_device")]
+    pub wg_peer: Option<String>,
+}
+
+impl PeerArgs {
+    /// The `[[peers]]` table with the settings given, and no others
+    pub fn table(&self) -> Table {
+        let mut table = Table::new();
+        let path = |p: &Path| value(p.display().to_string());
+        table["public_key"] = path(&self.public_key);
+        if let Some(ca) = self.ca.as_ref() {
+            table["ca"] = path(ca);
+        }
+        if let Some(endpoint) = self.endpoint.as_ref() {
+            table["endpoint"] = value(endpoint);
+        }
+        if let Some(psk) = self.pre_shared_key.as_ref() {
+            table["pre_shared_key"] = path(psk);
+        }
+        if let Some(key_out) = self.key_out.as_ref() {
+            table["key_out"] = path(key_out);
+        }
+        if let Some(group) = self.group.as_ref() {
+            table["group"] = value(group);
+        }
+        if !self.tags.is_empty() {
+            table["tags"] = value(self.tags.iter().collect::<Array>());
+        }
+        if let (Some(dev), Some(peer)) = (self.wg_device.as_ref(), self.wg_peer.as_ref()) {
+            table["device"] = value(dev);
+            table["peer"] = value(peer);
+        }
+        table
+    }
+}
+
+/// A config file being edited
+#[derive(Debug)]
+pub struct ConfigEditor {
+    path: PathBuf,
+    doc: Document,
+}
+
+impl ConfigEditor {
+    pub fn open(path: &Path) -> Result<Self> {
+        ensure!(
+            path != Path::new(config::STDIN),
+            "a config read from stdin can not be edited"
+        );
+        let data = fs::read(path).with_context(|| format!("could not read {path:?}"))?;
+        ensure!(
+            !age::is_encrypted(&data),
+            "{path:?} is encrypted and can not be edited in place"
+        );
+        let text = std::str::from_utf8(&data)?;
+        ensure!(
+            !text.trim_start().starts_with('{'),
+            "{path:?} is JSON, only TOML configs can be edited"
+        );
+        let doc = text
+            .parse::<Document>()
+            .with_context(|| format!("could not parse {path:?}"))?;
+        Ok(Self {
+            path: path.to_owned(),
+            doc,
+        })
+    }
+
+    /// The config as edited so far, neither resolved nor checked
+    fn parsed(&self) -> Result<config::Rosenpass> {
+        let mut config: config::Rosenpass = toml::from_str(&self.doc.to_string())?;
+        config.config_file_path = self.path.clone();
+        Ok(config)
+    }
+
+    /// The config as edited so far, having passed the checks done on startup
+    pub fn config(&self) -> Result<config::Rosenpass> {
+        let mut config = self.parsed()?;
+        config.resolve_credentials()?;
+        config.resolve_groups()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn peers(&self) -> Result<Option<&ArrayOfTables>> {
+        match self.doc.get("peers") {
+            None => Ok(None),
+            Some(Item::ArrayOfTables(peers)) => Ok(Some(peers)),
+            Some(_) => bail!(
+                "the peers of {:?} are not given as [[peers]] tables",
+                self.path
+            ),
+        }
+    }
+
+    /// The `[[peers]]` table of peer `no` as TOML
+    pub fn block(&self, no: usize) -> Result<String> {
+        let table = self
+            .peers()?
+            .and_then(|peers| peers.get(no))
+            .with_context(|| format!("there is no peer {no}"))?;
+        let mut peers = ArrayOfTables::new();
+        peers.push(table.clone());
+        let mut doc = Document::new();
+        doc["peers"] = Item::ArrayOfTables(peers);
+        Ok(doc.to_string().trim_start().to_owned())
+    }
+
+    /// Append `peer`; returns its number
+    pub fn add(&mut self, peer: Table) -> Result<usize> {
+        let len = self.peers()?.map_or(0, ArrayOfTables::len);
+        let peers = self
+            .doc
+            .entry("peers")
+            .or_insert(Item::ArrayOfTables(ArrayOfTables::new()));
+        // checked by peers() above
+        peers.as_array_of_tables_mut().unwrap().push(peer);
+        Ok(len)
+    }
+
+    pub fn remove(&mut self, no: usize) -> Result<()> {
+        let len = self.peers()?.map_or(0, ArrayOfTables::len);
+        ensure!(no < len, "there is no peer {no}");
+        let peers = self.doc["peers"].as_array_of_tables_mut().unwrap();
+        peers.remove(no);
+        if peers.is_empty() {
+            self.doc.remove("peers");
+        }
+        Ok(())
+    }
+
+    /// Check the edited config, then replace the file with it
+    pub fn commit(&self) -> Result<()> {
+        self.config()
+            .with_context(|| format!("the edited config {:?} would be invalid", self.path))?;
+        let tmp = self.path.with_extension("tmp");
+        let perms = fs::metadata(&self.path)?.permissions();
+        fs::write(&tmp, self.doc.to_string())
+            .and_then(|()| fs::set_permissions(&tmp, perms))
+            .and_then(|()| fs::rename(&tmp, &self.path))
+            .with_context(|| format!("could not write {:?}", self.path))
+    }
+}
+
+/// A drop-in of a peer directory
+#[derive(Debug)]
+struct DropIn {
+    path: PathBuf,
+    text: String,
+    peer: config::RosenpassPeer,
+}
+
+/// The drop-ins of a peer directory being edited
+#[derive(Debug)]
+pub struct DropIns {
+    editor: ConfigEditor,
+    dir: PathBuf,
+    files: Vec<DropIn>,
+    /// The drop-in added, if one was
+    added: Option<usize>,
+    removed: Vec<PathBuf>,
+}
+
+impl DropIns {
+    /// The drop-ins of `dir`, one of the peer directories of the config of
+    /// `editor`
+    pub fn open(editor: ConfigEditor, dir: &Path) -> Result<Self> {
+        let config = editor.parsed()?;
+        ensure!(
+            config.peer_dirs.iter().any(|d| same_dir(d, dir)),
+            "{dir:?} is not one of the peer_dirs of {:?}",
+            editor.path
+        );
+        let files = watch::drop_ins(dir)?
+            .into_iter()
+            .map(|path| {
+                let text = fs::read_to_string(&path)
+                    .with_context(|| format!("could not read {path:?}"))?;
+                let peer = watch::parse_drop_in(&path, &text)?;
+                Ok(DropIn { path, text, peer })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            editor,
+            dir: dir.to_owned(),
+            files,
+            added: None,
+            removed: vec![],
+        })
+    }
+
+    /// The peers of the drop-ins, as edited so far
+    pub fn peers(&self) -> impl Iterator<Item = &config::RosenpassPeer> {
+        self.files.iter().map(|f| &f.peer)
+    }
+
+    /// The drop-in of peer `no`, with its path
+    pub fn block(&self, no: usize) -> Result<String> {
+        let file = self
+            .files
+            .get(no)
+            .with_context(|| format!("there is no peer {no}"))?;
+        Ok(format!("# {}\n{}", file.path.display(), file.text))
+    }
+
+    /// Add `peer` as the drop-in `<name>.toml`; returns its number
+    pub fn add(&mut self, name: &str, peer: Table) -> Result<usize> {
+        ensure!(self.added.is_none(), "only one peer can be added at once");
+        let path = self.dir.join(format!("{name}.toml"));
+        ensure!(
+            !path.exists() && self.files.iter().all(|f| f.path != path),
+            "{path:?} exists already"
+        );
+        let text = peer.to_string();
+        let peer = watch::parse_drop_in(&path, &text)?;
+        self.files.push(DropIn { path, text, peer });
+        self.added = Some(self.files.len() - 1);
+        Ok(self.files.len() - 1)
+    }
+
+    pub fn remove(&mut self, no: usize) -> Result<()> {
+        ensure!(no < self.files.len(), "there is no peer {no}");
+        let file = self.files.remove(no);
+        self.added = match self.added {
+            Some(added) if added == no => None,
+            Some(added) if added > no => Some(added - 1),
+            added => added,
+        };
+        self.removed.push(file.path);
+        Ok(())
+    }
+
+    /// The config with the drop-ins as edited, having passed the checks
+    /// done on startup
+    pub fn config(&self) -> Result<config::Rosenpass> {
+        let mut config = self.editor.parsed()?;
+        for dir in config.peer_dirs.clone() {
+            match same_dir(&dir, &self.dir) {
+                true => config.peers.extend(self.peers().cloned()),
+                false => config.peers.extend(watch::load_dir(&dir)?),
+            }
+        }
+        config.resolve_credentials()?;
+        config.resolve_groups()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Check the edited config, then write the drop-in added and delete
+    /// those removed
+    pub fn commit(&self) -> Result<()> {
+        self.config()
+            .with_context(|| format!("the edited peers of {:?} would be invalid", self.dir))?;
+        for path in self.removed.iter() {
+            fs::remove_file(path).with_context(|| format!("could not remove {path:?}"))?;
+        }
+        if let Some(file) = self.added.map(|no| &self.files[no]) {
+            // hidden, so it is no drop-in until renamed
+            let name = file.path.file_name().unwrap().to_string_lossy();
+            let tmp = self.dir.join(format!(".{name}.tmp"));
+            fs::write(&tmp, &file.text)
+                .and_then(|()| fs::rename(&tmp, &file.path))
+                .with_context(|| format!("could not write {:?}", file.path))?;
+        }
+        Ok(())
+    }
+}
+
+fn same_dir(a: &Path, b: &Path) -> bool {
+    a == b || matches!((fs::canonicalize(a), fs::canonicalize(b)), (Ok(a), Ok(b)) if a == b)
+}
+
+/// The peers `rosenpass peer` edits
+#[derive(Debug)]
+pub enum Target {
+    /// Those of the config file
+    Config(ConfigEditor),
+    /// Those of the drop-ins of a peer directory
+    Dir(DropIns),
+}
+
+impl Target {
+    pub fn peers(&self) -> Result<Vec<config::RosenpassPeer>> {
+        match self {
+            Target::Config(editor) => Ok(editor.config()?.peers),
+            Target::Dir(drop_ins) => Ok(drop_ins.peers().cloned().collect()),
+        }
+    }
+
+    pub fn block(&self, no: usize) -> Result<String> {
+        match self {
+            Target::Config(editor) => editor.block(no),
+            Target::Dir(drop_ins) => drop_ins.block(no),
+        }
+    }
+
+    /// Add `peer`, as a drop-in named `name` in a peer directory; returns
+    /// its number
+    pub fn add(&mut self, name: &str, peer: Table) -> Result<usize> {
+        match self {
+            Target::Config(editor) => editor.add(peer),
+            Target::Dir(drop_ins) => drop_ins.add(name, peer),
+        }
+    }
+
+    pub fn remove(&mut self, no: usize) -> Result<()> {
+        match self {
+            Target::Config(editor) => editor.remove(no),
+            Target::Dir(drop_ins) => drop_ins.remove(no),
+        }
+    }
+
+    pub fn commit(&self) -> Result<()> {
+        match self {
+            Target::Config(editor) => editor.commit(),
+            Target::Dir(drop_ins) => drop_ins.commit(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn keeps_the_rest_of_the_file() {
+        let dir = std::env::temp_dir().join(format!("rp-config-edit-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for key in ["pk", "sk", "peer-a", "peer-b"] {
+            fs::write(dir.join(key), "").unwrap();
+        }
+        let path = dir.join("rp.toml");
+        let text = format!(
+            "# our keys\npublic_key = {pk:?}\nsecret_key = {sk:?}\nlisten = []\nverbosity = \"Quiet\"\n\n\
+            [[peers]]\npublic_key = {a:?} # the first one\n",
+            pk = dir.join("pk"),
+            sk = dir.join("sk"),
+            a = dir.join("peer-a"),
+        );
+        fs::write(&path, &text).unwrap();
+
+        let mut editor = ConfigEditor::open(&path).unwrap();
+        let args = PeerArgs {
+            public_key: dir.join("peer-b"),
+            endpoint: Some("192.0.2.1:9999".into()),
+            tags: vec!["lab".into()],
+            ..Default::default()
+        };
+        assert_eq!(editor.add(args.table()).unwrap(), 1);
+        editor.commit().unwrap();
+        let edited = fs::read_to_string(&path).unwrap();
+        assert!(edited.starts_with(&text), "{edited}");
+        let config = ConfigEditor::open(&path).unwrap().config().unwrap();
+        assert_eq!(config.peers[1].endpoint.as_deref(), Some("192.0.2.1:9999"));
+        assert_eq!(config.peers[1].tags, vec!["lab".to_string()]);
+
+        let mut editor = ConfigEditor::open(&path).unwrap();
+        editor.remove(0).unwrap();
+        assert!(editor.block(0).unwrap().contains("192.0.2.1:9999"));
+        editor.commit().unwrap();
+        let edited = fs::read_to_string(&path).unwrap();
+        assert!(edited.contains("# our keys") && !edited.contains("the first one"));
+
+        // nothing is written if the result would not start
+        let mut editor = ConfigEditor::open(&path).unwrap();
+        let missing = PeerArgs {
+            public_key: dir.join("missing"),
+            ..Default::default()
+        };
+        editor.add(missing.table()).unwrap();
+        assert!(editor.commit().is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), edited);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn edits_drop_ins() {
+        let dir = std::env::temp_dir().join(format!("rp-drop-in-edit-{}", std::process::id()));
+        let peers_d = dir.join("peers.d");
+        fs::create_dir_all(&peers_d).unwrap();
+        for key in ["pk", "sk", "peer-a"] {
+            fs::write(dir.join(key), "").unwrap();
+        }
+        let path = dir.join("rp.toml");
+        let text = format!(
+            "public_key = {pk:?}\nsecret_key = {sk:?}\nlisten = []\nverbosity = \"Quiet\"\n\
+            peer_dirs = [{peers_d:?}]\npeers = []\n",
+            pk = dir.join("pk"),
+            sk = dir.join("sk"),
+        );
+        fs::write(&path, &text).unwrap();
+
+        // the directory has to be one of the config
+        let editor = ConfigEditor::open(&path).unwrap();
+        assert!(DropIns::open(editor, &dir).is_err());
+
+        let editor = ConfigEditor::open(&path).unwrap();
+        let mut target = Target::Dir(DropIns::open(editor, &peers_d).unwrap());
+        let args = PeerArgs {
+            public_key: dir.join("peer-a"),
+            endpoint: Some("192.0.2.1:9999".into()),
+            ..Default::default()
+        };
+        assert_eq!(target.add("a", args.table()).unwrap(), 0);
+        target.commit().unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), text);
+        let peers = watch::load_dir(&peers_d).unwrap();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].endpoint.as_deref(), Some("192.0.2.1:9999"));
+
+        // an invalid drop-in is not written
+        let editor = ConfigEditor::open(&path).unwrap();
+        let mut target = Target::Dir(DropIns::open(editor, &peers_d).unwrap());
+        let missing = PeerArgs {
+            public_key: dir.join("missing"),
+            ..Default::default()
+        };
+        target.add("b", missing.table()).unwrap();
+        assert!(target.commit().is_err());
+        assert!(!peers_d.join("b.toml").exists());
+
+        let editor = ConfigEditor::open(&path).unwrap();
+        let mut target = Target::Dir(DropIns::open(editor, &peers_d).unwrap());
+        assert!(target.block(0).unwrap().contains("192.0.2.1:9999"));
+        target.remove(0).unwrap();
+        target.commit().unwrap();
+        assert!(watch::drop_ins(&peers_d).unwrap().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
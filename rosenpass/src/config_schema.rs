@@ -0,0 +1,740 @@
+//! JSON Schema of the configuration file
+//!
+//! `rosenpass config-schema` prints a JSON Schema (draft 2020-12) of the
+//! config, so editors, CI pipelines and provisioning tools can check configs
+//! without running rosenpass. Editor plugins for TOML take JSON Schemas too.
+//!
+//! The schema is not written by hand but traced from the serde types in
+//! [crate::config]: [config_schema] deserializes a [Rosenpass] from a
+//! deserializer which hands every type a sample of what it asks for and
+//! writes down what that was. A type is traced once for every variant of
+//! every enum in it, and once without each of its fields to find out which
+//! of them are required.
+//!
+//! Keys rosenpass does not know are rejected by the schema, though rosenpass
+//! ignores them, to catch typos. Serde does not reveal the fields of
+//! flattened structs, nor what internally tagged enums such as
+//! [crate::keywrap::KeyWrap] consist of. The flattened structs of the config
+//! are listed in [config_schema]; values serde hides otherwise, like
+//! `secret_key_wrap`, may be anything as far as the schema is concerned.
+
+use anyhow::{anyhow, bail, Result};
+use serde::{
+    de::{
+        self, DeserializeOwned, DeserializeSeed, EnumAccess, Expected, MapAccess, SeqAccess,
+        VariantAccess, Visitor,
+    },
+    forward_to_deserialize_any, Deserializer, Serialize,
+};
+use serde_json::{json, Map, Value};
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    fmt,
+};
+
+use crate::{
+    config::{Rosenpass, RosenpassPeer, WireGuard},
+    quota::{Limits, QuotaConfig},
+};
+
+/// The JSON Schema of the config file
+pub fn config_schema() -> Result<Value> {
+    let mut schema = Schema::default();
+    schema.flattened::<RosenpassPeer, WireGuard>()?;
+    schema.flattened::<QuotaConfig, Limits>()?;
+    let root = schema.trace::<Rosenpass>()?;
+    Ok(json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "rosenpass configuration",
+        "$ref": root["$ref"],
+        "$defs": schema.defs(),
+    }))
+}
+
+/// A field of a struct, by the name of each
+type FieldPath = (String, String);
+
+/// What was traced of the types so far
+#[derive(Debug, Default)]
+struct Schema {
+    /// Properties of every struct
+    structs: BTreeMap<String, Map<String, Value>>,
+    /// Fields of every struct, in order
+    fields: BTreeMap<String, Vec<String>>,
+    required: BTreeMap<String, Vec<String>>,
+    /// Variants of every enum and their schemas, once traced
+    enums: BTreeMap<String, Vec<(String, Option<Value>)>>,
+    /// The variant to take of each enum in this pass; the first by default
+    choice: BTreeMap<String, usize>,
+    /// The choices a struct or enum was first traced with
+    reached: BTreeMap<String, BTreeMap<String, usize>>,
+    /// The field left out in this pass
+    omit: Option<FieldPath>,
+    /// Fields whose values are of a type serde does not reveal
+    opaque: BTreeSet<FieldPath>,
+    /// Own fields of structs serde reads as maps, as they have flattened ones
+    maps: BTreeMap<String, Vec<String>>,
+}
+
+impl Schema {
+    /// Trace `T`, with the fields of the flattened `F` added to its own
+    fn flattened<T: Serialize + Default, F: DeserializeOwned>(&mut self) -> Result<()> {
+        let ref_of = |r: Value| r["$ref"].as_str().unwrap_or_default().to_owned();
+        let name = type_name::<T>();
+        let flat = ref_of(self.trace::<F>()?);
+        let flat = flat.trim_start_matches("#/$defs/");
+        let Some(flat_fields) = self.fields.get(flat).cloned() else {
+            bail!("{flat} flattened into {name} is not a struct");
+        };
+        // every field of a struct is serialized, unless it is skipped
+        let Value::Object(sample) = serde_json::to_value(T::default())? else {
+            bail!("{name} is not serialized as a map");
+        };
+        let own: Vec<_> = sample
+            .keys()
+            .filter(|k| !flat_fields.contains(k))
+            .cloned()
+            .collect();
+        self.maps.insert(name.clone(), own);
+        let props = self.structs[flat].clone();
+        self.structs.entry(name).or_default().extend(props);
+        Ok(())
+    }
+
+    /// Trace `T`, returning the schema of its values
+    fn trace<T: DeserializeOwned>(&mut self) -> Result<Value> {
+        let failed = |e: Error| anyhow!("could not trace the config: {e}");
+        let mut root = self.pass::<T>(BTreeMap::new()).map_err(failed)?;
+        // take every variant of every enum once, making the same choices as
+        // when the enum was reached first
+        while let Some((name, next)) = self.enums.iter().find_map(|(name, variants)| {
+            let next = variants.iter().position(|(_, s)| s.is_none())?;
+            Some((name.clone(), next))
+        }) {
+            let mut choice = self.reached.get(&name).cloned().unwrap_or_default();
+            choice.insert(name.clone(), next);
+            root = self.pass::<T>(choice).map_err(failed)?;
+            if self.enums[&name][next].1.is_none() {
+                bail!("variant {next} of {name} can not be reached");
+            }
+        }
+        // leave out every field once, where the struct was traced
+        let fields: Vec<FieldPath> = self
+            .fields
+            .iter()
+            .flat_map(|(s, fields)| fields.iter().map(|f| (s.clone(), f.clone())))
+            .filter(|path| !self.opaque.contains(path) && !self.required_known(path))
+            .collect();
+        for (name, field) in fields {
+            let choice = self.reached.get(&name).cloned().unwrap_or_default();
+            self.omit = Some((name.clone(), field.clone()));
+            let res = self.pass::<T>(choice);
+            self.omit = None;
+            let required = self.required.entry(name).or_default();
+            match res {
+                Err(Error::Custom(e)) if e == format!("missing field `{field}`") => {
+                    required.push(field)
+                }
+                Err(e) => return Err(failed(e)),
+                Ok(_) => {}
+            }
+        }
+        Ok(root)
+    }
+
+    fn required_known(&self, (name, _): &FieldPath) -> bool {
+        self.required.contains_key(name)
+    }
+
+    /// Deserialize `T` once with the variants in `choice`, retrying as long
+    /// as values of types serde does not reveal turn up
+    fn pass<T: DeserializeOwned>(
+        &mut self,
+        choice: BTreeMap<String, usize>,
+    ) -> Result<Value, Error> {
+        self.choice = choice;
+        loop {
+            let mut root = Value::Null;
+            match T::deserialize(Tracer {
+                schema: self,
+                out: &mut root,
+            }) {
+                Ok(_) => return Ok(root),
+                Err(Error::Opaque(Some(path))) if !self.opaque.contains(&path) => {
+                    self.opaque.insert(path.clone());
+                    self.structs
+                        .entry(path.0)
+                        .or_default()
+                        .insert(path.1, json!({}));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Schemas of all structs and enums
+    fn defs(&self) -> Map<String, Value> {
+        let structs = self.structs.iter().map(|(name, props)| {
+            let mut def = json!({
+                "type": "object",
+                "properties": props,
+                "additionalProperties": false,
+            });
+            match self.required.get(name) {
+                Some(required) if !required.is_empty() => def["required"] = json!(required),
+                _ => {}
+            }
+            (name.clone(), def)
+        });
+        let enums = self.enums.iter().map(|(name, variants)| {
+            let schemas: Vec<_> = variants.iter().filter_map(|(_, s)| s.clone()).collect();
+            let names: Option<Vec<_>> = schemas.iter().map(|s| s.get("const").cloned()).collect();
+            let def = match names {
+                // enums of unit variants only are just strings
+                Some(names) => json!({ "enum": names }),
+                None => json!({ "oneOf": schemas }),
+            };
+            (name.clone(), def)
+        });
+        structs.chain(enums).collect()
+    }
+}
+
+fn type_name<T>() -> String {
+    let name = std::any::type_name::<T>();
+    name.rsplit("::").next().unwrap_or(name).to_owned()
+}
+
+fn ref_to(name: &str) -> Value {
+    json!({ "$ref": format!("#/$defs/{name}") })
+}
+
+/// A string the visitor expecting `expected` accepts
+fn sample_str(expected: &dyn Expected) -> &'static str {
+    match expected.to_string().as_str() {
+        "socket address" | "IPv4 socket address" => "0.0.0.0:0",
+        "IPv6 socket address" => "[::]:0",
+        "IP address" | "IPv4 address" => "0.0.0.0",
+        "IPv6 address" => "::",
+        _ => "",
+    }
+}
+
+#[derive(Debug)]
+enum Error {
+    /// A value of a type serde does not reveal, in this field if known
+    Opaque(Option<FieldPath>),
+    Custom(String),
+}
+
+impl Error {
+    fn at(self, name: &str, field: &str) -> Self {
+        match self {
+            Error::Opaque(None) => Error::Opaque(Some((name.to_owned(), field.to_owned()))),
+            e => e,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Opaque(_) => f.write_str("a value of unknown type"),
+            Error::Custom(msg) => f.write_str(msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl de::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Error::Custom(msg.to_string())
+    }
+}
+
+/// Deserializes a sample of whatever is asked for, writing the schema of
+/// it to `out`
+struct Tracer<'a> {
+    schema: &'a mut Schema,
+    out: &'a mut Value,
+}
+
+macro_rules! trace_integers {
+    ($($method:ident $visit:ident $ty:ty),*) => {$(
+        fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+            *self.out = json!({
+                "type": "integer",
+                "minimum": <$ty>::MIN,
+                "maximum": <$ty>::MAX,
+            });
+            // one rather than zero, for the sake of non-zero types
+            visitor.$visit(1)
+        }
+    )*};
+}
+
+impl<'de> Deserializer<'de> for Tracer<'_> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Error> {
+        Err(Error::Opaque(None))
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        *self.out = json!({ "type": "boolean" });
+        visitor.visit_bool(false)
+    }
+
+    trace_integers! {
+        deserialize_i8 visit_i8 i8,
+        deserialize_i16 visit_i16 i16,
+        deserialize_i32 visit_i32 i32,
+        deserialize_i64 visit_i64 i64,
+        deserialize_u8 visit_u8 u8,
+        deserialize_u16 visit_u16 u16,
+        deserialize_u32 visit_u32 u32,
+        deserialize_u64 visit_u64 u64
+    }
+
+    fn deserialize_f32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        *self.out = json!({ "type": "number" });
+        visitor.visit_f32(1.0)
+    }
+
+    fn deserialize_f64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        *self.out = json!({ "type": "number" });
+        visitor.visit_f64(1.0)
+    }
+
+    fn deserialize_char<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        *self.out = json!({ "type": "string", "minLength": 1, "maxLength": 1 });
+        visitor.visit_char(' ')
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        *self.out = json!({ "type": "string" });
+        let sample = sample_str(&visitor);
+        visitor.visit_str(sample)
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        *self.out = json!({
+            "type": "array",
+            "items": { "type": "integer", "minimum": 0, "maximum": 255 },
+        });
+        visitor.visit_bytes(&[])
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_bytes(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        // TOML has no null, absent options are left out
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        *self.out = json!({ "type": "null" });
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_unit(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let mut elements = Elements::new(self.schema, 1);
+        let res = visitor.visit_seq(&mut elements);
+        let items = elements.schemas.pop().unwrap_or_default();
+        *self.out = json!({ "type": "array", "items": items });
+        res
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value, Error> {
+        let mut elements = Elements::new(self.schema, len);
+        let res = visitor.visit_seq(&mut elements);
+        *self.out = tuple_schema(elements.schemas);
+        res
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_tuple(len, visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        // structs with flattened fields are read as maps
+        let expecting = (&visitor as &dyn Expected).to_string();
+        let name = expecting.strip_prefix("struct ").unwrap_or_default();
+        if let Some(fields) = self.schema.maps.get(name).cloned() {
+            *self.out = ref_to(name);
+            return trace_struct(self.schema, name, fields, visitor);
+        }
+        let mut entry = Entry {
+            schema: self.schema,
+            left: true,
+            value: Value::Null,
+        };
+        let res = visitor.visit_map(&mut entry);
+        *self.out = json!({ "type": "object", "additionalProperties": entry.value });
+        res
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        *self.out = ref_to(name);
+        let fields = fields.iter().map(|f| f.to_string()).collect();
+        trace_struct(self.schema, name, fields, visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        *self.out = ref_to(name);
+        let schema = self.schema;
+        if !schema.enums.contains_key(name) {
+            let variants = variants.iter().map(|v| (v.to_string(), None)).collect();
+            schema.enums.insert(name.to_owned(), variants);
+            schema
+                .reached
+                .insert(name.to_owned(), schema.choice.clone());
+        }
+        let index = schema.choice.get(name).copied().unwrap_or(0);
+        let Some(variant) = variants.get(index) else {
+            return Err(de::Error::custom(format!("enum {name} has no variants")));
+        };
+        visitor.visit_enum(Variant {
+            schema,
+            name,
+            variant,
+            index,
+        })
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        *self.out = json!({});
+        visitor.visit_unit()
+    }
+}
+
+fn tuple_schema(mut schemas: Vec<Value>) -> Value {
+    let len = schemas.len();
+    schemas.dedup();
+    match schemas.len() {
+        1 => json!({
+            "type": "array",
+            "items": schemas[0],
+            "minItems": len,
+            "maxItems": len,
+        }),
+        _ => json!({
+            "type": "array",
+            "prefixItems": schemas,
+            "minItems": len,
+            "maxItems": len,
+        }),
+    }
+}
+
+/// Hand `visitor` every field of the struct `name` but the one left out
+fn trace_struct<'de, V: Visitor<'de>>(
+    schema: &mut Schema,
+    name: &str,
+    fields: Vec<String>,
+    visitor: V,
+) -> Result<V::Value, Error> {
+    if schema.omit.is_none() {
+        schema.fields.insert(name.to_owned(), fields.clone());
+        if !schema.reached.contains_key(name) {
+            let choice = schema.choice.clone();
+            schema.reached.insert(name.to_owned(), choice);
+        }
+    }
+    let mut access = Fields {
+        name: name.to_owned(),
+        fields: fields.into(),
+        current: String::new(),
+        props: Map::new(),
+        schema,
+    };
+    let res = visitor.visit_map(&mut access);
+    let Fields { schema, props, .. } = access;
+    if schema.omit.is_none() {
+        schema
+            .structs
+            .entry(name.to_owned())
+            .or_default()
+            .extend(props);
+    }
+    res
+}
+
+/// The fields of a struct being traced
+struct Fields<'a> {
+    schema: &'a mut Schema,
+    name: String,
+    fields: VecDeque<String>,
+    current: String,
+    props: Map<String, Value>,
+}
+
+impl<'de> MapAccess<'de> for Fields<'_> {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Error> {
+        while let Some(field) = self.fields.pop_front() {
+            let path = (self.name.clone(), field);
+            if self.schema.omit.as_ref() == Some(&path) || self.schema.opaque.contains(&path) {
+                continue;
+            }
+            let key = seed.deserialize(Ident(&path.1))?;
+            self.current = path.1;
+            return Ok(Some(key));
+        }
+        Ok(None)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        let field = std::mem::take(&mut self.current);
+        let mut out = Value::Null;
+        let res = seed.deserialize(Tracer {
+            schema: self.schema,
+            out: &mut out,
+        });
+        self.props.insert(field.clone(), out);
+        res.map_err(|e| e.at(&self.name, &field))
+    }
+}
+
+/// The one entry of a map being traced
+struct Entry<'a> {
+    schema: &'a mut Schema,
+    left: bool,
+    value: Value,
+}
+
+impl<'de> MapAccess<'de> for Entry<'_> {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Error> {
+        if !std::mem::take(&mut self.left) {
+            return Ok(None);
+        }
+        // keys are strings in TOML and JSON alike
+        let mut key = Value::Null;
+        seed.deserialize(Tracer {
+            schema: self.schema,
+            out: &mut key,
+        })
+        .map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        seed.deserialize(Tracer {
+            schema: self.schema,
+            out: &mut self.value,
+        })
+    }
+}
+
+/// The elements of a sequence or tuple being traced
+struct Elements<'a> {
+    schema: &'a mut Schema,
+    left: usize,
+    schemas: Vec<Value>,
+}
+
+impl<'a> Elements<'a> {
+    fn new(schema: &'a mut Schema, len: usize) -> Self {
+        Self {
+            schema,
+            left: len,
+            schemas: Vec::new(),
+        }
+    }
+}
+
+impl<'de> SeqAccess<'de> for Elements<'_> {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Error> {
+        if self.left == 0 {
+            return Ok(None);
+        }
+        self.left -= 1;
+        let mut out = Value::Null;
+        let res = seed.deserialize(Tracer {
+            schema: self.schema,
+            out: &mut out,
+        });
+        self.schemas.push(out);
+        res.map(Some)
+    }
+}
+
+/// The variant of an enum taken in this pass
+struct Variant<'a> {
+    schema: &'a mut Schema,
+    name: &'static str,
+    variant: &'static str,
+    index: usize,
+}
+
+impl Variant<'_> {
+    /// Note down the schema of the variant, an object with its name as the
+    /// only key unless it has no data
+    fn record(self, data: Option<Value>) {
+        if self.schema.omit.is_some() {
+            return;
+        }
+        let schema = match data {
+            None => json!({ "const": self.variant }),
+            Some(data) => json!({
+                "type": "object",
+                "properties": { self.variant: data },
+                "required": [self.variant],
+                "additionalProperties": false,
+            }),
+        };
+        if let Some(variants) = self.schema.enums.get_mut(self.name) {
+            variants[self.index].1 = Some(schema);
+        }
+    }
+}
+
+impl<'de> EnumAccess<'de> for Variant<'_> {
+    type Error = Error;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self), Error> {
+        Ok((seed.deserialize(Ident(self.variant))?, self))
+    }
+}
+
+impl<'de> VariantAccess<'de> for Variant<'_> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Error> {
+        self.record(None);
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, Error> {
+        let mut out = Value::Null;
+        let res = seed.deserialize(Tracer {
+            schema: &mut *self.schema,
+            out: &mut out,
+        });
+        self.record(Some(out));
+        res
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value, Error> {
+        let mut elements = Elements::new(&mut *self.schema, len);
+        let res = visitor.visit_seq(&mut elements);
+        let data = tuple_schema(elements.schemas);
+        self.record(Some(data));
+        res
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        let name = format!("{}.{}", self.name, self.variant);
+        let fields = fields.iter().map(|f| f.to_string()).collect();
+        let res = trace_struct(&mut *self.schema, &name, fields, visitor);
+        self.record(Some(ref_to(&name)));
+        res
+    }
+}
+
+/// Deserializes as the name of a field or variant
+struct Ident<'a>(&'a str);
+
+impl<'de> Deserializer<'de> for Ident<'_> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_str(self.0)
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn traces_the_config() {
+        let schema = config_schema().unwrap();
+        let defs = &schema["$defs"];
+        assert_eq!(schema["$ref"], "#/$defs/Rosenpass");
+
+        let config = &defs["Rosenpass"];
+        assert_eq!(config["required"], json!(["public_key", "peers"]));
+        assert_eq!(config["properties"]["listen"]["items"]["type"], "string");
+        assert_eq!(config["properties"]["dscp"]["maximum"], 255);
+        assert_eq!(config["properties"]["secret_key_wrap"], json!({}));
+        assert_eq!(defs["Verbosity"], json!({ "enum": ["Quiet", "Verbose"] }));
+
+        // flattened fields are merged into the struct
+        let peer = &defs["RosenpassPeer"]["properties"];
+        assert_eq!(peer["device"]["type"], "string");
+        assert_eq!(peer["stale_key"]["$ref"], "#/$defs/StaleKeyPolicy");
+        assert!(defs["QuotaConfig"]["properties"]["kem_per_second"].is_object());
+        assert!(defs["QuotaConfig"]["properties"]["tags"].is_object());
+
+        let stale = defs["StaleKeyPolicy"]["oneOf"].as_array().unwrap();
+        assert_eq!(stale[0], json!({ "const": "erase" }));
+        assert_eq!(stale[2]["required"], json!(["after"]));
+    }
+}
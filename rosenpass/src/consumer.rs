@@ -0,0 +1,113 @@
+//! Handing exchanged keys to what protects traffic with them
+//!
+//! A [KeyConsumer] gets every key exchanged with a peer, and the random key
+//! replacing it once it is stale; WireGuard, through `wg` or its UAPI, and
+//! IPsec, see [crate::xfrm], are consumers. Besides the output key, a
+//! consumer can take keys the exporter derives under labels of its own, see
+//! [crate::exporter], for one key for each direction, say.
+//!
+//! Keys are handed over from a background thread, so a slow consumer does
+//! not stall the handshakes. Failed attempts are retried with exponential
+//! backoff, until a newer key comes along or after [APPLY_ATTEMPTS]; giving
+//! up makes the instance unhealthy until a key gets through.
+
+use anyhow::Result;
+use log::{debug, error, warn};
+use std::{
+    fmt::Debug,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering::SeqCst},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::protocol::SymKey;
+
+/// How often handing a key over is attempted before giving up
+pub const APPLY_ATTEMPTS: u32 = 6;
+/// Wait before the first retry; doubled after every further failed attempt
+pub const APPLY_BACKOFF: Duration = Duration::from_millis(250);
+
+/// Something the keys of a peer are installed into
+pub trait KeyConsumer: Debug + Send + Sync {
+    /// What the keys go to, for the logs and the health report
+    fn target(&self) -> String;
+
+    /// Labels of the further keys to export along with the output key
+    fn labels(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// Install the output key `key` and the keys `exported` under the
+    /// [KeyConsumer::labels], in their order
+    fn install(&self, key: &SymKey, exported: &[SymKey]) -> Result<()>;
+}
+
+/// Progress of handing keys to a consumer, shared with the threads doing so
+#[derive(Default, Debug)]
+pub struct ApplyState {
+    /// Incremented for every new key; attempts for older keys stop
+    generation: AtomicU64,
+    /// Held while setting a key, so an outdated key can not overwrite a newer one
+    lock: Mutex<()>,
+    /// Set when the latest key could not be applied
+    pub failed: AtomicBool,
+    /// Number of keys given up on
+    pub failures: AtomicU64,
+}
+
+/// A consumer of the keys of a peer, with its progress
+#[derive(Debug, Clone)]
+pub struct Consumer {
+    pub consumer: Arc<dyn KeyConsumer>,
+    pub state: Arc<ApplyState>,
+}
+
+impl Consumer {
+    pub fn new(consumer: impl KeyConsumer + 'static) -> Self {
+        Self {
+            consumer: Arc::new(consumer),
+            state: Arc::default(),
+        }
+    }
+
+    /// Install `key` and `exported` from a background thread, retrying with
+    /// exponential backoff
+    pub fn apply_in_background(&self, key: &SymKey, exported: Vec<SymKey>) {
+        let generation = self.state.generation.fetch_add(1, SeqCst) + 1;
+        let (consumer, state, key) = (self.consumer.clone(), self.state.clone(), key.clone());
+        thread::spawn(move || {
+            let target = consumer.target();
+            let mut backoff = APPLY_BACKOFF;
+            for attempt in 1..=APPLY_ATTEMPTS {
+                {
+                    let _guard = state.lock.lock().unwrap();
+                    if state.generation.load(SeqCst) != generation {
+                        return; // superseded by a newer key
+                    }
+                    match consumer.install(&key, &exported) {
+                        Ok(()) => {
+                            debug!("successfully passed the key to {target}");
+                            state.failed.store(false, SeqCst);
+                            return;
+                        }
+                        Err(e) => warn!(
+                            "could not pass the key to {target} (attempt {attempt} of {APPLY_ATTEMPTS}): {e:#}"
+                        ),
+                    }
+                }
+                if attempt < APPLY_ATTEMPTS {
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                }
+            }
+            if state.generation.load(SeqCst) == generation {
+                state.failed.store(true, SeqCst);
+                state.failures.fetch_add(1, SeqCst);
+                error!("giving up passing the key to {target}, which keeps the previous key");
+            }
+        });
+    }
+}
@@ -0,0 +1,175 @@
+//! Running as the only process of a container
+//!
+//! `exchange-config --container` adapts rosenpass to container runtimes
+//! instead of a classic host:
+//!
+//! - The secret key may come from a mounted secret, with its path in
+//!   `ROSENPASS_SECRET_KEY_FILE`, or from an inherited file descriptor, with
+//!   its number in `ROSENPASS_SECRET_KEY_FD`; either overrides the config file.
+//! - Logs are written to stdout as JSON lines, at level info unless
+//!   `RUST_LOG` says otherwise; so are the notices about written keys.
+//! - SIGTERM and SIGINT stop the key exchange right away, also as PID 1,
+//!   which ignores them by default.
+//! - Without a control socket configured, one is opened at [CONTROL_SOCKET],
+//!   which `rosenpass healthcheck` uses when given no arguments, making it
+//!   usable as a container health probe as is.
+//! - Nothing is written outside the volumes listed in `ROSENPASS_VOLUMES`,
+//!   separated by colons, and by default the directory of [CONTROL_SOCKET];
+//!   configurations writing keys or the control socket elsewhere are
+//!   refused before anything is written.
+
+use anyhow::{ensure, Context, Result};
+use std::{
+    fs,
+    io::Write,
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, OnceLock,
+    },
+};
+
+use crate::config::Rosenpass;
+
+/// The control socket opened in container mode unless the config file
+/// names one
+pub const CONTROL_SOCKET: &str = "/run/rosenpass/control.sock";
+
+static TERMINATE: AtomicBool = AtomicBool::new(false);
+static WAKER: OnceLock<Arc<mio::Waker>> = OnceLock::new();
+
+/// Log JSON lines to stdout
+pub fn init_logging() {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"))
+        .target(env_logger::Target::Stdout)
+        .format(|buf, record| {
+            let line = serde_json::json!({
+                "time": buf.timestamp().to_string(),
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            });
+            writeln!(buf, "{line}")
+        })
+        .init();
+}
+
+extern "C" fn on_signal(_: libc::c_int) {
+    TERMINATE.store(true, Ordering::SeqCst);
+    // writing to the eventfd behind the waker is safe in a signal handler
+    if let Some(waker) = WAKER.get() {
+        let _ = waker.wake();
+    }
+}
+
+/// Have SIGTERM and SIGINT wake the event loop through `waker` and make
+/// [terminating] true
+pub fn handle_termination(waker: Arc<mio::Waker>) -> Result<()> {
+    let _ = WAKER.set(waker);
+    for sig in [libc::SIGTERM, libc::SIGINT] {
+        let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+        action.sa_sigaction = on_signal as *const () as usize;
+        let res = unsafe { libc::sigaction(sig, &action, std::ptr::null_mut()) };
+        ensure!(
+            res == 0,
+            "could not handle signal {sig}: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+    Ok(())
+}
+
+/// Whether a termination signal arrived
+pub fn terminating() -> bool {
+    TERMINATE.load(Ordering::SeqCst)
+}
+
+/// Adapt `config` to the container environment
+pub fn apply(config: &mut Rosenpass) -> Result<()> {
+    if let Ok(fd) = std::env::var("ROSENPASS_SECRET_KEY_FD") {
+        let fd: u32 = fd
+            .parse()
+            .with_context(|| format!("ROSENPASS_SECRET_KEY_FD={fd} is not a file descriptor"))?;
+        config.secret_key = PathBuf::from(format!("/dev/fd/{fd}"));
+    } else if let Some(path) = std::env::var_os("ROSENPASS_SECRET_KEY_FILE") {
+        config.secret_key = path.into();
+    }
+    let socket = config
+        .control_socket
+        .get_or_insert_with(|| CONTROL_SOCKET.into());
+    let dir = socket.parent().map(Path::to_owned);
+    check_writes(config, &volumes())?;
+    if let Some(dir) = dir.filter(|d| !d.as_os_str().is_empty()) {
+        fs::create_dir_all(&dir).with_context(|| format!("could not create {dir:?}"))?;
+    }
+    Ok(())
+}
+
+/// The volumes declared writable
+pub fn volumes() -> Vec<PathBuf> {
+    match std::env::var_os("ROSENPASS_VOLUMES") {
+        Some(list) => std::env::split_paths(&list)
+            .filter(|p| !p.as_os_str().is_empty())
+            .collect(),
+        None => vec![Path::new(CONTROL_SOCKET).parent().unwrap().to_owned()],
+    }
+}
+
+/// Make sure the files the key exchange writes lie within `volumes`
+pub fn check_writes(config: &Rosenpass, volumes: &[PathBuf]) -> Result<()> {
+    let volumes = volumes
+        .iter()
+        .map(|v| normalize(v))
+        .collect::<Result<Vec<_>>>()?;
+    let writes = config
+        .peers
+        .iter()
+        .filter_map(|p| p.key_out.as_ref())
+        .chain(config.control_socket.as_ref());
+    for path in writes {
+        let abs = normalize(path)?;
+        ensure!(
+            volumes.iter().any(|v| abs.starts_with(v)),
+            "{path:?} lies outside the volumes declared in ROSENPASS_VOLUMES ({})",
+            std::env::join_paths(&volumes)?.to_string_lossy()
+        );
+    }
+    Ok(())
+}
+
+/// `path` made absolute, with `.` and `..` resolved without following links
+fn normalize(path: &Path) -> Result<PathBuf> {
+    let mut abs = PathBuf::new();
+    for c in std::path::absolute(path)?.components() {
+        match c {
+            Component::ParentDir => {
+                abs.pop();
+            }
+            Component::CurDir => {}
+            c => abs.push(c),
+        }
+    }
+    Ok(abs)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn writes_within_volumes() {
+        let mut config = Rosenpass::new("pk", "sk");
+        config.control_socket = Some("/run/rosenpass/control.sock".into());
+        config.peers.push(Default::default());
+        config.peers[0].key_out = Some("/out/peer.osk".into());
+
+        let volumes = [PathBuf::from("/run/rosenpass"), PathBuf::from("/out/")];
+        assert!(check_writes(&config, &volumes).is_ok());
+        assert!(check_writes(&config, &volumes[..1]).is_err());
+
+        config.peers[0].key_out = Some("/out/../etc/peer.osk".into());
+        assert!(check_writes(&config, &volumes).is_err());
+        config.peers[0].key_out = Some("/outside/peer.osk".into());
+        assert!(check_writes(&config, &volumes).is_err());
+    }
+}
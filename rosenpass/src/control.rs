@@ -0,0 +1,389 @@
+//! Local control interface of a running rosenpass instance
+//!
+//! An `exchange` process can be asked to listen on a unix domain socket.
+//! Clients connect, send a single command terminated by a newline and receive
+//! a single line of JSON in return; after that the connection is closed.
+//!
+//! The server side is driven by [crate::app_server::AppServer], the client
+//! side is provided by [request].
+//!
+//! Whether the WireGuard devices keys go to can be reached is found out on a
+//! thread of its own, see [DeviceChecks], so a `wg` that hangs does not stall
+//! the event loop answering the healthcheck.
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs,
+    io::{BufRead, BufReader, Write},
+    net::SocketAddr,
+    os::unix::{fs::FileTypeExt, net::UnixStream},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{netns, serve, uapi, upgrade};
+
+/// How long either side waits for the other one to send its line
+pub const CONTROL_TIMEOUT: Duration = Duration::from_millis(500);
+/// Most time checking one WireGuard device may take before it counts as not
+/// reachable
+pub const DEVICE_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+/// Age at which the last check of the devices is done again
+pub const DEVICE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Commands understood by the control socket
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ControlCommand {
+    Healthcheck,
+    /// List the peers, optionally only those carrying the given tag
+    Status {
+        tag: Option<String>,
+    },
+    /// Handshake statistics, optionally only of the peers carrying the given tag
+    Stats {
+        tag: Option<String>,
+    },
+    /// Initiate handshakes right away with the peer given by fingerprint or
+    /// id, or with all of them; see [crate::rekey]
+    Rekey {
+        peer: String,
+    },
+    /// Execute the binary at the given path, or at the one we were started
+    /// from, in place of ours; see [crate::upgrade]
+    Upgrade {
+        binary: Option<PathBuf>,
+    },
+    /// Load the config again and take up changes to its peers and keys;
+    /// see [crate::watch]
+    Reload,
+}
+
+impl FromStr for ControlCommand {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let words: Vec<&str> = s.split_whitespace().collect();
+        Ok(match words.as_slice() {
+            ["healthcheck"] => ControlCommand::Healthcheck,
+            ["status"] => ControlCommand::Status { tag: None },
+            ["status", tag] => ControlCommand::Status {
+                tag: Some(tag.to_string()),
+            },
+            ["stats"] => ControlCommand::Stats { tag: None },
+            ["stats", tag] => ControlCommand::Stats {
+                tag: Some(tag.to_string()),
+            },
+            ["rekey", peer] => ControlCommand::Rekey {
+                peer: peer.to_string(),
+            },
+            ["upgrade"] => ControlCommand::Upgrade { binary: None },
+            ["reload"] => ControlCommand::Reload,
+            ["upgrade", binary] => ControlCommand::Upgrade {
+                binary: Some(binary.into()),
+            },
+            _ => bail!("unknown control command {:?}", s.trim()),
+        })
+    }
+}
+
+/// Answer to [ControlCommand::Healthcheck]
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct HealthReport {
+    pub healthy: bool,
+    /// Human readable description of every failed check
+    pub problems: Vec<String>,
+}
+
+impl HealthReport {
+    pub fn from_problems(problems: Vec<String>) -> Self {
+        Self {
+            healthy: problems.is_empty(),
+            problems,
+        }
+    }
+}
+
+/// A WireGuard device keys are passed to
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Device {
+    pub dev: String,
+    pub uapi_socket: Option<PathBuf>,
+    pub netns: Option<String>,
+}
+
+impl Device {
+    /// Whether the device answers within [DEVICE_CHECK_TIMEOUT]
+    fn reachable(&self) -> bool {
+        if let Some(socket) = &self.uapi_socket {
+            return uapi::reachable(socket);
+        }
+        netns::within(self.netns.as_deref(), || {
+            let mut child = Command::new("wg")
+                .args(["show", &self.dev, "public-key"])
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .spawn()?;
+            let until = Instant::now() + DEVICE_CHECK_TIMEOUT;
+            while Instant::now() < until {
+                if let Some(status) = child.try_wait()? {
+                    return Ok(status.success());
+                }
+                thread::sleep(Duration::from_millis(20));
+            }
+            let _ = child.kill();
+            let _ = child.wait();
+            Ok(false)
+        })
+        .unwrap_or(false)
+    }
+}
+
+/// What the last check of the devices found
+#[derive(Debug, Default)]
+struct Checked {
+    reachable: HashMap<Device, bool>,
+    at: Option<Instant>,
+    running: bool,
+}
+
+/// Whether the WireGuard devices could be reached when last checked
+///
+/// The devices are checked on a thread of its own once the last check is
+/// [DEVICE_CHECK_INTERVAL] old, so asking never waits for them.
+#[derive(Debug, Default)]
+pub struct DeviceChecks {
+    state: Arc<Mutex<Checked>>,
+}
+
+impl DeviceChecks {
+    /// Those of `devices` the last check could not reach, having them all
+    /// checked again in the background if that check is stale
+    ///
+    /// Devices not checked yet count as reachable.
+    pub fn unreachable(&self, devices: Vec<Device>) -> Vec<Device> {
+        let mut checked = self.state.lock().unwrap();
+        let stale = checked
+            .at
+            .is_none_or(|at| at.elapsed() >= DEVICE_CHECK_INTERVAL);
+        if stale && !checked.running {
+            checked.running = true;
+            let (state, devices) = (self.state.clone(), devices.clone());
+            let spawned = thread::Builder::new()
+                .name("rosenpass-device-check".to_string())
+                .spawn(move || {
+                    let reachable = devices
+                        .into_iter()
+                        .map(|d| {
+                            let ok = d.reachable();
+                            (d, ok)
+                        })
+                        .collect();
+                    let mut checked = state.lock().unwrap();
+                    *checked = Checked {
+                        reachable,
+                        at: Some(Instant::now()),
+                        running: false,
+                    };
+                });
+            if spawned.is_err() {
+                checked.running = false;
+            }
+        }
+        devices
+            .into_iter()
+            .filter(|d| checked.reachable.get(d) == Some(&false))
+            .collect()
+    }
+}
+
+/// Answer to [ControlCommand::Rekey]
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct RekeyReport {
+    /// Fingerprints of the peers a handshake is initiated with
+    pub initiated: Vec<String>,
+    /// Fingerprints of the peers without a known endpoint
+    pub skipped: Vec<String>,
+}
+
+/// One entry of the answer to [ControlCommand::Status]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PeerStatus {
+    /// Base64 encoded peer id
+    pub peer_id: String,
+    pub fingerprint: String,
+    /// See [crate::fingerprint]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub tags: Vec<String>,
+    /// Addresses the peer is currently reachable at or being looked for at
+    pub endpoints: Vec<SocketAddr>,
+    /// Age in seconds of the current key, if there is one
+    pub key_age: Option<f64>,
+    /// The peer missed its rekey window, see [crate::liveness]
+    #[serde(default)]
+    pub dead: bool,
+    /// Handshakes with the peer went unanswered, see [crate::breaker]
+    #[serde(default)]
+    pub unreachable: bool,
+    /// Messages to the peer go through a relay, see [crate::relay]
+    #[serde(default)]
+    pub relayed: bool,
+}
+
+/// Listening end of the control socket; removes the socket file when dropped
+#[derive(Debug)]
+pub struct ControlSocket {
+    pub listener: mio::net::UnixListener,
+    pub path: PathBuf,
+}
+
+impl ControlSocket {
+    /// Bind to `path`, replacing a stale socket left behind by a previous instance
+    pub fn bind<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_owned();
+        if let Some(listener) = upgrade::take_unix_listener(&path) {
+            listener.set_nonblocking(true)?;
+            let listener = mio::net::UnixListener::from_std(listener);
+            return Ok(Self { listener, path });
+        }
+        if let Ok(meta) = fs::symlink_metadata(&path) {
+            if !meta.file_type().is_socket() {
+                bail!("refusing to replace {path:?} with the control socket, it is not a socket");
+            }
+            fs::remove_file(&path)?;
+        }
+        let listener = mio::net::UnixListener::bind(&path)
+            .with_context(|| format!("could not bind control socket {path:?}"))?;
+        Ok(Self { listener, path })
+    }
+
+    /// Accept a pending connection, if any
+    ///
+    /// The returned stream is blocking with [CONTROL_TIMEOUT] applied, so a
+    /// misbehaving client can stall the caller for no longer than that.
+    pub fn accept(&self) -> Result<Option<UnixStream>> {
+        serve::accept(&self.listener, CONTROL_TIMEOUT)
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Read the command sent by a client
+pub fn read_command(stream: &UnixStream) -> Result<ControlCommand> {
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    line.parse()
+}
+
+/// Send one command to the control socket at `path`, returning the reply line
+pub fn request<P: AsRef<Path>>(path: P, cmd: &str) -> Result<String> {
+    let p = path.as_ref();
+    let mut stream = UnixStream::connect(p)
+        .with_context(|| format!("could not connect to control socket {p:?}"))?;
+    stream.set_read_timeout(Some(CONTROL_TIMEOUT))?;
+    stream.set_write_timeout(Some(CONTROL_TIMEOUT))?;
+    writeln!(stream, "{cmd}")?;
+
+    let mut reply = String::new();
+    BufReader::new(&stream)
+        .read_line(&mut reply)
+        .context("no reply on the control socket")?;
+    if reply.is_empty() {
+        bail!("control socket {p:?} closed the connection without replying");
+    }
+    Ok(reply)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(
+            "healthcheck\n".parse::<ControlCommand>().unwrap(),
+            ControlCommand::Healthcheck
+        );
+        assert_eq!(
+            "status".parse::<ControlCommand>().unwrap(),
+            ControlCommand::Status { tag: None }
+        );
+        assert_eq!(
+            "status office\n".parse::<ControlCommand>().unwrap(),
+            ControlCommand::Status {
+                tag: Some("office".into())
+            }
+        );
+        assert_eq!(
+            "stats\n".parse::<ControlCommand>().unwrap(),
+            ControlCommand::Stats { tag: None }
+        );
+        assert_eq!(
+            "rekey all\n".parse::<ControlCommand>().unwrap(),
+            ControlCommand::Rekey { peer: "all".into() }
+        );
+        assert!("rekey".parse::<ControlCommand>().is_err());
+        assert_eq!(
+            "upgrade /usr/bin/rosenpass\n"
+                .parse::<ControlCommand>()
+                .unwrap(),
+            ControlCommand::Upgrade {
+                binary: Some("/usr/bin/rosenpass".into())
+            }
+        );
+        assert_eq!(
+            "reload\n".parse::<ControlCommand>().unwrap(),
+            ControlCommand::Reload
+        );
+        assert!("status a b".parse::<ControlCommand>().is_err());
+        assert!("reboot".parse::<ControlCommand>().is_err());
+    }
+
+    #[test]
+    fn devices_are_checked_in_the_background() {
+        let dir = std::env::temp_dir().join(format!("rp-devices-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        // answers nothing, so checking it takes until the uapi timeout
+        let silent = dir.join("silent.sock");
+        let _listener = std::os::unix::net::UnixListener::bind(&silent).unwrap();
+        let devices = vec![
+            Device {
+                dev: "wg-silent".into(),
+                uapi_socket: Some(silent),
+                netns: None,
+            },
+            Device {
+                dev: "wg-gone".into(),
+                uapi_socket: Some(dir.join("gone.sock")),
+                netns: None,
+            },
+        ];
+
+        let checks = DeviceChecks::default();
+        let start = Instant::now();
+        assert!(checks.unreachable(devices.clone()).is_empty());
+        assert!(checks.unreachable(devices.clone()).is_empty());
+        assert!(start.elapsed() < Duration::from_millis(500));
+
+        let unreachable = loop {
+            let unreachable = checks.unreachable(devices.clone());
+            if !unreachable.is_empty() {
+                break unreachable;
+            }
+            assert!(start.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(50));
+        };
+        assert_eq!(unreachable, devices);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
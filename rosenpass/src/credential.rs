@@ -0,0 +1,64 @@
+//! Key files handed over as systemd credentials
+//!
+//! Under systemd, keys can be passed with `LoadCredential=` or
+//! `LoadCredentialEncrypted=`, so they are encrypted at rest, optionally bound
+//! to the TPM, and only readable by the service while it runs:
+//!
+//! ```text
+//! [Service]
+//! LoadCredentialEncrypted=rp-sk:/etc/rosenpass/rp-sk.cred
+//! ```
+//!
+//! In the config, a path of `credential:<name>` stands for the credential of
+//! that name, `secret_key = "credential:rp-sk"` for the one above. It is
+//! looked up in `$CREDENTIALS_DIRECTORY` where systemd put it. This works for
+//! all files rosenpass reads keys from: the public and secret key, the peers'
+//! public keys, CA keys, pre-shared keys and the identity of a wrapped key.
+
+use anyhow::{bail, ensure, Result};
+use std::path::{Path, PathBuf};
+
+/// Paths starting with this name a systemd credential
+pub const PREFIX: &str = "credential:";
+
+/// Environment variable systemd sets to the directory holding the credentials
+pub const DIRECTORY_ENV: &str = "CREDENTIALS_DIRECTORY";
+
+/// Replace a `credential:<name>` path by the path of the credential
+pub fn resolve(path: &mut PathBuf) -> Result<()> {
+    let Some(name) = path.to_str().and_then(|p| p.strip_prefix(PREFIX)) else {
+        return Ok(());
+    };
+    ensure!(
+        !name.is_empty() && !name.contains('/'),
+        "{path:?} does not name a credential"
+    );
+    let Some(dir) = std::env::var_os(DIRECTORY_ENV) else {
+        bail!("{path:?} is a systemd credential, but ${DIRECTORY_ENV} is not set");
+    };
+    *path = Path::new(&dir).join(name);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn resolves_credentials() {
+        let mut plain = PathBuf::from("/etc/rosenpass/rp-sk");
+        resolve(&mut plain).unwrap();
+        assert_eq!(plain, PathBuf::from("/etc/rosenpass/rp-sk"));
+
+        assert!(resolve(&mut PathBuf::from("credential:")).is_err());
+        assert!(resolve(&mut PathBuf::from("credential:../rp-sk")).is_err());
+
+        std::env::set_var(DIRECTORY_ENV, "/run/credentials/rosenpass.service");
+        let mut cred = PathBuf::from("credential:rp-sk");
+        resolve(&mut cred).unwrap();
+        assert_eq!(
+            cred,
+            PathBuf::from("/run/credentials/rosenpass.service/rp-sk")
+        );
+    }
+}
@@ -0,0 +1,750 @@
+//! A minimal D-Bus connection
+//!
+//! Just enough of the D-Bus wire protocol to offer a service on the system
+//! bus, as needed by [crate::nm]: the unix socket transport authenticated
+//! with EXTERNAL, and the marshalling of all basic and container types in
+//! little endian byte order. Passing file descriptors and introspection are
+//! not supported.
+
+use anyhow::{bail, ensure, Context, Result};
+use std::{
+    collections::VecDeque,
+    io::{ErrorKind, Read, Write},
+    os::unix::net::UnixStream,
+    time::Duration,
+};
+
+/// Where the system bus listens unless `DBUS_SYSTEM_BUS_ADDRESS` says
+/// otherwise
+pub const SYSTEM_BUS: &str = "/var/run/dbus/system_bus_socket";
+
+pub const METHOD_CALL: u8 = 1;
+pub const METHOD_RETURN: u8 = 2;
+pub const ERROR: u8 = 3;
+pub const SIGNAL: u8 = 4;
+
+const NO_REPLY_EXPECTED: u8 = 0x1;
+
+const FIELD_PATH: u8 = 1;
+const FIELD_INTERFACE: u8 = 2;
+const FIELD_MEMBER: u8 = 3;
+const FIELD_ERROR_NAME: u8 = 4;
+const FIELD_REPLY_SERIAL: u8 = 5;
+const FIELD_DESTINATION: u8 = 6;
+const FIELD_SENDER: u8 = 7;
+const FIELD_SIGNATURE: u8 = 8;
+
+/// Largest message accepted from the bus; NetworkManager's are far smaller
+const MAX_MESSAGE_LEN: usize = 1 << 20;
+/// Deepest nesting of containers the specification allows
+const MAX_DEPTH: usize = 64;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Byte(u8),
+    Bool(bool),
+    I16(i16),
+    U16(u16),
+    I32(i32),
+    U32(u32),
+    I64(i64),
+    U64(u64),
+    Double(f64),
+    Str(String),
+    Path(String),
+    Sig(String),
+    /// The signature of the elements, and the elements
+    Array(String, Vec<Value>),
+    Struct(Vec<Value>),
+    DictEntry(Box<Value>, Box<Value>),
+    Variant(Box<Value>),
+}
+
+impl Value {
+    pub fn str(s: &str) -> Self {
+        Self::Str(s.to_string())
+    }
+
+    /// A dictionary with string keys and variant values, `a{sv}`
+    pub fn dict(entries: Vec<(&str, Value)>) -> Self {
+        let entries = entries
+            .into_iter()
+            .map(|(k, v)| {
+                Self::DictEntry(Box::new(Self::str(k)), Box::new(Self::Variant(v.into())))
+            })
+            .collect();
+        Self::Array("{sv}".into(), entries)
+    }
+
+    pub fn signature(&self) -> String {
+        match self {
+            Self::Byte(_) => "y".into(),
+            Self::Bool(_) => "b".into(),
+            Self::I16(_) => "n".into(),
+            Self::U16(_) => "q".into(),
+            Self::I32(_) => "i".into(),
+            Self::U32(_) => "u".into(),
+            Self::I64(_) => "x".into(),
+            Self::U64(_) => "t".into(),
+            Self::Double(_) => "d".into(),
+            Self::Str(_) => "s".into(),
+            Self::Path(_) => "o".into(),
+            Self::Sig(_) => "g".into(),
+            Self::Array(elem, _) => format!("a{elem}"),
+            Self::Struct(fields) => format!(
+                "({})",
+                fields.iter().map(Self::signature).collect::<String>()
+            ),
+            Self::DictEntry(k, v) => format!("{{{}{}}}", k.signature(), v.signature()),
+            Self::Variant(_) => "v".into(),
+        }
+    }
+
+    /// The value held by a variant; any other value stands for itself
+    pub fn inner(&self) -> &Self {
+        match self {
+            Self::Variant(v) => v.inner(),
+            v => v,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self.inner() {
+            Self::Str(s) | Self::Path(s) | Self::Sig(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_u32(&self) -> Option<u32> {
+        match self.inner() {
+            Self::U32(x) => Some(*x),
+            _ => None,
+        }
+    }
+
+    /// Look up `key` in a dictionary with string keys
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        let Self::Array(_, entries) = self.inner() else {
+            return None;
+        };
+        entries.iter().find_map(|e| match e {
+            Self::DictEntry(k, v) if k.as_str() == Some(key) => Some(v.inner()),
+            _ => None,
+        })
+    }
+
+    /// The string entries of a dictionary with string keys
+    pub fn str_entries(&self) -> Vec<(&str, &str)> {
+        let Self::Array(_, entries) = self.inner() else {
+            return vec![];
+        };
+        entries
+            .iter()
+            .filter_map(|e| match e {
+                Self::DictEntry(k, v) => Some((k.as_str()?, v.as_str()?)),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// Alignment of the type starting the signature `sig`
+fn alignment(sig: &str) -> usize {
+    match sig.as_bytes().first() {
+        Some(b'n' | b'q') => 2,
+        Some(b'b' | b'i' | b'u' | b's' | b'o' | b'a') => 4,
+        Some(b'x' | b't' | b'd' | b'(' | b'{') => 8,
+        _ => 1,
+    }
+}
+
+/// Split the first complete type off the signature `sig`
+fn split_type(sig: &str) -> Result<(&str, &str)> {
+    match sig.as_bytes().first() {
+        Some(b'a') => {
+            let (_, rest) = split_type(&sig[1..])?;
+            Ok(sig.split_at(sig.len() - rest.len()))
+        }
+        Some(b'(' | b'{') => {
+            let mut depth = 0usize;
+            for (i, c) in sig.bytes().enumerate() {
+                match c {
+                    b'(' | b'{' => depth += 1,
+                    b')' | b'}' => depth -= 1,
+                    _ => {}
+                }
+                if depth == 0 {
+                    return Ok(sig.split_at(i + 1));
+                }
+            }
+            bail!("unbalanced signature {sig:?}")
+        }
+        Some(c) if b"ybnqiuxtdsogv".contains(c) => Ok(sig.split_at(1)),
+        _ => bail!("unsupported signature {sig:?}"),
+    }
+}
+
+/// The complete types in the signature `sig`
+fn split_types(mut sig: &str) -> Result<Vec<&str>> {
+    let mut types = Vec::new();
+    while !sig.is_empty() {
+        let (ty, rest) = split_type(sig)?;
+        types.push(ty);
+        sig = rest;
+    }
+    Ok(types)
+}
+
+#[derive(Default)]
+struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    fn pad(&mut self, align: usize) {
+        while !self.buf.len().is_multiple_of(align) {
+            self.buf.push(0);
+        }
+    }
+
+    fn fixed<const N: usize>(&mut self, bytes: [u8; N]) {
+        self.pad(N);
+        self.buf.extend_from_slice(&bytes);
+    }
+
+    fn put(&mut self, v: &Value) {
+        match v {
+            Value::Byte(x) => self.buf.push(*x),
+            Value::Bool(x) => self.fixed((*x as u32).to_le_bytes()),
+            Value::I16(x) => self.fixed(x.to_le_bytes()),
+            Value::U16(x) => self.fixed(x.to_le_bytes()),
+            Value::I32(x) => self.fixed(x.to_le_bytes()),
+            Value::U32(x) => self.fixed(x.to_le_bytes()),
+            Value::I64(x) => self.fixed(x.to_le_bytes()),
+            Value::U64(x) => self.fixed(x.to_le_bytes()),
+            Value::Double(x) => self.fixed(x.to_le_bytes()),
+            Value::Str(s) | Value::Path(s) => {
+                self.fixed((s.len() as u32).to_le_bytes());
+                self.buf.extend_from_slice(s.as_bytes());
+                self.buf.push(0);
+            }
+            Value::Sig(s) => {
+                self.buf.push(s.len() as u8);
+                self.buf.extend_from_slice(s.as_bytes());
+                self.buf.push(0);
+            }
+            Value::Array(elem, items) => {
+                self.fixed([0u8; 4]);
+                let len_at = self.buf.len() - 4;
+                // the padding to the first element does not count
+                self.pad(alignment(elem));
+                let start = self.buf.len();
+                items.iter().for_each(|i| self.put(i));
+                let len = (self.buf.len() - start) as u32;
+                self.buf[len_at..len_at + 4].copy_from_slice(&len.to_le_bytes());
+            }
+            Value::Struct(fields) => {
+                self.pad(8);
+                fields.iter().for_each(|f| self.put(f));
+            }
+            Value::DictEntry(k, v) => {
+                self.pad(8);
+                self.put(k);
+                self.put(v);
+            }
+            Value::Variant(v) => {
+                self.put(&Value::Sig(v.signature()));
+                self.put(v);
+            }
+        }
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    depth: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        let b = self
+            .buf
+            .get(self.pos..self.pos + n)
+            .context("truncated D-Bus message")?;
+        self.pos += n;
+        Ok(b)
+    }
+
+    fn fixed<const N: usize>(&mut self) -> Result<[u8; N]> {
+        self.pos = self.pos.next_multiple_of(N);
+        Ok(self.take(N)?.try_into()?)
+    }
+
+    fn string(&mut self, len: usize) -> Result<String> {
+        let s = String::from_utf8(self.take(len)?.to_vec())?;
+        self.take(1)?;
+        Ok(s)
+    }
+
+    /// Read a value of the single complete type `sig`
+    fn read(&mut self, sig: &str) -> Result<Value> {
+        ensure!(self.depth < MAX_DEPTH, "D-Bus values nested too deeply");
+        self.depth += 1;
+        let v = self.read_nested(sig);
+        self.depth -= 1;
+        v
+    }
+
+    fn read_nested(&mut self, sig: &str) -> Result<Value> {
+        Ok(match sig.as_bytes().first() {
+            Some(b'y') => Value::Byte(self.take(1)?[0]),
+            Some(b'b') => Value::Bool(u32::from_le_bytes(self.fixed()?) != 0),
+            Some(b'n') => Value::I16(i16::from_le_bytes(self.fixed()?)),
+            Some(b'q') => Value::U16(u16::from_le_bytes(self.fixed()?)),
+            Some(b'i') => Value::I32(i32::from_le_bytes(self.fixed()?)),
+            Some(b'u') => Value::U32(u32::from_le_bytes(self.fixed()?)),
+            Some(b'x') => Value::I64(i64::from_le_bytes(self.fixed()?)),
+            Some(b't') => Value::U64(u64::from_le_bytes(self.fixed()?)),
+            Some(b'd') => Value::Double(f64::from_le_bytes(self.fixed()?)),
+            Some(b's') => {
+                let len = u32::from_le_bytes(self.fixed()?) as usize;
+                Value::Str(self.string(len)?)
+            }
+            Some(b'o') => {
+                let len = u32::from_le_bytes(self.fixed()?) as usize;
+                Value::Path(self.string(len)?)
+            }
+            Some(b'g') => {
+                let len = self.take(1)?[0] as usize;
+                Value::Sig(self.string(len)?)
+            }
+            Some(b'v') => {
+                let len = self.take(1)?[0] as usize;
+                let inner = self.string(len)?;
+                ensure!(
+                    split_types(&inner)?.len() == 1,
+                    "variant of signature {inner:?}"
+                );
+                Value::Variant(Box::new(self.read(&inner)?))
+            }
+            Some(b'a') => {
+                let len = u32::from_le_bytes(self.fixed()?) as usize;
+                let elem = &sig[1..];
+                self.pos = self.pos.next_multiple_of(alignment(elem));
+                let end = self.pos + len;
+                ensure!(end <= self.buf.len(), "truncated D-Bus array");
+                let mut items = Vec::new();
+                while self.pos < end {
+                    items.push(self.read(elem)?);
+                }
+                Value::Array(elem.to_string(), items)
+            }
+            Some(b'(') => {
+                self.pos = self.pos.next_multiple_of(8);
+                let fields = split_types(&sig[1..sig.len() - 1])?;
+                Value::Struct(
+                    fields
+                        .into_iter()
+                        .map(|f| self.read(f))
+                        .collect::<Result<_>>()?,
+                )
+            }
+            Some(b'{') => {
+                self.pos = self.pos.next_multiple_of(8);
+                let types = split_types(&sig[1..sig.len() - 1])?;
+                ensure!(types.len() == 2, "dict entry of signature {sig:?}");
+                let k = self.read(types[0])?;
+                Value::DictEntry(Box::new(k), Box::new(self.read(types[1])?))
+            }
+            _ => bail!("unsupported signature {sig:?}"),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Message {
+    /// One of [METHOD_CALL], [METHOD_RETURN], [ERROR] and [SIGNAL]
+    pub kind: u8,
+    pub flags: u8,
+    pub serial: u32,
+    pub path: Option<String>,
+    pub interface: Option<String>,
+    pub member: Option<String>,
+    pub error_name: Option<String>,
+    pub reply_serial: Option<u32>,
+    pub destination: Option<String>,
+    pub sender: Option<String>,
+    pub body: Vec<Value>,
+}
+
+impl Message {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body = Writer::default();
+        self.body.iter().for_each(|v| body.put(v));
+        let signature: String = self.body.iter().map(Value::signature).collect();
+
+        let mut fields = Vec::new();
+        let mut field = |code: u8, v: Value| {
+            fields.push(Value::Struct(vec![
+                Value::Byte(code),
+                Value::Variant(Box::new(v)),
+            ]))
+        };
+        let strings = [
+            (FIELD_PATH, &self.path),
+            (FIELD_INTERFACE, &self.interface),
+            (FIELD_MEMBER, &self.member),
+            (FIELD_ERROR_NAME, &self.error_name),
+            (FIELD_DESTINATION, &self.destination),
+            (FIELD_SENDER, &self.sender),
+        ];
+        for (code, s) in strings {
+            if let Some(s) = s {
+                match code {
+                    FIELD_PATH => field(code, Value::Path(s.clone())),
+                    _ => field(code, Value::Str(s.clone())),
+                }
+            }
+        }
+        if let Some(serial) = self.reply_serial {
+            field(FIELD_REPLY_SERIAL, Value::U32(serial));
+        }
+        if !signature.is_empty() {
+            field(FIELD_SIGNATURE, Value::Sig(signature));
+        }
+
+        let mut msg = Writer::default();
+        msg.buf.extend_from_slice(&[b'l', self.kind, self.flags, 1]);
+        msg.put(&Value::U32(body.buf.len() as u32));
+        msg.put(&Value::U32(self.serial));
+        msg.put(&Value::Array("(yv)".into(), fields));
+        // the body starts at a multiple of eight, so it could be
+        // marshalled on its own
+        msg.pad(8);
+        msg.buf.extend_from_slice(&body.buf);
+        msg.buf
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        ensure!(buf.first() == Some(&b'l'), "not a little endian message");
+        let mut r = Reader {
+            buf,
+            pos: 4,
+            depth: 0,
+        };
+        let body_len = u32::from_le_bytes(r.fixed()?) as usize;
+        let mut msg = Self {
+            kind: buf[1],
+            flags: buf[2],
+            serial: u32::from_le_bytes(r.fixed()?),
+            ..Self::default()
+        };
+        let Value::Array(_, fields) = r.read("a(yv)")? else {
+            unreachable!()
+        };
+        let mut signature = String::new();
+        for f in fields {
+            let Value::Struct(f) = f else { unreachable!() };
+            let (Value::Byte(code), v) = (&f[0], f[1].inner()) else {
+                unreachable!()
+            };
+            let s = v.as_str().map(str::to_string);
+            match *code {
+                FIELD_PATH => msg.path = s,
+                FIELD_INTERFACE => msg.interface = s,
+                FIELD_MEMBER => msg.member = s,
+                FIELD_ERROR_NAME => msg.error_name = s,
+                FIELD_REPLY_SERIAL => msg.reply_serial = v.as_u32(),
+                FIELD_DESTINATION => msg.destination = s,
+                FIELD_SENDER => msg.sender = s,
+                FIELD_SIGNATURE => signature = s.unwrap_or_default(),
+                // unknown fields must be ignored
+                _ => {}
+            }
+        }
+        r.pos = r.pos.next_multiple_of(8);
+        let body = r.take(body_len)?;
+        let mut r = Reader {
+            buf: body,
+            pos: 0,
+            depth: 0,
+        };
+        for ty in split_types(&signature)? {
+            msg.body.push(r.read(ty)?);
+        }
+        Ok(msg)
+    }
+
+    /// Whether this calls `member` of `interface`
+    pub fn is_call(&self, interface: &str, member: &str) -> bool {
+        self.kind == METHOD_CALL
+            && self.interface.as_deref() == Some(interface)
+            && self.member.as_deref() == Some(member)
+    }
+}
+
+pub struct Connection {
+    stream: UnixStream,
+    serial: u32,
+    /// Messages which arrived while waiting for a reply
+    queue: VecDeque<Message>,
+    /// The name the bus assigned to the connection
+    pub unique_name: String,
+}
+
+impl Connection {
+    /// Connect to the system bus
+    pub fn system() -> Result<Self> {
+        let addr = std::env::var("DBUS_SYSTEM_BUS_ADDRESS").ok();
+        let path = match addr.as_deref() {
+            Some(addr) => addr
+                .split(';')
+                .find_map(|a| a.strip_prefix("unix:path="))
+                .map(|a| a.split(',').next().unwrap_or(a))
+                .with_context(|| format!("unsupported bus address {addr:?}"))?,
+            None => SYSTEM_BUS,
+        };
+        Self::connect(path)
+    }
+
+    pub fn connect(path: &str) -> Result<Self> {
+        let stream = UnixStream::connect(path)
+            .with_context(|| format!("could not connect to the bus at {path}"))?;
+        let mut conn = Self {
+            stream,
+            serial: 0,
+            queue: VecDeque::new(),
+            unique_name: String::new(),
+        };
+        conn.authenticate()?;
+        let reply = conn.call(
+            "org.freedesktop.DBus",
+            "/org/freedesktop/DBus",
+            "org.freedesktop.DBus",
+            "Hello",
+            vec![],
+        )?;
+        conn.unique_name = reply
+            .first()
+            .and_then(Value::as_str)
+            .context("no unique name in the reply to Hello")?
+            .to_string();
+        Ok(conn)
+    }
+
+    fn authenticate(&mut self) -> Result<()> {
+        // EXTERNAL takes the user id, as decimal number in hex digits
+        let uid = unsafe { libc::getuid() }.to_string();
+        let hex: String = uid.bytes().map(|b| format!("{b:02x}")).collect();
+        self.stream
+            .write_all(format!("\0AUTH EXTERNAL {hex}\r\n").as_bytes())?;
+
+        // read byte by byte, so nothing after the line is consumed
+        let mut line = Vec::new();
+        let mut byte = [0u8];
+        while !line.ends_with(b"\r\n") {
+            ensure!(line.len() < 512, "overlong authentication reply");
+            self.stream.read_exact(&mut byte)?;
+            line.push(byte[0]);
+        }
+        ensure!(
+            line.starts_with(b"OK "),
+            "the bus rejected the authentication: {}",
+            String::from_utf8_lossy(&line).trim()
+        );
+        self.stream.write_all(b"BEGIN\r\n")?;
+        Ok(())
+    }
+
+    /// Send `msg` with the next serial; returns the serial
+    pub fn send(&mut self, mut msg: Message) -> Result<u32> {
+        self.serial += 1;
+        msg.serial = self.serial;
+        self.stream.write_all(&msg.encode())?;
+        Ok(msg.serial)
+    }
+
+    /// The next message, or none if none arrived within `timeout`
+    pub fn receive(&mut self, timeout: Duration) -> Result<Option<Message>> {
+        if let Some(msg) = self.queue.pop_front() {
+            return Ok(Some(msg));
+        }
+        let mut buf = vec![0u8; 16];
+        self.stream.set_read_timeout(Some(timeout))?;
+        match self.stream.read(&mut buf[..1]) {
+            Ok(0) => bail!("the bus closed the connection"),
+            Ok(_) => {}
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                return Ok(None)
+            }
+            Err(e) => return Err(e.into()),
+        }
+        // the bus sends whole messages, so the rest follows right away
+        self.stream.set_read_timeout(None)?;
+        self.stream.read_exact(&mut buf[1..])?;
+        let body_len = u32::from_le_bytes(buf[4..8].try_into()?) as usize;
+        let fields_len = u32::from_le_bytes(buf[12..16].try_into()?) as usize;
+        let len = (16 + fields_len).next_multiple_of(8) + body_len;
+        ensure!(len <= MAX_MESSAGE_LEN, "overlong D-Bus message");
+        buf.resize(len, 0);
+        self.stream.read_exact(&mut buf[16..])?;
+        Message::decode(&buf).map(Some)
+    }
+
+    /// Call a method and wait for its reply
+    pub fn call(
+        &mut self,
+        destination: &str,
+        path: &str,
+        interface: &str,
+        member: &str,
+        body: Vec<Value>,
+    ) -> Result<Vec<Value>> {
+        let serial = self.send(Message {
+            kind: METHOD_CALL,
+            destination: Some(destination.into()),
+            path: Some(path.into()),
+            interface: Some(interface.into()),
+            member: Some(member.into()),
+            body,
+            ..Message::default()
+        })?;
+        let mut others = Vec::new();
+        let reply = loop {
+            let msg = self
+                .receive(Duration::from_secs(25))?
+                .with_context(|| format!("no reply to {interface}.{member} from {destination}"))?;
+            match msg.reply_serial {
+                Some(s) if s == serial => break msg,
+                _ => others.push(msg),
+            }
+        };
+        self.queue.extend(others);
+        match reply.kind {
+            METHOD_RETURN => Ok(reply.body),
+            _ => bail!(
+                "{interface}.{member} failed: {} {}",
+                reply.error_name.unwrap_or_default(),
+                reply.body.first().and_then(Value::as_str).unwrap_or("")
+            ),
+        }
+    }
+
+    /// Become the owner of the well-known name `name`
+    pub fn request_name(&mut self, name: &str) -> Result<()> {
+        // do not queue, fail if somebody else owns the name
+        const DO_NOT_QUEUE: u32 = 0x4;
+        const PRIMARY_OWNER: u32 = 1;
+        const ALREADY_OWNER: u32 = 4;
+        let reply = self.call(
+            "org.freedesktop.DBus",
+            "/org/freedesktop/DBus",
+            "org.freedesktop.DBus",
+            "RequestName",
+            vec![Value::str(name), Value::U32(DO_NOT_QUEUE)],
+        )?;
+        match reply.first().and_then(Value::as_u32) {
+            Some(PRIMARY_OWNER | ALREADY_OWNER) => Ok(()),
+            _ => bail!("could not acquire the bus name {name}; is the service running already?"),
+        }
+    }
+
+    pub fn reply(&mut self, call: &Message, body: Vec<Value>) -> Result<()> {
+        if call.flags & NO_REPLY_EXPECTED != 0 {
+            return Ok(());
+        }
+        self.send(Message {
+            kind: METHOD_RETURN,
+            reply_serial: Some(call.serial),
+            destination: call.sender.clone(),
+            body,
+            ..Message::default()
+        })?;
+        Ok(())
+    }
+
+    pub fn error(&mut self, call: &Message, name: &str, text: &str) -> Result<()> {
+        if call.flags & NO_REPLY_EXPECTED != 0 {
+            return Ok(());
+        }
+        self.send(Message {
+            kind: ERROR,
+            reply_serial: Some(call.serial),
+            destination: call.sender.clone(),
+            error_name: Some(name.into()),
+            body: vec![Value::str(text)],
+            ..Message::default()
+        })?;
+        Ok(())
+    }
+
+    pub fn signal(
+        &mut self,
+        path: &str,
+        interface: &str,
+        member: &str,
+        body: Vec<Value>,
+    ) -> Result<()> {
+        self.send(Message {
+            kind: SIGNAL,
+            path: Some(path.into()),
+            interface: Some(interface.into()),
+            member: Some(member.into()),
+            body,
+            ..Message::default()
+        })?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        // like the connection settings NetworkManager hands to VPN plugins
+        let settings = Value::Array(
+            "{sa{sv}}".into(),
+            vec![Value::DictEntry(
+                Box::new(Value::str("vpn")),
+                Box::new(Value::dict(vec![
+                    ("service-type", Value::str("org.example")),
+                    ("timeout", Value::U32(60)),
+                    ("persistent", Value::Bool(true)),
+                ])),
+            )],
+        );
+        let msg = Message {
+            kind: METHOD_CALL,
+            serial: 7,
+            path: Some("/org/example".into()),
+            interface: Some("org.example.Iface".into()),
+            member: Some("Connect".into()),
+            body: vec![
+                settings,
+                Value::Byte(3),
+                Value::U64(1 << 40),
+                Value::Struct(vec![Value::I16(-2), Value::Double(0.5)]),
+            ],
+            ..Message::default()
+        };
+        let buf = msg.encode();
+        let decoded = Message::decode(&buf).unwrap();
+        assert_eq!(decoded, msg);
+        assert!(decoded.is_call("org.example.Iface", "Connect"));
+
+        let vpn = decoded.body[0].get("vpn").unwrap();
+        assert_eq!(
+            vpn.get("service-type").unwrap().as_str(),
+            Some("org.example")
+        );
+        assert_eq!(vpn.get("timeout").unwrap().as_u32(), Some(60));
+        assert_eq!(vpn.str_entries(), vec![("service-type", "org.example")]);
+
+        assert!(Message::decode(&buf[..buf.len() - 1]).is_err());
+        assert!(split_types("a{sv").is_err());
+        assert_eq!(split_types("a{sv}(iu)s").unwrap(), ["a{sv}", "(iu)", "s"]);
+    }
+}
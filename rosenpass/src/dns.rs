@@ -0,0 +1,390 @@
+//! Discovering endpoints through DNS SRV records
+//!
+//! An endpoint of `dns-srv:_rosenpass._udp.example.org` stands for the
+//! responders listed in the SRV records of that name (RFC 2782), so a pool of
+//! responders can be managed in DNS alone. Records are tried by priority, and
+//! by weight within the same priority; the addresses of all targets become
+//! the candidates of endpoint discovery, so unreachable hosts fall back to the
+//! next one.
+//!
+//! Lookups run in the background. They are repeated whenever the key of the
+//! peer expires without being replaced, and [RETRY_INTERVAL] seconds after a
+//! lookup failed.
+
+use anyhow::{bail, ensure, Context, Result};
+use log::debug;
+use std::{
+    cmp::Reverse,
+    fs,
+    net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use crate::protocol::Timing;
+
+/// Endpoints starting with this are looked up as SRV records
+pub const PREFIX: &str = "dns-srv:";
+
+/// Seconds to wait before repeating a failed lookup
+pub const RETRY_INTERVAL: Timing = 30.0;
+
+/// How long to wait for each name server
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+const TYPE_SRV: u16 = 33;
+const TYPE_TXT: u16 = 16;
+const CLASS_IN: u16 = 1;
+/// Most compression pointers followed in a single name
+const MAX_POINTERS: usize = 16;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SrvRecord {
+    /// The name the record belongs to
+    pub name: String,
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    pub target: String,
+}
+
+/// The addresses of the targets of the SRV records of `name`, most preferred
+/// first
+pub fn resolve(name: &str) -> Result<Vec<SocketAddr>> {
+    let mut records = query_srv(name)?;
+    records.sort_by_key(|r| (r.priority, Reverse(r.weight)));
+
+    let mut addrs = Vec::new();
+    // a target of "." means the service is explicitly unavailable
+    for r in records.iter().filter(|r| !r.target.is_empty()) {
+        match (r.target.as_str(), r.port).to_socket_addrs() {
+            Ok(found) => addrs.extend(found),
+            Err(e) => debug!("could not resolve SRV target {}: {e}", r.target),
+        }
+    }
+    ensure!(!addrs.is_empty(), "no usable SRV records for {name}");
+    Ok(addrs)
+}
+
+/// Ask the name servers of the system for the SRV records of `name`
+pub fn query_srv(name: &str) -> Result<Vec<SrvRecord>> {
+    lookup(name, TYPE_SRV, parse_response, None)
+        .with_context(|| format!("could not look up SRV records of {name}"))
+}
+
+/// Ask the name servers of the system for the TXT records of `name`, each
+/// with its strings joined
+pub fn query_txt(name: &str, within: Duration) -> Result<Vec<String>> {
+    lookup(
+        name,
+        TYPE_TXT,
+        parse_txt_response,
+        Some(Instant::now() + within),
+    )
+    .with_context(|| format!("could not look up TXT records of {name}"))
+}
+
+/// Ask the name servers one after the other, until one answers or the
+/// `deadline` passed
+fn lookup<T>(
+    name: &str,
+    qtype: u16,
+    parse: fn(&[u8], u16) -> Result<T>,
+    deadline: Option<Instant>,
+) -> Result<T> {
+    let mut id = [0u8; 2];
+    rosenpass_sodium::helpers::randombytes_buf(&mut id);
+    let id = u16::from_be_bytes(id);
+    let query = encode_query(id, name, qtype)?;
+
+    let mut last_err = None;
+    for ns in nameservers() {
+        let timeout = match deadline.map(|d| d.saturating_duration_since(Instant::now())) {
+            Some(left) if left.is_zero() => bail!("no answer in time"),
+            Some(left) => left.min(TIMEOUT),
+            None => TIMEOUT,
+        };
+        match ask(ns, &query, timeout).and_then(|resp| parse(&resp, id)) {
+            Ok(records) => return Ok(records),
+            Err(e) => last_err = Some(e.context(format!("name server {ns}"))),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| anyhow::anyhow!("no name servers")))
+}
+
+/// The name servers from `/etc/resolv.conf`, or a local one
+fn nameservers() -> Vec<SocketAddr> {
+    let conf = fs::read_to_string("/etc/resolv.conf").unwrap_or_default();
+    let mut servers: Vec<SocketAddr> = conf
+        .lines()
+        .filter_map(|l| l.trim().strip_prefix("nameserver"))
+        .filter_map(|addr| addr.trim().parse::<IpAddr>().ok())
+        .map(|addr| SocketAddr::new(addr, 53))
+        .collect();
+    if servers.is_empty() {
+        servers.push(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 53));
+    }
+    servers
+}
+
+fn ask(ns: SocketAddr, query: &[u8], timeout: Duration) -> Result<Vec<u8>> {
+    let local: SocketAddr = match ns {
+        SocketAddr::V4(_) => "0.0.0.0:0".parse()?,
+        SocketAddr::V6(_) => "[::]:0".parse()?,
+    };
+    let sock = UdpSocket::bind(local)?;
+    sock.set_read_timeout(Some(timeout))?;
+    sock.connect(ns)?;
+    sock.send(query)?;
+    let mut buf = vec![0u8; 4096];
+    let len = sock.recv(&mut buf).context("no answer")?;
+    buf.truncate(len);
+    Ok(buf)
+}
+
+fn encode_query(id: u16, name: &str, qtype: u16) -> Result<Vec<u8>> {
+    let mut q = Vec::with_capacity(name.len() + 18);
+    q.extend_from_slice(&id.to_be_bytes());
+    // recursion desired, one question
+    q.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    encode_name(&mut q, name)?;
+    q.extend_from_slice(&qtype.to_be_bytes());
+    q.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(q)
+}
+
+/// An unsolicited answer consisting of an SRV record and a TXT record of
+/// `txt` for the same name, as sent to announce services with multicast DNS
+pub fn encode_srv_announcement(record: &SrvRecord, txt: &[&[u8]], ttl: u32) -> Result<Vec<u8>> {
+    let mut msg = Vec::new();
+    // an authoritative answer with two records
+    msg.extend_from_slice(&[0, 0, 0x84, 0x00, 0, 0, 0, 2, 0, 0, 0, 0]);
+    encode_name(&mut msg, &record.name)?;
+    msg.extend_from_slice(&TYPE_SRV.to_be_bytes());
+    // the record replaces those cached for the name before
+    msg.extend_from_slice(&(CLASS_IN | 0x8000).to_be_bytes());
+    msg.extend_from_slice(&ttl.to_be_bytes());
+    let mut rdata = Vec::new();
+    rdata.extend_from_slice(&record.priority.to_be_bytes());
+    rdata.extend_from_slice(&record.weight.to_be_bytes());
+    rdata.extend_from_slice(&record.port.to_be_bytes());
+    encode_name(&mut rdata, &record.target)?;
+    msg.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+    msg.extend_from_slice(&rdata);
+
+    // pointing back at the name of the SRV record
+    msg.extend_from_slice(&[0xc0, 12]);
+    msg.extend_from_slice(&TYPE_TXT.to_be_bytes());
+    msg.extend_from_slice(&(CLASS_IN | 0x8000).to_be_bytes());
+    msg.extend_from_slice(&ttl.to_be_bytes());
+    let mut rdata = Vec::new();
+    for text in txt {
+        ensure!(text.len() < 256, "TXT string too long");
+        rdata.push(text.len() as u8);
+        rdata.extend_from_slice(text);
+    }
+    ensure!(rdata.len() <= u16::MAX as usize, "TXT record too long");
+    msg.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+    msg.extend_from_slice(&rdata);
+    Ok(msg)
+}
+
+fn encode_name(buf: &mut Vec<u8>, name: &str) -> Result<()> {
+    let start = buf.len();
+    for label in name.trim_end_matches('.').split('.') {
+        ensure!(
+            !label.is_empty() && label.len() < 64,
+            "invalid DNS name {name:?}"
+        );
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+    buf.push(0);
+    ensure!(buf.len() - start <= 255, "DNS name {name:?} is too long");
+    Ok(())
+}
+
+fn u16_at(buf: &[u8], off: usize) -> Result<u16> {
+    let b = buf.get(off..off + 2).context("truncated DNS message")?;
+    Ok(u16::from_be_bytes([b[0], b[1]]))
+}
+
+/// Read the possibly compressed name at `off`; returns the name and the
+/// offset right after it
+fn read_name(buf: &[u8], mut off: usize) -> Result<(String, usize)> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut end = None;
+    for _ in 0..MAX_POINTERS {
+        loop {
+            let len = *buf.get(off).context("truncated DNS name")? as usize;
+            match len {
+                0 => {
+                    return Ok((labels.join("."), end.unwrap_or(off + 1)));
+                }
+                l if l & 0xc0 == 0xc0 => {
+                    end.get_or_insert(off + 2);
+                    off = (u16_at(buf, off)? & 0x3fff) as usize;
+                    break;
+                }
+                l if l < 64 => {
+                    let label = buf
+                        .get(off + 1..off + 1 + l)
+                        .context("truncated DNS label")?;
+                    labels.push(String::from_utf8_lossy(label).into_owned());
+                    off += 1 + l;
+                }
+                _ => bail!("invalid DNS label"),
+            }
+        }
+    }
+    bail!("too many compression pointers in DNS name")
+}
+
+/// The SRV records in the answer `buf` to the query `id`
+pub fn parse_response(buf: &[u8], id: u16) -> Result<Vec<SrvRecord>> {
+    let mut records = Vec::new();
+    for (name, rdata) in answers(buf, id, TYPE_SRV)? {
+        records.push(SrvRecord {
+            name,
+            priority: u16_at(buf, rdata)?,
+            weight: u16_at(buf, rdata + 2)?,
+            port: u16_at(buf, rdata + 4)?,
+            target: read_name(buf, rdata + 6)?.0,
+        });
+    }
+    Ok(records)
+}
+
+/// The TXT records in the answer `buf` to the query `id`
+pub fn parse_txt_response(buf: &[u8], id: u16) -> Result<Vec<String>> {
+    Ok(parse_txt_strings(buf, id)?
+        .into_iter()
+        .map(|(_, strings)| String::from_utf8_lossy(&strings.concat()).into_owned())
+        .collect())
+}
+
+/// The names of the TXT records in the answer `buf` to the query `id`,
+/// with the strings of each
+pub fn parse_txt_strings(buf: &[u8], id: u16) -> Result<Vec<(String, Vec<Vec<u8>>)>> {
+    let mut records = Vec::new();
+    for (name, rdata) in answers(buf, id, TYPE_TXT)? {
+        let (mut off, end) = (rdata, rdata + u16_at(buf, rdata - 2)? as usize);
+        let mut strings = Vec::new();
+        while off < end {
+            let len = buf[off] as usize;
+            ensure!(off + 1 + len <= end, "truncated TXT record");
+            strings.push(buf[off + 1..off + 1 + len].to_vec());
+            off += 1 + len;
+        }
+        records.push((name, strings));
+    }
+    Ok(records)
+}
+
+/// The names and the offsets of the data of the records of type `rtype` in
+/// the answer `buf` to the query `id`
+fn answers(buf: &[u8], id: u16, rtype: u16) -> Result<Vec<(String, usize)>> {
+    ensure!(u16_at(buf, 0)? == id, "answer to a different query");
+    let flags = u16_at(buf, 2)?;
+    ensure!(flags & 0x8000 != 0, "not an answer");
+    ensure!(flags & 0x0200 == 0, "answer truncated");
+    match flags & 0x000f {
+        0 => {}
+        3 => bail!("name does not exist"),
+        rcode => bail!("lookup failed with rcode {rcode}"),
+    }
+    let (questions, answers) = (u16_at(buf, 4)?, u16_at(buf, 6)?);
+
+    let mut off = 12;
+    for _ in 0..questions {
+        off = read_name(buf, off)?.1 + 4;
+    }
+    let mut records = Vec::new();
+    for _ in 0..answers {
+        let (name, rr) = read_name(buf, off)?;
+        off = rr;
+        let (ty, rdlen) = (u16_at(buf, off)?, u16_at(buf, off + 8)? as usize);
+        let rdata = off + 10;
+        ensure!(rdata + rdlen <= buf.len(), "truncated DNS record");
+        // answers may include the CNAME records leading to the records
+        if ty == rtype {
+            records.push((name, rdata));
+        }
+        off = rdata + rdlen;
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_srv_answer() {
+        let name = "_rosenpass._udp.example.org";
+        let mut msg = encode_query(0x1234, name, TYPE_SRV).unwrap();
+        msg[2] |= 0x80; // an answer
+        msg[7] = 2; // with two records
+
+        let record = |msg: &mut Vec<u8>, prio: u8, port: u16, target: &[u8]| {
+            msg.extend_from_slice(&[0xc0, 12]); // the name of the question
+            msg.extend_from_slice(&[0, 33, 0, 1, 0, 0, 0, 60]);
+            msg.extend_from_slice(&(6 + target.len() as u16).to_be_bytes());
+            msg.extend_from_slice(&[0, prio, 0, 5]);
+            msg.extend_from_slice(&port.to_be_bytes());
+            msg.extend_from_slice(target);
+        };
+        record(&mut msg, 10, 9999, b"\x02rp\x07example\x03org\x00");
+        // the second target points into the first one: "b.example.org"
+        let example = msg.len() - 13;
+        record(&mut msg, 20, 9998, &[1, b'b', 0xc0, example as u8]);
+
+        let records = parse_response(&msg, 0x1234).unwrap();
+        assert_eq!(
+            records,
+            vec![
+                SrvRecord {
+                    name: name.into(),
+                    priority: 10,
+                    weight: 5,
+                    port: 9999,
+                    target: "rp.example.org".into()
+                },
+                SrvRecord {
+                    name: name.into(),
+                    priority: 20,
+                    weight: 5,
+                    port: 9998,
+                    target: "b.example.org".into()
+                },
+            ]
+        );
+
+        let announcement = encode_srv_announcement(&records[0], &[b"a", b"bc"], 120).unwrap();
+        assert_eq!(parse_response(&announcement, 0).unwrap(), records[..1]);
+        let strings = vec![b"a".to_vec(), b"bc".to_vec()];
+        assert_eq!(
+            parse_txt_strings(&announcement, 0).unwrap(),
+            vec![(name.to_owned(), strings)]
+        );
+
+        assert!(parse_response(&msg, 0x4321).is_err());
+        msg[3] |= 3;
+        assert!(parse_response(&msg, 0x1234).is_err());
+    }
+
+    #[test]
+    fn parse_txt_answer() {
+        let mut msg = encode_query(7, "_rosenpass-challenge.example.org", TYPE_TXT).unwrap();
+        msg[2] |= 0x80;
+        msg[7] = 1;
+        msg.extend_from_slice(&[0xc0, 12, 0, 16, 0, 1, 0, 0, 0, 60, 0, 9]);
+        // strings of a record are joined
+        msg.extend_from_slice(b"\x03abc\x04defg");
+        assert_eq!(parse_txt_response(&msg, 7).unwrap(), vec!["abcdefg"]);
+        assert!(parse_response(&msg, 7).unwrap().is_empty());
+
+        let len = msg.len();
+        msg[len - 5] = 5; // running past the record
+        assert!(parse_txt_response(&msg, 7).is_err());
+    }
+}
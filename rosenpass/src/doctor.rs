@@ -0,0 +1,401 @@
+//! Diagnosing the environment a configuration is run in
+//!
+//! `rosenpass doctor CONFIG_FILE` goes through what most often keeps an
+//! exchange from working and prints a fix for every problem found:
+//!
+//! - the own key pair, the peers' public and preshared keys, and whether the
+//!   secret key can be read by others
+//! - for peers keyed into WireGuard, whether the kernel module is loaded,
+//!   the device exists and `wg` may configure it, or the UAPI socket of a
+//!   userspace implementation accepts connections
+//! - whether the network namespaces named exist and can be entered, and
+//!   whether the VRF to bind to is one
+//! - whether the directories keys are written to are writable
+//! - whether the endpoints of the peers resolve, are routed and do not
+//!   refuse datagrams; an empty datagram is sent to each, which the peer
+//!   drops
+//! - whether enough memory may be locked to keep the secrets out of swap
+//!
+//! An endpoint behind a firewall which drops datagrams can not be told apart
+//! from one which is reachable, so that check only fails on definite errors.
+
+use anyhow::Result;
+use std::{
+    fmt,
+    io::ErrorKind,
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{FileTypeExt, PermissionsExt},
+        net::UnixStream,
+    },
+    path::Path,
+    process::Command,
+    time::Duration,
+};
+
+use crate::{config::Rosenpass, interface, keystream, netns, unix};
+
+/// How long to wait for an endpoint to refuse a datagram
+const REFUSAL_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Memory locked for the secrets of the server and of every peer, roughly;
+/// each secret takes at least one page
+const MLOCK_BASE: u64 = 64 * 1024;
+const MLOCK_PER_PEER: u64 = 32 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Ok,
+    Warn,
+    Fail,
+}
+
+/// The outcome of one check
+#[derive(Debug, Clone)]
+pub struct Finding {
+    pub verdict: Verdict,
+    pub check: String,
+    pub detail: String,
+    /// What to do about it, unless it is fine
+    pub fix: Option<String>,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let verdict = match self.verdict {
+            Verdict::Ok => "ok",
+            Verdict::Warn => "WARN",
+            Verdict::Fail => "FAIL",
+        };
+        write!(f, "{verdict:<4}  {}: {}", self.check, self.detail)?;
+        if let Some(fix) = self.fix.as_ref() {
+            write!(f, "\n      fix: {fix}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The findings of all checks so far
+#[derive(Debug, Default)]
+pub struct Doctor {
+    pub findings: Vec<Finding>,
+}
+
+impl Doctor {
+    pub fn ok(&mut self, check: impl Into<String>, detail: impl Into<String>) {
+        self.add(Verdict::Ok, check.into(), detail.into(), None);
+    }
+
+    pub fn warn(&mut self, check: impl Into<String>, detail: impl Into<String>, fix: String) {
+        self.add(Verdict::Warn, check.into(), detail.into(), Some(fix));
+    }
+
+    pub fn fail(&mut self, check: impl Into<String>, detail: impl Into<String>, fix: String) {
+        self.add(Verdict::Fail, check.into(), detail.into(), Some(fix));
+    }
+
+    /// A finding from the result of a check, with `fix` for a failure
+    pub fn check(&mut self, check: &str, res: Result<String>, fix: impl FnOnce() -> String) {
+        match res {
+            Ok(detail) => self.ok(check, detail),
+            Err(e) => self.fail(check, format!("{e:#}"), fix()),
+        }
+    }
+
+    fn add(&mut self, verdict: Verdict, check: String, detail: String, fix: Option<String>) {
+        self.findings.push(Finding {
+            verdict,
+            check,
+            detail,
+            fix,
+        });
+    }
+
+    pub fn failures(&self) -> usize {
+        self.findings
+            .iter()
+            .filter(|f| f.verdict == Verdict::Fail)
+            .count()
+    }
+
+    /// Check everything about `config` which is not about loading its keys
+    pub fn examine(&mut self, config: &Rosenpass) {
+        self.secret_key_mode(config);
+        self.namespaces(config);
+        self.wireguard(config);
+        self.key_outputs(config);
+        self.endpoints(config);
+        self.mlock(config);
+    }
+
+    fn secret_key_mode(&mut self, config: &Rosenpass) {
+        if config.secret_key_vault.is_some() {
+            return;
+        }
+        let path = &config.secret_key;
+        let Ok(meta) = std::fs::metadata(path) else {
+            // loading the key reports this
+            return;
+        };
+        match meta.permissions().mode() & 0o077 {
+            0 => self.ok(
+                "secret key",
+                format!("{path:?} is only accessible to its owner"),
+            ),
+            _ => self.warn(
+                "secret key",
+                format!("{path:?} can be read by others"),
+                format!("chmod 600 {path:?}"),
+            ),
+        }
+    }
+
+    fn namespaces(&mut self, config: &Rosenpass) {
+        let devices = config.peers.iter().filter_map(|p| p.wg.as_ref());
+        let mut names: Vec<&str> = devices
+            .filter_map(|wg| wg.device_netns.as_deref())
+            .collect();
+        names.extend(config.netns.as_deref());
+        names.sort_unstable();
+        names.dedup();
+        for name in names {
+            self.check(
+                &format!("network namespace {name}"),
+                netns::within(Some(name), || Ok("can be entered".to_string())),
+                || match netns::path(name).exists() {
+                    true => "run as root or grant CAP_SYS_ADMIN".into(),
+                    false => format!("ip netns add {name}"),
+                },
+            );
+        }
+        if let Some(vrf) = config.vrf.as_ref() {
+            let res = netns::within(config.netns.as_deref(), || interface::ensure_vrf(vrf));
+            self.check(
+                &format!("vrf {vrf}"),
+                res.map(|()| "is a VRF device".into()),
+                || {
+                    format!(
+                        "point vrf at a VRF device, or create one with \
+                        ip link add {vrf} type vrf table <TABLE>"
+                    )
+                },
+            );
+        }
+    }
+
+    fn wireguard(&mut self, config: &Rosenpass) {
+        let mut devices: Vec<(String, Option<&str>)> = Vec::new();
+        for (i, peer) in config.peers.iter().enumerate() {
+            let Some(wg) = peer.wg.as_ref() else { continue };
+            match wg.uapi_socket.as_ref() {
+                Some(socket) => self.check(
+                    &format!("peers[{i}].wg.uapi_socket"),
+                    UnixStream::connect(socket)
+                        .map(|_| format!("{socket:?} accepts connections"))
+                        .map_err(|e| anyhow::anyhow!("can not connect to {socket:?}: {e}")),
+                    || {
+                        format!(
+                            "start the userspace WireGuard implementation for {}, or point \
+                            uapi_socket at its socket",
+                            wg.device
+                        )
+                    },
+                ),
+                None => {
+                    let device = (wg.device.clone(), wg.device_netns.as_deref());
+                    if !devices.contains(&device) {
+                        devices.push(device);
+                    }
+                }
+            }
+        }
+        if devices.is_empty() {
+            return;
+        }
+
+        if Path::new("/sys/module/wireguard").exists() {
+            self.ok("wireguard module", "loaded");
+        } else {
+            self.warn(
+                "wireguard module",
+                "not loaded, unless it is built in",
+                "modprobe wireguard, or use a userspace implementation with uapi_socket".into(),
+            );
+        }
+        for (dev, ns) in devices {
+            let check = format!("wireguard device {dev}");
+            // sysfs shows the devices of the namespace it was mounted in
+            if ns.is_none() && !Path::new("/sys/class/net").join(&dev).exists() {
+                self.fail(
+                    check,
+                    "does not exist",
+                    format!("ip link add {dev} type wireguard"),
+                );
+                continue;
+            }
+            let wg_show =
+                netns::within(ns, || Ok(Command::new("wg").args(["show", &dev]).output()));
+            let Ok(wg_show) = wg_show else {
+                continue; // reported by namespaces()
+            };
+            match wg_show {
+                Err(e) if e.kind() == ErrorKind::NotFound => self.fail(
+                    check,
+                    "the wg tool is not installed",
+                    "install wireguard-tools".into(),
+                ),
+                Err(e) => self.fail(
+                    check,
+                    format!("could not run wg: {e}"),
+                    "make sure wg can be run by the user rosenpass runs as".into(),
+                ),
+                Ok(out) if out.status.success() => self.ok(check, "wg can configure it"),
+                Ok(out) => {
+                    let err = String::from_utf8_lossy(&out.stderr).trim().to_string();
+                    let fix = match err.contains("Operation not permitted") {
+                        true => {
+                            "run as root or grant CAP_NET_ADMIN, e.g. with \
+                            AmbientCapabilities=CAP_NET_ADMIN in the systemd unit"
+                        }
+                        false => "make sure wg show works for this device",
+                    };
+                    self.fail(check, err, fix.into());
+                }
+            }
+        }
+    }
+
+    fn key_outputs(&mut self, config: &Rosenpass) {
+        let outputs = config.peers.iter().enumerate().flat_map(|(i, peer)| {
+            let key_out = peer
+                .key_out
+                .iter()
+                .map(move |out| (format!("peers[{i}].key_out"), out));
+            let exports = peer.exports.iter().enumerate().map(move |(j, export)| {
+                (format!("peers[{i}].exports[{j}].key_out"), &export.key_out)
+            });
+            key_out.chain(exports)
+        });
+        for (check, out) in outputs {
+            if out == Path::new(keystream::STDOUT) {
+                continue;
+            }
+            let dir = match out.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            if !dir.is_dir() {
+                self.fail(
+                    check,
+                    format!("directory {dir:?} does not exist"),
+                    format!("mkdir -p {dir:?}"),
+                );
+            } else if !writable(dir) {
+                self.fail(
+                    check,
+                    format!("directory {dir:?} is not writable"),
+                    "make it writable for the user rosenpass runs as".into(),
+                );
+            } else {
+                self.ok(check, format!("{out:?} can be written"));
+            }
+        }
+    }
+
+    fn endpoints(&mut self, config: &Rosenpass) {
+        for (i, peer) in config.peers.iter().enumerate() {
+            let Some(endpoint) = peer.endpoint.as_ref() else {
+                continue;
+            };
+            let check = format!("peers[{i}].endpoint {endpoint}");
+            if let Some(path) = endpoint.strip_prefix(unix::PREFIX) {
+                match Path::new(path).metadata() {
+                    Ok(meta) if meta.file_type().is_socket() => self.ok(&check, "is a socket"),
+                    _ => self.fail(
+                        &check,
+                        "is not a socket",
+                        "make sure rosenpass runs on the peer and listens on this path".into(),
+                    ),
+                }
+                continue;
+            }
+            let addrs = match endpoint.to_socket_addrs() {
+                Ok(addrs) => addrs.collect::<Vec<_>>(),
+                Err(e) => {
+                    self.fail(
+                        check,
+                        format!("does not resolve: {e}"),
+                        "fix the host name, or the DNS configuration".into(),
+                    );
+                    continue;
+                }
+            };
+            for addr in addrs {
+                match probe(addr) {
+                    Ok(()) => self.ok(&check, format!("{addr} is routed and does not refuse")),
+                    Err(e) if e.kind() == ErrorKind::ConnectionRefused => self.fail(
+                        &check,
+                        format!("{addr} refuses datagrams"),
+                        "make sure rosenpass runs on the peer and listens on this port".into(),
+                    ),
+                    Err(e) => self.fail(
+                        &check,
+                        format!("{addr} is unreachable: {e}"),
+                        "check the routes to the peer".into(),
+                    ),
+                }
+            }
+        }
+    }
+
+    fn mlock(&mut self, config: &Rosenpass) {
+        let mut limit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        if unsafe { libc::getrlimit(libc::RLIMIT_MEMLOCK, &mut limit) } != 0 {
+            return;
+        }
+        let wanted = MLOCK_BASE + MLOCK_PER_PEER * config.peers.len() as u64;
+        match limit.rlim_cur == libc::RLIM_INFINITY || limit.rlim_cur >= wanted {
+            true => self.ok("locked memory", "the limit leaves room for all secrets"),
+            false => self.warn(
+                "locked memory",
+                format!(
+                    "limited to {} KiB, secrets may be swapped out",
+                    limit.rlim_cur / 1024
+                ),
+                format!(
+                    "raise it to at least {} KiB with ulimit -l, or LimitMEMLOCK= in the \
+                    systemd unit",
+                    wanted / 1024
+                ),
+            ),
+        }
+    }
+}
+
+/// Send an empty datagram to `addr` and wait for it to be refused
+fn probe(addr: SocketAddr) -> std::io::Result<()> {
+    let any: SocketAddr = match addr {
+        SocketAddr::V4(_) => "0.0.0.0:0".parse().unwrap(),
+        SocketAddr::V6(_) => "[::]:0".parse().unwrap(),
+    };
+    let sock = UdpSocket::bind(any)?;
+    sock.connect(addr)?;
+    sock.send(&[])?;
+    sock.set_read_timeout(Some(REFUSAL_TIMEOUT))?;
+    match sock.recv(&mut [0u8; 1]) {
+        Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => Ok(()),
+        Err(e) => Err(e),
+        Ok(_) => Ok(()),
+    }
+}
+
+fn writable(dir: &Path) -> bool {
+    let Ok(path) = std::ffi::CString::new(dir.as_os_str().as_bytes()) else {
+        return false;
+    };
+    unsafe { libc::access(path.as_ptr(), libc::W_OK) == 0 }
+}
@@ -0,0 +1,781 @@
+//! Enrollment of new peers with tokens or DNS challenges
+//!
+//! Rolling out many clients to a hub takes copying every public key onto
+//! it. With enrollment, the hub hands out tokens instead, and each client
+//! registers its public key itself with `rosenpass enroll`; the hub adds the
+//! peer and starts exchanging keys with it right away:
+//!
+//! ```toml
+//! [enrollment]
+//! listen = "0.0.0.0:9998"
+//! tokens = "/etc/rosenpass/enrollment-tokens"
+//! secrets = "/etc/rosenpass/enrollment-secrets"
+//! dns_names = ["devices.example.org"]
+//! bundle = "/etc/rosenpass/hub.bundle"
+//! directory = "/var/lib/rosenpass/enrolled"
+//! group = "clients"
+//! ```
+//!
+//! The `tokens` file holds one token per line; a token is removed from it
+//! once a peer enrolled with it. The `secrets` file is read the same way,
+//! but its secrets are shared by any number of clients and never used up.
+//!
+//! Clients can instead prove that they control a DNS name below one of the
+//! `dns_names`, like ACME does for certificates. The hub answers the first
+//! request for a name with a challenge, which the client publishes as TXT
+//! record of `_rosenpass-challenge.<name>` before asking again; the hub
+//! looks the record up and enrolls the client once it holds the challenge.
+//! Challenges are bound to the name and the public key of the client, and
+//! only valid until the hub restarts.
+//!
+//! Enrolled clients receive the `bundle` of the hub, if one is configured:
+//! its public key signed by a CA (see [crate::ca]), which they can use as
+//! the public key of the hub right away.
+//!
+//! Enrolled peers are kept in the `directory` just like the peers of the
+//! [crate::api], and are members of the `group`, if one is given, which
+//! lends them everything else: their `key_out_dir`, tags and so on. A change is checked by loading the config again, so
+//! enrollment is only available with a config file.
+//!
+//! Clients connect over TCP and need to know the public key of the hub. The
+//! request is encrypted to it with the static KEM: it carries the token or
+//! DNS name and the public key of the client, and is answered with whether
+//! the client was enrolled, encrypted with the same shared key. So neither
+//! the token nor the key can be read or swapped on the way, and only the hub
+//! can confirm the enrollment.
+//!
+//! Each connection is served on a thread of its own, which has to receive
+//! the request within [ENROLLMENT_TIMEOUT], decrypts it and looks up the
+//! challenge, within [CHALLENGE_LOOKUP_TIMEOUT]; only then the thread
+//! exchanging keys decides on it, see [crate::serve]. Every source may send
+//! [RATE_BURST] requests in a row, and one every [RATE_INTERVAL] after that;
+//! further connections are closed before anything is looked up.
+
+use anyhow::{bail, ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs,
+    io::{Read, Write},
+    net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs},
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use rosenpass_ciphers::xaead;
+use rosenpass_constant_time::memeq;
+use rosenpass_sodium::helpers::{memzero, randombytes_buf};
+use rosenpass_util::b64::fmt_b64;
+
+use crate::{
+    api, ca, dns, labeled_prf as lprf,
+    pqkem::{StaticKEM, KEM},
+    protocol::{SPk, SSk, SymKey},
+    serve::{self, Connections},
+};
+
+/// How long a client may take to send its request, or to receive the reply
+pub const ENROLLMENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Tokens and DNS names are at most this many bytes long
+pub const MAX_TOKEN_LEN: usize = 255;
+
+/// How often a client asks again while the hub can not see its challenge
+pub const CHALLENGE_RETRY: Duration = Duration::from_secs(10);
+
+/// Records holding challenges are these names, followed by the name
+pub const CHALLENGE_PREFIX: &str = "_rosenpass-challenge.";
+
+/// How long the hub looks for a challenge, short of the time a client waits
+/// for the reply
+pub const CHALLENGE_LOOKUP_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Requests a source may send in a row, see [RateLimit]
+pub const RATE_BURST: u32 = 5;
+
+/// How often a source may send a request once it used up [RATE_BURST]
+pub const RATE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Most sources [RateLimit] keeps track of
+const MAX_SOURCES: usize = 4096;
+
+const MAGIC: &[u8] = b"rosenpass enrollment v2";
+const TOKEN: u8 = 0;
+const DNS: u8 = 1;
+const ENROLLED: u8 = 0;
+const REFUSED: u8 = 1;
+const CHALLENGE: u8 = 2;
+
+/// Longest request a client can send
+const MAX_REQUEST: usize = MAGIC.len()
+    + StaticKEM::CT_LEN
+    + xaead::NONCE_LEN
+    + 2
+    + MAX_TOKEN_LEN
+    + StaticKEM::PK_LEN
+    + xaead::TAG_LEN;
+
+/// Longest reply the hub sends, one with a bundle of the longest name
+const MAX_REPLY: usize = xaead::NONCE_LEN
+    + 1
+    + 18
+    + u16::MAX as usize
+    + StaticKEM::PK_LEN
+    + ca::SIG_LEN
+    + xaead::TAG_LEN;
+
+/// Settings of enrollment; disabled without them
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Enrollment {
+    pub listen: SocketAddr,
+    /// File with the tokens still unused, one per line
+    #[serde(default)]
+    pub tokens: Option<PathBuf>,
+    /// File with the secrets clients may enroll with any number of times
+    #[serde(default)]
+    pub secrets: Option<PathBuf>,
+    /// Domains whose names clients may enroll with, by DNS challenge
+    #[serde(default)]
+    pub dns_names: Vec<String>,
+    /// The public key of the hub signed by a CA, handed to enrolled clients
+    #[serde(default)]
+    pub bundle: Option<PathBuf>,
+    /// Where the enrolled peers are kept
+    pub directory: PathBuf,
+    /// Group the enrolled peers are members of
+    #[serde(default)]
+    pub group: Option<String>,
+}
+
+impl Enrollment {
+    pub fn validate(&self) -> Result<()> {
+        ensure!(
+            self.tokens.is_some() || self.secrets.is_some() || !self.dns_names.is_empty(),
+            "enrollment needs tokens, secrets or dns_names"
+        );
+        for file in self.tokens.iter().chain(self.secrets.iter()) {
+            ensure!(file.is_file(), "enrollment file {file:?} does not exist");
+        }
+        if let Some(bundle) = self.bundle.as_ref() {
+            ensure!(
+                bundle.is_file(),
+                "enrollment bundle {bundle:?} does not exist"
+            );
+        }
+        Ok(())
+    }
+}
+
+/// What a client enrolls with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Proof {
+    /// A token or secret handed out by the hub
+    Token(Vec<u8>),
+    /// A DNS name the client can publish records for
+    Dns(String),
+}
+
+/// A request decrypted by the thread serving the client, for the event loop
+/// to decide on; a DNS name was checked to hold the challenge already
+#[derive(Debug)]
+pub struct Request {
+    pub from: SocketAddr,
+    pub proof: Proof,
+    pub pk: SPk,
+}
+
+/// The answer of the hub to a request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    /// With the bundle of the hub, empty if it has none
+    Enrolled(Vec<u8>),
+    Refused(String),
+    /// The value the TXT record of the DNS name is to hold
+    Challenge(String),
+}
+
+/// The challenge for enrolling `pk` with the DNS name `name`, made with
+/// the secret `key` of the hub
+pub fn challenge(key: &SymKey, name: &str, pk: &SPk) -> Result<String> {
+    let value = lprf::enrollment()?
+        .mix_secret(key.clone())?
+        .mix(b"dns challenge")?
+        .mix(name.to_ascii_lowercase().as_bytes())?
+        .mix(pk.secret())?
+        .into_secret();
+    Ok(fmt_b64(value.secret()).to_string())
+}
+
+/// The name of the TXT record holding the challenge for `name`
+pub fn challenge_record(name: &str) -> String {
+    format!("{CHALLENGE_PREFIX}{}", name.trim_end_matches('.'))
+}
+
+/// Whether `name` is one of the `domains` or a name below one
+pub fn is_covered(domains: &[String], name: &str) -> bool {
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+    domains.iter().any(|d| {
+        let d = d.trim_end_matches('.').to_ascii_lowercase();
+        name == d || name.strip_suffix(&d).is_some_and(|sub| sub.ends_with('.'))
+    })
+}
+
+/// What DNS challenges are made and checked with
+#[derive(Clone)]
+pub struct Challenges {
+    /// Domains whose names clients may enroll with
+    pub dns_names: Vec<String>,
+    /// What the challenges are made with, see [challenge]
+    key: SymKey,
+}
+
+impl std::fmt::Debug for Challenges {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Challenges")
+            .field("dns_names", &self.dns_names)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Challenges {
+    pub fn new(dns_names: Vec<String>) -> Self {
+        Self {
+            dns_names,
+            key: SymKey::random(),
+        }
+    }
+
+    /// Look up whether the client controls `name` to enroll `pk`; returns
+    /// the challenge it is to publish unless it did so already
+    pub fn check(&self, name: &str, pk: &SPk) -> Result<Option<String>> {
+        ensure!(
+            is_covered(&self.dns_names, name),
+            "{name:?} is not below the dns_names of the hub"
+        );
+        let value = challenge(&self.key, name, pk)?;
+        let record = challenge_record(name);
+        match dns::query_txt(&record, CHALLENGE_LOOKUP_TIMEOUT) {
+            Ok(found) if found.contains(&value) => Ok(None),
+            Ok(_) => Ok(Some(value)),
+            Err(e) => {
+                log::debug!("challenge of {name} not found: {e:#}");
+                Ok(Some(value))
+            }
+        }
+    }
+}
+
+/// What a source of requests used up of its [RATE_BURST], and when
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    used: f64,
+    at: Instant,
+}
+
+impl Bucket {
+    fn used_at(&self, now: Instant) -> f64 {
+        let regained =
+            now.saturating_duration_since(self.at).as_secs_f64() / RATE_INTERVAL.as_secs_f64();
+        (self.used - regained).max(0.0)
+    }
+}
+
+/// The requests of each source, as a token bucket: of each IPv4 address,
+/// and of each /64 prefix for IPv6, where clients have many addresses at
+/// hand
+#[derive(Debug, Default)]
+pub struct RateLimit {
+    sources: HashMap<IpAddr, Bucket>,
+}
+
+impl RateLimit {
+    /// Whether another request from `addr` is allowed at `now`
+    pub fn admits(&mut self, addr: IpAddr, now: Instant) -> bool {
+        let source = match addr {
+            IpAddr::V4(_) => addr,
+            IpAddr::V6(v6) => IpAddr::V6((u128::from(v6) & !(u64::MAX as u128)).into()),
+        };
+        if !self.sources.contains_key(&source) && self.sources.len() >= MAX_SOURCES {
+            // sources which can send a full burst again are as good as new
+            self.sources.retain(|_, bucket| bucket.used_at(now) > 0.0);
+            if self.sources.len() >= MAX_SOURCES {
+                return false;
+            }
+        }
+        let bucket = self
+            .sources
+            .entry(source)
+            .or_insert(Bucket { used: 0.0, at: now });
+        let used = bucket.used_at(now);
+        if used + 1.0 > RATE_BURST as f64 {
+            return false;
+        }
+        *bucket = Bucket {
+            used: used + 1.0,
+            at: now,
+        };
+        true
+    }
+}
+
+/// The key a request and its reply are encrypted with
+fn seal_key(shk: SymKey, ct: &[u8]) -> Result<SymKey> {
+    Ok(lprf::enrollment()?.mix_secret(shk)?.mix(ct)?.into_secret())
+}
+
+fn encrypt(key: &SymKey, ad: &[u8], plain: &[u8]) -> Result<Vec<u8>> {
+    let mut nonce = [0u8; xaead::NONCE_LEN];
+    randombytes_buf(&mut nonce);
+    let mut ct = vec![0u8; xaead::NONCE_LEN + plain.len() + xaead::TAG_LEN];
+    xaead::encrypt(&mut ct, key.secret(), &nonce, ad, plain)?;
+    Ok(ct)
+}
+
+fn decrypt(key: &SymKey, ad: &[u8], ct: &[u8]) -> Result<Vec<u8>> {
+    ensure!(
+        ct.len() >= xaead::NONCE_LEN + xaead::TAG_LEN,
+        "enrollment message is truncated"
+    );
+    let mut plain = vec![0u8; ct.len() - xaead::NONCE_LEN - xaead::TAG_LEN];
+    xaead::decrypt(&mut plain, key.secret(), ad, ct)
+        .context("could not decrypt enrollment message")?;
+    Ok(plain)
+}
+
+/// Encrypt the request to enroll `pk` with `proof` at the hub with the
+/// public key `hub`; returns the request and the key of the reply
+pub fn seal_request(hub: &SPk, proof: &Proof, pk: &SPk) -> Result<(Vec<u8>, SymKey)> {
+    let (kind, token) = match proof {
+        Proof::Token(token) => (TOKEN, token.as_slice()),
+        Proof::Dns(name) => (DNS, name.as_bytes()),
+    };
+    ensure!(
+        !token.is_empty() && token.len() <= MAX_TOKEN_LEN,
+        "enrollment tokens and names are between 1 and {MAX_TOKEN_LEN} bytes long"
+    );
+    let mut shk = SymKey::zero();
+    let mut ct = vec![0u8; StaticKEM::CT_LEN];
+    StaticKEM::encaps(shk.secret_mut(), &mut ct, hub.secret())?;
+    let key = seal_key(shk, &ct)?;
+
+    let mut plain = vec![kind, token.len() as u8];
+    plain.extend_from_slice(token);
+    plain.extend_from_slice(pk.secret());
+    let head = [MAGIC, &ct].concat();
+    let res = encrypt(&key, &head, &plain);
+    memzero(&mut plain);
+    Ok(([head, res?].concat(), key))
+}
+
+/// Decrypt a request with the secret key of the hub; returns the proof, the
+/// public key to enroll and the key of the reply
+pub fn open_request(sk: &SSk, msg: &[u8]) -> Result<(Proof, SPk, SymKey)> {
+    let head_len = MAGIC.len() + StaticKEM::CT_LEN;
+    ensure!(
+        msg.len() > head_len && msg.starts_with(MAGIC),
+        "not an enrollment request"
+    );
+    let (head, sealed) = msg.split_at(head_len);
+    let mut shk = SymKey::zero();
+    StaticKEM::decaps(shk.secret_mut(), sk.secret(), &head[MAGIC.len()..])?;
+    let key = seal_key(shk, &head[MAGIC.len()..])?;
+
+    let mut plain = decrypt(&key, head, sealed)?;
+    let res = match plain.as_slice() {
+        [kind, len, rest @ ..] if rest.len() == *len as usize + StaticKEM::PK_LEN => {
+            let (token, pk) = rest.split_at(*len as usize);
+            let proof = match *kind {
+                TOKEN => Ok(Proof::Token(token.to_vec())),
+                DNS => String::from_utf8(token.to_vec())
+                    .map(Proof::Dns)
+                    .context("the DNS name is not UTF-8"),
+                _ => Err(anyhow::anyhow!("unknown kind of enrollment {kind}")),
+            };
+            proof.map(|proof| (proof, SPk::from_slice(pk), key))
+        }
+        _ => Err(anyhow::anyhow!("enrollment request is malformed")),
+    };
+    memzero(&mut plain);
+    res
+}
+
+/// Encrypt the answer to a request
+pub fn seal_reply(key: &SymKey, verdict: &Verdict) -> Result<Vec<u8>> {
+    let plain = match verdict {
+        Verdict::Enrolled(bundle) => [&[ENROLLED], bundle.as_slice()].concat(),
+        Verdict::Refused(why) => [&[REFUSED], why.as_bytes()].concat(),
+        Verdict::Challenge(value) => [&[CHALLENGE], value.as_bytes()].concat(),
+    };
+    encrypt(key, MAGIC, &plain)
+}
+
+/// Decrypt the answer to a request
+pub fn open_reply(key: &SymKey, msg: &[u8]) -> Result<Verdict> {
+    let plain = decrypt(key, MAGIC, msg)?;
+    let text = |b: &[u8]| String::from_utf8_lossy(b).into_owned();
+    Ok(match plain.split_first() {
+        Some((&ENROLLED, bundle)) => Verdict::Enrolled(bundle.to_vec()),
+        Some((&REFUSED, why)) => Verdict::Refused(text(why)),
+        Some((&CHALLENGE, value)) => Verdict::Challenge(text(value)),
+        _ => bail!("enrollment reply is malformed"),
+    })
+}
+
+/// Messages go over the connection with their length in front
+fn write_msg<W: Write>(mut w: W, msg: &[u8]) -> Result<()> {
+    w.write_all(&(msg.len() as u32).to_be_bytes())?;
+    w.write_all(msg)?;
+    Ok(w.flush()?)
+}
+
+fn read_msg<R: Read>(mut r: R, max: usize) -> Result<Vec<u8>> {
+    let mut len = [0u8; 4];
+    r.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    ensure!(len <= max, "enrollment message of {len} bytes is too long");
+    let mut msg = vec![0u8; len];
+    r.read_exact(&mut msg)?;
+    Ok(msg)
+}
+
+/// Whether `token` is in the tokens file `path`
+pub fn is_valid(path: &Path, token: &[u8]) -> Result<bool> {
+    let tokens = read_tokens(path)?;
+    // every token is compared, so the time taken tells nothing about them
+    Ok(tokens
+        .iter()
+        .fold(false, |found, t| memeq(t.as_bytes(), token) | found))
+}
+
+/// Remove `token` from the tokens file `path`
+pub fn consume(path: &Path, token: &[u8]) -> Result<()> {
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("could not read enrollment tokens {path:?}"))?;
+    let rest: String = text
+        .lines()
+        .filter(|line| !memeq(line.trim().as_bytes(), token))
+        .map(|line| format!("{line}\n"))
+        .collect();
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, rest)
+        .and_then(|()| fs::rename(&tmp, path))
+        .with_context(|| format!("could not write enrollment tokens {path:?}"))
+}
+
+fn read_tokens(path: &Path) -> Result<Vec<String>> {
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("could not read enrollment tokens {path:?}"))?;
+    Ok(text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect())
+}
+
+/// What a token was found among
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Credential {
+    /// The one-time tokens; it is to be consumed
+    Token,
+    Secret,
+}
+
+/// The listening side of enrollment, driven by
+/// [crate::app_server::AppServer]
+pub struct EnrollmentServer {
+    pub listener: mio::net::TcpListener,
+    /// The clients being served
+    pub connections: Connections<Request, Verdict>,
+    /// Requests taken from each source
+    pub rate: RateLimit,
+    pub tokens: Option<PathBuf>,
+    pub secrets: Option<PathBuf>,
+    pub challenges: Challenges,
+    pub bundle: Option<PathBuf>,
+    pub directory: PathBuf,
+    pub group: Option<String>,
+    /// Loads the config again and returns the enrolled peers
+    pub reload: api::Reload,
+}
+
+impl std::fmt::Debug for EnrollmentServer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EnrollmentServer")
+            .field("listener", &self.listener)
+            .field("tokens", &self.tokens)
+            .field("secrets", &self.secrets)
+            .field("challenges", &self.challenges)
+            .field("bundle", &self.bundle)
+            .field("directory", &self.directory)
+            .field("group", &self.group)
+            .finish_non_exhaustive()
+    }
+}
+
+impl EnrollmentServer {
+    /// Listen as configured by `cfg`; `waker` wakes the event loop once a
+    /// request was read
+    pub fn bind(cfg: &Enrollment, reload: api::Reload, waker: Arc<mio::Waker>) -> Result<Self> {
+        fs::create_dir_all(&cfg.directory).with_context(|| {
+            format!("could not create enrollment directory {:?}", cfg.directory)
+        })?;
+        let listener = mio::net::TcpListener::bind(cfg.listen)
+            .with_context(|| format!("could not listen for enrollment on {}", cfg.listen))?;
+        Ok(Self {
+            listener,
+            connections: Connections::new("enrollment", ENROLLMENT_TIMEOUT, waker),
+            rate: RateLimit::default(),
+            tokens: cfg.tokens.clone(),
+            secrets: cfg.secrets.clone(),
+            challenges: Challenges::new(cfg.dns_names.clone()),
+            bundle: cfg.bundle.clone(),
+            directory: cfg.directory.clone(),
+            group: cfg.group.clone(),
+            reload,
+        })
+    }
+
+    /// Whether `token` is one of the tokens or of the secrets
+    pub fn credential(&self, token: &[u8]) -> Result<Option<Credential>> {
+        for (file, kind) in [
+            (self.tokens.as_ref(), Credential::Token),
+            (self.secrets.as_ref(), Credential::Secret),
+        ] {
+            if let Some(file) = file {
+                if is_valid(file, token)? {
+                    return Ok(Some(kind));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// The bundle handed to enrolled clients, empty without one
+    pub fn bundle(&self) -> Result<Vec<u8>> {
+        match self.bundle.as_ref() {
+            Some(path) => fs::read(path).with_context(|| format!("could not read bundle {path:?}")),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Accept a pending connection, if any; as blocking with
+    /// [ENROLLMENT_TIMEOUT]
+    pub fn accept(&self) -> Result<Option<TcpStream>> {
+        serve::accept(&self.listener, ENROLLMENT_TIMEOUT)
+    }
+
+    /// Serve the client of `stream` off the event loop, opening its request
+    /// with our secret key `sk` and checking its challenge; it is decided on
+    /// through [Connections::try_next]
+    ///
+    /// The connection is closed right away if its source sent too many
+    /// requests, see [RateLimit].
+    pub fn serve(&mut self, stream: TcpStream, sk: &SSk) {
+        let from = match stream.peer_addr() {
+            Ok(from) if self.rate.admits(from.ip(), Instant::now()) => from,
+            from => {
+                log::debug!("Too many enrollment requests from {from:?}, closing");
+                return;
+            }
+        };
+        let (sk, challenges) = (sk.clone(), self.challenges.clone());
+        self.connections.serve(stream, move |stream, event_loop| {
+            let request = read_msg(&mut *stream, MAX_REQUEST)?;
+            // without the token, a client is told nothing but that it is wrong
+            let (proof, pk, key) = open_request(&sk, &request)?;
+            let checked = match &proof {
+                Proof::Dns(name) => match challenges.check(name, &pk) {
+                    Ok(None) => None,
+                    Ok(Some(value)) => {
+                        log::info!("Challenged {name} to enroll");
+                        Some(Verdict::Challenge(value))
+                    }
+                    Err(e) => {
+                        log::warn!("Enrollment from {from} failed: {e:#}");
+                        Some(Verdict::Refused(format!("{e:#}")))
+                    }
+                },
+                Proof::Token(_) => None,
+            };
+            let verdict = checked.unwrap_or_else(|| {
+                event_loop
+                    .ask(Request { from, proof, pk })
+                    .unwrap_or_else(|| Verdict::Refused("the hub is shutting down".into()))
+            });
+            let reply = seal_reply(&key, &verdict)?;
+            stream.renew();
+            write_msg(stream, &reply)
+        });
+    }
+}
+
+/// Enroll `pk` with `proof` at the hub at `addr` with the public key `hub`;
+/// returns the bundle of the hub, empty if it has none
+///
+/// Challenges are handed to `publish` along with the name of their record,
+/// and asked about again every [CHALLENGE_RETRY] for at most `wait`.
+pub fn enroll<F>(
+    addr: &str,
+    hub: &SPk,
+    proof: &Proof,
+    pk: &SPk,
+    wait: Duration,
+    mut publish: F,
+) -> Result<Vec<u8>>
+where
+    F: FnMut(&str, &str) -> Result<()>,
+{
+    let start = Instant::now();
+    let mut published = None;
+    loop {
+        let value = match request(addr, hub, proof, pk)? {
+            Verdict::Enrolled(bundle) => return Ok(bundle),
+            Verdict::Refused(why) => bail!("the hub refused the enrollment: {why}"),
+            Verdict::Challenge(value) => value,
+        };
+        let Proof::Dns(name) = proof else {
+            bail!("the hub sent a challenge for a token");
+        };
+        // a hub which restarted has a new challenge
+        if published.as_ref() != Some(&value) {
+            publish(&challenge_record(name), &value)?;
+            published = Some(value);
+        }
+        ensure!(
+            start.elapsed() < wait,
+            "the hub did not find the challenge for {name} within {wait:?}"
+        );
+        thread::sleep(CHALLENGE_RETRY);
+    }
+}
+
+/// Send a single request to enroll `pk` with `proof` at the hub at `addr`
+pub fn request(addr: &str, hub: &SPk, proof: &Proof, pk: &SPk) -> Result<Verdict> {
+    let (request, key) = seal_request(hub, proof, pk)?;
+    let addrs = addr
+        .to_socket_addrs()
+        .with_context(|| format!("could not resolve hub {addr}"))?
+        .collect::<Vec<_>>();
+    let mut stream = TcpStream::connect_timeout(
+        addrs
+            .first()
+            .with_context(|| format!("hub {addr} has no address"))?,
+        ENROLLMENT_TIMEOUT,
+    )
+    .with_context(|| format!("could not connect to hub {addr}"))?;
+    stream.set_read_timeout(Some(ENROLLMENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(ENROLLMENT_TIMEOUT))?;
+    write_msg(&mut stream, &request)?;
+    // a hub which can not decrypt the request hangs up
+    let reply = read_msg(&mut stream, MAX_REPLY)
+        .context("no reply from the hub, is the public key the one of the hub?")?;
+    open_reply(&key, &reply)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn keypair() -> (SSk, SPk) {
+        let (mut sk, mut pk) = (SSk::zero(), SPk::zero());
+        StaticKEM::keygen(sk.secret_mut(), pk.secret_mut()).unwrap();
+        (sk, pk)
+    }
+
+    #[test]
+    fn requests_are_only_read_by_the_hub() {
+        let (hub_sk, hub_pk) = keypair();
+        let (_, client_pk) = keypair();
+        let token = Proof::Token(b"t0ken".to_vec());
+        let (request, key) = seal_request(&hub_pk, &token, &client_pk).unwrap();
+        let (proof, pk, hub_key) = open_request(&hub_sk, &request).unwrap();
+        assert_eq!(proof, token);
+        assert_eq!(pk.secret(), client_pk.secret());
+
+        for verdict in [
+            Verdict::Enrolled(vec![]),
+            Verdict::Enrolled(vec![1, 2, 3]),
+            Verdict::Refused("used up".into()),
+            Verdict::Challenge("abc".into()),
+        ] {
+            let reply = seal_reply(&hub_key, &verdict).unwrap();
+            assert_eq!(open_reply(&key, &reply).unwrap(), verdict);
+        }
+
+        let name = Proof::Dns("laptop.devices.example.org".into());
+        let (request, _) = seal_request(&hub_pk, &name, &client_pk).unwrap();
+        assert_eq!(open_request(&hub_sk, &request).unwrap().0, name);
+
+        let (other_sk, _) = keypair();
+        assert!(open_request(&other_sk, &request).is_err());
+        let mut tampered = request.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(open_request(&hub_sk, &tampered).is_err());
+        assert!(seal_request(&hub_pk, &Proof::Token(vec![]), &client_pk).is_err());
+    }
+
+    #[test]
+    fn challenges_are_bound_to_name_and_key() {
+        let (_, a) = keypair();
+        let (_, b) = keypair();
+        let key = SymKey::random();
+        let laptop = challenge(&key, "laptop.example.org", &a).unwrap();
+        assert_eq!(laptop, challenge(&key, "Laptop.example.org", &a).unwrap());
+        assert_ne!(laptop, challenge(&key, "phone.example.org", &a).unwrap());
+        assert_ne!(laptop, challenge(&key, "laptop.example.org", &b).unwrap());
+        assert_ne!(
+            laptop,
+            challenge(&SymKey::random(), "laptop.example.org", &a).unwrap()
+        );
+        assert_eq!(
+            challenge_record("laptop.example.org."),
+            "_rosenpass-challenge.laptop.example.org"
+        );
+
+        let domains = ["devices.example.org.".to_string()];
+        assert!(is_covered(&domains, "devices.example.org"));
+        assert!(is_covered(&domains, "laptop.Devices.example.org"));
+        assert!(!is_covered(&domains, "evildevices.example.org"));
+        assert!(!is_covered(&domains, "example.org"));
+    }
+
+    #[test]
+    fn requests_are_limited_per_source() {
+        let mut rate = RateLimit::default();
+        let now = Instant::now();
+        let a: IpAddr = "192.0.2.1".parse().unwrap();
+        for _ in 0..RATE_BURST {
+            assert!(rate.admits(a, now));
+        }
+        assert!(!rate.admits(a, now));
+        assert!(rate.admits("192.0.2.2".parse().unwrap(), now));
+        assert!(rate.admits(a, now + RATE_INTERVAL));
+        assert!(!rate.admits(a, now + RATE_INTERVAL));
+
+        // the addresses of a /64 are one source
+        for i in 0..RATE_BURST {
+            assert!(rate.admits(format!("2001:db8::{i}").parse().unwrap(), now));
+        }
+        assert!(!rate.admits("2001:db8::ffff".parse().unwrap(), now));
+        assert!(rate.admits("2001:db8:0:1::1".parse().unwrap(), now));
+    }
+
+    #[test]
+    fn tokens_are_used_once() {
+        let path = std::env::temp_dir().join(format!("rp-tokens-{}", std::process::id()));
+        fs::write(&path, "# for the lab\nabc\n  def \n").unwrap();
+        assert!(is_valid(&path, b"abc").unwrap());
+        assert!(is_valid(&path, b"def").unwrap());
+        assert!(!is_valid(&path, b"# for the lab").unwrap());
+        assert!(!is_valid(&path, b"ab").unwrap());
+
+        consume(&path, b"def").unwrap();
+        assert!(!is_valid(&path, b"def").unwrap());
+        assert!(is_valid(&path, b"abc").unwrap());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "# for the lab\nabc\n");
+        fs::remove_file(&path).unwrap();
+    }
+}
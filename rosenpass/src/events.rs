@@ -0,0 +1,183 @@
+//! Events for applications embedding rosenpass as a library
+//!
+//! [AppServer::subscribe](crate::app_server::AppServer::subscribe) returns an
+//! [EventStream] of what happens to the peers, so embedders can react to keys
+//! and failures without scraping logs or watching key files. The stream does
+//! not depend on an async runtime: [EventStream::poll_next] has the signature
+//! of `futures::Stream::poll_next`, so
+//!
+//! ```text
+//! let stream = futures::stream::poll_fn(move |cx| Pin::new(&mut events).poll_next(cx));
+//! ```
+//!
+//! turns it into a `Stream`; [EventStream::next] can be awaited directly and
+//! [EventStream::try_next] is for synchronous callers. Subscribers which fall
+//! behind by more than [QUEUE_LEN] events lose the oldest ones. The stream
+//! ends once the server is dropped.
+
+use std::{
+    collections::VecDeque,
+    future::poll_fn,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+};
+
+use crate::{app_server::AppPeerPtr, protocol::SymKey, stats::FailureCause};
+
+/// Events waiting for a subscriber, at most; older ones are dropped
+pub const QUEUE_LEN: usize = 1024;
+
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// A key was exchanged with the peer, and both sides are known to have
+    /// it; a handshake which is not confirmed yields no key
+    KeyEstablished { peer: AppPeerPtr, key: SymKey },
+    /// The key of the peer was replaced by a random one, as it ran out
+    /// without a new one or the peer was revoked
+    KeyExpired { peer: AppPeerPtr },
+    /// Handshakes with the peer went unanswered, see [crate::breaker]
+    PeerUnreachable { peer: AppPeerPtr },
+    /// An unreachable peer completed a handshake again
+    PeerReachable { peer: AppPeerPtr },
+    /// A handshake message was rejected; the peer is known unless the
+    /// message came from an address of none of them
+    HandshakeFailed {
+        peer: Option<AppPeerPtr>,
+        from: Option<SocketAddr>,
+        cause: FailureCause,
+    },
+}
+
+#[derive(Debug, Default)]
+struct Queue {
+    events: VecDeque<Event>,
+    waker: Option<Waker>,
+    closed: bool,
+}
+
+/// The events of one subscriber, see the [module documentation](self)
+#[derive(Debug)]
+pub struct EventStream {
+    queue: Arc<Mutex<Queue>>,
+}
+
+impl EventStream {
+    /// The next event, or `None` once the server is gone; with no event
+    /// there yet, `cx` is woken when there is one
+    pub fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Event>> {
+        let mut queue = self.queue.lock().unwrap();
+        match queue.events.pop_front() {
+            Some(ev) => Poll::Ready(Some(ev)),
+            None if queue.closed => Poll::Ready(None),
+            None => {
+                queue.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+
+    /// Wait for the next event, or `None` once the server is gone
+    pub async fn next(&mut self) -> Option<Event> {
+        poll_fn(|cx| Pin::new(&mut *self).poll_next(cx)).await
+    }
+
+    /// The next event if there is one already
+    pub fn try_next(&mut self) -> Option<Event> {
+        self.queue.lock().unwrap().events.pop_front()
+    }
+}
+
+/// The queues of all subscribers, closed when dropped
+#[derive(Debug, Default)]
+pub struct Subscribers {
+    queues: Mutex<Vec<Arc<Mutex<Queue>>>>,
+}
+
+impl Subscribers {
+    pub fn subscribe(&self) -> EventStream {
+        let queue = Arc::new(Mutex::new(Queue::default()));
+        self.queues.lock().unwrap().push(queue.clone());
+        EventStream { queue }
+    }
+
+    /// Hand `ev` to every subscriber which is still listening
+    pub fn send(&self, ev: Event) {
+        let mut queues = self.queues.lock().unwrap();
+        queues.retain(|q| Arc::strong_count(q) > 1);
+        for queue in queues.iter() {
+            let mut queue = queue.lock().unwrap();
+            if queue.events.len() == QUEUE_LEN {
+                queue.events.pop_front();
+            }
+            queue.events.push_back(ev.clone());
+            if let Some(waker) = queue.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+impl Drop for Subscribers {
+    fn drop(&mut self) {
+        for queue in self.queues.get_mut().unwrap().iter() {
+            let mut queue = queue.lock().unwrap();
+            queue.closed = true;
+            if let Some(waker) = queue.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::task::Wake;
+
+    struct Flag(Mutex<bool>);
+
+    impl Wake for Flag {
+        fn wake(self: Arc<Self>) {
+            *self.0.lock().unwrap() = true;
+        }
+    }
+
+    #[test]
+    fn delivers_and_ends() {
+        let subs = Subscribers::default();
+        let mut events = subs.subscribe();
+        let flag = Arc::new(Flag(Mutex::new(false)));
+        let waker = Waker::from(flag.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        assert!(Pin::new(&mut events).poll_next(&mut cx).is_pending());
+        subs.send(Event::KeyExpired {
+            peer: AppPeerPtr(3),
+        });
+        assert!(*flag.0.lock().unwrap());
+        assert!(matches!(
+            Pin::new(&mut events).poll_next(&mut cx),
+            Poll::Ready(Some(Event::KeyExpired {
+                peer: AppPeerPtr(3)
+            }))
+        ));
+
+        for _ in 0..QUEUE_LEN + 1 {
+            subs.send(Event::PeerUnreachable {
+                peer: AppPeerPtr(0),
+            });
+        }
+        drop(subs);
+        let mut left = 0;
+        while let Poll::Ready(Some(_)) = Pin::new(&mut events).poll_next(&mut cx) {
+            left += 1;
+        }
+        assert_eq!(left, QUEUE_LEN);
+        assert!(matches!(
+            Pin::new(&mut events).poll_next(&mut cx),
+            Poll::Ready(None)
+        ));
+    }
+}
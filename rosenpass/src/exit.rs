@@ -0,0 +1,194 @@
+//! Exit codes and machine readable errors
+//!
+//! Wrappers and init scripts can tell why rosenpass failed by its exit
+//! status, which stays the same across releases:
+//!
+//! | Status | Failure                                                           |
+//! |--------|-------------------------------------------------------------------|
+//! | 0      | none                                                              |
+//! | 1      | any other error                                                   |
+//! | 2      | `usage`: the command line is invalid                              |
+//! | 3      | `config`: the config can not be read or is invalid                |
+//! | 4      | `key`: a key can not be loaded or unwrapped, or does not match    |
+//! | 5      | `bind`: a socket or network interface can not be bound            |
+//! | 6      | `broker`: a key can not be handed over, to a key file or the like |
+//!
+//! With `--error-format json`, the error is printed on stderr as one line of
+//! JSON, instead of being logged:
+//!
+//! ```json
+//! {"error":"config","code":3,"message":"…","causes":["…"]}
+//! ```
+//!
+//! `error` is the name of the failure in the table above, or `other`;
+//! `causes` are the errors which led to `message`, outermost first.
+//!
+//! Errors are put in a category with [ResultExt::failure], deep down where
+//! they occur; what is put around them later does not change it.
+
+use anyhow::Error;
+use serde::Serialize;
+use std::{fmt, io::Write};
+
+/// Exit status of errors of no [Failure] category
+pub const OTHER: i32 = 1;
+
+/// Categories of errors with an exit status of their own
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    Usage,
+    Config,
+    Key,
+    Bind,
+    /// Handing a key over to the key file, a named pipe or WireGuard
+    Broker,
+}
+
+impl Failure {
+    pub fn code(self) -> i32 {
+        match self {
+            Failure::Usage => 2,
+            Failure::Config => 3,
+            Failure::Key => 4,
+            Failure::Bind => 5,
+            Failure::Broker => 6,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Failure::Usage => "usage",
+            Failure::Config => "config",
+            Failure::Key => "key",
+            Failure::Bind => "bind",
+            Failure::Broker => "broker",
+        }
+    }
+
+    /// Put `e` in this category
+    pub fn wrap(self, e: impl Into<Error>) -> Error {
+        Error::new(Categorized {
+            failure: self,
+            error: e.into(),
+        })
+    }
+
+    /// The innermost category `e` was put in
+    pub fn of(e: &Error) -> Option<Self> {
+        e.chain()
+            .filter_map(|e| e.downcast_ref::<Categorized>())
+            .map(|c| c.failure)
+            .last()
+    }
+}
+
+/// An error in a category, which otherwise looks just like the error
+#[derive(Debug)]
+struct Categorized {
+    failure: Failure,
+    error: Error,
+}
+
+impl fmt::Display for Categorized {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.error, f)
+    }
+}
+
+impl std::error::Error for Categorized {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.error.source()
+    }
+}
+
+pub trait ResultExt<T> {
+    /// Put the error in the category `failure`
+    fn failure(self, failure: Failure) -> Result<T, Error>;
+}
+
+impl<T, E: Into<Error>> ResultExt<T> for Result<T, E> {
+    fn failure(self, failure: Failure) -> Result<T, Error> {
+        self.map_err(|e| failure.wrap(e))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ErrorFormat {
+    /// Log the error
+    #[default]
+    Text,
+    /// Print the error on stderr as JSON
+    Json,
+}
+
+impl ErrorFormat {
+    /// The format asked for on a command line which could not be parsed
+    pub fn from_args(args: &[String]) -> Self {
+        let json = args.iter().any(|a| a == "--error-format=json")
+            || args
+                .windows(2)
+                .any(|w| w[0] == "--error-format" && w[1] == "json");
+        match json {
+            true => ErrorFormat::Json,
+            false => ErrorFormat::Text,
+        }
+    }
+}
+
+/// One error, as printed with `--error-format json`
+#[derive(Debug, Serialize)]
+pub struct ErrorReport {
+    pub error: &'static str,
+    pub code: i32,
+    pub message: String,
+    pub causes: Vec<String>,
+}
+
+impl ErrorReport {
+    pub fn new(e: &Error) -> Self {
+        let failure = Failure::of(e);
+        let mut messages = e.chain().map(|e| e.to_string());
+        Self {
+            error: failure.map_or("other", Failure::name),
+            code: failure.map_or(OTHER, Failure::code),
+            message: messages.next().unwrap_or_default(),
+            causes: messages.collect(),
+        }
+    }
+
+    /// Print the report as a line of JSON on stderr
+    pub fn emit(&self) {
+        let mut err = std::io::stderr().lock();
+        // there is nowhere left to report a failure to print the error to
+        let _ = serde_json::to_writer(&mut err, self);
+        let _ = err.write_all(b"\n");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use anyhow::{anyhow, Context};
+
+    #[test]
+    fn reports_the_innermost_failure() {
+        let e = Err::<(), _>(anyhow!("no such file"))
+            .failure(Failure::Key)
+            .context("could not load the secret key")
+            .failure(Failure::Config)
+            .unwrap_err();
+        assert_eq!(e.to_string(), "could not load the secret key");
+        let report = ErrorReport::new(&e);
+        assert_eq!(report.error, "key");
+        assert_eq!(report.code, 4);
+        assert_eq!(report.message, "could not load the secret key");
+        assert_eq!(report.causes, vec!["no such file"]);
+
+        let other = ErrorReport::new(&anyhow!("oops"));
+        assert_eq!((other.error, other.code), ("other", OTHER));
+
+        let args = ["rosenpass", "--error-format", "json"].map(String::from);
+        assert_eq!(ErrorFormat::from_args(&args), ErrorFormat::Json);
+        assert_eq!(ErrorFormat::from_args(&args[..2]), ErrorFormat::Text);
+    }
+}
@@ -0,0 +1,95 @@
+//! Further keys derived from a handshake under labels of their own
+//!
+//! Besides the key for WireGuard, every handshake can yield any number of
+//! keys for other protocols, much like a TLS exporter: each is derived from
+//! the chaining key of the session under a label chosen by the application,
+//! so the keys are independent of each other and of the WireGuard key. Two
+//! peers exporting under the same label get the same key.
+//!
+//! ```toml
+//! [[peers]]
+//! public_key = "peer.rosenpass-public/pqpk"
+//! key_out = "/run/rosenpass/peer.osk"
+//!
+//! [[peers.exports]]
+//! label = "example.com/sync v1"
+//! key_out = "/run/sync/peer.key"
+//! ```
+//!
+//! Exported keys are written whenever the WireGuard key is, and replaced by
+//! random ones along with it once it goes stale. Embedders call
+//! [CryptoServer::export](crate::protocol::CryptoServer::export) instead.
+//! Labels should name the protocol and its version, so they are not reused
+//! by accident.
+
+use anyhow::{ensure, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+use crate::keystream;
+
+/// Labels are at most this many bytes long
+pub const MAX_LABEL_LEN: usize = 255;
+
+/// A key to export for a peer
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Export {
+    pub label: String,
+    /// File the key is written to, base64 encoded like `key_out`
+    pub key_out: PathBuf,
+}
+
+impl Export {
+    pub fn validate(&self) -> Result<()> {
+        ensure!(!self.label.is_empty(), "export label is empty");
+        ensure!(
+            self.label.len() <= MAX_LABEL_LEN,
+            "export label {:?} is longer than {MAX_LABEL_LEN} bytes",
+            self.label
+        );
+        ensure!(
+            self.key_out != Path::new(keystream::STDOUT),
+            "exported keys can not be streamed to stdout"
+        );
+        Ok(())
+    }
+}
+
+/// Make sure no two of `exports` share a label or a file
+pub fn validate(exports: &[Export]) -> Result<()> {
+    for (i, export) in exports.iter().enumerate() {
+        export.validate()?;
+        for other in exports[..i].iter() {
+            ensure!(
+                other.label != export.label,
+                "export label {:?} is used twice",
+                export.label
+            );
+            ensure!(
+                other.key_out != export.key_out,
+                "exports {:?} and {:?} are written to the same file",
+                other.label,
+                export.label
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn labels_and_files_are_unique() {
+        let export = |label: &str, key_out: &str| Export {
+            label: label.into(),
+            key_out: key_out.into(),
+        };
+        assert!(validate(&[export("a v1", "/a"), export("b v1", "/b")]).is_ok());
+        assert!(validate(&[export("a v1", "/a"), export("a v1", "/b")]).is_err());
+        assert!(validate(&[export("a v1", "/a"), export("b v1", "/a")]).is_err());
+        assert!(validate(&[export("", "/a")]).is_err());
+        assert!(validate(&[export(&"a".repeat(MAX_LABEL_LEN + 1), "/a")]).is_err());
+    }
+}
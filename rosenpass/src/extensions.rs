@@ -0,0 +1,234 @@
+//! Extending handshake messages without breaking deployed peers
+//!
+//! The messages in [crate::msgs] have a fixed size, and a peer rejects any
+//! message of another size, so nothing can be added to them as is. Two things
+//! make room for later additions such as padding, hybrid components or
+//! exporter labels:
+//!
+//! - Every message carries the [Features] of its sender in the `reserved`
+//!   bytes of the envelope, which older peers send as zeros and ignore on
+//!   receipt.
+//! - A handshake message answering one which advertised [EXTENSIONS] may be
+//!   followed by an extension area. An InitHello answers nothing and never
+//!   has one; its sender can not know if the responder still understands it.
+//!
+//! ```text
+//! [ message, fixed size ][ len: 2 ][ extension ... ][ mac: 16 ]
+//! extension = [ kind: 2 ][ len: 2 ][ data: len ]
+//! ```
+//!
+//! Lengths are big endian and `len` counts the bytes of all extensions. The
+//! `mac` covers the message and all of the area before it, keyed with the
+//! recipient's public key just like the `mac` of the envelope. Anyone who
+//! knows that key can compute either, so they only tell whom a message is
+//! for.
+//!
+//! What protects features and extensions is the handshake: once a message
+//! and the one it answers both advertise features, both sides mix the
+//! [transcript] of the former into the chaining key. For a RespHello, that
+//! includes the features of the InitHello, and goes into the biscuit; an
+//! InitConf has it mixed in before its `auth`, and an EmptyData has it as
+//! the additional data of its `auth`. A feature changed on the way, as well
+//! as an extension area changed, stripped or added, makes the handshake
+//! fail. Peers predating features send none and mix in nothing, so they
+//! still understand us. Only clearing the features of every message of the
+//! handshake, in both directions, goes unnoticed, and leaves a handshake
+//! like that with such a peer, without any of the optional parts. The
+//! features in the envelopes of other messages are not authenticated, and
+//! ignored. An extension whose data must stay secret has to bring its own
+//! protection.
+//!
+//! Peers look at the extensions they know and ignore all others, so kinds
+//! can be added at any time; a kind must never change its meaning, though.
+//! No kinds are defined yet.
+//!
+//! Keying the `mac` of a message with the recipient's public key lets anyone
+//! who knows the public keys of the peers tell who a reply from a responder
+//! is for, which gives away who started the handshake. An initiator
+//! advertising [IDENTITY_HIDING] has the responder key the `mac` of all of
+//! its messages to the initiator with the responder's own public key
+//! instead, so only the responder learns who is connecting; the initiator
+//! finds the responder by the session id before checking the `mac`.
+//! Fragments carry no session id, so their `mac` is checked with the keys of
+//! all peers the initiator has a handshake or session with.
+
+use anyhow::{ensure, Context, Result};
+use rosenpass_constant_time as constant_time;
+
+use crate::{labeled_prf as lprf, sodium::MAC_SIZE};
+
+/// Bit mask of the optional parts of the protocol a peer understands,
+/// carried in the three `reserved` bytes of the envelope
+pub type Features = u32;
+
+/// The peer accepts handshake messages followed by an extension area
+pub const EXTENSIONS: Features = 1 << 0;
+
+/// The peer asks for messages to it as initiator to be sealed with the
+/// responder's public key rather than with its own
+pub const IDENTITY_HIDING: Features = 1 << 1;
+
+/// Everything this implementation understands
+pub const SUPPORTED: Features = EXTENSIONS | IDENTITY_HIDING;
+
+/// Bytes of an extension area without any extensions
+pub const AREA_OVERHEAD: usize = 2 + MAC_SIZE;
+
+/// Bytes in front of the data of each extension
+pub const EXTENSION_HEADER_LEN: usize = 4;
+
+/// One extension in the extension area
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Extension {
+    pub kind: u16,
+    pub data: Vec<u8>,
+}
+
+/// The features in the `reserved` bytes of an envelope
+pub fn features(reserved: &[u8]) -> Features {
+    reserved
+        .iter()
+        .take(3)
+        .fold(0, |acc, b| (acc << 8) | *b as Features)
+}
+
+/// Fill the `reserved` bytes of an envelope with `features`
+pub fn store_features(features: Features, reserved: &mut [u8]) {
+    for (i, b) in reserved.iter_mut().rev().enumerate() {
+        *b = (features >> (8 * i)) as u8;
+    }
+}
+
+/// The bytes the handshake mixes in for a message with `features` and the
+/// extension area `area`, see [crate::extensions]
+pub fn transcript(features: Features, area: Option<&[Extension]>) -> Vec<u8> {
+    let mut out = vec![0u8; 3];
+    store_features(features, &mut out);
+    if let Some(extensions) = area {
+        out.extend(encode(extensions));
+    }
+    out
+}
+
+/// The extension area for `extensions`, without its `mac`
+pub fn encode(extensions: &[Extension]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for ext in extensions {
+        out.extend(ext.kind.to_be_bytes());
+        out.extend((ext.data.len() as u16).to_be_bytes());
+        out.extend(&ext.data);
+    }
+    let len = (out.len() as u16).to_be_bytes();
+    out.splice(0..0, len);
+    out
+}
+
+fn area_mac(spk: &[u8], data: &[u8]) -> Result<[u8; MAC_SIZE]> {
+    let mac = lprf::extension_mac()?.mix(spk)?.mix(data)?.into_value();
+    Ok(mac[..MAC_SIZE].try_into().unwrap())
+}
+
+/// Append the extension area for `extensions` to the message of `msg_len`
+/// bytes at the start of `buf`, for the recipient with the public key `spkt`;
+/// returns the length of message and area
+pub fn seal(
+    buf: &mut [u8],
+    msg_len: usize,
+    extensions: &[Extension],
+    spkt: &[u8],
+) -> Result<usize> {
+    let area = encode(extensions);
+    let len = area.len() - 2;
+    let total = msg_len + area.len() + MAC_SIZE;
+    ensure!(
+        total <= buf.len() && len <= u16::MAX as usize,
+        "the extensions do not fit into the message"
+    );
+    let off = msg_len + area.len();
+    buf[msg_len..off].copy_from_slice(&area);
+    let mac = area_mac(spkt, &buf[..off])?;
+    buf[off..total].copy_from_slice(&mac);
+    Ok(total)
+}
+
+/// Check and take apart the extension area following the first `msg_len`
+/// bytes of `msg`, which was sent to the holder of the public key `spkm`
+pub fn open(msg: &[u8], msg_len: usize, spkm: &[u8]) -> Result<Vec<Extension>> {
+    ensure!(
+        msg.len() >= msg_len + AREA_OVERHEAD,
+        "truncated extension area"
+    );
+    let (covered, mac) = msg.split_at(msg.len() - MAC_SIZE);
+    ensure!(
+        constant_time::tag_eq(mac, &area_mac(spkm, covered)?),
+        "extension area seal broken"
+    );
+    decode(&covered[msg_len..])
+}
+
+/// Take apart the extension area without its `mac`
+pub fn decode(area: &[u8]) -> Result<Vec<Extension>> {
+    let (len, mut rest) = split(area, 2).context("truncated extension area")?;
+    ensure!(
+        u16::from_be_bytes([len[0], len[1]]) as usize == rest.len(),
+        "the extension area has the wrong length"
+    );
+    let mut extensions = Vec::new();
+    while !rest.is_empty() {
+        let (header, tail) = split(rest, EXTENSION_HEADER_LEN).context("truncated extension")?;
+        let len = u16::from_be_bytes([header[2], header[3]]) as usize;
+        let (data, tail) = split(tail, len).context("truncated extension")?;
+        extensions.push(Extension {
+            kind: u16::from_be_bytes([header[0], header[1]]),
+            data: data.to_vec(),
+        });
+        rest = tail;
+    }
+    Ok(extensions)
+}
+
+fn split(buf: &[u8], at: usize) -> Option<(&[u8], &[u8])> {
+    (at <= buf.len()).then(|| buf.split_at(at))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn seals_and_opens_extensions() {
+        let spk = [7u8; 32];
+        let extensions = vec![
+            Extension {
+                kind: 1,
+                data: b"padding".to_vec(),
+            },
+            Extension {
+                kind: 0xbeef,
+                data: vec![],
+            },
+        ];
+        let mut buf = [0u8; 128];
+        buf[..10].copy_from_slice(b"fixed part");
+        let len = seal(&mut buf, 10, &extensions, &spk).unwrap();
+        assert_eq!(len, 10 + AREA_OVERHEAD + 2 * EXTENSION_HEADER_LEN + 7);
+        assert_eq!(open(&buf[..len], 10, &spk).unwrap(), extensions);
+
+        assert!(open(&buf[..len], 10, &[8u8; 32]).is_err());
+        buf[0] ^= 1;
+        assert!(open(&buf[..len], 10, &spk).is_err());
+
+        assert!(decode(&[0, 3, 0, 1, 0]).is_err());
+        assert!(decode(&[0, 4, 0, 1, 0, 1]).is_err());
+        assert_eq!(decode(&[0, 0]).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn features_fit_the_reserved_bytes() {
+        let mut reserved = [0u8; 3];
+        store_features(SUPPORTED | 1 << 23, &mut reserved);
+        assert_eq!(reserved, [0x80, 0, 3]);
+        assert_eq!(features(&reserved), SUPPORTED | 1 << 23);
+        assert_eq!(features(&[0, 0, 0]), 0);
+    }
+}
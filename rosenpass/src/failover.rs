@@ -0,0 +1,145 @@
+//! Falling back to other endpoints of a peer
+//!
+//! A peer reachable under several addresses, like a responder with
+//! redundant uplinks, lists the others as `fallback_endpoints`, tried after
+//! its `endpoint`:
+//!
+//! ```toml
+//! [[peers]]
+//! public_key = "peer.rosenpass-public/pqpk"
+//! endpoint = "primary.example.com:9999"
+//! fallback_endpoints = ["backup.example.com:9999", "[2001:db8::1]:9999"]
+//! happy_eyeballs = true
+//! ```
+//!
+//! Handshakes try the addresses of all the endpoints in turn, moving on to
+//! the next one with every retransmission, and stick to the one the peer
+//! answers on. Once a handshake on it goes unanswered, the other addresses
+//! are tried again, starting from the one which worked last.
+//!
+//! Host names resolving to both IPv6 and IPv4 addresses try those of one
+//! family before those of the other, so if either is broken it takes many
+//! retransmissions to get to the other. With `happy_eyeballs`, the families
+//! take turns instead, IPv6 first, like RFC 8305 has it for TCP.
+
+use anyhow::{ensure, Context, Result};
+use std::{
+    collections::HashSet,
+    net::{SocketAddr, ToSocketAddrs},
+};
+
+use crate::{config::QUIC_PREFIX, dns, unix};
+
+/// Make sure `fallbacks` can be used with the `endpoint` of a peer
+pub fn validate(endpoint: Option<&str>, fallbacks: &[String]) -> Result<()> {
+    ensure!(
+        endpoint.is_some() || fallbacks.is_empty(),
+        "fallback_endpoints need an endpoint to fall back from"
+    );
+    let prefixed = |e: &str| {
+        e.starts_with(dns::PREFIX) || e.starts_with(unix::PREFIX) || e.starts_with(QUIC_PREFIX)
+    };
+    ensure!(
+        fallbacks.is_empty() || !endpoint.is_some_and(prefixed),
+        "fallback_endpoints can only be used with host names and addresses"
+    );
+    for e in fallbacks {
+        ensure!(
+            !prefixed(e),
+            "fallback endpoint {e} is not a host name or an address"
+        );
+        ensure!(
+            e.to_socket_addrs().is_ok(),
+            "fallback endpoint {e} can not be parsed to a socket address"
+        );
+    }
+    Ok(())
+}
+
+/// The addresses of `fallbacks`, in order
+pub fn resolve(fallbacks: &[String]) -> Result<Vec<SocketAddr>> {
+    let mut addrs = Vec::new();
+    for e in fallbacks {
+        addrs.extend(
+            e.to_socket_addrs()
+                .with_context(|| format!("could not resolve fallback endpoint {e}"))?,
+        );
+    }
+    Ok(addrs)
+}
+
+/// `addrs` without duplicates, in the order they are tried in
+pub fn order(addrs: Vec<SocketAddr>, happy_eyeballs: bool) -> Vec<SocketAddr> {
+    let mut seen = HashSet::new();
+    let addrs: Vec<SocketAddr> = addrs.into_iter().filter(|a| seen.insert(*a)).collect();
+    if !happy_eyeballs {
+        return addrs;
+    }
+    let (mut v6, mut v4) = (Vec::new(), Vec::new());
+    for a in addrs {
+        match a.is_ipv6() {
+            true => v6.push(a),
+            false => v4.push(a),
+        }
+    }
+    let (mut v6, mut v4) = (v6.into_iter(), v4.into_iter());
+    let mut ordered = Vec::new();
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => return ordered,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn families_take_turns() {
+        let addrs: Vec<SocketAddr> = [
+            "192.0.2.1:1",
+            "192.0.2.2:1",
+            "192.0.2.1:1",
+            "192.0.2.3:1",
+            "[2001:db8::1]:1",
+        ]
+        .iter()
+        .map(|a| a.parse().unwrap())
+        .collect();
+        let ordered = |happy| {
+            order(addrs.clone(), happy)
+                .iter()
+                .map(|a| a.to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            ordered(false),
+            [
+                "192.0.2.1:1",
+                "192.0.2.2:1",
+                "192.0.2.3:1",
+                "[2001:db8::1]:1"
+            ]
+        );
+        assert_eq!(
+            ordered(true),
+            [
+                "[2001:db8::1]:1",
+                "192.0.2.1:1",
+                "192.0.2.2:1",
+                "192.0.2.3:1"
+            ]
+        );
+    }
+
+    #[test]
+    fn fallbacks_need_an_endpoint() {
+        let fallbacks = vec!["192.0.2.2:9999".to_string()];
+        assert!(validate(Some("192.0.2.1:9999"), &fallbacks).is_ok());
+        assert!(validate(None, &fallbacks).is_err());
+        assert!(validate(Some("dns-srv:_rosenpass._udp.example.com"), &fallbacks).is_err());
+        assert!(validate(Some("192.0.2.1:9999"), &["unix:/run/rp".into()]).is_err());
+    }
+}
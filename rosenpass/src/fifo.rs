@@ -0,0 +1,99 @@
+//! Pushing keys into named pipes
+//!
+//! If the `key_out` of a peer is a named pipe (see `mkfifo(1)`) when
+//! rosenpass starts, its keys are written into the pipe as they come, one
+//! base64 encoded key per line, so a consumer gets them by reading instead of
+//! watching a file. Random keys replacing stale ones are written just the
+//! same.
+//!
+//! Each pipe is served by a thread of its own, which opens it once there is
+//! a key and keeps it open, so the event loop and the other peers are never
+//! held up by a missing or slow reader. Until a reader shows up, only the
+//! newest key waits for it. When the reader goes away, the pipe is opened
+//! again for the next one, which gets the key the previous one missed.
+
+use log::{debug, warn};
+use rosenpass_util::b64::fmt_b64;
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    os::unix::fs::FileTypeExt,
+    path::{Path, PathBuf},
+    sync::{Arc, Condvar, Mutex},
+    thread,
+    time::Duration,
+};
+
+use crate::protocol::SymKey;
+
+/// How long to wait before opening a pipe again which could not be opened
+const REOPEN_DELAY: Duration = Duration::from_secs(10);
+
+/// Whether `path` is a named pipe
+pub fn is_fifo(path: &Path) -> bool {
+    std::fs::metadata(path).is_ok_and(|m| m.file_type().is_fifo())
+}
+
+#[derive(Debug, Default)]
+struct Pending {
+    key: Mutex<Option<SymKey>>,
+    arrived: Condvar,
+}
+
+/// The writing end of a named pipe keys are pushed into
+#[derive(Debug, Clone)]
+pub struct FifoOut {
+    pending: Arc<Pending>,
+}
+
+impl FifoOut {
+    /// Start the thread writing keys to the pipe in `path`
+    pub fn start(path: PathBuf) -> Self {
+        let pending = Arc::new(Pending::default());
+        let shared = pending.clone();
+        thread::spawn(move || write_keys(&path, &shared));
+        Self { pending }
+    }
+
+    /// Hand `key` to the reader, in place of a key still waiting for one
+    pub fn send(&self, key: &SymKey) {
+        // like the key file, this copy of the key is not erased; see
+        // AppServer::output_key
+        *self.pending.key.lock().unwrap() = Some(key.clone());
+        self.pending.arrived.notify_one();
+    }
+}
+
+fn write_keys(path: &Path, pending: &Pending) {
+    let mut pipe: Option<File> = None;
+    loop {
+        {
+            let mut key = pending.key.lock().unwrap();
+            while key.is_none() {
+                key = pending.arrived.wait(key).unwrap();
+            }
+        }
+        let file = match pipe.as_mut() {
+            Some(file) => file,
+            // blocks until there is a reader
+            None => match OpenOptions::new().write(true).open(path) {
+                Ok(file) => pipe.insert(file),
+                Err(e) => {
+                    warn!("could not open key output pipe {path:?}: {e}");
+                    thread::sleep(REOPEN_DELAY);
+                    continue;
+                }
+            },
+        };
+        // the newest key, which may have arrived while waiting for a reader
+        let Some(key) = pending.key.lock().unwrap().take() else {
+            continue;
+        };
+        let line = format!("{}\n", fmt_b64(key.secret()));
+        if let Err(e) = file.write_all(line.as_bytes()) {
+            debug!("the reader of key output pipe {path:?} went away: {e}");
+            pipe = None;
+            pending.key.lock().unwrap().get_or_insert(key);
+        }
+    }
+}
@@ -0,0 +1,180 @@
+//! Short, human comparable fingerprints of static public keys
+//!
+//! Public keys are far too large to be compared by reading them out, so
+//! operators compare fingerprints instead. A fingerprint is the first
+//! [FINGERPRINT_LEN] bytes of the peer id, which is already a hash of the
+//! public key; this way fingerprints and peer ids found in logs always agree.
+//!
+//! Fingerprints still say little about which site a peer is, so peers can be
+//! given a `name` of their own, which logs, `status`, `stats`, the metrics
+//! and the events use instead of or along with the fingerprint:
+//!
+//! ```toml
+//! [[peers]]
+//! public_key = "office-berlin.rosenpass-public/pqpk"
+//! name = "office-berlin"
+//! ```
+//!
+//! Names are unique, at most [MAX_NAME_LEN] characters of letters, digits,
+//! `.`, `_` and `-`, so they can go into log lines and metrics labels as
+//! they are; `rekey` accepts them as well.
+
+use anyhow::{ensure, Context, Result};
+use std::{fmt, str::FromStr};
+
+use crate::{
+    coloring::Public,
+    labeled_prf as lprf,
+    protocol::{PeerId, SPk},
+};
+
+/// Length of a fingerprint in bytes
+pub const FINGERPRINT_LEN: usize = 16;
+
+/// Longest name of a peer
+pub const MAX_NAME_LEN: usize = 64;
+
+/// Make sure `name` can name a peer, and is not taken for anything else
+pub fn validate_name(name: &str) -> Result<()> {
+    ensure!(
+        !name.is_empty() && name.len() <= MAX_NAME_LEN,
+        "peer name {name:?} must be between 1 and {MAX_NAME_LEN} characters"
+    );
+    ensure!(
+        name.chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-')),
+        "peer name {name:?} may only consist of letters, digits, '.', '_' and '-'"
+    );
+    ensure!(
+        name != "all" && name.parse::<Fingerprint>().is_err(),
+        "peer name {name:?} would be taken for a fingerprint or all peers"
+    );
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Fingerprint(pub [u8; FINGERPRINT_LEN]);
+
+impl Fingerprint {
+    pub fn from_peer_id(id: &PeerId) -> Self {
+        let mut fp = [0u8; FINGERPRINT_LEN];
+        fp.copy_from_slice(&id[..FINGERPRINT_LEN]);
+        Self(fp)
+    }
+
+    #[rustfmt::skip]
+    pub fn of_public_key(spk: &SPk) -> Result<Self> {
+        let id: PeerId = Public::new(
+            lprf::peerid()?
+                .mix(spk.secret())?
+                .into_value());
+        Ok(Self::from_peer_id(&id))
+    }
+
+    /// Draw the fingerprint as OpenSSH style randomart ("drunken bishop")
+    pub fn randomart(&self) -> String {
+        const W: usize = 17;
+        const H: usize = 9;
+        const SYMBOLS: &[u8] = b" .o+=*BOX@%&#/^";
+
+        let mut field = [[0usize; W]; H];
+        let (mut x, mut y) = (W / 2, H / 2);
+        for byte in self.0 {
+            for step in 0..4 {
+                let bits = byte >> (2 * step);
+                x = if bits & 1 == 1 {
+                    (x + 1).min(W - 1)
+                } else {
+                    x.saturating_sub(1)
+                };
+                y = if bits & 2 == 2 {
+                    (y + 1).min(H - 1)
+                } else {
+                    y.saturating_sub(1)
+                };
+                field[y][x] += 1;
+            }
+        }
+
+        let mut art = String::from("+---[ROSENPASS]---+\n");
+        for (row_no, row) in field.iter().enumerate() {
+            art.push('|');
+            for (col_no, &visits) in row.iter().enumerate() {
+                art.push(match (col_no, row_no) {
+                    (c, r) if (c, r) == (W / 2, H / 2) => 'S',
+                    (c, r) if (c, r) == (x, y) => 'E',
+                    _ => SYMBOLS[visits.min(SYMBOLS.len() - 1)] as char,
+                });
+            }
+            art.push_str("|\n");
+        }
+        art.push_str("+-----------------+");
+        art
+    }
+}
+
+/// Lower case hex in groups of four digits, e.g. `3fa1:09c2:…`
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, pair) in self.0.chunks(2).enumerate() {
+            if i > 0 {
+                f.write_str(":")?;
+            }
+            for b in pair {
+                write!(f, "{b:02x}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The [Display](fmt::Display) form, with the colons being optional
+impl FromStr for Fingerprint {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let hex: String = s.chars().filter(|&c| c != ':').collect();
+        ensure!(
+            hex.len() == 2 * FINGERPRINT_LEN && hex.is_ascii(),
+            "{s:?} is not a fingerprint"
+        );
+        let mut fp = [0u8; FINGERPRINT_LEN];
+        for (i, b) in fp.iter_mut().enumerate() {
+            *b = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16)
+                .with_context(|| format!("{s:?} is not a fingerprint"))?;
+        }
+        Ok(Self(fp))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn display_and_randomart() {
+        let fp = Fingerprint(*b"\x00\x01\x02\x03\x04\x05\x06\x07\x08\x09\x0a\x0b\x0c\x0d\x0e\xff");
+        assert_eq!(fp.to_string(), "0001:0203:0405:0607:0809:0a0b:0c0d:0eff");
+        assert_eq!(fp.to_string().parse::<Fingerprint>().unwrap(), fp);
+        assert!("0001:0203".parse::<Fingerprint>().is_err());
+
+        let art = fp.randomart();
+        let lines: Vec<&str> = art.lines().collect();
+        assert_eq!(lines.len(), 11);
+        assert!(lines.iter().all(|l| l.chars().count() == 19));
+        let field = lines[1..10].concat();
+        assert_eq!(field.matches('S').count(), 1);
+    }
+
+    #[test]
+    fn names() {
+        assert!(validate_name("office-berlin").is_ok());
+        assert!(validate_name("rack_2.fra").is_ok());
+        assert!(validate_name("").is_err());
+        assert!(validate_name("office berlin").is_err());
+        assert!(validate_name("all").is_err());
+        assert!(validate_name("00010203040506070809").is_ok());
+        assert!(validate_name("000102030405060708090a0b0c0d0eff").is_err());
+        assert!(validate_name(&"a".repeat(MAX_NAME_LEN + 1)).is_err());
+    }
+}
@@ -0,0 +1,322 @@
+//! Splitting handshake messages into fragments fitting small MTUs
+//!
+//! InitHello and RespHello carry post-quantum keys and ciphertexts and are
+//! well over a kilobyte long. Some paths drop datagrams of that size, or drop
+//! the IP fragments they are split into. With fragmentation enabled, such
+//! messages are sent as a series of [Fragment] messages instead, each with a
+//! [FRAGMENT_DATA_LEN] bytes slice of the original message.
+//!
+//! Fragments are sealed for the receiver like any other message. The seal
+//! tells only whom a fragment is for and keeps fragments meant for others
+//! out of the buffers. It is no authentication, since anyone knowing the
+//! receiver's public key can seal a fragment. The reassembled message is
+//! authenticated by the handshake as usual.
+//!
+//! Reassembly is bounded. At most [MAX_REASSEMBLIES_PER_SOURCE] messages
+//! from one source are pending, and a source's oldest message makes room for
+//! its next. A source is an IPv4 address, or the /64 prefix of an IPv6
+//! address, since a single host commonly controls a whole /64. At most
+//! [MAX_REASSEMBLIES] messages are pending overall, and once that many are
+//! pending, the oldest of them makes room. Fragments of a message older than
+//! [REASSEMBLY_TIMEOUT] are discarded.
+
+use anyhow::{ensure, Result};
+
+use crate::{
+    msgs::{
+        EnvelopeExt, Fragment, FragmentExt, MsgType, FRAGMENT_DATA_LEN, MAX_MESSAGE_LEN,
+        RELAY_DATA_LEN,
+    },
+    protocol::{CryptoServer, PeerPtr, Timing},
+};
+use std::net::{IpAddr, SocketAddr};
+
+/// Most fragments a message may be split into
+pub const MAX_FRAGMENTS: usize = MAX_MESSAGE_LEN.div_ceil(FRAGMENT_DATA_LEN);
+
+/// Most messages being reassembled at the same time
+pub const MAX_REASSEMBLIES: usize = 64;
+
+/// Most messages from one [source] being reassembled at the same time;
+/// enough for an InitHello and a RespHello crossing each other
+pub const MAX_REASSEMBLIES_PER_SOURCE: usize = 2;
+
+/// Time in seconds all fragments of a message must arrive within
+pub const REASSEMBLY_TIMEOUT: Timing = 5.0;
+
+/// Length of a sealed fragment on the wire
+pub const FRAGMENT_MSG_LEN: usize = RELAY_DATA_LEN;
+
+/// Split `msg` into sealed fragments addressed to `peer`
+pub fn split(srv: &CryptoServer, peer: PeerPtr, msg: &[u8]) -> Result<Vec<Vec<u8>>> {
+    ensure!(
+        msg.len() <= MAX_FRAGMENTS * FRAGMENT_DATA_LEN,
+        "message too long to be fragmented"
+    );
+    let mut msg_id = [0u8; 4];
+    rosenpass_sodium::helpers::randombytes_buf(&mut msg_id);
+
+    let count = msg.len().div_ceil(FRAGMENT_DATA_LEN);
+    let mut fragments = Vec::with_capacity(count);
+    for (index, chunk) in msg.chunks(FRAGMENT_DATA_LEN).enumerate() {
+        let mut buf = vec![0u8; FRAGMENT_MSG_LEN];
+        let mut env = buf.as_mut_slice().envelope::<Fragment<&mut [u8]>>()?;
+        env.msg_type_mut()[0] = MsgType::Fragment as u8;
+        {
+            let mut frag = env.payload_mut().fragment()?;
+            frag.msg_id_mut().copy_from_slice(&msg_id);
+            frag.index_mut()[0] = index as u8;
+            frag.count_mut()[0] = count as u8;
+            frag.len_mut()
+                .copy_from_slice(&(chunk.len() as u16).to_be_bytes());
+            frag.data_mut()[..chunk.len()].copy_from_slice(chunk);
+        }
+        // fragments are sealed like the message they carry
+        match MsgType::try_from(msg[0]) {
+            Ok(msg_type) => env.seal_for(srv.seal_key(peer, msg_type))?,
+            Err(_) => env.seal(peer, srv)?,
+        }
+        fragments.push(buf);
+    }
+    Ok(fragments)
+}
+
+/// The source reassembly limits apply to: an IPv4 address, or the /64
+/// prefix of an IPv6 address
+pub fn source(addr: &SocketAddr) -> IpAddr {
+    match addr.ip().to_canonical() {
+        IpAddr::V6(ip) => IpAddr::V6((u128::from(ip) & !(u64::MAX as u128)).into()),
+        ip => ip,
+    }
+}
+
+#[derive(Debug)]
+struct Partial {
+    from: SocketAddr,
+    msg_id: [u8; 4],
+    started: Timing,
+    parts: Vec<Option<Vec<u8>>>,
+}
+
+/// Messages whose fragments are still arriving
+#[derive(Debug, Default)]
+pub struct Reassembler {
+    partial: Vec<Partial>,
+}
+
+impl Reassembler {
+    /// Process the fragment `buf` received from `from`; returns the message
+    /// once all of its fragments arrived
+    pub fn add(
+        &mut self,
+        srv: &CryptoServer,
+        from: SocketAddr,
+        buf: &[u8],
+        now: Timing,
+    ) -> Result<Option<Vec<u8>>> {
+        let env = buf.envelope::<Fragment<&[u8]>>()?;
+        ensure!(env.check_seal(srv)?, "Message seal broken!");
+        let frag = env.payload().fragment()?;
+        let msg_id: [u8; 4] = frag.msg_id().try_into().unwrap();
+        let (index, count) = (frag.index()[0] as usize, frag.count()[0] as usize);
+        let len = u16::from_be_bytes(frag.len().try_into().unwrap()) as usize;
+        ensure!(
+            (1..=MAX_FRAGMENTS).contains(&count) && index < count,
+            "fragment {index} of {count} is out of range"
+        );
+        ensure!(
+            len <= FRAGMENT_DATA_LEN && (index + 1 == count || len == FRAGMENT_DATA_LEN),
+            "fragment {index} of {count} has an invalid length {len}"
+        );
+
+        self.partial
+            .retain(|p| now - p.started < REASSEMBLY_TIMEOUT);
+        let pos = match self
+            .partial
+            .iter()
+            .position(|p| p.from == from && p.msg_id == msg_id)
+        {
+            Some(pos) => pos,
+            None => {
+                let src = source(&from);
+                let from_source = self.partial.iter().filter(|p| source(&p.from) == src);
+                // the oldest message of the source, or the oldest of all,
+                // makes room
+                let oldest = if from_source.count() >= MAX_REASSEMBLIES_PER_SOURCE {
+                    self.partial.iter().position(|p| source(&p.from) == src)
+                } else if self.partial.len() >= MAX_REASSEMBLIES {
+                    // kept in the order they started
+                    Some(0)
+                } else {
+                    None
+                };
+                if let Some(oldest) = oldest {
+                    self.partial.remove(oldest);
+                }
+                self.partial.push(Partial {
+                    from,
+                    msg_id,
+                    started: now,
+                    parts: vec![None; count],
+                });
+                self.partial.len() - 1
+            }
+        };
+
+        let partial = &mut self.partial[pos];
+        ensure!(
+            partial.parts.len() == count,
+            "fragments of one message disagree on their count"
+        );
+        partial.parts[index] = Some(frag.data()[..len].to_vec());
+        if partial.parts.iter().any(Option::is_none) {
+            return Ok(None);
+        }
+
+        let msg: Vec<u8> = self
+            .partial
+            .remove(pos)
+            .parts
+            .into_iter()
+            .flatten()
+            .flatten()
+            .collect();
+        ensure!(
+            msg.len() <= MAX_MESSAGE_LEN,
+            "reassembled message is too long"
+        );
+        Ok(Some(msg))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pqkem::{StaticKEM, KEM};
+    use crate::protocol::{SPk, SSk};
+
+    fn server() -> (CryptoServer, SPk) {
+        let (mut sk, mut pk) = (SSk::zero(), SPk::zero());
+        StaticKEM::keygen(sk.secret_mut(), pk.secret_mut()).unwrap();
+        (CryptoServer::new(sk, pk.clone()), pk)
+    }
+
+    #[test]
+    fn split_and_reassemble() {
+        rosenpass_sodium::init().unwrap();
+        let (mut a, _) = server();
+        let (b, b_pk) = server();
+        let b_at_a = a.add_peer(None, b_pk).unwrap();
+        let from: SocketAddr = "192.0.2.1:9999".parse().unwrap();
+
+        let msg: Vec<u8> = (0..1100u32).map(|i| i as u8).collect();
+        let mut fragments = split(&a, b_at_a, &msg).unwrap();
+        assert_eq!(fragments.len(), 3);
+        assert!(fragments.iter().all(|f| f.len() == FRAGMENT_MSG_LEN));
+
+        // order does not matter, duplicates are harmless
+        let mut r = Reassembler::default();
+        fragments.swap(0, 2);
+        assert_eq!(r.add(&b, from, &fragments[0], 0.0).unwrap(), None);
+        assert_eq!(r.add(&b, from, &fragments[0], 0.0).unwrap(), None);
+        assert_eq!(r.add(&b, from, &fragments[1], 0.0).unwrap(), None);
+        assert_eq!(
+            r.add(&b, from, &fragments[2], 0.0).unwrap(),
+            Some(msg.clone())
+        );
+
+        // fragments time out
+        let fragments = split(&a, b_at_a, &msg).unwrap();
+        for f in fragments[..2].iter() {
+            assert_eq!(r.add(&b, from, f, 0.0).unwrap(), None);
+        }
+        assert_eq!(
+            r.add(&b, from, &fragments[2], REASSEMBLY_TIMEOUT).unwrap(),
+            None
+        );
+
+        // only fragments sealed for the receiver are accepted
+        let (c, _) = server();
+        assert!(r.add(&c, from, &fragments[0], 0.0).is_err());
+    }
+
+    #[test]
+    fn reassemblies_are_capped_per_source() {
+        rosenpass_sodium::init().unwrap();
+        let (mut a, _) = server();
+        let (b, b_pk) = server();
+        let b_at_a = a.add_peer(None, b_pk).unwrap();
+        let msg: Vec<u8> = (0..1100u32).map(|i| i as u8).collect();
+        let mut r = Reassembler::default();
+
+        // a message under way from a peer
+        let from: SocketAddr = "192.0.2.1:9999".parse().unwrap();
+        let fragments = split(&a, b_at_a, &msg).unwrap();
+        assert_eq!(r.add(&b, from, &fragments[0], 0.0).unwrap(), None);
+
+        // a flood of first fragments, from any port of another address
+        for port in 0..(2 * MAX_REASSEMBLIES as u16) {
+            let flood = split(&a, b_at_a, &msg).unwrap();
+            let attacker = SocketAddr::new(attacker_ip(), port);
+            assert_eq!(r.add(&b, attacker, &flood[0], 1.0).unwrap(), None);
+        }
+        assert_eq!(r.partial.len(), 1 + MAX_REASSEMBLIES_PER_SOURCE);
+
+        // does not keep the message of the peer from completing
+        assert_eq!(r.add(&b, from, &fragments[1], 2.0).unwrap(), None);
+        assert_eq!(
+            r.add(&b, from, &fragments[2], 2.0).unwrap(),
+            Some(msg.clone())
+        );
+
+        // once full, the oldest message makes room for a new one
+        for host in 0..MAX_REASSEMBLIES {
+            let flood = split(&a, b_at_a, &msg).unwrap();
+            let sender = SocketAddr::new([203, 0, 113, host as u8].into(), 9999);
+            assert_eq!(r.add(&b, sender, &flood[0], 3.0).unwrap(), None);
+        }
+        assert_eq!(r.partial.len(), MAX_REASSEMBLIES);
+        let fragments = split(&a, b_at_a, &msg).unwrap();
+        assert_eq!(r.add(&b, from, &fragments[0], 4.0).unwrap(), None);
+        assert_eq!(r.partial.len(), MAX_REASSEMBLIES);
+        assert!(r.partial.iter().all(|p| p.from.ip() != attacker_ip()));
+        assert_eq!(r.add(&b, from, &fragments[1], 4.0).unwrap(), None);
+        assert_eq!(
+            r.add(&b, from, &fragments[2], 4.0).unwrap(),
+            Some(msg.clone())
+        );
+    }
+
+    fn attacker_ip() -> IpAddr {
+        "198.51.100.7".parse().unwrap()
+    }
+
+    #[test]
+    fn ipv6_sources_are_counted_per_prefix() {
+        rosenpass_sodium::init().unwrap();
+        let (mut a, _) = server();
+        let (b, b_pk) = server();
+        let b_at_a = a.add_peer(None, b_pk).unwrap();
+        let msg: Vec<u8> = (0..1100u32).map(|i| i as u8).collect();
+        let mut r = Reassembler::default();
+
+        // addresses of one /64 share a limit
+        for host in 0..(2 * MAX_REASSEMBLIES as u16) {
+            let flood = split(&a, b_at_a, &msg).unwrap();
+            let ip: IpAddr = format!("2001:db8:0:1::{host:x}").parse().unwrap();
+            let sender = SocketAddr::new(ip, 9999);
+            assert_eq!(r.add(&b, sender, &flood[0], 0.0).unwrap(), None);
+        }
+        assert_eq!(r.partial.len(), MAX_REASSEMBLIES_PER_SOURCE);
+
+        // the next /64 is a source of its own
+        let flood = split(&a, b_at_a, &msg).unwrap();
+        let sender: SocketAddr = "[2001:db8:0:2::1]:9999".parse().unwrap();
+        assert_eq!(r.add(&b, sender, &flood[0], 0.0).unwrap(), None);
+        assert_eq!(r.partial.len(), MAX_REASSEMBLIES_PER_SOURCE + 1);
+
+        // IPv4 mapped addresses count as IPv4
+        let mapped: SocketAddr = "[::ffff:192.0.2.1]:9999".parse().unwrap();
+        assert_eq!(source(&mapped), "192.0.2.1".parse::<IpAddr>().unwrap());
+    }
+}
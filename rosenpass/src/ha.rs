@@ -0,0 +1,587 @@
+//! Active/standby high availability
+//!
+//! Two instances with the same keys and peers can share an endpoint, e.g. an
+//! address moved between two hosts by VRRP: one of them is active and
+//! exchanges keys, while the other one stands by. Every [SYNC_INTERVAL]
+//! seconds, the active instance sends the standby what it needs to carry on
+//! in its place: the biscuit keys, and the sessions and biscuit numbers of
+//! some of the peers, so every peer is covered at least every
+//! [FULL_SYNC_INTERVAL] seconds; a peer is also sent right after each of its
+//! key exchanges. The standby writes the keys of those sessions just like
+//! the active instance does, so a WireGuard instance on the standby host is
+//! ready as well.
+//!
+//! A standby neither sends nor answers handshake messages. Once it heard
+//! nothing from the active instance for [HighAvailability::takeover_after]
+//! seconds, it becomes active itself: the sessions it was sent stay valid,
+//! and so do the InitConf messages answering RespHellos the other instance
+//! sent, so peers carry on without a new handshake. Every instance starts
+//! out as standby, so an instance starting next to an active one, e.g. after
+//! a crash, does not disrupt it; should both become active anyway, the one
+//! which became active first stays active.
+//!
+//! The [SyncMsg]s are encrypted and authenticated with the key shared by the
+//! instances in [HighAvailability::key], a base64 encoded random 32 byte key
+//! like a pre-shared key. They carry the time on the wall clock they were
+//! sent at and are dropped if they were sent more than [MAX_CLOCK_SKEW]
+//! seconds ago, or are older than the last message of the same run of the
+//! sender; so the clocks of the instances need to be synchronized.
+
+use anyhow::{bail, ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+};
+
+use rosenpass_ciphers::xaead;
+use rosenpass_constant_time::{compare, select_into};
+use rosenpass_sodium::helpers::{memzero, randombytes_buf};
+use rosenpass_util::file::LoadValueB64;
+
+use crate::{
+    coloring::Secret,
+    lockdown,
+    msgs::{BISCUIT_ID_LEN, SESSION_ID_LEN},
+    prftree::SecretPrfTree,
+    protocol::{CryptoServer, HandshakeRole, PeerId, PeerPtr, Session, SessionId, SymKey, Timing},
+};
+
+/// Seconds between the messages of the active instance
+pub const SYNC_INTERVAL: Timing = 1.0;
+
+/// Seconds every peer is sent to the standby within
+pub const FULL_SYNC_INTERVAL: Timing = 10.0;
+
+/// Default of [HighAvailability::takeover_after]
+pub const DEFAULT_TAKEOVER_AFTER: Timing = 3.0 * SYNC_INTERVAL;
+
+/// Seconds a message may have been sent ago on the wall clock of the receiver
+pub const MAX_CLOCK_SKEW: Timing = 30.0;
+
+/// Peers sent in a single message, keeping it below common MTUs
+pub const PEERS_PER_MSG: usize = 6;
+
+/// Added to the transmission nonces of replicated sessions on taking over,
+/// skipping those the other instance used since its last message
+pub const NONCE_GAP: u64 = 1 << 32;
+
+const MAGIC: &[u8] = b"rosenpass high availability v1";
+const BOOT_ID_LEN: usize = 16;
+const HAS_SESSION: u8 = 1;
+const HAS_ENDPOINT: u8 = 2;
+
+/// Settings of high availability; disabled without them
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HighAvailability {
+    /// Address to receive the messages of the other instance on
+    pub listen: SocketAddr,
+
+    /// Where the other instance receives its messages
+    pub partner: SocketAddr,
+
+    /// File with the base64 encoded key shared by both instances
+    pub key: PathBuf,
+
+    /// Seconds without messages after which the standby takes over;
+    /// defaults to [DEFAULT_TAKEOVER_AFTER]
+    #[serde(default)]
+    pub takeover_after: Option<f64>,
+}
+
+/// State of a peer, as replicated to the standby
+#[derive(Debug)]
+pub struct PeerState {
+    pub id: [u8; 32],
+    pub biscuit_used: [u8; BISCUIT_ID_LEN],
+    pub session: Option<SessionState>,
+    pub endpoint: Option<SocketAddr>,
+}
+
+#[derive(Debug)]
+pub struct SessionState {
+    /// Seconds since the session was established
+    pub age: Timing,
+    pub sidm: [u8; SESSION_ID_LEN],
+    pub sidt: [u8; SESSION_ID_LEN],
+    pub initiator: bool,
+    pub ck: SymKey,
+    pub txkm: SymKey,
+    pub txkt: SymKey,
+    pub txnm: u64,
+    pub txnt: u64,
+}
+
+/// What the active instance sends the standby
+#[derive(Debug)]
+pub struct SyncMsg {
+    /// Seconds since the sender became active
+    pub active_for: Timing,
+    pub biscuit_ctr: [u8; BISCUIT_ID_LEN],
+    /// Age in seconds and value of each biscuit key
+    pub biscuit_keys: [(Timing, SymKey); 2],
+    pub peers: Vec<PeerState>,
+}
+
+/// The high availability state of an instance
+#[derive(Debug)]
+pub struct Ha {
+    pub socket: mio::net::UdpSocket,
+    pub partner: SocketAddr,
+    key: SymKey,
+    pub takeover_after: Timing,
+    /// When this instance became active, if it is
+    pub active_since: Option<Timing>,
+    /// When to send the next message, while active
+    pub sync_at: Timing,
+    /// The peer to start the next message with
+    pub next_peer: usize,
+    /// When to take over, while standing by
+    pub takeover_at: Timing,
+    /// Identifies this run of the instance
+    boot: [u8; BOOT_ID_LEN],
+    seq: u64,
+    /// Boot id and sequence number of the last message received
+    last: Option<([u8; BOOT_ID_LEN], u64)>,
+}
+
+impl HighAvailability {
+    pub fn validate(&self) -> Result<()> {
+        ensure!(
+            self.key.is_file(),
+            "high_availability key file {:?} does not exist",
+            self.key
+        );
+        if let Some(t) = self.takeover_after {
+            ensure!(
+                t > SYNC_INTERVAL,
+                "high_availability.takeover_after must be longer than {SYNC_INTERVAL} seconds"
+            );
+        }
+        Ok(())
+    }
+}
+
+impl Ha {
+    /// Start out as standby at `now`
+    pub fn new(cfg: &HighAvailability, now: Timing) -> Result<Self> {
+        let socket = mio::net::UdpSocket::bind(cfg.listen)
+            .with_context(|| format!("could not listen on {}", cfg.listen))?;
+        let key = SymKey::load_b64(&cfg.key)?;
+        let takeover_after = cfg.takeover_after.unwrap_or(DEFAULT_TAKEOVER_AFTER);
+        let mut boot = [0u8; BOOT_ID_LEN];
+        randombytes_buf(&mut boot);
+        Ok(Self {
+            socket,
+            partner: cfg.partner,
+            key,
+            takeover_after,
+            active_since: None,
+            sync_at: now,
+            next_peer: 0,
+            takeover_at: now + takeover_after,
+            boot,
+            seq: 0,
+            last: None,
+        })
+    }
+
+    pub fn active(&self) -> bool {
+        self.active_since.is_some()
+    }
+
+    /// Encrypt `msg`, sent at `wall` on the wall clock
+    pub fn seal(&mut self, msg: &SyncMsg, wall: f64) -> Result<Vec<u8>> {
+        self.seq += 1;
+        let mut head = self.boot.to_vec();
+        head.extend_from_slice(&self.seq.to_be_bytes());
+        let ad = [MAGIC, &head].concat();
+
+        let mut plain = wall.to_be_bytes().to_vec();
+        msg.encode(&mut plain);
+        let mut nonce = [0u8; xaead::NONCE_LEN];
+        randombytes_buf(&mut nonce);
+        let mut ct = vec![0u8; xaead::NONCE_LEN + plain.len() + xaead::TAG_LEN];
+        let res = xaead::encrypt(&mut ct, self.key.secret(), &nonce, &ad, &plain);
+        memzero(&mut plain);
+        res?;
+        head.extend_from_slice(&ct);
+        Ok(head)
+    }
+
+    /// Decrypt a message received at `wall` on the wall clock
+    pub fn open(&mut self, buf: &[u8], wall: f64) -> Result<SyncMsg> {
+        let head_len = BOOT_ID_LEN + 8;
+        ensure!(
+            buf.len() >= head_len + xaead::NONCE_LEN + 8 + xaead::TAG_LEN,
+            "high availability message is truncated"
+        );
+        let (head, ct) = buf.split_at(head_len);
+        let ad = [MAGIC, head].concat();
+        let mut plain = vec![0u8; ct.len() - xaead::NONCE_LEN - xaead::TAG_LEN];
+        xaead::decrypt(&mut plain, self.key.secret(), &ad, ct)
+            .context("could not decrypt high availability message")?;
+        let res = self.check_and_decode(head, &plain, wall);
+        memzero(&mut plain);
+        res
+    }
+
+    fn check_and_decode(&mut self, head: &[u8], plain: &[u8], wall: f64) -> Result<SyncMsg> {
+        let (boot, seq) = head.split_at(BOOT_ID_LEN);
+        let boot: [u8; BOOT_ID_LEN] = boot.try_into().unwrap();
+        let seq = u64::from_be_bytes(seq.try_into().unwrap());
+        ensure!(
+            boot != self.boot,
+            "high availability message from ourselves"
+        );
+        if let Some((last_boot, last_seq)) = self.last {
+            ensure!(
+                boot != last_boot || seq > last_seq,
+                "replayed high availability message"
+            );
+        }
+        let mut r = Reader(plain);
+        let sent = r.f64()?;
+        ensure!(
+            (wall - sent).abs() <= MAX_CLOCK_SKEW,
+            "high availability message sent {:.0} seconds off our clock",
+            wall - sent
+        );
+        let msg = SyncMsg::decode(&mut r)?;
+        self.last = Some((boot, seq));
+        Ok(msg)
+    }
+}
+
+impl SyncMsg {
+    /// The biscuit state of `srv` at `now`, without any peers
+    pub fn new(srv: &CryptoServer, now: Timing, active_for: Timing) -> Self {
+        let key = |i: usize| {
+            let k = &srv.biscuit_keys[i];
+            (now - k.created_at, k.key.clone())
+        };
+        Self {
+            active_for,
+            biscuit_ctr: srv.biscuit_ctr.value,
+            biscuit_keys: [key(0), key(1)],
+            peers: Vec::new(),
+        }
+    }
+
+    /// The message as handed over in an upgrade, see [crate::upgrade]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode(&mut out);
+        out
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self> {
+        Self::decode(&mut Reader(buf))
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.active_for.to_be_bytes());
+        out.extend_from_slice(&self.biscuit_ctr);
+        for (age, key) in self.biscuit_keys.iter() {
+            out.extend_from_slice(&age.to_be_bytes());
+            out.extend_from_slice(key.secret());
+        }
+        out.push(self.peers.len() as u8);
+        for p in self.peers.iter() {
+            out.extend_from_slice(&p.id);
+            out.extend_from_slice(&p.biscuit_used);
+            let flags = match (&p.session, &p.endpoint) {
+                (Some(_), Some(_)) => HAS_SESSION | HAS_ENDPOINT,
+                (Some(_), None) => HAS_SESSION,
+                (None, Some(_)) => HAS_ENDPOINT,
+                (None, None) => 0,
+            };
+            out.push(flags);
+            if let Some(s) = p.session.as_ref() {
+                out.extend_from_slice(&s.age.to_be_bytes());
+                out.extend_from_slice(&s.sidm);
+                out.extend_from_slice(&s.sidt);
+                out.push(s.initiator as u8);
+                for key in [&s.ck, &s.txkm, &s.txkt] {
+                    out.extend_from_slice(key.secret());
+                }
+                out.extend_from_slice(&s.txnm.to_be_bytes());
+                out.extend_from_slice(&s.txnt.to_be_bytes());
+            }
+            if let Some(addr) = p.endpoint {
+                let ip = match addr.ip() {
+                    IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                    IpAddr::V6(ip) => ip,
+                };
+                out.extend_from_slice(&ip.octets());
+                out.extend_from_slice(&addr.port().to_be_bytes());
+            }
+        }
+    }
+
+    fn decode(r: &mut Reader) -> Result<Self> {
+        let active_for = r.f64()?;
+        let biscuit_ctr = r.array()?;
+        let mut key = || -> Result<_> { Ok((r.f64()?, r.key()?)) };
+        let biscuit_keys = [key()?, key()?];
+        let mut peers = Vec::new();
+        for _ in 0..r.u8()? {
+            let id = r.array()?;
+            let biscuit_used = r.array()?;
+            let flags = r.u8()?;
+            let session = match flags & HAS_SESSION != 0 {
+                true => Some(SessionState {
+                    age: r.f64()?,
+                    sidm: r.array()?,
+                    sidt: r.array()?,
+                    initiator: r.u8()? != 0,
+                    ck: r.key()?,
+                    txkm: r.key()?,
+                    txkt: r.key()?,
+                    txnm: r.u64()?,
+                    txnt: r.u64()?,
+                }),
+                false => None,
+            };
+            let endpoint = match flags & HAS_ENDPOINT != 0 {
+                true => {
+                    let ip = Ipv6Addr::from(r.array::<16>()?);
+                    let port = u16::from_be_bytes(r.array()?);
+                    Some(SocketAddr::new(lockdown::canonical(ip.into()), port))
+                }
+                false => None,
+            };
+            peers.push(PeerState {
+                id,
+                biscuit_used,
+                session,
+                endpoint,
+            });
+        }
+        ensure!(r.0.is_empty(), "trailing data in high availability message");
+        Ok(Self {
+            active_for,
+            biscuit_ctr,
+            biscuit_keys,
+            peers,
+        })
+    }
+
+    /// Adopt the biscuit state of the message at `now`
+    pub fn apply_biscuits(&self, srv: &mut CryptoServer, now: Timing) {
+        let newer = compare(&self.biscuit_ctr, &*srv.biscuit_ctr) > 0;
+        select_into(&mut *srv.biscuit_ctr, &self.biscuit_ctr, newer);
+        for (ours, (age, key)) in srv.biscuit_keys.iter_mut().zip(self.biscuit_keys.iter()) {
+            ours.created_at = now - age;
+            ours.key = key.clone();
+        }
+    }
+}
+
+impl PeerState {
+    /// The state of `peer` at `now`
+    pub fn new(
+        srv: &CryptoServer,
+        peer: PeerPtr,
+        endpoint: Option<SocketAddr>,
+        now: Timing,
+    ) -> Result<Self> {
+        let p = peer.get(srv);
+        let session = p.session.as_ref().map(|s| SessionState {
+            age: now - s.created_at,
+            sidm: s.sidm.value,
+            sidt: s.sidt.value,
+            initiator: s.handshake_role.is_initiator(),
+            ck: s.ck.clone().danger_into_secret(),
+            txkm: s.txkm.clone(),
+            txkt: s.txkt.clone(),
+            txnm: s.txnm,
+            txnt: s.txnt,
+        });
+        Ok(Self {
+            id: p.pidt()?.value,
+            biscuit_used: p.biscuit_used.value,
+            session,
+            endpoint,
+        })
+    }
+
+    /// Adopt the state at `now`; returns the peer if it has a new session
+    pub fn apply(&self, srv: &mut CryptoServer, now: Timing) -> Result<Option<PeerPtr>> {
+        let Some(peer) = srv.find_peer(PeerId::new(self.id)) else {
+            bail!("high availability partner sent unknown peer");
+        };
+        let p = peer.get_mut(srv);
+        let newer = compare(&self.biscuit_used, &*p.biscuit_used) > 0;
+        select_into(&mut *p.biscuit_used, &self.biscuit_used, newer);
+        let Some(s) = self.session.as_ref() else {
+            return Ok(None);
+        };
+        if let Some(ours) = p.session.as_mut().filter(|o| o.sidm.value == s.sidm) {
+            ours.txnm = ours.txnm.max(s.txnm);
+            ours.txnt = ours.txnt.max(s.txnt);
+            return Ok(None);
+        }
+        let ses = Session {
+            created_at: now - s.age,
+            sidm: SessionId::new(s.sidm),
+            sidt: SessionId::new(s.sidt),
+            handshake_role: match s.initiator {
+                true => HandshakeRole::Initiator,
+                false => HandshakeRole::Responder,
+            },
+            ck: SecretPrfTree::danger_from_secret(s.ck.clone()).dup(),
+            txkm: s.txkm.clone(),
+            txkt: s.txkt.clone(),
+            txnm: s.txnm,
+            txnt: s.txnt,
+        };
+        peer.session().insert(srv, ses)?;
+        Ok(Some(peer))
+    }
+}
+
+/// Prepare the replicated state of `srv` for taking over: skip the nonces
+/// and biscuit numbers the other instance may have used since its last
+/// message, and let handshakes due be started right away
+pub fn take_over(srv: &mut CryptoServer) {
+    // adds 2^32 to the little endian counter
+    rosenpass_sodium::helpers::increment(&mut srv.biscuit_ctr.value[4..]);
+    for p in srv.peers.iter_mut() {
+        p.initiation_requested = false;
+        if let Some(s) = p.session.as_mut() {
+            s.txnm = s.txnm.saturating_add(NONCE_GAP);
+        }
+    }
+}
+
+/// Stop handshakes in progress on becoming standby
+pub fn stand_by(srv: &mut CryptoServer) {
+    for no in 0..srv.peers.len() {
+        PeerPtr(no).hs().take(srv);
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn take(&mut self, n: usize) -> Result<&[u8]> {
+        ensure!(self.0.len() >= n, "high availability message is truncated");
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_be_bytes(self.array()?))
+    }
+
+    fn f64(&mut self) -> Result<f64> {
+        Ok(f64::from_be_bytes(self.array()?))
+    }
+
+    fn key(&mut self) -> Result<SymKey> {
+        Ok(Secret::from_slice(self.take(32)?))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ha(key: &SymKey) -> Ha {
+        let any = "127.0.0.1:0".parse().unwrap();
+        Ha {
+            socket: mio::net::UdpSocket::bind(any).unwrap(),
+            partner: any,
+            key: key.clone(),
+            takeover_after: DEFAULT_TAKEOVER_AFTER,
+            active_since: None,
+            sync_at: 0.0,
+            next_peer: 0,
+            takeover_at: 0.0,
+            boot: rand_boot(),
+            seq: 0,
+            last: None,
+        }
+    }
+
+    fn rand_boot() -> [u8; BOOT_ID_LEN] {
+        let mut boot = [0u8; BOOT_ID_LEN];
+        randombytes_buf(&mut boot);
+        boot
+    }
+
+    #[test]
+    fn seal_and_open() {
+        let key = SymKey::random();
+        let (mut active, mut standby) = (ha(&key), ha(&key));
+        let msg = SyncMsg {
+            active_for: 12.5,
+            biscuit_ctr: [3; BISCUIT_ID_LEN],
+            biscuit_keys: [(1.0, SymKey::random()), (400.0, SymKey::random())],
+            peers: vec![
+                PeerState {
+                    id: [1; 32],
+                    biscuit_used: [2; BISCUIT_ID_LEN],
+                    session: Some(SessionState {
+                        age: 30.0,
+                        sidm: [4; SESSION_ID_LEN],
+                        sidt: [5; SESSION_ID_LEN],
+                        initiator: true,
+                        ck: SymKey::random(),
+                        txkm: SymKey::random(),
+                        txkt: SymKey::random(),
+                        txnm: 7,
+                        txnt: 8,
+                    }),
+                    endpoint: Some("192.0.2.1:9999".parse().unwrap()),
+                },
+                PeerState {
+                    id: [6; 32],
+                    biscuit_used: [0; BISCUIT_ID_LEN],
+                    session: None,
+                    endpoint: None,
+                },
+            ],
+        };
+
+        let wall = 1_700_000_000.0;
+        let buf = active.seal(&msg, wall).unwrap();
+        let got = standby.open(&buf, wall + 1.0).unwrap();
+        assert_eq!(got.active_for, msg.active_for);
+        assert_eq!(
+            got.biscuit_keys[1].1.secret(),
+            msg.biscuit_keys[1].1.secret()
+        );
+        assert_eq!(got.peers.len(), 2);
+        let (s, t) = (
+            got.peers[0].session.as_ref().unwrap(),
+            msg.peers[0].session.as_ref().unwrap(),
+        );
+        assert_eq!((s.sidm, s.txnm, s.txnt), (t.sidm, t.txnm, t.txnt));
+        assert_eq!(s.ck.secret(), t.ck.secret());
+        assert_eq!(got.peers[0].endpoint, msg.peers[0].endpoint);
+        assert!(got.peers[1].session.is_none() && got.peers[1].endpoint.is_none());
+
+        // replays, stale messages, tampering and other keys are rejected
+        assert!(standby.open(&buf, wall).is_err());
+        let old = active.seal(&msg, wall - 2.0 * MAX_CLOCK_SKEW).unwrap();
+        assert!(standby.open(&old, wall).is_err());
+        let mut forged = active.seal(&msg, wall).unwrap();
+        forged[BOOT_ID_LEN] ^= 1;
+        assert!(standby.open(&forged, wall).is_err());
+        let other = ha(&SymKey::random()).seal(&msg, wall).unwrap();
+        assert!(standby.open(&other, wall).is_err());
+        let next = active.seal(&msg, wall).unwrap();
+        assert!(standby.open(&next, wall).is_ok());
+    }
+}
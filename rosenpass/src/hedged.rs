@@ -0,0 +1,356 @@
+//! Hedging the randomness against a weak source
+//!
+//! Session ids, biscuit keys, nonces and the ephemeral keys of the
+//! handshakes are only as unpredictable as the randomness they are drawn
+//! from. Embedded devices often start rosenpass before the kernel gathered
+//! enough entropy, and on some the kernel never does, without anything
+//! telling. So all randomness, that of libsodium and of liboqs alike, is
+//! drawn from a generator of our own which mixes several sources:
+//!
+//! - the kernel, through `getrandom`, again every [RESEED_INTERVAL] and in
+//!   every process forked off
+//! - the jitter in the time a loop of memory accesses takes
+//! - a seed file kept across restarts, set with `random_seed`, which is
+//!   replaced by a fresh seed as soon as it is read
+//!
+//! ```toml
+//! random_seed = "/var/lib/rosenpass/random-seed"
+//! ```
+//!
+//! The sources are mixed with BLAKE2b, so the output is unpredictable as
+//! long as any one of them is. The generator erases its key with every
+//! output, so neither the seed file nor the state of the process tells the
+//! randomness drawn before. If the kernel has not initialized its entropy
+//! pool yet, a warning says so and the other sources carry on.
+
+use anyhow::{Context, Result};
+use libsodium_sys as libsodium;
+use log::warn;
+use std::{
+    ffi::c_void,
+    fs::{self, OpenOptions},
+    io::{ErrorKind, Write},
+    ops::{Deref, DerefMut},
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+    ptr::null_mut,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::{pqkem, sodium::KEY_SIZE};
+
+/// Seconds until the randomness of the kernel is mixed in again
+pub const RESEED_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Timings taken to gather jitter
+const JITTER_SAMPLES: usize = 1024;
+
+/// Bytes of the seed file
+pub const SEED_LEN: usize = 32;
+
+/// Memory of its own for the key of a [Drbg], which is locked, so it is
+/// never swapped, and left out of core dumps
+///
+/// This is not a [crate::coloring::Secret], as those are drawn from a pool
+/// which needs randomness for its canaries, and from libsodium, which must
+/// not be initialized before it draws its randomness from here.
+struct LockedKey(*mut [u8; KEY_SIZE]);
+
+/// # Safety
+///
+/// A [LockedKey] exclusively owns the memory it maps.
+unsafe impl Send for LockedKey {}
+
+impl LockedKey {
+    fn new() -> Self {
+        let ptr = unsafe {
+            libc::mmap(
+                null_mut(),
+                KEY_SIZE,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            abort("could not map memory for the key of the random generator");
+        }
+        // the memory is zeroed; locking it is best effort, as with libsodium
+        unsafe {
+            libc::mlock(ptr, KEY_SIZE);
+            libc::madvise(ptr, KEY_SIZE, libc::MADV_DONTDUMP);
+        }
+        Self(ptr as *mut [u8; KEY_SIZE])
+    }
+}
+
+impl Deref for LockedKey {
+    type Target = [u8; KEY_SIZE];
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.0 }
+    }
+}
+
+impl DerefMut for LockedKey {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.0 }
+    }
+}
+
+impl Drop for LockedKey {
+    fn drop(&mut self) {
+        rosenpass_sodium::helpers::memzero(&mut **self);
+        unsafe {
+            libc::munlock(self.0 as *mut c_void, KEY_SIZE);
+            libc::munmap(self.0 as *mut c_void, KEY_SIZE);
+        }
+    }
+}
+
+/// End the process: randomness is drawn from C, so there is no error to
+/// return, and going on with predictable randomness is worse than stopping
+fn abort(why: &str) -> ! {
+    eprintln!("{why}; aborting");
+    std::process::abort()
+}
+
+/// Set `key` to the BLAKE2b mac of `data` under it
+fn ratchet(key: &mut [u8; KEY_SIZE], data: &[u8]) {
+    let mut next = [0u8; KEY_SIZE];
+    if crate::sodium::mac_into(&mut next, &key[..], data).is_err() {
+        abort("could not derive the next key of the random generator");
+    }
+    key.copy_from_slice(&next);
+    rosenpass_sodium::helpers::memzero(&mut next);
+}
+
+/// A deterministic generator, into whose key further entropy is mixed
+pub struct Drbg {
+    key: LockedKey,
+    pid: u32,
+    reseeded_at: Instant,
+}
+
+impl Drbg {
+    pub fn new() -> Self {
+        Self {
+            key: LockedKey::new(),
+            pid: std::process::id(),
+            reseeded_at: Instant::now(),
+        }
+    }
+
+    /// Mix `data` from the source named `label` into the key
+    pub fn mix(&mut self, label: &str, data: &[u8]) {
+        let mut input = Vec::with_capacity(label.len() + 1 + data.len());
+        input.extend_from_slice(label.as_bytes());
+        input.push(0);
+        input.extend_from_slice(data);
+        ratchet(&mut self.key, &input);
+        rosenpass_sodium::helpers::memzero(&mut input);
+    }
+
+    /// Fill `buf` and replace the key
+    ///
+    /// Aborts the process rather than yield anything but the output of the
+    /// generator, or the same output twice.
+    pub fn fill(&mut self, buf: &mut [u8]) {
+        let mut block = [0u8; KEY_SIZE];
+        for (ctr, chunk) in buf.chunks_mut(KEY_SIZE).enumerate() {
+            let ctr = (ctr as u64 + 1).to_le_bytes();
+            if crate::sodium::mac_into(&mut block, &self.key[..], &ctr).is_err() {
+                abort("could not draw from the random generator");
+            }
+            chunk.copy_from_slice(&block[..chunk.len()]);
+        }
+        rosenpass_sodium::helpers::memzero(&mut block);
+        ratchet(&mut self.key, &0u64.to_le_bytes());
+    }
+
+    /// Mix in what the kernel, the clocks and the process id yield
+    fn reseed(&mut self) {
+        let mut buf = [0u8; KEY_SIZE];
+        if !getrandom(&mut buf) {
+            warn!("the kernel's randomness is not ready yet, relying on the other sources");
+        }
+        self.mix("getrandom", &buf);
+        rosenpass_sodium::helpers::memzero(&mut buf);
+        self.mix("context", &context());
+        (self.pid, self.reseeded_at) = (std::process::id(), Instant::now());
+    }
+
+    fn due(&self) -> bool {
+        self.pid != std::process::id() || self.reseeded_at.elapsed() >= RESEED_INTERVAL
+    }
+}
+
+impl Default for Drbg {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Fill `buf` from the kernel without blocking; false if its entropy pool
+/// is not initialized yet, in which case `buf` is filled regardless
+fn getrandom(buf: &mut [u8]) -> bool {
+    let get = |buf: &mut [u8], flags| {
+        let ptr = buf.as_mut_ptr() as *mut c_void;
+        unsafe { libc::getrandom(ptr, buf.len(), flags) == buf.len() as isize }
+    };
+    if get(buf, libc::GRND_NONBLOCK) {
+        return true;
+    }
+    // GRND_INSECURE is Linux 5.6 and later
+    const GRND_INSECURE: libc::c_uint = 4;
+    if !get(buf, GRND_INSECURE) {
+        let _ =
+            fs::File::open("/dev/urandom").and_then(|mut f| std::io::Read::read_exact(&mut f, buf));
+    }
+    false
+}
+
+/// The time, the process and its parent, which differ between processes
+fn context() -> Vec<u8> {
+    let wall = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    let mut ctx = wall.as_nanos().to_le_bytes().to_vec();
+    ctx.extend_from_slice(&std::process::id().to_le_bytes());
+    ctx.extend_from_slice(&unsafe { libc::getppid() }.to_le_bytes());
+    ctx
+}
+
+/// Timings of a loop of memory accesses, which vary with the state of the
+/// caches, the pipeline and the interrupts
+pub fn jitter() -> Vec<u8> {
+    let mut memory = vec![0u8; 4096];
+    let mut out = Vec::with_capacity(JITTER_SAMPLES * 4);
+    let mut pos = 0usize;
+    for i in 0..JITTER_SAMPLES {
+        let start = Instant::now();
+        for _ in 0..64 {
+            pos = (pos + 67 + memory[pos] as usize) % memory.len();
+            memory[pos] = memory[pos].wrapping_add(i as u8);
+        }
+        let nanos = start.elapsed().as_nanos() as u32;
+        out.extend_from_slice(&std::hint::black_box(nanos).to_le_bytes());
+    }
+    out
+}
+
+static DRBG: Mutex<Option<Drbg>> = Mutex::new(None);
+
+/// Fill `buf` from the generator, seeding it first if need be
+///
+/// Must not panic, since it is called from C.
+fn fill(buf: &mut [u8]) {
+    let mut drbg = DRBG.lock().unwrap_or_else(|e| e.into_inner());
+    let drbg = drbg.get_or_insert_with(|| {
+        let mut drbg = Drbg::new();
+        drbg.mix("jitter", &jitter());
+        drbg.reseed();
+        drbg
+    });
+    if drbg.due() {
+        drbg.reseed();
+    }
+    drbg.fill(buf);
+}
+
+unsafe extern "C" fn fill_raw(buf: *mut u8, len: usize) {
+    if len > 0 {
+        fill(std::slice::from_raw_parts_mut(buf, len));
+    }
+}
+
+unsafe extern "C" fn sodium_buf(buf: *mut c_void, len: usize) {
+    fill_raw(buf as *mut u8, len)
+}
+
+unsafe extern "C" fn sodium_random() -> u32 {
+    let mut buf = [0u8; 4];
+    fill(&mut buf);
+    u32::from_le_bytes(buf)
+}
+
+unsafe extern "C" fn sodium_stir() {}
+
+unsafe extern "C" fn sodium_name() -> *const libc::c_char {
+    c"rosenpass hedged".as_ptr()
+}
+
+static IMPLEMENTATION: libsodium::randombytes_implementation =
+    libsodium::randombytes_implementation {
+        implementation_name: Some(sodium_name),
+        random: Some(sodium_random),
+        stir: Some(sodium_stir),
+        uniform: None,
+        buf: Some(sodium_buf),
+        close: None,
+    };
+
+/// Draw all randomness of libsodium and liboqs from the generator
+///
+/// Called once, before any other threads are started, and before libsodium
+/// is initialized, as it must not be before its randomness is set.
+pub fn install() -> Result<()> {
+    // seed right away, so the first handshake does not wait for the jitter
+    fill(&mut [0u8; 1]);
+    unsafe { libsodium::randombytes_set_implementation(&IMPLEMENTATION) };
+    pqkem::draw_randomness_from(fill_raw);
+    Ok(())
+}
+
+/// Mix the seed file at `path` into the generator, if there is one, and
+/// replace it with a fresh seed
+pub fn mix_seed_file(path: &Path) -> Result<()> {
+    match fs::read(path) {
+        Ok(mut seed) => {
+            let mut drbg = DRBG.lock().unwrap_or_else(|e| e.into_inner());
+            drbg.get_or_insert_with(Drbg::new).mix("seed file", &seed);
+            rosenpass_sodium::helpers::memzero(&mut seed);
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => warn!("could not read the random seed {path:?}: {e}"),
+    }
+    let mut seed = [0u8; SEED_LEN];
+    fill(&mut seed);
+    let tmp = path.with_extension("new");
+    let res = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp)
+        .and_then(|mut f| f.write_all(&seed).and_then(|_| f.sync_all()))
+        .and_then(|_| fs::rename(&tmp, path));
+    rosenpass_sodium::helpers::memzero(&mut seed);
+    res.with_context(|| format!("could not replace the random seed {path:?}"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sources_change_the_output() {
+        rosenpass_sodium::init().unwrap();
+        let out = |drbg: &mut Drbg| {
+            let mut buf = [0u8; 40];
+            drbg.fill(&mut buf);
+            buf
+        };
+        let (mut a, mut b) = (Drbg::new(), Drbg::new());
+        a.mix("seed file", b"seed");
+        b.mix("seed file", b"seed");
+        let first = out(&mut a);
+        assert_eq!(first, out(&mut b));
+        // the key is replaced with every output
+        assert_ne!(first, out(&mut a));
+        b.mix("jitter", &jitter());
+        assert_ne!(out(&mut a), out(&mut b));
+    }
+}
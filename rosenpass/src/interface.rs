@@ -0,0 +1,143 @@
+//! Binding the key exchange to network interfaces
+//!
+//! On routers with several uplinks and with policy routing, the route the
+//! kernel picks for a peer is not necessarily the one the key exchange has to
+//! take. The `interface` option binds handshake traffic to a network
+//! interface with `SO_BINDTODEVICE`:
+//!
+//! - Set globally, all listen sockets are bound to the interface, so nothing
+//!   is sent or received through any other.
+//! - Set for a peer, an additional socket bound to the interface is opened on
+//!   an ephemeral port, and every message to the peer is sent through it, no
+//!   matter which socket the messages of the peer arrive on. Peers using the
+//!   same interface share the socket.
+//!
+//! On routers separating management and underlay with VRFs, binding to an
+//! address is not enough, as the address is routed through the main table
+//! all the same. The `vrf` option binds the listen sockets to a VRF device
+//! instead, so handshakes are routed by the table of the VRF; unlike with
+//! `interface`, they may go through any interface enslaved to it. Peer
+//! interfaces should then be enslaved to the VRF too.
+//!
+//! Only Linux is supported.
+
+use anyhow::{bail, ensure, Context, Result};
+use std::{io, net::SocketAddr, os::fd::AsRawFd, process::Command};
+
+/// Maximum length of an interface name, including the terminating zero
+pub const IFNAMSIZ: usize = 16;
+
+/// Make sure `name` can be the name of a network interface
+pub fn validate_name(name: &str) -> Result<()> {
+    ensure!(!name.is_empty(), "interface name is empty");
+    ensure!(
+        name.len() < IFNAMSIZ,
+        "interface name {name:?} is longer than {} bytes",
+        IFNAMSIZ - 1
+    );
+    ensure!(
+        name != "." && name != ".." && !name.contains(['/', ':', '\0']),
+        "interface name {name:?} is invalid"
+    );
+    ensure!(
+        !name.chars().any(char::is_whitespace),
+        "interface name {name:?} contains white space"
+    );
+    Ok(())
+}
+
+/// Send and receive through `sock` on the interface `name` only
+#[cfg(target_os = "linux")]
+pub fn bind_to_device(sock: &impl AsRawFd, name: &str) -> Result<()> {
+    validate_name(name)?;
+    let res = unsafe {
+        libc::setsockopt(
+            sock.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            name.as_ptr() as *const libc::c_void,
+            name.len() as libc::socklen_t,
+        )
+    };
+    if res != 0 {
+        let e = io::Error::last_os_error();
+        bail!("could not bind socket to interface {name}: {e}");
+    }
+    Ok(())
+}
+
+/// Send and receive through `sock` on the interface `name` only
+#[cfg(not(target_os = "linux"))]
+pub fn bind_to_device(_sock: &impl AsRawFd, name: &str) -> Result<()> {
+    bail!("can not bind to interface {name}: only supported on Linux")
+}
+
+/// Make sure the network device `name` is a VRF, asking `ip link`
+pub fn ensure_vrf(name: &str) -> Result<()> {
+    validate_name(name)?;
+    let out = Command::new("ip")
+        .args(["-details", "-json", "link", "show", "dev", name])
+        .output()
+        .context("could not run ip")?;
+    ensure!(
+        out.status.success(),
+        "could not look up VRF {name}: {}",
+        String::from_utf8_lossy(&out.stderr).trim()
+    );
+    match link_kind(&out.stdout)? {
+        Some(kind) if kind == "vrf" => Ok(()),
+        Some(kind) => bail!("device {name} is a {kind} device, not a VRF"),
+        None => bail!("device {name} is not a VRF"),
+    }
+}
+
+/// The kind of the device `ip -details -json link show` describes
+fn link_kind(json: &[u8]) -> Result<Option<String>> {
+    let links: serde_json::Value = serde_json::from_slice(json)?;
+    let kind = links[0]["linkinfo"]["info_kind"].as_str();
+    Ok(kind.map(str::to_owned))
+}
+
+/// A socket on an ephemeral port bound to the interface `name`; dual stack
+/// where the operating system allows, IPv4 only otherwise
+pub fn device_socket(name: &str) -> Result<mio::net::UdpSocket> {
+    let v6: SocketAddr = "[::]:0".parse().unwrap();
+    let v4: SocketAddr = "0.0.0.0:0".parse().unwrap();
+    let sock = mio::net::UdpSocket::bind(v6)
+        .or_else(|_| mio::net::UdpSocket::bind(v4))
+        .with_context(|| format!("could not open a socket for interface {name}"))?;
+    bind_to_device(&sock, name)?;
+    Ok(sock)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn names() {
+        for name in ["eth0", "wan.10", "wg-uplink", "a23456789012345"] {
+            validate_name(name).unwrap();
+        }
+        for name in ["", ".", "..", "a/b", "eth0:1", "a b", "a234567890123456"] {
+            assert!(validate_name(name).is_err(), "{name:?}");
+        }
+    }
+
+    #[test]
+    fn kinds() {
+        let vrf = br#"[{"ifname":"mgmt","linkinfo":{"info_kind":"vrf","info_data":{"table":10}}}]"#;
+        assert_eq!(link_kind(vrf).unwrap().as_deref(), Some("vrf"));
+        assert_eq!(link_kind(br#"[{"ifname":"lo"}]"#).unwrap(), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn bind_loopback() {
+        // binding a socket not bound to a device yet needs no privileges
+        let sock = device_socket("lo").unwrap();
+        let addr = sock.local_addr().unwrap();
+        assert_ne!(addr.port(), 0);
+        assert!(device_socket("rp-no-such-if").is_err());
+    }
+}
@@ -0,0 +1,173 @@
+//! Generating key pairs on several cores
+//!
+//! Classic McEliece generates a key pair by drawing random codes until it
+//! finds one of the right form, which takes a few tries on average and many
+//! more now and then; on small devices, that is minutes. Which try will
+//! succeed can not be told in advance, and a single try can not be split up,
+//! so `gen-keys` runs several key generations from seeds of their own at
+//! once and takes the first to finish; with four of them, the wait is less
+//! than half as long on average.
+//!
+//! liboqs draws its randomness through a process wide hook, see
+//! [StaticKEM::keygen_from_seed], so each key generation runs in a process
+//! forked off for it, which hands the key pair back through a pipe. Key
+//! pairs derived from a given seed, see `--from-seed`, are generated in a
+//! single process.
+//!
+//! How far a key generation is along can not be known, so while waiting,
+//! `gen-keys` tells every [PROGRESS_INTERVAL] that it still is and for how
+//! long already, unless it is `--quiet`.
+
+use anyhow::{bail, ensure, Context, Result};
+use std::{
+    fs::File,
+    io::{ErrorKind, Read, Write},
+    os::fd::{AsRawFd, FromRawFd},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    coloring::Secret,
+    pqkem::{StaticKEM, KEYGEN_SEED_LEN},
+    protocol::{SPk, SSk},
+};
+
+/// At most this many key generations run at once
+pub const MAX_JOBS: usize = 16;
+
+/// Time between the progress reports
+pub const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A key pair, with the seed it was generated from
+pub struct KeyPair {
+    pub seed: Secret<KEYGEN_SEED_LEN>,
+    pub sk: SSk,
+    pub pk: SPk,
+}
+
+/// How many key generations to run at once by default, one for each core
+pub fn default_jobs() -> usize {
+    thread::available_parallelism().map_or(1, |n| n.get().min(MAX_JOBS))
+}
+
+/// A key generation running in a process of its own
+struct Worker {
+    pid: libc::pid_t,
+    rx: File,
+    seed: Secret<KEYGEN_SEED_LEN>,
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        unsafe {
+            libc::kill(self.pid, libc::SIGKILL);
+            libc::waitpid(self.pid, std::ptr::null_mut(), 0);
+        }
+    }
+}
+
+impl Worker {
+    fn spawn(seed: Secret<KEYGEN_SEED_LEN>) -> Result<Self> {
+        let mut fds = [0; 2];
+        ensure!(
+            unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } == 0,
+            "could not create a pipe: {}",
+            std::io::Error::last_os_error()
+        );
+        let (rx, tx) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+        match unsafe { libc::fork() } {
+            -1 => bail!(
+                "could not fork a key generation: {}",
+                std::io::Error::last_os_error()
+            ),
+            0 => {
+                drop(rx);
+                let code = match generate_into(seed.secret(), tx) {
+                    Ok(()) => 0,
+                    Err(_) => 1,
+                };
+                unsafe { libc::_exit(code) }
+            }
+            pid => Ok(Self { pid, rx, seed }),
+        }
+    }
+
+    /// The key pair, once the process is done; [None] if it failed
+    fn receive(&mut self) -> Option<(SSk, SPk)> {
+        let (mut sk, mut pk) = (SSk::zero(), SPk::zero());
+        self.rx.read_exact(sk.secret_mut()).ok()?;
+        self.rx.read_exact(pk.secret_mut()).ok()?;
+        Some((sk, pk))
+    }
+}
+
+/// Generate a key pair from `seed` and write it to `tx`
+fn generate_into(seed: &[u8], mut tx: File) -> Result<()> {
+    let (mut sk, mut pk) = (SSk::zero(), SPk::zero());
+    StaticKEM::keygen_from_seed(seed, sk.secret_mut(), pk.secret_mut())?;
+    tx.write_all(sk.secret())?;
+    tx.write_all(pk.secret())?;
+    Ok(())
+}
+
+/// Generate key pairs from all of `seeds` at once, returning the first to
+/// be done; `progress` is told how long it has been every
+/// [PROGRESS_INTERVAL]
+pub fn race(
+    seeds: Vec<Secret<KEYGEN_SEED_LEN>>,
+    mut progress: impl FnMut(Duration),
+) -> Result<KeyPair> {
+    let mut workers = seeds
+        .into_iter()
+        .map(Worker::spawn)
+        .collect::<Result<Vec<_>>>()?;
+    let start = Instant::now();
+    let mut report_at = start + PROGRESS_INTERVAL;
+    while !workers.is_empty() {
+        let mut fds: Vec<libc::pollfd> = workers
+            .iter()
+            .map(|w| libc::pollfd {
+                fd: w.rx.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .collect();
+        let timeout = report_at.saturating_duration_since(Instant::now());
+        let ready = unsafe {
+            libc::poll(
+                fds.as_mut_ptr(),
+                fds.len() as libc::nfds_t,
+                timeout.as_millis() as libc::c_int,
+            )
+        };
+        if ready < 0 {
+            let e = std::io::Error::last_os_error();
+            match e.kind() {
+                ErrorKind::Interrupted => continue,
+                _ => return Err(e).context("could not wait for the key generation"),
+            }
+        }
+        if Instant::now() >= report_at {
+            progress(start.elapsed());
+            report_at += PROGRESS_INTERVAL;
+        }
+        // the processes which are done, or failed
+        for i in (0..fds.len()).rev().filter(|&i| fds[i].revents != 0) {
+            let mut worker = workers.remove(i);
+            if let Some((sk, pk)) = worker.receive() {
+                let seed = std::mem::replace(&mut worker.seed, Secret::zero());
+                return Ok(KeyPair { seed, sk, pk });
+            }
+        }
+    }
+    bail!("the key generation failed")
+}
+
+/// Generate a key pair, running `jobs` key generations at once
+pub fn generate(jobs: usize, progress: impl FnMut(Duration)) -> Result<KeyPair> {
+    race(
+        (0..jobs.max(1)).map(|_| Secret::random()).collect(),
+        progress,
+    )
+}
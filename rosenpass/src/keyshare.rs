@@ -0,0 +1,372 @@
+//! Splitting a secret key into shares for escrow
+//!
+//! `rosenpass split-key` splits a secret-key file with Shamir's scheme over
+//! GF(2^8): any `threshold` of the shares recover the key with `rosenpass
+//! recover-key`, while fewer reveal nothing about it. Each custodian keeps
+//! one share, so the long-term identity survives the loss of the machine
+//! without any single custodian holding it.
+//!
+//! Every share carries commitments, hashes with a label of their own, to
+//! each share of its split and to the secret itself. A custodian can check
+//! their share on its own with `recover-key --check`; on recovery, a share
+//! which was tampered with or belongs to another split is pointed out, and
+//! the recovered key is checked against the commitment to the secret.
+//! Unlike verifiable secret sharing schemes built on discrete logarithms,
+//! the commitments do not prove that the dealer handed out shares of a
+//! single secret, only that the shares are the ones it handed out.
+//!
+//! Shares are text files:
+//!
+//! ```text
+//! # rosenpass key share 2 of 5, any 3 recover the key
+//! split <BASE64>
+//! threshold 3
+//! index 2
+//! secret <BASE64>
+//! commitments <BASE64> <BASE64> <BASE64> <BASE64> <BASE64>
+//! share <BASE64>
+//! ```
+
+use anyhow::{bail, ensure, Context, Result};
+use rosenpass_sodium::helpers::{memzero, randombytes_buf};
+use rosenpass_util::b64::{b64_reader, fmt_b64};
+use std::{fmt, io::Read};
+
+use crate::{labeled_prf as lprf, sodium::KEY_SIZE};
+
+const SPLIT_ID_LEN: usize = 16;
+
+type Commitment = [u8; KEY_SIZE];
+
+/// One share of a secret
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Share {
+    /// Random, to tell the shares of different splits apart
+    pub split: [u8; SPLIT_ID_LEN],
+    pub threshold: u8,
+    /// From one to the number of shares
+    pub index: u8,
+    /// Commitment to the secret
+    pub secret: Commitment,
+    /// Commitments to all shares of the split, in order
+    pub commitments: Vec<Commitment>,
+    data: Vec<u8>,
+}
+
+impl Drop for Share {
+    fn drop(&mut self) {
+        memzero(&mut self.data);
+    }
+}
+
+fn commit(split: &[u8; SPLIT_ID_LEN], what: &[u8], data: &[u8]) -> Result<Commitment> {
+    let tree = lprf::key_share()?.mix(split)?.mix(what)?;
+    Ok(tree.mix(data)?.into_value())
+}
+
+fn commit_share(split: &[u8; SPLIT_ID_LEN], index: u8, data: &[u8]) -> Result<Commitment> {
+    commit(split, &[b's', index], data)
+}
+
+fn commit_secret(split: &[u8; SPLIT_ID_LEN], secret: &[u8]) -> Result<Commitment> {
+    commit(split, b"secret", secret)
+}
+
+impl Share {
+    /// Check the share against its commitment
+    pub fn verify(&self) -> Result<()> {
+        let commitment = self
+            .commitments
+            .get(usize::from(self.index) - 1)
+            .context("the share has no commitment")?;
+        ensure!(
+            commit_share(&self.split, self.index, &self.data)? == *commitment,
+            "share {} does not match its commitment, it was altered",
+            self.index
+        );
+        Ok(())
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let (mut split, mut threshold, mut index, mut secret, mut commitments, mut data) =
+            (None, None, None, None, None, None);
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+            match key {
+                "split" => split = Some(decode_fixed(value)?),
+                "threshold" => threshold = Some(value.parse::<u8>()?),
+                "index" => index = Some(value.parse::<u8>()?),
+                "secret" => secret = Some(decode_fixed(value)?),
+                "commitments" => {
+                    let list = value.split_whitespace().map(decode_fixed);
+                    commitments = Some(list.collect::<Result<Vec<_>>>()?)
+                }
+                "share" => data = Some(decode(value)?),
+                _ => bail!("unknown key {key:?} in key share"),
+            }
+        }
+        let missing = |what| format!("the key share has no {what}");
+        let share = Self {
+            split: split.with_context(|| missing("split"))?,
+            threshold: threshold.with_context(|| missing("threshold"))?,
+            index: index.with_context(|| missing("index"))?,
+            secret: secret.with_context(|| missing("secret"))?,
+            commitments: commitments.with_context(|| missing("commitments"))?,
+            data: data.with_context(|| missing("share"))?,
+        };
+        let shares = share.commitments.len();
+        ensure!(
+            share.threshold >= 1 && usize::from(share.threshold) <= shares,
+            "the key share needs {} of {shares} shares, which can not be",
+            share.threshold
+        );
+        ensure!(
+            share.index >= 1 && usize::from(share.index) <= shares,
+            "the key share is share {} of {shares}, which can not be",
+            share.index
+        );
+        Ok(share)
+    }
+}
+
+impl fmt::Display for Share {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "# rosenpass key share {} of {}, any {} recover the key",
+            self.index,
+            self.commitments.len(),
+            self.threshold
+        )?;
+        writeln!(f, "split {}", fmt_b64(&self.split))?;
+        writeln!(f, "threshold {}", self.threshold)?;
+        writeln!(f, "index {}", self.index)?;
+        writeln!(f, "secret {}", fmt_b64(&self.secret))?;
+        write!(f, "commitments")?;
+        for commitment in self.commitments.iter() {
+            write!(f, " {}", fmt_b64(commitment))?;
+        }
+        writeln!(f)?;
+        writeln!(f, "share {}", fmt_b64(&self.data))
+    }
+}
+
+fn decode(b64: &str) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    b64_reader(b64.as_bytes())
+        .read_to_end(&mut data)
+        .context("invalid base64 in key share")?;
+    Ok(data)
+}
+
+fn decode_fixed<const N: usize>(b64: &str) -> Result<[u8; N]> {
+    let data = decode(b64)?;
+    data.try_into()
+        .map_err(|data: Vec<u8>| anyhow::anyhow!("{} bytes in key share, not {N}", data.len()))
+}
+
+/// Multiply in GF(2^8) with the polynomial of AES, in constant time
+fn mul(mut a: u8, mut b: u8) -> u8 {
+    let mut p = 0;
+    for _ in 0..8 {
+        p ^= (b & 1).wrapping_neg() & a;
+        let carry = (a >> 7).wrapping_neg();
+        a = (a << 1) ^ (0x1b & carry);
+        b >>= 1;
+    }
+    p
+}
+
+fn inv(a: u8) -> u8 {
+    // a^254 is the inverse of a, as a^255 = 1
+    let mut res = 1;
+    let mut square = a;
+    for _ in 0..7 {
+        square = mul(square, square);
+        res = mul(res, square);
+    }
+    res
+}
+
+/// Split `secret` into `shares` shares, any `threshold` of which recover it
+pub fn split(secret: &[u8], threshold: u8, shares: u8) -> Result<Vec<Share>> {
+    ensure!(threshold >= 1, "the threshold must be at least one");
+    ensure!(
+        threshold <= shares,
+        "the threshold {threshold} exceeds the {shares} shares"
+    );
+    let mut split = [0u8; SPLIT_ID_LEN];
+    randombytes_buf(&mut split);
+    let commitment = commit_secret(&split, secret)?;
+
+    // the coefficients of the polynomial of every byte but the secret one
+    let mut coefficients = vec![0u8; secret.len() * (usize::from(threshold) - 1)];
+    randombytes_buf(&mut coefficients);
+    let mut res = Vec::with_capacity(shares.into());
+    for index in 1..=shares {
+        let mut data = Vec::with_capacity(secret.len());
+        for (i, &byte) in secret.iter().enumerate() {
+            let coeffs = coefficients.iter().skip(i).step_by(secret.len());
+            // Horner's method, from the highest coefficient down
+            let high = coeffs.rev().fold(0, |acc, &c| mul(acc, index) ^ c);
+            data.push(mul(high, index) ^ byte);
+        }
+        res.push(Share {
+            split,
+            threshold,
+            index,
+            secret: commitment,
+            commitments: Vec::new(),
+            data,
+        });
+    }
+    memzero(&mut coefficients);
+
+    let commitments = res
+        .iter()
+        .map(|s| commit_share(&split, s.index, &s.data))
+        .collect::<Result<Vec<_>>>()?;
+    for share in res.iter_mut() {
+        share.commitments = commitments.clone();
+    }
+    Ok(res)
+}
+
+/// Recover the secret from at least as many shares as the threshold
+pub fn recover(shares: &[Share]) -> Result<Vec<u8>> {
+    let first = shares.first().context("no key shares given")?;
+    for share in shares {
+        ensure!(
+            share.split == first.split,
+            "share {} belongs to another split than share {}",
+            share.index,
+            first.index
+        );
+        ensure!(
+            share.threshold == first.threshold
+                && share.secret == first.secret
+                && share.commitments == first.commitments,
+            "share {} disagrees with share {} about the split, one of them was altered",
+            share.index,
+            first.index
+        );
+        share.verify()?;
+        ensure!(
+            shares.iter().filter(|s| s.index == share.index).count() == 1,
+            "share {} is given more than once",
+            share.index
+        );
+        ensure!(
+            share.data.len() == first.data.len(),
+            "share {} is of another length than share {}",
+            share.index,
+            first.index
+        );
+    }
+    ensure!(
+        shares.len() >= usize::from(first.threshold),
+        "{} shares given, but {} are needed",
+        shares.len(),
+        first.threshold
+    );
+
+    // Lagrange interpolation at zero; subtraction is addition in GF(2^8)
+    let used = &shares[..first.threshold.into()];
+    let mut secret = vec![0u8; first.data.len()];
+    for share in used {
+        let basis = used
+            .iter()
+            .filter(|other| other.index != share.index)
+            .fold(1, |acc, other| {
+                mul(acc, mul(other.index, inv(other.index ^ share.index)))
+            });
+        for (byte, &y) in secret.iter_mut().zip(share.data.iter()) {
+            *byte ^= mul(y, basis);
+        }
+    }
+    if commit_secret(&first.split, &secret)? != first.secret {
+        memzero(&mut secret);
+        bail!("the recovered key does not match the commitment to it");
+    }
+    Ok(secret)
+}
+
+/// Put a secret-key file and an optional WireGuard secret-key file together
+/// into one secret to split
+pub fn bundle(secret_key: &[u8], wireguard_key: Option<&[u8]>) -> Vec<u8> {
+    let mut res = Vec::with_capacity(4 + secret_key.len());
+    res.extend_from_slice(&(secret_key.len() as u32).to_be_bytes());
+    res.extend_from_slice(secret_key);
+    res.extend_from_slice(wireguard_key.unwrap_or_default());
+    res
+}
+
+/// Take a secret made by [bundle] apart again
+pub fn unbundle(secret: &[u8]) -> Result<(&[u8], Option<&[u8]>)> {
+    let (len, rest) = secret
+        .split_first_chunk::<4>()
+        .context("the recovered secret is truncated")?;
+    let len = u32::from_be_bytes(*len) as usize;
+    ensure!(len <= rest.len(), "the recovered secret is truncated");
+    let (secret_key, wireguard_key) = rest.split_at(len);
+    Ok((
+        secret_key,
+        Some(wireguard_key).filter(|key| !key.is_empty()),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn field() {
+        for a in 1..=255 {
+            assert_eq!(mul(a, inv(a)), 1, "{a}");
+        }
+        assert_eq!(mul(0x57, 0x83), 0xc1);
+    }
+
+    #[test]
+    fn any_threshold_of_the_shares_recover() {
+        rosenpass_sodium::init().unwrap();
+        let secret: Vec<u8> = (0..200).map(|i| (i * 7) as u8).collect();
+        let shares = split(&secret, 3, 5).unwrap();
+        let pick = |indices: &[usize]| -> Vec<Share> {
+            indices.iter().map(|&i| shares[i].clone()).collect()
+        };
+        for indices in [[0, 1, 2], [4, 2, 0], [1, 3, 4]] {
+            assert_eq!(recover(&pick(&indices)).unwrap(), secret);
+        }
+        assert_eq!(recover(&pick(&[0, 1, 2, 3, 4])).unwrap(), secret);
+        assert!(recover(&pick(&[0, 1])).is_err());
+        assert!(recover(&pick(&[0, 1, 1])).is_err());
+
+        let parsed = Share::parse(&shares[3].to_string()).unwrap();
+        assert_eq!(parsed, shares[3]);
+        parsed.verify().unwrap();
+
+        let mut altered = pick(&[0, 1, 2]);
+        altered[1].data[17] ^= 1;
+        let err = recover(&altered).unwrap_err().to_string();
+        assert!(err.contains("share 2 does not match"), "{err}");
+
+        let other = split(&secret, 3, 5).unwrap();
+        let mixed = vec![shares[0].clone(), shares[1].clone(), other[2].clone()];
+        assert!(recover(&mixed).is_err());
+    }
+
+    #[test]
+    fn bundles() {
+        let bundled = bundle(b"secret key", Some(b"wireguard key\n"));
+        let (sk, wg) = unbundle(&bundled).unwrap();
+        assert_eq!(
+            (sk, wg),
+            (&b"secret key"[..], Some(&b"wireguard key\n"[..]))
+        );
+        assert_eq!(unbundle(&bundle(b"sk", None)).unwrap(), (&b"sk"[..], None));
+    }
+}
@@ -0,0 +1,50 @@
+//! Streaming keys on stdout
+//!
+//! With `--stream-keys` (or `stream_keys = true` in the config), the keys of
+//! all peers are printed on stdout, and so are those of peers with
+//! `key_out = "stdout"`; a supervising process reads them from a pipe instead
+//! of watching key files. Every key is one line of JSON:
+//!
+//! ```json
+//! {"peer_id":"…","fingerprint":"…","key":"…","event":"exchanged","expires":1760432180}
+//! ```
+//!
+//! `key` is base64 encoded and `expires` the Unix time the key is no longer
+//! accepted at. A `stale` event carries the random key replacing one which
+//! expired, and no `expires`. While keys are streamed, the `output-key`
+//! notifications otherwise printed on stdout go to the log.
+
+use anyhow::Result;
+use serde::Serialize;
+use std::io::Write;
+
+/// `key_out` of peers whose keys are streamed on stdout
+pub const STDOUT: &str = "stdout";
+
+/// One line of the stream
+#[derive(Debug, Serialize)]
+pub struct KeyEvent {
+    /// Base64 encoded peer id
+    pub peer_id: String,
+    pub fingerprint: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// The base64 encoded key
+    pub key: String,
+    /// `exchanged` or `stale`
+    pub event: &'static str,
+    /// Seconds since the Unix epoch the key expires at
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires: Option<u64>,
+}
+
+impl KeyEvent {
+    /// Print the event as a line of JSON
+    pub fn emit(&self) -> Result<()> {
+        let mut out = std::io::stdout().lock();
+        serde_json::to_writer(&mut out, self)?;
+        out.write_all(b"\n")?;
+        out.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,206 @@
+//! Envelope encryption of the secret key file
+//!
+//! A wrapped key file holds a random data key encrypted by an external key
+//! management tool and the secret key encrypted under that data key:
+//!
+//! ```text
+//! MAGIC | len(wrapped data key): u32 big endian | wrapped data key | xaead(secret key)
+//! ```
+//!
+//! Going through a data key keeps the secret key away from the size limits
+//! of the KMS APIs (the Classic McEliece secret key is several kilobytes) and
+//! lets all methods share one file format. The external tools are invoked as
+//! commands, so their usual configuration and credentials apply. The secret
+//! key is always encrypted with XChaCha20-Poly1305, so wrapped key files can
+//! not be used in FIPS-constrained mode.
+
+use anyhow::{bail, ensure, Context, Result};
+use rosenpass_sodium::aead::xchacha20poly1305_ietf as xaead;
+use rosenpass_util::b64::b64_reader;
+use serde::{Deserialize, Serialize};
+use std::{
+    io::{Read, Write},
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+use crate::{coloring::Secret, protocol::SymKey};
+
+/// Identifies a wrapped key file; also used as additional data of the encryption
+pub const MAGIC: &[u8; 8] = b"RPWRAP01";
+
+/// How the data key of a wrapped secret key is protected
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "method")]
+pub enum KeyWrap {
+    /// Encrypt to age recipients; unwrapping needs a matching identity
+    Age {
+        /// Identity file used to unwrap
+        #[serde(default)]
+        identity: Option<PathBuf>,
+
+        /// Recipients used to wrap
+        #[serde(default)]
+        recipients: Vec<String>,
+    },
+
+    /// AWS KMS, through the `aws` command
+    AwsKms {
+        key_id: String,
+
+        #[serde(default)]
+        region: Option<String>,
+    },
+
+    /// Google Cloud KMS, through the `gcloud` command; `key` is the full
+    /// resource name of the crypto key
+    GcpKms { key: String },
+}
+
+/// Run `cmd` with `input` on stdin, returning its stdout
+fn pipe(mut cmd: Command, input: &[u8]) -> Result<Vec<u8>> {
+    let name = cmd.get_program().to_string_lossy().into_owned();
+    let mut child = cmd
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .with_context(|| format!("could not run {name}"))?;
+    child.stdin.take().unwrap().write_all(input)?;
+    let out = child.wait_with_output()?;
+    ensure!(out.status.success(), "{name} failed with {}", out.status);
+    Ok(out.stdout)
+}
+
+fn b64_decode(b64: &[u8]) -> Result<Vec<u8>> {
+    let end = b64
+        .iter()
+        .rposition(|c| !c.is_ascii_whitespace())
+        .map_or(0, |i| i + 1);
+    let mut v = vec![];
+    b64_reader(&b64[..end]).read_to_end(&mut v)?;
+    Ok(v)
+}
+
+impl KeyWrap {
+    fn wrap_data_key(&self, dk: &[u8]) -> Result<Vec<u8>> {
+        Ok(match self {
+            KeyWrap::Age { recipients, .. } => {
+                ensure!(!recipients.is_empty(), "age needs at least one recipient");
+                let mut cmd = Command::new("age");
+                cmd.arg("--encrypt");
+                for r in recipients {
+                    cmd.args(["--recipient", r]);
+                }
+                pipe(cmd, dk)?
+            }
+            KeyWrap::AwsKms { key_id, region } => {
+                let mut cmd = Command::new("aws");
+                cmd.args(["kms", "encrypt", "--key-id", key_id]);
+                cmd.args(["--plaintext", "fileb:///dev/stdin"]);
+                cmd.args(["--output", "text", "--query", "CiphertextBlob"]);
+                if let Some(region) = region {
+                    cmd.args(["--region", region]);
+                }
+                b64_decode(&pipe(cmd, dk)?)?
+            }
+            KeyWrap::GcpKms { key } => {
+                let mut cmd = Command::new("gcloud");
+                cmd.args(["kms", "encrypt", "--key", key]);
+                cmd.args(["--plaintext-file", "-", "--ciphertext-file", "-"]);
+                pipe(cmd, dk)?
+            }
+        })
+    }
+
+    fn unwrap_data_key(&self, wrapped: &[u8]) -> Result<Vec<u8>> {
+        Ok(match self {
+            KeyWrap::Age { identity, .. } => {
+                let Some(identity) = identity else {
+                    bail!("age needs an identity file to unwrap the key");
+                };
+                let mut cmd = Command::new("age");
+                cmd.arg("--decrypt").arg("--identity").arg(identity);
+                pipe(cmd, wrapped)?
+            }
+            KeyWrap::AwsKms { key_id, region } => {
+                let mut cmd = Command::new("aws");
+                cmd.args(["kms", "decrypt", "--key-id", key_id]);
+                cmd.args(["--ciphertext-blob", "fileb:///dev/stdin"]);
+                cmd.args(["--output", "text", "--query", "Plaintext"]);
+                if let Some(region) = region {
+                    cmd.args(["--region", region]);
+                }
+                b64_decode(&pipe(cmd, wrapped)?)?
+            }
+            KeyWrap::GcpKms { key } => {
+                let mut cmd = Command::new("gcloud");
+                cmd.args(["kms", "decrypt", "--key", key]);
+                cmd.args(["--ciphertext-file", "-", "--plaintext-file", "-"]);
+                pipe(cmd, wrapped)?
+            }
+        })
+    }
+
+    /// Produce the contents of a wrapped key file for `key`
+    pub fn seal(&self, key: &[u8]) -> Result<Vec<u8>> {
+        let dk = SymKey::random();
+        let wrapped = self.wrap_data_key(dk.secret())?;
+
+        let mut nonce = [0u8; xaead::NONCE_LEN];
+        rosenpass_sodium::helpers::randombytes_buf(&mut nonce);
+        let mut ct = vec![0u8; xaead::NONCE_LEN + key.len() + xaead::TAG_LEN];
+        xaead::encrypt(&mut ct, dk.secret(), &nonce, MAGIC, key)?;
+
+        let mut file = MAGIC.to_vec();
+        file.extend_from_slice(&u32::try_from(wrapped.len())?.to_be_bytes());
+        file.extend_from_slice(&wrapped);
+        file.extend_from_slice(&ct);
+        Ok(file)
+    }
+
+    /// Decrypt the wrapped key file contents `file` into `key`
+    pub fn open(&self, file: &[u8], key: &mut [u8]) -> Result<()> {
+        let Some(rest) = file.strip_prefix(MAGIC) else {
+            bail!("not a wrapped key file");
+        };
+        ensure!(rest.len() >= 4, "wrapped key file is truncated");
+        let (len, rest) = rest.split_at(4);
+        let len = u32::from_be_bytes(len.try_into().unwrap()) as usize;
+        ensure!(
+            rest.len() == len + xaead::NONCE_LEN + key.len() + xaead::TAG_LEN,
+            "wrapped key file has the wrong size for this key type"
+        );
+        let (wrapped, ct) = rest.split_at(len);
+
+        let mut plain = self.unwrap_data_key(wrapped)?;
+        ensure!(
+            plain.len() == xaead::KEY_LEN,
+            "unwrapped data key has {} bytes, expected {}",
+            plain.len(),
+            xaead::KEY_LEN
+        );
+        let dk = Secret::<{ xaead::KEY_LEN }>::from_slice(&plain);
+        rosenpass_sodium::helpers::memzero(&mut plain);
+        xaead::decrypt(key, dk.secret(), MAGIC, ct).context("could not decrypt the secret key")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn open_rejects_malformed_files() {
+        // these are rejected before the external tool would be invoked
+        let wrap = KeyWrap::GcpKms { key: "k".into() };
+        let mut key = [0u8; 16];
+        assert!(wrap.open(b"not a wrapped key", &mut key).is_err());
+        assert!(wrap.open(MAGIC, &mut key).is_err());
+
+        let mut file = MAGIC.to_vec();
+        file.extend_from_slice(&4u32.to_be_bytes());
+        file.extend_from_slice(&[0; 4 + xaead::NONCE_LEN + xaead::TAG_LEN]);
+        assert!(wrap.open(&file, &mut [0u8; 17]).is_err());
+    }
+}
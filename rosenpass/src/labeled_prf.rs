@@ -4,10 +4,22 @@
 use {
     crate::{prftree::PrfTree, sodium::KEY_SIZE},
     anyhow::Result,
+    rosenpass_ciphers::fips,
 };
 
+/// Label of the protocol all keys are derived under
+pub const PROTOCOL: &str = "Rosenpass v1 mceliece460896 Kyber512 ChaChaPoly1305 BLAKE2s";
+
+/// [PROTOCOL] in FIPS-constrained mode
+pub const FIPS_PROTOCOL: &str = "Rosenpass v1 mceliece460896 Kyber512 AES256GCM HMAC-SHA256 FIPS";
+
 pub fn protocol() -> Result<PrfTree> {
-    PrfTree::zero().mix("Rosenpass v1 mceliece460896 Kyber512 ChaChaPoly1305 BLAKE2s".as_bytes())
+    // peers in FIPS-constrained mode only talk to each other
+    let label = match fips::enabled() {
+        true => FIPS_PROTOCOL,
+        false => PROTOCOL,
+    };
+    PrfTree::zero().mix(label.as_bytes())
 }
 
 // TODO Use labels that can serve as identifiers
@@ -23,6 +35,7 @@ macro_rules! prflabel {
 
 prflabel!(protocol, mac, "mac");
 prflabel!(protocol, cookie, "cookie");
+prflabel!(protocol, extension_mac, "extension mac");
 prflabel!(protocol, peerid, "peer id");
 prflabel!(protocol, biscuit_ad, "biscuit additional data");
 prflabel!(protocol, ckinit, "chaining key init");
@@ -46,3 +59,30 @@ prflabel_leaf!(_ckextract, res_enc, "responder handshake encryption");
 prflabel!(_ckextract, _user, "user");
 prflabel!(_user, _rp, "rosenpass.eu");
 prflabel_leaf!(_rp, osk, "wireguard psk");
+prflabel!(_rp, exporter, "exporter");
+
+/// Root of the keys `gen-keys --from-seed` derives, which must not change
+/// with the mode of the protocol
+pub fn keygen() -> Result<PrfTree> {
+    PrfTree::zero().mix("Rosenpass v1 key generation".as_bytes())
+}
+
+prflabel!(keygen, device_seed, "device seed");
+prflabel!(keygen, wireguard_key, "wireguard secret key");
+
+/// Root of the keys enrollment requests are encrypted with, see
+/// [crate::enrollment]
+pub fn enrollment() -> Result<PrfTree> {
+    PrfTree::zero().mix("Rosenpass v1 enrollment".as_bytes())
+}
+
+/// Root of the keys multicast DNS announcements are authenticated with, see
+/// [crate::mdns]
+pub fn mdns() -> Result<PrfTree> {
+    PrfTree::zero().mix("Rosenpass v1 multicast DNS".as_bytes())
+}
+
+/// Root of the commitments of key shares, see [crate::keyshare]
+pub fn key_share() -> Result<PrfTree> {
+    PrfTree::zero().mix("Rosenpass v1 key share".as_bytes())
+}
@@ -0,0 +1,209 @@
+//! Loading the public keys of peers on demand
+//!
+//! Responders with a large fleet of peers spend their startup reading
+//! public keys, and their memory keeping them, although most peers only
+//! handshake every few minutes. With lazy keys, the key of a peer is only
+//! read once a handshake with it needs the key:
+//!
+//! ```toml
+//! [lazy_keys]
+//! cache = 1024                            # peers whose keys stay loaded
+//! index = "/var/cache/rosenpass/peer-ids" # peer ids of the key files
+//! ```
+//!
+//! Only the keys of the [LazyKeys::cache] peers used last, and those of
+//! peers in a handshake we initiated, stay loaded. This applies to keys read
+//! from files; keys from a vault or vouched for by a CA are loaded right
+//! away, as are old keys in a rollover and the keys of peers added while
+//! rosenpass runs.
+//!
+//! Handshakes find their peer by its id, a hash of its key, so every key
+//! file is still read once at startup. With an index, the ids are kept in a
+//! file along with the length, modification time and inode of their key
+//! file, and key files are only read at startup once they changed. A key is
+//! checked against its id whenever it is loaded, so a key file replaced
+//! while rosenpass runs fails the handshakes of its peer rather than
+//! changing its key.
+//!
+//! Lazy keys can not be combined with `identity_hiding`, which needs the
+//! keys of all responders to tell which sent a message.
+
+use anyhow::{ensure, Context, Result};
+use rosenpass_util::{
+    b64::{b64_reader, fmt_b64},
+    file::LoadValue,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{ErrorKind, Read},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    protocol::{peer_id, PeerId, SPk},
+    shared_pk::SharedPk,
+};
+
+/// Default of [LazyKeys::cache]
+pub const DEFAULT_CACHE: usize = 1024;
+
+/// The `[lazy_keys]` section of the config
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LazyKeys {
+    /// Peers whose keys stay loaded at most, [DEFAULT_CACHE] by default
+    #[serde(default)]
+    pub cache: Option<usize>,
+    /// File the peer ids are kept in from one start to the next
+    #[serde(default)]
+    pub index: Option<PathBuf>,
+}
+
+impl LazyKeys {
+    pub fn validate(&self) -> Result<()> {
+        ensure!(self.cache != Some(0), "lazy_keys.cache must be at least 1");
+        Ok(())
+    }
+}
+
+/// What the index knows of a key file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Entry {
+    len: u64,
+    /// Nanoseconds since the epoch
+    modified: i128,
+    inode: u64,
+    /// Base64 encoded peer id
+    id: String,
+}
+
+impl Entry {
+    fn of(meta: &fs::Metadata, id: &PeerId) -> Self {
+        let (len, modified, inode) = stamp(meta);
+        Self {
+            len,
+            modified,
+            inode,
+            id: fmt_b64(&id.value).to_string(),
+        }
+    }
+
+    fn id(&self) -> Result<PeerId> {
+        let mut id = PeerId::zero();
+        b64_reader(self.id.as_bytes())
+            .read_exact(&mut id.value)
+            .context("peer id is not base64 encoded")?;
+        Ok(id)
+    }
+}
+
+/// Length, modification time and inode of a file
+fn stamp(meta: &fs::Metadata) -> (u64, i128, u64) {
+    let modified = meta.mtime() as i128 * 1_000_000_000 + meta.mtime_nsec() as i128;
+    (meta.len(), modified, meta.ino())
+}
+
+/// The peer ids of the key files, as far as they are known
+#[derive(Debug, Default)]
+pub struct Index {
+    path: Option<PathBuf>,
+    known: BTreeMap<PathBuf, Entry>,
+    /// The entries of the keys asked for, which are those stored
+    used: BTreeMap<PathBuf, Entry>,
+}
+
+impl Index {
+    /// Read the index at `path`; without `path`, all ids are computed
+    pub fn open(path: Option<&Path>) -> Result<Self> {
+        let known = match path.map(fs::read) {
+            None => BTreeMap::new(),
+            Some(Err(e)) if e.kind() == ErrorKind::NotFound => BTreeMap::new(),
+            Some(res) => {
+                let path = path.unwrap();
+                let data = res.with_context(|| format!("could not read key index {path:?}"))?;
+                match serde_json::from_slice(&data) {
+                    Ok(known) => known,
+                    // it is only a cache, so it is built anew
+                    Err(e) => {
+                        log::warn!("ignoring key index {path:?}, which is corrupted: {e}");
+                        BTreeMap::new()
+                    }
+                }
+            }
+        };
+        Ok(Self {
+            path: path.map(Path::to_owned),
+            known,
+            used: BTreeMap::new(),
+        })
+    }
+
+    /// The key in the file at `path`, loaded once it is needed
+    pub fn key(&mut self, path: &Path) -> Result<SharedPk> {
+        let meta = fs::metadata(path).with_context(|| format!("could not read {path:?}"))?;
+        let known = self
+            .known
+            .get(path)
+            .filter(|e| (e.len, e.modified, e.inode) == stamp(&meta));
+        let id = match known.map(Entry::id) {
+            Some(Ok(id)) => id,
+            _ => peer_id(&SharedPk::from(SPk::load(path)?))?,
+        };
+        self.used.insert(path.to_owned(), Entry::of(&meta, &id));
+        Ok(SharedPk::lazy(path, id))
+    }
+
+    /// Write the ids of the keys asked for to the index, if there is one and
+    /// it changed
+    pub fn store(&self) -> Result<()> {
+        let Some(path) = self.path.as_ref().filter(|_| self.used != self.known) else {
+            return Ok(());
+        };
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec(&self.used)?)
+            .and_then(|()| fs::rename(&tmp, path))
+            .with_context(|| format!("could not write key index {path:?}"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pqkem::{StaticKEM, KEM};
+
+    #[test]
+    fn ids_are_kept_in_the_index() {
+        rosenpass_sodium::init().unwrap();
+        let dir = std::env::temp_dir().join(format!("rp-lazy-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (pk_file, index) = (dir.join("peer.pk"), dir.join("index"));
+        let (mut sk, mut pk) = (crate::protocol::SSk::zero(), SPk::zero());
+        StaticKEM::keygen(sk.secret_mut(), pk.secret_mut()).unwrap();
+        fs::write(&pk_file, pk.secret()).unwrap();
+        let id = peer_id(&SharedPk::from(&pk)).unwrap();
+
+        let mut keys = Index::open(Some(&index)).unwrap();
+        let mut key = keys.key(&pk_file).unwrap();
+        assert!(!key.is_loaded());
+        assert_eq!(peer_id(&key).unwrap(), id);
+        keys.store().unwrap();
+
+        // known from the index, without reading the key
+        let mut keys = Index::open(Some(&index)).unwrap();
+        assert_eq!(keys.known[&pk_file].id().unwrap(), id);
+        assert_eq!(peer_id(&keys.key(&pk_file).unwrap()).unwrap(), id);
+
+        key.load().unwrap();
+        assert_eq!(key.value(), pk.secret());
+        key.unload();
+        assert!(!key.is_loaded());
+
+        // another key in the file is refused
+        StaticKEM::keygen(sk.secret_mut(), pk.secret_mut()).unwrap();
+        fs::write(&pk_file, pk.secret()).unwrap();
+        assert!(key.load().is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
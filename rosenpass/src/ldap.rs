@@ -0,0 +1,444 @@
+//! Peers provisioned from an LDAP directory
+//!
+//! Enterprises which manage the identity of their devices in a directory,
+//! like OpenLDAP or Active Directory, can have rosenpass take its peers from
+//! there instead of keeping a list of their own:
+//!
+//! ```toml
+//! [ldap]
+//! uri = "ldaps://ldap.example.com"
+//! base = "ou=devices,dc=example,dc=com"
+//! bind_dn = "cn=rosenpass,ou=services,dc=example,dc=com"
+//! password_file = "/etc/rosenpass/ldap-password"
+//! keys = "/var/lib/rosenpass/ldap"
+//! ca = "/etc/rosenpass/devices-ca.pk"
+//! device = "wg0"
+//! group = "devices"
+//! refresh = 300
+//! ```
+//!
+//! The directory is searched below `base` with `ldapsearch` from OpenLDAP,
+//! binding as `bind_dn` if given, or anonymously. Each entry matching
+//! `filter`, by default those with a fingerprint or a bundle, is a peer:
+//!
+//! - `rosenpassFingerprint` is the fingerprint of the public key of the
+//!   device (see [crate::fingerprint]), which is read from the file named
+//!   after the fingerprint without colons in `keys`, and refused unless it
+//!   matches.
+//! - `rosenpassBundle` is the URL of a bundle signed by the `ca` (see
+//!   [crate::ca]), which is fetched into `keys` with `curl`, and again once
+//!   it changed.
+//! - `rosenpassEndpoint` is the endpoint of the device, if it has one.
+//! - `rosenpassWireguardKey` is the public key of the WireGuard peer of the
+//!   device on the WireGuard `device`.
+//!
+//! Directories with another schema, like Active Directory, can name other
+//! attributes in `[ldap.attributes]`. The peers are members of the `group`,
+//! if one is given, which lends them everything else, like their
+//! `key_out_dir`.
+//!
+//! Every [Ldap::refresh] seconds, the directory is searched again and the
+//! peers are reconciled just like those of the [crate::peer_store]: peers
+//! whose entry is gone or changed are removed, new ones are added. An entry
+//! which can not be used, e.g. as its key file does not match its
+//! fingerprint, is skipped with a warning. The peers found last are kept in
+//! `keys` and taken while the directory can not be reached, so rosenpass
+//! also starts while it is down. Changes are only picked up if the config
+//! was read from a file.
+
+use anyhow::{bail, ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::hash_map::DefaultHasher,
+    collections::HashMap,
+    fs,
+    hash::{Hash, Hasher},
+    io::Read,
+    path::PathBuf,
+    process::Command,
+};
+
+use rosenpass_util::{b64::b64_reader, file::LoadValue};
+
+use crate::{
+    config::{RosenpassPeer, WireGuard},
+    fingerprint::Fingerprint,
+    protocol::SPk,
+};
+
+/// Default of [Ldap::refresh]
+pub const DEFAULT_REFRESH: u64 = 300;
+
+/// File in [Ldap::keys] the peers found last are kept in
+pub const CACHE: &str = "peers.json";
+
+/// Longest a bundle may take to download
+const FETCH_TIMEOUT_SECS: u32 = 30;
+
+/// The `[ldap]` section of the config
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Ldap {
+    pub uri: String,
+    pub base: String,
+    /// Search filter, by default entries with either a fingerprint or a
+    /// bundle
+    #[serde(default)]
+    pub filter: Option<String>,
+    #[serde(default)]
+    pub bind_dn: Option<String>,
+    /// File holding the password of `bind_dn`
+    #[serde(default)]
+    pub password_file: Option<PathBuf>,
+    /// Directory of the public keys and bundles of the devices
+    pub keys: PathBuf,
+    /// Public key of the CA bundles are signed by
+    #[serde(default)]
+    pub ca: Option<PathBuf>,
+    /// WireGuard device the WireGuard peers of the devices are on
+    #[serde(default)]
+    pub device: Option<String>,
+    #[serde(default)]
+    pub group: Option<String>,
+    /// Seconds between searches, [DEFAULT_REFRESH] by default
+    #[serde(default)]
+    pub refresh: Option<u64>,
+    #[serde(default)]
+    pub attributes: Attributes,
+}
+
+/// Names of the attributes of the entries
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Attributes {
+    pub fingerprint: String,
+    pub bundle: String,
+    pub endpoint: String,
+    pub wireguard: String,
+}
+
+impl Default for Attributes {
+    fn default() -> Self {
+        Self {
+            fingerprint: "rosenpassFingerprint".to_string(),
+            bundle: "rosenpassBundle".to_string(),
+            endpoint: "rosenpassEndpoint".to_string(),
+            wireguard: "rosenpassWireguardKey".to_string(),
+        }
+    }
+}
+
+/// An entry of the directory: its DN and its attributes, by their name in
+/// lower case
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Entry {
+    pub dn: String,
+    pub attrs: HashMap<String, Vec<String>>,
+}
+
+impl Entry {
+    fn get(&self, attr: &str) -> Option<&str> {
+        let values = self.attrs.get(&attr.to_ascii_lowercase())?;
+        values.first().map(String::as_str)
+    }
+}
+
+impl Ldap {
+    pub fn validate(&self) -> Result<()> {
+        ensure!(
+            self.refresh != Some(0),
+            "ldap refresh interval must be at least one second"
+        );
+        ensure!(
+            self.password_file.is_none() || self.bind_dn.is_some(),
+            "ldap password_file needs a bind_dn"
+        );
+        Ok(())
+    }
+
+    fn filter(&self) -> String {
+        match self.filter.as_ref() {
+            Some(filter) => filter.clone(),
+            None => format!(
+                "(|({}=*)({}=*))",
+                self.attributes.fingerprint, self.attributes.bundle
+            ),
+        }
+    }
+
+    /// The peers in the directory, or those found last if it can not be
+    /// searched
+    pub fn load(&self) -> Result<Vec<RosenpassPeer>> {
+        let cache = self.keys.join(CACHE);
+        let entries = match self.search() {
+            Ok(entries) => entries,
+            Err(e) => {
+                let cached = fs::read(&cache).with_context(|| {
+                    format!("{e:#}, and no peers were found in {:?} before", self.uri)
+                })?;
+                log::warn!("Taking the peers found before: {e:#}");
+                let mut peers: Vec<RosenpassPeer> = serde_json::from_slice(&cached)
+                    .with_context(|| format!("{cache:?} is corrupted"))?;
+                for peer in peers.iter_mut() {
+                    peer.from_ldap = true;
+                }
+                return Ok(peers);
+            }
+        };
+        let peers: Vec<_> = entries
+            .iter()
+            .filter_map(|entry| match self.peer(entry) {
+                Ok(peer) => Some(peer),
+                Err(e) => {
+                    log::warn!("Skipping LDAP entry {:?}: {e:#}", entry.dn);
+                    None
+                }
+            })
+            .collect();
+        let tmp = cache.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec(&peers)?)
+            .and_then(|()| fs::rename(&tmp, &cache))
+            .with_context(|| format!("could not write {cache:?}"))?;
+        Ok(peers)
+    }
+
+    /// All entries matching the filter
+    pub fn search(&self) -> Result<Vec<Entry>> {
+        let a = &self.attributes;
+        let mut cmd = Command::new("ldapsearch");
+        cmd.args([
+            "-LLL",
+            "-x",
+            "-o",
+            "ldif-wrap=no",
+            "-H",
+            &self.uri,
+            "-b",
+            &self.base,
+        ]);
+        if let Some(dn) = self.bind_dn.as_ref() {
+            cmd.args(["-D", dn]);
+        }
+        if let Some(file) = self.password_file.as_ref() {
+            cmd.arg("-y").arg(file);
+        }
+        cmd.arg(self.filter());
+        cmd.args([&a.fingerprint, &a.bundle, &a.endpoint, &a.wireguard]);
+        let out = cmd.output().context("could not run ldapsearch")?;
+        ensure!(
+            out.status.success(),
+            "ldapsearch failed to search {:?} with {}: {}",
+            self.uri,
+            out.status,
+            String::from_utf8_lossy(&out.stderr).trim()
+        );
+        parse_ldif(&String::from_utf8_lossy(&out.stdout))
+    }
+
+    /// The `[[peers]]` table `entry` stands for
+    fn peer(&self, entry: &Entry) -> Result<RosenpassPeer> {
+        let a = &self.attributes;
+        let mut peer = match (entry.get(&a.fingerprint), entry.get(&a.bundle)) {
+            (Some(fp), _) => {
+                let fp: Fingerprint = fp.parse()?;
+                let path = self.keys.join(fp.to_string().replace(':', ""));
+                let pk = SPk::load(&path)
+                    .with_context(|| format!("could not read the key {fp} from {path:?}"))?;
+                ensure!(
+                    Fingerprint::of_public_key(&pk)? == fp,
+                    "the key in {path:?} does not match fingerprint {fp}"
+                );
+                RosenpassPeer {
+                    public_key: path,
+                    ..Default::default()
+                }
+            }
+            (None, Some(url)) => {
+                let Some(ca) = self.ca.clone() else {
+                    bail!("bundles need the ca of the ldap section");
+                };
+                RosenpassPeer {
+                    public_key: self.fetch(url)?,
+                    ca: Some(ca),
+                    ..Default::default()
+                }
+            }
+            (None, None) => bail!("the entry has neither {} nor {}", a.fingerprint, a.bundle),
+        };
+        peer.endpoint = entry.get(&a.endpoint).map(str::to_string);
+        if let Some(key) = entry.get(&a.wireguard) {
+            let Some(device) = self.device.clone() else {
+                bail!("WireGuard peers need the device of the ldap section");
+            };
+            peer.wg = Some(WireGuard {
+                device,
+                peer: key.to_string(),
+                ..Default::default()
+            });
+        }
+        peer.group = self.group.clone();
+        peer.from_ldap = true;
+        Ok(peer)
+    }
+
+    /// Download the bundle at `url` unless it did not change, returning the
+    /// file it is kept in
+    fn fetch(&self, url: &str) -> Result<PathBuf> {
+        let mut hasher = DefaultHasher::new();
+        url.hash(&mut hasher);
+        let path = self.keys.join(format!("{:016x}.bundle", hasher.finish()));
+        let tmp = path.with_extension("tmp");
+        let mut cmd = Command::new("curl");
+        cmd.args(["-fsSL", "--max-time", &FETCH_TIMEOUT_SECS.to_string()]);
+        // only downloaded if it changed since
+        if path.exists() {
+            cmd.arg("-z").arg(&path);
+        }
+        let out = cmd
+            .arg("-o")
+            .arg(&tmp)
+            .arg(url)
+            .output()
+            .context("could not run curl")?;
+        ensure!(
+            out.status.success(),
+            "curl failed to fetch {url:?} with {}: {}",
+            out.status,
+            String::from_utf8_lossy(&out.stderr).trim()
+        );
+        if tmp.exists() {
+            fs::rename(&tmp, &path).with_context(|| format!("could not write {path:?}"))?;
+        }
+        ensure!(path.exists(), "{url:?} was not fetched");
+        Ok(path)
+    }
+}
+
+/// The entries in the output of `ldapsearch -LLL`
+pub fn parse_ldif(text: &str) -> Result<Vec<Entry>> {
+    // lines starting with a space continue the one before
+    let mut lines: Vec<String> = Vec::new();
+    for line in text.lines() {
+        match (line.strip_prefix(' '), lines.last_mut()) {
+            (Some(rest), Some(last)) if !last.is_empty() => last.push_str(rest),
+            _ => lines.push(line.to_string()),
+        }
+    }
+    let mut entries = Vec::new();
+    for record in lines.split(|l| l.is_empty()) {
+        let mut entry = Entry::default();
+        for line in record.iter().filter(|l| !l.starts_with('#')) {
+            let Some((attr, value)) = line.split_once(':') else {
+                bail!("malformed LDIF line {line:?}");
+            };
+            let value = match (value.strip_prefix(':'), value.strip_prefix('<')) {
+                (Some(b64), _) => {
+                    let mut decoded = Vec::new();
+                    b64_reader(b64.trim().as_bytes())
+                        .read_to_end(&mut decoded)
+                        .with_context(|| format!("{attr} is not base64 encoded"))?;
+                    String::from_utf8(decoded).with_context(|| format!("{attr} is not text"))?
+                }
+                // values by reference are not asked for
+                (None, Some(_)) => continue,
+                (None, None) => value.trim_start().to_string(),
+            };
+            match attr {
+                "dn" => entry.dn = value,
+                _ => entry
+                    .attrs
+                    .entry(attr.to_ascii_lowercase())
+                    .or_default()
+                    .push(value),
+            }
+        }
+        if !entry.dn.is_empty() {
+            entries.push(entry);
+        }
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pqkem::KEM;
+
+    #[test]
+    fn entries_are_peers() {
+        let ldif = "dn: cn=laptop-1,ou=devices,dc=example,dc=com\n\
+                    rosenpassFingerprint: 3fa1:09c2\n \
+                    :0000:0000:0000:0000:0000:0000\n\
+                    rosenpassEndpoint: 192.0.2.1:9999\n\
+                    \n\
+                    # a comment\n\
+                    dn:: Y249bGFwdG9wLTIsb3U9ZGV2aWNlcw==\n\
+                    rosenpassBundle: https://pki.example.com/laptop-2.bundle\n\
+                    rosenpasswireguardkey: d2cga2V5\n";
+        let entries = parse_ldif(ldif).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].dn, "cn=laptop-1,ou=devices,dc=example,dc=com");
+        let fp = entries[0].get("rosenpassFingerprint").unwrap();
+        assert_eq!(fp, "3fa1:09c2:0000:0000:0000:0000:0000:0000");
+        assert_eq!(entries[1].dn, "cn=laptop-2,ou=devices");
+        assert_eq!(entries[1].get("rosenpassWireguardKey"), Some("d2cga2V5"));
+        assert!(parse_ldif("dn: a\nno colon\n").is_err());
+
+        let ldap = Ldap {
+            uri: "ldap://localhost".to_string(),
+            base: "dc=example,dc=com".to_string(),
+            filter: None,
+            bind_dn: None,
+            password_file: None,
+            keys: std::env::temp_dir().join(format!("rp-ldap-{}", std::process::id())),
+            ca: None,
+            device: None,
+            group: Some("devices".to_string()),
+            refresh: None,
+            attributes: Attributes::default(),
+        };
+        assert_eq!(
+            ldap.filter(),
+            "(|(rosenpassFingerprint=*)(rosenpassBundle=*))"
+        );
+        // no key file, no CA for the bundle
+        assert!(ldap.peer(&entries[0]).is_err());
+        assert!(ldap.peer(&entries[1]).is_err());
+    }
+
+    #[test]
+    fn keys_are_found_by_fingerprint() {
+        rosenpass_sodium::init().unwrap();
+        let keys = std::env::temp_dir().join(format!("rp-ldap-keys-{}", std::process::id()));
+        fs::create_dir_all(&keys).unwrap();
+        let (mut sk, mut pk) = (crate::protocol::SSk::zero(), SPk::zero());
+        crate::pqkem::StaticKEM::keygen(sk.secret_mut(), pk.secret_mut()).unwrap();
+        let fp = Fingerprint::of_public_key(&pk).unwrap();
+        let path = keys.join(fp.to_string().replace(':', ""));
+        fs::write(&path, pk.secret()).unwrap();
+
+        let ldap: Ldap = toml::from_str(&format!(
+            "uri = 'ldap://localhost'\nbase = 'dc=example'\nkeys = {keys:?}\ndevice = 'wg0'"
+        ))
+        .unwrap();
+        let mut entry = Entry {
+            dn: "cn=laptop".to_string(),
+            ..Default::default()
+        };
+        entry
+            .attrs
+            .insert("rosenpassfingerprint".into(), vec![fp.to_string()]);
+        entry
+            .attrs
+            .insert("rosenpasswireguardkey".into(), vec!["d2cga2V5".into()]);
+        let peer = ldap.peer(&entry).unwrap();
+        assert!(peer.from_ldap);
+        assert_eq!(peer.public_key, path);
+        assert_eq!(peer.wg.unwrap().device, "wg0");
+
+        // another key under the name of the fingerprint
+        crate::pqkem::StaticKEM::keygen(sk.secret_mut(), pk.secret_mut()).unwrap();
+        fs::write(&path, pk.secret()).unwrap();
+        assert!(ldap.peer(&entry).is_err());
+        fs::remove_dir_all(&keys).unwrap();
+    }
+}
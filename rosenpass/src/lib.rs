@@ -3,13 +3,91 @@ pub mod sodium;
 pub mod coloring;
 #[rustfmt::skip]
 pub mod labeled_prf;
+pub mod age;
+pub mod alerts;
+pub mod api;
 pub mod app_server;
+pub mod audit;
+pub mod breaker;
+pub mod build_info;
+pub mod ca;
 pub mod cli;
 pub mod config;
+pub mod config_edit;
+pub mod config_schema;
+pub mod consumer;
+pub mod container;
+pub mod control;
+pub mod credential;
+pub mod dbus;
+pub mod dns;
+pub mod doctor;
+pub mod enrollment;
+pub mod events;
+pub mod exit;
+pub mod exporter;
+pub mod extensions;
+pub mod failover;
+pub mod fifo;
+pub mod fingerprint;
+pub mod fragment;
+pub mod ha;
+pub mod hedged;
+pub mod interface;
+pub mod keygen;
+pub mod keyshare;
+pub mod keystream;
+pub mod keywrap;
+pub mod lazy_keys;
+pub mod ldap;
+pub mod liveness;
+pub mod lockdown;
+pub mod mdns;
+pub mod memnet;
 pub mod msgs;
+pub mod nat;
+pub mod netns;
+pub mod nm;
+pub mod peer_store;
 pub mod pqkem;
 pub mod prftree;
+pub mod profile;
+#[cfg(kani)]
+mod proofs;
 pub mod protocol;
+#[cfg(feature = "quic")]
+pub mod quic;
+#[cfg(feature = "quic")]
+pub mod quic_crypto;
+#[cfg(feature = "quic")]
+pub mod quic_tls;
+pub mod quota;
+pub mod rekey;
+pub mod relay;
+pub mod rendezvous;
+pub mod reuseport;
+pub mod revocation;
+pub mod sched;
+pub mod selftest;
+pub mod serve;
+pub mod shared_pk;
+pub mod shed;
+pub mod sockopt;
+pub mod stagger;
+pub mod stale;
+pub mod stats;
+pub mod supervisor;
+pub mod timers;
+pub mod tofu;
+pub mod uapi;
+pub mod unix;
+pub mod upgrade;
+pub mod vault;
+pub mod watch;
+pub mod wg_import;
+pub mod wizard;
+pub mod workers;
+pub mod xfrm;
 
 #[derive(thiserror::Error, Debug)]
 pub enum RosenpassError {
@@ -0,0 +1,159 @@
+//! Detecting peers which stopped exchanging keys
+//!
+//! Peers renew their key every [crate::protocol::CryptoServer::rekey_after]
+//! seconds at the latest. A peer which let that window pass by more than
+//! [DeadPeerPolicy::grace] seconds, counted from its last key exchange or from
+//! the start of rosenpass, is considered dead until it completes a handshake
+//! again. Either transition is logged and handed to the configured hooks.
+
+use anyhow::{ensure, Context, Result};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+    thread,
+};
+
+use crate::protocol::Timing;
+
+/// Default of [DeadPeerPolicy::grace]
+pub const DEFAULT_GRACE: Timing = 30.0;
+
+/// Settings of dead peer detection; detection is disabled without them
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeadPeerPolicy {
+    /// Seconds a key exchange may be overdue before the peer is considered
+    /// dead; defaults to [DEFAULT_GRACE]
+    #[serde(default)]
+    pub grace: Option<f64>,
+
+    /// Command run on every event, with the details in its environment:
+    /// `ROSENPASS_EVENT`, `ROSENPASS_PEER` (the fingerprint),
+    /// `ROSENPASS_PEER_ID` and `ROSENPASS_TAGS` (comma separated)
+    #[serde(default)]
+    pub exec: Vec<String>,
+
+    /// URL every event is POSTed to as JSON, using `curl`
+    #[serde(default)]
+    pub webhook: Option<String>,
+
+    /// Replace the key of a dead peer in WireGuard with a random one right
+    /// away, instead of when it expires
+    #[serde(default)]
+    pub reset_psk: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Liveness {
+    /// The peer missed its rekey window
+    Dead,
+    /// A dead peer exchanged a key again
+    Alive,
+    /// Handshakes went unanswered too often, see [crate::breaker]
+    Unreachable,
+    /// An unreachable peer completed a handshake again
+    Reachable,
+}
+
+impl Liveness {
+    /// Log the peer with `fingerprint` changing to this state
+    pub fn log(self, name: &str) {
+        match self {
+            Liveness::Dead => warn!("peer {name} missed its rekey window"),
+            Liveness::Alive => info!("peer {name} exchanged a key again"),
+            Liveness::Unreachable => {
+                warn!("peer {name} does not answer, probing it from now on")
+            }
+            Liveness::Reachable => info!("peer {name} answers again"),
+        }
+    }
+}
+
+/// What the hooks are told about a peer changing its [Liveness]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerEvent {
+    pub event: Liveness,
+    /// Base64 encoded peer id
+    pub peer_id: String,
+    pub fingerprint: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub tags: Vec<String>,
+    /// Seconds since the last key exchange with the peer, if there was one
+    pub last_exchange_age: Option<f64>,
+}
+
+impl DeadPeerPolicy {
+    pub fn validate(&self) -> Result<()> {
+        ensure!(
+            self.grace.unwrap_or(DEFAULT_GRACE) >= 0.0,
+            "dead_peer.grace must not be negative"
+        );
+        Ok(())
+    }
+
+    /// Seconds after the last exchange a peer is considered dead, given the
+    /// rekey interval `rekey_after`
+    pub fn deadline(&self, rekey_after: Timing) -> Timing {
+        rekey_after + self.grace.unwrap_or(DEFAULT_GRACE)
+    }
+
+    /// Run the hooks for `ev` in the background
+    pub fn notify(&self, ev: PeerEvent) {
+        if self.exec.is_empty() && self.webhook.is_none() {
+            return;
+        }
+
+        let (exec, webhook) = (self.exec.clone(), self.webhook.clone());
+        thread::spawn(move || {
+            if !exec.is_empty() {
+                if let Err(e) = run_exec(&exec, &ev) {
+                    warn!("dead peer hook for peer {} failed: {e:#}", ev.fingerprint);
+                }
+            }
+            if let Some(url) = webhook {
+                if let Err(e) = post_webhook(&url, &ev) {
+                    warn!(
+                        "dead peer webhook for peer {} failed: {e:#}",
+                        ev.fingerprint
+                    );
+                }
+            }
+        });
+    }
+}
+
+fn run_exec(exec: &[String], ev: &PeerEvent) -> Result<()> {
+    let event = serde_json::to_value(ev.event)?;
+    let status = Command::new(&exec[0])
+        .args(&exec[1..])
+        .env("ROSENPASS_EVENT", event.as_str().unwrap_or_default())
+        .env("ROSENPASS_PEER", &ev.fingerprint)
+        .env("ROSENPASS_PEER_ID", &ev.peer_id)
+        .env("ROSENPASS_TAGS", ev.tags.join(","))
+        .stdin(Stdio::null())
+        .status()
+        .with_context(|| format!("could not run {:?}", exec[0]))?;
+    ensure!(status.success(), "{:?} failed with {status}", exec[0]);
+    Ok(())
+}
+
+fn post_webhook(url: &str, ev: &PeerEvent) -> Result<()> {
+    let mut child = Command::new("curl")
+        .args(["-fsS", "-m", "10", "-H", "Content-Type: application/json"])
+        .args(["--data-binary", "@-", url])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()
+        .context("could not run curl")?;
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(&serde_json::to_vec(ev)?)?;
+    let status = child.wait()?;
+    ensure!(status.success(), "curl failed with {status}");
+    Ok(())
+}
@@ -0,0 +1,95 @@
+//! Accepting handshakes of a peer from known networks only
+//!
+//! Rosenpass authenticates peers by their keys alone and normally follows a
+//! peer to whatever address its handshakes arrive from. Operators who know
+//! where their partners live can pin them there as an additional control: a
+//! locked down peer is only accepted from the addresses of its configured
+//! endpoint and from the [IpPrefix]es listed for it, and it never roams.
+//!
+//! Rejected messages are counted as
+//! [crate::stats::FailureCause::DisallowedSource].
+
+use anyhow::{ensure, Context, Result};
+use std::{net::IpAddr, str::FromStr};
+
+/// An address block like `192.0.2.0/24`; a plain address stands for itself
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpPrefix {
+    pub addr: IpAddr,
+    pub len: u8,
+}
+
+impl FromStr for IpPrefix {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (addr, len) = match s.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .with_context(|| format!("{s:?} is not an address or prefix"))?;
+        let max = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let len = match len {
+            Some(len) => len
+                .parse()
+                .with_context(|| format!("{s:?} has an invalid prefix length"))?,
+            None => max,
+        };
+        ensure!(len <= max, "{s:?} has a prefix longer than {max} bits");
+        Ok(Self { addr, len })
+    }
+}
+
+impl IpPrefix {
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        let bits = |a: IpAddr| match canonical(a) {
+            IpAddr::V4(a) => (u32::from(a) as u128, 32u32),
+            IpAddr::V6(a) => (u128::from(a), 128),
+        };
+        let ((net, net_width), (addr, width)) = (bits(self.addr), bits(addr));
+        if net_width != width {
+            return false;
+        }
+        let host_bits = width - (self.len as u32).min(width);
+        net.checked_shr(host_bits) == addr.checked_shr(host_bits)
+    }
+}
+
+/// `addr`, with IPv4 addresses mapped into IPv6 by dual-stack sockets
+/// turned back into IPv4
+pub fn canonical(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(a) => a.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(addr),
+        IpAddr::V4(_) => addr,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn prefixes() {
+        let net: IpPrefix = "192.0.2.0/24".parse().unwrap();
+        assert!(net.contains("192.0.2.77".parse().unwrap()));
+        assert!(net.contains("::ffff:192.0.2.77".parse().unwrap()));
+        assert!(!net.contains("192.0.3.1".parse().unwrap()));
+        assert!(!net.contains("2001:db8::1".parse().unwrap()));
+
+        let host: IpPrefix = "2001:db8::1".parse().unwrap();
+        assert_eq!(host.len, 128);
+        assert!(host.contains("2001:db8::1".parse().unwrap()));
+        assert!(!host.contains("2001:db8::2".parse().unwrap()));
+
+        let any: IpPrefix = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains("198.51.100.1".parse().unwrap()));
+
+        assert!("192.0.2.0/33".parse::<IpPrefix>().is_err());
+        assert!("example.org".parse::<IpPrefix>().is_err());
+    }
+}
@@ -1,22 +1,60 @@
+use anyhow::anyhow;
 use log::error;
-use rosenpass::cli::Cli;
+use rosenpass::cli::Args;
+use rosenpass::exit::{ErrorFormat, ErrorReport, Failure};
 use rosenpass_util::attempt;
 use std::process::exit;
 
-/// Catches errors, prints them through the logger, then exits
+/// Catches errors, prints them through the logger or as JSON, then exits
+/// with the status of their category
 pub fn main() {
-    env_logger::init();
+    // keys must not end up in a core dump
+    rosenpass::coloring::zeroize_on_panic();
+
+    let args = match Args::try_parse_checked() {
+        Ok(args) => args,
+        // help and version, or usage errors clap prints itself
+        Err(e) if !e.use_stderr() => e.exit(),
+        Err(e)
+            if ErrorFormat::from_args(&std::env::args().collect::<Vec<_>>())
+                == ErrorFormat::Text =>
+        {
+            e.exit()
+        }
+        Err(e) => {
+            // without the usage clap appends
+            let rendered = e.to_string();
+            let msg: Vec<_> = rendered
+                .lines()
+                .take_while(|l| !l.starts_with("Usage:"))
+                .map(str::trim)
+                .filter(|l| !l.is_empty())
+                .collect();
+            let msg = msg.join(" ");
+            let e = Failure::Usage.wrap(anyhow!("{}", msg.trim_start_matches("error: ")));
+            ErrorReport::new(&e).emit();
+            exit(Failure::Usage.code());
+        }
+    };
+    if let Some(command) = args.command.as_ref() {
+        command.init_logging();
+    }
 
     let res = attempt!({
+        rosenpass::hedged::install()?;
         rosenpass_sodium::init()?;
-        Cli::run()
+        match args.command {
+            Some(command) => command.run(),
+            None => Args::print_version(args.json),
+        }
     });
 
-    match res {
-        Ok(_) => {}
-        Err(e) => {
-            error!("{e}");
-            exit(1);
+    if let Err(e) = res {
+        let report = ErrorReport::new(&e);
+        match args.error_format {
+            ErrorFormat::Text => error!("{}", report.message),
+            ErrorFormat::Json => report.emit(),
         }
+        exit(report.code);
     }
 }
@@ -0,0 +1,284 @@
+//! Finding peers on the local network with multicast DNS
+//!
+//! With mDNS enabled, rosenpass announces itself to the multicast DNS group
+//! every [ANNOUNCE_INTERVAL] seconds, as an SRV record of the [instance] named
+//! after its fingerprint and pointing at the port it listens on. The
+//! announcements of configured peers become addresses to look for the peer
+//! at; all others are ignored, as are those of locked down peers (see
+//! [crate::lockdown]).
+//!
+//! Announcements are authenticated with the preshared keys of the peers:
+//! their TXT record holds the time they were sent at and, for every peer
+//! with a preshared key, a tag keyed with it over the name, port and time.
+//! Those of peers without a preshared key, without a valid tag or sent more
+//! than [FRESHNESS] seconds apart from our clock are ignored. Replaying a
+//! recent announcement from elsewhere only makes rosenpass send handshakes
+//! to the wrong address, where they fail, as only the holder of the
+//! configured keys can complete them. Only IPv4 is supported.
+
+use anyhow::{Context, Result};
+use rosenpass_constant_time::memeq;
+use std::{
+    io::{self, ErrorKind},
+    mem,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+};
+
+use crate::{
+    dns::{self, SrvRecord},
+    fingerprint::Fingerprint,
+    labeled_prf as lprf,
+    protocol::{SymKey, Timing},
+};
+
+/// Seconds between announcements
+pub const ANNOUNCE_INTERVAL: Timing = 30.0;
+
+/// The service all instances are announced under
+pub const SERVICE: &str = "_rosenpass._udp.local";
+
+const GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const PORT: u16 = 5353;
+/// Seconds others may cache an announcement for
+const TTL: u32 = 120;
+
+/// Seconds an announcement is taken for, before or after the time it
+/// claims to be sent at
+pub const FRESHNESS: f64 = TTL as f64;
+/// Length of the tags of announcements
+const TAG_LEN: usize = 16;
+/// Tags one announcement carries at most; with more peers there are more
+/// announcements
+const TAGS_PER_ANNOUNCEMENT: usize = 64;
+
+#[derive(Debug)]
+pub struct Mdns {
+    pub socket: mio::net::UdpSocket,
+    /// When to announce ourselves next
+    pub announce_at: Timing,
+}
+
+/// An announcement received
+#[derive(Debug)]
+pub struct Announcement {
+    /// The name of the instance announced, see [instance]
+    pub instance: String,
+    /// Where the instance listens
+    pub addr: SocketAddr,
+    /// The time it was sent at, in seconds since the Unix epoch
+    time: u64,
+    tags: Vec<[u8; TAG_LEN]>,
+}
+
+impl Announcement {
+    /// Whether the announcement was sent by the holder of `psk` at most
+    /// [FRESHNESS] seconds apart from `now`, in seconds since the Unix epoch
+    pub fn authentic(&self, psk: &SymKey, now: f64) -> bool {
+        if (now - self.time as f64).abs() > FRESHNESS {
+            return false;
+        }
+        let Ok(expected) = tag(psk, &self.instance, self.addr.port(), self.time) else {
+            return false;
+        };
+        self.tags
+            .iter()
+            .fold(false, |found, tag| memeq(tag, &expected) | found)
+    }
+}
+
+/// The tag keyed with `psk` for announcing `instance` at `port`, at `time`
+fn tag(psk: &SymKey, instance: &str, port: u16, time: u64) -> Result<[u8; TAG_LEN]> {
+    let value = lprf::mdns()?
+        .mix_secret(psk.clone())?
+        .mix(instance.as_bytes())?
+        .mix(&port.to_be_bytes())?
+        .mix(&time.to_be_bytes())?
+        .into_secret();
+    Ok(value.secret()[..TAG_LEN].try_into()?)
+}
+
+/// The name the peer with the fingerprint `fp` is announced as
+pub fn instance(fp: &Fingerprint) -> String {
+    let hex: String = fp.0.iter().map(|b| format!("{b:02x}")).collect();
+    format!("{hex}.{SERVICE}")
+}
+
+impl Mdns {
+    /// Join the multicast DNS group, sharing its port with other responders
+    /// on the host
+    pub fn bind() -> Result<Self> {
+        let sock = shared_socket(PORT).context("could not bind the multicast DNS port")?;
+        sock.join_multicast_v4(&GROUP, &Ipv4Addr::UNSPECIFIED)
+            .context("could not join the multicast DNS group")?;
+        sock.set_nonblocking(true)?;
+        Ok(Self {
+            socket: mio::net::UdpSocket::from_std(sock),
+            announce_at: 0.0,
+        })
+    }
+
+    /// Announce that the instance with the fingerprint `me` listens on
+    /// `port`, to the peers with the preshared keys `psks`; `now` is the time
+    /// in seconds since the Unix epoch
+    pub fn announce(&self, me: &Fingerprint, port: u16, psks: &[&SymKey], now: f64) -> Result<()> {
+        for msg in announcements(me, port, psks, now)? {
+            self.socket
+                .send_to(&msg, SocketAddrV4::new(GROUP, PORT).into())?;
+        }
+        Ok(())
+    }
+
+    /// Drain the socket; returns the announcements of instances received
+    pub fn receive(&self) -> Result<Vec<Announcement>> {
+        let mut found = Vec::new();
+        let mut buf = [0u8; 1500];
+        loop {
+            let (len, from) = match self.socket.recv_from(&mut buf) {
+                Ok(r) => r,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(found),
+                Err(e) => return Err(e.into()),
+            };
+            found.extend(parse(&buf[..len], from));
+        }
+    }
+}
+
+/// The messages announcing `me` at `port` to the peers with `psks`
+fn announcements(me: &Fingerprint, port: u16, psks: &[&SymKey], now: f64) -> Result<Vec<Vec<u8>>> {
+    let name = instance(me);
+    let record = SrvRecord {
+        target: format!("{}.local", &name[..name.find('.').unwrap_or(0)]),
+        name,
+        priority: 0,
+        weight: 0,
+        port,
+    };
+    let time = now as u64;
+    let stamp = format!("t={time}");
+    let mut msgs = Vec::new();
+    for chunk in psks.chunks(TAGS_PER_ANNOUNCEMENT) {
+        let tags = chunk
+            .iter()
+            .map(|psk| tag(psk, &record.name, port, time))
+            .collect::<Result<Vec<_>>>()?;
+        let mut txt = vec![stamp.as_bytes()];
+        txt.extend(tags.iter().map(|t| &t[..]));
+        msgs.push(dns::encode_srv_announcement(&record, &txt, TTL)?);
+    }
+    Ok(msgs)
+}
+
+/// The announcements in the message `msg` from `from`
+fn parse(msg: &[u8], from: SocketAddr) -> Vec<Announcement> {
+    // queries and answers about anything else are none of our business
+    let (Ok(records), Ok(txts)) = (dns::parse_response(msg, 0), dns::parse_txt_strings(msg, 0))
+    else {
+        return Vec::new();
+    };
+    let mut found = Vec::new();
+    for r in records.into_iter().filter(|r| r.name.ends_with(SERVICE)) {
+        let strings = txts
+            .iter()
+            .find(|(name, _)| *name == r.name)
+            .map_or(&[][..], |(_, strings)| &strings[..]);
+        let time = strings.first().and_then(|s| {
+            std::str::from_utf8(s)
+                .ok()?
+                .strip_prefix("t=")?
+                .parse()
+                .ok()
+        });
+        let Some(time) = time else {
+            continue;
+        };
+        let tags = strings[1..]
+            .iter()
+            .filter_map(|s| s[..].try_into().ok())
+            .collect();
+        found.push(Announcement {
+            addr: SocketAddr::new(from.ip(), r.port),
+            instance: r.name,
+            time,
+            tags,
+        });
+    }
+    found
+}
+
+/// A UDP socket bound to `port` on all IPv4 addresses, with address reuse
+/// enabled
+fn shared_socket(port: u16) -> io::Result<std::net::UdpSocket> {
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    let sock = std::net::UdpSocket::from(fd);
+
+    let one: libc::c_int = 1;
+    for opt in [libc::SO_REUSEADDR, libc::SO_REUSEPORT] {
+        let res = unsafe {
+            libc::setsockopt(
+                sock.as_raw_fd(),
+                libc::SOL_SOCKET,
+                opt,
+                &one as *const _ as *const libc::c_void,
+                mem::size_of_val(&one) as libc::socklen_t,
+            )
+        };
+        if res != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    let mut addr: libc::sockaddr_in = unsafe { mem::zeroed() };
+    addr.sin_family = libc::AF_INET as libc::sa_family_t;
+    addr.sin_port = port.to_be();
+    let res = unsafe {
+        libc::bind(
+            sock.as_raw_fd(),
+            &addr as *const _ as *const libc::sockaddr,
+            mem::size_of_val(&addr) as libc::socklen_t,
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(sock)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn announcements_are_authenticated() {
+        rosenpass_sodium::init().unwrap();
+        let me = Fingerprint([7; 16]);
+        let (psk, other) = (SymKey::random(), SymKey::random());
+        let from: SocketAddr = "192.0.2.1:5353".parse().unwrap();
+
+        let psks: Vec<&SymKey> = std::iter::repeat_n(&other, TAGS_PER_ANNOUNCEMENT)
+            .chain([&psk])
+            .collect();
+        let msgs = announcements(&me, 9999, &psks, 1000.0).unwrap();
+        assert_eq!(msgs.len(), 2);
+        let found = parse(&msgs[1], from);
+        assert_eq!(found.len(), 1);
+        let a = &found[0];
+        assert_eq!(a.instance, instance(&me));
+        assert_eq!(a.addr, "192.0.2.1:9999".parse().unwrap());
+
+        assert!(a.authentic(&psk, 1000.0 + FRESHNESS));
+        assert!(!a.authentic(&psk, 1001.0 + FRESHNESS));
+        assert!(!a.authentic(&other, 1000.0));
+        assert!(parse(&msgs[0], from)[0].authentic(&other, 1000.0));
+        assert!(!parse(&msgs[0], from)[0].authentic(&psk, 1000.0));
+
+        // the tag covers the port
+        let mut moved = parse(&msgs[1], from).pop().unwrap();
+        moved.addr.set_port(9998);
+        assert!(!moved.authentic(&psk, 1000.0));
+    }
+}
@@ -0,0 +1,314 @@
+//! An in-memory network for testing several instances in one process
+//!
+//! [AppServer::in_memory] creates a server whose sockets are bound on a
+//! [MemNet] rather than on the host, so integration tests need no free ports
+//! and do not wait for time to pass: datagrams are handed between the servers
+//! on the net, and all of them follow the [ManualClock] of the net.
+//! [MemNet::run] drives the servers like the event loop would, moving the
+//! clock forward whenever none of them has anything left to do:
+//!
+//! ```text
+//! let net = MemNet::default();
+//! let mut a = AppServer::in_memory(ska, pka, &net, vec![addr_a], Verbosity::Quiet)?;
+//! let mut b = AppServer::in_memory(skb, pkb, &net, vec![addr_b], Verbosity::Quiet)?;
+//! // add the peers, subscribe to their events, then
+//! net.run(&mut [&mut a, &mut b], 600.0)?;
+//! ```
+//!
+//! Keys are taken from [AppServer::subscribe] instead of a key broker.
+//! Datagrams to addresses nothing is bound to are dropped, just like those
+//! to and from an address [MemNet::set_reachable] took off the net.
+
+use anyhow::{ensure, Result};
+use rosenpass_util::time::{ManualClock, Timebase};
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    app_server::AppServer,
+    protocol::{MsgBuf, Timing},
+};
+
+#[derive(Debug)]
+struct Port {
+    queue: VecDeque<(Vec<u8>, SocketAddr)>,
+    waker: Arc<mio::Waker>,
+    reachable: bool,
+}
+
+/// The sockets bound on a net and the clock of the net; clones share both
+#[derive(Debug, Clone, Default)]
+pub struct MemNet {
+    ports: Arc<Mutex<HashMap<SocketAddr, Port>>>,
+    clock: ManualClock,
+}
+
+impl MemNet {
+    /// Bind `addr` on the net; `waker` is woken whenever a datagram arrives
+    pub fn bind(&self, addr: SocketAddr, waker: Arc<mio::Waker>) -> io::Result<MemSocket> {
+        let mut ports = self.ports.lock().unwrap();
+        if ports.contains_key(&addr) {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{addr} is bound already"),
+            ));
+        }
+        let port = Port {
+            queue: VecDeque::new(),
+            waker,
+            reachable: true,
+        };
+        ports.insert(addr, port);
+        Ok(MemSocket {
+            net: self.clone(),
+            addr,
+        })
+    }
+
+    /// The clock all servers on the net follow
+    pub fn clock(&self) -> &ManualClock {
+        &self.clock
+    }
+
+    pub fn timebase(&self) -> Timebase {
+        Timebase::manual(self.clock.clone())
+    }
+
+    /// Drop all datagrams to and from `addr` until it is reachable again
+    pub fn set_reachable(&self, addr: SocketAddr, reachable: bool) {
+        if let Some(port) = self.ports.lock().unwrap().get_mut(&addr) {
+            port.reachable = reachable;
+        }
+    }
+
+    /// Drive `servers` for `secs` seconds on the clock of the net
+    pub fn run(&self, servers: &mut [&mut AppServer], secs: Timing) -> Result<()> {
+        let until = self.clock.now() + secs;
+        let (mut rx, mut tx) = (MsgBuf::zero(), MsgBuf::zero());
+        loop {
+            let left = until - self.clock.now();
+            let mut idle = left;
+            let mut busy = false;
+            for srv in servers.iter_mut() {
+                match srv.poll_for(&mut *rx, false)? {
+                    Ok(ev) => {
+                        busy = true;
+                        ensure!(srv.process_event(ev, &mut rx, &mut tx)?, "a server stopped");
+                    }
+                    Err(timeout) => idle = idle.min(timeout),
+                }
+            }
+            if busy {
+                continue;
+            }
+            if left <= 0.0 {
+                return Ok(());
+            }
+            self.clock.advance(idle);
+        }
+    }
+
+    fn send(&self, from: SocketAddr, to: SocketAddr, buf: &[u8]) {
+        let mut ports = self.ports.lock().unwrap();
+        if !ports.get(&from).is_some_and(|p| p.reachable) {
+            return;
+        }
+        if let Some(port) = ports.get_mut(&to).filter(|p| p.reachable) {
+            port.queue.push_back((buf.to_vec(), from));
+            // a server which is gone got the datagram just as well
+            let _ = port.waker.wake();
+        }
+    }
+}
+
+/// A socket bound on a [MemNet], with the interface of a UDP socket
+#[derive(Debug)]
+pub struct MemSocket {
+    net: MemNet,
+    addr: SocketAddr,
+}
+
+impl MemSocket {
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+
+    pub fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        self.net.send(self.addr, addr, buf);
+        Ok(buf.len())
+    }
+
+    /// Like [std::net::UdpSocket::recv_from] on a non-blocking socket
+    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let mut ports = self.net.ports.lock().unwrap();
+        let queue = &mut ports.get_mut(&self.addr).unwrap().queue;
+        match queue.pop_front() {
+            Some((msg, from)) => {
+                // the rest of a datagram too large for the buffer is lost
+                let len = msg.len().min(buf.len());
+                buf[..len].copy_from_slice(&msg[..len]);
+                Ok((len, from))
+            }
+            None => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+impl Drop for MemSocket {
+    fn drop(&mut self) {
+        self.net.ports.lock().unwrap().remove(&self.addr);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        app_server::AppPeerPtr,
+        config::Verbosity,
+        events::Event,
+        pqkem::{StaticKEM, KEM},
+        protocol::{SPk, SSk, REJECT_AFTER_TIME},
+    };
+
+    fn keygen() -> (SSk, SPk) {
+        let (mut sk, mut pk) = (SSk::zero(), SPk::zero());
+        StaticKEM::keygen(sk.secret_mut(), pk.secret_mut()).unwrap();
+        (sk, pk)
+    }
+
+    #[test]
+    fn exchanges_keys_in_memory() {
+        rosenpass_sodium::init().unwrap();
+        let ((ska, pka), (skb, pkb)) = (keygen(), keygen());
+        let addr_a: SocketAddr = "192.0.2.1:9999".parse().unwrap();
+        let addr_b: SocketAddr = "192.0.2.2:9999".parse().unwrap();
+        let net = MemNet::default();
+        let mut a =
+            AppServer::in_memory(ska, pka.clone(), &net, vec![addr_a], Verbosity::Quiet).unwrap();
+        let mut b =
+            AppServer::in_memory(skb, pkb.clone(), &net, vec![addr_b], Verbosity::Quiet).unwrap();
+        assert!(net.bind(addr_a, a.waker.clone()).is_err());
+        a.add_peer(None, pkb, None, None, Some(addr_b.to_string()), vec![])
+            .unwrap();
+        b.add_peer(None, pka, None, None, None, vec![]).unwrap();
+        let (mut events_a, mut events_b) = (a.subscribe(), b.subscribe());
+        let keys = |events: &mut crate::events::EventStream| {
+            std::iter::from_fn(|| events.try_next())
+                .filter_map(|ev| match ev {
+                    Event::KeyEstablished { key, .. } => Some(key.secret().to_vec()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // ten minutes take a few handshakes, rather than ten minutes
+        net.run(&mut [&mut a, &mut b], 600.0).unwrap();
+        let exchanged = keys(&mut events_a);
+        assert!(exchanged.len() >= 4, "{} keys exchanged", exchanged.len());
+        assert_eq!(exchanged, keys(&mut events_b));
+        assert!(net.clock().now() >= 600.0);
+
+        // the last key runs out once b can not be reached
+        net.set_reachable(addr_b, false);
+        net.run(&mut [&mut a, &mut b], REJECT_AFTER_TIME).unwrap();
+        let expired = std::iter::from_fn(|| events_a.try_next())
+            .any(|ev| matches!(ev, Event::KeyExpired { .. }));
+        assert!(expired);
+    }
+
+    fn keys_with(events: &mut crate::events::EventStream, peer: AppPeerPtr) -> Vec<Vec<u8>> {
+        std::iter::from_fn(|| events.try_next())
+            .filter_map(|ev| match ev {
+                Event::KeyEstablished { peer: p, key } if p.0 == peer.0 => {
+                    Some(key.secret().to_vec())
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn queued(net: &MemNet, addr: SocketAddr) -> Vec<Vec<u8>> {
+        let ports = net.ports.lock().unwrap();
+        ports[&addr]
+            .queue
+            .iter()
+            .map(|(msg, _)| msg.clone())
+            .collect()
+    }
+
+    #[test]
+    fn relays_handshakes() {
+        rosenpass_sodium::init().unwrap();
+        let ((ska, pka), (skb, pkb), (skc, pkc)) = (keygen(), keygen(), keygen());
+        let addr_a: SocketAddr = "192.0.2.1:9999".parse().unwrap();
+        let addr_b: SocketAddr = "192.0.2.2:9999".parse().unwrap();
+        let addr_c: SocketAddr = "192.0.2.3:9999".parse().unwrap();
+        let net = MemNet::default();
+        let server = |sk, pk, addr| {
+            AppServer::in_memory(sk, pk, &net, vec![addr], Verbosity::Quiet).unwrap()
+        };
+        let mut a = server(ska, pka.clone(), addr_a);
+        let mut b = server(skb, pkb.clone(), addr_b);
+        let mut c = server(skc, pkc.clone(), addr_c);
+
+        // a and c only know how to reach the relay b
+        b.relay_server = true;
+        b.add_peer(None, pka.clone(), None, None, None, vec![])
+            .unwrap();
+        b.add_peer(None, pkc.clone(), None, None, None, vec![])
+            .unwrap();
+        let relay = Some(addr_b.to_string());
+        let b_at_a = a
+            .add_peer(None, pkb.clone(), None, None, relay.clone(), vec![])
+            .unwrap();
+        // where c would be, were it not behind a NAT
+        let hidden = Some("192.0.2.30:9999".to_string());
+        let c_at_a = a.add_peer(None, pkc, None, None, hidden, vec![]).unwrap();
+        let b_at_c = c.add_peer(None, pkb, None, None, relay, vec![]).unwrap();
+        let a_at_c = c.add_peer(None, pka, None, None, None, vec![]).unwrap();
+        a.peers[b_at_a.0].relay = true;
+        c.peers[b_at_c.0].relay = true;
+        let (mut events_a, mut events_c) = (a.subscribe(), c.subscribe());
+
+        net.run(&mut [&mut a, &mut b, &mut c], 600.0).unwrap();
+        let exchanged = keys_with(&mut events_a, c_at_a);
+        assert!(exchanged.len() >= 4, "{} keys exchanged", exchanged.len());
+        assert_eq!(exchanged, keys_with(&mut events_c, a_at_c));
+        assert_eq!(a.peers[c_at_a.0].via_relay.map(|r| r.0), Some(b_at_a.0));
+
+        // a handshake with c alone, so the session with b stays the same,
+        // stopped on its way to b
+        let to_c = a.peer_name(c_at_a);
+        a.rekey(&to_c, "test").unwrap();
+        net.run(&mut [&mut a], 0.0).unwrap();
+        let relayed = queued(&net, addr_b);
+        assert!(!relayed.is_empty());
+        net.run(&mut [&mut a, &mut b, &mut c], 10.0).unwrap();
+        assert!(!keys_with(&mut events_a, c_at_a).is_empty());
+
+        // replayed, its messages are not forwarded to c again
+        for msg in relayed.iter() {
+            net.send(addr_a, addr_b, msg);
+        }
+        net.run(&mut [&mut b], 0.0).unwrap();
+        assert!(queued(&net, addr_c).is_empty());
+
+        // nor are those for a peer the relay does not know
+        let (skd, pkd) = keygen();
+        let addr_d: SocketAddr = "192.0.2.4:9999".parse().unwrap();
+        let _d = server(skd, pkd.clone(), addr_d);
+        let d_at_a = a.add_peer(None, pkd, None, None, None, vec![]).unwrap();
+        net.run(&mut [&mut a], 0.0).unwrap();
+        assert_eq!(a.peers[d_at_a.0].via_relay.map(|r| r.0), Some(b_at_a.0));
+        assert!(!queued(&net, addr_b).is_empty());
+        net.run(&mut [&mut b], 0.0).unwrap();
+        for addr in [addr_a, addr_c, addr_d] {
+            assert!(queued(&net, addr).is_empty());
+        }
+    }
+}
@@ -44,8 +44,10 @@
 //! ```
 
 use super::RosenpassError;
-use crate::{pqkem::*, sodium};
+use crate::{extensions, pqkem::*, sodium};
 use rosenpass_ciphers::{aead, xaead};
+use serde::Serialize;
+use std::collections::BTreeMap;
 
 // Macro magic ////////////////////////////////////////////////////////////////
 
@@ -191,6 +193,10 @@ macro_rules! data_lense(
         impl<__ContainerType $(, $( $generic: LenseView ),+ )? > LenseView for $type<__ContainerType $(, $( $generic ),+ )? >{
             /// Number of bytes required to store this type in binary format
             const LEN: usize = $( $len + )+ 0;
+
+            fn fields() -> Vec<(&'static str, usize)> {
+                vec![ $( (stringify!($field), $len) ),+ ]
+            }
         }
 
         /// Extension trait to allow checked creation of a lense over
@@ -254,6 +260,9 @@ macro_rules! data_lense(
 /// Common trait shared by all Lenses
 pub trait LenseView {
     const LEN: usize;
+
+    /// Names and sizes in bytes of all fields, in the order they appear on the wire
+    fn fields() -> Vec<(&'static str, usize)>;
 }
 
 data_lense! { Envelope<M> :=
@@ -327,6 +336,41 @@ data_lense! { Biscuit :=
     ck: sodium::KEY_SIZE
 }
 
+data_lense! { Fragment :=
+    /// Random id shared by all fragments of one message
+    msg_id: 4,
+    /// Position of this fragment, starting at zero
+    index: 1,
+    /// Number of fragments the message was split into
+    count: 1,
+    /// Number of bytes of `data` in use, big endian
+    len: 2,
+    /// Part of the fragmented message, padded with zeros
+    data: FRAGMENT_DATA_LEN
+}
+
+data_lense! { Rendezvous :=
+    /// Session id, as with [EmptyData]
+    sid: 4,
+    /// Nonce
+    ctr: 8,
+    /// Encrypted [crate::rendezvous::RendezvousMsg]
+    payload: RENDEZVOUS_PAYLOAD_LEN + aead::TAG_LEN
+}
+
+data_lense! { Relay :=
+    /// Session id, as with [EmptyData]
+    sid: 4,
+    /// Nonce
+    ctr: 8,
+    /// Id of the peer to relay to, or of the peer relayed from
+    peer: 32,
+    /// A [Fragment] message, sealed for the peer at the other end
+    data: RELAY_DATA_LEN,
+    /// Authenticates the above with the session
+    auth: aead::TAG_LEN
+}
+
 data_lense! { DataMsg :=
     dummy: 4
 }
@@ -353,6 +397,16 @@ pub const WIRE_ENVELOPE_LEN: usize = 1 + 3 + 16 + 16; // TODO verify this
 /// Size required to fit any message in binary form
 pub const MAX_MESSAGE_LEN: usize = 2500; // TODO fix this
 
+/// Bytes of a fragmented message carried by one [Fragment]; chosen so a
+/// sealed fragment fits into a 576 byte IPv4 datagram
+pub const FRAGMENT_DATA_LEN: usize = 480;
+
+/// Bytes of the plain text carried by a [Rendezvous] message
+pub const RENDEZVOUS_PAYLOAD_LEN: usize = 51;
+
+/// Bytes of the sealed [Fragment] carried by a [Relay] message
+pub const RELAY_DATA_LEN: usize = <Envelope<(), Fragment<()>> as LenseView>::LEN;
+
 /// Recognized message types
 #[repr(u8)]
 #[derive(Hash, PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy)]
@@ -363,6 +417,13 @@ pub enum MsgType {
     EmptyData = 0x84,
     DataMsg = 0x85,
     CookieReply = 0x86,
+    Fragment = 0x87,
+    /// Same layout as [EmptyData], sent on a live session to keep NAT mappings open
+    Keepalive = 0x88,
+    /// Talking to a rendezvous server, see [crate::rendezvous]
+    Rendezvous = 0x89,
+    /// A message relayed for another peer, see [crate::relay]
+    Relay = 0x8a,
 }
 
 impl TryFrom<u8> for MsgType {
@@ -376,6 +437,10 @@ impl TryFrom<u8> for MsgType {
             0x84 => MsgType::EmptyData,
             0x85 => MsgType::DataMsg,
             0x86 => MsgType::CookieReply,
+            0x87 => MsgType::Fragment,
+            0x88 => MsgType::Keepalive,
+            0x89 => MsgType::Rendezvous,
+            0x8a => MsgType::Relay,
             _ => return Err(RosenpassError::InvalidMessageType(value)),
         })
     }
@@ -387,6 +452,237 @@ pub const BISCUIT_PT_LEN: usize = Biscuit::<()>::LEN;
 /// Length in bytes of an encrypted Biscuit (cipher text)
 pub const BISCUIT_CT_LEN: usize = BISCUIT_PT_LEN + xaead::NONCE_LEN + xaead::TAG_LEN;
 
+// Schema /////////////////////////////////////////////////////////////////////
+
+/// Description of a single field inside of a lense; offsets are absolute,
+/// i.e. counted from the start of the message
+#[derive(Debug, Serialize)]
+pub struct FieldSchema {
+    pub name: &'static str,
+    pub offset: usize,
+    pub len: usize,
+    /// Sub-fields, for fields that are lenses themselves (e.g. the payload)
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<FieldSchema>,
+}
+
+/// Layout of one message as it appears on the wire
+#[derive(Debug, Serialize)]
+pub struct MessageSchema {
+    pub name: String,
+    pub msg_type: u8,
+    pub len: usize,
+    pub fields: Vec<FieldSchema>,
+}
+
+/// Machine readable description of the wire format
+///
+/// Intended for external consumers such as packet dissectors or third party
+/// implementations, so they can be kept in sync with this module.
+#[derive(Debug, Serialize)]
+pub struct WireSchema {
+    pub constants: BTreeMap<&'static str, usize>,
+    pub message_types: BTreeMap<String, u8>,
+    pub messages: Vec<MessageSchema>,
+    /// Message types without a layout, since no peer sends or accepts them
+    /// yet, with the reason
+    pub unsupported: BTreeMap<String, &'static str>,
+    /// Plain text layout of the encrypted biscuit
+    pub biscuit: Vec<FieldSchema>,
+    pub extension_area: ExtensionAreaSchema,
+    /// Messages carrying parts of or whole other messages
+    pub framing: Vec<FramingSchema>,
+}
+
+/// Layout of the extension area which may follow a handshake message, see
+/// [extensions]
+#[derive(Debug, Serialize)]
+pub struct ExtensionAreaSchema {
+    /// Messages which may be followed by an extension area, if the message
+    /// they answer advertised the `EXTENSIONS` feature
+    pub follows: Vec<String>,
+    /// Envelope field holding the sender's features, big endian
+    pub features_field: &'static str,
+    pub features: BTreeMap<&'static str, extensions::Features>,
+    /// Fields in front of the extensions, offsets counted from the end of
+    /// the message; `len` counts the bytes of all extensions
+    pub header: Vec<FieldSchema>,
+    /// Fields of each extension, offsets counted from its start; `len` bytes
+    /// of data follow
+    pub extension: Vec<FieldSchema>,
+    /// Fields behind the extensions, offsets counted from the end of the
+    /// last one; `mac` covers the message and the area in front of it
+    pub trailer: Vec<FieldSchema>,
+}
+
+/// A message whose payload field holds other wire data
+#[derive(Debug, Serialize)]
+pub struct FramingSchema {
+    pub message: String,
+    pub field: &'static str,
+    /// What the field holds
+    pub carries: &'static str,
+}
+
+fn lense_schema<L: LenseView>(offset: usize) -> Vec<FieldSchema> {
+    fields_at(offset, &L::fields())
+}
+
+fn fields_at(mut offset: usize, fields: &[(&'static str, usize)]) -> Vec<FieldSchema> {
+    fields
+        .iter()
+        .map(|&(name, len)| {
+            let f = FieldSchema {
+                name,
+                offset,
+                len,
+                fields: vec![],
+            };
+            offset += len;
+            f
+        })
+        .collect()
+}
+
+fn message_schema<M: LenseView>(msg_type: MsgType) -> MessageSchema {
+    let mut fields = lense_schema::<Envelope<(), M>>(0);
+    for f in fields.iter_mut().filter(|f| f.name == "payload") {
+        f.fields = lense_schema::<M>(f.offset);
+    }
+    MessageSchema {
+        name: format!("{msg_type:?}"),
+        msg_type: msg_type as u8,
+        len: <Envelope<(), M> as LenseView>::LEN,
+        fields,
+    }
+}
+
+/// Describe all message layouts, field offsets and constants
+pub fn wire_schema() -> WireSchema {
+    use MsgType::*;
+
+    let constants = BTreeMap::from([
+        ("SESSION_ID_LEN", SESSION_ID_LEN),
+        ("BISCUIT_ID_LEN", BISCUIT_ID_LEN),
+        ("BISCUIT_PT_LEN", BISCUIT_PT_LEN),
+        ("BISCUIT_CT_LEN", BISCUIT_CT_LEN),
+        ("MAC_SIZE", sodium::MAC_SIZE),
+        ("MAX_MESSAGE_LEN", MAX_MESSAGE_LEN),
+        ("FRAGMENT_DATA_LEN", FRAGMENT_DATA_LEN),
+        ("RENDEZVOUS_PAYLOAD_LEN", RENDEZVOUS_PAYLOAD_LEN),
+        ("RELAY_DATA_LEN", RELAY_DATA_LEN),
+        ("EXTENSION_AREA_OVERHEAD", extensions::AREA_OVERHEAD),
+        ("EXTENSION_HEADER_LEN", extensions::EXTENSION_HEADER_LEN),
+    ]);
+
+    let message_types = [
+        InitHello,
+        RespHello,
+        InitConf,
+        EmptyData,
+        DataMsg,
+        CookieReply,
+        Fragment,
+        Keepalive,
+        Rendezvous,
+        Relay,
+    ]
+    .into_iter()
+    .map(|t| (format!("{t:?}"), t as u8))
+    .collect();
+
+    WireSchema {
+        constants,
+        message_types,
+        messages: vec![
+            message_schema::<self::InitHello<()>>(InitHello),
+            message_schema::<self::RespHello<()>>(RespHello),
+            message_schema::<self::InitConf<()>>(InitConf),
+            message_schema::<self::EmptyData<()>>(EmptyData),
+            message_schema::<self::Fragment<()>>(Fragment),
+            message_schema::<self::EmptyData<()>>(Keepalive),
+            message_schema::<self::Rendezvous<()>>(Rendezvous),
+            message_schema::<self::Relay<()>>(Relay),
+        ],
+        unsupported: BTreeMap::from([
+            (format!("{DataMsg:?}"), "reserved, not implemented"),
+            (format!("{CookieReply:?}"), "reserved, not implemented"),
+        ]),
+        biscuit: lense_schema::<Biscuit<()>>(0),
+        extension_area: ExtensionAreaSchema {
+            follows: [RespHello, InitConf, EmptyData]
+                .iter()
+                .map(|t| format!("{t:?}"))
+                .collect(),
+            features_field: "reserved",
+            features: BTreeMap::from([
+                ("EXTENSIONS", extensions::EXTENSIONS),
+                ("IDENTITY_HIDING", extensions::IDENTITY_HIDING),
+            ]),
+            header: fields_at(0, &[("len", extensions::AREA_OVERHEAD - sodium::MAC_SIZE)]),
+            extension: fields_at(0, &[("kind", 2), ("len", 2)]),
+            trailer: fields_at(0, &[("mac", sodium::MAC_SIZE)]),
+        },
+        framing: vec![
+            FramingSchema {
+                message: format!("{Fragment:?}"),
+                field: "data",
+                carries: "bytes index * FRAGMENT_DATA_LEN onwards of another message, \
+                    of which len are in use; the message is reassembled from the count \
+                    fragments sharing its msg_id",
+            },
+            FramingSchema {
+                message: format!("{Relay:?}"),
+                field: "data",
+                carries: "a whole Fragment message, sealed for the peer at the other end; \
+                    peer names that peer towards the relay, and the sender on the way back",
+            },
+        ],
+    }
+}
+
+#[cfg(test)]
+mod test_schema {
+    use super::*;
+
+    #[test]
+    fn fields_are_contiguous() {
+        for msg in wire_schema().messages {
+            let mut off = 0;
+            for f in msg.fields.iter() {
+                assert_eq!(f.offset, off, "gap before {}.{}", msg.name, f.name);
+                off += f.len;
+            }
+            assert_eq!(
+                off, msg.len,
+                "fields of {} do not cover the message",
+                msg.name
+            );
+        }
+    }
+
+    #[test]
+    fn every_type_is_described() {
+        let schema = wire_schema();
+        for name in schema.message_types.keys() {
+            let layouts = schema.messages.iter().filter(|m| &m.name == name).count();
+            let unsupported = schema.unsupported.contains_key(name) as usize;
+            assert_eq!(layouts + unsupported, 1, "{name} is not described once");
+        }
+    }
+
+    #[test]
+    fn extension_area_matches_constants() {
+        let area = wire_schema().extension_area;
+        let len = |fields: &[FieldSchema]| fields.iter().map(|f| f.len).sum::<usize>();
+        assert_eq!(
+            len(&area.header) + len(&area.trailer),
+            extensions::AREA_OVERHEAD
+        );
+        assert_eq!(len(&area.extension), extensions::EXTENSION_HEADER_LEN);
+    }
+}
+
 #[cfg(test)]
 mod test_constants {
     use crate::{
@@ -0,0 +1,207 @@
+//! Getting handshakes through NAT
+//!
+//! With STUN servers configured, rosenpass asks them for the reflexive
+//! address of each of its sockets every [STUN_INTERVAL] seconds, i.e. the
+//! address and port the outermost NAT maps the socket to (RFC 8489). The
+//! requests are sent from the sockets handshakes are sent from, so they also
+//! keep the mapping open. Learned addresses are logged and reported with the
+//! statistics, to be published as the endpoint of this host wherever the
+//! peers learn about it, e.g. in DNS (see [crate::dns]).
+//!
+//! When both peers are behind NAT, the first handshake message of either
+//! side is dropped by the NAT of the other side, until that side sent
+//! something itself. Peers with hole punching enabled therefore start their
+//! handshakes at the next multiple of [PUNCH_SLOT] seconds on the wall clock
+//! only; with synchronized clocks both sides send at once, opening their
+//! mappings for the messages of each other, and the retransmissions which
+//! follow stay aligned as well.
+
+use anyhow::{bail, ensure, Context, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use crate::protocol::Timing;
+
+/// Seconds between the requests to the STUN servers; short enough to keep
+/// common NAT mappings open
+pub const STUN_INTERVAL: Timing = 25.0;
+
+/// Handshakes with hole punching peers start at multiples of this many
+/// seconds on the wall clock
+pub const PUNCH_SLOT: Timing = 5.0;
+
+const MAGIC_COOKIE: u32 = 0x2112_a442;
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_SUCCESS: u16 = 0x0101;
+const ATTR_MAPPED_ADDRESS: u16 = 0x0001;
+const ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const HEADER_LEN: usize = 20;
+
+pub type TransactionId = [u8; 12];
+
+#[derive(Debug)]
+pub struct Stun {
+    pub servers: Vec<SocketAddr>,
+    /// When to ask the servers next
+    pub request_at: Timing,
+    /// The requests awaiting an answer, with the socket each was sent from
+    pub pending: Vec<(TransactionId, usize)>,
+    /// The reflexive address learned for each socket
+    pub reflexive: Vec<Option<SocketAddr>>,
+}
+
+impl Stun {
+    pub fn new(servers: Vec<SocketAddr>, sockets: usize) -> Self {
+        Self {
+            servers,
+            request_at: 0.0,
+            pending: Vec::new(),
+            reflexive: vec![None; sockets],
+        }
+    }
+
+    /// Record the answer `buf` received on the socket `socket`; returns the
+    /// reflexive address of the socket if it changed
+    pub fn handle_response(&mut self, buf: &[u8], socket: usize) -> Result<Option<SocketAddr>> {
+        let (txid, addr) = parse_binding_response(buf)?;
+        ensure!(
+            self.pending.contains(&(txid, socket)),
+            "unsolicited STUN response"
+        );
+        self.pending.retain(|(id, _)| *id != txid);
+        let known = self.reflexive.get_mut(socket).context("unknown socket")?;
+        Ok(match known.replace(addr) {
+            Some(prev) if prev == addr => None,
+            _ => Some(addr),
+        })
+    }
+}
+
+/// Whether `buf` looks like a STUN message rather than a rosenpass one;
+/// the message types of the latter all have the highest bit set
+pub fn is_stun(buf: &[u8]) -> bool {
+    buf.len() >= HEADER_LEN && buf[0] & 0xc0 == 0 && buf[4..8] == MAGIC_COOKIE.to_be_bytes()
+}
+
+pub fn encode_binding_request(txid: &TransactionId) -> [u8; HEADER_LEN] {
+    let mut msg = [0u8; HEADER_LEN];
+    msg[0..2].copy_from_slice(&BINDING_REQUEST.to_be_bytes());
+    // no attributes, so the length stays zero
+    msg[4..8].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    msg[8..].copy_from_slice(txid);
+    msg
+}
+
+/// The transaction id and the reflexive address of a Binding success response
+pub fn parse_binding_response(buf: &[u8]) -> Result<(TransactionId, SocketAddr)> {
+    ensure!(is_stun(buf), "not a STUN message");
+    let kind = u16::from_be_bytes([buf[0], buf[1]]);
+    ensure!(kind == BINDING_SUCCESS, "STUN message of type {kind:#06x}");
+    let len = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+    let attrs = buf
+        .get(HEADER_LEN..HEADER_LEN + len)
+        .context("truncated STUN message")?;
+    let txid: TransactionId = buf[8..HEADER_LEN].try_into()?;
+
+    let mut mapped = None;
+    let mut off = 0;
+    while off + 4 <= attrs.len() {
+        let kind = u16::from_be_bytes([attrs[off], attrs[off + 1]]);
+        let len = u16::from_be_bytes([attrs[off + 2], attrs[off + 3]]) as usize;
+        let value = attrs
+            .get(off + 4..off + 4 + len)
+            .context("truncated STUN attribute")?;
+        match kind {
+            ATTR_XOR_MAPPED_ADDRESS => return Ok((txid, parse_address(value, Some(&txid))?)),
+            // sent by servers predating the magic cookie
+            ATTR_MAPPED_ADDRESS => mapped = Some(parse_address(value, None)?),
+            _ => {}
+        }
+        // attributes are padded to multiples of four bytes
+        off += 4 + len.div_ceil(4) * 4;
+    }
+    match mapped {
+        Some(addr) => Ok((txid, addr)),
+        None => bail!("STUN response lacks the mapped address"),
+    }
+}
+
+/// Read a (XOR-)MAPPED-ADDRESS attribute; `txid` is given for the XOR variant
+fn parse_address(value: &[u8], txid: Option<&TransactionId>) -> Result<SocketAddr> {
+    ensure!(value.len() >= 4, "truncated STUN address");
+    let mut key = [0u8; 16];
+    if let Some(txid) = txid {
+        key[..4].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        key[4..].copy_from_slice(txid);
+    }
+    let port = u16::from_be_bytes([value[2] ^ key[0], value[3] ^ key[1]]);
+    let mut addr = [0u8; 16];
+    let ip = match (value[1], &value[4..]) {
+        (0x01, raw) if raw.len() == 4 => {
+            addr[..4].copy_from_slice(raw);
+            addr.iter_mut().zip(key).for_each(|(a, k)| *a ^= k);
+            IpAddr::V4(Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3]))
+        }
+        (0x02, raw) if raw.len() == 16 => {
+            addr.copy_from_slice(raw);
+            addr.iter_mut().zip(key).for_each(|(a, k)| *a ^= k);
+            IpAddr::V6(Ipv6Addr::from(addr))
+        }
+        (family, _) => bail!("invalid STUN address of family {family}"),
+    };
+    Ok(SocketAddr::new(ip, port))
+}
+
+/// Seconds from the wall clock time `wall` to the next [PUNCH_SLOT]
+pub fn punch_delay(wall: f64) -> Timing {
+    PUNCH_SLOT - wall.rem_euclid(PUNCH_SLOT)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn binding_response() {
+        // the IPv4 response of RFC 5769, 2.2, with its integrity and
+        // fingerprint attributes, which are ignored
+        let msg: Vec<u8> = [
+            "0101003c2112a442b7e7a701bc34d686fa87dfae",
+            "8022000b",
+            "7465737420766563746f7220",
+            "00200008",
+            "0001a147e112a643",
+            "00080014",
+            "2b91f599fd9e90c38c7489f92af9ba53f06be7d7",
+            "80280004",
+            "c07d4c96",
+        ]
+        .concat()
+        .as_bytes()
+        .chunks(2)
+        .map(|b| u8::from_str_radix(std::str::from_utf8(b).unwrap(), 16).unwrap())
+        .collect();
+
+        assert!(is_stun(&msg));
+        let (txid, addr) = parse_binding_response(&msg).unwrap();
+        assert_eq!(txid[..4], [0xb7, 0xe7, 0xa7, 0x01]);
+        assert_eq!(addr, "192.0.2.1:32853".parse().unwrap());
+
+        let mut stun = Stun::new(vec![], 1);
+        assert!(stun.handle_response(&msg, 0).is_err());
+        stun.pending.push((txid, 0));
+        assert_eq!(stun.handle_response(&msg, 0).unwrap(), Some(addr));
+        stun.pending.push((txid, 0));
+        assert_eq!(stun.handle_response(&msg, 0).unwrap(), None);
+
+        let req = encode_binding_request(&txid);
+        assert!(is_stun(&req));
+        assert!(parse_binding_response(&req).is_err());
+        assert!(!is_stun(&[0x81; 64]));
+    }
+
+    #[test]
+    fn punch_slots() {
+        assert_eq!(punch_delay(1000.0), PUNCH_SLOT);
+        assert_eq!(punch_delay(1003.5), 1.5);
+    }
+}
@@ -0,0 +1,85 @@
+//! Network namespaces
+//!
+//! A common setup keeps the WireGuard device in one network namespace and the
+//! uplink in another: the device is created where the uplink is, so its
+//! encrypted traffic leaves through it, and then moved into the namespace the
+//! tunnel is used in. Rosenpass follows the same split with two options,
+//! naming namespaces the way `ip netns` does:
+//!
+//! - `netns` opens the handshake sockets, including those bound to the
+//!   interfaces of peers and the mDNS one, inside the namespace, while
+//!   everything else stays in the namespace rosenpass was started in.
+//! - `device_netns` of a peer runs `wg` inside the namespace its WireGuard
+//!   device is in. UAPI sockets are files, so they need no namespace.
+//!
+//! Either way, the namespace is only entered for as long as it takes to open
+//! a socket or to run `wg`. Starting rosenpass with `ip netns exec` instead
+//! moves it into a namespace for good. Entering a namespace takes
+//! `CAP_SYS_ADMIN`; only Linux is supported.
+
+use anyhow::{ensure, Result};
+use std::path::PathBuf;
+
+/// Where `ip netns` keeps its namespaces
+pub const NETNS_DIR: &str = "/run/netns";
+
+/// Make sure `name` can be the name of a namespace
+pub fn validate_name(name: &str) -> Result<()> {
+    ensure!(!name.is_empty(), "network namespace name is empty");
+    ensure!(
+        name != "." && name != ".." && !name.contains(['/', '\0']),
+        "network namespace name {name:?} is invalid"
+    );
+    Ok(())
+}
+
+/// The file of the namespace `name`
+pub fn path(name: &str) -> PathBuf {
+    PathBuf::from(NETNS_DIR).join(name)
+}
+
+/// Run `f` inside the namespace `name`, or where we are if there is none
+///
+/// Only the calling thread enters the namespace; sockets opened and
+/// processes started by `f` stay in it once the thread has left.
+pub fn within<T>(name: Option<&str>, f: impl FnOnce() -> Result<T>) -> Result<T> {
+    match name {
+        None => f(),
+        Some(name) => imp::within(name, f),
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use anyhow::{Context, Result};
+    use std::{fs::File, io, os::fd::AsRawFd};
+
+    fn enter(ns: &File) -> io::Result<()> {
+        match unsafe { libc::setns(ns.as_raw_fd(), libc::CLONE_NEWNET) } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    pub fn within<T>(name: &str, f: impl FnOnce() -> Result<T>) -> Result<T> {
+        let home = File::open("/proc/thread-self/ns/net")
+            .context("could not open the current network namespace")?;
+        let path = super::path(name);
+        let ns = File::open(&path)
+            .with_context(|| format!("could not open network namespace {name} at {path:?}"))?;
+        enter(&ns).with_context(|| format!("could not enter network namespace {name}"))?;
+        let res = f();
+        // a thread left behind would open all of its later sockets in there
+        enter(&home).expect("could not return to the original network namespace");
+        res
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod imp {
+    use anyhow::{bail, Result};
+
+    pub fn within<T>(name: &str, _f: impl FnOnce() -> Result<T>) -> Result<T> {
+        bail!("network namespace {name} can not be entered, namespaces are only supported on Linux")
+    }
+}
@@ -0,0 +1,375 @@
+//! Service side of a NetworkManager VPN plugin
+//!
+//! NetworkManager starts `rosenpass nm-vpn-service` for connections of the
+//! VPN type [SERVICE_TYPE] (see the files in `config-examples/networkmanager`)
+//! and controls it over D-Bus with the `org.freedesktop.NetworkManager.VPN.Plugin`
+//! interface. On `Connect`, the service starts a key exchange with the
+//! settings of the connection, to keep the PSK of a WireGuard connection
+//! post-quantum secure. NetworkManager is told the connection is up once the
+//! first key was exchanged, and that it failed when the exchange stops.
+//!
+//! The `vpn.data` of a connection holds either `config`, the path of a
+//! complete configuration file, or the settings of a single peer:
+//! `public-key`, `secret-key`, `peer-public-key`, `endpoint`, `listen` (a comma
+//! separated list of addresses), `wireguard-interface` and `wireguard-peer`.
+//! Instead of as a file, the secret key may be kept with the other secrets of
+//! NetworkManager, as the base64 encoded `secret-key` in `vpn.secrets`;
+//! `NeedSecrets` asks for it when neither is given. While connected, the
+//! configuration and the secret key are kept in a private directory below
+//! [RUNTIME_DIR], which is removed on disconnect.
+
+use anyhow::{bail, ensure, Context, Result};
+use log::{error, info, warn};
+use rosenpass_util::b64::b64_reader;
+use std::{
+    collections::HashMap,
+    fs,
+    io::{Read, Write},
+    os::unix::fs::{DirBuilderExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+    process::{Child, Command},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    config::{self, RosenpassPeer, WireGuard},
+    control::{self, PeerStatus},
+    dbus::{self, Message, Value},
+};
+
+/// The VPN service type of rosenpass connections, and the bus name of the
+/// service unless NetworkManager asks for another one
+pub const SERVICE_TYPE: &str = "org.freedesktop.NetworkManager.rosenpass";
+
+/// Where the files of active connections are kept
+pub const RUNTIME_DIR: &str = "/run/rosenpass-nm";
+
+const PATH: &str = "/org/freedesktop/NetworkManager/VPN/Plugin";
+const INTERFACE: &str = "org.freedesktop.NetworkManager.VPN.Plugin";
+const PROPERTIES: &str = "org.freedesktop.DBus.Properties";
+
+/// How often the key exchange is checked on
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// How long the key exchange gets to stop before it is killed
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+// states and failure reasons of NMVpnServiceState and NMVpnPluginFailure
+const STATE_INIT: u32 = 1;
+const STATE_STARTING: u32 = 3;
+const STATE_STARTED: u32 = 4;
+const STATE_STOPPING: u32 = 5;
+const STATE_STOPPED: u32 = 6;
+const FAILURE_CONNECT_FAILED: u32 = 1;
+
+/// A running key exchange
+struct Exchange {
+    child: Child,
+    dir: PathBuf,
+    control_socket: PathBuf,
+}
+
+pub struct Service {
+    state: u32,
+    exchange: Option<Exchange>,
+    runtime_dir: PathBuf,
+}
+
+impl Service {
+    /// Offer the service on the system bus under `bus_name` and serve
+    /// NetworkManager until the connection is down again
+    pub fn run(bus_name: &str, runtime_dir: &Path) -> Result<()> {
+        let mut bus = dbus::Connection::system()?;
+        bus.request_name(bus_name)?;
+        info!("serving NetworkManager as {bus_name}");
+
+        let mut svc = Self {
+            state: STATE_INIT,
+            exchange: None,
+            runtime_dir: runtime_dir.to_owned(),
+        };
+        while svc.state != STATE_STOPPED {
+            if let Some(msg) = bus.receive(POLL_INTERVAL)? {
+                svc.handle(&mut bus, msg)?;
+            }
+            svc.poll(&mut bus)?;
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, bus: &mut dbus::Connection, msg: Message) -> Result<()> {
+        if msg.kind != dbus::METHOD_CALL {
+            return Ok(());
+        }
+        if msg.path.as_deref() != Some(PATH) {
+            return bus.error(
+                &msg,
+                "org.freedesktop.DBus.Error.UnknownObject",
+                "no such object",
+            );
+        }
+        let settings = msg.body.first();
+        if msg.is_call(INTERFACE, "Connect") || msg.is_call(INTERFACE, "ConnectInteractive") {
+            let res = match (self.exchange.is_some(), settings) {
+                (true, _) => Err(anyhow::anyhow!("already connected")),
+                (false, Some(settings)) => self.connect(bus, settings),
+                (false, None) => Err(anyhow::anyhow!("no connection settings")),
+            };
+            match res {
+                Ok(()) => bus.reply(&msg, vec![]),
+                Err(e) => {
+                    error!("could not connect: {e:?}");
+                    bus.error(
+                        &msg,
+                        "org.freedesktop.NetworkManager.VPN.Error.LaunchFailed",
+                        &format!("{e:#}"),
+                    )
+                }
+            }
+        } else if msg.is_call(INTERFACE, "NeedSecrets") {
+            let needed = settings.map(needs_secret_key).unwrap_or(false);
+            let setting = if needed { "vpn" } else { "" };
+            bus.reply(&msg, vec![Value::str(setting)])
+        } else if msg.is_call(INTERFACE, "Disconnect") {
+            self.disconnect(bus)?;
+            bus.reply(&msg, vec![])
+        } else if [
+            "SetConfig",
+            "SetIp4Config",
+            "SetIp6Config",
+            "SetFailure",
+            "NewSecrets",
+        ]
+        .iter()
+        .any(|m| msg.is_call(INTERFACE, m))
+        {
+            // the addresses are up to the WireGuard connection
+            bus.reply(&msg, vec![])
+        } else if msg.is_call(PROPERTIES, "Get") {
+            match msg.body.get(1).and_then(Value::as_str) {
+                Some("State") => {
+                    bus.reply(&msg, vec![Value::Variant(Value::U32(self.state).into())])
+                }
+                _ => bus.error(
+                    &msg,
+                    "org.freedesktop.DBus.Error.UnknownProperty",
+                    "no such property",
+                ),
+            }
+        } else if msg.is_call(PROPERTIES, "GetAll") {
+            let props = Value::dict(vec![("State", Value::U32(self.state))]);
+            bus.reply(&msg, vec![props])
+        } else {
+            bus.error(
+                &msg,
+                "org.freedesktop.DBus.Error.UnknownMethod",
+                "no such method",
+            )
+        }
+    }
+
+    fn connect(&mut self, bus: &mut dbus::Connection, settings: &Value) -> Result<()> {
+        let dir = self.runtime_dir.join(std::process::id().to_string());
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&dir)
+            .with_context(|| format!("could not create {dir:?}"))?;
+
+        let started = exchange_config(settings, &dir).and_then(|config| {
+            let path = dir.join("config.toml");
+            config.store(&path)?;
+            let child = Command::new(std::env::current_exe()?)
+                .arg("exchange-config")
+                .arg(&path)
+                .spawn()
+                .context("could not start the key exchange")?;
+            info!("started the key exchange as process {}", child.id());
+            Ok(Exchange {
+                child,
+                dir: dir.clone(),
+                control_socket: config.control_socket.unwrap_or_default(),
+            })
+        });
+        match started {
+            Ok(exchange) => self.exchange = Some(exchange),
+            Err(e) => {
+                let _ = fs::remove_dir_all(&dir);
+                return Err(e);
+            }
+        }
+        self.set_state(bus, STATE_STARTING)
+    }
+
+    fn disconnect(&mut self, bus: &mut dbus::Connection) -> Result<()> {
+        if let Some(exchange) = self.exchange.take() {
+            self.set_state(bus, STATE_STOPPING)?;
+            exchange.stop();
+        }
+        self.set_state(bus, STATE_STOPPED)
+    }
+
+    /// Tell NetworkManager about the first key, or about the key exchange
+    /// having stopped
+    fn poll(&mut self, bus: &mut dbus::Connection) -> Result<()> {
+        let Some(exchange) = self.exchange.as_mut() else {
+            return Ok(());
+        };
+        if let Some(status) = exchange.child.try_wait()? {
+            warn!("the key exchange stopped with {status}");
+            if let Some(exchange) = self.exchange.take() {
+                exchange.stop();
+            }
+            bus.signal(
+                PATH,
+                INTERFACE,
+                "Failure",
+                vec![Value::U32(FAILURE_CONNECT_FAILED)],
+            )?;
+            return self.set_state(bus, STATE_STOPPED);
+        }
+        if self.state != STATE_STARTING {
+            return Ok(());
+        }
+        // the control socket shows up only once the exchange is running
+        let Ok(reply) = control::request(&exchange.control_socket, "status") else {
+            return Ok(());
+        };
+        let peers: Vec<PeerStatus> = serde_json::from_str(&reply)?;
+        if peers.iter().any(|p| p.key_age.is_some()) {
+            let config = Value::dict(vec![
+                ("has-ip4", Value::Bool(false)),
+                ("has-ip6", Value::Bool(false)),
+            ]);
+            bus.signal(PATH, INTERFACE, "Config", vec![config])?;
+            self.set_state(bus, STATE_STARTED)?;
+        }
+        Ok(())
+    }
+
+    fn set_state(&mut self, bus: &mut dbus::Connection, state: u32) -> Result<()> {
+        if self.state != state {
+            self.state = state;
+            bus.signal(PATH, INTERFACE, "StateChanged", vec![Value::U32(state)])?;
+        }
+        Ok(())
+    }
+}
+
+impl Exchange {
+    /// Stop the key exchange and remove its files
+    fn stop(mut self) {
+        if self.child.try_wait().ok().flatten().is_none() {
+            unsafe { libc::kill(self.child.id() as libc::pid_t, libc::SIGTERM) };
+            let deadline = Instant::now() + STOP_TIMEOUT;
+            while self.child.try_wait().ok().flatten().is_none() {
+                if Instant::now() > deadline {
+                    let _ = self.child.kill();
+                    let _ = self.child.wait();
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(50));
+            }
+        }
+        if let Err(e) = fs::remove_dir_all(&self.dir) {
+            warn!("could not remove {:?}: {e}", self.dir);
+        }
+    }
+}
+
+/// The string dictionary `name` of the `vpn` setting
+fn vpn_strings<'a>(settings: &'a Value, name: &str) -> HashMap<&'a str, &'a str> {
+    settings
+        .get("vpn")
+        .and_then(|vpn| vpn.get(name))
+        .map(|d| d.str_entries().into_iter().collect())
+        .unwrap_or_default()
+}
+
+/// Whether the secret key has to be asked for
+fn needs_secret_key(settings: &Value) -> bool {
+    let data = vpn_strings(settings, "data");
+    let secrets = vpn_strings(settings, "secrets");
+    !data.contains_key("config")
+        && !data.contains_key("secret-key")
+        && !secrets.contains_key("secret-key")
+}
+
+/// The configuration of the key exchange for the connection `settings`;
+/// the secret key from the secrets of the connection is written to `dir`
+pub fn exchange_config(settings: &Value, dir: &Path) -> Result<config::Rosenpass> {
+    let data = vpn_strings(settings, "data");
+    let secrets = vpn_strings(settings, "secrets");
+
+    let mut config = match data.get("config") {
+        Some(path) => config::Rosenpass::load(path)?,
+        None => {
+            let public_key = data
+                .get("public-key")
+                .context("the connection sets neither config nor public-key")?;
+            let secret_key = data
+                .get("secret-key")
+                .map(PathBuf::from)
+                .unwrap_or_default();
+            let mut config = config::Rosenpass::new(public_key, secret_key);
+            if let Some(listen) = data.get("listen") {
+                for addr in listen.split(',') {
+                    let addr = addr.trim();
+                    config.listen.push(
+                        addr.parse()
+                            .with_context(|| format!("invalid listen address {addr:?}"))?,
+                    );
+                }
+            }
+            let wg = match (data.get("wireguard-interface"), data.get("wireguard-peer")) {
+                (Some(device), Some(peer)) => Some(WireGuard {
+                    device: device.to_string(),
+                    peer: peer.to_string(),
+                    extra_params: vec![],
+                    uapi_socket: None,
+                    device_netns: None,
+                }),
+                (None, None) => None,
+                _ => bail!("wireguard-interface and wireguard-peer must be set together"),
+            };
+            config.peers.push(RosenpassPeer {
+                public_key: data
+                    .get("peer-public-key")
+                    .context("the connection does not set peer-public-key")?
+                    .into(),
+                endpoint: data.get("endpoint").map(|e| e.to_string()),
+                wg,
+                ..RosenpassPeer::default()
+            });
+            config
+        }
+    };
+
+    if let Some(b64) = secrets.get("secret-key") {
+        let mut key = Vec::new();
+        b64_reader(b64.trim().as_bytes())
+            .read_to_end(&mut key)
+            .context("the secret-key secret is not valid base64")?;
+        let path = dir.join("secret-key");
+        fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)
+            .and_then(|mut f| f.write_all(&key))
+            .with_context(|| format!("could not write {path:?}"))?;
+        config.secret_key = path;
+        config.secret_key_vault = None;
+        config.secret_key_wrap = None;
+    }
+    ensure!(
+        config.secret_key_vault.is_some() || !config.secret_key.as_os_str().is_empty(),
+        "the connection has no secret key"
+    );
+
+    config.control_socket = Some(dir.join("control.sock"));
+    config.config_file_path = dir.join("config.toml");
+    if !data.contains_key("config") {
+        config.validate()?;
+    }
+    Ok(config)
+}
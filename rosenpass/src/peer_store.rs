@@ -0,0 +1,323 @@
+//! Peers kept in a SQLite database
+//!
+//! Gateways with tens of thousands of peers can keep them in a database
+//! rather than in `[[peers]]` tables, which does away with parsing a giant
+//! config on startup and lets provisioning change peers transactionally:
+//!
+//! ```toml
+//! [peer_store]
+//! database = "/var/lib/rosenpass/peers.db"
+//! refresh = 30
+//! ```
+//!
+//! The database is read with the `sqlite3` command line shell, from a table
+//! of one row per peer:
+//!
+//! ```sql
+//! CREATE TABLE peers (
+//!     public_key TEXT NOT NULL, -- public-key file of the peer
+//!     endpoint TEXT,
+//!     key_out TEXT,
+//!     device TEXT,              -- WireGuard device and peer
+//!     peer TEXT,
+//!     settings TEXT             -- JSON object of any other [[peers]] settings
+//! );
+//! ```
+//!
+//! Columns which are NULL are left out; a row is the same as a `[[peers]]`
+//! table with the columns and the settings as its keys, taken after the
+//! peers of the config file, and is checked just like one. Every
+//! [PeerStore::refresh] seconds, rosenpass checks whether the database was
+//! written to; if so, the config is loaded again and the stored peers are
+//! compared by their `public_key`. Peers whose row is gone are removed: their
+//! handshakes are refused and their key is erased, just like with a revoked
+//! peer. Peers whose row changed are removed and added again, so they start
+//! over with a handshake. All rows are read in one query, so a change made in
+//! one transaction is picked up as a whole or not at all; a change which does
+//! not load is reported, and the peers stay as they are. Changes are only
+//! picked up if the config was read from a file.
+
+use anyhow::{bail, ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{
+        mpsc::{self, Receiver},
+        Arc,
+    },
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use crate::{app_server::AppPeerPtr, config::RosenpassPeer, protocol::SPk};
+
+/// Default of [PeerStore::refresh]
+pub const DEFAULT_REFRESH: u64 = 30;
+
+/// The query the peers are read with
+pub const QUERY: &str =
+    "SELECT rowid, public_key, endpoint, key_out, device, peer, settings FROM peers ORDER BY rowid";
+
+/// Where to find the stored peers
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerStore {
+    pub database: PathBuf,
+    /// Seconds between checks for changes, [DEFAULT_REFRESH] by default
+    #[serde(default)]
+    pub refresh: Option<u64>,
+}
+
+/// What changed in the database, or another source of peers, since it was
+/// read last
+#[derive(Debug)]
+pub struct Update {
+    /// Where the peers are from, for the log
+    pub source: &'static str,
+    /// The public-key files of the peers to remove
+    pub removed: Vec<PathBuf>,
+    /// The peers to add, with their public keys
+    pub added: Vec<(RosenpassPeer, SPk)>,
+}
+
+/// Modification time and length of a file, if it exists
+type Stamp = Option<(Option<SystemTime>, u64)>;
+
+impl PeerStore {
+    /// Read all stored peers
+    pub fn load(&self) -> Result<Vec<RosenpassPeer>> {
+        let out = Command::new("sqlite3")
+            .args(["-readonly", "-json"])
+            .arg(&self.database)
+            .arg(QUERY)
+            .output()
+            .context("could not run sqlite3")?;
+        ensure!(
+            out.status.success(),
+            "sqlite3 failed to read {:?} with {}: {}",
+            self.database,
+            out.status,
+            String::from_utf8_lossy(&out.stderr).trim()
+        );
+        // no rows yield no output at all
+        if out.stdout.iter().all(u8::is_ascii_whitespace) {
+            return Ok(Vec::new());
+        }
+        let rows: Vec<Map<String, Value>> = serde_json::from_slice(&out.stdout)
+            .with_context(|| format!("unexpected output of sqlite3 for {:?}", self.database))?;
+        rows.into_iter().map(peer_of_row).collect()
+    }
+
+    fn stamps(&self) -> [Stamp; 2] {
+        let mut wal = OsString::from(self.database.as_os_str());
+        wal.push("-wal");
+        let stamp = |path: &Path| {
+            let meta = fs::metadata(path).ok()?;
+            Some((meta.modified().ok(), meta.len()))
+        };
+        // in WAL mode, commits only touch the write-ahead log
+        [stamp(&self.database), stamp(Path::new(&wal))]
+    }
+
+    /// Check for changes in the background; `current` are the stored peers
+    /// in use, `reload` loads the config again and returns its stored peers,
+    /// and `load_key` the public key of a peer
+    pub fn watch<R, K>(
+        &self,
+        current: Vec<RosenpassPeer>,
+        reload: R,
+        load_key: K,
+        waker: Arc<mio::Waker>,
+    ) -> Receiver<Result<Update>>
+    where
+        R: FnMut() -> Result<Vec<RosenpassPeer>> + Send + 'static,
+        K: Fn(&RosenpassPeer) -> Result<SPk> + Send + 'static,
+    {
+        let store = self.clone();
+        let mut stamps = store.stamps();
+        let changed = move || {
+            let now = store.stamps();
+            let changed = now != stamps;
+            stamps = now;
+            changed
+        };
+        let every = self.refresh.unwrap_or(DEFAULT_REFRESH);
+        watch(
+            "the peer store",
+            every,
+            changed,
+            current,
+            reload,
+            load_key,
+            waker,
+        )
+    }
+}
+
+/// Check a source of peers for changes in the background, every `every`
+/// seconds once `changed` tells it did; see [PeerStore::watch]
+pub fn watch<C, R, K>(
+    source: &'static str,
+    every: u64,
+    mut changed: C,
+    mut current: Vec<RosenpassPeer>,
+    mut reload: R,
+    load_key: K,
+    waker: Arc<mio::Waker>,
+) -> Receiver<Result<Update>>
+where
+    C: FnMut() -> bool + Send + 'static,
+    R: FnMut() -> Result<Vec<RosenpassPeer>> + Send + 'static,
+    K: Fn(&RosenpassPeer) -> Result<SPk> + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(every));
+        if !changed() {
+            continue;
+        }
+        let update = reload().and_then(|peers| {
+            let (removed, added) = diff(&current, &peers);
+            let added = added
+                .into_iter()
+                .map(|peer| Ok((peer.clone(), load_key(peer)?)))
+                .collect::<Result<_>>()?;
+            current = peers;
+            Ok(Update {
+                source,
+                removed,
+                added,
+            })
+        });
+        if tx.send(update).is_err() {
+            return;
+        }
+        let _ = waker.wake();
+    });
+    rx
+}
+
+/// The `[[peers]]` table a row of [QUERY] stands for
+fn peer_of_row(mut row: Map<String, Value>) -> Result<RosenpassPeer> {
+    let rowid = row.remove("rowid").unwrap_or_default();
+    let mut table = match row.remove("settings") {
+        None | Some(Value::Null) => Map::new(),
+        Some(Value::String(s)) => serde_json::from_str(&s)
+            .with_context(|| format!("settings of peer row {rowid} are not a JSON object"))?,
+        Some(v) => bail!("settings of peer row {rowid} are {v}, not a JSON object"),
+    };
+    table.extend(row.into_iter().filter(|(_, v)| !v.is_null()));
+    let mut peer: RosenpassPeer = serde_json::from_value(Value::Object(table))
+        .with_context(|| format!("peer row {rowid} is invalid"))?;
+    peer.stored = true;
+    Ok(peer)
+}
+
+/// The public-key files of the peers in `old` which are not in `new` the
+/// same way, and the peers in `new` which are not in `old` the same way
+pub fn diff<'a>(
+    old: &[RosenpassPeer],
+    new: &'a [RosenpassPeer],
+) -> (Vec<PathBuf>, Vec<&'a RosenpassPeer>) {
+    fn by_key(peers: &[RosenpassPeer]) -> HashMap<&Path, &RosenpassPeer> {
+        peers.iter().map(|p| (p.public_key.as_path(), p)).collect()
+    }
+    fn same(peer: &RosenpassPeer, others: &HashMap<&Path, &RosenpassPeer>) -> bool {
+        others.get(peer.public_key.as_path()) == Some(&peer)
+    }
+    let (old_by_key, new_by_key) = (by_key(old), by_key(new));
+    let removed = old
+        .iter()
+        .filter(|peer| !same(peer, &new_by_key))
+        .map(|peer| peer.public_key.clone())
+        .collect();
+    let added = new.iter().filter(|peer| !same(peer, &old_by_key)).collect();
+    (removed, added)
+}
+
+/// The stored peers in use, and the updates of them coming in, from the
+/// peer store and any other sources like [crate::ldap]
+#[derive(Debug, Default)]
+pub struct StoredPeers {
+    pub peers: HashMap<PathBuf, AppPeerPtr>,
+    updates: Vec<Receiver<Result<Update>>>,
+}
+
+impl StoredPeers {
+    /// Take up the `peers` of another source, changed by `updates`
+    pub fn add(&mut self, peers: HashMap<PathBuf, AppPeerPtr>, updates: Receiver<Result<Update>>) {
+        self.peers.extend(peers);
+        self.updates.push(updates);
+    }
+
+    /// The next update, if there was one
+    pub fn try_update(&self) -> Option<Result<Update>> {
+        self.updates
+            .iter()
+            .find_map(|updates| updates.try_recv().ok())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn row(value: Value) -> Map<String, Value> {
+        value.as_object().unwrap().clone()
+    }
+
+    #[test]
+    fn rows_are_peers() {
+        let peer = peer_of_row(row(json!({
+            "rowid": 7,
+            "public_key": "/peers/a.pk",
+            "endpoint": "192.0.2.1:9999",
+            "key_out": null,
+            "device": "rp0",
+            "peer": "wg-a",
+            "settings": r#"{"tags": ["lab"], "endpoint": "198.51.100.1:9999"}"#,
+        })))
+        .unwrap();
+        assert!(peer.stored);
+        assert_eq!(peer.public_key, PathBuf::from("/peers/a.pk"));
+        // the columns take precedence over the settings
+        assert_eq!(peer.endpoint.as_deref(), Some("192.0.2.1:9999"));
+        assert_eq!(peer.key_out, None);
+        assert_eq!(peer.tags, vec!["lab".to_string()]);
+        assert_eq!(peer.wg.unwrap().device, "rp0");
+
+        let bad = json!({"rowid": 8, "public_key": "/peers/b.pk", "settings": "[1]"});
+        assert!(peer_of_row(row(bad)).is_err());
+        let bad = json!({"rowid": 9, "public_key": "/peers/c.pk", "keepalive": "often"});
+        assert!(peer_of_row(row(bad)).is_err());
+    }
+
+    #[test]
+    fn changed_peers_are_removed_and_added() {
+        let peer = |pk: &str, endpoint: &str| RosenpassPeer {
+            public_key: pk.into(),
+            endpoint: Some(endpoint.into()),
+            stored: true,
+            ..Default::default()
+        };
+        let old = [
+            peer("/a", "192.0.2.1:1"),
+            peer("/b", "192.0.2.2:1"),
+            peer("/c", "192.0.2.3:1"),
+        ];
+        let new = [
+            peer("/a", "192.0.2.1:1"),
+            peer("/c", "192.0.2.3:2"),
+            peer("/d", "192.0.2.4:1"),
+        ];
+        let (removed, added) = diff(&old, &new);
+        assert_eq!(removed, vec![PathBuf::from("/b"), PathBuf::from("/c")]);
+        assert_eq!(added, vec![&new[1], &new[2]]);
+        assert_eq!(diff(&new, &new), (vec![], vec![]));
+    }
+}
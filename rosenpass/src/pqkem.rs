@@ -10,6 +10,8 @@
 //! The [KEM] Trait describes the basic API offered by a Key Encapsulation
 //! Mechanism. Two implementations for it are provided, [StaticKEM] and [EphemeralKEM].
 
+use std::sync::Mutex;
+
 use crate::{RosenpassError, RosenpassMaybeError};
 
 /// Key Encapsulation Mechanism
@@ -111,6 +113,96 @@ impl KEM for StaticKEM {
     }
 }
 
+/// Length of the seed [StaticKEM::keygen_from_seed] derives a key pair from
+pub const KEYGEN_SEED_LEN: usize = 32;
+
+/// Serializes seeded key generations, the randomness hook of liboqs is process wide
+static SEEDED_KEYGEN: Mutex<()> = Mutex::new(());
+
+/// Seed and block counter of the random stream handed to liboqs during seeded key generation
+static SEEDED_RNG: Mutex<Option<([u8; KEYGEN_SEED_LEN], u64)>> = Mutex::new(None);
+
+/// A randomness hook for liboqs
+pub type Randombytes = unsafe extern "C" fn(*mut u8, usize);
+
+/// The hook liboqs draws from outside of seeded key generations; the
+/// randomness of the system if [None]
+static RANDOMBYTES: Mutex<Option<Randombytes>> = Mutex::new(None);
+
+/// Let liboqs draw all its randomness from `f`, see [crate::hedged]
+pub fn draw_randomness_from(f: Randombytes) {
+    *RANDOMBYTES.lock().unwrap_or_else(|e| e.into_inner()) = Some(f);
+    unsafe { oqs_sys::rand::OQS_randombytes_custom_algorithm(Some(f)) };
+}
+
+/// Point liboqs back to the hook set by [draw_randomness_from], if any
+unsafe fn restore_randombytes() {
+    match *RANDOMBYTES.lock().unwrap_or_else(|e| e.into_inner()) {
+        Some(f) => oqs_sys::rand::OQS_randombytes_custom_algorithm(Some(f)),
+        None => {
+            oqs_sys::rand::OQS_randombytes_switch_algorithm(
+                oqs_sys::rand::OQS_RAND_alg_system.as_ptr() as *const std::ffi::c_char,
+            );
+        }
+    }
+}
+
+/// Randomness hook for liboqs producing the stream `mac(seed, counter)`
+///
+/// Must not panic, since it is called from C.
+unsafe extern "C" fn seeded_randombytes(buf: *mut u8, len: usize) {
+    let buf = std::slice::from_raw_parts_mut(buf, len);
+    let mut rng = SEEDED_RNG.lock().unwrap_or_else(|e| e.into_inner());
+    for chunk in buf.chunks_mut(crate::sodium::KEY_SIZE) {
+        let block = match rng.as_mut() {
+            Some((seed, ctr)) => {
+                *ctr += 1;
+                crate::sodium::mac(seed, &ctr.to_le_bytes()).ok()
+            }
+            None => None,
+        };
+        match block {
+            Some(block) => chunk.copy_from_slice(&block[..chunk.len()]),
+            // without a seed there is nothing to be deterministic about
+            None => rosenpass_sodium::helpers::randombytes_buf(chunk),
+        }
+    }
+}
+
+impl StaticKEM {
+    /// Deterministically generate a key pair from `seed`
+    ///
+    /// liboqs draws randomness through a process wide hook; for the duration
+    /// of the key generation it is pointed to a stream derived from the seed.
+    /// The same seed yields the same key pair for as long as the key
+    /// generation of liboqs does not change.
+    pub fn keygen_from_seed(
+        seed: &[u8],
+        sk: &mut [u8],
+        pk: &mut [u8],
+    ) -> Result<(), RosenpassError> {
+        RosenpassError::check_buffer_size(seed.len(), KEYGEN_SEED_LEN)?;
+        let _guard = SEEDED_KEYGEN.lock().unwrap_or_else(|e| e.into_inner());
+
+        let mut s = [0u8; KEYGEN_SEED_LEN];
+        s.copy_from_slice(seed);
+        *SEEDED_RNG.lock().unwrap_or_else(|e| e.into_inner()) = Some((s, 0));
+        rosenpass_sodium::helpers::memzero(&mut s);
+
+        let res = unsafe {
+            oqs_sys::rand::OQS_randombytes_custom_algorithm(Some(seeded_randombytes));
+            let res = Self::keygen(sk, pk);
+            restore_randombytes();
+            res
+        };
+
+        if let Some((mut seed, _)) = SEEDED_RNG.lock().unwrap_or_else(|e| e.into_inner()).take() {
+            rosenpass_sodium::helpers::memzero(&mut seed);
+        }
+        res
+    }
+}
+
 /// Implements a KEM that is secure against Chosen Plaintext Attacks (CPA).
 /// In the context of rosenpass this is used for ephemeral keys.
 /// Currently the implementation uses
@@ -166,3 +258,22 @@ impl KEM for EphemeralKEM {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn seeded_keygen_is_deterministic() {
+        let gen = |seed: &[u8]| {
+            let mut sk = vec![0u8; StaticKEM::SK_LEN];
+            let mut pk = vec![0u8; StaticKEM::PK_LEN];
+            StaticKEM::keygen_from_seed(seed, &mut sk, &mut pk).unwrap();
+            (sk, pk)
+        };
+        let a = gen(&[1; KEYGEN_SEED_LEN]);
+        assert_eq!(a, gen(&[1; KEYGEN_SEED_LEN]));
+        assert_ne!(a, gen(&[2; KEYGEN_SEED_LEN]));
+        assert!(StaticKEM::keygen_from_seed(&[0; 16], &mut a.0.clone(), &mut a.1.clone()).is_err());
+    }
+}
@@ -0,0 +1,82 @@
+//! Named sets of protocol parameters
+//!
+//! `profile = "conservative" | "balanced" | "performance"` picks the rekey
+//! margin, replay window and replay mode together, so a fleet configured by
+//! profile name ends up with settings that fit each other instead of values
+//! tuned one by one on every host. Settings given explicitly in the
+//! configuration take precedence over those of the profile.
+//!
+//! The primitives are not part of a profile: Classic McEliece and Kyber as
+//! KEMs, and ChaCha20-Poly1305 and XChaCha20-Poly1305 as AEADs, are fixed by
+//! the protocol, and two peers using different ones could not talk to each
+//! other at all. What differs is how long keys live and how readily
+//! delayed messages are accepted:
+//!
+//! | profile        | rekey margin | replay window | replay mode |
+//! |----------------|--------------|---------------|-------------|
+//! | `conservative` | 90 s         | 60 s          | strict      |
+//! | `balanced`     | 60 s         | 300 s         | normal      |
+//! | `performance`  | 30 s         | 600 s         | lenient     |
+//!
+//! `balanced` is the default and matches the protocol defaults. With
+//! `conservative`, keys are renewed sooner and InitConfs are accepted only
+//! briefly and once, at the price of retransmissions going unanswered;
+//! `performance` saves handshakes and tolerates slow links.
+
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::{ReplayMode, Timing, BISCUIT_EPOCH, REKEY_MARGIN};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Profile {
+    Conservative,
+    #[default]
+    Balanced,
+    Performance,
+}
+
+/// The parameters a [Profile] stands for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Params {
+    /// See [crate::config::Rosenpass::rekey_margin]
+    pub rekey_margin: Timing,
+    /// See [crate::config::Rosenpass::replay_window]
+    pub replay_window: Timing,
+    pub replay_mode: ReplayMode,
+}
+
+impl Profile {
+    pub fn params(self) -> Params {
+        match self {
+            Profile::Conservative => Params {
+                rekey_margin: 90.0,
+                replay_window: 60.0,
+                replay_mode: ReplayMode::Strict,
+            },
+            Profile::Balanced => Params {
+                rekey_margin: REKEY_MARGIN,
+                replay_window: BISCUIT_EPOCH,
+                replay_mode: ReplayMode::Normal,
+            },
+            Profile::Performance => Params {
+                rekey_margin: 30.0,
+                replay_window: 600.0,
+                replay_mode: ReplayMode::Lenient,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn balanced_is_the_default() {
+        let params = Profile::default().params();
+        assert_eq!(params.rekey_margin, REKEY_MARGIN);
+        assert_eq!(params.replay_window, BISCUIT_EPOCH);
+        assert_eq!(params.replay_mode, ReplayMode::default());
+    }
+}
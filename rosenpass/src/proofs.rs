@@ -0,0 +1,166 @@
+//! Proof harnesses for the code taking received messages apart
+//!
+//! Only built by [Kani](https://model-checking.github.io/kani/), which checks
+//! the harnesses for every possible input instead of some: run them with
+//! `cargo kani -p rosenpass`, or a single one with `--harness <name>`.
+//!
+//! The harnesses show that the size checks of the lenses in [crate::msgs]
+//! admit exactly the buffers all their fields fit into, so no accessor of a
+//! lense that was created can index out of bounds, for messages of any
+//! length up to [MAX_MESSAGE_LEN] and any content. The lenses are the only
+//! way bytes are turned into messages; there are no unchecked casts to
+//! cover. Biscuits are shown to be sealed and opened without panicking,
+//! whatever an InitConf carries in place of the biscuit, and extension areas
+//! are taken apart within their bounds.
+//!
+//! Libsodium is out of Kani's reach, so the AEAD is stubbed by a model with
+//! the same requirements on the lengths of its arguments, which is what the
+//! harnesses are about; the cryptography itself is not part of the proofs.
+
+use rosenpass_ciphers::xaead;
+
+use crate::msgs::*;
+use crate::protocol::BiscuitKeyPtr;
+
+/// The first `len` bytes of `buf`, for any `len` up to [MAX_MESSAGE_LEN]
+fn any_prefix(buf: &[u8; MAX_MESSAGE_LEN]) -> &[u8] {
+    let len = kani::any_where(|len: &usize| *len <= MAX_MESSAGE_LEN);
+    &buf[..len]
+}
+
+macro_rules! envelope_proof {
+    ($proof:ident: $msg:ident, $lense:ident, [$( $field:ident ),+]) => {
+        #[kani::proof]
+        fn $proof() {
+            let buf: [u8; MAX_MESSAGE_LEN] = kani::any();
+            let rx = any_prefix(&buf);
+            let required = <Envelope<(), $msg<()>> as LenseView>::LEN;
+
+            match rx.envelope::<$msg<&[u8]>>() {
+                Ok(env) => {
+                    assert_eq!(rx.len(), required);
+                    let _ = (env.msg_type(), env.reserved(), env.until_mac());
+                    let _ = (env.mac(), env.cookie(), env.all_bytes());
+                    let msg = env.payload().$lense().unwrap();
+                    $( let _ = msg.$field(); )+
+                }
+                Err(_) => assert_ne!(rx.len(), required),
+            }
+
+            match rx.envelope_truncating::<$msg<&[u8]>>() {
+                Ok(env) => assert_eq!(env.all_bytes().len(), required),
+                Err(_) => assert!(rx.len() < required),
+            }
+        }
+    };
+}
+
+envelope_proof!(init_hello_envelope: InitHello, init_hello, [sidi, epki, sctr, pidic, auth]);
+envelope_proof!(resp_hello_envelope: RespHello, resp_hello, [sidr, sidi, ecti, scti, auth, biscuit]);
+envelope_proof!(init_conf_envelope: InitConf, init_conf, [sidi, sidr, biscuit, auth]);
+envelope_proof!(empty_data_envelope: EmptyData, empty_data, [sid, ctr, auth]);
+envelope_proof!(fragment_envelope: Fragment, fragment, [msg_id, index, count, len, data]);
+envelope_proof!(rendezvous_envelope: Rendezvous, rendezvous, [sid, ctr, payload]);
+envelope_proof!(relay_envelope: Relay, relay, [sid, ctr, peer, data, auth]);
+
+/// Every message type byte received is recognized as itself, or rejected
+#[kani::proof]
+fn msg_type_from_any_byte() {
+    let byte: u8 = kani::any();
+    if let Ok(msg_type) = MsgType::try_from(byte) {
+        assert_eq!(msg_type as u8, byte);
+    }
+}
+
+/// Any extension area received is taken apart within its bounds, into
+/// extensions which account for all of its bytes
+#[kani::proof]
+#[kani::unwind(8)]
+fn extension_area_of_any_bytes() {
+    let buf: [u8; 16] = kani::any();
+    let len = kani::any_where(|len: &usize| *len <= buf.len());
+    if let Ok(exts) = crate::extensions::decode(&buf[..len]) {
+        let used: usize = exts
+            .iter()
+            .map(|ext| crate::extensions::EXTENSION_HEADER_LEN + ext.data.len())
+            .sum();
+        assert_eq!(used + 2, len);
+    }
+}
+
+/// Whatever an InitConf carries as its biscuit, telling the biscuit key
+/// yields one of the two keys
+#[kani::proof]
+fn biscuit_key_of_any_biscuit() {
+    let buf: [u8; MAX_MESSAGE_LEN] = kani::any();
+    let biscuit_ct = any_prefix(&buf);
+    if let Ok(bk) = BiscuitKeyPtr::of_biscuit(biscuit_ct) {
+        assert_eq!(biscuit_ct.len(), BISCUIT_CT_LEN);
+        assert!(bk.0 < 2);
+    }
+}
+
+/// The key a biscuit is sealed with is told correctly when it is opened
+#[kani::proof]
+fn biscuit_key_survives_sealing() {
+    let bk = BiscuitKeyPtr(kani::any_where(|k: &usize| *k < 2));
+    let mut biscuit_ct: [u8; BISCUIT_CT_LEN] = kani::any();
+    bk.mark_nonce(&mut biscuit_ct[..xaead::NONCE_LEN]);
+    assert_eq!(BiscuitKeyPtr::of_biscuit(&biscuit_ct).unwrap(), bk);
+}
+
+/// Sealing a biscuit and opening any biscuit received, the way
+/// [crate::protocol::HandshakeState] does, meets the length requirements of
+/// the AEAD and stays within the biscuit lense
+#[kani::proof]
+#[kani::stub(rosenpass_ciphers::xaead::encrypt, stubs::xaead_encrypt)]
+#[kani::stub(rosenpass_ciphers::xaead::decrypt, stubs::xaead_decrypt)]
+fn biscuit_sealing() {
+    let key: [u8; xaead::KEY_LEN] = kani::any();
+    let nonce: [u8; xaead::NONCE_LEN] = kani::any();
+    let ad: [u8; 32] = kani::any();
+
+    let mut pt: [u8; BISCUIT_PT_LEN] = kani::any();
+    let biscuit = (&mut pt[..]).biscuit().unwrap();
+    let _ = (biscuit.pidi(), biscuit.biscuit_no(), biscuit.ck());
+    let mut biscuit_ct = [0u8; BISCUIT_CT_LEN];
+    xaead::encrypt(&mut biscuit_ct, &key, &nonce, &ad, biscuit.all_bytes()).unwrap();
+
+    let received: [u8; BISCUIT_CT_LEN] = kani::any();
+    let mut pt = [0u8; BISCUIT_PT_LEN];
+    let mut biscuit = (&mut pt[..]).biscuit().unwrap();
+    if xaead::decrypt(biscuit.all_bytes_mut(), &key, &ad, &received).is_ok() {
+        let _ = (biscuit.pidi(), biscuit.biscuit_no(), biscuit.ck());
+    }
+}
+
+mod stubs {
+    use rosenpass_ciphers::xaead::{KEY_LEN, NONCE_LEN, TAG_LEN};
+
+    /// Like [rosenpass_ciphers::xaead::encrypt], leaving the ciphertext as is
+    pub fn xaead_encrypt(
+        ciphertext: &mut [u8],
+        key: &[u8],
+        nonce: &[u8],
+        _ad: &[u8],
+        plaintext: &[u8],
+    ) -> anyhow::Result<()> {
+        assert!(ciphertext.len() == plaintext.len() + NONCE_LEN + TAG_LEN);
+        assert!(key.len() == KEY_LEN);
+        ciphertext[..NONCE_LEN].copy_from_slice(nonce);
+        Ok(())
+    }
+
+    /// Like [rosenpass_ciphers::xaead::decrypt], failing or not at random
+    pub fn xaead_decrypt(
+        plaintext: &mut [u8],
+        key: &[u8],
+        _ad: &[u8],
+        ciphertext: &[u8],
+    ) -> anyhow::Result<()> {
+        assert!(ciphertext.len() == plaintext.len() + NONCE_LEN + TAG_LEN);
+        assert!(key.len() == KEY_LEN);
+        anyhow::ensure!(kani::any::<bool>(), "forged ciphertext");
+        Ok(())
+    }
+}
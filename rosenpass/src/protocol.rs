@@ -69,18 +69,25 @@
 
 use crate::{
     coloring::*,
-    labeled_prf as lprf,
+    extensions::{self, Extension, Features, EXTENSIONS, IDENTITY_HIDING},
+    labeled_prf as lprf, lazy_keys,
     msgs::*,
     pqkem::*,
     prftree::{SecretPrfTree, SecretPrfTreeBranch},
+    shared_pk::SharedPk,
     sodium::*,
 };
 use anyhow::{bail, ensure, Context, Result};
 use rosenpass_ciphers::{aead, xaead};
+use rosenpass_constant_time as constant_time;
 use rosenpass_util::{cat, mem::cpy_min, ord::max_usize, time::Timebase};
-use std::collections::hash_map::{
-    Entry::{Occupied, Vacant},
-    HashMap,
+use serde::{Deserialize, Serialize};
+use std::collections::{
+    hash_map::{
+        Entry::{Occupied, Vacant},
+        HashMap,
+    },
+    VecDeque,
 };
 
 // CONSTANTS & SETTINGS //////////////////////////
@@ -110,11 +117,38 @@ pub const REKEY_AFTER_TIME_RESPONDER: Timing = 120.0;
 pub const REKEY_AFTER_TIME_INITIATOR: Timing = 130.0;
 pub const REJECT_AFTER_TIME: Timing = 180.0;
 
+// Default of CryptoServer::rekey_margin; the seconds before a key is rejected
+// at which the responder starts the next handshake
+pub const REKEY_MARGIN: Timing = REJECT_AFTER_TIME - REKEY_AFTER_TIME_RESPONDER;
+// Extra wait of the initiator before starting the next handshake itself
+pub const REKEY_STAGGER: Timing = REKEY_AFTER_TIME_INITIATOR - REKEY_AFTER_TIME_RESPONDER;
+
 // Seconds until the biscuit key is changed; we issue biscuits
 // using one biscuit key for one epoch and store the biscuit for
 // decryption for a second epoch
 pub const BISCUIT_EPOCH: Timing = 300.0;
 
+/// How InitConf messages are checked for being replayed
+///
+/// Every InitConf carries the biscuit from the RespHello it answers; biscuits
+/// are numbered, and a peer's biscuit number must not go backwards. Biscuits
+/// can be decrypted for at least one [CryptoServer::biscuit_epoch] and at most
+/// two, after which their InitConf is rejected no matter its number.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ReplayMode {
+    /// Accept no biscuit number twice, not even that of a retransmitted
+    /// InitConf; should the answer be lost, the initiator keeps retransmitting
+    /// until it gives up, unless a keepalive confirms the key
+    Strict,
+    /// Accept the most recent biscuit number again, to answer retransmissions
+    #[default]
+    Normal,
+    /// Like [ReplayMode::Normal], but keep retired biscuit keys for up to two
+    /// epochs rather than one, for InitConfs held up by misbehaving timers
+    Lenient,
+}
+
 // Retransmission pub constants; will retransmit for up to _ABORT ms; starting with a delay of
 // _DELAY_BEG ms and increasing the delay exponentially by a factor of
 // _DELAY_GROWTH up to _DELAY_END. An additional jitter factor of ±_DELAY_JITTER
@@ -125,6 +159,10 @@ pub const RETRANSMIT_DELAY_BEGIN: Timing = 0.5;
 pub const RETRANSMIT_DELAY_END: Timing = 10.0;
 pub const RETRANSMIT_DELAY_JITTER: Timing = 0.5;
 
+/// Retransmissions of an InitHello after which the other public key of a peer
+/// in a key rollover is tried instead; see [Rollover]
+pub const ROLLOVER_RETRANSMISSIONS: usize = 4;
+
 pub const EVENT_GRACE: Timing = 0.0025;
 
 // UTILITY FUNCTIONS /////////////////////////////
@@ -185,6 +223,30 @@ pub struct CryptoServer {
 
     // Tick handling
     pub peer_poll_off: usize,
+    /// Width of the random part of the retransmission delay, relative to the
+    /// delay; see [RETRANSMIT_DELAY_JITTER]
+    pub retransmit_jitter: Timing,
+    /// Seconds before a key is rejected at which the next handshake is
+    /// started; see [REKEY_MARGIN]
+    pub rekey_margin: Timing,
+    /// Seconds a biscuit key is used for; see [BISCUIT_EPOCH]
+    pub biscuit_epoch: Timing,
+    pub replay_mode: ReplayMode,
+    /// Appended to the handshake messages for peers which understand them;
+    /// see [crate::extensions]
+    pub extensions: Vec<Extension>,
+    /// Ask responders not to key the messages to us with our public key; see
+    /// [IDENTITY_HIDING]
+    pub identity_hiding: bool,
+    /// The optional parts of the protocol we advertise to understand, all of
+    /// [extensions::SUPPORTED] unless told otherwise
+    pub supported: Features,
+    /// Keys of peers loaded on demand are kept loaded for this many peers at
+    /// most; see [crate::lazy_keys]
+    pub key_cache: usize,
+    /// The peers whose keys loaded on demand are loaded, the one used last
+    /// at the end
+    pub loaded_keys: VecDeque<PeerPtr>,
 }
 
 /// A Biscuit is like a fancy cookie. To avoid state disruption attacks,
@@ -207,22 +269,44 @@ pub enum IndexKey {
 #[derive(Debug)]
 pub struct Peer {
     pub psk: SymKey,
-    pub spkt: SPk,
+    pub spkt: SharedPk,
     pub biscuit_used: BiscuitId,
     pub session: Option<Session>,
     pub handshake: Option<InitiatorHandshake>,
     pub initiation_requested: bool,
+    pub rollover: Option<Rollover>,
+    /// What the last handshake message of the peer advertised
+    pub features: Features,
+}
+
+/// The second public key of a peer rotating its identity
+///
+/// During the transition, InitHellos are accepted under both keys, and
+/// [Peer::spkt] is the one the peer used last. Handshakes we initiate go to
+/// [Peer::spkt] at first and switch to the other key once
+/// [ROLLOVER_RETRANSMISSIONS] retransmissions went unanswered. The old key is
+/// dropped at [Rollover::until].
+#[derive(Debug)]
+pub struct Rollover {
+    /// The key of the two not in [Peer::spkt]
+    pub spare: SharedPk,
+    /// Whether [Peer::spkt] is the old key
+    pub using_old: bool,
+    /// When the old key stops being accepted
+    pub until: Timing,
 }
 
 impl Peer {
     pub fn zero() -> Self {
         Self {
             psk: SymKey::zero(),
-            spkt: SPk::zero(),
+            spkt: SharedPk::zero(),
             biscuit_used: BiscuitId::zero(),
             session: None,
             initiation_requested: false,
             handshake: None,
+            rollover: None,
+            features: 0,
         }
     }
 }
@@ -359,6 +443,26 @@ impl PeerPtr {
     pub fn hs(&self) -> IniHsPtr {
         IniHsPtr(self.0)
     }
+
+    /// Swap [Peer::spkt] for the other key of a peer in a key rollover
+    pub fn swap_keys(&self, srv: &mut CryptoServer) {
+        let Peer { spkt, rollover, .. } = self.get_mut(srv);
+        if let Some(r) = rollover.as_mut() {
+            std::mem::swap(spkt, &mut r.spare);
+            r.using_old = !r.using_old;
+        }
+    }
+
+    /// Drop the old key of a peer in a key rollover
+    pub fn end_rollover(&self, srv: &mut CryptoServer) -> Result<()> {
+        if self.get(srv).rollover.as_ref().is_some_and(|r| r.using_old) {
+            self.swap_keys(srv);
+        }
+        if let Some(r) = self.get_mut(srv).rollover.take() {
+            srv.index.remove(&IndexKey::Peer(peer_id(&r.spare)?));
+        }
+        Ok(())
+    }
 }
 
 impl IniHsPtr {
@@ -430,6 +534,30 @@ impl BiscuitKeyPtr {
     pub fn get_mut<'a>(&self, srv: &'a mut CryptoServer) -> &'a mut BiscuitKey {
         &mut srv.biscuit_keys[self.0]
     }
+
+    /// The biscuit key `biscuit_ct` was sealed with, told by the first bit
+    /// of its nonce
+    pub fn of_biscuit(biscuit_ct: &[u8]) -> Result<Self> {
+        ensure!(
+            biscuit_ct.len() == BISCUIT_CT_LEN,
+            "Rejecting biscuit: Expected {BISCUIT_CT_LEN} bytes, got {}",
+            biscuit_ct.len()
+        );
+        Ok(Self(((biscuit_ct[0] & 0b1000_0000) >> 7) as usize))
+    }
+
+    /// Set the first bit of `nonce` to tell [Self::of_biscuit] this key
+    ///
+    /// This way a biscuit is opened with one decryption instead of trying
+    /// both keys, so a forged InitConf costs the responder no more than one,
+    /// and the Kani proofs can tell the key of any biscuit. The bit taken
+    /// from the random nonce leaves 191, plenty for random nonces; it lies
+    /// outside of the 96 bits AES-256-GCM takes in FIPS-constrained mode,
+    /// see [rosenpass_ciphers::xaead].
+    pub fn mark_nonce(&self, nonce: &mut [u8]) {
+        nonce[0] &= 0b0111_1111;
+        nonce[0] |= (self.0 as u8 & 0x1) << 7;
+    }
 }
 
 // DATABASE //////////////////////////////////////
@@ -450,9 +578,23 @@ impl CryptoServer {
             peers: Vec::new(),
             index: HashMap::new(),
             peer_poll_off: 0,
+            retransmit_jitter: RETRANSMIT_DELAY_JITTER,
+            rekey_margin: REKEY_MARGIN,
+            biscuit_epoch: BISCUIT_EPOCH,
+            replay_mode: ReplayMode::Normal,
+            extensions: Vec::new(),
+            identity_hiding: false,
+            supported: extensions::SUPPORTED,
+            key_cache: lazy_keys::DEFAULT_CACHE,
+            loaded_keys: VecDeque::new(),
         }
     }
 
+    /// Seconds after a key exchange the next handshake is started at the latest
+    pub fn rekey_after(&self) -> Timing {
+        REJECT_AFTER_TIME - self.rekey_margin + REKEY_STAGGER
+    }
+
     /// Iterate over the many (2) biscuit keys
     pub fn biscuit_key_ptrs(&self) -> impl Iterator<Item = BiscuitKeyPtr> {
         (0..self.biscuit_keys.len()).map(BiscuitKeyPtr)
@@ -475,7 +617,10 @@ impl CryptoServer {
     }
 
     /// Add a peer with an optional pre shared key (`psk`) and its public key (`pk`)
-    pub fn add_peer(&mut self, psk: Option<SymKey>, pk: SPk) -> Result<PeerPtr> {
+    pub fn add_peer(&mut self, psk: Option<SymKey>, pk: impl Into<SharedPk>) -> Result<PeerPtr> {
+        let mut pk = pk.into();
+        // loaded again once it is needed, so it counts towards key_cache
+        pk.unload();
         let peer = Peer {
             psk: psk.unwrap_or_else(SymKey::zero),
             spkt: pk,
@@ -483,6 +628,8 @@ impl CryptoServer {
             session: None,
             handshake: None,
             initiation_requested: false,
+            rollover: None,
+            features: 0,
         };
         let peerid = peer.pidt()?;
         let peerno = self.peers.len();
@@ -497,6 +644,71 @@ impl CryptoServer {
         Ok(PeerPtr(peerno))
     }
 
+    /// Stop accepting messages of `peer`: its session and handshake are
+    /// erased and its ids forgotten
+    pub fn forget_peer(&mut self, peer: PeerPtr) -> Result<()> {
+        peer.session().take(self);
+        peer.hs().take(self);
+        let (new, old) = peer.get(self).rollover_keys();
+        let ids = [Some(peer_id(new)?), old.map(peer_id).transpose()?];
+        for id in ids.into_iter().flatten() {
+            self.index.remove(&IndexKey::Peer(id));
+        }
+        Ok(())
+    }
+
+    /// Accept `old` as public key of `peer` too, for the next `window`
+    /// seconds; see [Rollover]
+    pub fn add_rollover_key(
+        &mut self,
+        peer: PeerPtr,
+        old: impl Into<SharedPk>,
+        window: Timing,
+    ) -> Result<()> {
+        ensure!(
+            peer.get(self).rollover.is_none(),
+            "Peer {peer:?} already has an old public key."
+        );
+        let old = old.into();
+        let peerid = peer_id(&old)?;
+        match self.index.entry(IndexKey::Peer(peerid)) {
+            Occupied(_) => bail!(
+                "Cannot add old public key with id {:?}; peer with this id already registered.",
+                peerid
+            ),
+            Vacant(e) => e.insert(peer.0),
+        };
+        peer.get_mut(self).rollover = Some(Rollover {
+            spare: old,
+            using_old: false,
+            until: self.timebase.now() + window,
+        });
+        Ok(())
+    }
+
+    /// Load the public key of `peer` if it is loaded on demand, and unload
+    /// those of the peers used least recently beyond [CryptoServer::key_cache];
+    /// peers in a handshake we initiated keep their key
+    pub fn load_key(&mut self, peer: PeerPtr) -> Result<()> {
+        if !peer.get(self).spkt.is_lazy() {
+            return Ok(());
+        }
+        peer.get_mut(self).spkt.load()?;
+        self.loaded_keys.retain(|&p| p != peer);
+        self.loaded_keys.push_back(peer);
+        let mut i = 0;
+        while self.loaded_keys.len() > self.key_cache && i + 1 < self.loaded_keys.len() {
+            let p = self.loaded_keys[i];
+            if p.get(self).handshake.is_some() {
+                i += 1;
+                continue;
+            }
+            self.loaded_keys.remove(i);
+            p.get_mut(self).spkt.unload();
+        }
+        Ok(())
+    }
+
     /// Register a new session (during a successful handshake, persisting longer
     /// than the handshake). Might return an error on session id collision
     pub fn register_session(&mut self, id: SessionId, peer: PeerPtr) -> Result<()> {
@@ -555,6 +767,119 @@ impl CryptoServer {
             })
     }
 
+    /// The peer the message in `rx_buf` belongs to, as far as this can be told
+    /// without public key cryptography; the message is not authenticated
+    ///
+    /// None for InitHello messages, whose sender is only known after
+    /// decapsulating them, and for messages of unknown sessions.
+    pub fn peer_of(&self, rx_buf: &[u8]) -> Option<PeerPtr> {
+        // without the extension area, if any
+        fn msg<M: LenseView>(rx_buf: &[u8]) -> Option<&[u8]> {
+            rx_buf.get(..<Envelope<(), M> as LenseView>::LEN)
+        }
+        match MsgType::try_from(*rx_buf.first()?).ok()? {
+            MsgType::RespHello => {
+                let rx_buf = msg::<RespHello<()>>(rx_buf)?;
+                let env = rx_buf.envelope::<RespHello<&[u8]>>().ok()?;
+                let rh = env.payload().resp_hello().ok()?;
+                let hs = self.lookup_handshake(SessionId::from_slice(rh.sidi()))?;
+                Some(hs.peer())
+            }
+            MsgType::InitConf => {
+                let rx_buf = msg::<InitConf<()>>(rx_buf)?;
+                let env = rx_buf.envelope::<InitConf<&[u8]>>().ok()?;
+                let ic = env.payload().init_conf().ok()?;
+                let (sidi, sidr) = (ic.sidi(), ic.sidr());
+                let (sidi, sidr) = (SessionId::from_slice(sidi), SessionId::from_slice(sidr));
+                let (peer, _, _, _) =
+                    HandshakeState::load_biscuit(self, ic.biscuit(), sidi, sidr).ok()?;
+                Some(peer)
+            }
+            MsgType::EmptyData => {
+                let rx_buf = msg::<EmptyData<()>>(rx_buf)?;
+                let env = rx_buf.envelope::<EmptyData<&[u8]>>().ok()?;
+                let rc = env.payload().empty_data().ok()?;
+                let hs = self.lookup_handshake(SessionId::from_slice(rc.sid()))?;
+                Some(hs.peer())
+            }
+            MsgType::Keepalive => {
+                let env = rx_buf.envelope::<EmptyData<&[u8]>>().ok()?;
+                let ka = env.payload().empty_data().ok()?;
+                let ses = self.lookup_session(SessionId::from_slice(ka.sid()))?;
+                Some(ses.peer())
+            }
+            MsgType::Rendezvous => {
+                let env = rx_buf.envelope::<Rendezvous<&[u8]>>().ok()?;
+                let rv = env.payload().rendezvous().ok()?;
+                let ses = self.lookup_session(SessionId::from_slice(rv.sid()))?;
+                Some(ses.peer())
+            }
+            MsgType::Relay => {
+                let env = rx_buf.envelope::<Relay<&[u8]>>().ok()?;
+                let rl = env.payload().relay().ok()?;
+                let ses = self.lookup_session(SessionId::from_slice(rl.sid()))?;
+                Some(ses.peer())
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether the message of `msg_type` to `peer` is sealed with our public
+    /// key rather than with that of `peer`: `peer` asked for
+    /// [IDENTITY_HIDING] and we are the responder of the handshake or
+    /// session the message belongs to
+    pub fn hides_identity(&self, peer: PeerPtr, msg_type: MsgType) -> bool {
+        let p = peer.get(self);
+        if p.features & IDENTITY_HIDING == 0 {
+            return false;
+        }
+        match msg_type {
+            MsgType::RespHello | MsgType::EmptyData => true,
+            MsgType::Keepalive | MsgType::Rendezvous | MsgType::Relay => p
+                .session
+                .as_ref()
+                .is_some_and(|s| !s.handshake_role.is_initiator()),
+            _ => false,
+        }
+    }
+
+    /// The public key to seal the message of `msg_type` to `peer` with
+    pub fn seal_key(&self, peer: PeerPtr, msg_type: MsgType) -> &[u8] {
+        match self.hides_identity(peer, msg_type) {
+            true => self.spkm.secret(),
+            false => peer.get(self).spkt.value(),
+        }
+    }
+
+    /// The public keys the message in `rx_buf` may be sealed with: ours and,
+    /// with [CryptoServer::identity_hiding], those of the responders which
+    /// may have sent it
+    pub fn seal_keys(&self, rx_buf: &[u8]) -> Vec<&[u8]> {
+        let mut keys = vec![self.spkm.secret().as_slice()];
+        if !self.identity_hiding {
+            return keys;
+        }
+        match rx_buf.first().map(|&t| MsgType::try_from(t)) {
+            Some(Ok(MsgType::Fragment)) => {
+                let responders = self.peers.iter().filter(|p| {
+                    p.handshake.is_some()
+                        || p.session
+                            .as_ref()
+                            .is_some_and(|s| s.handshake_role.is_initiator())
+                });
+                // keys loaded on demand are not for identity hiding
+                let responders = responders.filter(|p| p.spkt.is_loaded());
+                keys.extend(responders.map(|p| p.spkt.value().as_slice()));
+            }
+            _ => keys.extend(
+                self.peer_of(rx_buf)
+                    .filter(|peer| peer.get(self).spkt.is_loaded())
+                    .map(|peer| peer.get(self).spkt.value().as_slice()),
+            ),
+        }
+        keys
+    }
+
     /// Swap the biscuit keys, also advancing both biscuit key's mortality
     pub fn active_biscuit_key(&mut self) -> BiscuitKeyPtr {
         let (a, b) = (BiscuitKeyPtr(0), BiscuitKeyPtr(1));
@@ -577,24 +902,67 @@ impl CryptoServer {
 }
 
 impl Peer {
-    pub fn new(psk: SymKey, pk: SPk) -> Peer {
+    pub fn new(psk: SymKey, pk: impl Into<SharedPk>) -> Peer {
         Peer {
             psk,
-            spkt: pk,
+            spkt: pk.into(),
             biscuit_used: BiscuitId::zero(),
             session: None,
             handshake: None,
             initiation_requested: false,
+            rollover: None,
+            features: 0,
         }
     }
 
-    #[rustfmt::skip]
     pub fn pidt(&self) -> Result<PeerId> {
-        Ok(Public::new(
-            lprf::peerid()?
-                .mix(self.spkt.secret())?
-                .into_value()))
+        peer_id(&self.spkt)
+    }
+
+    /// The new public key, and the old one if the peer is in a key rollover
+    pub fn rollover_keys(&self) -> (&SharedPk, Option<&SharedPk>) {
+        match self.rollover.as_ref() {
+            Some(r) if r.using_old => (&r.spare, Some(&self.spkt)),
+            Some(r) => (&self.spkt, Some(&r.spare)),
+            None => (&self.spkt, None),
+        }
+    }
+
+    /// Whether the peer using the public key with id `pid` means switching
+    /// to the other key of its key rollover; fails if that is the old key
+    /// and it is not accepted anymore
+    pub fn rollover_to(&self, pid: &PeerId, now: Timing) -> Result<bool> {
+        let Some(r) = self.rollover.as_ref() else {
+            return Ok(false);
+        };
+        let other = self.pidt()? != *pid;
+        ensure!(
+            other != r.using_old || now <= r.until,
+            "Rejecting old public key of peer {:?}: its rollover ended.",
+            self.pidt()?
+        );
+        Ok(other)
+    }
+
+    /// [Peer::spkt], or the other key of the rollover if `other` is set
+    pub fn key(&self, other: bool) -> &SharedPk {
+        match (other, self.rollover.as_ref()) {
+            (true, Some(r)) => &r.spare,
+            _ => &self.spkt,
+        }
+    }
+}
+
+#[rustfmt::skip]
+pub fn peer_id(pk: &SharedPk) -> Result<PeerId> {
+    // keys loaded on demand are known by their id while they are not loaded
+    if let Some(id) = pk.id() {
+        return Ok(*id);
     }
+    Ok(Public::new(
+        lprf::peerid()?
+            .mix(pk.value())?
+            .into_value()))
 }
 
 impl Session {
@@ -670,11 +1038,11 @@ impl Mortal for SessionPtr {
         // and responder role.
         use HandshakeRole::*;
         self.get(srv).as_ref().map(|p| {
-            let wait = match p.handshake_role {
-                Initiator => REKEY_AFTER_TIME_INITIATOR,
-                Responder => REKEY_AFTER_TIME_RESPONDER,
+            let stagger = match p.handshake_role {
+                Initiator => REKEY_STAGGER,
+                Responder => 0.0,
             };
-            p.created_at + wait
+            p.created_at + REJECT_AFTER_TIME - srv.rekey_margin + stagger
         })
     }
 
@@ -694,11 +1062,15 @@ impl Mortal for BiscuitKeyPtr {
     }
 
     fn retire_at(&self, srv: &CryptoServer) -> Option<Timing> {
-        self.created_at(srv).map(|t| t + BISCUIT_EPOCH)
+        self.created_at(srv).map(|t| t + srv.biscuit_epoch)
     }
 
     fn die_at(&self, srv: &CryptoServer) -> Option<Timing> {
-        self.retire_at(srv).map(|t| t + BISCUIT_EPOCH)
+        let epochs = match srv.replay_mode {
+            ReplayMode::Lenient => 2.0,
+            ReplayMode::Strict | ReplayMode::Normal => 1.0,
+        };
+        self.retire_at(srv).map(|t| t + epochs * srv.biscuit_epoch)
     }
 }
 
@@ -738,6 +1110,7 @@ impl CryptoServer {
     // TODO remove unnecessary copying between global tx_buf and per-peer buf
     // TODO move retransmission storage to io server
     pub fn initiate_handshake(&mut self, peer: PeerPtr, tx_buf: &mut [u8]) -> Result<usize> {
+        self.load_key(peer)?;
         let mut msg = tx_buf.envelope_truncating::<InitHello<()>>()?; // Envelope::<InitHello>::default(); // TODO
         self.handle_initiation(peer, msg.payload_mut().init_hello()?)?;
         let len = self.seal_and_commit_msg(peer, MsgType::InitHello, msg)?;
@@ -749,8 +1122,14 @@ impl CryptoServer {
 
 #[derive(Debug)]
 pub struct HandleMsgResult {
+    /// The peer the message came from
+    pub peer: PeerPtr,
+    /// The peer the message confirmed both sides to have the new key with;
+    /// only then may the key be used
     pub exchanged_with: Option<PeerPtr>,
     pub resp: Option<usize>,
+    /// All extensions which came with the message, known or not
+    pub extensions: Vec<Extension>,
 }
 
 impl CryptoServer {
@@ -771,7 +1150,7 @@ impl CryptoServer {
     /// 5. seal the response with cryptographic authentication
     /// 6. if the response is a ResponseHello, store the sealed response for
     ///    further retransmission
-    /// 7. return some peer pointer if the exchange completed with this message
+    /// 7. return some peer pointer if this message confirmed the exchange
     /// 8. return the length of the response generated
     ///
     /// This is the sequence of a successful handshake:
@@ -782,74 +1161,215 @@ impl CryptoServer {
     /// | t1   |             | <-        | `RespHello` |
     /// | t2   | `InitConf`  | ->        |             |
     /// | t3   |             | <-        | `EmptyData` |
+    ///
+    /// The responder has the key at t2 and knows the initiator to have it,
+    /// too, as the `InitConf` is authenticated with it. The initiator has the
+    /// key at t1 already, but only learns that the responder got it from the
+    /// `EmptyData`, or from a keepalive on the new session should that be
+    /// lost; until then, the key is not reported as exchanged, since the
+    /// responder may still be using the old one.
     pub fn handle_msg(&mut self, rx_buf: &[u8], tx_buf: &mut [u8]) -> Result<HandleMsgResult> {
         let seal_broken = "Message seal broken!";
         // length of the response. We assume no response, so None for now
         let mut len = 0;
         let mut exchanged = false;
+        let mut extensions = Vec::new();
 
         ensure!(!rx_buf.is_empty(), "received empty message, ignoring it");
 
         let peer = match rx_buf[0].try_into() {
             Ok(MsgType::InitHello) => {
+                let (rx_buf, exts) = self.split_extensions::<InitHello<()>>(rx_buf)?;
                 let msg_in = rx_buf.envelope::<InitHello<&[u8]>>()?;
                 ensure!(msg_in.check_seal(self)?, seal_broken);
 
+                ensure!(exts.is_none(), "an InitHello carries no extension area");
+                let features = extensions::features(msg_in.reserved());
+                let mut sending = self.sending_transcript(features);
+                if !sending.is_empty() {
+                    sending.splice(0..0, extensions::transcript(features, None));
+                }
                 let mut msg_out = tx_buf.envelope_truncating::<RespHello<&mut [u8]>>()?;
                 let peer = self.handle_init_hello(
                     msg_in.payload().init_hello()?,
                     msg_out.payload_mut().resp_hello()?,
+                    &sending,
                 )?;
+                peer.get_mut(self).features = features;
                 len = self.seal_and_commit_msg(peer, MsgType::RespHello, msg_out)?;
+                len = self.append_extensions(peer, tx_buf, len)?;
+                extensions = exts.unwrap_or_default();
                 peer
             }
             Ok(MsgType::RespHello) => {
+                let (rx_buf, exts) = self.split_extensions::<RespHello<()>>(rx_buf)?;
                 let msg_in = rx_buf.envelope::<RespHello<&[u8]>>()?;
                 ensure!(msg_in.check_seal(self)?, seal_broken);
 
+                let features = extensions::features(msg_in.reserved());
+                let mut received = self.received_transcript(features, exts.as_deref())?;
+                if !received.is_empty() {
+                    let init_hello = extensions::transcript(self.advertised(), None);
+                    received.splice(0..0, init_hello);
+                }
                 let mut msg_out = tx_buf.envelope_truncating::<InitConf<&mut [u8]>>()?;
                 let peer = self.handle_resp_hello(
                     msg_in.payload().resp_hello()?,
                     msg_out.payload_mut().init_conf()?,
+                    &received,
+                    &self.sending_transcript(features),
                 )?;
+                peer.get_mut(self).features = features;
                 len = self.seal_and_commit_msg(peer, MsgType::InitConf, msg_out)?;
+                len = self.append_extensions(peer, tx_buf, len)?;
                 peer.hs()
                     .store_msg_for_retransmission(self, &tx_buf[..len])?;
-                exchanged = true;
+                extensions = exts.unwrap_or_default();
                 peer
             }
             Ok(MsgType::InitConf) => {
+                let (rx_buf, exts) = self.split_extensions::<InitConf<()>>(rx_buf)?;
                 let msg_in = rx_buf.envelope::<InitConf<&[u8]>>()?;
                 ensure!(msg_in.check_seal(self)?, seal_broken);
 
+                let features = extensions::features(msg_in.reserved());
+                let received = self.received_transcript(features, exts.as_deref())?;
                 let mut msg_out = tx_buf.envelope_truncating::<EmptyData<&mut [u8]>>()?;
                 let peer = self.handle_init_conf(
                     msg_in.payload().init_conf()?,
                     msg_out.payload_mut().empty_data()?,
+                    &received,
+                    &self.sending_transcript(features),
                 )?;
+                peer.get_mut(self).features = features;
                 len = self.seal_and_commit_msg(peer, MsgType::EmptyData, msg_out)?;
+                len = self.append_extensions(peer, tx_buf, len)?;
                 exchanged = true;
+                extensions = exts.unwrap_or_default();
                 peer
             }
             Ok(MsgType::EmptyData) => {
+                let (rx_buf, exts) = self.split_extensions::<EmptyData<()>>(rx_buf)?;
                 let msg_in = rx_buf.envelope::<EmptyData<&[u8]>>()?;
                 ensure!(msg_in.check_seal(self)?, seal_broken);
 
-                self.handle_resp_conf(msg_in.payload().empty_data()?)?
+                let features = extensions::features(msg_in.reserved());
+                let received = self.received_transcript(features, exts.as_deref())?;
+                let peer = self.handle_resp_conf(msg_in.payload().empty_data()?, &received)?;
+                peer.get_mut(self).features = features;
+                exchanged = true;
+                extensions = exts.unwrap_or_default();
+                peer
             }
             Ok(MsgType::DataMsg) => bail!("DataMsg handling not implemented!"),
             Ok(MsgType::CookieReply) => bail!("CookieReply handling not implemented!"),
+            Ok(MsgType::Keepalive) => {
+                let msg_in = rx_buf.envelope::<EmptyData<&[u8]>>()?;
+                ensure!(msg_in.check_seal(self)?, seal_broken);
+
+                let peer = self.handle_keepalive(msg_in.payload().empty_data()?)?;
+                // the EmptyData confirming the session got lost
+                let sid = SessionId::from_slice(msg_in.payload().empty_data()?.sid());
+                let confirming = |hs: &IniHsPtr| {
+                    let next = hs.get(self).as_ref().map(|hs| hs.next);
+                    next == Some(HandshakeStateMachine::RespConf)
+                };
+                if let Some(hs) = self.lookup_handshake(sid).filter(confirming) {
+                    hs.take(self);
+                    exchanged = true;
+                }
+                peer
+            }
+            Ok(MsgType::Fragment) => bail!("Fragments must be reassembled before handling them"),
+            Ok(MsgType::Rendezvous | MsgType::Relay) => {
+                bail!("Rendezvous and relay messages must be handled by the application")
+            }
             Err(_) => {
                 bail!("CookieReply handling not implemented!")
             }
         };
 
         Ok(HandleMsgResult {
+            peer,
             exchanged_with: exchanged.then_some(peer),
             resp: if len == 0 { None } else { Some(len) },
+            extensions,
         })
     }
 
+    /// Split the extension area, if any, off a received message of type `M`
+    fn split_extensions<'a, M: LenseView>(
+        &self,
+        rx_buf: &'a [u8],
+    ) -> Result<(&'a [u8], Option<Vec<Extension>>)> {
+        let len = <Envelope<(), M> as LenseView>::LEN;
+        // a message too short is rejected by its lense
+        if rx_buf.len() <= len {
+            return Ok((rx_buf, None));
+        }
+        let mut res = Err(anyhow::anyhow!("extension area seal broken"));
+        for spk in self.seal_keys(rx_buf) {
+            res = extensions::open(rx_buf, len, spk);
+            if res.is_ok() {
+                break;
+            }
+        }
+        Ok((&rx_buf[..len], Some(res?)))
+    }
+
+    /// The features we advertise in the envelopes of our messages
+    fn advertised(&self) -> Features {
+        match self.identity_hiding {
+            true => self.supported,
+            false => self.supported & !IDENTITY_HIDING,
+        }
+    }
+
+    /// The extensions to append to messages for a peer advertising
+    /// `features`, if any
+    fn extensions_for(&self, features: Features) -> Option<&[Extension]> {
+        let understood = self.advertised() & features & EXTENSIONS != 0;
+        (understood && !self.extensions.is_empty()).then_some(self.extensions.as_slice())
+    }
+
+    /// What the handshake mixes in for a received message with `features`
+    /// and the extension area `area`: nothing, unless both sides advertise
+    /// features; see [crate::extensions]
+    fn received_transcript(
+        &self,
+        features: Features,
+        area: Option<&[Extension]>,
+    ) -> Result<Vec<u8>> {
+        if self.advertised() == 0 || features == 0 {
+            ensure!(
+                area.is_none(),
+                "an extension area from a peer without features"
+            );
+            return Ok(Vec::new());
+        }
+        Ok(extensions::transcript(features, area))
+    }
+
+    /// What the handshake mixes in for our answer to a message with
+    /// `features`, see [Self::received_transcript]
+    fn sending_transcript(&self, features: Features) -> Vec<u8> {
+        if self.advertised() == 0 || features == 0 {
+            return Vec::new();
+        }
+        extensions::transcript(self.advertised(), self.extensions_for(features))
+    }
+
+    /// Append [CryptoServer::extensions] to the message of `len` bytes in
+    /// `tx_buf`, if `peer` advertised to understand them
+    fn append_extensions(&self, peer: PeerPtr, tx_buf: &mut [u8], len: usize) -> Result<usize> {
+        let Some(exts) = self.extensions_for(peer.get(self).features) else {
+            return Ok(len);
+        };
+        let msg_type = tx_buf[0].try_into()?;
+        let spk = self.seal_key(peer, msg_type);
+        extensions::seal(tx_buf, len, exts, spk)
+    }
+
     /// Serialize message to `tx_buf`, generating the `mac` in the process of
     /// doing so
     ///
@@ -862,7 +1382,9 @@ impl CryptoServer {
         mut msg: Envelope<&mut [u8], M>,
     ) -> Result<usize> {
         msg.msg_type_mut()[0] = msg_type as u8;
-        msg.seal(peer, self)?;
+        extensions::store_features(self.advertised(), msg.reserved_mut());
+        self.load_key(peer)?;
+        msg.seal_for(self.seal_key(peer, msg_type))?;
         Ok(<Envelope<(), M> as LenseView>::LEN)
     }
 }
@@ -1061,6 +1583,14 @@ impl CryptoServer {
         };
         r.ok()
     }
+
+    /// Like [CryptoServer::poll], but only for the biscuit keys; for
+    /// applications polling the peers themselves with [Pollable::poll]
+    pub fn poll_biscuits(&mut self) -> Result<PollResult> {
+        begin_poll()
+            .poll_children(self, self.biscuit_key_ptrs())?
+            .ok()
+    }
 }
 
 impl Pollable for BiscuitKeyPtr {
@@ -1074,7 +1604,16 @@ impl Pollable for BiscuitKeyPtr {
 impl Pollable for PeerPtr {
     fn poll(&self, srv: &mut CryptoServer) -> Result<PollResult> {
         let (ses, hs) = (self.session(), self.hs());
+        let rollover_left = self
+            .get(srv)
+            .rollover
+            .as_ref()
+            .map(|r| r.until - srv.timebase.now());
         begin_poll()
+            .try_sched(rollover_left, || {
+                self.end_rollover(srv)?;
+                Ok(PollResult::default())
+            })?
             .sched(hs.life_left(srv), void_poll(|| hs.take(srv))) // Silently erase old handshakes
             .sched(ses.life_left(srv), || {
                 // Erase old sessions
@@ -1102,6 +1641,14 @@ impl Pollable for PeerPtr {
 impl Pollable for IniHsPtr {
     fn poll(&self, srv: &mut CryptoServer) -> Result<PollResult> {
         begin_poll().try_sched(self.retransmission_in(srv), || {
+            // A peer in a key rollover may not answer under this key
+            let tx_count = self.get(srv).as_ref().map_or(0, |hs| hs.tx_count);
+            if self.peer().get(srv).rollover.is_some() && tx_count > ROLLOVER_RETRANSMISSIONS {
+                self.take(srv);
+                self.peer().swap_keys(srv);
+                self.peer().get_mut(srv).initiation_requested = true;
+                return Ok(PollResult::SendInitiation(self.peer()));
+            }
             // Registering retransmission even if app does not retransmit.
             // This explicitly permits applications to ignore the event.
             self.register_retransmission(srv)?;
@@ -1141,12 +1688,12 @@ impl IniHsPtr {
     }
 
     pub fn register_retransmission(&self, srv: &mut CryptoServer) -> Result<()> {
-        let tb = srv.timebase.clone();
+        let (tb, jitter) = (srv.timebase.clone(), srv.retransmit_jitter);
         let ih = self
             .get_mut(srv)
             .as_mut()
             .with_context(|| format!("No current handshake for peer {:?}", self.peer()))?;
-        // Base delay, exponential increase, ±50% jitter
+        // Base delay, exponential increase, jitter; by default 50% to 100% of the delay
         ih.tx_retry_at = tb.now()
             + RETRANSMIT_DELAY_BEGIN
                 * RETRANSMIT_DELAY_GROWTH.powf(
@@ -1154,8 +1701,7 @@ impl IniHsPtr {
                         .log(RETRANSMIT_DELAY_GROWTH)
                         .min(ih.tx_count as f64),
                 )
-                * RETRANSMIT_DELAY_JITTER
-                * (rosenpass_sodium::helpers::rand_f64() + 1.0); // TODO: Replace with the rand crate
+                * (RETRANSMIT_DELAY_JITTER + jitter * rosenpass_sodium::helpers::rand_f64()); // TODO: Replace with the rand crate
         ih.tx_count += 1;
         Ok(())
     }
@@ -1175,9 +1721,12 @@ where
 {
     /// Calculate the message authentication code (`mac`)
     pub fn seal(&mut self, peer: PeerPtr, srv: &CryptoServer) -> Result<()> {
-        let mac = lprf::mac()?
-            .mix(peer.get(srv).spkt.secret())?
-            .mix(self.until_mac())?;
+        self.seal_for(peer.get(srv).spkt.value())
+    }
+
+    /// Calculate the `mac` keyed with the public key `spk`
+    pub fn seal_for(&mut self, spk: &[u8]) -> Result<()> {
+        let mac = lprf::mac()?.mix(spk)?.mix(self.until_mac())?;
         self.mac_mut()
             .copy_from_slice(mac.into_value()[..16].as_ref());
         Ok(())
@@ -1190,11 +1739,13 @@ where
 {
     /// Check the message authentication code
     pub fn check_seal(&self, srv: &CryptoServer) -> Result<bool> {
-        let expected = lprf::mac()?.mix(srv.spkm.secret())?.mix(self.until_mac())?;
-        Ok(rosenpass_sodium::helpers::memcmp(
-            self.mac(),
-            &expected.into_value()[..16],
-        ))
+        for spk in srv.seal_keys(self.all_bytes()) {
+            let expected = lprf::mac()?.mix(spk)?.mix(self.until_mac())?;
+            if constant_time::tag_eq(self.mac(), &expected.into_value()) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
     }
 }
 
@@ -1238,6 +1789,14 @@ impl HandshakeState {
         Ok(self)
     }
 
+    /// Mix in the [extensions::transcript] of a message, unless it is empty
+    pub fn mix_transcript(&mut self, transcript: &[u8]) -> Result<&mut Self> {
+        match transcript.is_empty() {
+            true => Ok(self),
+            false => self.mix(transcript),
+        }
+    }
+
     pub fn encrypt_and_mix(&mut self, ct: &mut [u8], pt: &[u8]) -> Result<&mut Self> {
         let k = self.ck.mix(&lprf::hs_enc()?)?.into_secret();
         aead::encrypt(ct, k.secret(), &NONCE0, &NOTHING, pt)?;
@@ -1300,12 +1859,9 @@ impl HandshakeState {
         // consume biscuit no
         rosenpass_sodium::helpers::increment(&mut *srv.biscuit_ctr);
 
-        // The first bit of the nonce indicates which biscuit key was used
-        // TODO: This is premature optimization. Remove!
         let bk = srv.active_biscuit_key();
         let mut n = XAEADNonce::random();
-        n[0] &= 0b0111_1111;
-        n[0] |= (bk.0 as u8 & 0x1) << 7;
+        bk.mark_nonce(&mut *n);
 
         let k = bk.get(srv).key.secret();
         let pt = biscuit.all_bytes();
@@ -1321,9 +1877,8 @@ impl HandshakeState {
         biscuit_ct: &[u8],
         sidi: SessionId,
         sidr: SessionId,
-    ) -> Result<(PeerPtr, BiscuitId, HandshakeState)> {
-        // The first bit of the biscuit indicates which biscuit key was used
-        let bk = BiscuitKeyPtr(((biscuit_ct[0] & 0b1000_0000) >> 7) as usize);
+    ) -> Result<(PeerPtr, PeerId, BiscuitId, HandshakeState)> {
+        let bk = BiscuitKeyPtr::of_biscuit(biscuit_ct)?;
 
         // Calculate additional data fields
         let ad = lprf::biscuit_ad()?
@@ -1340,7 +1895,8 @@ impl HandshakeState {
             bk.get(srv).key.secret(),
             &ad,
             biscuit_ct,
-        )?;
+        )
+        .context("Rejecting biscuit: Could not decrypt it")?;
 
         // Reconstruct the biscuit fields
         let no = BiscuitId::from_slice(biscuit.biscuit_no());
@@ -1360,13 +1916,14 @@ impl HandshakeState {
         // the most recent biscuit no again (bn = peer.bn_{prev}) which
         // indicates retransmission
         // TODO: Handle retransmissions without involving the crypto code
-        ensure!(
-            rosenpass_sodium::helpers::compare(biscuit.biscuit_no(), &*peer.get(srv).biscuit_used)
-                >= 0,
-            "Rejecting biscuit: Outdated biscuit number"
-        );
+        let cmp = constant_time::compare(biscuit.biscuit_no(), &*peer.get(srv).biscuit_used);
+        let fresh = match srv.replay_mode {
+            ReplayMode::Strict => cmp > 0,
+            ReplayMode::Normal | ReplayMode::Lenient => cmp >= 0,
+        };
+        ensure!(fresh, "Rejecting biscuit: Outdated biscuit number");
 
-        Ok((peer, no, hs))
+        Ok((peer, pid, no, hs))
     }
 
     pub fn enter_live(self, srv: &CryptoServer, role: HandshakeRole) -> Result<Session> {
@@ -1405,6 +1962,18 @@ impl CryptoServer {
             .with_context(|| format!("No current session for peer {:?}", peer))?;
         Ok(session.ck.mix(&lprf::osk()?)?.into_secret())
     }
+
+    /// Derive a key for another protocol from the session with `peer`, see
+    /// [crate::exporter]; keys under different labels are independent
+    pub fn export(&self, peer: PeerPtr, label: &[u8]) -> Result<SymKey> {
+        let session = peer
+            .session()
+            .get(self)
+            .as_ref()
+            .with_context(|| format!("No current session for peer {:?}", peer))?;
+        let label = lprf::exporter()?.mix(label)?.into_value();
+        Ok(session.ck.mix(&label)?.into_secret())
+    }
 }
 
 impl CryptoServer {
@@ -1418,7 +1987,7 @@ impl CryptoServer {
         let mut hs = InitiatorHandshake::zero_with_timestamp(self);
 
         // IHI1
-        hs.core.init(peer.get(self).spkt.secret())?;
+        hs.core.init(peer.get(self).spkt.value())?;
 
         // IHI2
         hs.core.sidi.randomize();
@@ -1435,7 +2004,7 @@ impl CryptoServer {
         hs.core
             .encaps_and_mix::<StaticKEM, { StaticKEM::SHK_LEN }>(
                 ih.sctr_mut(),
-                peer.get(self).spkt.secret(),
+                peer.get(self).spkt.value(),
             )?;
 
         // IHI6
@@ -1456,10 +2025,13 @@ impl CryptoServer {
         Ok(peer)
     }
 
+    /// Answer the InitHello `ih` with `rh`, mixing in `sending` for the
+    /// features of both, see [crate::extensions]
     pub fn handle_init_hello(
         &mut self,
         ih: InitHello<&[u8]>,
         mut rh: RespHello<&mut [u8]>,
+        sending: &[u8],
     ) -> Result<PeerPtr> {
         let mut core = HandshakeState::zero();
 
@@ -1479,20 +2051,26 @@ impl CryptoServer {
         )?;
 
         // IHR6
-        let peer = {
-            let mut peerid = PeerId::zero();
-            core.decrypt_and_mix(&mut *peerid, ih.pidic())?;
-            self.find_peer(peerid)
-                .with_context(|| format!("No such peer {peerid:?}."))?
-        };
+        let mut peerid = PeerId::zero();
+        core.decrypt_and_mix(&mut *peerid, ih.pidic())?;
+        let peer = self
+            .find_peer(peerid)
+            .with_context(|| format!("No such peer {peerid:?}."))?;
+        self.load_key(peer)?;
+        let other_key = peer.get(self).rollover_to(&peerid, self.timebase.now())?;
 
         // IHR7
-        core.mix(peer.get(self).spkt.secret())?
+        core.mix(peer.get(self).key(other_key).value())?
             .mix(peer.get(self).psk.secret())?;
 
         // IHR8
         core.decrypt_and_mix(&mut [0u8; 0], ih.auth())?;
 
+        // the peer authenticated itself with the other key of its rollover
+        if other_key {
+            peer.swap_keys(self);
+        }
+
         // RHR1
         core.sidr.randomize();
         rh.sidi_mut().copy_from_slice(core.sidi.as_ref());
@@ -1507,9 +2085,12 @@ impl CryptoServer {
         // RHR5
         core.encaps_and_mix::<StaticKEM, { StaticKEM::SHK_LEN }>(
             rh.scti_mut(),
-            peer.get(self).spkt.secret(),
+            peer.get(self).spkt.value(),
         )?;
 
+        // the features of both hellos, kept in the biscuit
+        core.mix_transcript(sending)?;
+
         // RHR6
         core.store_biscuit(self, peer, rh.biscuit_mut())?;
 
@@ -1519,10 +2100,15 @@ impl CryptoServer {
         Ok(peer)
     }
 
+    /// Answer the RespHello `rh` with `ic`, mixing in `received` for the
+    /// features of both hellos and `sending` for those of `ic`, see
+    /// [crate::extensions]
     pub fn handle_resp_hello(
         &mut self,
         rh: RespHello<&[u8]>,
         mut ic: InitConf<&mut [u8]>,
+        received: &[u8],
+        sending: &[u8],
     ) -> Result<PeerPtr> {
         // RHI2
         let peer = self
@@ -1583,6 +2169,8 @@ impl CryptoServer {
             rh.scti(),
         )?;
 
+        core.mix_transcript(received)?;
+
         // RHI6
         core.mix(rh.biscuit())?;
 
@@ -1599,6 +2187,8 @@ impl CryptoServer {
         core.mix(ic.sidi())?.mix(ic.sidr())?;
         ic.biscuit_mut().copy_from_slice(rh.biscuit());
 
+        core.mix_transcript(sending)?;
+
         // ICI4
         core.encrypt_and_mix(ic.auth_mut(), &NOTHING)?;
 
@@ -1615,14 +2205,19 @@ impl CryptoServer {
         Ok(peer)
     }
 
+    /// Answer the InitConf `ic` with `rc`, mixing in `received` for the
+    /// features of `ic`; `sending` for those of `rc` is the additional data
+    /// of its `auth`, see [crate::extensions]
     pub fn handle_init_conf(
         &mut self,
         ic: InitConf<&[u8]>,
         mut rc: EmptyData<&mut [u8]>,
+        received: &[u8],
+        sending: &[u8],
     ) -> Result<PeerPtr> {
         // (peer, bn) ← LoadBiscuit(InitConf.biscuit)
         // ICR1
-        let (peer, biscuit_no, mut core) = HandshakeState::load_biscuit(
+        let (peer, peerid, biscuit_no, mut core) = HandshakeState::load_biscuit(
             self,
             ic.biscuit(),
             SessionId::from_slice(ic.sidi()),
@@ -1635,11 +2230,18 @@ impl CryptoServer {
         // ICR3
         core.mix(ic.sidi())?.mix(ic.sidr())?;
 
+        core.mix_transcript(received)?;
+
         // ICR4
         core.decrypt_and_mix(&mut [0u8; 0], ic.auth())?;
 
+        // the InitHello may have been answered by a handshake worker
+        if peer.get(self).rollover_to(&peerid, self.timebase.now())? {
+            peer.swap_keys(self);
+        }
+
         // ICR5
-        if rosenpass_sodium::helpers::compare(&*biscuit_no, &*peer.get(self).biscuit_used) > 0 {
+        if constant_time::compare(&*biscuit_no, &*peer.get(self).biscuit_used) > 0 {
             // ICR6
             peer.get_mut(self).biscuit_used = biscuit_no;
 
@@ -1686,12 +2288,14 @@ impl CryptoServer {
 
         let n = cat!(aead::NONCE_LEN; rc.ctr(), &[0u8; 4]);
         let k = ses.txkm.secret();
-        aead::encrypt(rc.auth_mut(), k, &n, &NOTHING, &NOTHING)?; // ct, k, n, ad, pt
+        aead::encrypt(rc.auth_mut(), k, &n, sending, &NOTHING)?; // ct, k, n, ad, pt
 
         Ok(peer)
     }
 
-    pub fn handle_resp_conf(&mut self, rc: EmptyData<&[u8]>) -> Result<PeerPtr> {
+    /// Take note of the EmptyData `rc`, authenticated with `received` for
+    /// its features, see [crate::extensions]
+    pub fn handle_resp_conf(&mut self, rc: EmptyData<&[u8]>, received: &[u8]) -> Result<PeerPtr> {
         let sid = SessionId::from_slice(rc.sid());
         let hs = self
             .lookup_handshake(sid)
@@ -1723,7 +2327,7 @@ impl CryptoServer {
                 &mut [0u8; 0],
                 s.txkt.secret(),
                 &cat!(aead::NONCE_LEN; rc.ctr(), &[0u8; 4]),
-                &NOTHING,
+                received,
                 rc.auth(),
             )?;
         }
@@ -1733,6 +2337,176 @@ impl CryptoServer {
 
         Ok(hs.peer())
     }
+
+    /// Write a keepalive for `peer` to `tx_buf`, authenticated with the live
+    /// session; returns the length of the message
+    pub fn keepalive(&mut self, peer: PeerPtr, tx_buf: &mut [u8]) -> Result<usize> {
+        let mut msg = tx_buf.envelope_truncating::<EmptyData<&mut [u8]>>()?;
+        {
+            let mut ka = msg.payload_mut().empty_data()?;
+            let ses = peer
+                .session()
+                .get_mut(self)
+                .as_mut()
+                .context("Cannot send keepalive. No session.")?;
+            ka.sid_mut().copy_from_slice(&ses.sidt.value);
+            ka.ctr_mut().copy_from_slice(&ses.txnm.to_le_bytes());
+            ses.txnm += 1;
+
+            // the message type keeps keepalives from passing for EmptyData,
+            // which shares the keys and counter
+            let n = cat!(aead::NONCE_LEN; ka.ctr(), &[0u8; 4]);
+            let ad = [MsgType::Keepalive as u8];
+            aead::encrypt(ka.auth_mut(), ses.txkm.secret(), &n, &ad, &NOTHING)?;
+        }
+        self.seal_and_commit_msg(peer, MsgType::Keepalive, msg)
+    }
+
+    pub fn handle_keepalive(&mut self, ka: EmptyData<&[u8]>) -> Result<PeerPtr> {
+        let sid = SessionId::from_slice(ka.sid());
+        let ses = self
+            .lookup_session(sid)
+            .with_context(|| format!("Got keepalive for non-existent session {sid:?}"))?;
+        // lookup_session only finds existing sessions
+        let s = ses.get_mut(self).as_mut().unwrap();
+        // the slice returned by ctr() is guaranteed to have the correct size
+        let n = u64::from_le_bytes(ka.ctr().try_into().unwrap());
+        ensure!(n >= s.txnt, "Stale nonce");
+        aead::decrypt(
+            // pt, k, n, ad, ct
+            &mut [0u8; 0],
+            s.txkt.secret(),
+            &cat!(aead::NONCE_LEN; ka.ctr(), &[0u8; 4]),
+            &[MsgType::Keepalive as u8],
+            ka.auth(),
+        )?;
+        // unlike with EmptyData, a keepalive may not be replayed
+        s.txnt = n + 1;
+        Ok(ses.peer())
+    }
+
+    /// Write a message carrying `payload` for `peer` to `tx_buf`, encrypted
+    /// with the live session; returns the length of the message. See
+    /// [crate::rendezvous]
+    pub fn seal_rendezvous(
+        &mut self,
+        peer: PeerPtr,
+        payload: &[u8; RENDEZVOUS_PAYLOAD_LEN],
+        tx_buf: &mut [u8],
+    ) -> Result<usize> {
+        let mut msg = tx_buf.envelope_truncating::<Rendezvous<&mut [u8]>>()?;
+        {
+            let mut rv = msg.payload_mut().rendezvous()?;
+            let ses = peer
+                .session()
+                .get_mut(self)
+                .as_mut()
+                .context("Cannot send rendezvous message. No session.")?;
+            rv.sid_mut().copy_from_slice(&ses.sidt.value);
+            rv.ctr_mut().copy_from_slice(&ses.txnm.to_le_bytes());
+            ses.txnm += 1;
+
+            let n = cat!(aead::NONCE_LEN; rv.ctr(), &[0u8; 4]);
+            aead::encrypt(rv.payload_mut(), ses.txkm.secret(), &n, &NOTHING, payload)?;
+        }
+        self.seal_and_commit_msg(peer, MsgType::Rendezvous, msg)
+    }
+
+    /// Check and decrypt the message in `rx_buf` into `payload`; returns the
+    /// peer who sent it
+    pub fn open_rendezvous(
+        &mut self,
+        rx_buf: &[u8],
+        payload: &mut [u8; RENDEZVOUS_PAYLOAD_LEN],
+    ) -> Result<PeerPtr> {
+        let msg = rx_buf.envelope::<Rendezvous<&[u8]>>()?;
+        ensure!(msg.check_seal(self)?, "Message seal broken!");
+        let rv = msg.payload().rendezvous()?;
+        let sid = SessionId::from_slice(rv.sid());
+        let ses = self
+            .lookup_session(sid)
+            .with_context(|| format!("Got rendezvous message for non-existent session {sid:?}"))?;
+        // lookup_session only finds existing sessions
+        let s = ses.get_mut(self).as_mut().unwrap();
+        // the slice returned by ctr() is guaranteed to have the correct size
+        let n = u64::from_le_bytes(rv.ctr().try_into().unwrap());
+        ensure!(n >= s.txnt, "Stale nonce");
+        aead::decrypt(
+            // pt, k, n, ad, ct
+            payload,
+            s.txkt.secret(),
+            &cat!(aead::NONCE_LEN; rv.ctr(), &[0u8; 4]),
+            &NOTHING,
+            rv.payload(),
+        )?;
+        s.txnt = n + 1;
+        Ok(ses.peer())
+    }
+
+    /// Write a message to `via` carrying the sealed fragment `data` and the
+    /// id `peer_id` to `tx_buf`, authenticated with the live session; returns
+    /// the length of the message. See [crate::relay]
+    pub fn seal_relay(
+        &mut self,
+        via: PeerPtr,
+        peer_id: &[u8; 32],
+        data: &[u8],
+        tx_buf: &mut [u8],
+    ) -> Result<usize> {
+        let mut msg = tx_buf.envelope_truncating::<Relay<&mut [u8]>>()?;
+        {
+            let mut rl = msg.payload_mut().relay()?;
+            let ses = via
+                .session()
+                .get_mut(self)
+                .as_mut()
+                .context("Cannot relay message. No session.")?;
+            rl.sid_mut().copy_from_slice(&ses.sidt.value);
+            rl.ctr_mut().copy_from_slice(&ses.txnm.to_le_bytes());
+            ses.txnm += 1;
+            rl.peer_mut().copy_from_slice(peer_id);
+            rl.data_mut().copy_from_slice(data);
+
+            let n = cat!(aead::NONCE_LEN; rl.ctr(), &[0u8; 4]);
+            let ad = rl.until_auth().to_vec();
+            aead::encrypt(rl.auth_mut(), ses.txkm.secret(), &n, &ad, &NOTHING)?;
+        }
+        self.seal_and_commit_msg(via, MsgType::Relay, msg)
+    }
+
+    /// Check the relay message in `rx_buf`, copying the id and the fragment
+    /// it carries to `peer_id` and `data`; returns the peer who sent it
+    pub fn open_relay(
+        &mut self,
+        rx_buf: &[u8],
+        peer_id: &mut [u8; 32],
+        data: &mut [u8],
+    ) -> Result<PeerPtr> {
+        let msg = rx_buf.envelope::<Relay<&[u8]>>()?;
+        ensure!(msg.check_seal(self)?, "Message seal broken!");
+        let rl = msg.payload().relay()?;
+        let sid = SessionId::from_slice(rl.sid());
+        let ses = self
+            .lookup_session(sid)
+            .with_context(|| format!("Got relay message for non-existent session {sid:?}"))?;
+        // lookup_session only finds existing sessions
+        let s = ses.get_mut(self).as_mut().unwrap();
+        // the slice returned by ctr() is guaranteed to have the correct size
+        let n = u64::from_le_bytes(rl.ctr().try_into().unwrap());
+        ensure!(n >= s.txnt, "Stale nonce");
+        aead::decrypt(
+            // pt, k, n, ad, ct
+            &mut [0u8; 0],
+            s.txkt.secret(),
+            &cat!(aead::NONCE_LEN; rl.ctr(), &[0u8; 4]),
+            rl.until_auth(),
+            rl.auth(),
+        )?;
+        s.txnt = n + 1;
+        peer_id.copy_from_slice(rl.peer());
+        data.copy_from_slice(rl.data());
+        Ok(ses.peer())
+    }
 }
 
 #[cfg(test)]
@@ -1810,6 +2584,421 @@ mod test {
         srv.handle_msg(&msgbuf[..msglen], resbuf).unwrap().resp
     }
 
+    #[test]
+    /// Keepalives are accepted in both directions once the handshake is
+    /// done, but can not be replayed
+    fn keepalive_after_handshake() {
+        rosenpass_sodium::init().unwrap();
+
+        stacker::grow(8 * 1024 * 1024, || {
+            const PEER0: PeerPtr = PeerPtr(0);
+
+            let (mut a, mut b) = make_server_pair().unwrap();
+            let (mut ab, mut ba) = (MsgBuf::zero(), MsgBuf::zero());
+            assert!(a.keepalive(PEER0, &mut *ab).is_err());
+
+            let len = a.initiate_handshake(PEER0, &mut *ab).unwrap();
+            let len = b.handle_msg(&ab[..len], &mut *ba).unwrap().resp.unwrap();
+            let len = a.handle_msg(&ba[..len], &mut *ab).unwrap().resp.unwrap();
+            let len = b.handle_msg(&ab[..len], &mut *ba).unwrap().resp.unwrap();
+            a.handle_msg(&ba[..len], &mut *ab).unwrap();
+
+            let len = a.keepalive(PEER0, &mut *ab).unwrap();
+            let res = b.handle_msg(&ab[..len], &mut *ba).unwrap();
+            assert_eq!(
+                (res.peer, res.resp, res.exchanged_with),
+                (PEER0, None, None)
+            );
+            assert!(b.handle_msg(&ab[..len], &mut *ba).is_err());
+
+            let len = b.keepalive(PEER0, &mut *ba).unwrap();
+            assert_eq!(a.handle_msg(&ba[..len], &mut *ab).unwrap().peer, PEER0);
+        });
+    }
+
+    #[test]
+    /// The initiator only reports the key once the responder confirmed it,
+    /// by the EmptyData or, with that lost, a keepalive
+    fn key_waits_for_confirmation() {
+        rosenpass_sodium::init().unwrap();
+
+        stacker::grow(8 * 1024 * 1024, || {
+            const PEER0: PeerPtr = PeerPtr(0);
+
+            let (mut a, mut b) = make_server_pair().unwrap();
+            let (mut ab, mut ba) = (MsgBuf::zero(), MsgBuf::zero());
+            for lost in [false, true] {
+                let len = a.initiate_handshake(PEER0, &mut *ab).unwrap();
+                let len = b.handle_msg(&ab[..len], &mut *ba).unwrap().resp.unwrap();
+                let res = a.handle_msg(&ba[..len], &mut *ab).unwrap();
+                assert_eq!(res.exchanged_with, None);
+                let res = b.handle_msg(&ab[..res.resp.unwrap()], &mut *ba).unwrap();
+                assert_eq!(res.exchanged_with, Some(PEER0));
+
+                let len = match lost {
+                    false => res.resp.unwrap(),
+                    true => b.keepalive(PEER0, &mut *ba).unwrap(),
+                };
+                let confirm = ba[..len].to_vec();
+                let res = a.handle_msg(&confirm, &mut *ab).unwrap();
+                assert_eq!(res.exchanged_with, Some(PEER0));
+                assert_eq!(
+                    a.osk(PEER0).unwrap().secret(),
+                    b.osk(PEER0).unwrap().secret()
+                );
+                // the key is reported once
+                if !lost {
+                    assert!(a.handle_msg(&confirm, &mut *ab).is_err());
+                }
+                let len = b.keepalive(PEER0, &mut *ba).unwrap();
+                let res = a.handle_msg(&ba[..len], &mut *ab).unwrap();
+                assert_eq!(res.exchanged_with, None);
+            }
+        });
+    }
+
+    #[test]
+    /// Keepalives can not be passed off as the EmptyData the initiator waits
+    /// for, though anyone knowing the public key can seal the envelope again;
+    /// without features, nothing else tells them apart
+    fn keepalives_are_no_empty_data() {
+        rosenpass_sodium::init().unwrap();
+
+        stacker::grow(8 * 1024 * 1024, || {
+            const PEER0: PeerPtr = PeerPtr(0);
+
+            let (mut a, mut b) = make_server_pair().unwrap();
+            (a.supported, b.supported) = (0, 0);
+            let (mut ab, mut ba) = (MsgBuf::zero(), MsgBuf::zero());
+            let len = a.initiate_handshake(PEER0, &mut *ab).unwrap();
+            let len = b.handle_msg(&ab[..len], &mut *ba).unwrap().resp.unwrap();
+            let len = a.handle_msg(&ba[..len], &mut *ab).unwrap().resp.unwrap();
+            // the EmptyData gets lost
+            b.handle_msg(&ab[..len], &mut *ba).unwrap();
+
+            let len = b.keepalive(PEER0, &mut *ba).unwrap();
+            let mut msg = (&mut ba[..len])
+                .envelope_truncating::<EmptyData<&mut [u8]>>()
+                .unwrap();
+            msg.msg_type_mut()[0] = MsgType::EmptyData as u8;
+            msg.seal_for(b.seal_key(PEER0, MsgType::EmptyData)).unwrap();
+            assert!(a.handle_msg(&ba[..len], &mut *ab).is_err());
+
+            let len = b.keepalive(PEER0, &mut *ba).unwrap();
+            let res = a.handle_msg(&ba[..len], &mut *ab).unwrap();
+            assert_eq!(res.exchanged_with, Some(PEER0));
+        });
+    }
+
+    #[test]
+    /// Both sides export the same keys, which are independent of each other
+    /// and of the WireGuard key
+    fn exported_keys() {
+        rosenpass_sodium::init().unwrap();
+
+        stacker::grow(8 * 1024 * 1024, || {
+            const PEER0: PeerPtr = PeerPtr(0);
+
+            let (mut a, mut b) = make_server_pair().unwrap();
+            let (mut ab, mut ba) = (MsgBuf::zero(), MsgBuf::zero());
+            assert!(a.export(PEER0, b"app v1").is_err());
+            let len = a.initiate_handshake(PEER0, &mut *ab).unwrap();
+            let len = b.handle_msg(&ab[..len], &mut *ba).unwrap().resp.unwrap();
+            let len = a.handle_msg(&ba[..len], &mut *ab).unwrap().resp.unwrap();
+            let len = b.handle_msg(&ab[..len], &mut *ba).unwrap().resp.unwrap();
+            a.handle_msg(&ba[..len], &mut *ab).unwrap();
+
+            let app = a.export(PEER0, b"app v1").unwrap();
+            assert_eq!(app.secret(), b.export(PEER0, b"app v1").unwrap().secret());
+            assert_ne!(app.secret(), a.export(PEER0, b"app v2").unwrap().secret());
+            assert_ne!(app.secret(), a.osk(PEER0).unwrap().secret());
+        });
+    }
+
+    #[test]
+    /// Extensions are attached for peers which advertise to understand them,
+    /// and only for those
+    fn extensions_follow_the_features() {
+        rosenpass_sodium::init().unwrap();
+
+        stacker::grow(8 * 1024 * 1024, || {
+            const PEER0: PeerPtr = PeerPtr(0);
+            let ext = Extension {
+                kind: 0x7fff,
+                data: b"unknown to anyone".to_vec(),
+            };
+            let resp_hello_len = <Envelope<(), RespHello<()>> as LenseView>::LEN;
+
+            let (mut a, mut b) = make_server_pair().unwrap();
+            b.extensions = vec![ext.clone()];
+            let (mut ab, mut ba) = (MsgBuf::zero(), MsgBuf::zero());
+
+            let len = a.initiate_handshake(PEER0, &mut *ab).unwrap();
+            let len = b.handle_msg(&ab[..len], &mut *ba).unwrap().resp.unwrap();
+            assert!(len > resp_hello_len);
+            let res = a.handle_msg(&ba[..len], &mut *ab).unwrap();
+            assert_eq!(res.extensions, vec![ext.clone()]);
+            assert_eq!(PEER0.get(&b).features, EXTENSIONS);
+
+            // a peer advertising nothing, like those predating extensions
+            a.supported = 0;
+            let len = a.initiate_handshake(PEER0, &mut *ab).unwrap();
+            let len = b.handle_msg(&ab[..len], &mut *ba).unwrap().resp.unwrap();
+            assert_eq!(len, resp_hello_len);
+            assert_eq!(PEER0.get(&b).features, 0);
+            let len = a.handle_msg(&ba[..len], &mut *ab).unwrap().resp.unwrap();
+            let len = b.handle_msg(&ab[..len], &mut *ba).unwrap().resp.unwrap();
+            a.handle_msg(&ba[..len], &mut *ab).unwrap();
+            assert_eq!(
+                a.osk(PEER0).unwrap().secret(),
+                b.osk(PEER0).unwrap().secret()
+            );
+        });
+    }
+
+    #[test]
+    /// Features and extensions changed on the way make the handshake fail,
+    /// even with all of the seals made anew
+    fn extensions_are_authenticated() {
+        rosenpass_sodium::init().unwrap();
+
+        stacker::grow(8 * 1024 * 1024, || {
+            const PEER0: PeerPtr = PeerPtr(0);
+            let ext = Extension {
+                kind: 0x7fff,
+                data: b"unknown to anyone".to_vec(),
+            };
+            let sent = vec![ext.clone()];
+            let resp_hello_len = <Envelope<(), RespHello<()>> as LenseView>::LEN;
+            let empty_data_len = <Envelope<(), EmptyData<()>> as LenseView>::LEN;
+            let servers = || {
+                let (mut a, mut b) = make_server_pair().unwrap();
+                a.identity_hiding = true;
+                b.extensions = sent.clone();
+                (a, b)
+            };
+            let (mut ab, mut ba) = (MsgBuf::zero(), MsgBuf::zero());
+
+            // the initiator no longer asking for identity hiding, or for
+            // anything at all; the responder can not tell, but the
+            // initiator notices
+            for features in [EXTENSIONS, 0] {
+                let (mut a, mut b) = servers();
+                let len = a.initiate_handshake(PEER0, &mut *ab).unwrap();
+                let mut msg = (&mut ab[..len]).envelope::<InitHello<()>>().unwrap();
+                extensions::store_features(features, msg.reserved_mut());
+                msg.seal(PEER0, &a).unwrap();
+                let len = b.handle_msg(&ab[..len], &mut *ba).unwrap().resp.unwrap();
+                assert!(a.handle_msg(&ba[..len], &mut *ab).is_err());
+            }
+
+            // the responder no longer offering extensions
+            let (mut a, mut b) = servers();
+            let len = a.initiate_handshake(PEER0, &mut *ab).unwrap();
+            let len = b.handle_msg(&ab[..len], &mut *ba).unwrap().resp.unwrap();
+            let mut msg = (&mut ba[..resp_hello_len])
+                .envelope::<RespHello<()>>()
+                .unwrap();
+            extensions::store_features(IDENTITY_HIDING, msg.reserved_mut());
+            msg.seal_for(b.spkm.secret()).unwrap();
+            extensions::seal(&mut *ba, resp_hello_len, &sent, b.spkm.secret()).unwrap();
+            assert!(a.handle_msg(&ba[..len], &mut *ab).is_err());
+
+            // an extension changed or stripped
+            for exts in [
+                vec![],
+                vec![Extension {
+                    kind: 1,
+                    ..ext.clone()
+                }],
+            ] {
+                let (mut a, mut b) = servers();
+                let len = a.initiate_handshake(PEER0, &mut *ab).unwrap();
+                let len = b.handle_msg(&ab[..len], &mut *ba).unwrap().resp.unwrap();
+                let spk = b.spkm.secret();
+                let forged = extensions::seal(&mut *ba, resp_hello_len, &exts, spk).unwrap();
+                assert!(a.handle_msg(&ba[..forged], &mut *ab).is_err());
+                assert!(a.handle_msg(&ba[..resp_hello_len], &mut *ab).is_err());
+                // while the message as sent still goes through
+                extensions::seal(&mut *ba, resp_hello_len, &sent, spk).unwrap();
+                assert!(a.handle_msg(&ba[..len], &mut *ab).is_ok());
+            }
+
+            // the features of the confirmation changed
+            let (mut a, mut b) = servers();
+            let len = a.initiate_handshake(PEER0, &mut *ab).unwrap();
+            let len = b.handle_msg(&ab[..len], &mut *ba).unwrap().resp.unwrap();
+            let len = a.handle_msg(&ba[..len], &mut *ab).unwrap().resp.unwrap();
+            let len = b.handle_msg(&ab[..len], &mut *ba).unwrap().resp.unwrap();
+            assert!(len > empty_data_len);
+            let mut msg = (&mut ba[..empty_data_len])
+                .envelope::<EmptyData<()>>()
+                .unwrap();
+            extensions::store_features(0, msg.reserved_mut());
+            msg.seal_for(b.spkm.secret()).unwrap();
+            extensions::seal(&mut *ba, empty_data_len, &sent, b.spkm.secret()).unwrap();
+            assert!(a.handle_msg(&ba[..len], &mut *ab).is_err());
+        });
+    }
+
+    #[test]
+    /// With identity hiding, nothing the responder sends is sealed with the
+    /// public key of the initiator, and the handshake completes all the same
+    fn identity_hiding() {
+        rosenpass_sodium::init().unwrap();
+
+        stacker::grow(8 * 1024 * 1024, || {
+            const PEER0: PeerPtr = PeerPtr(0);
+            fn sealed_for<M: LenseView>(buf: &[u8], spk: &SPk) -> bool {
+                let len = <Envelope<(), M> as LenseView>::LEN;
+                let env = (&buf[..len]).envelope::<M>().unwrap();
+                let mac = lprf::mac().unwrap().mix(spk.secret()).unwrap();
+                let mac = mac.mix(env.until_mac()).unwrap().into_value();
+                constant_time::tag_eq(env.mac(), &mac)
+            }
+
+            let (mut a, mut b) = make_server_pair().unwrap();
+            let (pka, pkb) = (a.spkm.clone(), b.spkm.clone());
+            a.identity_hiding = true;
+            b.extensions = vec![Extension {
+                kind: 0x7fff,
+                data: b"unknown to anyone".to_vec(),
+            }];
+            let (mut ab, mut ba) = (MsgBuf::zero(), MsgBuf::zero());
+
+            let len = a.initiate_handshake(PEER0, &mut *ab).unwrap();
+            assert!(sealed_for::<InitHello<()>>(&ab[..len], &pkb));
+            let len = b.handle_msg(&ab[..len], &mut *ba).unwrap().resp.unwrap();
+            assert!(!sealed_for::<RespHello<()>>(&ba[..len], &pka));
+            assert!(sealed_for::<RespHello<()>>(&ba[..len], &pkb));
+            let res = a.handle_msg(&ba[..len], &mut *ab).unwrap();
+            assert_eq!(res.extensions, b.extensions);
+            let len = b.handle_msg(&ab[..res.resp.unwrap()], &mut *ba);
+            let len = len.unwrap().resp.unwrap();
+            assert!(!sealed_for::<EmptyData<()>>(&ba[..len], &pka));
+            a.handle_msg(&ba[..len], &mut *ab).unwrap();
+            assert_eq!(
+                a.osk(PEER0).unwrap().secret(),
+                b.osk(PEER0).unwrap().secret()
+            );
+
+            let len = b.keepalive(PEER0, &mut *ba).unwrap();
+            assert!(!sealed_for::<EmptyData<()>>(&ba[..len], &pka));
+            assert_eq!(a.handle_msg(&ba[..len], &mut *ab).unwrap().peer, PEER0);
+            let len = a.keepalive(PEER0, &mut *ab).unwrap();
+            assert!(sealed_for::<EmptyData<()>>(&ab[..len], &pkb));
+            b.handle_msg(&ab[..len], &mut *ba).unwrap();
+        });
+    }
+
+    #[test]
+    /// Rendezvous and relay messages travel on the session, just like
+    /// keepalives
+    fn rendezvous_after_handshake() {
+        rosenpass_sodium::init().unwrap();
+
+        stacker::grow(8 * 1024 * 1024, || {
+            const PEER0: PeerPtr = PeerPtr(0);
+
+            let (mut a, mut b) = make_server_pair().unwrap();
+            let (mut ab, mut ba) = (MsgBuf::zero(), MsgBuf::zero());
+            let sent = [5u8; RENDEZVOUS_PAYLOAD_LEN];
+            let mut got = [0u8; RENDEZVOUS_PAYLOAD_LEN];
+            assert!(a.seal_rendezvous(PEER0, &sent, &mut *ab).is_err());
+
+            let len = a.initiate_handshake(PEER0, &mut *ab).unwrap();
+            let len = b.handle_msg(&ab[..len], &mut *ba).unwrap().resp.unwrap();
+            let len = a.handle_msg(&ba[..len], &mut *ab).unwrap().resp.unwrap();
+            let len = b.handle_msg(&ab[..len], &mut *ba).unwrap().resp.unwrap();
+            a.handle_msg(&ba[..len], &mut *ab).unwrap();
+
+            let len = a.seal_rendezvous(PEER0, &sent, &mut *ab).unwrap();
+            assert_eq!(a.peer_of(&ab[..len]), None);
+            assert_eq!(b.peer_of(&ab[..len]), Some(PEER0));
+            assert!(b.handle_msg(&ab[..len], &mut *ba).is_err());
+            assert_eq!(b.open_rendezvous(&ab[..len], &mut got).unwrap(), PEER0);
+            assert_eq!(got, sent);
+            assert!(b.open_rendezvous(&ab[..len], &mut got).is_err());
+
+            ab[len - 1] ^= 1;
+            assert!(b.open_rendezvous(&ab[..len], &mut got).is_err());
+
+            // relay messages likewise
+            let (id, data) = ([3u8; 32], [4u8; RELAY_DATA_LEN]);
+            let (mut got_id, mut got_data) = ([0u8; 32], [0u8; RELAY_DATA_LEN]);
+            let len = a.seal_relay(PEER0, &id, &data, &mut *ab).unwrap();
+            assert_eq!(b.peer_of(&ab[..len]), Some(PEER0));
+            let from = b.open_relay(&ab[..len], &mut got_id, &mut got_data);
+            assert_eq!(from.unwrap(), PEER0);
+            assert_eq!((got_id, got_data), (id, data));
+            assert!(b
+                .open_relay(&ab[..len], &mut got_id, &mut got_data)
+                .is_err());
+        });
+    }
+
+    #[test]
+    /// A peer rekeying long before the other one expects it, as with a
+    /// larger rekey margin, replaces the session on both sides
+    fn early_rekey() {
+        rosenpass_sodium::init().unwrap();
+
+        stacker::grow(8 * 1024 * 1024, || {
+            const PEER0: PeerPtr = PeerPtr(0);
+
+            let (mut a, mut b) = make_server_pair().unwrap();
+            let (mut ab, mut ba) = (MsgBuf::zero(), MsgBuf::zero());
+            let mut handshake = |a: &mut CryptoServer, b: &mut CryptoServer| {
+                let len = a.initiate_handshake(PEER0, &mut *ab).unwrap();
+                let len = b.handle_msg(&ab[..len], &mut *ba).unwrap().resp.unwrap();
+                let len = a.handle_msg(&ba[..len], &mut *ab).unwrap().resp.unwrap();
+                let res = b.handle_msg(&ab[..len], &mut *ba).unwrap();
+                assert_eq!(res.exchanged_with, Some(PEER0));
+                a.handle_msg(&ba[..res.resp.unwrap()], &mut *ab).unwrap();
+                assert_eq!(
+                    a.osk(PEER0).unwrap().secret(),
+                    b.osk(PEER0).unwrap().secret()
+                );
+                a.osk(PEER0).unwrap()
+            };
+
+            let first = handshake(&mut a, &mut b);
+            let second = handshake(&mut a, &mut b);
+            assert_ne!(first.secret(), second.secret());
+
+            // a larger margin starts the handshake earlier
+            a.rekey_margin = 100.0;
+            assert_eq!(a.rekey_after(), REJECT_AFTER_TIME - 100.0 + REKEY_STAGGER);
+            let youth = a.peers[0].session.as_ref().unwrap().created_at + a.rekey_after();
+            assert_eq!(PEER0.session().retire_at(&a), Some(youth));
+        });
+    }
+
+    #[test]
+    /// A retransmitted InitConf is answered again, unless replay protection
+    /// is strict
+    fn replayed_init_conf() {
+        rosenpass_sodium::init().unwrap();
+
+        stacker::grow(8 * 1024 * 1024, || {
+            const PEER0: PeerPtr = PeerPtr(0);
+
+            for mode in [ReplayMode::Normal, ReplayMode::Strict] {
+                let (mut a, mut b) = make_server_pair().unwrap();
+                b.replay_mode = mode;
+                let (mut ab, mut ba) = (MsgBuf::zero(), MsgBuf::zero());
+                let len = a.initiate_handshake(PEER0, &mut *ab).unwrap();
+                let len = b.handle_msg(&ab[..len], &mut *ba).unwrap().resp.unwrap();
+                let len = a.handle_msg(&ba[..len], &mut *ab).unwrap().resp.unwrap();
+                let conf = ab[..len].to_vec();
+                assert!(b.handle_msg(&conf, &mut *ba).is_ok());
+
+                let again = b.handle_msg(&conf, &mut *ba);
+                assert_eq!(again.is_ok(), mode == ReplayMode::Normal);
+            }
+        });
+    }
+
     fn keygen() -> Result<(SSk, SPk)> {
         // TODO: Copied from the benchmark; deduplicate
         let (mut sk, mut pk) = (SSk::zero(), SPk::zero());
@@ -1817,6 +3006,56 @@ mod test {
         Ok((sk, pk))
     }
 
+    #[test]
+    /// A peer in a key rollover is reached under the old key, both when it
+    /// initiates and when we fall back to it, until the window ends
+    fn rollover_falls_back_to_old_key() {
+        rosenpass_sodium::init().unwrap();
+
+        stacker::grow(8 * 1024 * 1024, || {
+            const PEER0: PeerPtr = PeerPtr(0);
+            let handshake = |a: &mut CryptoServer, b: &mut CryptoServer| -> Result<()> {
+                let (mut ab, mut ba) = (MsgBuf::zero(), MsgBuf::zero());
+                let len = a.initiate_handshake(PEER0, &mut *ab)?;
+                let len = b.handle_msg(&ab[..len], &mut *ba)?.resp.unwrap();
+                let len = a.handle_msg(&ba[..len], &mut *ab)?.resp.unwrap();
+                let len = b.handle_msg(&ab[..len], &mut *ba)?.resp.unwrap();
+                a.handle_msg(&ba[..len], &mut *ab)?;
+                ensure!(a.osk(PEER0)?.secret() == b.osk(PEER0)?.secret());
+                Ok(())
+            };
+
+            // b did not switch to its new key yet
+            let (mut a, mut b) = make_server_pair().unwrap();
+            let old = std::mem::replace(&mut a.peers[0].spkt, keygen().unwrap().1.into());
+            a.index.clear();
+            a.index
+                .insert(IndexKey::Peer(a.peers[0].pidt().unwrap()), 0);
+            a.add_rollover_key(PEER0, old, 60.0).unwrap();
+
+            handshake(&mut b, &mut a).unwrap();
+            assert!(a.peers[0].rollover.as_ref().unwrap().using_old);
+
+            // our handshake to the new key goes unanswered
+            PEER0.swap_keys(&mut a);
+            let mut buf = MsgBuf::zero();
+            a.initiate_handshake(PEER0, &mut *buf).unwrap();
+            let hs = a.peers[0].handshake.as_mut().unwrap();
+            hs.tx_count = ROLLOVER_RETRANSMISSIONS + 1;
+            hs.tx_retry_at = 0.0;
+            assert!(matches!(
+                PEER0.hs().poll(&mut a).unwrap(),
+                PollResult::SendInitiation(PEER0)
+            ));
+            handshake(&mut a, &mut b).unwrap();
+
+            PEER0.get_mut(&mut a).rollover.as_mut().unwrap().until = 0.0;
+            PEER0.poll(&mut a).unwrap();
+            assert!(a.peers[0].rollover.is_none());
+            assert!(handshake(&mut b, &mut a).is_err());
+        });
+    }
+
     fn make_server_pair() -> Result<(CryptoServer, CryptoServer)> {
         // TODO: Copied from the benchmark; deduplicate
         let psk = SymKey::random();
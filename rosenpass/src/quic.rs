@@ -0,0 +1,1661 @@
+//! Handshakes over QUIC
+//!
+//! On networks which drop UDP to odd ports but let QUIC to port 443
+//! through, rosenpass can carry its handshake messages in the DATAGRAM
+//! frames (RFC 9221) of QUIC version 1 connections (RFC 9000). Built with
+//! the `quic` feature, it listens for connections on the sockets of
+//! `listen_quic` and opens them to the peers with `quic:` endpoints:
+//!
+//! ```toml
+//! listen_quic = ["[::]:443"]
+//!
+//! [[peers]]
+//! public_key = "b.pqpk"
+//! endpoint = "quic:vpn.example.com:443"
+//! ```
+//!
+//! `listen quic:<address>` does the same on the command line. The QUIC
+//! sockets are opened alongside those of `listen`; without `listen_quic`,
+//! connections to `quic:` endpoints go out of a socket on an ephemeral port.
+//!
+//! Connections are opened once there is a message for the peer. Clients
+//! keep them alive with PING frames every [KEEPALIVE_INTERVAL] seconds and
+//! open a new one when the server stops answering, as after its restart.
+//! Servers follow clients to new addresses, like those left by a NAT
+//! rebinding, once the client answered a PATH_CHALLENGE from there.
+//!
+//! QUIC only carries the messages here: its TLS handshake authenticates
+//! nobody (see [crate::quic_tls]), the rosenpass handshake inside does, just
+//! as over UDP. Like unix sockets (see [crate::unix]), connections stand for
+//! addresses of their own in the discard-only prefix `100::/64`, with port
+//! zero: `100:0:0:1::/64` holds those of incoming connections, named by
+//! their connection id, `100:0:0:2::/64` those of `quic:` endpoints. The UDP
+//! sockets refuse to send to these addresses, and the QUIC sockets to any
+//! others.
+//!
+//! Left out are streams, 0-RTT, Retry packets, stateless resets, key
+//! updates, new connection ids and migration initiated by the client
+//! itself.
+
+use anyhow::{bail, ensure, Context, Result};
+use log::{debug, info};
+use rosenpass_util::time::Timebase;
+use std::{
+    cell::RefCell,
+    collections::hash_map::DefaultHasher,
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
+    hash::{Hash, Hasher},
+    io::{self, ErrorKind},
+    net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6, ToSocketAddrs},
+    sync::Mutex,
+};
+
+use crate::{
+    protocol::Timing,
+    quic_crypto::{Keys, SAMPLE_LEN},
+    quic_tls::{Output, Tls},
+    upgrade,
+};
+
+pub use crate::config::QUIC_PREFIX as PREFIX;
+
+/// Seconds between the PING frames of clients
+pub const KEEPALIVE_INTERVAL: Timing = 15.0;
+/// Seconds a connection may go without packets; also sent as the
+/// max_idle_timeout transport parameter
+const IDLE_TIMEOUT: Timing = 60.0;
+/// Seconds a handshake may take
+const HANDSHAKE_TIMEOUT: Timing = 10.0;
+/// Seconds a client waits for acknowledgements before it gives up on the
+/// connection
+const UNANSWERED_TIMEOUT: Timing = 10.0;
+/// The first probe timeout, which doubles with every probe; there is no
+/// round trip time estimate
+const INITIAL_PTO: Timing = 1.0;
+/// The longest we wait to acknowledge packets after the handshake
+const ACK_DELAY: Timing = 0.025;
+
+const VERSION: u32 = 1;
+/// Length of the connection ids we choose
+const CID_LEN: usize = 8;
+/// Datagrams with Initial packets are padded to this size
+const MIN_INITIAL_SIZE: usize = 1200;
+/// The largest DATAGRAM frame we take
+const MAX_DATAGRAM_FRAME: u64 = 65535;
+/// Messages kept for a connection which is being set up
+const MAX_WAITING: usize = 8;
+/// Connections a socket keeps at most
+const MAX_CONNECTIONS: usize = 4096;
+/// Packet numbers remembered for acknowledgements and duplicate detection
+const MAX_RECEIVED: usize = 64;
+/// How far CRYPTO data may reach beyond what was taken up
+const MAX_CRYPTO_AHEAD: u64 = 1 << 14;
+
+const PADDING: u64 = 0x00;
+const PING: u64 = 0x01;
+const ACK: u64 = 0x02;
+const ACK_ECN: u64 = 0x03;
+const CRYPTO: u64 = 0x06;
+const NEW_TOKEN: u64 = 0x07;
+const NEW_CONNECTION_ID: u64 = 0x18;
+const RETIRE_CONNECTION_ID: u64 = 0x19;
+const PATH_CHALLENGE: u64 = 0x1a;
+const PATH_RESPONSE: u64 = 0x1b;
+const CONNECTION_CLOSE: u64 = 0x1c;
+const CONNECTION_CLOSE_APP: u64 = 0x1d;
+const HANDSHAKE_DONE: u64 = 0x1e;
+const DATAGRAM: u64 = 0x30;
+const DATAGRAM_LEN: u64 = 0x31;
+
+const PROTOCOL_VIOLATION: u64 = 0x0a;
+
+/// The packet number spaces, which are also the encryption levels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Space {
+    Initial = 0,
+    Handshake = 1,
+    Data = 2,
+}
+
+/// The peers behind `quic:` endpoints, by the address they stand for
+static TARGETS: Mutex<Option<HashMap<SocketAddr, Target>>> = Mutex::new(None);
+
+#[derive(Debug, Clone)]
+struct Target {
+    addrs: Vec<SocketAddr>,
+    /// The host name, for the server name extension
+    name: Option<String>,
+}
+
+fn synthetic(kind: u16, id: [u8; 8]) -> SocketAddr {
+    let s = |i: usize| u16::from_be_bytes([id[i], id[i + 1]]);
+    let ip = Ipv6Addr::new(0x100, 0, 0, kind, s(0), s(2), s(4), s(6));
+    SocketAddr::V6(SocketAddrV6::new(ip, 0, 0, 0))
+}
+
+/// The address standing for the QUIC endpoint `endpoint`, a host name or
+/// address with a port, which is looked up right away
+pub fn address_of(endpoint: &str) -> Result<SocketAddr> {
+    let addrs: Vec<SocketAddr> = endpoint
+        .to_socket_addrs()
+        .with_context(|| format!("could not look up QUIC endpoint {endpoint}"))?
+        .collect();
+    ensure!(!addrs.is_empty(), "QUIC endpoint {endpoint} has no address");
+    let host = match endpoint.rsplit_once(':') {
+        Some((host, _)) => host.trim_start_matches('[').trim_end_matches(']'),
+        None => endpoint,
+    };
+    let name = host.parse::<IpAddr>().is_err().then(|| host.to_owned());
+
+    let mut hasher = DefaultHasher::new();
+    endpoint.hash(&mut hasher);
+    let addr = synthetic(2, hasher.finish().to_be_bytes());
+    TARGETS
+        .lock()
+        .unwrap()
+        .get_or_insert_with(HashMap::new)
+        .insert(addr, Target { addrs, name });
+    Ok(addr)
+}
+
+fn target_of(addr: &SocketAddr) -> Option<Target> {
+    TARGETS.lock().unwrap().as_ref()?.get(addr).cloned()
+}
+
+/// The connection id of the incoming connection `addr` stands for
+fn incoming_cid(addr: &SocketAddr) -> Option<[u8; CID_LEN]> {
+    match addr {
+        SocketAddr::V6(v6) if is_quic(addr) && v6.ip().segments()[3] == 1 => {
+            v6.ip().octets()[8..].try_into().ok()
+        }
+        _ => None,
+    }
+}
+
+/// Whether `addr` stands for a QUIC connection
+pub fn is_quic(addr: &SocketAddr) -> bool {
+    match addr {
+        SocketAddr::V6(v6) => {
+            let s = v6.ip().segments();
+            s[..3] == [0x100, 0, 0] && matches!(s[3], 1 | 2) && v6.port() == 0
+        }
+        SocketAddr::V4(_) => false,
+    }
+}
+
+/// The error of sockets asked to send to an address of the other kind
+pub fn wrong_kind() -> io::Error {
+    io::Error::new(
+        ErrorKind::Unsupported,
+        "QUIC and UDP sockets do not reach each other",
+    )
+}
+
+/// Reads the fields of packets, frames and handshake messages
+pub struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self(data)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// All that is left
+    pub fn rest(&mut self) -> &'a [u8] {
+        std::mem::take(&mut self.0)
+    }
+
+    pub fn bytes(&mut self, n: usize) -> Result<&'a [u8]> {
+        ensure!(self.0.len() >= n, "truncated data");
+        let (taken, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    pub fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_be_bytes(self.bytes(2)?.try_into()?))
+    }
+
+    pub fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into()?))
+    }
+
+    /// A variable-length integer of QUIC
+    pub fn varint(&mut self) -> Result<u64> {
+        let first = self.u8()?;
+        let mut v = (first & 0x3f) as u64;
+        for &b in self.bytes((1 << (first >> 6)) - 1)? {
+            v = (v << 8) | b as u64;
+        }
+        Ok(v)
+    }
+
+    pub fn vec8(&mut self) -> Result<&'a [u8]> {
+        let len = self.u8()?;
+        self.bytes(len as usize)
+    }
+
+    pub fn vec16(&mut self) -> Result<&'a [u8]> {
+        let len = self.u16()?;
+        self.bytes(len as usize)
+    }
+
+    /// Bytes preceded by their length as a variable-length integer
+    pub fn vec_varint(&mut self) -> Result<&'a [u8]> {
+        let len = self.varint()?;
+        self.bytes(usize::try_from(len)?)
+    }
+}
+
+/// Append `v` as a variable-length integer
+pub fn put_varint(out: &mut Vec<u8>, v: u64) {
+    match v {
+        0..=0x3f => out.push(v as u8),
+        0x40..=0x3fff => out.extend_from_slice(&(v as u16 | 0x4000).to_be_bytes()),
+        0x4000..=0x3fff_ffff => out.extend_from_slice(&(v as u32 | 0x8000_0000).to_be_bytes()),
+        _ => out.extend_from_slice(&(v | 0xc000_0000_0000_0000).to_be_bytes()),
+    }
+}
+
+/// The transport parameters we send and look at
+#[derive(Debug, Default)]
+struct Params {
+    original_dcid: Option<Vec<u8>>,
+    initial_scid: Option<Vec<u8>>,
+    max_datagram_frame_size: Option<u64>,
+}
+
+impl Params {
+    const ORIGINAL_DCID: u64 = 0x00;
+    const MAX_IDLE_TIMEOUT: u64 = 0x01;
+    const INITIAL_SCID: u64 = 0x0f;
+    const MAX_DATAGRAM_FRAME_SIZE: u64 = 0x20;
+
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut param = |id, value: &[u8]| {
+            put_varint(&mut out, id);
+            put_varint(&mut out, value.len() as u64);
+            out.extend_from_slice(value);
+        };
+        let varint = |v| {
+            let mut out = Vec::new();
+            put_varint(&mut out, v);
+            out
+        };
+        if let Some(cid) = self.original_dcid.as_ref() {
+            param(Self::ORIGINAL_DCID, cid);
+        }
+        param(
+            Self::MAX_IDLE_TIMEOUT,
+            &varint((IDLE_TIMEOUT * 1000.0) as u64),
+        );
+        if let Some(cid) = self.initial_scid.as_ref() {
+            param(Self::INITIAL_SCID, cid);
+        }
+        if let Some(size) = self.max_datagram_frame_size {
+            param(Self::MAX_DATAGRAM_FRAME_SIZE, &varint(size));
+        }
+        out
+    }
+
+    fn decode(data: &[u8]) -> Result<Self> {
+        let mut params = Self::default();
+        let mut r = Reader::new(data);
+        while !r.is_empty() {
+            let id = r.varint()?;
+            let value = r.vec_varint()?;
+            match id {
+                Self::ORIGINAL_DCID => params.original_dcid = Some(value.to_vec()),
+                Self::INITIAL_SCID => params.initial_scid = Some(value.to_vec()),
+                Self::MAX_DATAGRAM_FRAME_SIZE => {
+                    params.max_datagram_frame_size = Some(Reader::new(value).varint()?)
+                }
+                _ => {}
+            }
+        }
+        Ok(params)
+    }
+}
+
+/// A frame to send again if the packet it went in is lost
+#[derive(Debug)]
+enum Retransmit {
+    Crypto(u64, Vec<u8>),
+    HandshakeDone,
+}
+
+#[derive(Debug, Default)]
+struct PacketSpace {
+    open: Option<Keys>,
+    seal: Option<Keys>,
+    next_pn: u64,
+    /// The latest packet numbers received
+    received: BTreeSet<u64>,
+    /// When to acknowledge the packets received
+    ack_at: Option<Timing>,
+    /// How much of the peer's CRYPTO data was taken up, and parts of it
+    /// from further on
+    crypto_offset: u64,
+    crypto_parts: BTreeMap<u64, Vec<u8>>,
+    /// CRYPTO data to send, by offset
+    crypto_out: VecDeque<(u64, Vec<u8>)>,
+    crypto_sent: u64,
+    /// The frames of packets not acknowledged yet, by packet number
+    unacked: BTreeMap<u64, Vec<Retransmit>>,
+}
+
+/// Where a PATH_CHALLENGE went
+#[derive(Debug)]
+struct Challenge {
+    to: SocketAddr,
+    data: [u8; 8],
+    sent_at: Timing,
+}
+
+/// One connection, without the socket it goes through
+#[derive(Debug)]
+struct Connection {
+    server: bool,
+    /// The address rosenpass knows the peer under
+    addr: SocketAddr,
+    /// Where the packets go to
+    remote: SocketAddr,
+    local_cid: [u8; CID_LEN],
+    remote_cid: Vec<u8>,
+    /// The destination connection id of the client's first Initial
+    original_dcid: Vec<u8>,
+    tls: Tls,
+    spaces: [PacketSpace; 3],
+    /// The largest DATAGRAM frame the peer takes, once the handshake is done
+    max_datagram: Option<usize>,
+    /// Messages to send once the handshake is done
+    waiting: VecDeque<Vec<u8>>,
+    /// Clients: whether packets of the server set the connection id yet
+    remote_cid_known: bool,
+    /// Servers: whether the client showed it receives at its address, and
+    /// what was received and sent before that
+    validated: bool,
+    bytes_received: usize,
+    bytes_sent: usize,
+    challenge: Option<Challenge>,
+    /// PATH_RESPONSE frames to send, with the size to pad their datagrams to
+    responses: Vec<(SocketAddr, [u8; 8], usize)>,
+    handshake_done: bool,
+    ping: bool,
+    created_at: Timing,
+    last_received: Timing,
+    /// When the last packet with frames to retransmit was sent
+    sent_at: Timing,
+    pto_count: u32,
+    ping_at: Timing,
+    /// Since when an ack-eliciting packet of ours waits for acknowledgement
+    unanswered_since: Option<Timing>,
+    closed: bool,
+    /// Datagrams to send
+    outbox: Vec<(Vec<u8>, SocketAddr)>,
+    /// Messages received
+    inbox: Vec<Vec<u8>>,
+}
+
+fn random_cid() -> [u8; CID_LEN] {
+    let mut cid = [0u8; CID_LEN];
+    rosenpass_sodium::helpers::randombytes_buf(&mut cid);
+    cid
+}
+
+/// The packet number closest to the next one expected (RFC 9000, appendix
+/// A.3)
+fn decode_pn(largest: Option<u64>, truncated: u64, pn_len: usize) -> u64 {
+    let expected = largest.map_or(0, |l| l + 1);
+    let win = 1u64 << (pn_len * 8);
+    let candidate = (expected & !(win - 1)) | truncated;
+    if candidate + win / 2 <= expected && candidate < (1 << 62) - win {
+        candidate + win
+    } else if candidate > expected + win / 2 && candidate >= win {
+        candidate - win
+    } else {
+        candidate
+    }
+}
+
+/// The ranges of packet numbers, lowest and highest, an ACK frame lists
+fn read_ack(r: &mut Reader, ecn: bool) -> Result<Vec<(u64, u64)>> {
+    let largest = r.varint()?;
+    let _delay = r.varint()?;
+    let count = r.varint()?;
+    let first = r.varint()?;
+    let mut smallest = largest.checked_sub(first).context("malformed ACK frame")?;
+    let mut ranges = vec![(smallest, largest)];
+    for _ in 0..count {
+        let (gap, len) = (r.varint()?, r.varint()?);
+        let high = smallest
+            .checked_sub(gap + 2)
+            .context("malformed ACK frame")?;
+        smallest = high.checked_sub(len).context("malformed ACK frame")?;
+        ranges.push((smallest, high));
+    }
+    if ecn {
+        for _ in 0..3 {
+            r.varint()?;
+        }
+    }
+    Ok(ranges)
+}
+
+/// Whether `frames` are ACK frames only, which need no acknowledgement
+fn ack_only(frames: &[u8]) -> bool {
+    let mut r = Reader::new(frames);
+    while !r.is_empty() {
+        match r.varint() {
+            Ok(ACK) if read_ack(&mut r, false).is_ok() => {}
+            _ => return false,
+        }
+    }
+    true
+}
+
+impl Connection {
+    fn new(server: bool, addr: SocketAddr, remote: SocketAddr, tls: Tls, now: Timing) -> Self {
+        Self {
+            server,
+            addr,
+            remote,
+            local_cid: random_cid(),
+            remote_cid: Vec::new(),
+            original_dcid: Vec::new(),
+            tls,
+            spaces: Default::default(),
+            max_datagram: None,
+            waiting: VecDeque::new(),
+            remote_cid_known: false,
+            validated: !server,
+            bytes_received: 0,
+            bytes_sent: 0,
+            challenge: None,
+            responses: Vec::new(),
+            handshake_done: false,
+            ping: false,
+            created_at: now,
+            last_received: now,
+            sent_at: now,
+            pto_count: 0,
+            ping_at: now + KEEPALIVE_INTERVAL,
+            unanswered_since: None,
+            closed: false,
+            outbox: Vec::new(),
+            inbox: Vec::new(),
+        }
+    }
+
+    /// A connection to `remote`, which rosenpass knows as `addr`
+    fn client(
+        addr: SocketAddr,
+        remote: SocketAddr,
+        server_name: Option<&str>,
+        now: Timing,
+    ) -> Result<Self> {
+        let local_cid = random_cid();
+        let params = Params {
+            initial_scid: Some(local_cid.to_vec()),
+            max_datagram_frame_size: Some(MAX_DATAGRAM_FRAME),
+            ..Default::default()
+        };
+        let (tls, hello) = Tls::client(params.encode(), server_name)?;
+        let mut conn = Self::new(false, addr, remote, tls, now);
+        let dcid = random_cid().to_vec();
+        conn.local_cid = local_cid;
+        conn.spaces[Space::Initial as usize].open = Some(Keys::initial(&dcid, true)?);
+        conn.spaces[Space::Initial as usize].seal = Some(Keys::initial(&dcid, false)?);
+        conn.remote_cid = dcid.clone();
+        conn.original_dcid = dcid;
+        conn.queue_crypto(Space::Initial, hello);
+        Ok(conn)
+    }
+
+    /// A connection from `remote`, whose first Initial went to `dcid` from
+    /// `scid`
+    fn server(remote: SocketAddr, dcid: &[u8], scid: &[u8], now: Timing) -> Result<Self> {
+        let local_cid = random_cid();
+        let params = Params {
+            original_dcid: Some(dcid.to_vec()),
+            initial_scid: Some(local_cid.to_vec()),
+            max_datagram_frame_size: Some(MAX_DATAGRAM_FRAME),
+        };
+        let tls = Tls::server(params.encode());
+        let mut conn = Self::new(true, synthetic(1, local_cid), remote, tls, now);
+        conn.local_cid = local_cid;
+        conn.spaces[Space::Initial as usize].open = Some(Keys::initial(dcid, false)?);
+        conn.spaces[Space::Initial as usize].seal = Some(Keys::initial(dcid, true)?);
+        conn.remote_cid = scid.to_vec();
+        conn.remote_cid_known = true;
+        conn.original_dcid = dcid.to_vec();
+        Ok(conn)
+    }
+
+    fn established(&self) -> bool {
+        self.max_datagram.is_some()
+    }
+
+    fn queue_crypto(&mut self, space: Space, data: Vec<u8>) {
+        let sp = &mut self.spaces[space as usize];
+        sp.crypto_out.push_back((sp.crypto_sent, data.clone()));
+        sp.crypto_sent += data.len() as u64;
+    }
+
+    /// Forget the keys and the state of `space`
+    fn discard(&mut self, space: Space) {
+        let sp = &mut self.spaces[space as usize];
+        sp.open = None;
+        sp.seal = None;
+        sp.unacked.clear();
+        sp.crypto_out.clear();
+        sp.ack_at = None;
+    }
+
+    /// Send `msg` to the peer, once the handshake is done
+    fn send(&mut self, msg: &[u8], now: Timing) -> Result<()> {
+        match self.max_datagram {
+            Some(max) => {
+                ensure!(
+                    msg.len() + 1 + 8 <= max,
+                    "the peer takes no datagrams this long"
+                );
+                let mut frame = vec![DATAGRAM_LEN as u8];
+                put_varint(&mut frame, msg.len() as u64);
+                frame.extend_from_slice(msg);
+                let ack = self.take_ack(Space::Data, now, true);
+                let packet = self.packet(
+                    Space::Data,
+                    ack.into_iter().flatten().chain(frame).collect(),
+                    Vec::new(),
+                    0,
+                    now,
+                )?;
+                self.transmit(packet, self.remote);
+            }
+            None => {
+                if self.waiting.len() == MAX_WAITING {
+                    self.waiting.pop_front();
+                }
+                self.waiting.push_back(msg.to_vec());
+            }
+        }
+        Ok(())
+    }
+
+    /// Take up a packet from `from` whose packet number starts at
+    /// `pn_offset`, with `scid` from its long header
+    #[allow(clippy::too_many_arguments)]
+    fn receive(
+        &mut self,
+        space: Space,
+        mut packet: Vec<u8>,
+        pn_offset: usize,
+        scid: Option<&[u8]>,
+        from: SocketAddr,
+        datagram_len: usize,
+        now: Timing,
+    ) -> Result<()> {
+        let long = space != Space::Data;
+        // servers take up no data before the handshake is done
+        ensure!(
+            long || self.tls.done(),
+            "a 1-RTT packet arrived before the handshake was done"
+        );
+        let keys = self.spaces[space as usize]
+            .open
+            .as_ref()
+            .with_context(|| format!("there are no keys for {space:?} packets"))?;
+        ensure!(
+            packet.len() >= pn_offset + 4 + SAMPLE_LEN,
+            "the packet is too short"
+        );
+
+        // remove the header protection
+        let sample = packet[pn_offset + 4..pn_offset + 4 + SAMPLE_LEN].try_into()?;
+        let mask = keys.mask(&sample)?;
+        packet[0] ^= mask[0] & if long { 0x0f } else { 0x1f };
+        let pn_len = (packet[0] & 0x03) as usize + 1;
+        let mut truncated = 0;
+        for i in 0..pn_len {
+            packet[pn_offset + i] ^= mask[1 + i];
+            truncated = (truncated << 8) | packet[pn_offset + i] as u64;
+        }
+        let sp = &self.spaces[space as usize];
+        let pn = decode_pn(sp.received.last().copied(), truncated, pn_len);
+        ensure!(!sp.received.contains(&pn), "packet {pn} arrived twice");
+        let (header, sealed) = packet.split_at(pn_offset + pn_len);
+        let payload = keys.open(pn, header, sealed)?;
+        let reserved = if long { 0x0c } else { 0x18 };
+        ensure!(header[0] & reserved == 0, "the reserved bits are set");
+        // key updates are not supported
+        ensure!(long || header[0] & 0x04 == 0, "the key phase changed");
+
+        self.last_received = now;
+        if !self.validated {
+            self.bytes_received += datagram_len;
+        }
+        let sp = &mut self.spaces[space as usize];
+        let newest = sp.received.last().is_none_or(|&l| pn > l);
+        sp.received.insert(pn);
+        if sp.received.len() > MAX_RECEIVED {
+            sp.received.pop_first();
+        }
+        if let (Some(scid), false) = (scid, self.remote_cid_known) {
+            // the server chose its connection id
+            self.remote_cid = scid.to_vec();
+            self.remote_cid_known = true;
+        }
+        if self.server && space == Space::Handshake && !self.validated {
+            // only the client could have sent this
+            self.validated = true;
+            self.discard(Space::Initial);
+        }
+
+        let (eliciting, probing) = match self.frames(space, &payload, from, datagram_len, now) {
+            Ok(kinds) => kinds,
+            Err(e) => {
+                self.close(&format!("{e:#}"), now);
+                return Err(e);
+            }
+        };
+        if eliciting {
+            let sp = &mut self.spaces[space as usize];
+            let at = match space {
+                Space::Data => now + ACK_DELAY,
+                _ => now,
+            };
+            sp.ack_at = Some(sp.ack_at.map_or(at, |a| a.min(at)));
+        }
+        if self.server && self.tls.done() && space == Space::Data {
+            self.discard(Space::Handshake);
+        }
+
+        // the client moved
+        if self.server && space == Space::Data && from != self.remote && newest && !probing {
+            let resend = self
+                .challenge
+                .as_ref()
+                .is_none_or(|c| c.to != from || now >= c.sent_at + INITIAL_PTO);
+            if resend {
+                let mut data = [0u8; 8];
+                rosenpass_sodium::helpers::randombytes_buf(&mut data);
+                let mut frame = vec![PATH_CHALLENGE as u8];
+                frame.extend_from_slice(&data);
+                let pad = MIN_INITIAL_SIZE.min(3 * datagram_len);
+                let packet = self.packet(Space::Data, frame, Vec::new(), pad, now)?;
+                self.outbox.push((packet, from));
+                self.challenge = Some(Challenge {
+                    to: from,
+                    data,
+                    sent_at: now,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Take up the frames of a packet, telling whether they are
+    /// ack-eliciting and whether they only probe the path
+    fn frames(
+        &mut self,
+        space: Space,
+        payload: &[u8],
+        from: SocketAddr,
+        datagram_len: usize,
+        now: Timing,
+    ) -> Result<(bool, bool)> {
+        let mut r = Reader::new(payload);
+        let (mut eliciting, mut probing) = (false, true);
+        ensure!(!r.is_empty(), "the packet has no frames");
+        while !r.is_empty() {
+            let typ = r.varint()?;
+            if !matches!(
+                typ,
+                PADDING | ACK | ACK_ECN | CONNECTION_CLOSE | CONNECTION_CLOSE_APP
+            ) {
+                eliciting = true;
+            }
+            if !matches!(
+                typ,
+                PADDING | PATH_CHALLENGE | PATH_RESPONSE | NEW_CONNECTION_ID
+            ) {
+                probing = false;
+            }
+            match typ {
+                PADDING | PING => {}
+                ACK | ACK_ECN => {
+                    let ranges = read_ack(&mut r, typ == ACK_ECN)?;
+                    self.acked(space, &ranges);
+                }
+                CRYPTO => {
+                    let offset = r.varint()?;
+                    let data = r.vec_varint()?;
+                    self.crypto(space, offset, data, now)?;
+                }
+                CONNECTION_CLOSE | CONNECTION_CLOSE_APP => {
+                    let code = r.varint()?;
+                    if typ == CONNECTION_CLOSE {
+                        r.varint()?;
+                    }
+                    let reason = String::from_utf8_lossy(r.vec_varint()?);
+                    debug!(
+                        "QUIC connection {} closed with {code:#x}: {reason}",
+                        self.addr
+                    );
+                    self.closed = true;
+                    return Ok((false, false));
+                }
+                _ if space != Space::Data => bail!("frame {typ:#x} in a {space:?} packet"),
+                NEW_TOKEN => {
+                    r.vec_varint()?;
+                }
+                NEW_CONNECTION_ID => {
+                    r.varint()?;
+                    r.varint()?;
+                    r.vec8()?;
+                    r.bytes(16)?;
+                }
+                RETIRE_CONNECTION_ID => {
+                    r.varint()?;
+                }
+                PATH_CHALLENGE => {
+                    let data = r.bytes(8)?.try_into()?;
+                    let pad = match self.validated {
+                        true => MIN_INITIAL_SIZE,
+                        false => MIN_INITIAL_SIZE.min(3 * datagram_len),
+                    };
+                    self.responses.push((from, data, pad));
+                }
+                PATH_RESPONSE => {
+                    let data = r.bytes(8)?;
+                    if let Some(c) = self.challenge.take_if(|c| c.data == data) {
+                        info!(
+                            "QUIC connection {} moved from {} to {}",
+                            self.addr, self.remote, c.to
+                        );
+                        self.remote = c.to;
+                    }
+                }
+                HANDSHAKE_DONE => {
+                    ensure!(!self.server, "HANDSHAKE_DONE sent by the client");
+                    self.discard(Space::Handshake);
+                }
+                DATAGRAM => self.inbox.push(r.rest().to_vec()),
+                DATAGRAM_LEN => self.inbox.push(r.vec_varint()?.to_vec()),
+                _ => bail!("unexpected frame {typ:#x}"),
+            }
+        }
+        Ok((eliciting, probing))
+    }
+
+    fn acked(&mut self, space: Space, ranges: &[(u64, u64)]) {
+        let sp = &mut self.spaces[space as usize];
+        sp.unacked
+            .retain(|pn, _| !ranges.iter().any(|&(low, high)| low <= *pn && *pn <= high));
+        if space == Space::Data {
+            self.unanswered_since = None;
+        }
+        if self.spaces.iter().all(|sp| sp.unacked.is_empty()) {
+            self.pto_count = 0;
+        }
+    }
+
+    /// Take up CRYPTO data at `offset`, handing it to TLS once all before it
+    /// arrived
+    fn crypto(&mut self, space: Space, offset: u64, data: &[u8], now: Timing) -> Result<()> {
+        let sp = &mut self.spaces[space as usize];
+        let end = offset + data.len() as u64;
+        ensure!(
+            end <= sp.crypto_offset + MAX_CRYPTO_AHEAD,
+            "CRYPTO data reaches too far"
+        );
+        if end <= sp.crypto_offset {
+            return Ok(());
+        }
+        let part = sp.crypto_parts.entry(offset).or_default();
+        if part.len() < data.len() {
+            *part = data.to_vec();
+        }
+        loop {
+            let sp = &mut self.spaces[space as usize];
+            let Some(entry) = sp.crypto_parts.first_entry() else {
+                break;
+            };
+            if *entry.key() > sp.crypto_offset {
+                break;
+            }
+            let (start, data) = entry.remove_entry();
+            let end = start + data.len() as u64;
+            if end <= sp.crypto_offset {
+                continue;
+            }
+            let new = &data[(sp.crypto_offset - start) as usize..];
+            sp.crypto_offset = end;
+            for output in self.tls.read(space, new)? {
+                match output {
+                    Output::Send(space, data) => self.queue_crypto(space, data),
+                    Output::Keys(space, open, seal) => {
+                        self.spaces[space as usize].open = Some(open);
+                        self.spaces[space as usize].seal = Some(seal);
+                    }
+                    Output::Done(params) => self.handshake_complete(&params, now)?,
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn handshake_complete(&mut self, params: &[u8], now: Timing) -> Result<()> {
+        let params = Params::decode(params)?;
+        ensure!(
+            params.initial_scid.as_deref() == Some(&self.remote_cid[..]),
+            "the initial_source_connection_id of the peer is wrong"
+        );
+        ensure!(
+            self.server || params.original_dcid.as_deref() == Some(&self.original_dcid[..]),
+            "the original_destination_connection_id of the server is wrong"
+        );
+        let max = params
+            .max_datagram_frame_size
+            .context("the peer takes no DATAGRAM frames")?;
+        self.max_datagram = Some(max.min(MAX_DATAGRAM_FRAME) as usize);
+        self.ping_at = now + KEEPALIVE_INTERVAL;
+        self.handshake_done = self.server;
+        debug!("QUIC connection {} to {} is set up", self.addr, self.remote);
+        Ok(())
+    }
+
+    /// An ACK frame for `space`, if acknowledgements are due, or with
+    /// `now_anyway` if they are pending at all
+    fn take_ack(&mut self, space: Space, now: Timing, now_anyway: bool) -> Option<Vec<u8>> {
+        let sp = &mut self.spaces[space as usize];
+        let at = sp.ack_at?;
+        if at > now && !now_anyway {
+            return None;
+        }
+        sp.ack_at = None;
+        let mut pns = sp.received.iter().rev().copied();
+        let largest = pns.next()?;
+        let mut ranges = vec![(largest, largest)];
+        for pn in pns {
+            let last = ranges.last_mut().unwrap();
+            match pn + 1 == last.0 {
+                true => last.0 = pn,
+                false => ranges.push((pn, pn)),
+            }
+        }
+        let mut frame = vec![ACK as u8];
+        put_varint(&mut frame, largest);
+        put_varint(&mut frame, 0);
+        put_varint(&mut frame, ranges.len() as u64 - 1);
+        put_varint(&mut frame, largest - ranges[0].0);
+        for pair in ranges.windows(2) {
+            let (prev, this) = (pair[0], pair[1]);
+            put_varint(&mut frame, prev.0 - this.1 - 2);
+            put_varint(&mut frame, this.1 - this.0);
+        }
+        Some(frame)
+    }
+
+    /// Protect a packet with `frames`, padded so it takes `min_len` bytes
+    fn packet(
+        &mut self,
+        space: Space,
+        mut frames: Vec<u8>,
+        retransmit: Vec<Retransmit>,
+        min_len: usize,
+        now: Timing,
+    ) -> Result<Vec<u8>> {
+        let eliciting = !ack_only(&frames);
+        let sp = &mut self.spaces[space as usize];
+        let keys = sp
+            .seal
+            .as_ref()
+            .with_context(|| format!("there are no keys for {space:?} packets"))?;
+        let pn = sp.next_pn;
+        sp.next_pn += 1;
+
+        // four byte packet numbers, so the sample starts right after them
+        let mut header = Vec::new();
+        let overhead = match space {
+            Space::Data => 1 + self.remote_cid.len() + 4,
+            _ => {
+                1 + 4
+                    + 2
+                    + self.remote_cid.len()
+                    + CID_LEN
+                    + 2
+                    + 4
+                    + (space == Space::Initial) as usize
+            }
+        } + crate::quic_crypto::TAG_LEN;
+        if overhead + frames.len() < min_len {
+            frames.resize(min_len - overhead, PADDING as u8);
+        }
+        match space {
+            Space::Data => {
+                header.push(0x40 | 0x03);
+                header.extend_from_slice(&self.remote_cid);
+            }
+            _ => {
+                let typ = match space {
+                    Space::Initial => 0,
+                    _ => 2,
+                };
+                header.push(0xc0 | (typ << 4) | 0x03);
+                header.extend_from_slice(&VERSION.to_be_bytes());
+                header.push(self.remote_cid.len() as u8);
+                header.extend_from_slice(&self.remote_cid);
+                header.push(CID_LEN as u8);
+                header.extend_from_slice(&self.local_cid);
+                if space == Space::Initial {
+                    // no token
+                    header.push(0);
+                }
+                let len = 4 + frames.len() + crate::quic_crypto::TAG_LEN;
+                header.extend_from_slice(&(len as u16 | 0x4000).to_be_bytes());
+            }
+        }
+        let pn_offset = header.len();
+        header.extend_from_slice(&(pn as u32).to_be_bytes());
+        let sealed = keys.seal(pn, &header, &frames)?;
+        let mask = keys.mask(sealed[..SAMPLE_LEN].try_into()?)?;
+        header[0] ^= mask[0] & if space == Space::Data { 0x1f } else { 0x0f };
+        for i in 0..4 {
+            header[pn_offset + i] ^= mask[1 + i];
+        }
+        header.extend_from_slice(&sealed);
+
+        if !retransmit.is_empty() {
+            sp.unacked.insert(pn, retransmit);
+            self.sent_at = now;
+        }
+        if eliciting && space == Space::Data {
+            self.ping_at = now + KEEPALIVE_INTERVAL;
+            self.unanswered_since.get_or_insert(now);
+        }
+        Ok(header)
+    }
+
+    /// Queue `datagram` for `to`, within the anti-amplification limit of
+    /// servers
+    fn transmit(&mut self, datagram: Vec<u8>, to: SocketAddr) {
+        if !self.validated {
+            if self.bytes_sent + datagram.len() > 3 * self.bytes_received {
+                debug!("not sending to unvalidated address {to} yet");
+                return;
+            }
+            self.bytes_sent += datagram.len();
+        }
+        self.outbox.push((datagram, to));
+    }
+
+    /// The frames to send in a packet of `space`, and the frames among them
+    /// to send again if it is lost
+    fn pending(&mut self, space: Space, now: Timing) -> (Vec<u8>, Vec<Retransmit>) {
+        let mut frames = Vec::new();
+        let mut retransmit = Vec::new();
+        if self.spaces[space as usize].seal.is_none() {
+            return (frames, retransmit);
+        }
+        for (offset, data) in self.spaces[space as usize].crypto_out.drain(..) {
+            frames.push(CRYPTO as u8);
+            put_varint(&mut frames, offset);
+            put_varint(&mut frames, data.len() as u64);
+            frames.extend_from_slice(&data);
+            retransmit.push(Retransmit::Crypto(offset, data));
+        }
+        if space == Space::Data && self.tls.done() {
+            if std::mem::take(&mut self.handshake_done) {
+                frames.push(HANDSHAKE_DONE as u8);
+                retransmit.push(Retransmit::HandshakeDone);
+            }
+            if std::mem::take(&mut self.ping) {
+                frames.push(PING as u8);
+            }
+        }
+        let anyway = !frames.is_empty();
+        if let Some(ack) = self.take_ack(space, now, anyway) {
+            frames.extend_from_slice(&ack);
+        }
+        (frames, retransmit)
+    }
+
+    /// Send what is pending
+    fn flush(&mut self, now: Timing) -> Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+        let mut later = Vec::new();
+        for space in [Space::Handshake, Space::Data] {
+            let (frames, retransmit) = self.pending(space, now);
+            if !frames.is_empty() {
+                later.extend(self.packet(space, frames, retransmit, 0, now)?);
+            }
+        }
+        let (frames, retransmit) = self.pending(Space::Initial, now);
+        let mut datagram = Vec::new();
+        if !frames.is_empty() {
+            let min_len = MIN_INITIAL_SIZE.saturating_sub(later.len());
+            datagram = self.packet(Space::Initial, frames, retransmit, min_len, now)?;
+        }
+        let handshake_sent =
+            !later.is_empty() && self.spaces[Space::Handshake as usize].seal.is_some();
+        datagram.extend(later);
+        if !datagram.is_empty() {
+            self.transmit(datagram, self.remote);
+        }
+        if !self.server && handshake_sent {
+            self.discard(Space::Initial);
+        }
+
+        if self.tls.done() {
+            if let Some(max) = self.max_datagram {
+                for msg in std::mem::take(&mut self.waiting) {
+                    match msg.len() + 1 + 8 <= max {
+                        true => self.send(&msg, now)?,
+                        false => debug!("dropping a message too long for {}", self.addr),
+                    }
+                }
+            }
+            for (to, data, pad) in std::mem::take(&mut self.responses) {
+                let mut frame = vec![PATH_RESPONSE as u8];
+                frame.extend_from_slice(&data);
+                let packet = self.packet(Space::Data, frame, Vec::new(), pad, now)?;
+                self.outbox.push((packet, to));
+            }
+        }
+        Ok(())
+    }
+
+    /// Tell the peer why the connection ends
+    fn close(&mut self, reason: &str, now: Timing) {
+        debug!("closing QUIC connection {}: {reason}", self.addr);
+        let space = [Space::Data, Space::Handshake, Space::Initial]
+            .into_iter()
+            .find(|&s| {
+                self.spaces[s as usize].seal.is_some() && (s != Space::Data || self.tls.done())
+            });
+        if let Some(space) = space {
+            let mut frame = vec![CONNECTION_CLOSE as u8];
+            put_varint(&mut frame, PROTOCOL_VIOLATION);
+            put_varint(&mut frame, 0);
+            put_varint(&mut frame, 0);
+            if let Ok(packet) = self.packet(space, frame, Vec::new(), 0, now) {
+                self.transmit(packet, self.remote);
+            }
+        }
+        self.closed = true;
+    }
+
+    fn pto_at(&self) -> Option<Timing> {
+        let unacked = self.spaces.iter().any(|sp| !sp.unacked.is_empty());
+        unacked.then(|| self.sent_at + INITIAL_PTO * (1 << self.pto_count.min(6)) as Timing)
+    }
+
+    /// When [Self::on_timeout] is due next
+    fn next_timeout(&self) -> Timing {
+        let mut at = self.last_received + IDLE_TIMEOUT;
+        if !self.tls.done() {
+            at = at.min(self.created_at + HANDSHAKE_TIMEOUT);
+        }
+        if let Some(pto) = self.pto_at() {
+            at = at.min(pto);
+        }
+        for sp in self.spaces.iter() {
+            if let Some(ack) = sp.ack_at {
+                at = at.min(ack);
+            }
+        }
+        if !self.server && self.established() {
+            at = at.min(self.ping_at);
+            if let Some(since) = self.unanswered_since {
+                at = at.min(since + UNANSWERED_TIMEOUT);
+            }
+        }
+        at
+    }
+
+    fn on_timeout(&mut self, now: Timing) -> Result<()> {
+        if now >= self.last_received + IDLE_TIMEOUT {
+            debug!("QUIC connection {} went idle", self.addr);
+            self.closed = true;
+        } else if !self.tls.done() && now >= self.created_at + HANDSHAKE_TIMEOUT {
+            debug!("QUIC connection {} timed out in the handshake", self.addr);
+            self.closed = true;
+        } else if !self.server
+            && self
+                .unanswered_since
+                .is_some_and(|s| now >= s + UNANSWERED_TIMEOUT)
+        {
+            info!(
+                "QUIC connection {} to {} stopped being answered",
+                self.addr, self.remote
+            );
+            self.closed = true;
+        }
+        if self.closed {
+            return Ok(());
+        }
+
+        if self.pto_at().is_some_and(|at| now >= at) {
+            self.pto_count += 1;
+            for space in [Space::Initial, Space::Handshake, Space::Data] {
+                let unacked = std::mem::take(&mut self.spaces[space as usize].unacked);
+                for frame in unacked.into_values().flatten() {
+                    match frame {
+                        Retransmit::Crypto(offset, data) => {
+                            let sp = &mut self.spaces[space as usize];
+                            sp.crypto_out.push_back((offset, data))
+                        }
+                        Retransmit::HandshakeDone => self.handshake_done = true,
+                    }
+                }
+            }
+            // something to send on the path, so it is clear the retransmission
+            // is not blocked by the anti-amplification limit alone
+            self.sent_at = now;
+        }
+        if !self.server && self.established() && now >= self.ping_at {
+            self.ping = true;
+        }
+        self.flush(now)
+    }
+}
+
+/// The connections of one socket
+#[derive(Debug)]
+struct Connections {
+    /// The address of the socket
+    local: SocketAddr,
+    /// Whether to accept connections
+    accept: bool,
+    by_cid: HashMap<[u8; CID_LEN], Connection>,
+    /// The accepted connections by the destination connection id of the
+    /// client's first Initial, which its Initials go to until it learns ours
+    initial: HashMap<Vec<u8>, [u8; CID_LEN]>,
+    /// The connections to `quic:` endpoints, by the address they stand for
+    clients: HashMap<SocketAddr, [u8; CID_LEN]>,
+    /// Messages received, and the addresses they came from
+    received: VecDeque<(Vec<u8>, SocketAddr)>,
+    /// Datagrams to send
+    outbox: Vec<(Vec<u8>, SocketAddr)>,
+}
+
+impl Connections {
+    fn new(local: SocketAddr, accept: bool) -> Self {
+        Self {
+            local,
+            accept,
+            by_cid: HashMap::new(),
+            initial: HashMap::new(),
+            clients: HashMap::new(),
+            received: VecDeque::new(),
+            outbox: Vec::new(),
+        }
+    }
+
+    /// Take up a UDP datagram
+    fn receive(&mut self, datagram: &[u8], from: SocketAddr, now: Timing) {
+        let mut rest = datagram;
+        while !rest.is_empty() {
+            match self.receive_packet(&mut rest, datagram.len(), from, now) {
+                Ok(Some(cid)) => self.collect(cid, now),
+                Ok(None) => {}
+                Err(e) => {
+                    debug!("dropping QUIC packet from {from}: {e:#}");
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Take up the packet at the beginning of `rest`, leaving the packets
+    /// after it; tells the connection it was for
+    fn receive_packet(
+        &mut self,
+        rest: &mut &[u8],
+        datagram_len: usize,
+        from: SocketAddr,
+        now: Timing,
+    ) -> Result<Option<[u8; CID_LEN]>> {
+        let data = *rest;
+        if data[0] & 0x80 == 0 {
+            *rest = &[];
+            ensure!(data.len() > 1 + CID_LEN, "the packet is too short");
+            let cid: [u8; CID_LEN] = data[1..1 + CID_LEN].try_into()?;
+            let conn = self
+                .by_cid
+                .get_mut(&cid)
+                .context("no connection has this id")?;
+            conn.receive(
+                Space::Data,
+                data.to_vec(),
+                1 + CID_LEN,
+                None,
+                from,
+                datagram_len,
+                now,
+            )?;
+            return Ok(Some(cid));
+        }
+
+        let mut r = Reader::new(&data[1..]);
+        let version = r.u32()?;
+        let dcid = r.vec8()?;
+        let scid = r.vec8()?;
+        ensure!(
+            dcid.len() <= 20 && scid.len() <= 20,
+            "overlong connection ids"
+        );
+        if version != VERSION {
+            *rest = &[];
+            if self.accept && datagram_len >= MIN_INITIAL_SIZE {
+                self.negotiate_version(dcid, scid, from);
+            }
+            bail!("version {version:#x} is not supported");
+        }
+        let space = match (data[0] >> 4) & 0x03 {
+            0 => Space::Initial,
+            2 => Space::Handshake,
+            typ => bail!("long header packets of type {typ} are not taken"),
+        };
+        if space == Space::Initial {
+            r.vec_varint()?;
+        }
+        let len = usize::try_from(r.varint()?)?;
+        let pn_offset = data.len() - r.len();
+        ensure!(r.len() >= len, "the packet is truncated");
+        let (packet, after) = data.split_at(pn_offset + len);
+        *rest = after;
+
+        let known = <[u8; CID_LEN]>::try_from(dcid)
+            .ok()
+            .filter(|cid| self.by_cid.contains_key(cid))
+            .or_else(|| self.initial.get(dcid).copied());
+        let cid = match known {
+            Some(cid) => cid,
+            None => {
+                ensure!(
+                    self.accept && space == Space::Initial && datagram_len >= MIN_INITIAL_SIZE,
+                    "no connection has this id"
+                );
+                ensure!(dcid.len() >= 8, "the connection id is too short");
+                ensure!(
+                    self.by_cid.len() < MAX_CONNECTIONS,
+                    "there are too many connections"
+                );
+                let conn = Connection::server(from, dcid, scid, now)?;
+                let cid = conn.local_cid;
+                self.initial.insert(dcid.to_vec(), cid);
+                self.by_cid.insert(cid, conn);
+                cid
+            }
+        };
+        let conn = self.by_cid.get_mut(&cid).unwrap();
+        conn.receive(
+            space,
+            packet.to_vec(),
+            pn_offset,
+            Some(scid),
+            from,
+            datagram_len,
+            now,
+        )?;
+        Ok(Some(cid))
+    }
+
+    /// Tell a client trying another version that we speak version 1
+    fn negotiate_version(&mut self, dcid: &[u8], scid: &[u8], to: SocketAddr) {
+        let mut packet = vec![0x80 | (random_cid()[0] & 0x7f)];
+        packet.extend_from_slice(&0u32.to_be_bytes());
+        packet.push(scid.len() as u8);
+        packet.extend_from_slice(scid);
+        packet.push(dcid.len() as u8);
+        packet.extend_from_slice(dcid);
+        packet.extend_from_slice(&VERSION.to_be_bytes());
+        self.outbox.push((packet, to));
+    }
+
+    /// Send what connection `cid` has pending and take its messages, or
+    /// forget it once closed
+    fn collect(&mut self, cid: [u8; CID_LEN], now: Timing) {
+        let Some(conn) = self.by_cid.get_mut(&cid) else {
+            return;
+        };
+        if let Err(e) = conn.flush(now) {
+            conn.close(&format!("{e:#}"), now);
+        }
+        self.outbox.append(&mut conn.outbox);
+        let addr = conn.addr;
+        self.received
+            .extend(conn.inbox.drain(..).map(|msg| (msg, addr)));
+        if conn.closed {
+            self.by_cid.remove(&cid);
+            self.initial.retain(|_, c| *c != cid);
+            self.clients.retain(|_, c| *c != cid);
+        }
+    }
+
+    /// Send `msg` to the peer rosenpass knows as `to`
+    fn send(&mut self, msg: &[u8], to: SocketAddr, now: Timing) -> io::Result<()> {
+        let cid = match (incoming_cid(&to), target_of(&to)) {
+            (Some(cid), _) if self.by_cid.contains_key(&cid) => cid,
+            // gone, like the datagrams sent to where nobody listens
+            (Some(_), _) => return Ok(()),
+            (None, Some(_)) if self.clients.contains_key(&to) => self.clients[&to],
+            (None, Some(target)) => {
+                let remote = match (
+                    self.local,
+                    target
+                        .addrs
+                        .iter()
+                        .find(|a| a.is_ipv4() == self.local.is_ipv4()),
+                ) {
+                    (_, Some(remote)) => *remote,
+                    // dual-stack sockets reach IPv4 addresses as mapped ones
+                    (SocketAddr::V6(_), None) => match target.addrs[0] {
+                        SocketAddr::V4(v4) => {
+                            SocketAddr::new(IpAddr::V6(v4.ip().to_ipv6_mapped()), v4.port())
+                        }
+                        v6 => v6,
+                    },
+                    (SocketAddr::V4(_), None) => return Err(wrong_kind()),
+                };
+                ensure_io(
+                    self.by_cid.len() < MAX_CONNECTIONS,
+                    "there are too many QUIC connections",
+                )?;
+                let conn = Connection::client(to, remote, target.name.as_deref(), now)
+                    .map_err(io::Error::other)?;
+                let cid = conn.local_cid;
+                self.clients.insert(to, cid);
+                self.by_cid.insert(cid, conn);
+                cid
+            }
+            (None, None) => return Err(wrong_kind()),
+        };
+        let conn = self.by_cid.get_mut(&cid).unwrap();
+        if let Err(e) = conn.send(msg, now) {
+            debug!("could not send to {to}: {e:#}");
+        }
+        self.collect(cid, now);
+        Ok(())
+    }
+
+    fn next_timeout(&self) -> Option<Timing> {
+        self.by_cid
+            .values()
+            .map(Connection::next_timeout)
+            .min_by(|a, b| a.total_cmp(b))
+    }
+
+    fn on_timeout(&mut self, now: Timing) {
+        let due: Vec<[u8; CID_LEN]> = self
+            .by_cid
+            .iter()
+            .filter(|(_, conn)| conn.next_timeout() <= now)
+            .map(|(&cid, _)| cid)
+            .collect();
+        for cid in due {
+            let conn = self.by_cid.get_mut(&cid).unwrap();
+            if let Err(e) = conn.on_timeout(now) {
+                conn.close(&format!("{e:#}"), now);
+            }
+            self.collect(cid, now);
+        }
+    }
+}
+
+fn ensure_io(cond: bool, msg: &str) -> io::Result<()> {
+    match cond {
+        true => Ok(()),
+        false => Err(io::Error::other(msg.to_owned())),
+    }
+}
+
+/// A UDP socket carrying QUIC connections
+#[derive(Debug)]
+pub struct QuicSocket {
+    pub socket: mio::net::UdpSocket,
+    /// Whether the socket listens for connections, and was handed over by
+    /// an upgrade, see [crate::upgrade]
+    pub accept: bool,
+    timebase: Timebase,
+    connections: RefCell<Connections>,
+}
+
+impl QuicSocket {
+    /// A socket at `addr`, accepting connections with `accept`; its timers
+    /// run on `timebase`
+    pub fn bind(addr: SocketAddr, accept: bool, timebase: Timebase) -> Result<Self> {
+        let socket = match accept {
+            true => upgrade::bind_udp(addr),
+            // an ephemeral port, for connections to QUIC endpoints only
+            false => mio::net::UdpSocket::bind(addr),
+        }
+        .with_context(|| format!("could not bind QUIC socket {addr}"))?;
+        let local = socket.local_addr()?;
+        Ok(Self {
+            socket,
+            accept,
+            timebase,
+            connections: RefCell::new(Connections::new(local, accept)),
+        })
+    }
+
+    fn send_out(&self, connections: &mut Connections) {
+        for (datagram, to) in connections.outbox.drain(..) {
+            if let Err(e) = self.socket.send_to(&datagram, to) {
+                debug!("could not send QUIC datagram to {to}: {e}");
+            }
+        }
+    }
+
+    pub fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        if !is_quic(&addr) {
+            return Err(wrong_kind());
+        }
+        let mut connections = self.connections.borrow_mut();
+        connections.send(buf, addr, self.timebase.now())?;
+        self.send_out(&mut connections);
+        Ok(buf.len())
+    }
+
+    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let mut connections = self.connections.borrow_mut();
+        let mut datagram = vec![0u8; 1 << 16];
+        loop {
+            if let Some((msg, from)) = connections.received.pop_front() {
+                let len = msg.len().min(buf.len());
+                buf[..len].copy_from_slice(&msg[..len]);
+                return Ok((len, from));
+            }
+            let (len, from) = self.socket.recv_from(&mut datagram)?;
+            connections.receive(&datagram[..len], from, self.timebase.now());
+            self.send_out(&mut connections);
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// When [Self::on_timeout] is due next
+    pub fn next_timeout(&self) -> Option<Timing> {
+        self.connections.borrow().next_timeout()
+    }
+
+    /// Handle the timers which are due
+    pub fn on_timeout(&self) {
+        let mut connections = self.connections.borrow_mut();
+        connections.on_timeout(self.timebase.now());
+        self.send_out(&mut connections);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Deliver the datagrams between `a` and `b`, which are at `at_a` and
+    /// `at_b`, until there are none, returning the datagrams sent to
+    /// anywhere else
+    fn exchange(
+        a: &mut Connections,
+        b: &mut Connections,
+        at_a: SocketAddr,
+        at_b: SocketAddr,
+        now: Timing,
+    ) -> Vec<(Vec<u8>, SocketAddr)> {
+        let mut elsewhere = Vec::new();
+        let mut rounds = 0;
+        while !(a.outbox.is_empty() && b.outbox.is_empty()) {
+            rounds += 1;
+            assert!(rounds < 20, "the connections keep talking");
+            for (datagram, to) in a.outbox.drain(..).collect::<Vec<_>>() {
+                match to == at_b {
+                    true => b.receive(&datagram, at_a, now),
+                    false => elsewhere.push((datagram, to)),
+                }
+            }
+            for (datagram, to) in b.outbox.drain(..).collect::<Vec<_>>() {
+                match to == at_a {
+                    true => a.receive(&datagram, at_b, now),
+                    false => elsewhere.push((datagram, to)),
+                }
+            }
+        }
+        elsewhere
+    }
+
+    #[test]
+    fn messages_go_through_connections() {
+        rosenpass_sodium::init().unwrap();
+        let (at_client, at_server): (SocketAddr, SocketAddr) = (
+            "127.0.0.1:5000".parse().unwrap(),
+            "127.0.0.1:443".parse().unwrap(),
+        );
+        let mut client = Connections::new(at_client, false);
+        let mut server = Connections::new(at_server, true);
+        let target = address_of("127.0.0.1:443").unwrap();
+        assert!(is_quic(&target) && incoming_cid(&target).is_none());
+        // connections which are gone lose the messages, like UDP
+        server.send(b"hi", synthetic(1, [1; CID_LEN]), 0.0).unwrap();
+        assert!(server.outbox.is_empty());
+        let udp = "127.0.0.1:443".parse().unwrap();
+        let err = server.send(b"hi", udp, 0.0).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Unsupported);
+
+        client.send(b"hello", target, 0.0).unwrap();
+        client.send(b"again", target, 0.0).unwrap();
+        let (datagram, _) = &client.outbox[0];
+        assert!(datagram.len() >= MIN_INITIAL_SIZE);
+        assert!(exchange(&mut client, &mut server, at_client, at_server, 0.0).is_empty());
+        let (msg, from) = server.received.pop_front().unwrap();
+        assert_eq!(msg, b"hello");
+        assert_eq!(server.received.pop_front().unwrap().0, b"again");
+        assert!(incoming_cid(&from).is_some());
+
+        // answers go back through the connection, which both keep
+        server.send(b"welcome", from, 0.1).unwrap();
+        exchange(&mut client, &mut server, at_client, at_server, 0.1);
+        assert_eq!(
+            client.received.pop_front(),
+            Some((b"welcome".to_vec(), target))
+        );
+        assert_eq!((client.by_cid.len(), server.by_cid.len()), (1, 1));
+        let conn = server.by_cid.values().next().unwrap();
+        assert!(conn.spaces.iter().take(2).all(|sp| sp.open.is_none()));
+        let conn = client.by_cid.values().next().unwrap();
+        assert!(conn.spaces.iter().take(2).all(|sp| sp.open.is_none()));
+
+        // pings keep the connection alive, and are answered
+        let at = client.next_timeout().unwrap();
+        assert!((at - KEEPALIVE_INTERVAL - 0.0).abs() < 1.0, "{at}");
+        client.on_timeout(at);
+        exchange(&mut client, &mut server, at_client, at_server, at);
+        server.on_timeout(at + ACK_DELAY);
+        exchange(
+            &mut client,
+            &mut server,
+            at_client,
+            at_server,
+            at + ACK_DELAY,
+        );
+        assert!(client.by_cid.values().all(|c| c.unanswered_since.is_none()));
+        assert!(client.next_timeout().unwrap() > at + 1.0);
+
+        // without the server around, the client gives up
+        let at = client.next_timeout().unwrap();
+        client.on_timeout(at);
+        client.outbox.clear();
+        client.on_timeout(at + UNANSWERED_TIMEOUT);
+        assert!(client.by_cid.is_empty());
+    }
+
+    #[test]
+    fn servers_follow_clients_to_new_addresses() {
+        rosenpass_sodium::init().unwrap();
+        let (at_client, at_server): (SocketAddr, SocketAddr) = (
+            "127.0.0.1:5001".parse().unwrap(),
+            "127.0.0.1:443".parse().unwrap(),
+        );
+        let mut client = Connections::new(at_client, false);
+        let mut server = Connections::new(at_server, true);
+        let target = address_of("localhost:443").unwrap();
+        assert!(target_of(&target).unwrap().name.as_deref() == Some("localhost"));
+        client.send(b"hello", target, 0.0).unwrap();
+        exchange(&mut client, &mut server, at_client, at_server, 0.0);
+        let (_, from) = server.received.pop_front().unwrap();
+
+        // the NAT gave the client another port
+        let moved: SocketAddr = "127.0.0.1:6001".parse().unwrap();
+        client.send(b"moved", target, 1.0).unwrap();
+        let (datagram, _) = client.outbox.pop().unwrap();
+        server.receive(&datagram, moved, 1.0);
+        assert_eq!(server.received.pop_front().unwrap().0, b"moved");
+        // answers keep going to the old address until the new one answered
+        server.send(b"still", from, 1.0).unwrap();
+        let to: Vec<SocketAddr> = server.outbox.iter().map(|(_, to)| *to).collect();
+        assert!(to.contains(&moved) && to.contains(&at_client), "{to:?}");
+        let challenge = server
+            .outbox
+            .iter()
+            .position(|(_, to)| *to == moved)
+            .unwrap();
+        let (datagram, _) = server.outbox.remove(challenge);
+        client.receive(&datagram, at_server, 1.0);
+        let (response, _) = client.outbox.pop().unwrap();
+        assert!(response.len() >= MIN_INITIAL_SIZE);
+        server.receive(&response, moved, 1.0);
+        server.outbox.clear();
+        server.send(b"there", from, 1.0).unwrap();
+        assert!(server.outbox.iter().all(|(_, to)| *to == moved));
+    }
+
+    #[test]
+    fn varints_and_packet_numbers_decode() {
+        for v in [0, 37, 15293, 494878333, 151288809941952652] {
+            let mut out = Vec::new();
+            put_varint(&mut out, v);
+            assert_eq!(Reader::new(&out).varint().unwrap(), v);
+        }
+        // RFC 9000, appendix A.3
+        assert_eq!(decode_pn(Some(0xa82f30ea), 0x9b32, 2), 0xa82f9b32);
+        assert_eq!(decode_pn(None, 0, 4), 0);
+    }
+}
@@ -0,0 +1,432 @@
+//! The packet protection of QUIC version 1 (RFC 9001, section 5), see
+//! [crate::quic]
+//!
+//! Initial packets are protected with AES-128-GCM under keys anyone can
+//! derive from the connection id on the wire, so they hide nothing and the
+//! table based AES below, neither fast nor free of timing side channels, is
+//! good enough for them. All later packets are protected with
+//! ChaCha20-Poly1305 from libsodium.
+
+use anyhow::{ensure, Context, Result};
+use rosenpass_sodium::aead::chacha20poly1305_ietf as chacha;
+
+use crate::sodium::{chacha20_xor, hmac_sha256, KEY_SIZE};
+
+/// Length of the AEAD tags of both ciphers
+pub const TAG_LEN: usize = 16;
+/// Length of the header protection sample
+pub const SAMPLE_LEN: usize = 16;
+
+/// The salt of the Initial secrets of QUIC version 1
+const INITIAL_SALT: [u8; 20] = [
+    0x38, 0x76, 0x2c, 0xf7, 0xf5, 0x59, 0x34, 0xb3, 0x4d, 0x17, 0x9a, 0xe6, 0xa4, 0xc8, 0x0c, 0xad,
+    0xcc, 0xbb, 0x7f, 0x0a,
+];
+
+/// HKDF-Extract with SHA-256
+pub fn extract(salt: &[u8], ikm: &[u8]) -> Result<[u8; KEY_SIZE]> {
+    hmac_sha256(salt, ikm)
+}
+
+/// HKDF-Expand-Label of TLS 1.3 (RFC 8446, section 7.1)
+pub fn expand_label(secret: &[u8], label: &str, context: &[u8], out: &mut [u8]) -> Result<()> {
+    let mut info = Vec::with_capacity(4 + 6 + label.len() + context.len());
+    info.extend_from_slice(&(out.len() as u16).to_be_bytes());
+    info.push((6 + label.len()) as u8);
+    info.extend_from_slice(b"tls13 ");
+    info.extend_from_slice(label.as_bytes());
+    info.push(context.len() as u8);
+    info.extend_from_slice(context);
+
+    let mut block = Vec::new();
+    for (i, chunk) in out.chunks_mut(KEY_SIZE).enumerate() {
+        block.extend_from_slice(&info);
+        block.push(i as u8 + 1);
+        let t = hmac_sha256(secret, &block)?;
+        chunk.copy_from_slice(&t[..chunk.len()]);
+        block.clear();
+        block.extend_from_slice(&t);
+    }
+    Ok(())
+}
+
+/// [expand_label] to a secret of [KEY_SIZE] bytes
+pub fn expand_secret(secret: &[u8], label: &str, context: &[u8]) -> Result<[u8; KEY_SIZE]> {
+    let mut out = [0u8; KEY_SIZE];
+    expand_label(secret, label, context, &mut out)?;
+    Ok(out)
+}
+
+const fn xtime(x: u8) -> u8 {
+    (x << 1) ^ if x & 0x80 != 0 { 0x1b } else { 0 }
+}
+
+const fn gmul(mut a: u8, mut b: u8) -> u8 {
+    let mut p = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            p ^= a;
+        }
+        a = xtime(a);
+        b >>= 1;
+    }
+    p
+}
+
+/// The AES S-box, from the inverses in GF(2^8) and the affine map of FIPS 197
+const SBOX: [u8; 256] = {
+    let mut sbox = [0u8; 256];
+    let mut x = 0;
+    while x < 256 {
+        // x^254 is the inverse of x, and 0 for 0
+        let mut inv = 1u8;
+        let mut i = 0;
+        while i < 254 {
+            inv = gmul(inv, x as u8);
+            i += 1;
+        }
+        sbox[x] = inv
+            ^ inv.rotate_left(1)
+            ^ inv.rotate_left(2)
+            ^ inv.rotate_left(3)
+            ^ inv.rotate_left(4)
+            ^ 0x63;
+        x += 1;
+    }
+    sbox
+};
+
+/// The AES-128 block cipher, encryption only
+pub struct Aes128 {
+    round_keys: [[u8; 16]; 11],
+}
+
+impl Aes128 {
+    pub fn new(key: &[u8; 16]) -> Self {
+        let mut words = [[0u8; 4]; 44];
+        for (i, word) in words.iter_mut().take(4).enumerate() {
+            word.copy_from_slice(&key[4 * i..4 * i + 4]);
+        }
+        let mut rcon = 1;
+        for i in 4..44 {
+            let mut t = words[i - 1];
+            if i % 4 == 0 {
+                t = [
+                    SBOX[t[1] as usize] ^ rcon,
+                    SBOX[t[2] as usize],
+                    SBOX[t[3] as usize],
+                    SBOX[t[0] as usize],
+                ];
+                rcon = xtime(rcon);
+            }
+            for j in 0..4 {
+                words[i][j] = words[i - 4][j] ^ t[j];
+            }
+        }
+        let mut round_keys = [[0u8; 16]; 11];
+        for (r, rk) in round_keys.iter_mut().enumerate() {
+            for c in 0..4 {
+                rk[4 * c..4 * c + 4].copy_from_slice(&words[4 * r + c]);
+            }
+        }
+        Self { round_keys }
+    }
+
+    pub fn encrypt(&self, block: &mut [u8; 16]) {
+        let add = |block: &mut [u8; 16], rk: &[u8; 16]| {
+            block.iter_mut().zip(rk).for_each(|(b, k)| *b ^= k);
+        };
+        add(block, &self.round_keys[0]);
+        for round in 1..11 {
+            // SubBytes and ShiftRows; the state is column major
+            let s = *block;
+            for c in 0..4 {
+                for r in 0..4 {
+                    block[r + 4 * c] = SBOX[s[r + 4 * ((c + r) % 4)] as usize];
+                }
+            }
+            if round < 10 {
+                for col in block.chunks_mut(4) {
+                    let [a0, a1, a2, a3] = [col[0], col[1], col[2], col[3]];
+                    col[0] = xtime(a0) ^ gmul(a1, 3) ^ a2 ^ a3;
+                    col[1] = a0 ^ xtime(a1) ^ gmul(a2, 3) ^ a3;
+                    col[2] = a0 ^ a1 ^ xtime(a2) ^ gmul(a3, 3);
+                    col[3] = gmul(a0, 3) ^ a1 ^ a2 ^ xtime(a3);
+                }
+            }
+            add(block, &self.round_keys[round]);
+        }
+    }
+}
+
+/// AES-128-GCM with 12 byte nonces and 16 byte tags
+pub struct Aes128Gcm {
+    aes: Aes128,
+    h: u128,
+}
+
+impl Aes128Gcm {
+    pub fn new(key: &[u8; 16]) -> Self {
+        let aes = Aes128::new(key);
+        let mut h = [0u8; 16];
+        aes.encrypt(&mut h);
+        Self {
+            aes,
+            h: u128::from_be_bytes(h),
+        }
+    }
+
+    /// Multiplication in GF(2^128) with the bit order of GCM
+    fn mul_h(&self, x: u128) -> u128 {
+        let (mut z, mut v) = (0u128, self.h);
+        for i in 0..128 {
+            if (x >> (127 - i)) & 1 == 1 {
+                z ^= v;
+            }
+            v = (v >> 1) ^ if v & 1 == 1 { 0xe1 << 120 } else { 0 };
+        }
+        z
+    }
+
+    fn ghash(&self, ad: &[u8], ciphertext: &[u8]) -> u128 {
+        let mut x = 0u128;
+        for data in [ad, ciphertext] {
+            for chunk in data.chunks(16) {
+                let mut block = [0u8; 16];
+                block[..chunk.len()].copy_from_slice(chunk);
+                x = self.mul_h(x ^ u128::from_be_bytes(block));
+            }
+        }
+        let lengths = ((ad.len() as u128 * 8) << 64) | (ciphertext.len() as u128 * 8);
+        self.mul_h(x ^ lengths)
+    }
+
+    /// XOR `data` with the counter mode key stream starting after `j0`
+    fn ctr(&self, j0: &[u8; 16], data: &mut [u8]) {
+        let mut counter = u32::from_be_bytes(j0[12..].try_into().unwrap());
+        for chunk in data.chunks_mut(16) {
+            counter = counter.wrapping_add(1);
+            let mut block = *j0;
+            block[12..].copy_from_slice(&counter.to_be_bytes());
+            self.aes.encrypt(&mut block);
+            chunk.iter_mut().zip(block).for_each(|(d, k)| *d ^= k);
+        }
+    }
+
+    fn tag(&self, j0: &[u8; 16], ad: &[u8], ciphertext: &[u8]) -> [u8; TAG_LEN] {
+        let mut mask = *j0;
+        self.aes.encrypt(&mut mask);
+        (self.ghash(ad, ciphertext) ^ u128::from_be_bytes(mask)).to_be_bytes()
+    }
+
+    fn j0(nonce: &[u8; 12]) -> [u8; 16] {
+        let mut j0 = [0u8; 16];
+        j0[..12].copy_from_slice(nonce);
+        j0[15] = 1;
+        j0
+    }
+
+    /// Encrypt `plaintext`, returning the ciphertext followed by the tag
+    pub fn seal(&self, nonce: &[u8; 12], ad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        let j0 = Self::j0(nonce);
+        let mut out = plaintext.to_vec();
+        self.ctr(&j0, &mut out);
+        let tag = self.tag(&j0, ad, &out);
+        out.extend_from_slice(&tag);
+        out
+    }
+
+    /// Check the tag at the end of `sealed` and decrypt the rest
+    pub fn open(&self, nonce: &[u8; 12], ad: &[u8], sealed: &[u8]) -> Result<Vec<u8>> {
+        ensure!(sealed.len() >= TAG_LEN, "no room for the tag");
+        let (ciphertext, tag) = sealed.split_at(sealed.len() - TAG_LEN);
+        let j0 = Self::j0(nonce);
+        ensure!(
+            rosenpass_constant_time::memeq(&self.tag(&j0, ad, ciphertext), tag),
+            "the packet does not decrypt"
+        );
+        let mut out = ciphertext.to_vec();
+        self.ctr(&j0, &mut out);
+        Ok(out)
+    }
+}
+
+enum Cipher {
+    /// Boxed as the round keys take up most of the space
+    Aes {
+        aead: Box<Aes128Gcm>,
+        hp: Box<Aes128>,
+    },
+    ChaCha {
+        key: [u8; KEY_SIZE],
+        hp: [u8; KEY_SIZE],
+    },
+}
+
+/// The keys protecting the packets sent in one direction at one encryption
+/// level
+pub struct Keys {
+    cipher: Cipher,
+    iv: [u8; 12],
+}
+
+impl std::fmt::Debug for Keys {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Keys { .. }")
+    }
+}
+
+impl Keys {
+    /// The Initial keys of the client, or with `server` those of the
+    /// server, of connections whose first client Initial went to `dcid`
+    pub fn initial(dcid: &[u8], server: bool) -> Result<Self> {
+        let initial = extract(&INITIAL_SALT, dcid)?;
+        let label = if server { "server in" } else { "client in" };
+        let secret = expand_secret(&initial, label, &[])?;
+        let mut key = [0u8; 16];
+        let mut hp = [0u8; 16];
+        let mut iv = [0u8; 12];
+        expand_label(&secret, "quic key", &[], &mut key)?;
+        expand_label(&secret, "quic iv", &[], &mut iv)?;
+        expand_label(&secret, "quic hp", &[], &mut hp)?;
+        Ok(Self {
+            cipher: Cipher::Aes {
+                aead: Box::new(Aes128Gcm::new(&key)),
+                hp: Box::new(Aes128::new(&hp)),
+            },
+            iv,
+        })
+    }
+
+    /// The ChaCha20-Poly1305 keys of a TLS traffic secret
+    pub fn chacha(secret: &[u8]) -> Result<Self> {
+        let mut iv = [0u8; 12];
+        expand_label(secret, "quic iv", &[], &mut iv)?;
+        Ok(Self {
+            cipher: Cipher::ChaCha {
+                key: expand_secret(secret, "quic key", &[])?,
+                hp: expand_secret(secret, "quic hp", &[])?,
+            },
+            iv,
+        })
+    }
+
+    fn nonce(&self, pn: u64) -> [u8; 12] {
+        let mut nonce = self.iv;
+        nonce[4..]
+            .iter_mut()
+            .zip(pn.to_be_bytes())
+            .for_each(|(n, p)| *n ^= p);
+        nonce
+    }
+
+    /// Encrypt the payload of packet `pn`, authenticating its `header`
+    pub fn seal(&self, pn: u64, header: &[u8], payload: &[u8]) -> Result<Vec<u8>> {
+        let nonce = self.nonce(pn);
+        match &self.cipher {
+            Cipher::Aes { aead, .. } => Ok(aead.seal(&nonce, header, payload)),
+            Cipher::ChaCha { key, .. } => {
+                let mut out = vec![0u8; payload.len() + TAG_LEN];
+                chacha::encrypt(&mut out, key, &nonce, header, payload)?;
+                Ok(out)
+            }
+        }
+    }
+
+    /// Decrypt the payload of packet `pn` with `header`
+    pub fn open(&self, pn: u64, header: &[u8], sealed: &[u8]) -> Result<Vec<u8>> {
+        let nonce = self.nonce(pn);
+        match &self.cipher {
+            Cipher::Aes { aead, .. } => aead.open(&nonce, header, sealed),
+            Cipher::ChaCha { key, .. } => {
+                ensure!(sealed.len() >= TAG_LEN, "no room for the tag");
+                let mut out = vec![0u8; sealed.len() - TAG_LEN];
+                chacha::decrypt(&mut out, key, &nonce, header, sealed)
+                    .context("the packet does not decrypt")?;
+                Ok(out)
+            }
+        }
+    }
+
+    /// The header protection mask for the ciphertext `sample`
+    pub fn mask(&self, sample: &[u8; SAMPLE_LEN]) -> Result<[u8; 5]> {
+        let mut mask = [0u8; 5];
+        match &self.cipher {
+            Cipher::Aes { hp, .. } => {
+                let mut block = *sample;
+                hp.encrypt(&mut block);
+                mask.copy_from_slice(&block[..5]);
+            }
+            Cipher::ChaCha { hp, .. } => {
+                let counter = u32::from_le_bytes(sample[..4].try_into().unwrap());
+                chacha20_xor(&mut mask, hp, sample[4..].try_into().unwrap(), counter)?;
+            }
+        }
+        Ok(mask)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn aes_and_gcm_match_their_test_vectors() {
+        // FIPS 197, appendix C.1
+        let aes = Aes128::new(&hex("000102030405060708090a0b0c0d0e0f").try_into().unwrap());
+        let mut block = hex("00112233445566778899aabbccddeeff").try_into().unwrap();
+        aes.encrypt(&mut block);
+        assert_eq!(block.to_vec(), hex("69c4e0d86a7b0430d8cdb78070b4c55a"));
+
+        // test cases 1 and 2 of the GCM specification
+        let gcm = Aes128Gcm::new(&[0u8; 16]);
+        assert_eq!(
+            gcm.seal(&[0u8; 12], &[], &[]),
+            hex("58e2fccefa7e3061367f1d57a4e7455a")
+        );
+        let sealed = gcm.seal(&[0u8; 12], &[], &[0u8; 16]);
+        assert_eq!(
+            sealed,
+            hex("0388dace60b6a392f328c2b971b2fe78ab6e47d42cec13bdf53a67b21257bddf")
+        );
+        assert_eq!(gcm.open(&[0u8; 12], &[], &sealed).unwrap(), [0u8; 16]);
+        assert!(gcm.open(&[0u8; 12], b"ad", &sealed).is_err());
+    }
+
+    #[test]
+    fn initial_keys_match_rfc_9001() {
+        // appendix A.1 and A.2
+        let dcid = hex("8394c8f03e515708");
+        let client = Keys::initial(&dcid, false).unwrap();
+        assert_eq!(client.iv.to_vec(), hex("fa044b2f42a3fd3b46fb255c"));
+        let sample = hex("d1b1c98dd7689fb8ec11d242b123dc9b").try_into().unwrap();
+        assert_eq!(client.mask(&sample).unwrap().to_vec(), hex("437b9aec36"));
+        let server = Keys::initial(&dcid, true).unwrap();
+        assert_eq!(server.iv.to_vec(), hex("0ac1493ca1905853b0bba03e"));
+
+        let header = hex("c300000001088394c8f03e5157080000449e00000002");
+        let sealed = client.seal(2, &header, b"CRYPTO").unwrap();
+        assert_eq!(client.open(2, &header, &sealed).unwrap(), b"CRYPTO");
+        assert!(server.open(2, &header, &sealed).is_err());
+    }
+
+    #[test]
+    fn chacha_keys_match_rfc_9001() {
+        // appendix A.5
+        let secret = hex("9ac312a7f877468ebe69422748ad00a15443f18203a07d6060f688f30f21632b");
+        let keys = Keys::chacha(&secret).unwrap();
+        assert_eq!(keys.iv.to_vec(), hex("e0459b3474bdd0e44a41c144"));
+        let sample = hex("5e5cd55c41f69080575d7999c25a5bfb").try_into().unwrap();
+        assert_eq!(keys.mask(&sample).unwrap().to_vec(), hex("aefefe7d03"));
+        let header = hex("4200bff4");
+        let sealed = keys.seal(654360564, &header, &[0x01]).unwrap();
+        assert_eq!(sealed, hex("655e5cd55c41f69080575d7999c25a5bfb"));
+    }
+}
@@ -0,0 +1,610 @@
+//! The TLS 1.3 handshake of [crate::quic] connections
+//!
+//! QUIC insists on TLS 1.3 to set up its keys, but rosenpass has no use for
+//! what TLS would authenticate: peers are authenticated by the rosenpass
+//! handshake running inside the connection. Both ends therefore prove
+//! knowledge of a fixed and public external pre-shared key and agree on the
+//! traffic secrets with X25519 (the psk_dhe_ke mode of RFC 8446, section
+//! 4.2.9), without any certificates. This keeps the connection confidential
+//! against passive observers only, which is all it is for.
+//!
+//! The one cipher suite is TLS_CHACHA20_POLY1305_SHA256 and the one
+//! application protocol `rosenpass`; session tickets, early data, hello
+//! retries and key updates are not supported.
+
+use anyhow::{bail, ensure, Context, Result};
+
+use crate::{
+    quic::{Reader, Space},
+    quic_crypto::{expand_secret, extract, Keys},
+    sodium::{hmac_sha256, sha256, x25519, x25519_keypair, KEY_SIZE, X25519_SIZE},
+};
+
+/// The application protocol negotiated with ALPN
+pub const ALPN: &[u8] = b"rosenpass";
+/// The identity of the pre-shared key
+const PSK_IDENTITY: &[u8] = b"rosenpass";
+/// The pre-shared key is the SHA-256 hash of this
+const PSK_LABEL: &[u8] = b"rosenpass QUIC version 1 pre-shared key";
+
+const TLS_CHACHA20_POLY1305_SHA256: u16 = 0x1303;
+const X25519_GROUP: u16 = 0x001d;
+const TLS13: u16 = 0x0304;
+const PSK_DHE_KE: u8 = 1;
+
+const CLIENT_HELLO: u8 = 1;
+const SERVER_HELLO: u8 = 2;
+const ENCRYPTED_EXTENSIONS: u8 = 8;
+const FINISHED: u8 = 20;
+
+const SERVER_NAME: u16 = 0;
+const SUPPORTED_GROUPS: u16 = 10;
+const APPLICATION_PROTOCOLS: u16 = 16;
+const PRE_SHARED_KEY: u16 = 41;
+const SUPPORTED_VERSIONS: u16 = 43;
+const PSK_KEY_EXCHANGE_MODES: u16 = 45;
+const KEY_SHARE: u16 = 51;
+const QUIC_TRANSPORT_PARAMETERS: u16 = 0x39;
+
+/// What the handshake asks of the connection
+#[derive(Debug)]
+pub enum Output {
+    /// Handshake data to send at an encryption level
+    Send(Space, Vec<u8>),
+    /// Keys for an encryption level, those to open packets with first
+    Keys(Space, Keys, Keys),
+    /// The handshake completed with the peer sending these transport
+    /// parameters
+    Done(Vec<u8>),
+}
+
+enum State {
+    ClientHelloSent { sk: [u8; X25519_SIZE] },
+    ServerHelloReceived { secrets: Secrets },
+    ExtensionsReceived { secrets: Secrets, params: Vec<u8> },
+    WaitingForClientHello,
+    ClientHelloReceived { secrets: Secrets, params: Vec<u8> },
+    Done,
+}
+
+/// The handshake traffic secrets
+struct Secrets {
+    handshake: [u8; KEY_SIZE],
+    client: [u8; KEY_SIZE],
+    server: [u8; KEY_SIZE],
+}
+
+/// One end of a handshake
+pub struct Tls {
+    state: State,
+    /// Our transport parameters
+    params: Vec<u8>,
+    /// The handshake messages so far
+    transcript: Vec<u8>,
+    /// Parts of the next message, by encryption level
+    input: [Vec<u8>; 3],
+}
+
+impl std::fmt::Debug for Tls {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Tls").field("done", &self.done()).finish()
+    }
+}
+
+fn psk() -> Result<[u8; KEY_SIZE]> {
+    sha256(PSK_LABEL)
+}
+
+fn hash(data: &[u8]) -> Result<[u8; KEY_SIZE]> {
+    sha256(data)
+}
+
+/// Derive-Secret of RFC 8446, section 7.1, over a transcript
+fn derive(secret: &[u8], label: &str, transcript: &[u8]) -> Result<[u8; KEY_SIZE]> {
+    expand_secret(secret, label, &hash(transcript)?)
+}
+
+fn early_secret() -> Result<[u8; KEY_SIZE]> {
+    extract(&[0u8; KEY_SIZE], &psk()?)
+}
+
+/// The verify data of a Finished message, or with the binder key that of a
+/// PSK binder
+fn verify_data(secret: &[u8], transcript: &[u8]) -> Result<[u8; KEY_SIZE]> {
+    let key = expand_secret(secret, "finished", &[])?;
+    hmac_sha256(&key, &hash(transcript)?)
+}
+
+fn handshake_secrets(shared: &[u8], transcript: &[u8]) -> Result<Secrets> {
+    let derived = derive(&early_secret()?, "derived", &[])?;
+    let handshake = extract(&derived, shared)?;
+    Ok(Secrets {
+        client: derive(&handshake, "c hs traffic", transcript)?,
+        server: derive(&handshake, "s hs traffic", transcript)?,
+        handshake,
+    })
+}
+
+/// The application traffic secrets of the client and the server
+fn application_secrets(
+    secrets: &Secrets,
+    transcript: &[u8],
+) -> Result<([u8; KEY_SIZE], [u8; KEY_SIZE])> {
+    let derived = derive(&secrets.handshake, "derived", &[])?;
+    let master = extract(&derived, &[0u8; KEY_SIZE])?;
+    Ok((
+        derive(&master, "c ap traffic", transcript)?,
+        derive(&master, "s ap traffic", transcript)?,
+    ))
+}
+
+/// Append what `f` writes, preceded by its length in `n` bytes
+fn with_len(out: &mut Vec<u8>, n: usize, f: impl FnOnce(&mut Vec<u8>)) {
+    let at = out.len();
+    out.resize(at + n, 0);
+    f(out);
+    let len = (out.len() - at - n) as u32;
+    out[at..at + n].copy_from_slice(&len.to_be_bytes()[4 - n..]);
+}
+
+fn message(typ: u8, f: impl FnOnce(&mut Vec<u8>)) -> Vec<u8> {
+    let mut out = vec![typ];
+    with_len(&mut out, 3, f);
+    out
+}
+
+fn extension(out: &mut Vec<u8>, typ: u16, f: impl FnOnce(&mut Vec<u8>)) {
+    out.extend_from_slice(&typ.to_be_bytes());
+    with_len(out, 2, f);
+}
+
+fn extensions<'a>(r: &mut Reader<'a>) -> Result<Vec<(u16, &'a [u8])>> {
+    let mut r = Reader::new(r.vec16()?);
+    let mut exts = Vec::new();
+    while !r.is_empty() {
+        let typ = r.u16()?;
+        ensure!(
+            exts.iter().all(|&(t, _)| t != typ),
+            "extension {typ} appears twice"
+        );
+        exts.push((typ, r.vec16()?));
+    }
+    Ok(exts)
+}
+
+fn find<'a>(exts: &[(u16, &'a [u8])], typ: u16) -> Option<Reader<'a>> {
+    exts.iter()
+        .find(|&&(t, _)| t == typ)
+        .map(|&(_, data)| Reader::new(data))
+}
+
+fn require<'a>(exts: &[(u16, &'a [u8])], typ: u16) -> Result<Reader<'a>> {
+    find(exts, typ).with_context(|| format!("the hello lacks extension {typ}"))
+}
+
+fn random() -> [u8; 32] {
+    let mut random = [0u8; 32];
+    rosenpass_sodium::helpers::randombytes_buf(&mut random);
+    random
+}
+
+/// Whether `alpn`, the data of an ALPN extension, lists rosenpass
+fn offers_rosenpass(mut alpn: Reader) -> Result<bool> {
+    let mut list = Reader::new(alpn.vec16()?);
+    while !list.is_empty() {
+        if list.vec8()? == ALPN {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn write_alpn(out: &mut Vec<u8>) {
+    extension(out, APPLICATION_PROTOCOLS, |out| {
+        with_len(out, 2, |out| {
+            with_len(out, 1, |out| out.extend_from_slice(ALPN));
+        })
+    });
+}
+
+impl Tls {
+    /// Start a handshake as the client, with the ClientHello to send in
+    /// an Initial packet; `server_name` goes into the server name
+    /// extension, as firewalls may look for it
+    pub fn client(params: Vec<u8>, server_name: Option<&str>) -> Result<(Self, Vec<u8>)> {
+        let (sk, pk) = x25519_keypair()?;
+        let mut hello = message(CLIENT_HELLO, |out| {
+            out.extend_from_slice(&0x0303u16.to_be_bytes());
+            out.extend_from_slice(&random());
+            // QUIC has no use for the legacy session id
+            out.push(0);
+            out.extend_from_slice(&[0, 2]);
+            out.extend_from_slice(&TLS_CHACHA20_POLY1305_SHA256.to_be_bytes());
+            out.extend_from_slice(&[1, 0]);
+            with_len(out, 2, |out| {
+                if let Some(name) = server_name {
+                    extension(out, SERVER_NAME, |out| {
+                        with_len(out, 2, |out| {
+                            out.push(0);
+                            with_len(out, 2, |out| out.extend_from_slice(name.as_bytes()));
+                        })
+                    });
+                }
+                extension(out, SUPPORTED_GROUPS, |out| {
+                    with_len(out, 2, |out| {
+                        out.extend_from_slice(&X25519_GROUP.to_be_bytes())
+                    })
+                });
+                write_alpn(out);
+                extension(out, SUPPORTED_VERSIONS, |out| {
+                    with_len(out, 1, |out| out.extend_from_slice(&TLS13.to_be_bytes()))
+                });
+                extension(out, PSK_KEY_EXCHANGE_MODES, |out| {
+                    with_len(out, 1, |out| out.push(PSK_DHE_KE))
+                });
+                extension(out, KEY_SHARE, |out| {
+                    with_len(out, 2, |out| {
+                        out.extend_from_slice(&X25519_GROUP.to_be_bytes());
+                        with_len(out, 2, |out| out.extend_from_slice(&pk));
+                    })
+                });
+                extension(out, QUIC_TRANSPORT_PARAMETERS, |out| {
+                    out.extend_from_slice(&params)
+                });
+                // the pre-shared key comes last, its binder is filled in below
+                extension(out, PRE_SHARED_KEY, |out| {
+                    with_len(out, 2, |out| {
+                        with_len(out, 2, |out| out.extend_from_slice(PSK_IDENTITY));
+                        // the ticket age of external keys is zero
+                        out.extend_from_slice(&[0; 4]);
+                    });
+                    with_len(out, 2, |out| {
+                        with_len(out, 1, |out| out.extend_from_slice(&[0; KEY_SIZE]))
+                    });
+                });
+            });
+        });
+        let binders = hello.len() - (2 + 1 + KEY_SIZE);
+        let binder_key = derive(&early_secret()?, "ext binder", &[])?;
+        let binder = verify_data(&binder_key, &hello[..binders])?;
+        hello[binders + 3..].copy_from_slice(&binder);
+
+        let tls = Self {
+            state: State::ClientHelloSent { sk },
+            params,
+            transcript: hello.clone(),
+            input: Default::default(),
+        };
+        Ok((tls, hello))
+    }
+
+    /// Wait for a ClientHello as the server
+    pub fn server(params: Vec<u8>) -> Self {
+        Self {
+            state: State::WaitingForClientHello,
+            params,
+            transcript: Vec::new(),
+            input: Default::default(),
+        }
+    }
+
+    /// Whether the handshake completed
+    pub fn done(&self) -> bool {
+        matches!(self.state, State::Done)
+    }
+
+    /// Take up handshake data which arrived at the encryption level `space`
+    pub fn read(&mut self, space: Space, data: &[u8]) -> Result<Vec<Output>> {
+        let mut outputs = Vec::new();
+        let input = &mut self.input[space as usize];
+        input.extend_from_slice(data);
+        ensure!(input.len() <= 1 << 14, "overlong handshake message");
+        loop {
+            let input = &mut self.input[space as usize];
+            if input.len() < 4 {
+                break;
+            }
+            let len = u32::from_be_bytes([0, input[1], input[2], input[3]]) as usize;
+            if input.len() < 4 + len {
+                break;
+            }
+            let msg: Vec<u8> = input.drain(..4 + len).collect();
+            self.handle(space, &msg, &mut outputs)?;
+        }
+        Ok(outputs)
+    }
+
+    fn handle(&mut self, space: Space, msg: &[u8], outputs: &mut Vec<Output>) -> Result<()> {
+        let state = std::mem::replace(&mut self.state, State::Done);
+        self.state = match (state, msg[0], space) {
+            (State::WaitingForClientHello, CLIENT_HELLO, Space::Initial) => {
+                self.client_hello(msg, outputs)?
+            }
+            (State::ClientHelloSent { sk }, SERVER_HELLO, Space::Initial) => {
+                self.server_hello(&sk, msg, outputs)?
+            }
+            (State::ServerHelloReceived { secrets }, ENCRYPTED_EXTENSIONS, Space::Handshake) => {
+                self.transcript.extend_from_slice(msg);
+                let mut r = Reader::new(&msg[4..]);
+                let exts = extensions(&mut r)?;
+                ensure!(
+                    offers_rosenpass(require(&exts, APPLICATION_PROTOCOLS)?)?,
+                    "the server does not speak rosenpass"
+                );
+                let params = require(&exts, QUIC_TRANSPORT_PARAMETERS)?.rest().to_vec();
+                State::ExtensionsReceived { secrets, params }
+            }
+            (State::ExtensionsReceived { secrets, params }, FINISHED, Space::Handshake) => {
+                let expected = verify_data(&secrets.server, &self.transcript)?;
+                ensure!(
+                    rosenpass_constant_time::memeq(&msg[4..], &expected),
+                    "the Finished message of the server does not verify"
+                );
+                self.transcript.extend_from_slice(msg);
+                let (client, server) = application_secrets(&secrets, &self.transcript)?;
+                let verify = verify_data(&secrets.client, &self.transcript)?;
+                let finished = message(FINISHED, |out| out.extend_from_slice(&verify));
+                self.transcript.extend_from_slice(&finished);
+                outputs.push(Output::Send(Space::Handshake, finished));
+                outputs.push(Output::Keys(
+                    Space::Data,
+                    Keys::chacha(&server)?,
+                    Keys::chacha(&client)?,
+                ));
+                outputs.push(Output::Done(params));
+                State::Done
+            }
+            (State::ClientHelloReceived { secrets, params }, FINISHED, Space::Handshake) => {
+                let expected = verify_data(&secrets.client, &self.transcript)?;
+                ensure!(
+                    rosenpass_constant_time::memeq(&msg[4..], &expected),
+                    "the Finished message of the client does not verify"
+                );
+                self.transcript.extend_from_slice(msg);
+                outputs.push(Output::Done(params));
+                State::Done
+            }
+            (_, typ, space) => bail!("unexpected handshake message {typ} in {space:?} packets"),
+        };
+        Ok(())
+    }
+
+    /// Take up the ClientHello as the server, answering it right away
+    fn client_hello(&mut self, msg: &[u8], outputs: &mut Vec<Output>) -> Result<State> {
+        let mut r = Reader::new(&msg[4..]);
+        r.bytes(2 + 32)?;
+        let session_id = r.vec8()?.to_vec();
+        let mut suites = Reader::new(r.vec16()?);
+        let mut chacha = false;
+        while !suites.is_empty() {
+            chacha |= suites.u16()? == TLS_CHACHA20_POLY1305_SHA256;
+        }
+        ensure!(
+            chacha,
+            "the client does not offer TLS_CHACHA20_POLY1305_SHA256"
+        );
+        r.vec8()?;
+        let exts = extensions(&mut r)?;
+        ensure!(r.is_empty(), "trailing data after the ClientHello");
+
+        let mut versions = Reader::new(require(&exts, SUPPORTED_VERSIONS)?.vec8()?);
+        let mut tls13 = false;
+        while !versions.is_empty() {
+            tls13 |= versions.u16()? == TLS13;
+        }
+        ensure!(tls13, "the client does not offer TLS 1.3");
+        ensure!(
+            require(&exts, PSK_KEY_EXCHANGE_MODES)?
+                .vec8()?
+                .contains(&PSK_DHE_KE),
+            "the client does not offer psk_dhe_ke"
+        );
+        ensure!(
+            offers_rosenpass(require(&exts, APPLICATION_PROTOCOLS)?)?,
+            "the client does not speak rosenpass"
+        );
+        let params = require(&exts, QUIC_TRANSPORT_PARAMETERS)?.rest().to_vec();
+
+        let mut shares = Reader::new(require(&exts, KEY_SHARE)?.vec16()?);
+        let mut client_pk = None;
+        while !shares.is_empty() {
+            let (group, pk) = (shares.u16()?, shares.vec16()?);
+            if group == X25519_GROUP {
+                client_pk = Some(pk);
+            }
+        }
+        let client_pk = client_pk.context("the client offers no X25519 key share")?;
+
+        // the pre-shared key, which must be the last extension
+        ensure!(
+            exts.last().map(|&(t, _)| t) == Some(PRE_SHARED_KEY),
+            "the client offers no pre-shared key last"
+        );
+        let mut psk = require(&exts, PRE_SHARED_KEY)?;
+        let mut identities = Reader::new(psk.vec16()?);
+        let mut chosen = None;
+        let mut i = 0;
+        while !identities.is_empty() {
+            if identities.vec16()? == PSK_IDENTITY && chosen.is_none() {
+                chosen = Some(i);
+            }
+            identities.u32()?;
+            i += 1;
+        }
+        let chosen = chosen.context("the client does not know the pre-shared key")?;
+        let binders_len = psk.rest().len();
+        let mut binders = Reader::new(&msg[msg.len() - binders_len..]);
+        let mut binders = Reader::new(binders.vec16()?);
+        for _ in 0..chosen {
+            binders.vec8()?;
+        }
+        let binder = binders.vec8()?;
+        let binder_key = derive(&early_secret()?, "ext binder", &[])?;
+        let expected = verify_data(&binder_key, &msg[..msg.len() - binders_len])?;
+        ensure!(
+            rosenpass_constant_time::memeq(binder, &expected),
+            "the binder of the pre-shared key does not verify"
+        );
+
+        let (sk, pk) = x25519_keypair()?;
+        let shared = x25519(&sk, client_pk)?;
+        let server_hello = message(SERVER_HELLO, |out| {
+            out.extend_from_slice(&0x0303u16.to_be_bytes());
+            out.extend_from_slice(&random());
+            with_len(out, 1, |out| out.extend_from_slice(&session_id));
+            out.extend_from_slice(&TLS_CHACHA20_POLY1305_SHA256.to_be_bytes());
+            out.push(0);
+            with_len(out, 2, |out| {
+                extension(out, SUPPORTED_VERSIONS, |out| {
+                    out.extend_from_slice(&TLS13.to_be_bytes())
+                });
+                extension(out, KEY_SHARE, |out| {
+                    out.extend_from_slice(&X25519_GROUP.to_be_bytes());
+                    with_len(out, 2, |out| out.extend_from_slice(&pk));
+                });
+                extension(out, PRE_SHARED_KEY, |out| {
+                    out.extend_from_slice(&(chosen as u16).to_be_bytes())
+                });
+            });
+        });
+        self.transcript.extend_from_slice(msg);
+        self.transcript.extend_from_slice(&server_hello);
+        let secrets = handshake_secrets(&shared, &self.transcript)?;
+
+        let mut flight = message(ENCRYPTED_EXTENSIONS, |out| {
+            with_len(out, 2, |out| {
+                write_alpn(out);
+                extension(out, QUIC_TRANSPORT_PARAMETERS, |out| {
+                    out.extend_from_slice(&self.params)
+                });
+            })
+        });
+        self.transcript.extend_from_slice(&flight);
+        let verify = verify_data(&secrets.server, &self.transcript)?;
+        let finished = message(FINISHED, |out| out.extend_from_slice(&verify));
+        self.transcript.extend_from_slice(&finished);
+        flight.extend_from_slice(&finished);
+        let (client, server) = application_secrets(&secrets, &self.transcript)?;
+
+        outputs.push(Output::Send(Space::Initial, server_hello));
+        outputs.push(Output::Keys(
+            Space::Handshake,
+            Keys::chacha(&secrets.client)?,
+            Keys::chacha(&secrets.server)?,
+        ));
+        outputs.push(Output::Send(Space::Handshake, flight));
+        outputs.push(Output::Keys(
+            Space::Data,
+            Keys::chacha(&client)?,
+            Keys::chacha(&server)?,
+        ));
+        Ok(State::ClientHelloReceived { secrets, params })
+    }
+
+    /// Take up the ServerHello as the client
+    fn server_hello(
+        &mut self,
+        sk: &[u8; X25519_SIZE],
+        msg: &[u8],
+        outputs: &mut Vec<Output>,
+    ) -> Result<State> {
+        let mut r = Reader::new(&msg[4..]);
+        r.bytes(2)?;
+        ensure!(
+            r.bytes(32)? != hash(b"HelloRetryRequest")?,
+            "the server asks to retry the hello, which is not supported"
+        );
+        r.vec8()?;
+        ensure!(
+            r.u16()? == TLS_CHACHA20_POLY1305_SHA256,
+            "the server chose another cipher suite"
+        );
+        r.u8()?;
+        let exts = extensions(&mut r)?;
+        ensure!(
+            require(&exts, SUPPORTED_VERSIONS)?.u16()? == TLS13,
+            "the server chose another version than TLS 1.3"
+        );
+        ensure!(
+            require(&exts, PRE_SHARED_KEY)?.u16()? == 0,
+            "the server chose another pre-shared key"
+        );
+        let mut share = require(&exts, KEY_SHARE)?;
+        ensure!(
+            share.u16()? == X25519_GROUP,
+            "the server chose another group than X25519"
+        );
+        let shared = x25519(sk, share.vec16()?)?;
+
+        self.transcript.extend_from_slice(msg);
+        let secrets = handshake_secrets(&shared, &self.transcript)?;
+        outputs.push(Output::Keys(
+            Space::Handshake,
+            Keys::chacha(&secrets.server)?,
+            Keys::chacha(&secrets.client)?,
+        ));
+        Ok(State::ServerHelloReceived { secrets })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Hand the data to send between `client` and `server` until neither
+    /// sends any more
+    fn run(client: &mut Tls, server: &mut Tls, hello: Vec<u8>) -> (Vec<u8>, Vec<u8>) {
+        let mut to_server = vec![(Space::Initial, hello)];
+        let mut done = (None, None);
+        while !to_server.is_empty() {
+            let mut to_client = Vec::new();
+            for (space, data) in to_server.drain(..) {
+                for out in server.read(space, &data).unwrap() {
+                    match out {
+                        Output::Send(space, data) => to_client.push((space, data)),
+                        Output::Done(params) => done.1 = Some(params),
+                        Output::Keys(..) => {}
+                    }
+                }
+            }
+            for (space, data) in to_client {
+                // in pieces, as they may arrive in several packets
+                for piece in data.chunks(50) {
+                    for out in client.read(space, piece).unwrap() {
+                        match out {
+                            Output::Send(space, data) => to_server.push((space, data)),
+                            Output::Done(params) => done.0 = Some(params),
+                            Output::Keys(..) => {}
+                        }
+                    }
+                }
+            }
+        }
+        (done.0.unwrap(), done.1.unwrap())
+    }
+
+    #[test]
+    fn handshakes_complete() {
+        rosenpass_sodium::init().unwrap();
+        let (mut client, hello) = Tls::client(b"client".to_vec(), Some("example.com")).unwrap();
+        let mut server = Tls::server(b"server".to_vec());
+        let (at_client, at_server) = run(&mut client, &mut server, hello);
+        assert!(client.done() && server.done());
+        assert_eq!(
+            (&at_client[..], &at_server[..]),
+            (&b"server"[..], &b"client"[..])
+        );
+    }
+
+    #[test]
+    fn broken_binders_are_refused() {
+        rosenpass_sodium::init().unwrap();
+        let (_, mut hello) = Tls::client(Vec::new(), None).unwrap();
+        let last = hello.len() - 1;
+        hello[last] ^= 1;
+        let err = Tls::server(Vec::new())
+            .read(Space::Initial, &hello)
+            .unwrap_err();
+        assert!(err.to_string().contains("binder"), "{err}");
+    }
+}
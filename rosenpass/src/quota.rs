@@ -0,0 +1,364 @@
+//! Keeping tenants of a gateway from starving each other
+//!
+//! A gateway serving the peers of many customers does all of their
+//! handshakes on the same cores. Quotas bound what the peers of a tag (see
+//! [crate::config::RosenpassPeer::tags]), and all peers together, may take
+//! up:
+//!
+//! ```toml
+//! [quota]
+//! handshakes = 1024     # handshakes we initiated in progress at once
+//! kem_per_second = 2000 # KEM operations per second
+//!
+//! [quota.tags.customer-a]
+//! handshakes = 64
+//! memory = 1048576      # bytes of handshake state held at once
+//! kem_per_second = 100
+//! ```
+//!
+//! A handshake we initiate takes [INITIATION] of every quota of the peer, and
+//! holds the handshakes and memory until it completes or expires. Answering
+//! an InitHello takes [RESPONSE]; the sender is only known once the message
+//! is decapsulated, so it is counted against the tags of the peer last seen
+//! at its address, or against the global quota alone. KEM operations are
+//! refilled continuously, up to one second worth of them.
+//!
+//! Initiations over quota are retried every [RETRY_INTERVAL] seconds,
+//! InitHello messages over quota are dropped. Every rejection is counted per
+//! quota and [Resource], in the statistics on the control socket and as
+//! `rosenpass_quota_rejections_total` for Prometheus.
+
+use anyhow::{ensure, Result};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fmt};
+
+use crate::{
+    app_server::AppPeerPtr,
+    protocol::{InitiatorHandshake, Timing},
+};
+
+/// Memory held for a handshake in progress, in bytes
+pub const HANDSHAKE_MEMORY: usize = std::mem::size_of::<InitiatorHandshake>();
+
+/// Seconds after which an initiation over quota is tried again
+pub const RETRY_INTERVAL: Timing = 1.0;
+
+/// What a handshake we initiate takes: the ephemeral key pair and the
+/// encapsulation in the InitHello, and the two decapsulations of the RespHello
+pub const INITIATION: Cost = Cost {
+    handshakes: 1,
+    memory: HANDSHAKE_MEMORY,
+    kem: 4,
+};
+
+/// What answering an InitHello takes: one decapsulation and two
+/// encapsulations; the responder keeps no state
+pub const RESPONSE: Cost = Cost {
+    handshakes: 0,
+    memory: 0,
+    kem: 3,
+};
+
+/// Limits of one quota; no limit where unset
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Limits {
+    /// Handshakes we initiated in progress at once
+    #[serde(default)]
+    pub handshakes: Option<usize>,
+    /// Bytes of handshake state held at once
+    #[serde(default)]
+    pub memory: Option<usize>,
+    /// KEM operations per second
+    #[serde(default)]
+    pub kem_per_second: Option<u32>,
+}
+
+impl Limits {
+    pub fn validate(&self, name: &str) -> Result<()> {
+        ensure!(
+            self.handshakes != Some(0),
+            "{name}.handshakes must be at least 1"
+        );
+        ensure!(
+            self.memory.is_none_or(|m| m >= HANDSHAKE_MEMORY),
+            "{name}.memory must be at least {HANDSHAKE_MEMORY}, the memory of one handshake"
+        );
+        ensure!(
+            self.kem_per_second.is_none_or(|k| k >= INITIATION.kem),
+            "{name}.kem_per_second must be at least {}, the operations of one handshake",
+            INITIATION.kem
+        );
+        Ok(())
+    }
+}
+
+/// The `[quota]` section of the config
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QuotaConfig {
+    /// Limits of all peers together
+    #[serde(flatten)]
+    pub global: Limits,
+    /// Limits of the peers of each tag
+    #[serde(default)]
+    pub tags: BTreeMap<String, Limits>,
+}
+
+impl QuotaConfig {
+    pub fn validate(&self) -> Result<()> {
+        self.global.validate("quota")?;
+        for (tag, limits) in self.tags.iter() {
+            limits.validate(&format!("quota.tags.{tag}"))?;
+        }
+        Ok(())
+    }
+}
+
+/// What a piece of work takes of a quota
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cost {
+    pub handshakes: usize,
+    pub memory: usize,
+    pub kem: u32,
+}
+
+/// The resource which ran out when work was rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Resource {
+    Handshakes,
+    Memory,
+    Kem,
+}
+
+impl fmt::Display for Resource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // reuse the serde names, so the control socket and metrics agree
+        let name = serde_json::to_value(self).map_err(|_| fmt::Error)?;
+        f.write_str(name.as_str().ok_or(fmt::Error)?)
+    }
+}
+
+/// Usage of one quota, as reported in [crate::stats::StatsReport]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct QuotaUsage {
+    /// The tag of the quota, `None` for the global one
+    pub tag: Option<String>,
+    pub handshakes: usize,
+    pub memory: usize,
+    pub rejections: BTreeMap<Resource, u64>,
+}
+
+#[derive(Debug, Default)]
+struct Quota {
+    limits: Limits,
+    usage: QuotaUsage,
+    /// KEM operations left, as of `refilled_at`
+    tokens: f64,
+    refilled_at: Timing,
+}
+
+impl Quota {
+    fn new(tag: Option<String>, limits: Limits) -> Self {
+        Self {
+            limits,
+            usage: QuotaUsage {
+                tag,
+                ..Default::default()
+            },
+            tokens: limits.kem_per_second.unwrap_or(0) as f64,
+            refilled_at: 0.0,
+        }
+    }
+
+    /// The resource `cost` would exceed at `now`, if any
+    fn exceeded(&mut self, cost: Cost, now: Timing) -> Option<Resource> {
+        let over = |limit: Option<usize>, used: usize, more: usize| {
+            more > 0 && limit.is_some_and(|l| used + more > l)
+        };
+        if over(
+            self.limits.handshakes,
+            self.usage.handshakes,
+            cost.handshakes,
+        ) {
+            return Some(Resource::Handshakes);
+        }
+        if over(self.limits.memory, self.usage.memory, cost.memory) {
+            return Some(Resource::Memory);
+        }
+        let rate = self.limits.kem_per_second? as f64;
+        let elapsed = (now - self.refilled_at).max(0.0);
+        self.tokens = (self.tokens + elapsed * rate).min(rate);
+        self.refilled_at = now;
+        (self.tokens < cost.kem as f64).then_some(Resource::Kem)
+    }
+
+    fn charge(&mut self, cost: Cost) {
+        self.usage.handshakes += cost.handshakes;
+        self.usage.memory += cost.memory;
+        if self.limits.kem_per_second.is_some() {
+            self.tokens -= cost.kem as f64;
+        }
+    }
+
+    fn release(&mut self, cost: Cost) {
+        self.usage.handshakes = self.usage.handshakes.saturating_sub(cost.handshakes);
+        self.usage.memory = self.usage.memory.saturating_sub(cost.memory);
+    }
+}
+
+/// The quotas of a running instance and what they hold
+#[derive(Debug, Default)]
+pub struct Quotas {
+    global: Quota,
+    tags: BTreeMap<String, Quota>,
+    /// Peers whose handshake holds a part of the quotas, with the tags it
+    /// was charged to
+    held: Vec<(AppPeerPtr, Vec<String>)>,
+}
+
+impl Quotas {
+    pub fn new(config: &QuotaConfig) -> Self {
+        Self {
+            global: Quota::new(None, config.global),
+            tags: config
+                .tags
+                .iter()
+                .map(|(tag, limits)| (tag.clone(), Quota::new(Some(tag.clone()), *limits)))
+                .collect(),
+            held: Vec::new(),
+        }
+    }
+
+    fn quotas_of<'a>(&'a mut self, tags: &'a [String]) -> impl Iterator<Item = &'a mut Quota> + 'a {
+        let Self {
+            global,
+            tags: quotas,
+            ..
+        } = self;
+        std::iter::once(global).chain(
+            quotas
+                .iter_mut()
+                .filter(|(tag, _)| tags.contains(tag))
+                .map(|(_, q)| q),
+        )
+    }
+
+    /// Take `cost` of the global quota and those of `tags`, unless one of
+    /// them would be exceeded; the rejection is counted against that one
+    pub fn admit(&mut self, tags: &[String], cost: Cost, now: Timing) -> Result<(), Resource> {
+        for quota in self.quotas_of(tags) {
+            if let Some(resource) = quota.exceeded(cost, now) {
+                *quota.usage.rejections.entry(resource).or_default() += 1;
+                return Err(resource);
+            }
+        }
+        for quota in self.quotas_of(tags) {
+            quota.charge(cost);
+        }
+        Ok(())
+    }
+
+    /// Admit a handshake initiated with `peer` of `tags`; a peer whose last
+    /// handshake still holds its part only takes the KEM operations again
+    pub fn admit_initiation(
+        &mut self,
+        peer: AppPeerPtr,
+        tags: &[String],
+        now: Timing,
+    ) -> Result<(), Resource> {
+        if self.held.iter().any(|(p, _)| p.0 == peer.0) {
+            let kem = Cost {
+                handshakes: 0,
+                memory: 0,
+                ..INITIATION
+            };
+            return self.admit(tags, kem, now);
+        }
+        self.admit(tags, INITIATION, now)?;
+        self.held.push((peer, tags.to_vec()));
+        Ok(())
+    }
+
+    /// Peers whose handshake holds a part of the quotas
+    pub fn holders(&self) -> impl Iterator<Item = AppPeerPtr> + '_ {
+        self.held.iter().map(|(p, _)| *p)
+    }
+
+    /// Give back what the handshake with `peer` holds
+    pub fn release(&mut self, peer: AppPeerPtr) {
+        let Some(i) = self.held.iter().position(|(p, _)| p.0 == peer.0) else {
+            return;
+        };
+        let (_, tags) = self.held.swap_remove(i);
+        for quota in self.quotas_of(&tags) {
+            quota.release(INITIATION);
+        }
+    }
+
+    /// Usage of all quotas, the global one first
+    pub fn usage(&self) -> Vec<QuotaUsage> {
+        std::iter::once(&self.global)
+            .chain(self.tags.values())
+            .map(|q| q.usage.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn limits_tenants_separately() {
+        let config: QuotaConfig = toml::from_str(
+            "handshakes = 3\n\
+             [tags.a]\n\
+             handshakes = 1\n\
+             kem_per_second = 6\n",
+        )
+        .unwrap();
+        config.validate().unwrap();
+        let mut quotas = Quotas::new(&config);
+        let (a, b) = (vec!["a".to_string()], vec!["b".to_string()]);
+
+        assert_eq!(quotas.admit_initiation(AppPeerPtr(0), &a, 0.0), Ok(()));
+        assert_eq!(
+            quotas.admit_initiation(AppPeerPtr(1), &a, 0.0),
+            Err(Resource::Handshakes)
+        );
+        // the running handshake may be started anew, given the KEM operations
+        assert_eq!(
+            quotas.admit_initiation(AppPeerPtr(0), &a, 0.0),
+            Err(Resource::Kem)
+        );
+        assert_eq!(quotas.admit_initiation(AppPeerPtr(0), &a, 1.0), Ok(()));
+
+        // other tenants are only bound by the global quota
+        assert_eq!(quotas.admit_initiation(AppPeerPtr(2), &b, 0.0), Ok(()));
+        assert_eq!(quotas.admit_initiation(AppPeerPtr(3), &[], 0.0), Ok(()));
+        assert_eq!(
+            quotas.admit_initiation(AppPeerPtr(4), &b, 0.0),
+            Err(Resource::Handshakes)
+        );
+        quotas.release(AppPeerPtr(2));
+        assert_eq!(quotas.admit_initiation(AppPeerPtr(4), &b, 0.0), Ok(()));
+
+        let usage = quotas.usage();
+        assert_eq!(usage[0].tag, None);
+        assert_eq!(usage[0].handshakes, 3);
+        assert_eq!(usage[0].memory, 3 * HANDSHAKE_MEMORY);
+        assert_eq!(usage[0].rejections[&Resource::Handshakes], 1);
+        assert_eq!(usage[1].tag.as_deref(), Some("a"));
+        assert_eq!(usage[1].rejections[&Resource::Handshakes], 1);
+        assert_eq!(usage[1].rejections[&Resource::Kem], 1);
+
+        let tiny = QuotaConfig {
+            global: Limits {
+                memory: Some(1),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(tiny.validate().is_err());
+    }
+}
@@ -0,0 +1,83 @@
+//! Renewing keys on demand
+//!
+//! After a key is suspected to be compromised, or to test the whole pipeline
+//! from the handshake to WireGuard without waiting for the rekey timer,
+//! `rosenpass rekey <peer|all>` asks a running instance over its control
+//! socket to initiate a handshake right away; the output key is replaced as
+//! soon as the handshake completes. Peers are named by their fingerprint or
+//! their base64 encoded id, as shown by `rosenpass status`. SIGUSR1 rekeys
+//! all peers, for instances without a control socket.
+//!
+//! Only peers with a known endpoint can be rekeyed from our side; peers we
+//! merely respond to are reported as skipped and have to be rekeyed from
+//! theirs. Rekeying a peer from both sides at once makes the handshakes
+//! cross, which may leave the two with different keys until the next
+//! regular exchange; one side is enough.
+
+use anyhow::{ensure, Result};
+use rosenpass_util::b64::fmt_b64;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, OnceLock,
+};
+
+use crate::{fingerprint::Fingerprint, protocol::PeerId};
+
+static SIGNALS: AtomicU64 = AtomicU64::new(0);
+static WAKER: OnceLock<Arc<mio::Waker>> = OnceLock::new();
+
+extern "C" fn on_signal(_: libc::c_int) {
+    SIGNALS.fetch_add(1, Ordering::SeqCst);
+    if let Some(waker) = WAKER.get() {
+        let _ = waker.wake();
+    }
+}
+
+/// Have SIGUSR1 wake the event loop through `waker` and increase [signals]
+pub fn handle_signal(waker: Arc<mio::Waker>) -> Result<()> {
+    let _ = WAKER.set(waker);
+    let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+    action.sa_sigaction = on_signal as *const () as usize;
+    let res = unsafe { libc::sigaction(libc::SIGUSR1, &action, std::ptr::null_mut()) };
+    ensure!(
+        res == 0,
+        "could not handle SIGUSR1: {}",
+        std::io::Error::last_os_error()
+    );
+    Ok(())
+}
+
+/// How many rekey signals arrived so far
+pub fn signals() -> u64 {
+    SIGNALS.load(Ordering::SeqCst)
+}
+
+/// Whether `selector` names the peer with the id `id` and the `name`, if any
+pub fn selects(selector: &str, id: &PeerId, name: Option<&str>) -> bool {
+    selector == "all"
+        || Some(selector) == name
+        || selector == Fingerprint::from_peer_id(id).to_string()
+        || selector == fmt_b64(&**id).to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn selectors() {
+        let id = PeerId::random();
+        let fp = Fingerprint::from_peer_id(&id).to_string();
+        assert!(selects("all", &id, None));
+        assert!(selects(&fp, &id, None));
+        assert!(selects(&fmt_b64(&*id).to_string(), &id, None));
+        assert!(selects("office-berlin", &id, Some("office-berlin")));
+        assert!(!selects("office-berlin", &id, None));
+        assert!(!selects(&fp[..fp.len() - 1], &id, None));
+        assert!(!selects(
+            &Fingerprint::from_peer_id(&PeerId::random()).to_string(),
+            &id,
+            None
+        ));
+    }
+}
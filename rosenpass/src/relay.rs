@@ -0,0 +1,24 @@
+//! Relaying handshakes through a third node
+//!
+//! Some pairs of peers can not reach each other at all, e.g. behind NATs
+//! which no hole punching gets through (see [crate::nat]). If a handshake
+//! with such a peer goes unanswered for [RELAY_AFTER] seconds, or if no
+//! endpoint of the peer is known at all, its messages are sent through a
+//! configured relay instead: a rosenpass instance running as relay server
+//! which both peers share a session with.
+//!
+//! Messages on the way are split into [crate::msgs::Fragment]s sealed for
+//! the peer at the other end, and each fragment is wrapped into a
+//! [crate::msgs::Relay] message authenticated with the session with the
+//! relay. The relay checks that, forwards the fragment to the peer it is
+//! addressed to, and tells the receiver whom it came from; it never sees
+//! more of the handshake than anyone else on the path would. Only peers of
+//! the relay with a live session are relayed to or from.
+//!
+//! Peers stay relayed until a handshake completes directly or their key
+//! expires, and replies to relayed messages are relayed as well.
+
+use crate::protocol::Timing;
+
+/// Seconds a handshake may go unanswered before it is relayed
+pub const RELAY_AFTER: Timing = 10.0;
@@ -0,0 +1,127 @@
+//! Finding peers through a rendezvous server
+//!
+//! A publicly reachable rosenpass instance running as rendezvous server
+//! keeps track of where its peers are: every [REGISTER_INTERVAL] seconds, the
+//! peers of a rendezvous server register with it, and the server takes the
+//! address each registration arrives from as the endpoint of the peer. The
+//! peers ask the server for the endpoints of those of their own peers they
+//! have no key with, e.g. because their address changed; the server answers
+//! with the last registered endpoint, and lets the peer asked for know about
+//! the one asking in turn, so both start a handshake at the same time.
+//!
+//! These [RendezvousMsg]s are exchanged in [crate::msgs::Rendezvous]
+//! messages, encrypted and authenticated with the key of the session with
+//! the server, so only peers which completed a handshake with the server,
+//! and thereby proved to hold their static key, can register or ask. The
+//! server only hands out the endpoints of its own peers; answers are taken
+//! from configured rendezvous servers only.
+
+use anyhow::{bail, ensure, Result};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+
+use crate::{lockdown, msgs::RENDEZVOUS_PAYLOAD_LEN, protocol::Timing};
+use rosenpass_util::mem::cpy;
+
+/// Seconds between registrations; short enough to keep common NAT mappings
+/// open
+pub const REGISTER_INTERVAL: Timing = 25.0;
+
+/// Seconds a registration is handed out for after it was last renewed
+pub const REGISTRATION_TTL: Timing = 3.0 * REGISTER_INTERVAL;
+
+const REGISTER: u8 = 1;
+const QUERY: u8 = 2;
+const ANSWER: u8 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RendezvousMsg {
+    /// Remember the address this was sent from as ours
+    Register,
+    /// Where is the peer with this id?
+    Query([u8; 32]),
+    /// The peer with this id is at this address, as far as the server knows
+    Answer([u8; 32], Option<SocketAddr>),
+}
+
+/// Where a peer of a rendezvous server registered from
+#[derive(Debug, Clone, Copy)]
+pub struct Registration {
+    /// Index of the socket the registration arrived on
+    pub socket: usize,
+    pub addr: SocketAddr,
+    pub at: Timing,
+}
+
+impl RendezvousMsg {
+    pub fn encode(&self) -> [u8; RENDEZVOUS_PAYLOAD_LEN] {
+        // op, peer id, IPv6 (or IPv4-mapped) address, port
+        let mut buf = [0u8; RENDEZVOUS_PAYLOAD_LEN];
+        let (op, id, addr) = match self {
+            Self::Register => (REGISTER, None, None),
+            Self::Query(id) => (QUERY, Some(id), None),
+            Self::Answer(id, addr) => (ANSWER, Some(id), addr.as_ref()),
+        };
+        buf[0] = op;
+        if let Some(id) = id {
+            cpy(id, &mut buf[1..33]);
+        }
+        if let Some(addr) = addr {
+            let ip = match addr.ip() {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+            cpy(&ip.octets(), &mut buf[33..49]);
+            cpy(&addr.port().to_be_bytes(), &mut buf[49..51]);
+        }
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        ensure!(
+            buf.len() == RENDEZVOUS_PAYLOAD_LEN,
+            "invalid rendezvous message"
+        );
+        let mut id = [0u8; 32];
+        cpy(&buf[1..33], &mut id);
+        let mut ip = [0u8; 16];
+        cpy(&buf[33..49], &mut ip);
+        let port = u16::from_be_bytes([buf[49], buf[50]]);
+        // an unknown address is encoded as all zeros
+        let addr = (port != 0)
+            .then(|| SocketAddr::new(lockdown::canonical(Ipv6Addr::from(ip).into()), port));
+        Ok(match buf[0] {
+            REGISTER => Self::Register,
+            QUERY => Self::Query(id),
+            ANSWER => Self::Answer(id, addr),
+            op => bail!("unknown rendezvous operation {op}"),
+        })
+    }
+}
+
+impl Registration {
+    /// Whether the registration is recent enough to be handed out at `now`
+    pub fn fresh(&self, now: Timing) -> bool {
+        now - self.at <= REGISTRATION_TTL
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let id = [7u8; 32];
+        for msg in [
+            RendezvousMsg::Register,
+            RendezvousMsg::Query(id),
+            RendezvousMsg::Answer(id, None),
+            RendezvousMsg::Answer(id, Some("192.0.2.1:9999".parse().unwrap())),
+            RendezvousMsg::Answer(id, Some("[2001:db8::1]:9999".parse().unwrap())),
+        ] {
+            assert_eq!(RendezvousMsg::decode(&msg.encode()).unwrap(), msg);
+        }
+        assert!(RendezvousMsg::decode(&[9u8; RENDEZVOUS_PAYLOAD_LEN]).is_err());
+        assert!(RendezvousMsg::decode(&[1u8; 3]).is_err());
+    }
+}
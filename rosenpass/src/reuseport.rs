@@ -0,0 +1,745 @@
+//! Spreading handshakes over several processes sharing a port
+//!
+//! A single event loop answers handshakes on one core only. Responders for
+//! more peers than that can handle run several worker processes instead:
+//!
+//! ```toml
+//! listen = ["[::]:9999"]
+//!
+//! [reuseport]
+//! workers = 4
+//! ```
+//!
+//! `exchange-config` then binds every listen address once for each worker,
+//! with `SO_REUSEPORT`, and starts the workers, running the same command
+//! line with `ROSENPASS_REUSEPORT_WORKER` set; workers which exit are
+//! started again, waiting twice as long for each exit in a row, see
+//! [Backoff]. A worker which keeps exiting right away stops all of them, so
+//! the service manager learns of it. A classic BPF program steers every datagram to the worker
+//! the source address and port of it hash to, by rendezvous hashing, so all
+//! messages of a peer land on the same worker and the workers need not share
+//! any state; changing the number of workers moves only a share of the peers
+//! to other workers.
+//!
+//! Each worker runs the whole config, but initiates handshakes only with the
+//! peers whose endpoint hashes to it, as their answers arrive there; peers
+//! without an endpoint are known to all of them, peers whose endpoint can
+//! not be resolved are left to the first one. The control socket of worker
+//! `n` is that of the config with `.n` appended. Peers roaming to another
+//! address may move to another worker, which starts over with them; as do
+//! peers reached through a relay.
+//!
+//! Sharing the view of all peers is left to configs without workers:
+//! `listen_unix`, `listen_quic`, `mdns`, `rendezvous_server`,
+//! `relay_server`, `api`, `enrollment` and `high_availability` can not be
+//! used with them.
+
+use anyhow::{bail, ensure, Context, Result};
+use log::{info, warn};
+use mio::{Events, Poll, Token, Waker};
+use serde::{Deserialize, Serialize};
+use std::{
+    ffi::OsString,
+    io::{self, ErrorKind},
+    net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket},
+    os::{
+        fd::{AsRawFd, FromRawFd, RawFd},
+        unix::process::CommandExt,
+    },
+    path::Path,
+    process::{Child, Command},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{config::Rosenpass, container, lockdown, netns, sockopt::setsockopt, upgrade};
+
+/// Tells a worker which one it is
+pub const WORKER_ENV: &str = "ROSENPASS_REUSEPORT_WORKER";
+
+/// Most workers the steering program can choose from
+pub const MAX_WORKERS: usize = 64;
+
+/// When workers which exited are started again
+#[derive(Debug, Clone, Copy)]
+struct Backoff {
+    /// Wait after the first exit, doubling with each exit in a row
+    first: Duration,
+    /// Longest wait
+    max: Duration,
+    /// Workers which ran this long start over with `first`
+    stable: Duration,
+    /// Exits in a row after which the worker is given up on
+    attempts: u32,
+}
+
+const BACKOFF: Backoff = Backoff {
+    first: Duration::from_secs(1),
+    max: Duration::from_secs(30),
+    stable: Duration::from_secs(60),
+    attempts: 10,
+};
+
+/// How often the workers are looked after
+const CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
+const M1: u32 = 0x9e37_79b1;
+const M2: u32 = 0x85eb_ca6b;
+const M3: u32 = 0xc2b2_ae35;
+const SEED: u32 = 0x5253_5057;
+
+/// The `[reuseport]` section of the config
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReusePort {
+    /// Number of worker processes
+    pub workers: usize,
+}
+
+impl ReusePort {
+    pub fn validate(&self) -> Result<()> {
+        ensure!(
+            (1..=MAX_WORKERS).contains(&self.workers),
+            "reuseport.workers must be between 1 and {MAX_WORKERS}"
+        );
+        Ok(())
+    }
+}
+
+/// Which worker this process is, if it is one
+pub fn worker() -> Option<usize> {
+    std::env::var(WORKER_ENV).ok()?.parse().ok()
+}
+
+fn mix(h: u32, word: u32) -> u32 {
+    let h = (h ^ word).wrapping_mul(M1);
+    h ^ (h >> 15)
+}
+
+fn seed_of(worker: usize) -> u32 {
+    (worker as u32 + 1).wrapping_mul(M1)
+}
+
+fn score(key: u32, worker: usize) -> u32 {
+    let s = (key ^ seed_of(worker)).wrapping_mul(M2);
+    let s = (s ^ (s >> 13)).wrapping_mul(M3);
+    s ^ (s >> 16)
+}
+
+/// The worker out of `workers` which receives the datagrams from `addr`
+pub fn owner(addr: SocketAddr, workers: usize) -> usize {
+    let words = match lockdown::canonical(addr.ip()) {
+        IpAddr::V4(ip) => vec![u32::from_be_bytes(ip.octets())],
+        IpAddr::V6(ip) => ip
+            .octets()
+            .chunks(4)
+            .map(|w| u32::from_be_bytes(w.try_into().unwrap()))
+            .collect(),
+    };
+    let key = words
+        .into_iter()
+        .chain([addr.port() as u32])
+        .fold(SEED, mix);
+    let mut best = (0, 0);
+    for worker in 0..workers {
+        let s = score(key, worker);
+        if s > best.0 {
+            best = (s, worker);
+        }
+    }
+    best.1
+}
+
+/// Builds classic BPF programs
+#[derive(Default)]
+struct Program(Vec<libc::sock_filter>);
+
+const SCRATCH_KEY: u32 = 0;
+const SCRATCH_TMP: u32 = 1;
+const SCRATCH_BEST: u32 = 2;
+const SCRATCH_WORKER: u32 = 3;
+const NET: u32 = libc::SKF_NET_OFF as u32;
+const BPF_TAX: u32 = 0x00;
+const BPF_A: u32 = 0x10;
+
+impl Program {
+    fn op(&mut self, code: u32, k: u32) -> &mut Self {
+        self.jump(code, k, 0, 0)
+    }
+
+    fn jump(&mut self, code: u32, k: u32, jt: u8, jf: u8) -> &mut Self {
+        self.0.push(libc::sock_filter {
+            code: code as u16,
+            jt,
+            jf,
+            k,
+        });
+        self
+    }
+
+    fn alu(&mut self, op: u32, k: u32) -> &mut Self {
+        self.op(libc::BPF_ALU | op | libc::BPF_K, k)
+    }
+
+    fn load_scratch(&mut self, m: u32) -> &mut Self {
+        self.op(libc::BPF_LD | libc::BPF_MEM, m)
+    }
+
+    fn store(&mut self, m: u32) -> &mut Self {
+        self.op(libc::BPF_ST, m)
+    }
+
+    /// A ^= A >> shift, by way of X and [SCRATCH_TMP]
+    fn xor_shift(&mut self, shift: u32) -> &mut Self {
+        self.store(SCRATCH_TMP)
+            .alu(libc::BPF_RSH, shift)
+            .op(libc::BPF_MISC | BPF_TAX, 0)
+            .load_scratch(SCRATCH_TMP)
+            .op(libc::BPF_ALU | libc::BPF_XOR | libc::BPF_X, 0)
+    }
+
+    /// [mix] the key with the word in A
+    fn mix(&mut self) -> &mut Self {
+        self.op(libc::BPF_LDX | libc::BPF_MEM, SCRATCH_KEY)
+            .op(libc::BPF_ALU | libc::BPF_XOR | libc::BPF_X, 0)
+            .alu(libc::BPF_MUL, M1)
+            .xor_shift(15)
+            .store(SCRATCH_KEY)
+    }
+
+    /// The key of the source address at `addr` and of the port at `port`,
+    /// which are relative to the network header, the port after X; see
+    /// [owner]
+    fn key(addr: &[u32], port: impl Fn(&mut Program)) -> Self {
+        let mut p = Program::default();
+        p.op(libc::BPF_LD | libc::BPF_IMM, SEED).store(SCRATCH_KEY);
+        for &offset in addr {
+            p.op(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, NET + offset)
+                .mix();
+        }
+        port(&mut p);
+        p.mix();
+        p
+    }
+
+    /// Steer datagrams to one of `workers` sockets by [owner]
+    fn steering(workers: usize) -> Self {
+        let v4 = Self::key(&[12], |p| {
+            // behind the header, which is as long as it says
+            p.op(libc::BPF_LD | libc::BPF_B | libc::BPF_ABS, NET)
+                .alu(libc::BPF_AND, 0xf)
+                .alu(libc::BPF_LSH, 2)
+                .op(libc::BPF_MISC | BPF_TAX, 0)
+                .op(libc::BPF_LD | libc::BPF_H | libc::BPF_IND, NET);
+        });
+        let v6 = Self::key(&[8, 12, 16, 20], |p| {
+            p.op(libc::BPF_LD | libc::BPF_H | libc::BPF_ABS, NET + 40);
+        });
+
+        let mut p = Program::default();
+        p.op(libc::BPF_LD | libc::BPF_B | libc::BPF_ABS, NET)
+            .alu(libc::BPF_RSH, 4)
+            .jump(
+                libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+                4,
+                0,
+                v4.0.len() as u8 + 1,
+            );
+        p.0.extend(v4.0);
+        p.op(libc::BPF_JMP | libc::BPF_JA, v6.0.len() as u32);
+        p.0.extend(v6.0);
+
+        p.op(libc::BPF_LD | libc::BPF_IMM, 0)
+            .store(SCRATCH_BEST)
+            .store(SCRATCH_WORKER);
+        for worker in 0..workers {
+            p.load_scratch(SCRATCH_KEY)
+                .alu(libc::BPF_XOR, seed_of(worker))
+                .alu(libc::BPF_MUL, M2)
+                .xor_shift(13)
+                .alu(libc::BPF_MUL, M3)
+                .xor_shift(16)
+                .op(libc::BPF_LDX | libc::BPF_MEM, SCRATCH_BEST)
+                .jump(libc::BPF_JMP | libc::BPF_JGT | libc::BPF_X, 0, 0, 3)
+                .store(SCRATCH_BEST)
+                .op(libc::BPF_LD | libc::BPF_IMM, worker as u32)
+                .store(SCRATCH_WORKER);
+        }
+        p.load_scratch(SCRATCH_WORKER).op(libc::BPF_RET | BPF_A, 0);
+        p
+    }
+}
+
+/// A UDP socket bound to `addr` with `SO_REUSEPORT`
+fn bind_reusing(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let domain = match addr {
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6,
+    };
+    let fd = unsafe { libc::socket(domain, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let sock = unsafe { UdpSocket::from_raw_fd(fd) };
+    setsockopt(
+        &sock,
+        libc::SOL_SOCKET,
+        libc::SO_REUSEPORT,
+        &1 as &libc::c_int,
+    )?;
+    let res = match addr {
+        SocketAddr::V4(a) => {
+            let sa = libc::sockaddr_in {
+                sin_family: libc::AF_INET as libc::sa_family_t,
+                sin_port: a.port().to_be(),
+                sin_addr: libc::in_addr {
+                    s_addr: u32::from_ne_bytes(a.ip().octets()),
+                },
+                sin_zero: [0; 8],
+            };
+            let len = std::mem::size_of_val(&sa) as libc::socklen_t;
+            unsafe { libc::bind(fd, &sa as *const _ as *const libc::sockaddr, len) }
+        }
+        SocketAddr::V6(a) => {
+            let sa = libc::sockaddr_in6 {
+                sin6_family: libc::AF_INET6 as libc::sa_family_t,
+                sin6_port: a.port().to_be(),
+                sin6_flowinfo: a.flowinfo(),
+                sin6_addr: libc::in6_addr {
+                    s6_addr: a.ip().octets(),
+                },
+                sin6_scope_id: a.scope_id(),
+            };
+            let len = std::mem::size_of_val(&sa) as libc::socklen_t;
+            unsafe { libc::bind(fd, &sa as *const _ as *const libc::sockaddr, len) }
+        }
+    };
+    match res {
+        0 => Ok(sock),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// The sockets of `workers` bound to `addr`, steered between by [owner]
+pub fn bind(addr: SocketAddr, workers: usize) -> Result<Vec<UdpSocket>> {
+    let mut sockets = Vec::new();
+    // the index of a socket in the group is the order they were bound in
+    for _ in 0..workers {
+        let sock = bind_reusing(addr).with_context(|| format!("could not bind {addr}"))?;
+        sockets.push(sock);
+    }
+    let mut program = Program::steering(workers).0;
+    let fprog = libc::sock_fprog {
+        len: program.len() as libc::c_ushort,
+        filter: program.as_mut_ptr(),
+    };
+    setsockopt(
+        &sockets[0],
+        libc::SOL_SOCKET,
+        libc::SO_ATTACH_REUSEPORT_CBPF,
+        &fprog,
+    )
+    .with_context(|| format!("could not attach the steering program to {addr}"))?;
+    Ok(sockets)
+}
+
+/// Adapt `config` to being run by `worker`, see [crate::reuseport]
+pub fn apply(config: &mut Rosenpass, worker: usize) -> Result<()> {
+    let Some(reuseport) = config.reuseport else {
+        bail!("{WORKER_ENV} is set, but the config has no [reuseport] section");
+    };
+    ensure!(
+        worker < reuseport.workers,
+        "{WORKER_ENV}={worker}, but there are only {} workers",
+        reuseport.workers
+    );
+    config.peers.retain(|peer| {
+        let Some(endpoint) = peer.endpoint.as_deref() else {
+            return true;
+        };
+        match endpoint.to_socket_addrs().map(|mut addrs| addrs.next()) {
+            Ok(Some(addr)) => owner(addr, reuseport.workers) == worker,
+            _ => {
+                warn!("Endpoint {endpoint} can not be resolved, leaving it to the first worker");
+                worker == 0
+            }
+        }
+    });
+    if let Some(path) = config.control_socket.take() {
+        let mut path = OsString::from(path);
+        path.push(format!(".{worker}"));
+        config.control_socket = Some(path.into());
+    }
+    Ok(())
+}
+
+fn spawn(binary: &Path, worker: usize, sockets: &[RawFd]) -> Result<Child> {
+    let memfd = upgrade::handover(sockets, &[])?;
+    let mut inherited = sockets.to_vec();
+    inherited.push(memfd.as_raw_fd());
+    let mut cmd = Command::new(binary);
+    cmd.args(std::env::args_os().skip(1))
+        .env(WORKER_ENV, worker.to_string())
+        .env(upgrade::ENV, memfd.as_raw_fd().to_string());
+    unsafe {
+        cmd.pre_exec(move || {
+            for &fd in inherited.iter() {
+                libc::fcntl(fd, libc::F_SETFD, 0);
+            }
+            // workers do not outlive us
+            libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGTERM);
+            Ok(())
+        });
+    }
+    Ok(cmd.spawn()?)
+}
+
+/// A worker process, started again whenever it exits
+struct Worker {
+    no: usize,
+    child: Option<Child>,
+    /// When the worker was last started
+    started: Instant,
+    /// When the worker is to be started next
+    start_at: Instant,
+    /// Exits in a row, each before [Backoff::stable]
+    exits: u32,
+}
+
+impl Worker {
+    fn new(no: usize, now: Instant) -> Self {
+        Self {
+            no,
+            child: None,
+            started: now,
+            start_at: now,
+            exits: 0,
+        }
+    }
+
+    /// Start the worker with `spawn` if it is not running and due at `now`;
+    /// fails once it exited more than [Backoff::attempts] times in a row
+    fn look_after<F>(&mut self, now: Instant, backoff: &Backoff, spawn: F) -> Result<()>
+    where
+        F: FnOnce() -> Result<Child>,
+    {
+        if let Some(status) = self
+            .child
+            .as_mut()
+            .map(Child::try_wait)
+            .transpose()?
+            .flatten()
+        {
+            self.child = None;
+            let delay = self.exited(now, backoff)?;
+            warn!(
+                "Worker {} exited with {status}, starting it again in {delay:?}",
+                self.no
+            );
+        }
+        if self.child.is_some() || now < self.start_at {
+            return Ok(());
+        }
+        self.started = now;
+        match spawn() {
+            Ok(child) => self.child = Some(child),
+            Err(e) => {
+                let delay = self.exited(now, backoff)?;
+                warn!(
+                    "Could not start worker {}, trying again in {delay:?}: {e:#}",
+                    self.no
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// How long to wait before starting the worker again at `now`
+    fn exited(&mut self, now: Instant, backoff: &Backoff) -> Result<Duration> {
+        if now.saturating_duration_since(self.started) >= backoff.stable {
+            self.exits = 0;
+        }
+        self.exits += 1;
+        ensure!(
+            self.exits <= backoff.attempts,
+            "worker {} exited {} times in a row, giving up",
+            self.no,
+            self.exits
+        );
+        let delay = backoff
+            .first
+            .saturating_mul(1 << (self.exits - 1).min(31))
+            .min(backoff.max);
+        self.start_at = now + delay;
+        Ok(delay)
+    }
+
+    fn stop(&mut self) {
+        if let Some(child) = self.child.as_ref() {
+            unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGTERM) };
+        }
+    }
+}
+
+/// Bind the sockets of the workers of `config` and keep the workers
+/// running, until SIGTERM or SIGINT or until one of them keeps exiting
+pub fn run(config: &Rosenpass) -> Result<()> {
+    let workers = config.reuseport.map_or(1, |r| r.workers);
+    let groups = netns::within(config.netns.as_deref(), || {
+        config
+            .listen
+            .iter()
+            .map(|&addr| bind(addr, workers))
+            .collect::<Result<Vec<_>>>()
+    })?;
+    let binary = upgrade::started_from().context("the binary we were started from is unknown")?;
+    let mut poll = Poll::new()?;
+    let waker = Arc::new(Waker::new(poll.registry(), Token(0))?);
+    container::handle_termination(waker)?;
+    info!("Starting {workers} workers on {:?}", config.listen);
+
+    let now = Instant::now();
+    let mut children: Vec<Worker> = (0..workers).map(|no| Worker::new(no, now)).collect();
+    let mut events = Events::with_capacity(1);
+    let res = loop {
+        if container::terminating() {
+            break Ok(());
+        }
+        let now = Instant::now();
+        let looked_after = children.iter_mut().try_for_each(|worker| {
+            let no = worker.no;
+            let sockets: Vec<RawFd> = groups.iter().map(|g| g[no].as_raw_fd()).collect();
+            worker.look_after(now, &BACKOFF, || spawn(binary, no, &sockets))
+        });
+        if let Err(e) = looked_after {
+            break Err(e);
+        }
+        match poll.poll(&mut events, Some(CHECK_INTERVAL)) {
+            Err(e) if e.kind() != ErrorKind::Interrupted => break Err(e.into()),
+            _ => {}
+        }
+    };
+
+    children.iter_mut().for_each(Worker::stop);
+    for child in children.iter_mut().filter_map(|w| w.child.as_mut()) {
+        let _ = child.wait();
+    }
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Run the few instructions [Program::steering] is made of on a
+    /// datagram whose network header is `net`
+    fn interpret(program: &[libc::sock_filter], net: &[u8]) -> u32 {
+        let (mut a, mut x, mut mem, mut pc) = (0u32, 0u32, [0u32; 16], 0);
+        let load = |at: u32, len: usize| {
+            let at = at.wrapping_sub(NET) as usize;
+            net[at..at + len]
+                .iter()
+                .fold(0u32, |v, &b| (v << 8) | b as u32)
+        };
+        loop {
+            let ins = &program[pc];
+            let (code, k) = (ins.code as u32, ins.k);
+            pc += 1;
+            let src = match code & libc::BPF_X {
+                0 => k,
+                _ => x,
+            };
+            match code & 0x07 {
+                c if c == libc::BPF_LD => match code & 0xe0 {
+                    m if m == libc::BPF_IMM => a = k,
+                    m if m == libc::BPF_MEM => a = mem[k as usize],
+                    m => {
+                        let len = match code & 0x18 {
+                            s if s == libc::BPF_W => 4,
+                            s if s == libc::BPF_H => 2,
+                            _ => 1,
+                        };
+                        let at = match m == libc::BPF_IND {
+                            true => x.wrapping_add(k),
+                            false => k,
+                        };
+                        a = load(at, len);
+                    }
+                },
+                c if c == libc::BPF_LDX => x = mem[k as usize],
+                c if c == libc::BPF_ST => mem[k as usize] = a,
+                c if c == libc::BPF_ALU => {
+                    a = match code & 0xf0 {
+                        o if o == libc::BPF_XOR => a ^ src,
+                        o if o == libc::BPF_MUL => a.wrapping_mul(src),
+                        o if o == libc::BPF_RSH => a >> src,
+                        o if o == libc::BPF_LSH => a << src,
+                        o if o == libc::BPF_AND => a & src,
+                        o => panic!("unexpected alu op {o:#x}"),
+                    }
+                }
+                c if c == libc::BPF_JMP => {
+                    let taken = match code & 0xf0 {
+                        o if o == libc::BPF_JA => {
+                            pc += k as usize;
+                            continue;
+                        }
+                        o if o == libc::BPF_JEQ => a == src,
+                        o if o == libc::BPF_JGT => a > src,
+                        o => panic!("unexpected jump {o:#x}"),
+                    };
+                    pc += match taken {
+                        true => ins.jt,
+                        false => ins.jf,
+                    } as usize;
+                }
+                c if c == libc::BPF_RET => return a,
+                c if c == libc::BPF_MISC => x = a,
+                c => panic!("unexpected class {c:#x}"),
+            }
+        }
+    }
+
+    fn header(addr: SocketAddr) -> Vec<u8> {
+        let mut net = match addr.ip() {
+            IpAddr::V4(ip) => {
+                // with options, to move the port
+                let mut h = vec![0x46; 24];
+                h[12..16].copy_from_slice(&ip.octets());
+                h
+            }
+            IpAddr::V6(ip) => {
+                let mut h = vec![0x60; 40];
+                h[8..24].copy_from_slice(&ip.octets());
+                h
+            }
+        };
+        net.extend_from_slice(&addr.port().to_be_bytes());
+        net.extend_from_slice(&[0; 6]);
+        net
+    }
+
+    #[test]
+    fn program_steers_like_owner() {
+        for workers in [1, 3, 8, MAX_WORKERS] {
+            let program = Program::steering(workers).0;
+            assert!(program.len() <= libc::BPF_MAXINSNS as usize);
+            let mut seen = vec![false; workers];
+            for i in 0..200u32 {
+                for addr in [
+                    format!("10.{}.{}.7:{}", i % 7, i, 1000 + i),
+                    format!("[2001:db8::{i:x}]:{}", 9999 - i),
+                ] {
+                    let addr: SocketAddr = addr.parse().unwrap();
+                    let worker = owner(addr, workers);
+                    assert_eq!(interpret(&program, &header(addr)), worker as u32);
+                    seen[worker] = true;
+                }
+            }
+            if workers <= 8 {
+                assert!(seen.iter().all(|&s| s), "some worker gets no peers");
+            }
+        }
+    }
+
+    #[test]
+    fn few_peers_move() {
+        let addrs: Vec<SocketAddr> = (0..1000u32)
+            .map(|i| SocketAddr::from(([192, 0, (i >> 8) as u8, i as u8], 9999)))
+            .collect();
+        let moved = addrs
+            .iter()
+            .filter(|&&a| owner(a, 4) != owner(a, 5))
+            .count();
+        // a fifth of them, ideally
+        assert!(moved < 300, "{moved} peers moved");
+    }
+
+    /// A fake worker, exiting as soon as it started
+    fn failing() -> Result<Child> {
+        Ok(Command::new("sh").args(["-c", "exit 1"]).spawn()?)
+    }
+
+    const QUICK: Backoff = Backoff {
+        first: Duration::from_millis(5),
+        max: Duration::from_millis(20),
+        stable: Duration::from_secs(60),
+        attempts: 5,
+    };
+
+    /// Look after `worker` until it fails, or [Backoff::stable] passed
+    fn drive(worker: &mut Worker, backoff: &Backoff, spawned: &mut Vec<Instant>) -> Result<()> {
+        let until = Instant::now() + backoff.stable;
+        while Instant::now() < until {
+            worker.look_after(Instant::now(), backoff, || {
+                spawned.push(Instant::now());
+                failing()
+            })?;
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn workers_are_started_again() {
+        let backoff = Backoff {
+            stable: Duration::from_millis(500),
+            attempts: 1000,
+            ..QUICK
+        };
+        let mut worker = Worker::new(0, Instant::now());
+        let mut spawned = vec![];
+        drive(&mut worker, &backoff, &mut spawned).unwrap();
+        assert!(spawned.len() >= 3, "started {} times", spawned.len());
+        // never before the delay, which stops growing at the maximum
+        for (i, pair) in spawned.windows(2).enumerate() {
+            let delay = QUICK.first * (1 << i.min(2));
+            assert!(pair[1] - pair[0] >= delay, "restart {i} came early");
+        }
+        worker.stop();
+    }
+
+    #[test]
+    fn delays_are_capped() {
+        let now = Instant::now();
+        let mut worker = Worker::new(0, now);
+        let delays: Vec<_> = (0..40)
+            .map(|_| worker.exited(now, &BACKOFF).map(|d| d.as_secs()))
+            .take_while(Result::is_ok)
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(delays, [1, 2, 4, 8, 16, 30, 30, 30, 30, 30]);
+
+        // a worker which ran for long enough starts over
+        let mut worker = Worker::new(0, now);
+        for _ in 0..5 {
+            worker.exited(now, &BACKOFF).unwrap();
+        }
+        let later = now + BACKOFF.stable;
+        assert_eq!(worker.exited(later, &BACKOFF).unwrap(), BACKOFF.first);
+    }
+
+    #[test]
+    fn crash_loops_are_given_up() {
+        let mut worker = Worker::new(3, Instant::now());
+        let mut spawned = vec![];
+        let e = drive(&mut worker, &QUICK, &mut spawned).unwrap_err();
+        assert!(e.to_string().contains("giving up"), "{e}");
+        assert_eq!(spawned.len(), QUICK.attempts as usize + 1);
+
+        // as is a worker which can not even be started
+        let mut worker = Worker::new(3, Instant::now());
+        let mut attempts = 0;
+        let e = loop {
+            let res = worker.look_after(Instant::now(), &QUICK, || {
+                attempts += 1;
+                bail!("no such binary")
+            });
+            if let Err(e) = res {
+                break e;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        };
+        assert!(e.to_string().contains("giving up"), "{e}");
+        assert_eq!(attempts, QUICK.attempts + 1);
+    }
+}
@@ -0,0 +1,273 @@
+//! Refusing peers whose public keys were revoked
+//!
+//! With `[revocation]` configured, rosenpass follows a list of revoked
+//! public keys, signed with the key of an operator CA (see [crate::ca]):
+//!
+//! ```toml
+//! [revocation]
+//! list = "https://ca.example.test/revoked.rpl" # or a file
+//! ca = "/etc/rosenpass/ca.pk"
+//! refresh = 300
+//! ```
+//!
+//! The list is loaded on startup, and rosenpass does not start without it;
+//! afterwards it is read or downloaded with `curl` again every
+//! [Revocation::refresh] seconds in the background. A list that fails to
+//! load, or is older than the one in use, is reported and ignored. Once the
+//! key of a peer is on the list, its handshakes are refused, its session is
+//! ended and its WireGuard PSK is replaced by a random one right away, and a
+//! `peer-revoked` event goes to the audit log. Revocations are not lifted
+//! again while rosenpass runs.
+//!
+//! Lists are made from the fingerprints of the revoked keys, one per line,
+//! with `rosenpass sign-revocations --ca-secret-key ca.sk revoked.txt
+//! revoked.rpl`. A list is laid out as follows, all numbers big endian:
+//!
+//! | bytes                  | content                                      |
+//! |------------------------|----------------------------------------------|
+//! | 8                      | `RPREVL01`                                   |
+//! | 8                      | seconds since the Unix epoch it was made at  |
+//! | 4                      | number of fingerprints                       |
+//! | [FINGERPRINT_LEN] each | the fingerprints                             |
+//! | [ca::SIG_LEN]          | signature over all of the above              |
+
+use anyhow::{ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{
+        mpsc::{self, Receiver},
+        Arc,
+    },
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    ca,
+    coloring::Secret,
+    fingerprint::{Fingerprint, FINGERPRINT_LEN},
+};
+
+/// Default of [Revocation::refresh]
+pub const DEFAULT_REFRESH: u64 = 300;
+
+const MAGIC: &[u8; 8] = b"RPREVL01";
+
+/// Where to find the revocation list
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Revocation {
+    /// Path of the list, or an `http://` or `https://` URL to download it
+    /// from
+    pub list: String,
+
+    /// Public key of the CA signing the list
+    pub ca: PathBuf,
+
+    /// Seconds between two refreshes of the list; defaults to
+    /// [DEFAULT_REFRESH]
+    #[serde(default)]
+    pub refresh: Option<u64>,
+}
+
+/// The fingerprints of revoked public keys
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RevocationList {
+    /// Seconds since the Unix epoch the list was made at
+    pub issued: u64,
+    pub revoked: Vec<Fingerprint>,
+}
+
+impl Revocation {
+    pub fn validate(&self) -> Result<()> {
+        ensure!(
+            self.ca.is_file(),
+            "revocation CA public key {:?} does not exist",
+            self.ca
+        );
+        ensure!(
+            self.refresh != Some(0),
+            "the revocation list can not be refreshed constantly"
+        );
+        Ok(())
+    }
+
+    fn is_url(&self) -> bool {
+        self.list.starts_with("http://") || self.list.starts_with("https://")
+    }
+
+    /// Read or download the list and check its signature
+    pub fn load(&self) -> Result<RevocationList> {
+        let ca = std::fs::read(&self.ca)
+            .with_context(|| format!("could not read CA public key {:?}", self.ca))?;
+        let list = match self.is_url() {
+            true => download(&self.list),
+            false => std::fs::read(&self.list).map_err(anyhow::Error::from),
+        }
+        .with_context(|| format!("could not load revocation list {:?}", self.list))?;
+        RevocationList::open(&list, &ca)
+            .map_err(|e| anyhow::anyhow!("rejecting revocation list {:?}: {e:#}", self.list))
+    }
+
+    /// Load the list every [Revocation::refresh] seconds on a background
+    /// thread, waking `waker` whenever it did
+    fn watch(&self, waker: Arc<mio::Waker>) -> Receiver<Result<RevocationList>> {
+        let (tx, rx) = mpsc::channel();
+        let (cfg, every) = (self.clone(), self.refresh.unwrap_or(DEFAULT_REFRESH));
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(every));
+            if tx.send(cfg.load()).is_err() {
+                return;
+            }
+            let _ = waker.wake();
+        });
+        rx
+    }
+}
+
+/// The revocation list in use, and the refreshes of it coming in
+#[derive(Debug)]
+pub struct Revocations {
+    pub list: RevocationList,
+    refreshes: Receiver<Result<RevocationList>>,
+}
+
+impl Revocations {
+    /// Load the list, and keep refreshing it in the background
+    pub fn start(cfg: &Revocation, waker: Arc<mio::Waker>) -> Result<Self> {
+        Ok(Self {
+            list: cfg.load()?,
+            refreshes: cfg.watch(waker),
+        })
+    }
+
+    /// The outcome of the next refresh, if there was one
+    pub fn try_refresh(&self) -> Option<Result<RevocationList>> {
+        self.refreshes.try_recv().ok()
+    }
+
+    /// Use `list` from now on, unless it is older than the one in use
+    pub fn update(&mut self, list: RevocationList) -> Result<()> {
+        ensure!(
+            list.issued >= self.list.issued,
+            "the new revocation list was made at {}, before the one in use",
+            list.issued
+        );
+        self.list = list;
+        Ok(())
+    }
+}
+
+fn download(url: &str) -> Result<Vec<u8>> {
+    let out = Command::new("curl")
+        .args(["-fsSL", "-m", "30", url])
+        .output()
+        .context("could not run curl")?;
+    ensure!(
+        out.status.success(),
+        "curl failed with {}: {}",
+        out.status,
+        String::from_utf8_lossy(&out.stderr).trim()
+    );
+    Ok(out.stdout)
+}
+
+impl RevocationList {
+    /// A list of `revoked`, made now
+    pub fn new(revoked: Vec<Fingerprint>) -> Self {
+        let issued = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        Self { issued, revoked }
+    }
+
+    /// Read fingerprints from `path`, one per line; empty lines and those
+    /// starting with `#` are skipped
+    pub fn read_fingerprints(path: &Path) -> Result<Vec<Fingerprint>> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("could not read fingerprints {path:?}"))?;
+        text.lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .map(str::parse)
+            .collect()
+    }
+
+    pub fn contains(&self, fp: &Fingerprint) -> bool {
+        self.revoked.contains(fp)
+    }
+
+    /// Sign the list with the CA secret key `sk`
+    pub fn sign(&self, sk: &Secret<{ ca::SK_LEN }>) -> Result<Vec<u8>> {
+        let count = u32::try_from(self.revoked.len()).context("too many fingerprints")?;
+        let mut buf = MAGIC.to_vec();
+        buf.extend_from_slice(&self.issued.to_be_bytes());
+        buf.extend_from_slice(&count.to_be_bytes());
+        for fp in self.revoked.iter() {
+            buf.extend_from_slice(&fp.0);
+        }
+        let sig = ca::sign(&buf, sk).context("could not sign the revocation list")?;
+        buf.extend_from_slice(&sig);
+        Ok(buf)
+    }
+
+    /// Check the signature of `list` against the CA public key `ca`
+    pub fn open(list: &[u8], ca: &[u8]) -> Result<Self> {
+        ensure!(list.starts_with(MAGIC), "not a revocation list");
+        ensure!(
+            list.len() >= MAGIC.len() + 8 + 4 + ca::SIG_LEN,
+            "the list is truncated"
+        );
+        let (signed, sig) = list.split_at(list.len() - ca::SIG_LEN);
+        ca::verify(signed, sig, ca).context("the list is not signed by this CA")?;
+
+        let rest = &signed[MAGIC.len()..];
+        let (issued, rest) = rest.split_at(8);
+        let (count, rest) = rest.split_at(4);
+        let count = u32::from_be_bytes(count.try_into()?) as usize;
+        ensure!(
+            rest.len() == count * FINGERPRINT_LEN,
+            "the list is malformed"
+        );
+        let revoked = rest
+            .chunks(FINGERPRINT_LEN)
+            .map(|fp| Fingerprint(fp.try_into().unwrap()))
+            .collect();
+        Ok(Self {
+            issued: u64::from_be_bytes(issued.try_into()?),
+            revoked,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sign_and_open() {
+        rosenpass_sodium::init().unwrap();
+        let mut ca = vec![0u8; ca::PK_LEN];
+        let mut sk = Secret::<{ ca::SK_LEN }>::zero();
+        unsafe {
+            oqs_sys::sig::OQS_SIG_dilithium_3_keypair(
+                ca.as_mut_ptr(),
+                sk.secret_mut().as_mut_ptr(),
+            );
+        }
+
+        let list = RevocationList::new(vec![Fingerprint([1; 16]), Fingerprint([2; 16])]);
+        let signed = list.sign(&sk).unwrap();
+        let opened = RevocationList::open(&signed, &ca).unwrap();
+        assert_eq!(opened, list);
+        assert!(opened.contains(&Fingerprint([2; 16])));
+        assert!(!opened.contains(&Fingerprint([3; 16])));
+
+        let mut forged = signed.clone();
+        forged[MAGIC.len() + 8 + 4] ^= 1;
+        assert!(RevocationList::open(&forged, &ca).is_err());
+        assert!(RevocationList::open(&signed[..signed.len() - 1], &ca).is_err());
+    }
+}
@@ -0,0 +1,192 @@
+//! Pinning threads to CPUs and choosing how they are scheduled
+//!
+//! On routers, rosenpass shares the machine with dataplane workloads which
+//! usually own a set of dedicated cores. [Scheduling] keeps the event loop and
+//! the handshake workers (see [crate::workers]) on the remaining cores and
+//! lowers or raises their priority, so neither side starves the other.
+//!
+//! Settings are applied by each thread to itself; threads it starts
+//! afterwards inherit them. Only Linux is supported.
+
+use anyhow::{ensure, Result};
+use serde::{Deserialize, Serialize};
+
+/// Scheduling of the threads of rosenpass
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Scheduling {
+    #[serde(default)]
+    pub event_loop: ThreadScheduling,
+
+    #[serde(default)]
+    pub handshake_workers: ThreadScheduling,
+}
+
+/// Scheduling of a single kind of thread; the defaults leave the thread as
+/// the operating system started it
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ThreadScheduling {
+    /// CPUs the thread may run on; all of them if empty
+    #[serde(default)]
+    pub cpus: Vec<usize>,
+
+    /// Niceness, from -20 (most favorable) to 19
+    #[serde(default)]
+    pub nice: Option<i32>,
+
+    /// Run under a realtime policy instead of the default time sharing
+    #[serde(default)]
+    pub realtime: Option<Realtime>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Realtime {
+    pub policy: RealtimePolicy,
+
+    /// From 1 (lowest) to 99
+    pub priority: i32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RealtimePolicy {
+    Fifo,
+    RoundRobin,
+}
+
+impl Scheduling {
+    pub fn validate(&self) -> Result<()> {
+        self.event_loop.validate("event_loop")?;
+        self.handshake_workers.validate("handshake_workers")
+    }
+}
+
+impl ThreadScheduling {
+    fn validate(&self, name: &str) -> Result<()> {
+        if let Some(nice) = self.nice {
+            ensure!(
+                (-20..=19).contains(&nice),
+                "scheduling.{name}.nice must be between -20 and 19"
+            );
+        }
+        if let Some(rt) = self.realtime.as_ref() {
+            ensure!(
+                (1..=99).contains(&rt.priority),
+                "scheduling.{name}.realtime.priority must be between 1 and 99"
+            );
+            // the niceness only matters to threads that are not realtime
+            ensure!(
+                self.nice.is_none(),
+                "scheduling.{name} can not set both nice and realtime"
+            );
+        }
+        #[cfg(target_os = "linux")]
+        for &cpu in self.cpus.iter() {
+            ensure!(
+                cpu < libc::CPU_SETSIZE as usize,
+                "scheduling.{name}.cpus contains {cpu}, which is out of range"
+            );
+        }
+        Ok(())
+    }
+
+    /// Apply the settings to the calling thread
+    #[cfg(target_os = "linux")]
+    pub fn apply(&self) -> Result<()> {
+        use anyhow::bail;
+        use std::{io, mem};
+
+        // Linux schedules threads rather than processes, so with a pid of zero
+        // the calls below affect the calling thread only
+        if !self.cpus.is_empty() {
+            let mut set: libc::cpu_set_t = unsafe { mem::zeroed() };
+            for &cpu in self.cpus.iter() {
+                unsafe { libc::CPU_SET(cpu, &mut set) };
+            }
+            if unsafe { libc::sched_setaffinity(0, mem::size_of_val(&set), &set) } != 0 {
+                let e = io::Error::last_os_error();
+                bail!("could not pin thread to CPUs {:?}: {e}", self.cpus);
+            }
+        }
+
+        if let Some(nice) = self.nice {
+            if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } != 0 {
+                let e = io::Error::last_os_error();
+                bail!("could not set niceness {nice}: {e}");
+            }
+        }
+
+        if let Some(rt) = self.realtime.as_ref() {
+            let policy = match rt.policy {
+                RealtimePolicy::Fifo => libc::SCHED_FIFO,
+                RealtimePolicy::RoundRobin => libc::SCHED_RR,
+            };
+            let param = libc::sched_param {
+                sched_priority: rt.priority,
+            };
+            if unsafe { libc::sched_setscheduler(0, policy, &param) } != 0 {
+                let e = io::Error::last_os_error();
+                bail!(
+                    "could not switch to realtime scheduling with priority {}: {e}",
+                    rt.priority
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply the settings to the calling thread
+    #[cfg(not(target_os = "linux"))]
+    pub fn apply(&self) -> Result<()> {
+        ensure!(
+            *self == Self::default(),
+            "CPU affinity and scheduling options are only supported on Linux"
+        );
+        Ok(())
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn validate_and_apply() {
+        let sched: Scheduling = toml::from_str(
+            r#"
+            [event_loop]
+            cpus = [0]
+            nice = 5
+
+            [handshake_workers]
+            realtime = { policy = "round-robin", priority = 10 }
+            "#,
+        )
+        .unwrap();
+        sched.validate().unwrap();
+        assert_eq!(
+            sched.handshake_workers.realtime.as_ref().unwrap().policy,
+            RealtimePolicy::RoundRobin
+        );
+
+        // lowering the own priority needs no privileges
+        std::thread::spawn(move || sched.event_loop.apply())
+            .join()
+            .unwrap()
+            .unwrap();
+
+        let both = ThreadScheduling {
+            nice: Some(0),
+            realtime: Some(Realtime {
+                policy: RealtimePolicy::Fifo,
+                priority: 1,
+            }),
+            ..Default::default()
+        };
+        assert!(both.validate("x").is_err());
+        let nice = ThreadScheduling {
+            nice: Some(20),
+            ..Default::default()
+        };
+        assert!(nice.validate("x").is_err());
+    }
+}
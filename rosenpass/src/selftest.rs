@@ -0,0 +1,273 @@
+//! Checking secret-dependent operations for timing leaks
+//!
+//! `rosenpass selftest timing` runs the operations whose duration must not
+//! depend on secrets on the machine at hand and tests whether it does, the
+//! way [dudect](https://eprint.iacr.org/2016/1123) does: each operation is
+//! measured many times, in random order, either on one fixed input or on
+//! fresh random inputs, and Welch's t-test tells whether the two classes of
+//! measurements differ. This is meant for packagers to check their builds
+//! of libsodium and liboqs, and the compiler settings they use, on the
+//! hardware they ship for:
+//!
+//! - the comparison of message authentication codes
+//! - the comparison of biscuit numbers against those used before
+//! - the decryption of biscuits which fail authentication
+//! - the decapsulation of both KEMs, which must not tell valid ciphertexts
+//!   from random ones
+//!
+//! Measurements above some percentile are cropped, as they are mostly
+//! disturbances by the rest of the system; the largest t value of the
+//! uncropped and the cropped measurements counts. With a t value above
+//! [SUSPICIOUS_T], a difference is likely, and with one above [LEAKY_T]
+//! certain. Busy or frequency scaling machines make for false alarms, so a
+//! finding should be confirmed on an idle machine before it is trusted, and
+//! little leaks may only show up with more samples.
+
+use rosenpass_ciphers::xaead;
+use rosenpass_constant_time as constant_time;
+use rosenpass_sodium::helpers::randombytes_buf;
+use std::{hint::black_box, time::Instant};
+
+use crate::{
+    doctor::{Finding, Verdict},
+    msgs::{BISCUIT_CT_LEN, BISCUIT_ID_LEN},
+    pqkem::{EphemeralKEM, StaticKEM, KEM},
+};
+
+/// Default number of measurements of each operation
+pub const DEFAULT_SAMPLES: usize = 20_000;
+
+/// t values above this make a difference likely
+pub const SUSPICIOUS_T: f64 = 4.5;
+
+/// t values above this make a difference certain
+pub const LEAKY_T: f64 = 10.0;
+
+/// Percentiles the measurements are additionally cropped at
+const CROPS: [f64; 4] = [0.5, 0.75, 0.9, 0.99];
+
+/// The class of the input of a measurement
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Class {
+    Fixed,
+    Random,
+}
+
+/// Welch's t-test of two classes of measurements
+#[derive(Debug, Default, Clone)]
+struct TTest {
+    n: [f64; 2],
+    mean: [f64; 2],
+    m2: [f64; 2],
+}
+
+impl TTest {
+    fn push(&mut self, class: Class, x: f64) {
+        let c = class as usize;
+        self.n[c] += 1.0;
+        let delta = x - self.mean[c];
+        self.mean[c] += delta / self.n[c];
+        self.m2[c] += delta * (x - self.mean[c]);
+    }
+
+    fn t(&self) -> f64 {
+        if self.n.iter().any(|&n| n < 2.0) {
+            return 0.0;
+        }
+        let var = |c: usize| self.m2[c] / (self.n[c] - 1.0) / self.n[c];
+        let se = (var(0) + var(1)).sqrt();
+        match se > 0.0 {
+            true => (self.mean[0] - self.mean[1]) / se,
+            false => 0.0,
+        }
+    }
+}
+
+/// The largest t value of the `times` of both classes, uncropped and
+/// cropped at [CROPS]
+fn max_t(times: &[(Class, f64)]) -> f64 {
+    let mut sorted: Vec<f64> = times.iter().map(|&(_, x)| x).collect();
+    sorted.sort_by(f64::total_cmp);
+    let limits = CROPS
+        .iter()
+        .map(|p| sorted[((sorted.len() - 1) as f64 * p) as usize])
+        .chain([f64::INFINITY]);
+    limits
+        .map(|limit| {
+            let mut test = TTest::default();
+            for &(class, x) in times.iter().filter(|&&(_, x)| x <= limit) {
+                test.push(class, x);
+            }
+            test.t().abs()
+        })
+        .fold(0.0, f64::max)
+}
+
+/// Measure `op` on `samples` inputs made by `input`, running it `reps`
+/// times per measurement
+fn measure<I>(
+    samples: usize,
+    reps: usize,
+    mut input: impl FnMut(Class) -> I,
+    mut op: impl FnMut(&I),
+) -> f64 {
+    let mut coins = vec![0u8; samples];
+    randombytes_buf(&mut coins);
+    let mut times = Vec::with_capacity(samples);
+    for coin in coins {
+        let class = match coin & 1 {
+            0 => Class::Fixed,
+            _ => Class::Random,
+        };
+        let input = input(class);
+        let start = Instant::now();
+        for _ in 0..reps {
+            op(black_box(&input));
+        }
+        times.push((class, start.elapsed().as_nanos() as f64));
+    }
+    max_t(&times)
+}
+
+fn random(len: usize) -> Vec<u8> {
+    let mut buf = vec![0u8; len];
+    randombytes_buf(&mut buf);
+    buf
+}
+
+/// The fixed input, or a random one of the same length
+fn pick(class: Class, fixed: &[u8]) -> Vec<u8> {
+    match class {
+        Class::Fixed => fixed.to_vec(),
+        Class::Random => random(fixed.len()),
+    }
+}
+
+fn decaps<T: KEM>(samples: usize) -> anyhow::Result<f64> {
+    let (mut sk, mut pk) = (vec![0u8; T::SK_LEN], vec![0u8; T::PK_LEN]);
+    T::keygen(&mut sk, &mut pk)?;
+    let (mut shk, mut ct) = (vec![0u8; T::SHK_LEN], vec![0u8; T::CT_LEN]);
+    T::encaps(&mut shk, &mut ct, &pk)?;
+    let mut out = vec![0u8; T::SHK_LEN];
+    Ok(measure(
+        samples,
+        1,
+        |class| pick(class, &ct),
+        |ct| {
+            // random ciphertexts yield a random key rather than an error
+            let _ = T::decaps(&mut out, &sk, ct);
+        },
+    ))
+}
+
+/// The t value of every operation, measured `samples` times each
+pub fn timing(samples: usize) -> anyhow::Result<Vec<(&'static str, f64)>> {
+    let mut results = Vec::new();
+
+    // a tag differing from the expected one in its last byte only
+    let expected = random(32);
+    let mut close = expected[..16].to_vec();
+    close[15] ^= 1;
+    results.push((
+        "mac comparison",
+        measure(
+            samples,
+            256,
+            |class| pick(class, &close),
+            |tag| {
+                black_box(constant_time::tag_eq(tag, &expected));
+            },
+        ),
+    ));
+
+    let used = random(BISCUIT_ID_LEN);
+    let mut close = used.clone();
+    close[0] ^= 1;
+    results.push((
+        "biscuit number comparison",
+        measure(
+            samples,
+            256,
+            |class| pick(class, &close),
+            |no| {
+                black_box(constant_time::compare(no, &used));
+            },
+        ),
+    ));
+
+    let key = random(xaead::KEY_LEN);
+    let (pt, ad) = (
+        random(BISCUIT_CT_LEN - xaead::NONCE_LEN - xaead::TAG_LEN),
+        random(32),
+    );
+    let mut forged = vec![0u8; BISCUIT_CT_LEN];
+    xaead::encrypt(&mut forged, &key, &random(xaead::NONCE_LEN), &ad, &pt)?;
+    *forged.last_mut().unwrap() ^= 1;
+    let mut out = vec![0u8; pt.len()];
+    results.push((
+        "biscuit decryption",
+        measure(
+            samples,
+            16,
+            |class| pick(class, &forged),
+            |ct| {
+                let _ = black_box(xaead::decrypt(&mut out, &key, &ad, ct));
+            },
+        ),
+    ));
+
+    results.push(("static KEM decapsulation", decaps::<StaticKEM>(samples)?));
+    results.push((
+        "ephemeral KEM decapsulation",
+        decaps::<EphemeralKEM>(samples)?,
+    ));
+    Ok(results)
+}
+
+/// The finding about an operation with the t value `t`
+pub fn finding(operation: &str, samples: usize, t: f64) -> Finding {
+    let detail = format!("|t| = {t:.2} over {samples} measurements");
+    let (verdict, fix) = match t {
+        t if t > LEAKY_T => (
+            Verdict::Fail,
+            "its timing depends on the input; check the build of libsodium and liboqs",
+        ),
+        t if t > SUSPICIOUS_T => (
+            Verdict::Warn,
+            "run again on an idle machine, with more samples, to confirm the difference",
+        ),
+        _ => (Verdict::Ok, ""),
+    };
+    Finding {
+        verdict,
+        check: operation.to_string(),
+        detail,
+        fix: (verdict != Verdict::Ok).then(|| fix.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn differences_are_told() {
+        let mut times = Vec::new();
+        for i in 0..2000 {
+            let class = [Class::Fixed, Class::Random][i % 2];
+            let noise = (i * 7919 % 13) as f64;
+            times.push((class, 100.0 + noise));
+        }
+        assert!(max_t(&times) < SUSPICIOUS_T);
+
+        // the random inputs take a little longer
+        for (class, x) in times.iter_mut() {
+            if *class == Class::Random {
+                *x += 3.0;
+            }
+        }
+        assert!(max_t(&times) > LEAKY_T);
+        assert_eq!(finding("op", 2000, max_t(&times)).verdict, Verdict::Fail);
+        assert_eq!(finding("op", 2000, 1.0).verdict, Verdict::Ok);
+    }
+}
@@ -0,0 +1,331 @@
+//! Serving the clients of the listeners the event loop polls
+//!
+//! The control socket, the [crate::api] and [crate::enrollment] accept
+//! connections from listeners registered with the poll of the
+//! [crate::app_server::AppServer]. Their clients are served with blocking
+//! reads and writes, each within a timeout, see [accept].
+//!
+//! Clients of the api and of enrollment come from the network, and must not
+//! hold up the handshakes of everyone else by sending their requests slowly.
+//! So each of their connections is served on a thread of its own, at most
+//! [MAX_CONNECTIONS] of them at once for each listener, and within one
+//! [Deadline] for the whole request. Only requests read in full are handed
+//! to the event loop, which answers them without waiting on the client; see
+//! [Connections].
+
+use anyhow::Result;
+use log::{debug, warn};
+use std::{
+    io::{self, ErrorKind, Read, Write},
+    net::{SocketAddr, TcpStream},
+    os::fd::{FromRawFd, IntoRawFd},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Most connections served at once for each listener; further ones are
+/// closed right away
+pub const MAX_CONNECTIONS: usize = 16;
+
+/// Listeners of mio whose connections are served blocking
+pub trait Listener {
+    /// The connections as mio hands them out
+    type Accepted: IntoRawFd;
+    /// The same kind of socket, served blocking
+    type Stream: FromRawFd;
+
+    fn accept_one(&self) -> io::Result<Self::Accepted>;
+    fn set_timeouts(stream: &Self::Stream, timeout: Duration) -> io::Result<()>;
+}
+
+impl Listener for mio::net::TcpListener {
+    type Accepted = mio::net::TcpStream;
+    type Stream = std::net::TcpStream;
+
+    fn accept_one(&self) -> io::Result<Self::Accepted> {
+        self.accept().map(|(stream, _)| stream)
+    }
+
+    fn set_timeouts(stream: &Self::Stream, timeout: Duration) -> io::Result<()> {
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))
+    }
+}
+
+impl Listener for mio::net::UnixListener {
+    type Accepted = mio::net::UnixStream;
+    type Stream = std::os::unix::net::UnixStream;
+
+    fn accept_one(&self) -> io::Result<Self::Accepted> {
+        self.accept().map(|(stream, _)| stream)
+    }
+
+    fn set_timeouts(stream: &Self::Stream, timeout: Duration) -> io::Result<()> {
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))
+    }
+}
+
+/// Accept a pending connection of `listener`, if any
+///
+/// The returned stream is blocking with `timeout` applied to every read and
+/// write.
+pub fn accept<L: Listener>(listener: &L, timeout: Duration) -> Result<Option<L::Stream>> {
+    let accepted = match listener.accept_one() {
+        Ok(accepted) => accepted,
+        Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    // SAFETY: mio offers no way to switch a stream to blocking mode, so we go
+    // through the raw fd. `into_raw_fd` hands over the open socket, which the
+    // std stream takes ownership of, so the fd is moved rather than shared or
+    // closed twice; [Listener] ties both to the same kind of socket.
+    let stream = unsafe { L::Stream::from_raw_fd(accepted.into_raw_fd()) };
+    L::set_timeouts(&stream, timeout)?;
+    Ok(Some(stream))
+}
+
+/// A connection whose reads and writes all have to be done by one point in
+/// time, so sending a request byte by byte takes no longer than sending it
+/// at once
+#[derive(Debug)]
+pub struct Deadline {
+    stream: TcpStream,
+    within: Duration,
+    until: Instant,
+}
+
+impl Deadline {
+    pub fn new(stream: TcpStream, within: Duration) -> Self {
+        let until = Instant::now() + within;
+        Self {
+            stream,
+            within,
+            until,
+        }
+    }
+
+    /// Start over with the time given, like for sending the reply
+    pub fn renew(&mut self) {
+        self.until = Instant::now() + self.within;
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.peer_addr()
+    }
+
+    fn left(&self) -> io::Result<Duration> {
+        match self.until.checked_duration_since(Instant::now()) {
+            Some(left) if !left.is_zero() => Ok(left),
+            _ => Err(io::Error::new(
+                ErrorKind::TimedOut,
+                "the client took too long",
+            )),
+        }
+    }
+}
+
+impl Read for Deadline {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.set_read_timeout(Some(self.left()?))?;
+        self.stream.read(buf)
+    }
+}
+
+impl Write for Deadline {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.set_write_timeout(Some(self.left()?))?;
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// A request read in full, for the event loop to answer
+#[derive(Debug)]
+pub struct Pending<Req, Rep> {
+    pub request: Req,
+    reply: Sender<Rep>,
+}
+
+impl<Req, Rep> Pending<Req, Rep> {
+    /// Hand `reply` to the thread serving the client
+    pub fn answer(self, reply: Rep) {
+        // the client may have given up already
+        let _ = self.reply.send(reply);
+    }
+}
+
+/// How the thread serving a client asks the event loop
+#[derive(Debug)]
+pub struct EventLoop<Req, Rep> {
+    tx: Sender<Pending<Req, Rep>>,
+    waker: Arc<mio::Waker>,
+}
+
+impl<Req, Rep> EventLoop<Req, Rep> {
+    /// Have the event loop answer `request`; [None] if it is gone
+    pub fn ask(&self, request: Req) -> Option<Rep> {
+        let (reply, rx) = mpsc::channel();
+        self.tx.send(Pending { request, reply }).ok()?;
+        let _ = self.waker.wake();
+        rx.recv().ok()
+    }
+}
+
+/// The clients of one listener, each served on a thread of its own
+///
+/// The event loop accepts the connections, hands them to [Self::serve] and
+/// answers whatever their threads ask it, see [Self::try_next].
+#[derive(Debug)]
+pub struct Connections<Req, Rep> {
+    what: &'static str,
+    within: Duration,
+    active: Arc<AtomicUsize>,
+    event_loop: EventLoop<Req, Rep>,
+    rx: Receiver<Pending<Req, Rep>>,
+}
+
+impl<Req: Send + 'static, Rep: Send + 'static> Connections<Req, Rep> {
+    /// Clients of `what`, whose requests take at most `within` to send;
+    /// `waker` wakes the event loop once any of them asks something
+    pub fn new(what: &'static str, within: Duration, waker: Arc<mio::Waker>) -> Self {
+        let (tx, rx) = mpsc::channel();
+        Self {
+            what,
+            within,
+            active: Arc::new(AtomicUsize::new(0)),
+            event_loop: EventLoop { tx, waker },
+            rx,
+        }
+    }
+
+    /// Have `f` serve the client of `stream` on a thread of its own, unless
+    /// [MAX_CONNECTIONS] are served already, in which case the connection is
+    /// closed right away
+    pub fn serve<F>(&self, stream: TcpStream, f: F)
+    where
+        F: FnOnce(&mut Deadline, &EventLoop<Req, Rep>) -> Result<()> + Send + 'static,
+    {
+        let addr = stream
+            .peer_addr()
+            .map_or_else(|_| "a client".to_string(), |addr| addr.to_string());
+        if self.active.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+            self.active.fetch_sub(1, Ordering::SeqCst);
+            debug!("Too many {}s, closing the connection of {addr}", self.what);
+            return;
+        }
+        let active = self.active.clone();
+        let event_loop = EventLoop {
+            tx: self.event_loop.tx.clone(),
+            waker: self.event_loop.waker.clone(),
+        };
+        let (what, mut stream) = (self.what, Deadline::new(stream, self.within));
+        let spawned = thread::Builder::new()
+            .name(format!("rosenpass-{}", what.replace(' ', "-")))
+            .spawn(move || {
+                if let Err(e) = f(&mut stream, &event_loop) {
+                    warn!("Could not serve the {what} of {addr}: {e:#}");
+                }
+                active.fetch_sub(1, Ordering::SeqCst);
+            });
+        if let Err(e) = spawned {
+            self.active.fetch_sub(1, Ordering::SeqCst);
+            warn!("Could not start a thread for the {what}: {e}");
+        }
+    }
+
+    /// A request a client is waiting for the answer to, if any
+    pub fn try_next(&self) -> Option<Pending<Req, Rep>> {
+        self.rx.try_recv().ok()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::TcpListener;
+
+    fn pair(listener: &TcpListener) -> (TcpStream, TcpStream) {
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        (client, listener.accept().unwrap().0)
+    }
+
+    #[test]
+    fn requests_have_one_deadline() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (mut client, server) = pair(&listener);
+        // a byte well within the timeout of each read, for far longer in all
+        let trickle = thread::spawn(move || {
+            for _ in 0..40 {
+                if client.write_all(b"x").is_err() {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(25));
+            }
+        });
+        let start = Instant::now();
+        let mut stream = Deadline::new(server, Duration::from_millis(200));
+        let e = stream.read_to_end(&mut vec![]).unwrap_err();
+        assert!(matches!(
+            e.kind(),
+            ErrorKind::TimedOut | ErrorKind::WouldBlock
+        ));
+        assert!(start.elapsed() < Duration::from_millis(600));
+        drop(stream);
+        trickle.join().unwrap();
+    }
+
+    #[test]
+    fn connections_are_capped() {
+        let poll = mio::Poll::new().unwrap();
+        let waker = Arc::new(mio::Waker::new(poll.registry(), mio::Token(0)).unwrap());
+        let conns = Connections::<u8, u8>::new("test", Duration::from_secs(5), waker);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let mut clients = vec![];
+        for _ in 0..MAX_CONNECTIONS {
+            let (client, server) = pair(&listener);
+            conns.serve(server, |stream, event_loop| {
+                let mut req = [0u8];
+                stream.read_exact(&mut req)?;
+                let rep = event_loop.ask(req[0]).unwrap();
+                Ok(stream.write_all(&[rep])?)
+            });
+            clients.push(client);
+        }
+        // one more is closed right away
+        let (mut late, server) = pair(&listener);
+        conns.serve(server, |_, _| unreachable!());
+        assert_eq!(late.read(&mut [0u8]).unwrap(), 0);
+
+        // the others are served once their requests are answered
+        for (i, client) in clients.iter_mut().enumerate() {
+            client.write_all(&[i as u8]).unwrap();
+        }
+        for _ in 0..MAX_CONNECTIONS {
+            let pending = loop {
+                match conns.try_next() {
+                    Some(pending) => break pending,
+                    None => thread::sleep(Duration::from_millis(5)),
+                }
+            };
+            let rep = pending.request + 1;
+            pending.answer(rep);
+        }
+        for (i, client) in clients.iter_mut().enumerate() {
+            let mut rep = [0u8];
+            client.read_exact(&mut rep).unwrap();
+            assert_eq!(rep[0], i as u8 + 1);
+        }
+    }
+}
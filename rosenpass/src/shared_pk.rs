@@ -0,0 +1,235 @@
+//! Public keys of peers in shared, read-only memory
+//!
+//! A Classic McEliece public key is over 500 KB large. Kept in a
+//! [Secret](crate::coloring::Secret), every peer takes up that much locked
+//! memory, and every replica of the peers held by a handshake worker (see
+//! [crate::workers]) or another instance of the [Supervisor](crate::supervisor::Supervisor)
+//! takes up the same again. Public keys need neither locking nor zeroizing,
+//! so the keys of peers are [SharedPk]s instead: a key is written to a
+//! mapping of its own once, which is then made read-only, and all copies of
+//! the same key in the process refer to that one mapping. It is unmapped
+//! once the last of them is dropped.
+//!
+//! The keys are copied from their files rather than mapping those, so a key
+//! file which is rewritten or truncated later does not change the key in
+//! use, nor fault reading it. Keys may also be loaded from their files only
+//! when needed, see [crate::lazy_keys].
+
+use anyhow::{ensure, Result};
+use rosenpass_util::file::LoadValue;
+use std::{
+    collections::hash_map::DefaultHasher,
+    collections::HashMap,
+    fmt,
+    hash::{Hash, Hasher},
+    path::{Path, PathBuf},
+    ptr::null_mut,
+    sync::{Arc, Mutex, Weak},
+};
+
+use crate::{
+    pqkem::{StaticKEM, KEM},
+    protocol::{peer_id, PeerId, SPk},
+};
+
+/// Length of the keys
+pub const LEN: usize = StaticKEM::PK_LEN;
+
+/// The mappings of the keys in use, by a hash of the key
+static KEYS: Mutex<Option<HashMap<u64, Vec<Weak<Mapping>>>>> = Mutex::new(None);
+
+/// A read-only mapping of [LEN] bytes
+struct Mapping {
+    ptr: *const u8,
+}
+
+// The mapping is never written to after it was made read-only, and only
+// unmapped once no reference to it is left
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl Mapping {
+    fn new(pk: &[u8; LEN]) -> Self {
+        use libc::{MAP_ANONYMOUS, MAP_FAILED, MAP_PRIVATE, PROT_READ, PROT_WRITE};
+        let ptr = unsafe {
+            libc::mmap(
+                null_mut(),
+                LEN,
+                PROT_READ | PROT_WRITE,
+                MAP_PRIVATE | MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        // like failing to allocate on the heap
+        assert!(ptr != MAP_FAILED, "could not map memory for a public key");
+        unsafe {
+            std::ptr::copy_nonoverlapping(pk.as_ptr(), ptr as *mut u8, LEN);
+            assert_eq!(libc::mprotect(ptr, LEN, PROT_READ), 0);
+        }
+        Self {
+            ptr: ptr as *const u8,
+        }
+    }
+
+    fn value(&self) -> &[u8; LEN] {
+        // the mapping is LEN bytes and lives as long as self
+        unsafe { &*(self.ptr as *const [u8; LEN]) }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr as *mut libc::c_void, LEN) };
+    }
+}
+
+/// Where a key loaded on demand is read from
+#[derive(Debug)]
+struct Source {
+    path: PathBuf,
+    id: PeerId,
+}
+
+/// The public key of a peer, shared by all its copies in the process
+#[derive(Clone)]
+pub struct SharedPk {
+    /// The key, unless it is loaded on demand and is not loaded
+    key: Option<Arc<Mapping>>,
+    source: Option<Arc<Source>>,
+}
+
+impl SharedPk {
+    pub fn new(pk: &[u8; LEN]) -> Self {
+        Self {
+            key: Some(Self::mapping(pk)),
+            source: None,
+        }
+    }
+
+    /// A key only read from `path` once [SharedPk::load] is called; `id` is
+    /// its peer id
+    pub fn lazy(path: &Path, id: PeerId) -> Self {
+        let path = path.to_owned();
+        Self {
+            key: None,
+            source: Some(Arc::new(Source { path, id })),
+        }
+    }
+
+    /// The mapping of `pk`, shared with the other copies of it
+    fn mapping(pk: &[u8; LEN]) -> Arc<Mapping> {
+        let mut hasher = DefaultHasher::new();
+        pk.hash(&mut hasher);
+        let mut keys = KEYS.lock().unwrap();
+        let same = keys
+            .get_or_insert_with(HashMap::new)
+            .entry(hasher.finish())
+            .or_default();
+        same.retain(|m| m.strong_count() > 0);
+        if let Some(mapping) = same
+            .iter()
+            .filter_map(Weak::upgrade)
+            .find(|m| m.value() == pk)
+        {
+            return mapping;
+        }
+        let mapping = Arc::new(Mapping::new(pk));
+        same.push(Arc::downgrade(&mapping));
+        mapping
+    }
+
+    pub fn zero() -> Self {
+        Self::new(&[0u8; LEN])
+    }
+
+    /// Borrows the key; keys loaded on demand must be loaded
+    pub fn value(&self) -> &[u8; LEN] {
+        match self.key.as_ref() {
+            Some(key) => key.value(),
+            None => panic!("public key {:?} is used before it was loaded", self),
+        }
+    }
+
+    /// The peer id of a key loaded on demand, known without loading it
+    pub fn id(&self) -> Option<&PeerId> {
+        self.source.as_ref().map(|s| &s.id)
+    }
+
+    pub fn is_lazy(&self) -> bool {
+        self.source.is_some()
+    }
+
+    pub fn is_loaded(&self) -> bool {
+        self.key.is_some()
+    }
+
+    /// Read a key loaded on demand from its file, if it is not loaded; fails
+    /// if the file holds another key by now
+    pub fn load(&mut self) -> Result<()> {
+        let Some(source) = self.source.as_ref().filter(|_| self.key.is_none()) else {
+            return Ok(());
+        };
+        let key = Self::new(SPk::load(&source.path)?.secret());
+        ensure!(
+            peer_id(&key)? == source.id,
+            "public key {:?} changed since it was first read",
+            source.path
+        );
+        self.key = key.key;
+        Ok(())
+    }
+
+    /// Let go of a key loaded on demand until it is loaded again
+    pub fn unload(&mut self) {
+        if self.source.is_some() {
+            self.key = None;
+        }
+    }
+
+    /// How many copies of the key there are in the process
+    pub fn copies(&self) -> usize {
+        self.key.as_ref().map_or(0, Arc::strong_count)
+    }
+}
+
+impl From<&SPk> for SharedPk {
+    fn from(pk: &SPk) -> Self {
+        Self::new(pk.secret())
+    }
+}
+
+impl From<SPk> for SharedPk {
+    fn from(pk: SPk) -> Self {
+        Self::from(&pk)
+    }
+}
+
+impl fmt::Debug for SharedPk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.key.as_ref(), self.source.as_ref()) {
+            (_, Some(source)) => write!(f, "SharedPk({:?})", source.path),
+            (Some(key), None) => write!(f, "SharedPk({:p})", key.ptr),
+            (None, None) => unreachable!(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn copies_share_a_mapping() {
+        let pk = SPk::random();
+        let (a, b) = (SharedPk::from(&pk), SharedPk::from(&pk));
+        assert_eq!(a.value(), pk.secret());
+        assert_eq!(a.value().as_ptr(), b.value().as_ptr());
+        assert_eq!(a.copies(), 2);
+
+        let other = SharedPk::from(SPk::random());
+        assert_ne!(a.value().as_ptr(), other.value().as_ptr());
+        drop(b);
+        assert_eq!(a.copies(), 1);
+    }
+}
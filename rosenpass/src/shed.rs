@@ -0,0 +1,131 @@
+//! Shedding new handshakes under load
+//!
+//! An attack or a flash crowd of new peers can take up all the CPU time
+//! there is for handshakes, and then the rekeys of peers whose tunnels are
+//! up run late or not at all. With load shedding, rosenpass turns away new
+//! handshakes first:
+//!
+//! ```toml
+//! [load_shedding]
+//! cpu = 0.8 # share of the handshake threads' time in use
+//! ```
+//!
+//! The CPU time of the process is compared to the time of the event loop
+//! and the handshake workers (see [crate::workers]) in windows of [WINDOW]
+//! seconds; rosenpass is under load from a window using more than `cpu` of
+//! it until a window using less. Under load, InitHello messages are only
+//! answered if the peer last seen at their address has a session, and we
+//! only initiate handshakes with peers which have one; other initiations are
+//! deferred by [RETRY_INTERVAL] seconds. Rekeys, retransmissions and the rest
+//! of handshakes in progress go ahead, so new connections are delayed while
+//! existing tunnels stay up.
+//!
+//! Shed InitHellos and initiations are counted in the statistics on the
+//! control socket and as `rosenpass_shed_total` for Prometheus;
+//! `rosenpass_under_load` tells whether rosenpass is under load.
+
+use anyhow::{ensure, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::Timing;
+
+/// Seconds of each window the load is measured over
+pub const WINDOW: Timing = 1.0;
+
+/// Seconds after which an initiation that was shed is tried again
+pub const RETRY_INTERVAL: Timing = 1.0;
+
+/// The `[load_shedding]` section of the config
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct LoadShedding {
+    /// Share of the time of the threads doing handshakes above which
+    /// rosenpass is under load
+    pub cpu: f64,
+}
+
+impl LoadShedding {
+    pub fn validate(&self) -> Result<()> {
+        ensure!(
+            self.cpu > 0.0 && self.cpu <= 1.0,
+            "load_shedding.cpu must be above 0 and at most 1"
+        );
+        Ok(())
+    }
+}
+
+/// What was shed so far
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShedCounts {
+    /// InitHello messages which were dropped
+    pub responses: u64,
+    /// Initiations which were deferred
+    pub initiations: u64,
+    pub under_load: bool,
+}
+
+/// Tells whether rosenpass is under load
+#[derive(Debug)]
+pub struct Shedder {
+    config: LoadShedding,
+    /// When the current window started, and the CPU time used up to then
+    window: Option<(Timing, f64)>,
+    pub counts: ShedCounts,
+}
+
+impl Shedder {
+    pub fn new(config: LoadShedding) -> Self {
+        Self {
+            config,
+            window: None,
+            counts: ShedCounts::default(),
+        }
+    }
+
+    /// Whether rosenpass is under load at `now`, with `threads` threads
+    /// doing handshakes
+    pub fn under_load(&mut self, now: Timing, threads: usize) -> bool {
+        self.update(now, cpu_time(), threads)
+    }
+
+    /// [Self::under_load] with `cpu` seconds of CPU time used so far
+    fn update(&mut self, now: Timing, cpu: f64, threads: usize) -> bool {
+        match self.window {
+            None => self.window = Some((now, cpu)),
+            Some((start, used)) if now - start >= WINDOW => {
+                let share = (cpu - used) / ((now - start) * threads as f64);
+                self.counts.under_load = share > self.config.cpu;
+                self.window = Some((now, cpu));
+            }
+            Some(_) => {}
+        }
+        self.counts.under_load
+    }
+}
+
+/// Seconds of CPU time used by all threads of the process
+fn cpu_time() -> f64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // can not fail with this clock and a valid pointer
+    unsafe { libc::clock_gettime(libc::CLOCK_PROCESS_CPUTIME_ID, &mut ts) };
+    ts.tv_sec as f64 + ts.tv_nsec as f64 * 1e-9
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn load_is_measured_per_window() {
+        let mut shedder = Shedder::new(LoadShedding { cpu: 0.5 });
+        assert!(!shedder.update(0.0, 0.0, 2));
+        // windows only end after WINDOW seconds
+        assert!(!shedder.update(0.5, 1.0, 2));
+        assert!(shedder.update(1.0, 1.2, 2));
+        assert!(shedder.update(1.5, 1.2, 2));
+        assert!(!shedder.update(2.0, 1.9, 2));
+        assert!(cpu_time() > 0.0);
+    }
+}
@@ -0,0 +1,250 @@
+//! Options of the sockets handshakes go through
+//!
+//! When the default route points into the WireGuard interface rosenpass
+//! keys, handshakes would be routed into the tunnel which needs them to come
+//! up. `fwmark` marks all handshake sockets with `SO_MARK`, so policy routing
+//! can send their traffic around the tunnel, like WireGuard does with its
+//! own `FwMark`:
+//!
+//! ```text
+//! fwmark = 0x51820
+//! ```
+//!
+//! ```text
+//! ip rule add not fwmark 0x51820 table 51820
+//! ```
+//!
+//! Marking sockets takes `CAP_NET_ADMIN` and is only supported on Linux.
+//!
+//! On saturated links, networks with QoS can put handshakes ahead of bulk
+//! traffic so keys keep being renewed, if they are told apart by their DSCP.
+//! `dscp` sets it on all handshake datagrams, as `IP_TOS` on IPv4 and
+//! `IPV6_TCLASS` on IPv6:
+//!
+//! ```text
+//! dscp = 46 # expedited forwarding
+//! ```
+//!
+//! A responder hit by a burst of handshakes, say when many peers come back
+//! at once, drops the datagrams its receive buffer has no room for. The
+//! default size is set by the `net.core.rmem_default` sysctl, and anything
+//! asked for above `net.core.rmem_max` is cut down to it. `recv_buffer` and
+//! `send_buffer` size the buffers of the handshake sockets, in bytes:
+//!
+//! ```text
+//! recv_buffer = 4194304
+//! ```
+//!
+//! With `CAP_NET_ADMIN` on Linux, the sizes are set regardless of the
+//! sysctls. The sizes the kernel settled on are logged for every socket,
+//! with a warning if they are smaller than asked for. Linux reports twice
+//! the size asked for, as it counts its own bookkeeping in.
+
+use anyhow::{bail, ensure, Result};
+use log::{info, warn};
+use std::{io, os::fd::AsRawFd};
+
+/// Largest valid DSCP, which has six bits
+pub const MAX_DSCP: u8 = 63;
+
+/// Largest valid socket buffer size; the kernel doubles it and takes an `int`
+pub const MAX_BUFFER: usize = i32::MAX as usize / 2;
+
+/// The options set on every handshake socket, including those opened later
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SocketOptions {
+    /// Firewall mark of the sockets
+    pub fwmark: Option<u32>,
+    /// Differentiated services code point of the datagrams sent
+    pub dscp: Option<u8>,
+    /// Size of the receive buffers in bytes
+    pub recv_buffer: Option<usize>,
+    /// Size of the send buffers in bytes
+    pub send_buffer: Option<usize>,
+}
+
+/// Which buffer of a socket to size
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Buffer {
+    Recv,
+    Send,
+}
+
+impl Buffer {
+    fn name(self) -> &'static str {
+        match self {
+            Buffer::Recv => "receive",
+            Buffer::Send => "send",
+        }
+    }
+
+    /// The sysctl limiting the size
+    fn sysctl(self) -> &'static str {
+        match self {
+            Buffer::Recv => "net.core.rmem_max",
+            Buffer::Send => "net.core.wmem_max",
+        }
+    }
+
+    fn opt(self) -> libc::c_int {
+        match self {
+            Buffer::Recv => libc::SO_RCVBUF,
+            Buffer::Send => libc::SO_SNDBUF,
+        }
+    }
+
+    /// The option which ignores the sysctl limits, given `CAP_NET_ADMIN`
+    #[cfg(target_os = "linux")]
+    fn force_opt(self) -> Option<libc::c_int> {
+        match self {
+            Buffer::Recv => Some(libc::SO_RCVBUFFORCE),
+            Buffer::Send => Some(libc::SO_SNDBUFFORCE),
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn force_opt(self) -> Option<libc::c_int> {
+        None
+    }
+}
+
+impl SocketOptions {
+    pub fn apply(&self, sock: &mio::net::UdpSocket) -> Result<()> {
+        if let Some(mark) = self.fwmark {
+            set_mark(sock, mark)?;
+        }
+        if let Some(dscp) = self.dscp {
+            set_dscp(sock, dscp)?;
+        }
+        for (buffer, size) in [
+            (Buffer::Recv, self.recv_buffer),
+            (Buffer::Send, self.send_buffer),
+        ] {
+            let Some(size) = size else { continue };
+            let effective = set_buffer(sock, buffer, size)?;
+            let addr = sock.local_addr()?;
+            match effective < size {
+                true => warn!(
+                    "{} buffer of {addr} is {effective} bytes, less than the {size} asked for; \
+                    raise {} or grant CAP_NET_ADMIN",
+                    buffer.name(),
+                    buffer.sysctl()
+                ),
+                false => info!("{} buffer of {addr} is {effective} bytes", buffer.name()),
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn setsockopt<T>(
+    sock: &impl AsRawFd,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: &T,
+) -> io::Result<()> {
+    let res = unsafe {
+        libc::setsockopt(
+            sock.as_raw_fd(),
+            level,
+            name,
+            value as *const T as *const libc::c_void,
+            std::mem::size_of::<T>() as libc::socklen_t,
+        )
+    };
+    match res {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+pub fn getsockopt(
+    sock: &impl AsRawFd,
+    level: libc::c_int,
+    name: libc::c_int,
+) -> io::Result<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(
+            sock.as_raw_fd(),
+            level,
+            name,
+            &mut value as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    match res {
+        0 => Ok(value),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// The size of the `buffer` of `sock` in bytes, as reported by the kernel
+pub fn buffer_size(sock: &impl AsRawFd, buffer: Buffer) -> Result<usize> {
+    match getsockopt(sock, libc::SOL_SOCKET, buffer.opt()) {
+        Ok(size) => Ok(size.max(0) as usize),
+        Err(e) => bail!(
+            "could not read the {} buffer size of a socket: {e}",
+            buffer.name()
+        ),
+    }
+}
+
+/// Ask for the `buffer` of `sock` to have `size` bytes, bypassing the sysctl
+/// limits where permitted; returns the size the kernel settled on
+pub fn set_buffer(sock: &impl AsRawFd, buffer: Buffer, size: usize) -> Result<usize> {
+    ensure!(
+        size <= MAX_BUFFER,
+        "{} buffer size must be at most {MAX_BUFFER}",
+        buffer.name()
+    );
+    let value = size as libc::c_int;
+    let forced = buffer
+        .force_opt()
+        .is_some_and(|opt| setsockopt(sock, libc::SOL_SOCKET, opt, &value).is_ok());
+    if !forced {
+        if let Err(e) = setsockopt(sock, libc::SOL_SOCKET, buffer.opt(), &value) {
+            bail!(
+                "could not set the {} buffer size {size} on a socket: {e}",
+                buffer.name()
+            );
+        }
+    }
+    buffer_size(sock, buffer)
+}
+
+/// Send the datagrams of `sock` with the code point `dscp`
+pub fn set_dscp(sock: &mio::net::UdpSocket, dscp: u8) -> Result<()> {
+    ensure!(dscp <= MAX_DSCP, "dscp must be at most {MAX_DSCP}");
+    // the lower two bits of the field are for ECN
+    let tos = (dscp as libc::c_int) << 2;
+    let res = match sock.local_addr()? {
+        std::net::SocketAddr::V4(_) => setsockopt(sock, libc::IPPROTO_IP, libc::IP_TOS, &tos),
+        std::net::SocketAddr::V6(_) => {
+            let res = setsockopt(sock, libc::IPPROTO_IPV6, libc::IPV6_TCLASS, &tos);
+            // for the IPv4 datagrams of a dual stack socket, where supported
+            let _ = setsockopt(sock, libc::IPPROTO_IP, libc::IP_TOS, &tos);
+            res
+        }
+    };
+    if let Err(e) = res {
+        bail!("could not set dscp {dscp} on a socket: {e}");
+    }
+    Ok(())
+}
+
+/// Mark the traffic of `sock` with `mark`
+#[cfg(target_os = "linux")]
+pub fn set_mark(sock: &impl AsRawFd, mark: u32) -> Result<()> {
+    if let Err(e) = setsockopt(sock, libc::SOL_SOCKET, libc::SO_MARK, &mark) {
+        bail!("could not set fwmark {mark:#x} on a socket: {e}");
+    }
+    Ok(())
+}
+
+/// Mark the traffic of `sock` with `mark`
+#[cfg(not(target_os = "linux"))]
+pub fn set_mark(_sock: &impl AsRawFd, mark: u32) -> Result<()> {
+    bail!("can not set fwmark {mark:#x}: only supported on Linux")
+}
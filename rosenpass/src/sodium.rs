@@ -2,6 +2,7 @@
 
 use anyhow::{ensure, Result};
 use libsodium_sys as libsodium;
+use rosenpass_ciphers::fips;
 use rosenpass_constant_time::xor_into;
 use rosenpass_util::attempt;
 use static_assertions::const_assert_eq;
@@ -19,6 +20,7 @@ const_assert_eq!(
     libsodium::crypto_aead_chacha20poly1305_IETF_KEYBYTES as usize
 );
 const_assert_eq!(KEY_SIZE, libsodium::crypto_generichash_BYTES as usize);
+const_assert_eq!(KEY_SIZE, HMAC_SHA256_SIZE);
 
 macro_rules! sodium_call {
     ($name:ident, $($args:expr),*) => { attempt!({
@@ -89,11 +91,17 @@ pub fn mac16(key: &[u8], data: &[u8]) -> Result<[u8; 16]> {
     Ok(out)
 }
 
+/// HMAC based on keyed BLAKE2b, or HMAC-SHA256 in FIPS-constrained mode,
+/// see [rosenpass_ciphers::fips]
 #[inline]
 pub fn hmac_into(out: &mut [u8], key: &[u8], data: &[u8]) -> Result<()> {
     // Not bothering with padding; the implementation
     // uses appropriately sized keys.
     ensure!(key.len() == KEY_SIZE);
+    if fips::enabled() {
+        out.copy_from_slice(&hmac_sha256(key, data)?);
+        return Ok(());
+    }
 
     const IPAD: [u8; KEY_SIZE] = [0x36u8; KEY_SIZE];
     let mut temp_key = [0u8; KEY_SIZE];
@@ -113,3 +121,135 @@ pub fn hmac(key: &[u8], data: &[u8]) -> Result<[u8; KEY_SIZE]> {
     hmac_into(&mut r, key, data)?;
     Ok(r)
 }
+
+pub const SIGN_PK_SIZE: usize = libsodium::crypto_sign_PUBLICKEYBYTES as usize;
+pub const SIGN_SK_SIZE: usize = libsodium::crypto_sign_SECRETKEYBYTES as usize;
+pub const SIGNATURE_SIZE: usize = libsodium::crypto_sign_BYTES as usize;
+
+/// Generate an Ed25519 key pair
+#[inline]
+pub fn sign_keypair(pk: &mut [u8], sk: &mut [u8]) -> Result<()> {
+    assert!(pk.len() == SIGN_PK_SIZE);
+    assert!(sk.len() == SIGN_SK_SIZE);
+    sodium_call!(crypto_sign_keypair, pk.as_mut_ptr(), sk.as_mut_ptr())
+}
+
+#[inline]
+pub fn sign_detached(sig: &mut [u8], msg: &[u8], sk: &[u8]) -> Result<()> {
+    assert!(sig.len() == SIGNATURE_SIZE);
+    assert!(sk.len() == SIGN_SK_SIZE);
+    sodium_call!(
+        crypto_sign_detached,
+        sig.as_mut_ptr(),
+        std::ptr::null_mut(),
+        msg.as_ptr(),
+        msg.len() as c_ulonglong,
+        sk.as_ptr()
+    )
+}
+
+/// Whether `sig` is a valid signature of `msg` by the owner of `pk`
+#[inline]
+pub fn sign_verify_detached(sig: &[u8], msg: &[u8], pk: &[u8]) -> bool {
+    sig.len() == SIGNATURE_SIZE
+        && pk.len() == SIGN_PK_SIZE
+        && unsafe {
+            libsodium::crypto_sign_verify_detached(
+                sig.as_ptr(),
+                msg.as_ptr(),
+                msg.len() as c_ulonglong,
+                pk.as_ptr(),
+            )
+        } == 0
+}
+
+pub const HMAC_SHA256_SIZE: usize = libsodium::crypto_auth_hmacsha256_BYTES as usize;
+
+/// HMAC-SHA256 with a key of any length, for talking to systems other than
+/// rosenpass peers
+#[inline]
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> Result<[u8; HMAC_SHA256_SIZE]> {
+    let mut out = [0u8; HMAC_SHA256_SIZE];
+    let mut state = std::mem::MaybeUninit::<libsodium::crypto_auth_hmacsha256_state>::uninit();
+    sodium_call!(
+        crypto_auth_hmacsha256_init,
+        state.as_mut_ptr(),
+        key.as_ptr(),
+        key.len()
+    )?;
+    sodium_call!(
+        crypto_auth_hmacsha256_update,
+        state.as_mut_ptr(),
+        data.as_ptr(),
+        data.len() as c_ulonglong
+    )?;
+    sodium_call!(
+        crypto_auth_hmacsha256_final,
+        state.as_mut_ptr(),
+        out.as_mut_ptr()
+    )?;
+    Ok(out)
+}
+
+pub const SHA256_SIZE: usize = libsodium::crypto_hash_sha256_BYTES as usize;
+
+/// SHA-256, for talking to systems other than rosenpass peers
+#[inline]
+pub fn sha256(data: &[u8]) -> Result<[u8; SHA256_SIZE]> {
+    let mut out = [0u8; SHA256_SIZE];
+    sodium_call!(
+        crypto_hash_sha256,
+        out.as_mut_ptr(),
+        data.as_ptr(),
+        data.len() as c_ulonglong
+    )?;
+    Ok(out)
+}
+
+pub const X25519_SIZE: usize = libsodium::crypto_scalarmult_BYTES as usize;
+
+/// A fresh X25519 key pair, secret key first
+#[inline]
+pub fn x25519_keypair() -> Result<([u8; X25519_SIZE], [u8; X25519_SIZE])> {
+    let mut sk = [0u8; X25519_SIZE];
+    let mut pk = [0u8; X25519_SIZE];
+    rosenpass_sodium::helpers::randombytes_buf(&mut sk);
+    sodium_call!(crypto_scalarmult_base, pk.as_mut_ptr(), sk.as_ptr())?;
+    Ok((sk, pk))
+}
+
+/// The X25519 shared secret of `sk` and `pk`; fails for public keys of low
+/// order
+#[inline]
+pub fn x25519(sk: &[u8; X25519_SIZE], pk: &[u8]) -> Result<[u8; X25519_SIZE]> {
+    ensure!(pk.len() == X25519_SIZE, "X25519 public keys are 32 bytes");
+    let mut shared = [0u8; X25519_SIZE];
+    sodium_call!(
+        crypto_scalarmult,
+        shared.as_mut_ptr(),
+        sk.as_ptr(),
+        pk.as_ptr()
+    )?;
+    Ok(shared)
+}
+
+/// XOR `buf` with the ChaCha20 key stream of `key` and `nonce`, starting at
+/// block `counter`
+#[inline]
+pub fn chacha20_xor(
+    buf: &mut [u8],
+    key: &[u8; KEY_SIZE],
+    nonce: &[u8; 12],
+    counter: u32,
+) -> Result<()> {
+    let input = buf.to_vec();
+    sodium_call!(
+        crypto_stream_chacha20_ietf_xor_ic,
+        buf.as_mut_ptr(),
+        input.as_ptr(),
+        input.len() as c_ulonglong,
+        nonce.as_ptr(),
+        counter,
+        key.as_ptr()
+    )
+}
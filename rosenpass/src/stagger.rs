@@ -0,0 +1,62 @@
+//! Spreading out the first handshakes with many peers
+//!
+//! Right after starting, and after the system resumed from suspend, every
+//! peer is due for a handshake at once. With hundreds of peers, the burst of
+//! large InitHellos overflows socket buffers and NAT tables along the way,
+//! and the handshakes lost to it are only retried after seconds, so the
+//! last peers take long to get a key. Instead, the handshake with each peer
+//! starts at a random point in a window whose length grows with the number
+//! of peers, so at most about [RATE] handshakes are started every second:
+//!
+//! ```toml
+//! startup_jitter = 5
+//! ```
+//!
+//! sets the window to five seconds instead; zero starts all handshakes at
+//! once, like before. Only our own initiations are delayed; handshakes the
+//! peers start are answered right away.
+
+use anyhow::{ensure, Result};
+use rosenpass_sodium::helpers::rand_f64;
+
+use crate::protocol::Timing;
+
+/// Handshakes started every second on average, by default
+pub const RATE: f64 = 100.0;
+
+/// The longest window; handshakes should be done well before a rekey is due
+pub const MAX_JITTER: Timing = 60.0;
+
+pub fn validate(jitter: Timing) -> Result<()> {
+    ensure!(
+        (0.0..=MAX_JITTER).contains(&jitter),
+        "startup_jitter must be between 0 and {MAX_JITTER} seconds"
+    );
+    Ok(())
+}
+
+/// The window the handshakes with `peers` peers are spread over, unless
+/// `configured`
+pub fn window(configured: Option<Timing>, peers: usize) -> Timing {
+    configured.unwrap_or_else(|| (peers as f64 / RATE).min(MAX_JITTER))
+}
+
+/// A random delay within `window`
+pub fn delay(window: Timing) -> Timing {
+    window * rand_f64()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn window_grows_with_the_peers() {
+        assert_eq!(window(None, 1), 0.01);
+        assert_eq!(window(None, 500), 5.0);
+        assert_eq!(window(None, 100_000), MAX_JITTER);
+        assert_eq!(window(Some(0.0), 500), 0.0);
+        assert!((0..100).all(|_| (0.0..=2.0).contains(&delay(2.0))));
+        assert!(validate(MAX_JITTER + 1.0).is_err());
+    }
+}
@@ -0,0 +1,86 @@
+//! What becomes of a key which was not renewed in time
+//!
+//! A key is renewed every [CryptoServer::rekey_after] seconds and rejected
+//! [REJECT_AFTER_TIME] seconds after it was exchanged. By default, the key
+//! handed out for the peer is then replaced by a random one, so WireGuard
+//! stops using a key without post-quantum protection and the tunnel fails
+//! closed. Deployments which prefer the tunnel to stay up can choose per
+//! peer:
+//!
+//! ```toml
+//! [[peers]]
+//! stale_key = "erase"       # replace the key once it expires, the default
+//! stale_key = "keep"        # keep the last key, with a warning
+//! stale_key = { after = 3 } # replace it once 3 renewals in a row were missed
+//! ```
+//!
+//! The first renewal counts as missed when the key expires, every further
+//! one a rekey interval later; `{ after = 1 }` is the same as `"erase"`. A
+//! kept key is replaced as soon as a handshake goes through again. Keys of
+//! revoked peers are always erased.
+//!
+//! [CryptoServer::rekey_after]: crate::protocol::CryptoServer::rekey_after
+//! [REJECT_AFTER_TIME]: crate::protocol::REJECT_AFTER_TIME
+
+use anyhow::{ensure, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::Timing;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StaleKeyPolicy {
+    /// Replace the key by a random one once it expires
+    #[default]
+    Erase,
+    /// Keep the last key until the next one
+    Keep,
+    /// Replace the key once this many renewals in a row were missed
+    After(u32),
+}
+
+impl StaleKeyPolicy {
+    pub fn validate(&self) -> Result<()> {
+        ensure!(
+            *self != StaleKeyPolicy::After(0),
+            "stale_key.after must be at least 1"
+        );
+        Ok(())
+    }
+
+    /// Seconds after expiring a key is still kept for, given the
+    /// `rekey_after` interval; `None` if it is kept for good
+    pub fn grace(&self, rekey_after: Timing) -> Option<Timing> {
+        match self {
+            StaleKeyPolicy::Erase => Some(0.0),
+            StaleKeyPolicy::Keep => None,
+            StaleKeyPolicy::After(n) => Some(n.saturating_sub(1) as Timing * rekey_after),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Debug, Deserialize)]
+    struct Peer {
+        #[serde(default)]
+        stale_key: StaleKeyPolicy,
+    }
+
+    #[test]
+    fn parses_and_counts_renewals() {
+        let parse = |s: &str| toml::from_str::<Peer>(s).unwrap().stale_key;
+        assert_eq!(parse(""), StaleKeyPolicy::Erase);
+        assert_eq!(parse("stale_key = \"keep\""), StaleKeyPolicy::Keep);
+        let after = parse("stale_key = { after = 3 }");
+        assert_eq!(after, StaleKeyPolicy::After(3));
+
+        assert_eq!(StaleKeyPolicy::Erase.grace(100.0), Some(0.0));
+        assert_eq!(StaleKeyPolicy::After(1).grace(100.0), Some(0.0));
+        assert_eq!(after.grace(100.0), Some(200.0));
+        assert_eq!(StaleKeyPolicy::Keep.grace(100.0), None);
+        assert!(StaleKeyPolicy::After(0).validate().is_err());
+    }
+}
@@ -0,0 +1,386 @@
+//! Per peer statistics of a running instance
+//!
+//! [crate::app_server::AppServer] counts initiated, completed and failed
+//! handshakes as well as retransmissions for every peer and keeps a histogram
+//! of how long handshakes it initiated took to complete. The numbers are
+//! served on the control socket with [crate::control::ControlCommand::Stats]
+//! and can be rendered for Prometheus with [StatsReport::to_prometheus].
+//!
+//! Failure causes are a small, fixed vocabulary rather than error messages, so
+//! counting them needs bounded memory no matter what arrives on the wire.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Write},
+    net::SocketAddr,
+};
+
+use crate::{msgs::MsgType, quota::QuotaUsage, shed::ShedCounts};
+
+/// Upper bounds of the buckets of [PeerStats::handshake_latency], in seconds
+pub const LATENCY_BUCKETS: [f64; 12] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+
+/// Histogram with fixed buckets
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Histogram {
+    /// Upper bounds of the buckets
+    pub bounds: Vec<f64>,
+    /// Observations per bucket, not cumulative; the last entry counts the
+    /// observations above the largest bound
+    pub counts: Vec<u64>,
+    pub sum: f64,
+    pub count: u64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &[f64]) -> Self {
+        Self {
+            bounds: bounds.to_vec(),
+            counts: vec![0; bounds.len() + 1],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    pub fn observe(&mut self, value: f64) {
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|&b| value <= b)
+            .unwrap_or(self.bounds.len());
+        self.counts[bucket] += 1;
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self::new(&LATENCY_BUCKETS)
+    }
+}
+
+/// Why a handshake did not go through
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FailureCause {
+    EmptyMessage,
+    UnknownMessageType,
+    BrokenSeal,
+    RejectedInitHello,
+    RejectedRespHello,
+    RejectedInitConf,
+    RejectedEmptyData,
+    RejectedFragment,
+    RejectedKeepalive,
+    RejectedRendezvous,
+    RejectedRelay,
+    UnsupportedMessage,
+    /// An InitConf whose biscuit was used before
+    Replayed,
+    /// An InitConf whose biscuit could not be decrypted
+    BrokenBiscuit,
+    /// A message of a locked down peer from an address it is not allowed at
+    DisallowedSource,
+    /// The key ran out without a new handshake replacing it
+    KeyExpired,
+}
+
+impl FailureCause {
+    /// Classify the error returned when handling the message `msg`
+    pub fn of_rejected(msg: &[u8], err: &anyhow::Error) -> Self {
+        use FailureCause::*;
+        // the protocol reports broken seals with this exact message
+        if err.to_string() == "Message seal broken!" {
+            return BrokenSeal;
+        }
+        if err.to_string() == "Rejecting biscuit: Outdated biscuit number" {
+            return Replayed;
+        }
+        if err.to_string() == "Rejecting biscuit: Could not decrypt it" {
+            return BrokenBiscuit;
+        }
+        match msg.first().map(|&t| MsgType::try_from(t)) {
+            None => EmptyMessage,
+            Some(Err(_)) => UnknownMessageType,
+            Some(Ok(MsgType::InitHello)) => RejectedInitHello,
+            Some(Ok(MsgType::RespHello)) => RejectedRespHello,
+            Some(Ok(MsgType::InitConf)) => RejectedInitConf,
+            Some(Ok(MsgType::EmptyData)) => RejectedEmptyData,
+            Some(Ok(MsgType::Fragment)) => RejectedFragment,
+            Some(Ok(MsgType::Keepalive)) => RejectedKeepalive,
+            Some(Ok(MsgType::Rendezvous)) => RejectedRendezvous,
+            Some(Ok(MsgType::Relay)) => RejectedRelay,
+            Some(Ok(MsgType::DataMsg | MsgType::CookieReply)) => UnsupportedMessage,
+        }
+    }
+}
+
+impl fmt::Display for FailureCause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // reuse the serde names, so the control socket and metrics agree
+        let name = serde_json::to_value(self).map_err(|_| fmt::Error)?;
+        f.write_str(name.as_str().ok_or(fmt::Error)?)
+    }
+}
+
+pub type FailureCounts = BTreeMap<FailureCause, u64>;
+
+/// Counters of a single peer
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PeerStats {
+    pub handshakes_initiated: u64,
+    /// Handshakes completed in either role
+    pub handshakes_completed: u64,
+    pub retransmissions: u64,
+    /// Keys that could not be handed to WireGuard despite retrying
+    #[serde(default)]
+    pub psk_apply_failures: u64,
+    /// Seconds from sending the first InitHello to receiving the RespHello,
+    /// for handshakes initiated by us
+    pub handshake_latency: Histogram,
+    pub failures: FailureCounts,
+}
+
+impl PeerStats {
+    pub fn failure(&mut self, cause: FailureCause) {
+        *self.failures.entry(cause).or_default() += 1;
+    }
+}
+
+/// One entry of [StatsReport]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PeerStatsEntry {
+    /// Base64 encoded peer id
+    pub peer_id: String,
+    pub fingerprint: String,
+    /// See [crate::fingerprint]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub tags: Vec<String>,
+    #[serde(flatten)]
+    pub stats: PeerStats,
+}
+
+impl PeerStatsEntry {
+    /// The name of the peer, or its fingerprint if it has none
+    pub fn label(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.fingerprint)
+    }
+}
+
+/// Answer to [crate::control::ControlCommand::Stats]
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct StatsReport {
+    pub peers: Vec<PeerStatsEntry>,
+    /// Failures that could not be attributed to a peer, e.g. messages from
+    /// unknown addresses
+    pub unattributed_failures: FailureCounts,
+    /// How often the event loop woke up from waiting
+    #[serde(default)]
+    pub wakeups: u64,
+    /// How often the system was suspended or the wall clock was set
+    #[serde(default)]
+    pub clock_jumps: u64,
+    /// Our addresses as seen from outside, see [crate::nat]
+    #[serde(default)]
+    pub reflexive_addresses: Vec<SocketAddr>,
+    /// Usage of the quotas, the global one first; see [crate::quota]
+    #[serde(default)]
+    pub quotas: Vec<QuotaUsage>,
+    /// What was turned away under load, see [crate::shed]
+    #[serde(default)]
+    pub shed: ShedCounts,
+}
+
+impl StatsReport {
+    /// Render in the Prometheus text exposition format; peers are labeled
+    /// with their [PeerStatsEntry::label]
+    pub fn to_prometheus(&self) -> String {
+        type Counter = fn(&PeerStats) -> u64;
+        let mut out = String::new();
+        let counters: [(&str, Counter); 4] = [
+            ("handshakes_initiated", |s| s.handshakes_initiated),
+            ("handshakes_completed", |s| s.handshakes_completed),
+            ("retransmissions", |s| s.retransmissions),
+            ("psk_apply_failures", |s| s.psk_apply_failures),
+        ];
+        for (name, get) in counters {
+            let _ = writeln!(out, "# TYPE rosenpass_{name}_total counter");
+            for p in self.peers.iter() {
+                let _ = writeln!(
+                    out,
+                    "rosenpass_{name}_total{{peer=\"{}\"}} {}",
+                    p.label(),
+                    get(&p.stats)
+                );
+            }
+        }
+
+        let _ = writeln!(out, "# TYPE rosenpass_handshake_failures_total counter");
+        for p in self.peers.iter() {
+            for (cause, n) in p.stats.failures.iter() {
+                let _ = writeln!(
+                    out,
+                    "rosenpass_handshake_failures_total{{peer=\"{}\",cause=\"{cause}\"}} {n}",
+                    p.label()
+                );
+            }
+        }
+        for (cause, n) in self.unattributed_failures.iter() {
+            let _ = writeln!(
+                out,
+                "rosenpass_handshake_failures_total{{peer=\"\",cause=\"{cause}\"}} {n}"
+            );
+        }
+
+        let _ = writeln!(out, "# TYPE rosenpass_wakeups_total counter");
+        let _ = writeln!(out, "rosenpass_wakeups_total {}", self.wakeups);
+        let _ = writeln!(out, "# TYPE rosenpass_clock_jumps_total counter");
+        let _ = writeln!(out, "rosenpass_clock_jumps_total {}", self.clock_jumps);
+        let _ = writeln!(out, "# TYPE rosenpass_reflexive_address_info gauge");
+        for addr in self.reflexive_addresses.iter() {
+            let _ = writeln!(
+                out,
+                "rosenpass_reflexive_address_info{{address=\"{addr}\"}} 1"
+            );
+        }
+
+        // the global quota has the empty tag
+        type Gauge = fn(&QuotaUsage) -> usize;
+        let gauges: [(&str, Gauge); 2] = [
+            ("handshakes", |q| q.handshakes),
+            ("memory_bytes", |q| q.memory),
+        ];
+        for (name, get) in gauges {
+            let _ = writeln!(out, "# TYPE rosenpass_quota_{name} gauge");
+            for q in self.quotas.iter() {
+                let tag = q.tag.as_deref().unwrap_or_default();
+                let _ = writeln!(out, "rosenpass_quota_{name}{{tag=\"{tag}\"}} {}", get(q));
+            }
+        }
+        let _ = writeln!(out, "# TYPE rosenpass_quota_rejections_total counter");
+        for q in self.quotas.iter() {
+            let tag = q.tag.as_deref().unwrap_or_default();
+            for (resource, n) in q.rejections.iter() {
+                let _ = writeln!(
+                    out,
+                    "rosenpass_quota_rejections_total{{tag=\"{tag}\",resource=\"{resource}\"}} {n}"
+                );
+            }
+        }
+
+        let _ = writeln!(out, "# TYPE rosenpass_shed_total counter");
+        let _ = writeln!(
+            out,
+            "rosenpass_shed_total{{kind=\"response\"}} {}",
+            self.shed.responses
+        );
+        let _ = writeln!(
+            out,
+            "rosenpass_shed_total{{kind=\"initiation\"}} {}",
+            self.shed.initiations
+        );
+        let _ = writeln!(out, "# TYPE rosenpass_under_load gauge");
+        let _ = writeln!(out, "rosenpass_under_load {}", self.shed.under_load as u8);
+
+        let _ = writeln!(out, "# TYPE rosenpass_handshake_latency_seconds histogram");
+        for p in self.peers.iter() {
+            let h = &p.stats.handshake_latency;
+            let fp = p.label();
+            let mut cumulative = 0;
+            for (bound, n) in h.bounds.iter().zip(h.counts.iter()) {
+                cumulative += n;
+                let _ = writeln!(
+                    out,
+                    "rosenpass_handshake_latency_seconds_bucket{{peer=\"{fp}\",le=\"{bound}\"}} {cumulative}"
+                );
+            }
+            let _ = writeln!(
+                out,
+                "rosenpass_handshake_latency_seconds_bucket{{peer=\"{fp}\",le=\"+Inf\"}} {}",
+                h.count
+            );
+            let _ = writeln!(
+                out,
+                "rosenpass_handshake_latency_seconds_sum{{peer=\"{fp}\"}} {}",
+                h.sum
+            );
+            let _ = writeln!(
+                out,
+                "rosenpass_handshake_latency_seconds_count{{peer=\"{fp}\"}} {}",
+                h.count
+            );
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn histogram_and_prometheus() {
+        let mut stats = PeerStats::default();
+        stats.handshake_latency.observe(0.003);
+        stats.handshake_latency.observe(0.003);
+        stats.handshake_latency.observe(60.0);
+        stats.failure(FailureCause::BrokenSeal);
+        assert_eq!(stats.handshake_latency.counts[2], 2);
+        assert_eq!(stats.handshake_latency.counts[12], 1);
+
+        let mut report = StatsReport {
+            peers: vec![PeerStatsEntry {
+                peer_id: "id".into(),
+                fingerprint: "fp".into(),
+                name: None,
+                tags: vec![],
+                stats,
+            }],
+            ..Default::default()
+        };
+        let text = report.to_prometheus();
+        assert!(text
+            .contains("rosenpass_handshake_failures_total{peer=\"fp\",cause=\"broken-seal\"} 1\n"));
+        assert!(text
+            .contains("rosenpass_handshake_latency_seconds_bucket{peer=\"fp\",le=\"0.01\"} 2\n"));
+        assert!(text
+            .contains("rosenpass_handshake_latency_seconds_bucket{peer=\"fp\",le=\"+Inf\"} 3\n"));
+
+        report.peers[0].name = Some("office-berlin".into());
+        assert!(report
+            .to_prometheus()
+            .contains("rosenpass_handshakes_completed_total{peer=\"office-berlin\"} 0\n"));
+    }
+
+    #[test]
+    fn classify_failures() {
+        let err = anyhow::anyhow!("anything");
+        assert_eq!(
+            FailureCause::of_rejected(&[], &err),
+            FailureCause::EmptyMessage
+        );
+        assert_eq!(
+            FailureCause::of_rejected(&[0x01], &err),
+            FailureCause::UnknownMessageType
+        );
+        assert_eq!(
+            FailureCause::of_rejected(&[0x82], &err),
+            FailureCause::RejectedRespHello
+        );
+        let seal = anyhow::anyhow!("Message seal broken!");
+        assert_eq!(
+            FailureCause::of_rejected(&[0x82], &seal),
+            FailureCause::BrokenSeal
+        );
+        let replay = anyhow::anyhow!("Rejecting biscuit: Outdated biscuit number");
+        assert_eq!(
+            FailureCause::of_rejected(&[0x83], &replay),
+            FailureCause::Replayed
+        );
+    }
+}
@@ -0,0 +1,175 @@
+//! Several independent instances in one process
+//!
+//! Given several configuration files, `exchange-config` runs an [AppServer]
+//! for each, with its own sockets, keys and peers, all on a single thread:
+//! the mio poll of every instance is registered with the poll of the
+//! [Supervisor], which waits for all of them at once and runs each instance
+//! only while it has something to do. Gateways terminating many VPNs save
+//! the processes, threads and buffers of running the instances separately.
+//!
+//! The instances stay independent otherwise: an instance failing is logged
+//! and stopped, while the others carry on.
+
+use anyhow::{ensure, Result};
+use log::{error, info};
+use mio::{unix::SourceFd, Events, Interest, Poll, Token};
+use std::{
+    io::ErrorKind,
+    os::fd::AsRawFd,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{app_server::AppServer, container, protocol::MsgBuf, rekey};
+
+/// Events an instance may handle before the others get their turn
+const MAX_EVENTS_PER_TURN: usize = 64;
+
+/// Token of the waker used by signal handlers
+const WAKER_TOKEN: Token = Token(usize::MAX);
+
+struct Instance {
+    /// The config file the instance was started from
+    name: PathBuf,
+    /// The server, until it stopped
+    srv: Option<Box<AppServer>>,
+    rx: MsgBuf,
+    tx: MsgBuf,
+    /// The poll of the instance has events
+    ready: bool,
+    /// When the instance has to be run next without any events
+    wake_at: Instant,
+}
+
+pub struct Supervisor {
+    poll: Poll,
+    events: Events,
+    instances: Vec<Instance>,
+    /// Wakes the supervisor from signal handlers
+    waker: Arc<mio::Waker>,
+}
+
+impl Supervisor {
+    pub fn new() -> Result<Self> {
+        let poll = Poll::new()?;
+        let waker = Arc::new(mio::Waker::new(poll.registry(), WAKER_TOKEN)?);
+        Ok(Self {
+            poll,
+            events: Events::with_capacity(64),
+            instances: Vec::new(),
+            waker,
+        })
+    }
+
+    /// Run `srv`, started from the config file `name`, with the others
+    pub fn add(&mut self, name: PathBuf, srv: Box<AppServer>) -> Result<()> {
+        let token = Token(self.instances.len());
+        let fd = srv.mio_poll.as_raw_fd();
+        self.poll
+            .registry()
+            .register(&mut SourceFd(&fd), token, Interest::READABLE)?;
+        self.instances.push(Instance {
+            name,
+            srv: Some(srv),
+            rx: MsgBuf::zero(),
+            tx: MsgBuf::zero(),
+            // the first turn sets up timers and sends the first handshakes
+            ready: true,
+            wake_at: Instant::now(),
+        });
+        Ok(())
+    }
+
+    /// Stop all instances on SIGTERM and SIGINT, see [crate::container]
+    pub fn terminate_on_signal(&self) -> Result<()> {
+        container::handle_termination(self.waker.clone())
+    }
+
+    /// Rekey the peers of all instances on SIGUSR1, see [crate::rekey]
+    pub fn rekey_on_signal(&self) -> Result<()> {
+        rekey::handle_signal(self.waker.clone())
+    }
+
+    /// Run the instances until all of them stopped
+    pub fn run(&mut self) -> Result<()> {
+        ensure!(!self.instances.is_empty(), "no instances to run");
+        info!("Supervising {} instances", self.instances.len());
+        let mut rekey_signals = rekey::signals();
+        while self.instances.iter().any(|i| i.srv.is_some()) {
+            if container::terminating() {
+                info!("Terminating on signal");
+                return Ok(());
+            }
+
+            let now = Instant::now();
+            for inst in self.instances.iter_mut() {
+                if inst.srv.is_none() || !(inst.ready || inst.wake_at <= now) {
+                    continue;
+                }
+                let res = inst.turn(now);
+                match res {
+                    Ok(true) => continue,
+                    Ok(false) => info!("Instance {:?} stopped", inst.name),
+                    Err(ref e) => error!("Instance {:?} failed: {e:?}", inst.name),
+                }
+                if let Some(srv) = inst.srv.take() {
+                    let fd = srv.mio_poll.as_raw_fd();
+                    self.poll.registry().deregister(&mut SourceFd(&fd))?;
+                }
+                inst.ready = false;
+            }
+
+            let running = self.instances.iter().filter(|i| i.srv.is_some());
+            let timeout = match running.clone().any(|i| i.ready) {
+                true => Duration::ZERO,
+                false => running
+                    .map(|i| i.wake_at.saturating_duration_since(Instant::now()))
+                    .min()
+                    .unwrap_or_default(),
+            };
+            match self.poll.poll(&mut self.events, Some(timeout)) {
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                res => res?,
+            }
+            for ev in self.events.iter() {
+                if let Some(inst) = self.instances.get_mut(ev.token().0) {
+                    inst.ready = true;
+                }
+                // each instance takes note of the signal when run
+                if ev.token() == WAKER_TOKEN && rekey::signals() != rekey_signals {
+                    rekey_signals = rekey::signals();
+                    self.instances.iter_mut().for_each(|i| i.ready = true);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Instance {
+    /// Handle the events of the instance, up to [MAX_EVENTS_PER_TURN];
+    /// returns false once the instance stopped
+    fn turn(&mut self, now: Instant) -> Result<bool> {
+        let Some(srv) = self.srv.as_mut() else {
+            return Ok(false);
+        };
+        for _ in 0..MAX_EVENTS_PER_TURN {
+            match srv.poll_for(&mut *self.rx, false)? {
+                Ok(ev) => {
+                    if !srv.process_event(ev, &mut self.rx, &mut self.tx)? {
+                        return Ok(false);
+                    }
+                }
+                Err(timeout) => {
+                    self.ready = false;
+                    self.wake_at = now + Duration::from_secs_f64(timeout.max(0.0));
+                    return Ok(true);
+                }
+            }
+        }
+        // more is to be done, after the others had their turn
+        self.ready = true;
+        Ok(true)
+    }
+}
@@ -0,0 +1,349 @@
+//! Hierarchical timer wheel scheduling the peers
+//!
+//! Instead of asking every peer for its next timer on each turn of the event
+//! loop, [crate::app_server::AppServer] keeps each peer in a [TimerWheel] at
+//! the earliest time any of its timers may be due, and only looks at the
+//! peers whose time has come. Peers whose state changed are scheduled right
+//! away, so their timers are worked out anew.
+//!
+//! Timers are kept in the slot of the tick of 1/[TICKS_PER_SEC] seconds
+//! their time falls into, but fire at that exact time rather than at a tick,
+//! and [TimerWheel::next_at] is the exact time of the next timer, so the
+//! event loop wakes up just when there is something to do. The wheel has [SLOTS]
+//! slots per level, each level covering [SLOTS] times the span of the one
+//! below; a timer sits on the level of the highest digit in which its tick
+//! differs from the current one and moves down a level whenever the current
+//! tick reaches its slot. Scheduling, finding the next timer and firing are
+//! all O(1), with every timer moved down at most once per level.
+//!
+//! Waiting until the next timer alone would miss a resume from suspend, as
+//! waits do not count the time spent suspended. [ClockChanges] wakes the
+//! event loop whenever the system resumes or the wall clock is set instead,
+//! so it does not need to wake up every so often to look at the clocks.
+
+use std::{
+    collections::VecDeque,
+    io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    ptr::null_mut,
+};
+
+use mio::{unix::SourceFd, Interest, Registry, Token};
+
+use crate::protocol::Timing;
+
+/// Resolution of the timers
+pub const TICKS_PER_SEC: f64 = 64.0;
+
+/// Bits of a tick handled per level
+const BITS: usize = 6;
+
+/// Slots per level
+pub const SLOTS: usize = 1 << BITS;
+
+/// Enough levels for every tick; those for the far future stay empty
+const LEVELS: usize = 64usize.div_ceil(BITS);
+
+#[derive(Debug)]
+pub struct TimerWheel {
+    /// The current tick; all ticks before it were handled
+    now: u64,
+    /// Ids and the tick they were scheduled for; entries whose tick does not
+    /// match [TimerWheel::at] any more were rescheduled or cancelled
+    slots: Vec<[Vec<(usize, u64)>; SLOTS]>,
+    /// Bit `s` of the entry for a level is set if its slot `s` is not empty
+    occupied: [u64; LEVELS],
+    /// The tick and the time each id is scheduled for, if it is
+    at: Vec<Option<(u64, Timing)>>,
+    /// Ids whose time has come
+    due: VecDeque<(usize, u64)>,
+}
+
+impl Default for TimerWheel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The tick `at` falls into
+fn tick(at: Timing) -> u64 {
+    (at.max(0.0) * TICKS_PER_SEC) as u64
+}
+
+impl TimerWheel {
+    pub fn new() -> Self {
+        Self {
+            now: 0,
+            slots: (0..LEVELS)
+                .map(|_| std::array::from_fn(|_| Vec::new()))
+                .collect(),
+            occupied: [0; LEVELS],
+            at: Vec::new(),
+            due: VecDeque::new(),
+        }
+    }
+
+    /// Fire `id` at `at`, instead of whenever it was scheduled before
+    pub fn schedule(&mut self, id: usize, at: Timing) {
+        if self.at.len() <= id {
+            self.at.resize(id + 1, None);
+        }
+        let t = tick(at).max(self.now);
+        self.at[id] = Some((t, at));
+        self.insert(id, t);
+    }
+
+    /// Do not fire `id`
+    pub fn cancel(&mut self, id: usize) {
+        if let Some(at) = self.at.get_mut(id) {
+            *at = None;
+        }
+    }
+
+    /// When `id` is going to fire, if it is scheduled
+    pub fn scheduled(&self, id: usize) -> Option<Timing> {
+        self.at.get(id).copied().flatten().map(|(_, at)| at)
+    }
+
+    /// An id due at `now`, if there is one; the id is not scheduled anymore
+    pub fn pop_due(&mut self, now: Timing) -> Option<usize> {
+        self.advance(tick(now));
+        // only those of the current tick may not be due yet
+        let mut i = 0;
+        while let Some(&(id, t)) = self.due.get(i) {
+            match self.at[id] {
+                Some((tick, at)) if tick == t && at > now => i += 1,
+                Some((tick, _)) if tick == t => {
+                    self.due.remove(i);
+                    self.at[id] = None;
+                    return Some(id);
+                }
+                _ => {
+                    self.due.remove(i);
+                }
+            }
+        }
+        None
+    }
+
+    /// When the next id is due; earlier if all timers of its slot were
+    /// rescheduled or cancelled, as that is only known once it is reached
+    pub fn next_at(&self) -> Option<Timing> {
+        let due = self.earliest(&self.due);
+        let next = self.next_tick().map(|(t, level)| {
+            let slot = (t >> (level * BITS)) as usize & (SLOTS - 1);
+            self.earliest(&self.slots[level][slot])
+                .unwrap_or(t as f64 / TICKS_PER_SEC)
+        });
+        due.into_iter().chain(next).reduce(Timing::min)
+    }
+
+    /// The earliest time of the `entries` still scheduled
+    fn earliest<'a>(&self, entries: impl IntoIterator<Item = &'a (usize, u64)>) -> Option<Timing> {
+        entries
+            .into_iter()
+            .filter_map(|&(id, t)| self.at[id].filter(|a| a.0 == t))
+            .map(|(_, at)| at)
+            .reduce(Timing::min)
+    }
+
+    fn insert(&mut self, id: usize, t: u64) {
+        let level = match t ^ self.now {
+            0 => 0,
+            diff => (63 - diff.leading_zeros() as usize) / BITS,
+        };
+        let slot = ((t >> (level * BITS)) as usize) & (SLOTS - 1);
+        self.slots[level][slot].push((id, t));
+        self.occupied[level] |= 1 << slot;
+    }
+
+    /// The first tick something has to be done at, and the level to do it
+    /// on: firing timers on level zero, moving them down above
+    fn next_tick(&self) -> Option<(u64, usize)> {
+        for level in 0..LEVELS {
+            let shift = level * BITS;
+            let digit = (self.now >> shift) as usize & (SLOTS - 1);
+            let pending = self.occupied[level] & (u64::MAX << digit);
+            if pending == 0 {
+                continue;
+            }
+            let slot = pending.trailing_zeros() as u64;
+            // the span of ticks above this level, and the slot within it
+            let above = match shift + BITS {
+                64.. => 0,
+                bits => (self.now >> bits) << bits,
+            };
+            return Some((above | (slot << shift), level));
+        }
+        None
+    }
+
+    /// Move the timers of all ticks up to `target` to [TimerWheel::due]
+    fn advance(&mut self, target: u64) {
+        while let Some((t, level)) = self.next_tick() {
+            if t > target {
+                break;
+            }
+            self.now = t;
+            let slot = (t >> (level * BITS)) as usize & (SLOTS - 1);
+            self.occupied[level] &= !(1 << slot);
+            let entries = std::mem::take(&mut self.slots[level][slot]);
+            for (id, at) in entries {
+                if self.at[id].map(|a| a.0) != Some(at) {
+                    continue;
+                }
+                match level {
+                    0 => self.due.push_back((id, at)),
+                    _ => self.insert(id, at),
+                }
+            }
+        }
+        self.now = self.now.max(target);
+    }
+}
+
+/// A timer which becomes readable when the wall clock is set, which the
+/// kernel also counts a resume from suspend as
+#[derive(Debug)]
+pub struct ClockChanges {
+    fd: OwnedFd,
+}
+
+impl ClockChanges {
+    pub fn new() -> io::Result<Self> {
+        use libc::{CLOCK_REALTIME, TFD_CLOEXEC, TFD_NONBLOCK};
+        let fd = unsafe { libc::timerfd_create(CLOCK_REALTIME, TFD_NONBLOCK | TFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let changes = Self {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+        };
+        changes.arm()?;
+        Ok(changes)
+    }
+
+    pub fn register(&self, registry: &Registry, token: Token) -> io::Result<()> {
+        registry.register(
+            &mut SourceFd(&self.fd.as_raw_fd()),
+            token,
+            Interest::READABLE,
+        )
+    }
+
+    /// Set the timer to the end of time; setting the clock cancels it
+    fn arm(&self) -> io::Result<()> {
+        use libc::{TFD_TIMER_ABSTIME, TFD_TIMER_CANCEL_ON_SET};
+        let zero = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        let spec = libc::itimerspec {
+            it_interval: zero,
+            it_value: libc::timespec {
+                tv_sec: libc::time_t::MAX,
+                tv_nsec: 0,
+            },
+        };
+        let flags = TFD_TIMER_ABSTIME | TFD_TIMER_CANCEL_ON_SET;
+        match unsafe { libc::timerfd_settime(self.fd.as_raw_fd(), flags, &spec, null_mut()) } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    /// Whether the clock changed since this was last asked
+    pub fn changed(&self) -> io::Result<bool> {
+        let mut expirations = 0u64;
+        let len = std::mem::size_of_val(&expirations);
+        let ptr = &mut expirations as *mut u64 as *mut libc::c_void;
+        if unsafe { libc::read(self.fd.as_raw_fd(), ptr, len) } >= 0 {
+            return Ok(false);
+        }
+        match io::Error::last_os_error() {
+            e if e.raw_os_error() == Some(libc::ECANCELED) => self.arm().map(|()| true),
+            e if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            e => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn fires_in_order() {
+        let mut wheel = TimerWheel::new();
+        wheel.schedule(0, 10.0);
+        wheel.schedule(1, 0.51);
+        wheel.schedule(2, 3600.0);
+        wheel.schedule(3, 2.0);
+        wheel.cancel(3);
+        wheel.schedule(4, 5.0);
+        wheel.schedule(4, 1.0);
+
+        assert_eq!(wheel.pop_due(0.4), None);
+        assert_eq!(wheel.next_at(), Some(0.51));
+        // not before its time within the tick
+        assert_eq!(wheel.pop_due(0.505), None);
+        assert_eq!(wheel.next_at(), Some(0.51));
+        assert_eq!(wheel.pop_due(0.51), Some(1));
+        assert_eq!(wheel.pop_due(0.9), None);
+        assert_eq!(wheel.pop_due(9.0), Some(4));
+        assert_eq!(wheel.pop_due(9.0), None);
+        assert_eq!(wheel.next_at(), Some(10.0));
+        assert_eq!(wheel.pop_due(10.0), Some(0));
+        assert_eq!(wheel.scheduled(2), Some(3600.0));
+        assert_eq!(wheel.pop_due(3599.9), None);
+        assert_eq!(wheel.pop_due(7200.0), Some(2));
+        assert_eq!(wheel.pop_due(1e9), None);
+        assert_eq!(wheel.next_at(), None);
+    }
+
+    #[test]
+    fn matches_scanning() {
+        // compare against walking all timers, with random times and steps
+        let rand = |n: u64| rosenpass_sodium::helpers::rand_u64() % n;
+        rosenpass_sodium::init().unwrap();
+        let mut wheel = TimerWheel::new();
+        let mut naive: Vec<Option<Timing>> = vec![None; 200];
+        let mut now = 0.0;
+        for _ in 0..5000 {
+            let id = rand(200) as usize;
+            match rand(4) {
+                0 => {
+                    wheel.cancel(id);
+                    naive[id] = None;
+                }
+                _ => {
+                    let at = now + rand(1 << rand(28)) as f64 / (16.0 * TICKS_PER_SEC);
+                    wheel.schedule(id, at);
+                    naive[id] = Some(at);
+                }
+            }
+            now += rand(1 << rand(20)) as f64 / (16.0 * TICKS_PER_SEC);
+            let mut fired = Vec::new();
+            while let Some(id) = wheel.pop_due(now) {
+                fired.push(id);
+            }
+            fired.sort();
+            let expected = (0..naive.len())
+                .filter(|&id| naive[id].is_some_and(|at| at <= now))
+                .collect::<Vec<_>>();
+            assert_eq!(fired, expected);
+            expected.iter().for_each(|&id| naive[id] = None);
+            let next = naive.iter().flatten().copied().reduce(f64::min);
+            match (wheel.next_at(), next) {
+                (Some(a), Some(b)) => assert!(a <= b && a >= now),
+                (a, b) => assert_eq!(a, b),
+            }
+        }
+    }
+
+    #[test]
+    fn clock_changes_are_not_made_up() {
+        let changes = ClockChanges::new().unwrap();
+        assert!(!changes.changed().unwrap());
+    }
+}
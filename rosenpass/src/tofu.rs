@@ -0,0 +1,126 @@
+//! Pinning peer public keys on first use
+//!
+//! For labs and home setups where public key files get copied around without
+//! much ceremony, `pin_store = "/var/lib/rosenpass/pins"` in the config (or
+//! `pin-store <file>` on the command line) has the fingerprint of each peer's
+//! public key recorded the first time it is seen. From then on, rosenpass
+//! refuses to start if the key of that peer ever changes, so a swapped key
+//! file is noticed instead of silently trusted.
+//!
+//! Peers are told apart by their endpoint, or by the path of their public
+//! key file if they have none, much like SSH remembers host keys by host
+//! name. The store lists one `<fingerprint> <peer>` per line;
+//! `rosenpass pins list` shows it and `rosenpass pins remove <peer>` forgets
+//! a pin, so the next key is accepted again. Fingerprints differ in FIPS mode,
+//! so switching to it means pinning anew.
+
+use anyhow::{bail, Context, Result};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use crate::{config::RosenpassPeer, fingerprint::Fingerprint};
+
+/// The fingerprints pinned so far, see the [module documentation](self)
+#[derive(Debug)]
+pub struct PinStore {
+    path: PathBuf,
+    pins: BTreeMap<String, String>,
+}
+
+/// What a peer is recorded as in the pin store
+pub fn pin_name(peer: &RosenpassPeer) -> String {
+    match peer.endpoint.as_ref() {
+        Some(endpoint) => endpoint.clone(),
+        None => std::path::absolute(&peer.public_key)
+            .unwrap_or_else(|_| peer.public_key.clone())
+            .display()
+            .to_string(),
+    }
+}
+
+impl PinStore {
+    /// Read the pins in `path`; there are none if the file does not exist
+    pub fn open(path: &Path) -> Result<Self> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e).with_context(|| format!("could not read pins {path:?}")),
+        };
+        let mut pins = BTreeMap::new();
+        for (no, line) in text.lines().enumerate() {
+            let Some((fp, name)) = line.split_once(' ') else {
+                bail!("{path:?} line {}: expected `<fingerprint> <peer>`", no + 1);
+            };
+            pins.insert(name.to_owned(), fp.to_owned());
+        }
+        Ok(Self {
+            path: path.to_owned(),
+            pins,
+        })
+    }
+
+    /// Check the key with fingerprint `fp` against the one pinned for
+    /// `name`, or pin it; returns whether it was pinned just now
+    pub fn check(&mut self, name: &str, fp: &Fingerprint) -> Result<bool> {
+        let fp = fp.to_string();
+        match self.pins.get(name) {
+            Some(pinned) if *pinned == fp => Ok(false),
+            Some(pinned) => bail!(
+                "the public key of peer {name} changed from {pinned} to {fp}; if this is \
+                intended, forget the old one with `rosenpass pins remove`"
+            ),
+            None => {
+                self.pins.insert(name.to_owned(), fp);
+                Ok(true)
+            }
+        }
+    }
+
+    /// Forget the pin of `name`; returns whether there was one
+    pub fn remove(&mut self, name: &str) -> bool {
+        self.pins.remove(name).is_some()
+    }
+
+    /// Names and fingerprints of all pins
+    pub fn pins(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.pins.iter().map(|(n, fp)| (n.as_str(), fp.as_str()))
+    }
+
+    /// Write the pins back to their file
+    pub fn store(&self) -> Result<()> {
+        let text: String = self
+            .pins
+            .iter()
+            .map(|(name, fp)| format!("{fp} {name}\n"))
+            .collect();
+        let tmp = self.path.with_extension("tmp");
+        std::fs::write(&tmp, text)
+            .and_then(|()| std::fs::rename(&tmp, &self.path))
+            .with_context(|| format!("could not write pins {:?}", self.path))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pins_on_first_use() {
+        let path = std::env::temp_dir().join(format!("rp-pins-{}", std::process::id()));
+        let (a, b) = (Fingerprint([1; 16]), Fingerprint([2; 16]));
+
+        let mut store = PinStore::open(&path).unwrap();
+        assert!(store.check("peer.test:9999", &a).unwrap());
+        assert!(!store.check("peer.test:9999", &a).unwrap());
+        store.store().unwrap();
+
+        let mut store = PinStore::open(&path).unwrap();
+        assert!(store.check("peer.test:9999", &b).is_err());
+        assert!(store.remove("peer.test:9999"));
+        assert!(store.check("peer.test:9999", &b).unwrap());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
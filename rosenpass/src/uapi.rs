@@ -0,0 +1,140 @@
+//! Handing keys to userspace WireGuard implementations
+//!
+//! wireguard-go and boringtun can not be configured through netlink like the
+//! kernel module; instead they listen on a unix socket speaking the
+//! cross-platform userspace API, conventionally at
+//! `/var/run/wireguard/<device>.sock`. Requests and responses are `key=value`
+//! lines terminated by an empty line, with keys hex encoded; see
+//! <https://www.wireguard.com/xplatform/>.
+//!
+//! Keys passed to and returned from this module are base64 encoded, as with
+//! the `wg` tool.
+
+use anyhow::{bail, ensure, Context, Result};
+use rosenpass_util::b64::{b64_reader, fmt_b64};
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    os::unix::net::UnixStream,
+    path::Path,
+    time::Duration,
+};
+
+/// How long to wait for the WireGuard implementation to answer
+const UAPI_TIMEOUT: Duration = Duration::from_secs(2);
+
+const WG_KEY_LEN: usize = 32;
+
+fn b64_to_hex(b64: &str) -> Result<String> {
+    let mut key = vec![];
+    b64_reader(b64.as_bytes())
+        .read_to_end(&mut key)
+        .with_context(|| format!("{b64:?} is not valid base64"))?;
+    ensure!(
+        key.len() == WG_KEY_LEN,
+        "WireGuard keys have {WG_KEY_LEN} bytes"
+    );
+    Ok(key.iter().map(|b| format!("{b:02x}")).collect())
+}
+
+fn hex_to_b64(hex: &str) -> Result<String> {
+    ensure!(hex.len() == 2 * WG_KEY_LEN, "malformed key {hex:?}");
+    let key = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+        .collect::<Result<Vec<u8>, _>>()
+        .with_context(|| format!("malformed key {hex:?}"))?;
+    Ok(fmt_b64(&key).to_string())
+}
+
+/// Send `request` and return the response lines, without the terminating
+/// empty line; fails unless the response reports `errno=0`
+fn transact(socket: &Path, request: &str) -> Result<Vec<String>> {
+    let mut stream = UnixStream::connect(socket)
+        .with_context(|| format!("could not connect to WireGuard socket {socket:?}"))?;
+    stream.set_read_timeout(Some(UAPI_TIMEOUT))?;
+    stream.set_write_timeout(Some(UAPI_TIMEOUT))?;
+    stream.write_all(request.as_bytes())?;
+
+    let mut lines = vec![];
+    for line in BufReader::new(&stream).lines() {
+        let line = line?;
+        if line.is_empty() {
+            break;
+        }
+        lines.push(line);
+    }
+    match lines.last().and_then(|l| l.strip_prefix("errno=")) {
+        Some("0") => {
+            lines.pop();
+            Ok(lines)
+        }
+        Some(errno) => bail!("WireGuard socket {socket:?} reported errno {errno}"),
+        None => bail!("WireGuard socket {socket:?} sent an incomplete response"),
+    }
+}
+
+/// Set the preshared key of the existing peer `peer`
+pub fn set_psk(socket: &Path, peer: &str, psk: &str) -> Result<()> {
+    let request = format!(
+        "set=1\npublic_key={}\nupdate_only=true\npreshared_key={}\n\n",
+        b64_to_hex(peer)?,
+        b64_to_hex(psk)?
+    );
+    transact(socket, &request)?;
+    Ok(())
+}
+
+/// The preshared key currently set for `peer`, if the peer exists
+pub fn psk_of(socket: &Path, peer: &str) -> Result<Option<String>> {
+    let response = transact(socket, "get=1\n\n")?;
+    find_psk(&response, &b64_to_hex(peer)?)
+}
+
+/// Find the preshared key of the peer with the hex encoded key `peer` in a
+/// `get` response
+fn find_psk(response: &[String], peer: &str) -> Result<Option<String>> {
+    let mut in_peer = false;
+    for line in response {
+        match line.split_once('=') {
+            Some(("public_key", key)) => in_peer = key == peer,
+            Some(("preshared_key", psk)) if in_peer => return hex_to_b64(psk).map(Some),
+            _ => {}
+        }
+    }
+    Ok(None)
+}
+
+/// Whether anything answers on `socket`
+pub fn reachable(socket: &Path) -> bool {
+    transact(socket, "get=1\n\n").is_ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn find_psk_in_response() {
+        let a = "aGVsbG8gd29ybGQgaGVsbG8gd29ybGQgaGVsbG8gd28=";
+        let b = "b2ZmaWNlIG9mZmljZSBvZmZpY2Ugb2ZmaWNlIG9mZmk=";
+        let psk = "bGFwdG9wIGxhcHRvcCBsYXB0b3AgbGFwdG9wIGxhcHQ=";
+        let response: Vec<String> = [
+            "private_key=0000000000000000000000000000000000000000000000000000000000000000"
+                .to_string(),
+            "listen_port=51820".to_string(),
+            format!("public_key={}", b64_to_hex(a).unwrap()),
+            "preshared_key=0000000000000000000000000000000000000000000000000000000000000000"
+                .to_string(),
+            format!("public_key={}", b64_to_hex(b).unwrap()),
+            "endpoint=192.0.2.1:51820".to_string(),
+            format!("preshared_key={}", b64_to_hex(psk).unwrap()),
+        ]
+        .into();
+
+        let hex_b = b64_to_hex(b).unwrap();
+        assert_eq!(find_psk(&response, &hex_b).unwrap().as_deref(), Some(psk));
+        let hex_psk = b64_to_hex(psk).unwrap();
+        assert_eq!(find_psk(&response, &hex_psk).unwrap(), None);
+        assert!(b64_to_hex("aGVsbG8=").is_err());
+    }
+}
@@ -0,0 +1,194 @@
+//! Handshakes over unix domain datagram sockets
+//!
+//! Instances on one host, like integration tests, fuzzing setups and
+//! sidecar containers sharing a volume, can exchange keys over datagram
+//! sockets in the file system instead of UDP ports:
+//!
+//! ```toml
+//! listen_unix = ["/run/rosenpass/a.sock"]
+//!
+//! [[peers]]
+//! public_key = "b.pqpk"
+//! endpoint = "unix:/run/rosenpass/b.sock"
+//! ```
+//!
+//! `listen unix:<path>` does the same on the command line. The sockets are
+//! used alongside those of `listen`; with `listen_unix` alone, no UDP socket
+//! is opened at all. Like UDP, a peer is answered on the socket it sent
+//! from, so peers need to send from a socket bound to a path, which all
+//! rosenpass sockets are.
+//!
+//! The rest of rosenpass knows peers by their IP address, so every socket
+//! path stands for an address of its own in the discard-only prefix
+//! `100::/64`, with port zero; UDP sockets refuse to send to those
+//! addresses, and the unix sockets to any others.
+
+use anyhow::{ensure, Context, Result};
+use std::{
+    collections::hash_map::DefaultHasher,
+    collections::HashMap,
+    fs,
+    hash::{Hash, Hasher},
+    io::{self, ErrorKind},
+    net::{Ipv6Addr, SocketAddr, SocketAddrV6},
+    os::unix::fs::FileTypeExt,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use crate::upgrade;
+
+/// Endpoints starting with this are paths of unix sockets
+pub const PREFIX: &str = "unix:";
+
+/// The paths of the sockets seen so far, by the address they stand for
+static PATHS: Mutex<Option<HashMap<SocketAddr, PathBuf>>> = Mutex::new(None);
+
+/// The address standing for the socket at `path`
+pub fn address_of(path: &Path) -> SocketAddr {
+    let mut hasher = DefaultHasher::new();
+    path.hash(&mut hasher);
+    let h = hasher.finish();
+    let ip = Ipv6Addr::new(
+        0x100,
+        0,
+        0,
+        0,
+        (h >> 48) as u16,
+        (h >> 32) as u16,
+        (h >> 16) as u16,
+        h as u16,
+    );
+    let addr = SocketAddr::V6(SocketAddrV6::new(ip, 0, 0, 0));
+    PATHS
+        .lock()
+        .unwrap()
+        .get_or_insert_with(HashMap::new)
+        .insert(addr, path.to_owned());
+    addr
+}
+
+/// Whether `addr` stands for a unix socket
+pub fn is_unix(addr: &SocketAddr) -> bool {
+    match addr {
+        SocketAddr::V6(v6) => v6.ip().segments()[..4] == [0x100, 0, 0, 0] && v6.port() == 0,
+        SocketAddr::V4(_) => false,
+    }
+}
+
+/// The path of the socket `addr` stands for, if it is known
+pub fn path_of(addr: &SocketAddr) -> Option<PathBuf> {
+    PATHS.lock().unwrap().as_ref()?.get(addr).cloned()
+}
+
+/// The error of sockets asked to send to an address of the other kind
+pub fn wrong_kind() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "unix and UDP sockets do not reach each other",
+    )
+}
+
+/// A datagram socket bound to a path, removed again when dropped
+#[derive(Debug)]
+pub struct UnixSocket {
+    pub socket: mio::net::UnixDatagram,
+    path: PathBuf,
+}
+
+impl UnixSocket {
+    pub fn bind(path: &Path) -> Result<Self> {
+        if let Some(socket) = upgrade::take_unix_datagram(path) {
+            socket.set_nonblocking(true)?;
+            address_of(path);
+            return Ok(Self {
+                socket: mio::net::UnixDatagram::from_std(socket),
+                path: path.to_owned(),
+            });
+        }
+        // a socket left behind by an instance which did not exit cleanly
+        if let Ok(meta) = fs::symlink_metadata(path) {
+            ensure!(
+                meta.file_type().is_socket(),
+                "{path:?} exists and is not a socket"
+            );
+            fs::remove_file(path)?;
+        }
+        let socket = mio::net::UnixDatagram::bind(path)
+            .with_context(|| format!("could not bind unix socket {path:?}"))?;
+        address_of(path);
+        Ok(Self {
+            socket,
+            path: path.to_owned(),
+        })
+    }
+
+    pub fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        let path = match is_unix(&addr) {
+            true => path_of(&addr).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, format!("no unix socket is {addr}"))
+            })?,
+            false => return Err(wrong_kind()),
+        };
+        match self.socket.send_to(buf, path) {
+            // lost like UDP datagrams to where nobody listens
+            Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::ConnectionRefused) => {
+                Ok(buf.len())
+            }
+            res => res,
+        }
+    }
+
+    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        loop {
+            let (len, from) = self.socket.recv_from(buf)?;
+            // sockets without a path can not be answered
+            if let Some(path) = from.as_pathname() {
+                return Ok((len, address_of(path)));
+            }
+        }
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        address_of(&self.path)
+    }
+}
+
+impl Drop for UnixSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn paths_stand_for_addresses() {
+        let dir = std::env::temp_dir().join(format!("rp-unix-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (a, b) = (
+            UnixSocket::bind(&dir.join("a")).unwrap(),
+            UnixSocket::bind(&dir.join("b")).unwrap(),
+        );
+        assert!(is_unix(&a.local_addr()));
+        assert_ne!(a.local_addr(), b.local_addr());
+        assert_eq!(address_of(&dir.join("a")), a.local_addr());
+        assert!(!is_unix(&"[100::1]:9999".parse().unwrap()));
+        assert!(!is_unix(&"127.0.0.1:0".parse().unwrap()));
+
+        a.send_to(b"hello", b.local_addr()).unwrap();
+        let mut buf = [0u8; 16];
+        let (len, from) = b.recv_from(&mut buf).unwrap();
+        assert_eq!((&buf[..len], from), (&b"hello"[..], a.local_addr()));
+        let err = a.send_to(b"hello", "127.0.0.1:9".parse().unwrap());
+        assert_eq!(err.unwrap_err().kind(), io::ErrorKind::Unsupported);
+        let gone = address_of(&dir.join("gone"));
+        assert_eq!(a.send_to(b"hello", gone).unwrap(), 5);
+
+        drop((a, b));
+        assert!(!dir.join("a").exists());
+        fs::remove_dir(&dir).unwrap();
+    }
+}
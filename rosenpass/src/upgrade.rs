@@ -0,0 +1,397 @@
+//! Upgrading the binary of a running instance in place
+//!
+//! Restarting a busy responder for a security update costs every peer a
+//! handshake, and every peer without a key until it completed. Instead, on
+//! SIGUSR2 or `rosenpass upgrade`, which goes through the control socket,
+//! rosenpass executes its binary anew in the same process, with the same
+//! arguments: the binary at the path it was started from, or the one given
+//! to `rosenpass upgrade --binary`. The new binary takes over
+//!
+//! - the sockets handshakes are received on, unix sockets included, and the
+//!   control socket, so no message sent in between is lost
+//! - the biscuit keys, and the sessions, biscuit numbers and endpoints of
+//!   all peers, see [crate::ha]; so peers keep their keys and their rekey
+//!   schedule, and answers to messages the old binary sent are accepted
+//!
+//! The state is handed over in a memfd sealed against any change, named by
+//! the [ENV] environment variable, so it never leaves memory. Handshakes in
+//! progress are started over, and other listeners, like the one of the
+//! [crate::api], are bound anew. The new binary loads the config again, so
+//! changes to it apply as well; the state of peers no longer in the config
+//! is dropped, as are the sockets no longer listened on.
+//!
+//! The new binary is run with `--version` first, and not executed unless
+//! that succeeds. Should it fail to start anyway, the process exits like on
+//! any other error, and the service manager restarts it.
+
+use anyhow::{bail, ensure, Context, Result};
+use std::{
+    ffi::OsString,
+    fs::File,
+    io::{Read, Seek, SeekFrom, Write},
+    mem::ManuallyDrop,
+    net::{SocketAddr, UdpSocket},
+    os::{
+        fd::{AsRawFd, FromRawFd, IntoRawFd, RawFd},
+        unix::{
+            net::{UnixDatagram, UnixListener},
+            process::CommandExt,
+        },
+    },
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+};
+
+use rosenpass_sodium::helpers::memzero;
+
+use crate::ha::SyncMsg;
+
+/// Names the memfd holding the state handed over
+pub const ENV: &str = "ROSENPASS_UPGRADE";
+
+const MAGIC: &[u8] = b"rosenpass upgrade v1";
+
+static SIGNALS: AtomicU64 = AtomicU64::new(0);
+static WAKER: OnceLock<Arc<mio::Waker>> = OnceLock::new();
+static STARTED_FROM: OnceLock<Option<PathBuf>> = OnceLock::new();
+
+/// What was handed over to us, once it is read
+static INHERITED: Mutex<Option<Inherited>> = Mutex::new(None);
+static READ: OnceLock<()> = OnceLock::new();
+
+#[derive(Debug, Default)]
+struct Inherited {
+    fds: Vec<RawFd>,
+    state: Vec<SyncMsg>,
+}
+
+extern "C" fn on_signal(_: libc::c_int) {
+    SIGNALS.fetch_add(1, Ordering::SeqCst);
+    if let Some(waker) = WAKER.get() {
+        let _ = waker.wake();
+    }
+}
+
+/// Have SIGUSR2 wake the event loop through `waker` and increase [signals]
+pub fn handle_signal(waker: Arc<mio::Waker>) -> Result<()> {
+    let _ = WAKER.set(waker);
+    let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+    action.sa_sigaction = on_signal as *const () as usize;
+    let res = unsafe { libc::sigaction(libc::SIGUSR2, &action, std::ptr::null_mut()) };
+    ensure!(
+        res == 0,
+        "could not handle SIGUSR2: {}",
+        std::io::Error::last_os_error()
+    );
+    Ok(())
+}
+
+/// Upgrade just like on SIGUSR2, see [crate::watch]; false if upgrades are
+/// not handled
+pub fn request() -> bool {
+    let Some(waker) = WAKER.get() else {
+        return false;
+    };
+    SIGNALS.fetch_add(1, Ordering::SeqCst);
+    let _ = waker.wake();
+    true
+}
+
+/// How many upgrade signals arrived so far
+pub fn signals() -> u64 {
+    SIGNALS.load(Ordering::SeqCst)
+}
+
+/// The path the binary was started from; the first call is to be made
+/// before the binary could be replaced, when the server is set up
+pub fn started_from() -> Option<&'static Path> {
+    STARTED_FROM
+        .get_or_init(|| std::env::current_exe().ok())
+        .as_deref()
+}
+
+/// Execute `binary` in place of this one, handing over the sockets `fds`
+/// and `state`; only returns if that failed
+pub fn exec(binary: &Path, fds: &[RawFd], state: &[SyncMsg]) -> anyhow::Error {
+    match prepare(binary, fds, state) {
+        Ok(memfd) => {
+            let err = Command::new(binary)
+                .args(std::env::args_os().skip(1).collect::<Vec<OsString>>())
+                .env(ENV, memfd.as_raw_fd().to_string())
+                .exec();
+            for &fd in fds {
+                set_cloexec(fd, true);
+            }
+            anyhow::Error::new(err).context(format!("could not execute {binary:?}"))
+        }
+        Err(e) => e,
+    }
+}
+
+/// Check `binary`, write the memfd and let the sockets be inherited
+fn prepare(binary: &Path, fds: &[RawFd], state: &[SyncMsg]) -> Result<File> {
+    let out = Command::new(binary)
+        .arg("--version")
+        .output()
+        .with_context(|| format!("could not run {binary:?}"))?;
+    ensure!(
+        out.status.success(),
+        "{binary:?} --version failed with {}",
+        out.status
+    );
+
+    let memfd = handover(fds, state)?;
+    for &fd in fds {
+        set_cloexec(fd, false);
+    }
+    Ok(memfd)
+}
+
+/// The sealed memfd handing over the sockets `fds` and `state` to a binary
+/// executed with [ENV] naming it; it is inherited by any binary executed
+pub fn handover(fds: &[RawFd], state: &[SyncMsg]) -> Result<File> {
+    let mut buf = encode(fds, state);
+    let res = seal(&buf);
+    memzero(&mut buf);
+    res
+}
+
+fn encode(fds: &[RawFd], state: &[SyncMsg]) -> Vec<u8> {
+    let mut buf = MAGIC.to_vec();
+    buf.extend_from_slice(&(fds.len() as u32).to_be_bytes());
+    for &fd in fds {
+        buf.extend_from_slice(&(fd as u32).to_be_bytes());
+    }
+    buf.extend_from_slice(&(state.len() as u32).to_be_bytes());
+    for msg in state {
+        let mut bytes = msg.to_bytes();
+        buf.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+        buf.extend_from_slice(&bytes);
+        memzero(&mut bytes);
+    }
+    buf
+}
+
+fn decode(buf: &[u8]) -> Result<Inherited> {
+    ensure!(buf.starts_with(MAGIC), "not an upgrade handover");
+    let mut rest = &buf[MAGIC.len()..];
+    let mut inherited = Inherited::default();
+    for _ in 0..take_u32(&mut rest)? {
+        inherited.fds.push(take_u32(&mut rest)? as RawFd);
+    }
+    for _ in 0..take_u32(&mut rest)? {
+        let len = take_u32(&mut rest)? as usize;
+        ensure!(rest.len() >= len, "upgrade handover is truncated");
+        inherited.state.push(SyncMsg::from_bytes(&rest[..len])?);
+        rest = &rest[len..];
+    }
+    ensure!(rest.is_empty(), "trailing data in upgrade handover");
+    Ok(inherited)
+}
+
+fn take_u32(rest: &mut &[u8]) -> Result<u32> {
+    ensure!(rest.len() >= 4, "upgrade handover is truncated");
+    let (n, tail) = rest.split_at(4);
+    *rest = tail;
+    Ok(u32::from_be_bytes(n.try_into().unwrap()))
+}
+
+/// A memfd holding `buf`, sealed against changes and inherited by the
+/// binary executed next
+fn seal(buf: &[u8]) -> Result<File> {
+    let fd = unsafe { libc::memfd_create(c"rosenpass-upgrade".as_ptr(), libc::MFD_ALLOW_SEALING) };
+    ensure!(
+        fd >= 0,
+        "could not create memfd: {}",
+        std::io::Error::last_os_error()
+    );
+    let mut file = unsafe { File::from_raw_fd(fd) };
+    file.write_all(buf)?;
+    let seals = libc::F_SEAL_SHRINK | libc::F_SEAL_GROW | libc::F_SEAL_WRITE | libc::F_SEAL_SEAL;
+    ensure!(
+        unsafe { libc::fcntl(fd, libc::F_ADD_SEALS, seals) } == 0,
+        "could not seal memfd: {}",
+        std::io::Error::last_os_error()
+    );
+    Ok(file)
+}
+
+fn set_cloexec(fd: RawFd, cloexec: bool) {
+    let flags = match cloexec {
+        true => libc::FD_CLOEXEC,
+        false => 0,
+    };
+    unsafe { libc::fcntl(fd, libc::F_SETFD, flags) };
+}
+
+/// Read what was handed over to us once, if we were started by an upgrade
+fn inherited() -> std::sync::MutexGuard<'static, Option<Inherited>> {
+    READ.get_or_init(|| {
+        let Some(fd) = std::env::var_os(ENV) else {
+            return;
+        };
+        // not to be handed on to whatever we run
+        std::env::remove_var(ENV);
+        match read_handover(&fd) {
+            Ok(inherited) => *INHERITED.lock().unwrap() = Some(inherited),
+            Err(e) => log::warn!("Starting afresh, the upgrade handover is unusable: {e:#}"),
+        }
+    });
+    INHERITED.lock().unwrap()
+}
+
+fn read_handover(fd: &OsString) -> Result<Inherited> {
+    let fd: RawFd = fd
+        .to_str()
+        .and_then(|s| s.parse().ok())
+        .with_context(|| format!("{ENV} is not a file descriptor: {fd:?}"))?;
+    let seals = unsafe { libc::fcntl(fd, libc::F_GET_SEALS) };
+    if seals & libc::F_SEAL_WRITE == 0 {
+        bail!("file descriptor {fd} is not a sealed memfd");
+    }
+    let mut file = unsafe { File::from_raw_fd(fd) };
+    file.seek(SeekFrom::Start(0))?;
+    let mut buf = Vec::new();
+    file.read_to_end(&mut buf)?;
+    let res = decode(&buf);
+    memzero(&mut buf);
+    let inherited = res?;
+    for &fd in inherited.fds.iter() {
+        set_cloexec(fd, true);
+    }
+    Ok(inherited)
+}
+
+/// Take the first socket handed over which `fits`
+fn take<T: FromRawFd + IntoRawFd>(fits: impl Fn(&T) -> bool) -> Option<T> {
+    let mut inherited = inherited();
+    let fds = &mut inherited.as_mut()?.fds;
+    let pos = fds.iter().position(|&fd| {
+        let socket = ManuallyDrop::new(unsafe { T::from_raw_fd(fd) });
+        fits(&socket)
+    })?;
+    Some(unsafe { T::from_raw_fd(fds.remove(pos)) })
+}
+
+fn socket_type(fd: RawFd) -> (libc::c_int, libc::c_int) {
+    let opt = |name| {
+        let mut val: libc::c_int = 0;
+        let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let res = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                name,
+                &mut val as *mut _ as *mut _,
+                &mut len,
+            )
+        };
+        if res == 0 {
+            val
+        } else {
+            -1
+        }
+    };
+    (opt(libc::SO_DOMAIN), opt(libc::SO_TYPE))
+}
+
+/// The UDP socket bound to `addr` handed over, if there is one
+pub fn take_udp(addr: SocketAddr) -> Option<UdpSocket> {
+    take(|s: &UdpSocket| {
+        let (domain, ty) = socket_type(s.as_raw_fd());
+        matches!(domain, libc::AF_INET | libc::AF_INET6)
+            && ty == libc::SOCK_DGRAM
+            && s.local_addr()
+                .is_ok_and(|a| a == addr || (addr.port() == 0 && a.ip() == addr.ip()))
+    })
+}
+
+/// The UDP socket bound to `addr` handed over, or a new one
+pub fn bind_udp(addr: SocketAddr) -> std::io::Result<mio::net::UdpSocket> {
+    match take_udp(addr) {
+        Some(socket) => {
+            socket.set_nonblocking(true)?;
+            Ok(mio::net::UdpSocket::from_std(socket))
+        }
+        None => mio::net::UdpSocket::bind(addr),
+    }
+}
+
+/// The unix datagram socket bound to `path` handed over, if there is one
+pub fn take_unix_datagram(path: &Path) -> Option<UnixDatagram> {
+    take(|s: &UnixDatagram| {
+        socket_type(s.as_raw_fd()) == (libc::AF_UNIX, libc::SOCK_DGRAM)
+            && s.local_addr().is_ok_and(|a| a.as_pathname() == Some(path))
+    })
+}
+
+/// The unix socket listening at `path` handed over, if there is one
+pub fn take_unix_listener(path: &Path) -> Option<UnixListener> {
+    take(|s: &UnixListener| {
+        socket_type(s.as_raw_fd()) == (libc::AF_UNIX, libc::SOCK_STREAM)
+            && s.local_addr().is_ok_and(|a| a.as_pathname() == Some(path))
+    })
+}
+
+/// The state handed over, empty unless we were started by an upgrade; the
+/// sockets not taken by then are closed
+pub fn take_state() -> Vec<SyncMsg> {
+    let Some(inherited) = inherited().take() else {
+        return Vec::new();
+    };
+    for fd in inherited.fds {
+        unsafe { libc::close(fd) };
+    }
+    inherited.state
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{msgs::BISCUIT_ID_LEN, protocol::SymKey};
+
+    #[test]
+    fn handover_is_read_back() {
+        let msg = SyncMsg {
+            active_for: 0.0,
+            biscuit_ctr: [3; BISCUIT_ID_LEN],
+            biscuit_keys: [(1.0, SymKey::random()), (2.0, SymKey::random())],
+            peers: Vec::new(),
+        };
+        let buf = encode(&[3, 7], &[msg]);
+        let file = seal(&buf).unwrap();
+        let mut sealed = unsafe { File::from_raw_fd(libc::dup(file.as_raw_fd())) };
+        assert!(sealed.write_all(b"x").is_err());
+
+        let got = read_handover(&file.as_raw_fd().to_string().into()).unwrap();
+        assert_eq!(got.fds, vec![3, 7]);
+        assert_eq!(got.state[0].biscuit_ctr, [3; BISCUIT_ID_LEN]);
+        assert!(decode(&buf[..buf.len() - 1]).is_err());
+        std::mem::forget(file); // closed by read_handover
+    }
+
+    #[test]
+    fn sockets_are_told_apart() {
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = udp.local_addr().unwrap();
+        let path = std::env::temp_dir().join(format!("rp-upgrade-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let unix = UnixDatagram::bind(&path).unwrap();
+        *INHERITED.lock().unwrap() = Some(Inherited {
+            fds: vec![unix.into_raw_fd(), udp.into_raw_fd()],
+            state: Vec::new(),
+        });
+        let _ = READ.set(());
+
+        assert!(take_unix_listener(&path).is_none());
+        assert!(take_udp("127.0.0.1:1".parse().unwrap()).is_none());
+        assert_eq!(take_udp(addr).unwrap().local_addr().unwrap(), addr);
+        assert!(take_unix_datagram(&path).is_some());
+        assert!(take_state().is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
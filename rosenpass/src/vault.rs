@@ -0,0 +1,297 @@
+//! Fetching key material from HashiCorp Vault
+//!
+//! Keys are stored base64 encoded in a field of a KV secret, optionally
+//! encrypted with a Transit key. Rather than linking a Vault client, the
+//! `vault` command line tool is used, so the usual `VAULT_ADDR`,
+//! `VAULT_TOKEN` and `~/.vault-token` mechanisms for authentication apply.
+//! Whatever the command prints is wiped once it is read.
+//!
+//! With `renew_token`, the token is renewed while the exchange runs, two
+//! thirds into each lease it gets. Keys fetched from Vault are fetched again
+//! whenever the config is loaded again, see [crate::watch].
+
+use anyhow::{bail, ensure, Context, Result};
+use log::{debug, info, warn};
+use rosenpass_util::b64::b64_reader;
+use serde::{Deserialize, Serialize};
+use std::{
+    io::Read,
+    ops::{Deref, DerefMut},
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::coloring::Secret;
+
+/// Wait before trying again after a renewal failed
+const RENEWAL_RETRY: Duration = Duration::from_secs(10);
+
+/// Shortest wait between renewals
+const MIN_RENEWAL_WAIT: Duration = Duration::from_secs(1);
+
+/// Connection settings shared by all keys fetched from Vault
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VaultConfig {
+    /// Address of the Vault server; `VAULT_ADDR` is used if unset
+    #[serde(default)]
+    pub address: Option<String>,
+
+    /// Renew the Vault token while the exchange runs, two thirds into each
+    /// lease, keeping a token with a short TTL from expiring
+    #[serde(default)]
+    pub renew_token: bool,
+
+    /// Renew the Vault token at least every so many seconds; implies
+    /// `renew_token`
+    #[serde(default)]
+    pub token_renew_interval: Option<f64>,
+}
+
+/// Output of the vault command, wiped when dropped
+#[derive(Debug, Default)]
+struct Wiped(Vec<u8>);
+
+impl Deref for Wiped {
+    type Target = Vec<u8>;
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for Wiped {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl Drop for Wiped {
+    fn drop(&mut self) {
+        rosenpass_sodium::helpers::memzero(&mut self.0);
+    }
+}
+
+/// The lease of a renewed token, as `vault token renew -format=json` tells
+#[derive(Debug, Deserialize)]
+struct Renewal {
+    auth: Lease,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+struct Lease {
+    /// Seconds the token is valid for; zero if it does not expire
+    lease_duration: u64,
+    renewable: bool,
+}
+
+impl Lease {
+    /// When to renew the token again, at most `interval` from now
+    fn renew_after(&self, interval: Option<Duration>) -> Duration {
+        let due = Duration::from_secs(self.lease_duration) * 2 / 3;
+        interval
+            .map_or(due, |interval| due.min(interval))
+            .max(MIN_RENEWAL_WAIT)
+    }
+}
+
+/// Location of a single key in Vault
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VaultSecret {
+    /// Path of the KV secret, e.g. `secret/rosenpass/host-a`
+    pub path: String,
+
+    /// Field of the secret holding the base64 encoded key
+    #[serde(default = "default_field")]
+    pub field: String,
+
+    /// If set, the field holds a ciphertext of this Transit key rather than
+    /// the key itself
+    #[serde(default)]
+    pub transit_key: Option<String>,
+}
+
+fn default_field() -> String {
+    "key".to_string()
+}
+
+impl VaultConfig {
+    fn command(&self) -> Command {
+        let mut cmd = Command::new("vault");
+        if let Some(addr) = self.address.as_ref() {
+            cmd.env("VAULT_ADDR", addr);
+        }
+        cmd.stdin(Stdio::null()).stderr(Stdio::inherit());
+        cmd
+    }
+
+    /// Run the vault command with `args`, returning its trimmed stdout
+    fn run(&self, args: &[&str]) -> Result<Wiped> {
+        let out = self
+            .command()
+            .args(args)
+            .output()
+            .context("could not run the vault command")?;
+        let mut stdout = Wiped(out.stdout);
+        ensure!(
+            out.status.success(),
+            "vault {} failed with {}",
+            args.first().unwrap_or(&""),
+            out.status
+        );
+        let len = stdout
+            .iter()
+            .rposition(|c| !c.is_ascii_whitespace())
+            .map_or(0, |i| i + 1);
+        stdout.truncate(len);
+        Ok(stdout)
+    }
+
+    /// Fetch the key stored at `secret`, which must be exactly `N` bytes long
+    pub fn fetch<const N: usize>(&self, secret: &VaultSecret) -> Result<Secret<N>> {
+        let field = format!("-field={}", secret.field);
+        let mut b64 = self
+            .run(&["kv", "get", &field, &secret.path])
+            .with_context(|| format!("could not read {} from vault", secret.path))?;
+
+        if let Some(transit_key) = secret.transit_key.as_ref() {
+            let ciphertext = std::str::from_utf8(&b64)
+                .with_context(|| format!("{} is not a transit ciphertext", secret.path))?
+                .to_owned();
+            b64 = self
+                .run(&[
+                    "write",
+                    "-field=plaintext",
+                    &format!("transit/decrypt/{transit_key}"),
+                    &format!("ciphertext={ciphertext}"),
+                ])
+                .with_context(|| {
+                    format!(
+                        "could not decrypt {} with transit key {transit_key}",
+                        secret.path
+                    )
+                })?;
+        }
+
+        // large enough for the key never to be moved
+        let mut key = Wiped(Vec::with_capacity(b64.len()));
+        b64_reader(b64.as_slice())
+            .read_to_end(&mut key)
+            .with_context(|| format!("{} is not valid base64", secret.path))?;
+        ensure!(
+            key.len() == N,
+            "key in {} has {} bytes, expected {N}",
+            secret.path,
+            key.len()
+        );
+        Ok(Secret::from_slice(&key))
+    }
+
+    /// Renew the Vault token once
+    fn renew(&self) -> Result<Lease> {
+        let out = self.run(&["token", "renew", "-format=json"])?;
+        let renewal: Renewal =
+            serde_json::from_slice(&out).context("vault token renew printed no lease")?;
+        Ok(renewal.auth)
+    }
+
+    /// Renew the Vault token in the background, if configured to do so
+    ///
+    /// The token is renewed right away, and then two thirds into each lease,
+    /// until it can not be renewed any further.
+    pub fn spawn_token_renewal(&self) -> Result<()> {
+        if let Some(interval) = self.token_renew_interval {
+            ensure!(
+                interval > 0.0,
+                "vault token_renew_interval must be positive"
+            );
+        } else if !self.renew_token {
+            return Ok(());
+        }
+        let interval = self.token_renew_interval.map(Duration::from_secs_f64);
+        let vault = self.clone();
+        thread::Builder::new()
+            .name("vault-token-renewal".to_string())
+            .spawn(move || {
+                let mut expires: Option<Instant> = None;
+                loop {
+                    let wait = match vault.renew() {
+                        Ok(lease) => match lease_end(lease, expires) {
+                            Ok(until) => {
+                                debug!("renewed vault token for {}s", lease.lease_duration);
+                                expires = Some(until);
+                                lease.renew_after(interval)
+                            }
+                            Err(e) => return info!("no longer renewing the vault token: {e}"),
+                        },
+                        Err(e) => {
+                            warn!("could not renew vault token: {e:#}");
+                            interval.map_or(RENEWAL_RETRY, |i| i.min(RENEWAL_RETRY))
+                        }
+                    };
+                    thread::sleep(wait);
+                }
+            })?;
+        Ok(())
+    }
+}
+
+/// When a token renewed with `lease` expires, or why renewing it again is
+/// pointless, given when it expired before
+fn lease_end(lease: Lease, expired: Option<Instant>) -> Result<Instant> {
+    if lease.lease_duration == 0 {
+        bail!("it does not expire");
+    }
+    if !lease.renewable {
+        bail!("it is not renewable");
+    }
+    let until = Instant::now() + Duration::from_secs(lease.lease_duration);
+    if expired.is_some_and(|before| until <= before) {
+        bail!(
+            "it reached its maximum TTL and expires in {}s",
+            lease.lease_duration
+        );
+    }
+    Ok(until)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn renewals_follow_the_lease() {
+        let out = br#"{"request_id":"x","auth":{"client_token":"hvs.secret","lease_duration":3600,"renewable":true,"policies":["default"]}}"#;
+        let lease = serde_json::from_slice::<Renewal>(out).unwrap().auth;
+        assert_eq!(
+            lease,
+            Lease {
+                lease_duration: 3600,
+                renewable: true
+            }
+        );
+        assert_eq!(lease.renew_after(None), Duration::from_secs(2400));
+        let interval = Some(Duration::from_secs(60));
+        assert_eq!(lease.renew_after(interval), Duration::from_secs(60));
+
+        let short = Lease {
+            lease_duration: 1,
+            renewable: true,
+        };
+        assert_eq!(short.renew_after(None), MIN_RENEWAL_WAIT);
+
+        // tokens which do not expire or can not be renewed are left alone
+        let until = lease_end(lease, None).unwrap();
+        let root = Lease {
+            lease_duration: 0,
+            renewable: false,
+        };
+        assert!(lease_end(root, None).is_err());
+        let fixed = Lease {
+            renewable: false,
+            ..lease
+        };
+        assert!(lease_end(fixed, None).is_err());
+        // as are those whose leases stop growing
+        assert!(lease_end(short, Some(until)).is_err());
+    }
+}
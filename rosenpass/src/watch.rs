@@ -0,0 +1,644 @@
+//! Applying changes to key files and peer drop-ins as they are made
+//!
+//! Config management pushing a new peer or a rotated key should not need
+//! someone to remember to signal or restart rosenpass afterwards. Peers can
+//! be kept in drop-in directories, one `*.toml` file for each with the
+//! settings of its `[[peers]]` table, taken after the peers of the config
+//! file:
+//!
+//! ```toml
+//! peer_dirs = ["/etc/rosenpass/peers.d"]
+//!
+//! [watch]
+//! debounce = 2
+//! ```
+//!
+//! With `[watch]`, the directories of the drop-ins, of the public keys of
+//! the peers and of our own keys are watched through inotify. Once changes
+//! stop for `debounce` seconds, [DEFAULT_DEBOUNCE] by default, so files
+//! written one after the other are taken up together, the files are compared
+//! to what they were before; if any differ, the config is loaded again and
+//! checked like on startup.
+//!
+//! The peers of the config file and the drop-ins are compared just like
+//! those of the [crate::peer_store]: peers which are gone are removed, and
+//! peers whose settings or public key changed are removed and added again.
+//! Keys stored in Vault are fetched again whenever the config is loaded
+//! again, and count as changed if they differ from what was fetched before.
+//! A new key pair of our own is checked to load, and then put to use by an
+//! upgrade in place, see [crate::upgrade], which takes up any other change
+//! to the config as well. A change which does not load is reported, and
+//! everything stays as it is until the next change.
+//!
+//! Without `[watch]`, nothing is watched, but the same happens when the
+//! `reload` command is sent on the control socket, which `rosenpass peer
+//! --reload` does after editing the peers; the files are then taken up
+//! whether or not they look changed.
+//!
+//! Directories which do not exist when rosenpass starts are not watched.
+//! Changes are only picked up if the config was read from a file.
+
+use anyhow::{bail, ensure, Context, Result};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::CString,
+    fs::{self, File},
+    io::{ErrorKind, Read, Write},
+    os::{
+        fd::{AsRawFd, FromRawFd},
+        unix::ffi::OsStrExt,
+    },
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, Receiver},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    config::{Rosenpass, RosenpassPeer},
+    peer_store::{self, Update},
+    pqkem::{StaticKEM, KEM},
+    protocol::{SPk, Timing},
+    upgrade,
+    vault::{VaultConfig, VaultSecret},
+};
+
+/// Default of [Watch::debounce]
+pub const DEFAULT_DEBOUNCE: Timing = 2.0;
+
+/// The longest [Watch::debounce]
+pub const MAX_DEBOUNCE: Timing = 60.0;
+
+/// Events which may change a file in a watched directory
+const EVENTS: u32 = libc::IN_CLOSE_WRITE
+    | libc::IN_MOVED_TO
+    | libc::IN_MOVED_FROM
+    | libc::IN_CREATE
+    | libc::IN_DELETE
+    | libc::IN_ATTRIB;
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Watch {
+    /// Seconds without changes before they are applied
+    #[serde(default)]
+    pub debounce: Option<Timing>,
+}
+
+impl Watch {
+    pub fn validate(&self) -> Result<()> {
+        if let Some(debounce) = self.debounce {
+            ensure!(
+                (0.0..=MAX_DEBOUNCE).contains(&debounce),
+                "watch debounce must be between 0 and {MAX_DEBOUNCE} seconds"
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Whether `peer` is one of the config file or of a drop-in, rather than
+/// from one of the sources [Rosenpass::load_external_peers] reads
+pub fn watched(peer: &RosenpassPeer) -> bool {
+    !(peer.stored || peer.from_ldap || peer.provisioned || peer.enrolled)
+}
+
+/// The drop-ins of `dir`, in order
+pub fn drop_ins(dir: &Path) -> Result<Vec<PathBuf>> {
+    let entries = match fs::read_dir(dir) {
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        res => res.with_context(|| format!("could not read peer directory {dir:?}"))?,
+    };
+    let mut paths = vec![];
+    for entry in entries {
+        let path = entry?.path();
+        let hidden = path
+            .file_name()
+            .is_some_and(|name| name.as_bytes().starts_with(b"."));
+        if !hidden && path.extension().is_some_and(|ext| ext == "toml") {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// Read the peers of the drop-ins in `dir`
+pub fn load_dir(dir: &Path) -> Result<Vec<RosenpassPeer>> {
+    drop_ins(dir)?
+        .into_iter()
+        .map(|path| {
+            let text =
+                fs::read_to_string(&path).with_context(|| format!("could not read {path:?}"))?;
+            parse_drop_in(&path, &text)
+        })
+        .collect()
+}
+
+/// The peer of the drop-in `path` reads `text` from
+pub fn parse_drop_in(path: &Path, text: &str) -> Result<RosenpassPeer> {
+    let mut peer: RosenpassPeer =
+        toml::from_str(text).with_context(|| format!("peer {path:?} is invalid"))?;
+    peer.from_dir = true;
+    Ok(peer)
+}
+
+/// What of a config is watched
+#[derive(Debug, Default)]
+struct Watched {
+    secret_key: PathBuf,
+    public_key: PathBuf,
+    secret_key_vault: Option<VaultSecret>,
+    vault: VaultConfig,
+    peer_dirs: Vec<PathBuf>,
+    /// The peers of the config file and the drop-ins
+    peers: Vec<RosenpassPeer>,
+}
+
+impl Watched {
+    fn of(config: &Rosenpass) -> Self {
+        Self {
+            secret_key: config.secret_key.clone(),
+            public_key: config.public_key.clone(),
+            secret_key_vault: config.secret_key_vault.clone(),
+            vault: config.vault.clone().unwrap_or_default(),
+            peer_dirs: config.peer_dirs.clone(),
+            peers: config
+                .peers
+                .iter()
+                .filter(|p| watched(p))
+                .cloned()
+                .collect(),
+        }
+    }
+}
+
+/// Hashes of the files a change of `config` shows in, [None] for those
+/// which do not exist, and of the keys fetched from Vault, under [vault_id]
+type Snapshot = HashMap<PathBuf, Option<[u8; 32]>>;
+
+/// The entry of the key at `secret` in a [Snapshot]
+fn vault_id(secret: &VaultSecret) -> PathBuf {
+    format!("vault:{}#{}", secret.path, secret.field).into()
+}
+
+/// The entry of the public key of `peer` in a [Snapshot]
+fn key_id(peer: &RosenpassPeer) -> PathBuf {
+    match peer.public_key_vault.as_ref() {
+        Some(secret) => vault_id(secret),
+        None => peer.public_key.clone(),
+    }
+}
+
+/// The entries of our own keys in a [Snapshot]
+fn own_key_ids(config: &Watched) -> Vec<PathBuf> {
+    let secret_key = match config.secret_key_vault.as_ref() {
+        Some(secret) => vault_id(secret),
+        None => config.secret_key.clone(),
+    };
+    vec![secret_key, config.public_key.clone()]
+}
+
+/// The files of `config`, and with `fetch` the keys it keeps in Vault
+fn snapshot(config: &Watched, fetch: bool) -> Snapshot {
+    let mut files = vec![config.secret_key.clone(), config.public_key.clone()];
+    files.extend(config.peers.iter().map(|p| p.public_key.clone()));
+    for dir in config.peer_dirs.iter() {
+        files.extend(drop_ins(dir).unwrap_or_default());
+    }
+    let hash = |data: &[u8]| crate::sodium::hash(data).ok();
+    let mut stamps: Snapshot = files
+        .into_iter()
+        .map(|path| {
+            let stamp = fs::read(&path).ok().and_then(|data| hash(&data));
+            (path, stamp)
+        })
+        .collect();
+    if !fetch {
+        return stamps;
+    }
+    if let Some(secret) = config.secret_key_vault.as_ref() {
+        let key = config.vault.fetch::<{ StaticKEM::SK_LEN }>(secret);
+        stamps.insert(vault_id(secret), key.ok().and_then(|k| hash(k.secret())));
+    }
+    for secret in config
+        .peers
+        .iter()
+        .filter_map(|p| p.public_key_vault.as_ref())
+    {
+        let key = config.vault.fetch::<{ StaticKEM::PK_LEN }>(secret);
+        stamps.insert(vault_id(secret), key.ok().and_then(|k| hash(k.secret())));
+    }
+    stamps
+}
+
+/// Whether the files of `now` are the same as in `before`
+fn unchanged(now: &Snapshot, before: &Snapshot) -> bool {
+    now.iter()
+        .all(|(path, stamp)| before.get(path) == Some(stamp))
+        && before.keys().filter(|id| !is_vault_id(id)).count() == now.len()
+}
+
+fn is_vault_id(id: &Path) -> bool {
+    id.as_os_str().as_bytes().starts_with(b"vault:")
+}
+
+/// The directories to watch for the files of `config`
+fn dirs(config: &Watched) -> HashSet<PathBuf> {
+    let parent = |path: &Path| match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_owned(),
+        _ => PathBuf::from("."),
+    };
+    let mut dirs: HashSet<PathBuf> = config.peer_dirs.iter().cloned().collect();
+    dirs.insert(parent(&config.secret_key));
+    dirs.insert(parent(&config.public_key));
+    dirs.extend(config.peers.iter().map(|p| parent(&p.public_key)));
+    dirs
+}
+
+/// An inotify instance, watching directories
+struct Inotify {
+    fd: File,
+    watched: HashSet<PathBuf>,
+}
+
+impl Inotify {
+    fn new() -> Result<Self> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC | libc::IN_NONBLOCK) };
+        if fd < 0 {
+            bail!(
+                "could not set up inotify: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+        Ok(Self {
+            fd: unsafe { File::from_raw_fd(fd) },
+            watched: HashSet::new(),
+        })
+    }
+
+    /// Watch all of `dirs`, unless they are already
+    fn add(&mut self, dirs: HashSet<PathBuf>) {
+        for dir in dirs {
+            if self.watched.contains(&dir) {
+                continue;
+            }
+            let Ok(path) = CString::new(dir.as_os_str().as_bytes()) else {
+                continue;
+            };
+            let wd = unsafe { libc::inotify_add_watch(self.fd.as_raw_fd(), path.as_ptr(), EVENTS) };
+            match wd < 0 {
+                true => warn!("not watching {dir:?}: {}", std::io::Error::last_os_error()),
+                false => {
+                    self.watched.insert(dir);
+                }
+            }
+        }
+    }
+
+    /// Wait up to `timeout`, or for ever, for an event or for `trigger`
+    fn wait(&self, trigger: &File, timeout: Option<Duration>) -> Result<Woken> {
+        let mut fds = [&self.fd, trigger].map(|f| libc::pollfd {
+            fd: f.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        });
+        let timeout = timeout.map_or(-1, |t| t.as_millis() as libc::c_int);
+        loop {
+            match unsafe { libc::poll(fds.as_mut_ptr(), 2, timeout) } {
+                -1 if std::io::Error::last_os_error().kind() == ErrorKind::Interrupted => {}
+                -1 => {
+                    return Err(std::io::Error::last_os_error())
+                        .context("could not wait on inotify")
+                }
+                _ => break,
+            }
+        }
+        // what changed is found by comparing the files, the events only
+        // tell that something did
+        drain(&self.fd).context("could not read from inotify")?;
+        drain(trigger).context("could not read the reload requests")?;
+        let ready = |fd: &libc::pollfd| fd.revents != 0;
+        Ok(match fds {
+            [_, trigger] if ready(&trigger) => Woken::Requested,
+            [inotify, _] if ready(&inotify) => Woken::Changed,
+            _ => Woken::TimedOut,
+        })
+    }
+}
+
+/// What [Inotify::wait] ended with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Woken {
+    TimedOut,
+    /// Something changed in a watched directory
+    Changed,
+    /// A [Trigger] was pulled
+    Requested,
+}
+
+/// Read everything there is to read from the non-blocking `file`
+fn drain(mut file: &File) -> std::io::Result<()> {
+    let mut buf = [0u8; 4096];
+    loop {
+        match file.read(&mut buf) {
+            Ok(n) if n > 0 => {}
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) if e.kind() != ErrorKind::WouldBlock => return Err(e),
+            _ => return Ok(()),
+        }
+    }
+}
+
+/// Makes a [Watcher] load the config again right away
+#[derive(Debug, Clone)]
+pub struct Trigger(Arc<File>);
+
+impl Trigger {
+    pub fn pull(&self) -> Result<()> {
+        match (&*self.0).write(&[0]) {
+            // a request is pending already
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(()),
+            res => res.map(|_| ()).context("could not request a reload"),
+        }
+    }
+}
+
+/// The ends of a non-blocking pipe
+fn pipe() -> Result<(File, File)> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC | libc::O_NONBLOCK) } < 0 {
+        bail!(
+            "could not create a pipe: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+    Ok(fds.map(|fd| unsafe { File::from_raw_fd(fd) }).into())
+}
+
+/// Watching the files of a config, from before they were read
+pub struct Watcher {
+    inotify: Inotify,
+    /// Whether directories are watched, rather than only [Self::trigger]
+    watching: bool,
+    requests: File,
+    trigger: Trigger,
+    config: Watched,
+    stamps: Snapshot,
+    debounce: Duration,
+}
+
+impl Watch {
+    /// Start watching the files of `config`, before they are read
+    pub fn start(&self, config: &Rosenpass) -> Result<Watcher> {
+        let mut watcher = Watcher::on_request(config)?;
+        watcher.inotify.add(dirs(&watcher.config));
+        watcher.watching = true;
+        watcher.debounce = Duration::from_secs_f64(self.debounce.unwrap_or(DEFAULT_DEBOUNCE));
+        Ok(watcher)
+    }
+}
+
+impl Watcher {
+    /// Take up changes to the files of `config` only when [Self::trigger]
+    /// is pulled
+    pub fn on_request(config: &Rosenpass) -> Result<Self> {
+        let (requests, trigger) = pipe()?;
+        let config = Watched::of(config);
+        Ok(Self {
+            inotify: Inotify::new()?,
+            watching: false,
+            requests,
+            trigger: Trigger(Arc::new(trigger)),
+            stamps: snapshot(&config, true),
+            config,
+            debounce: Duration::from_secs_f64(DEFAULT_DEBOUNCE),
+        })
+    }
+
+    /// Makes the watcher load the config again, changed or not
+    pub fn trigger(&self) -> Trigger {
+        self.trigger.clone()
+    }
+
+    /// Check for changes in the background; `reload` loads the config again,
+    /// `load_key` the public key of a peer and `check_own_keys` makes sure
+    /// our own keys load
+    pub fn spawn<R, K, O>(
+        self,
+        mut reload: R,
+        load_key: K,
+        check_own_keys: O,
+        waker: Arc<mio::Waker>,
+    ) -> Receiver<Result<Update>>
+    where
+        R: FnMut() -> Result<Rosenpass> + Send + 'static,
+        K: Fn(&RosenpassPeer) -> Result<SPk> + Send + 'static,
+        O: Fn(&Rosenpass) -> Result<()> + Send + 'static,
+    {
+        let Self {
+            mut inotify,
+            watching,
+            requests,
+            trigger: _,
+            mut config,
+            mut stamps,
+            debounce,
+        } = self;
+        let (tx, rx) = mpsc::channel();
+        let send = move |update| {
+            let sent = tx.send(update).is_ok();
+            let _ = waker.wake();
+            sent
+        };
+        thread::spawn(move || loop {
+            let settled = inotify.wait(&requests, None).and_then(|mut woken| {
+                while woken == Woken::Changed {
+                    woken = inotify.wait(&requests, Some(debounce))?;
+                }
+                Ok(woken)
+            });
+            let requested = match settled {
+                Ok(woken) => woken == Woken::Requested,
+                Err(e) if watching => {
+                    send(Err(e.context("no longer watching the key and peer files")));
+                    return;
+                }
+                Err(e) => {
+                    send(Err(e.context("no longer taking up reload requests")));
+                    return;
+                }
+            };
+            if requested {
+                info!("loading the config again, as requested");
+            } else if unchanged(&snapshot(&config, false), &stamps) {
+                continue;
+            }
+            let res = reload().and_then(|loaded| {
+                let new = Watched::of(&loaded);
+                let now = snapshot(&new, true);
+                let update = changes(&config, &new, &stamps, &now, &load_key)?;
+                let own_key = own_key_ids(&new)
+                    .iter()
+                    .any(|id| stamps.get(id) != now.get(id));
+                if own_key {
+                    check_own_keys(&loaded).context("not taking up our new keys")?;
+                }
+                inotify.add(dirs(&new));
+                (config, stamps) = (new, now);
+                Ok((update, own_key))
+            });
+            let update = match res {
+                Ok((update, own_key)) => {
+                    if own_key && !upgrade::request() {
+                        warn!("our keys changed, restart rosenpass to take them up");
+                    } else if own_key {
+                        info!("our keys changed, upgrading to take them up");
+                    }
+                    if update.removed.is_empty() && update.added.is_empty() {
+                        continue;
+                    }
+                    Ok(update)
+                }
+                Err(e) => Err(e),
+            };
+            if !send(update) {
+                return;
+            }
+        });
+        rx
+    }
+}
+
+/// The peers to remove and add to get from the peers of `old` to those of
+/// `new`, with `before` and `after` the files of either
+fn changes<K>(
+    old: &Watched,
+    new: &Watched,
+    before: &Snapshot,
+    after: &Snapshot,
+    load_key: K,
+) -> Result<Update>
+where
+    K: Fn(&RosenpassPeer) -> Result<SPk>,
+{
+    let (mut removed, mut added) = peer_store::diff(&old.peers, &new.peers);
+    // the same settings, but another public key
+    for peer in new.peers.iter() {
+        let path = &peer.public_key;
+        let id = key_id(peer);
+        let rekeyed = before.get(&id) != after.get(&id)
+            && old.peers.iter().any(|p| p == peer)
+            && !removed.contains(path);
+        if rekeyed {
+            removed.push(path.clone());
+            added.push(peer);
+        }
+    }
+    let added = added
+        .into_iter()
+        .map(|peer| Ok((peer.clone(), load_key(peer)?)))
+        .collect::<Result<_>>()?;
+    Ok(Update {
+        source: "the watched files",
+        removed,
+        added,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn peer(public_key: &str, endpoint: Option<&str>) -> RosenpassPeer {
+        RosenpassPeer {
+            public_key: public_key.into(),
+            endpoint: endpoint.map(str::to_owned),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn rekeyed_peers_are_added_again() {
+        let (mut old, mut new) = (Watched::default(), Watched::default());
+        old.peers = vec![peer("a", None), peer("b", None), peer("c", None)];
+        new.peers = vec![peer("a", None), peer("b", Some("192.0.2.1:9999"))];
+        let before: Snapshot = [("a".into(), Some([1; 32])), ("b".into(), Some([2; 32]))].into();
+        let mut after = before.clone();
+        after.insert("a".into(), Some([3; 32]));
+
+        let update = changes(&old, &new, &before, &after, |_| Ok(SPk::zero())).unwrap();
+        let mut removed = update.removed.clone();
+        removed.sort();
+        assert_eq!(removed, [PathBuf::from("a"), "b".into(), "c".into()]);
+        let added: Vec<_> = update
+            .added
+            .iter()
+            .map(|(p, _)| p.public_key.clone())
+            .collect();
+        assert_eq!(added, [PathBuf::from("b"), "a".into()]);
+
+        let unchanged = changes(&old, &old, &before, &before, |_| Ok(SPk::zero())).unwrap();
+        assert!(unchanged.removed.is_empty() && unchanged.added.is_empty());
+    }
+
+    #[test]
+    fn drop_ins_are_read_in_order() {
+        let dir = std::env::temp_dir().join(format!("rosenpass-drop-ins-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("b.toml"), "public_key = \"b.pqpk\"\n").unwrap();
+        fs::write(
+            dir.join("a.toml"),
+            "public_key = \"a.pqpk\"\nendpoint = \"192.0.2.1:9999\"\n",
+        )
+        .unwrap();
+        fs::write(dir.join(".a.toml.swp"), "garbage").unwrap();
+        fs::write(dir.join("a.pqpk"), "key").unwrap();
+        let peers = load_dir(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(peers.len(), 2);
+        assert_eq!(peers[0].public_key, PathBuf::from("a.pqpk"));
+        assert_eq!(peers[0].endpoint.as_deref(), Some("192.0.2.1:9999"));
+        assert!(peers.iter().all(|p| p.from_dir));
+        assert!(load_dir(&dir).unwrap().is_empty());
+    }
+
+    #[test]
+    fn reloads_are_taken_up_on_request() {
+        rosenpass_sodium::init().unwrap();
+        let mut config = Rosenpass::new("pk", "sk");
+        config.peers = vec![peer("a", None)];
+        let watcher = Watcher::on_request(&config).unwrap();
+        let trigger = watcher.trigger();
+        let poll = mio::Poll::new().unwrap();
+        let waker = Arc::new(mio::Waker::new(poll.registry(), mio::Token(0)).unwrap());
+        let updates = watcher.spawn(
+            || {
+                let mut config = Rosenpass::new("pk", "sk");
+                config.peers = vec![peer("a", Some("192.0.2.1:9999"))];
+                Ok(config)
+            },
+            |_| Ok(SPk::zero()),
+            |_| Ok(()),
+            waker,
+        );
+
+        // no file changed, the peer is taken up all the same
+        trigger.pull().unwrap();
+        let update = updates
+            .recv_timeout(Duration::from_secs(10))
+            .unwrap()
+            .unwrap();
+        assert_eq!(update.removed, vec![PathBuf::from("a")]);
+        assert_eq!(update.added.len(), 1);
+        assert_eq!(
+            update.added[0].0.endpoint.as_deref(),
+            Some("192.0.2.1:9999")
+        );
+    }
+}
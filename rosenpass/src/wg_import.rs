@@ -0,0 +1,173 @@
+//! Migrating existing WireGuard setups
+//!
+//! Reads a WireGuard configuration file (the `wg-quick` flavour as well as
+//! the plain `wg setconf` one) and derives a rosenpass configuration which
+//! hands its keys to the same device and peers.
+
+use anyhow::{bail, Result};
+use std::path::{Path, PathBuf};
+
+use crate::{
+    config::{Rosenpass, RosenpassPeer, WireGuard},
+    wizard::SidePaths,
+};
+
+/// The parts of a WireGuard configuration rosenpass cares about
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct WgConfig {
+    pub peers: Vec<WgPeer>,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct WgPeer {
+    pub public_key: String,
+    pub endpoint: Option<String>,
+}
+
+impl WgConfig {
+    pub fn parse(s: &str) -> Result<Self> {
+        enum Section {
+            None,
+            Interface,
+            Peer,
+        }
+
+        let mut config = Self::default();
+        let mut section = Section::None;
+        for (no, line) in s.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            if line.starts_with('[') {
+                section = match line.to_ascii_lowercase().as_str() {
+                    "[interface]" => Section::Interface,
+                    "[peer]" => {
+                        config.peers.push(WgPeer::default());
+                        Section::Peer
+                    }
+                    other => bail!("line {}: unknown section {other}", no + 1),
+                };
+                continue;
+            }
+
+            let Some((key, value)) = line.split_once('=') else {
+                bail!("line {}: expected key = value", no + 1);
+            };
+            let (key, value) = (key.trim().to_ascii_lowercase(), value.trim());
+            match (&section, key.as_str()) {
+                (Section::Peer, "publickey") => {
+                    config.peers.last_mut().unwrap().public_key = value.to_string()
+                }
+                (Section::Peer, "endpoint") => {
+                    config.peers.last_mut().unwrap().endpoint = Some(value.to_string())
+                }
+                (Section::None, _) => bail!("line {}: setting outside of a section", no + 1),
+                _ => {} // everything else is none of our business
+            }
+        }
+
+        for (i, peer) in config.peers.iter().enumerate() {
+            if peer.public_key.is_empty() {
+                bail!("peer {i} has no PublicKey");
+            }
+        }
+        Ok(config)
+    }
+
+    /// Build the matching rosenpass configuration, stored at `config_file`
+    ///
+    /// Rosenpass listens on `port`, and expects its peers to do the same at
+    /// the hosts of their WireGuard endpoints. The rosenpass public keys of
+    /// the peers are expected next to the config file, as
+    /// `<config name>-peer-<no>-public-key`.
+    pub fn to_rosenpass(&self, config_file: &Path, device: &str, port: u16) -> Result<Rosenpass> {
+        let own = SidePaths::for_config(config_file);
+        let mut config = Rosenpass::new(&own.public_key, &own.secret_key);
+        config.add_if_any(port);
+
+        for (i, peer) in self.peers.iter().enumerate() {
+            let endpoint = match peer.endpoint.as_ref() {
+                Some(ep) => {
+                    let Some((host, _)) = ep.rsplit_once(':') else {
+                        bail!("peer {i} endpoint {ep} has no port");
+                    };
+                    Some(format!("{host}:{port}"))
+                }
+                None => None,
+            };
+            config.peers.push(RosenpassPeer {
+                public_key: peer_public_key_path(config_file, i),
+                endpoint,
+                wg: Some(WireGuard {
+                    device: device.to_string(),
+                    peer: peer.public_key.clone(),
+                    extra_params: vec![],
+                    uapi_socket: None,
+                    device_netns: None,
+                }),
+                ..Default::default()
+            });
+        }
+        Ok(config)
+    }
+}
+
+/// Where [WgConfig::to_rosenpass] expects the public key of peer `no`
+pub fn peer_public_key_path(config_file: &Path, no: usize) -> PathBuf {
+    let stem = config_file
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "rp".to_string());
+    config_file.with_file_name(format!("{stem}-peer-{no}-public-key"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const WG_QUICK: &str = "
+        [Interface]
+        Address = 10.0.0.1/24
+        ListenPort = 51820
+        PrivateKey = aGVsbG8gd29ybGQgaGVsbG8gd29ybGQgaGVsbG8gd28=
+
+        # office
+        [Peer]
+        PublicKey = b2ZmaWNlIG9mZmljZSBvZmZpY2Ugb2ZmaWNlIG9mZmk=
+        Endpoint = office.test:51820
+        AllowedIPs = 10.0.0.2/32
+
+        [Peer]
+        PublicKey = bGFwdG9wIGxhcHRvcCBsYXB0b3AgbGFwdG9wIGxhcHQ=
+        AllowedIPs = 10.0.0.3/32
+    ";
+
+    #[test]
+    fn parse_and_convert() {
+        let wg = WgConfig::parse(WG_QUICK).unwrap();
+        assert_eq!(wg.peers.len(), 2);
+        assert_eq!(wg.peers[0].endpoint.as_deref(), Some("office.test:51820"));
+        assert_eq!(wg.peers[1].endpoint, None);
+
+        let rp = wg
+            .to_rosenpass(Path::new("/etc/rp/wg0.toml"), "wg0", 9999)
+            .unwrap();
+        assert_eq!(rp.secret_key, PathBuf::from("/etc/rp/wg0-secret-key"));
+        assert_eq!(rp.peers[0].endpoint.as_deref(), Some("office.test:9999"));
+        assert_eq!(
+            rp.peers[1].public_key,
+            PathBuf::from("/etc/rp/wg0-peer-1-public-key")
+        );
+        let wg_out = rp.peers[0].wg.as_ref().unwrap();
+        assert_eq!(wg_out.device, "wg0");
+        assert_eq!(wg_out.peer, wg.peers[0].public_key);
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(WgConfig::parse("ListenPort = 1").is_err());
+        assert!(WgConfig::parse("[Peer]\nEndpoint = x:1").is_err());
+        assert!(WgConfig::parse("[Interface]\nListenPort").is_err());
+    }
+}
@@ -0,0 +1,298 @@
+//! Guided creation of working configurations
+//!
+//! Used by `gen-config` when any of the [WizardArgs] are given. Settings are
+//! taken from the command line, asked for interactively or defaulted, and
+//! turned into one configuration, or two for both ends of a point-to-point
+//! link. Key and output files are placed next to their configuration file.
+
+use anyhow::{bail, Result};
+use std::{
+    io::{self, BufRead, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::config::{Rosenpass, RosenpassPeer, WireGuard};
+
+const DEFAULT_LISTEN: &str = "[::]:9999";
+
+#[derive(clap::Args, Debug, Default)]
+pub struct WizardArgs {
+    /// Ask for every setting not given on the command line
+    #[clap(short, long)]
+    pub interactive: bool,
+
+    /// Also generate keys and configuration for the other end of a point-to-point link
+    #[clap(long, value_name = "PATH")]
+    pub peer_config: Option<PathBuf>,
+
+    /// Address to listen on [default: [::]:9999]
+    #[clap(long)]
+    pub listen: Option<String>,
+
+    /// Address the other end listens on, with --peer-config [default: [::]:9999]
+    #[clap(long)]
+    pub peer_listen: Option<String>,
+
+    /// Host and port this end is reachable at, used in the peer's configuration
+    #[clap(long)]
+    pub endpoint: Option<String>,
+
+    /// Host and port the other end is reachable at
+    #[clap(long)]
+    pub peer_endpoint: Option<String>,
+
+    /// Public-key file of the other end, when not using --peer-config
+    #[clap(long, value_name = "PATH")]
+    pub peer_public_key: Option<PathBuf>,
+
+    /// WireGuard device keys are handed to; without one keys are written to a file
+    #[clap(long)]
+    pub wireguard_device: Option<String>,
+
+    /// WireGuard public key of this end
+    #[clap(long)]
+    pub wireguard_pubkey: Option<String>,
+
+    /// WireGuard public key of the other end
+    #[clap(long)]
+    pub peer_wireguard_pubkey: Option<String>,
+}
+
+/// File locations of one end, derived from its config file path
+pub struct SidePaths {
+    pub public_key: PathBuf,
+    pub secret_key: PathBuf,
+    pub key_out: PathBuf,
+}
+
+impl SidePaths {
+    /// `dir/name.toml` gets `dir/name-public-key`, `dir/name-secret-key` and so on
+    pub fn for_config(config: &Path) -> Self {
+        let stem = config
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "rp".to_string());
+        let file = |suffix: &str| config.with_file_name(format!("{stem}-{suffix}"));
+        Self {
+            public_key: file("public-key"),
+            secret_key: file("secret-key"),
+            key_out: file("rp-out-key"),
+        }
+    }
+}
+
+impl WizardArgs {
+    /// Whether the wizard was asked for at all
+    pub fn requested(&self) -> bool {
+        self.interactive
+            || self.peer_config.is_some()
+            || self.listen.is_some()
+            || self.peer_listen.is_some()
+            || self.endpoint.is_some()
+            || self.peer_endpoint.is_some()
+            || self.peer_public_key.is_some()
+            || self.wireguard_device.is_some()
+            || self.wireguard_pubkey.is_some()
+            || self.peer_wireguard_pubkey.is_some()
+    }
+
+    /// Ask for the unset settings if interactive; empty answers keep them unset
+    fn ask(&mut self) -> Result<()> {
+        if !self.interactive {
+            return Ok(());
+        }
+        let p2p = self.peer_config.is_some();
+        ask(
+            &mut self.listen,
+            "Address to listen on",
+            Some(DEFAULT_LISTEN),
+        )?;
+        if p2p {
+            ask(
+                &mut self.peer_listen,
+                "Address the other end listens on",
+                Some(DEFAULT_LISTEN),
+            )?;
+            ask(
+                &mut self.endpoint,
+                "Host and port this end is reachable at",
+                None,
+            )?;
+        } else {
+            let mut pk = self
+                .peer_public_key
+                .as_ref()
+                .map(|p| p.display().to_string());
+            ask(&mut pk, "Public-key file of the other end", None)?;
+            self.peer_public_key = pk.map(PathBuf::from);
+        }
+        ask(
+            &mut self.peer_endpoint,
+            "Host and port the other end is reachable at",
+            None,
+        )?;
+        ask(
+            &mut self.wireguard_device,
+            "WireGuard device (empty to write keys to a file)",
+            None,
+        )?;
+        if self.wireguard_device.is_some() {
+            if p2p {
+                ask(
+                    &mut self.wireguard_pubkey,
+                    "WireGuard public key of this end",
+                    None,
+                )?;
+            }
+            ask(
+                &mut self.peer_wireguard_pubkey,
+                "WireGuard public key of the other end",
+                None,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Produce the configurations to write, the own one first
+    pub fn build(mut self, config_file: &Path) -> Result<Vec<(PathBuf, Rosenpass)>> {
+        self.ask()?;
+
+        let own = SidePaths::for_config(config_file);
+        let listen = self.listen.as_deref().unwrap_or(DEFAULT_LISTEN);
+        let wg_for = |peer_wg_pubkey: &Option<String>| -> Result<Option<WireGuard>> {
+            Ok(match (&self.wireguard_device, peer_wg_pubkey) {
+                (Some(device), Some(peer)) => Some(WireGuard {
+                    device: device.clone(),
+                    peer: peer.clone(),
+                    extra_params: vec![],
+                    uapi_socket: None,
+                    device_netns: None,
+                }),
+                (Some(_), None) => {
+                    bail!("a WireGuard device needs the WireGuard public key of the other end")
+                }
+                (None, _) => None,
+            })
+        };
+
+        let Some(peer_config) = self.peer_config.clone() else {
+            let peer_pk = self
+                .peer_public_key
+                .clone()
+                .unwrap_or_else(|| "rp-peer-public-key".into());
+            let config = side(
+                &own,
+                listen,
+                peer_pk,
+                self.peer_endpoint.clone(),
+                wg_for(&self.peer_wireguard_pubkey)?,
+            )?;
+            return Ok(vec![(config_file.to_owned(), config)]);
+        };
+
+        let peer = SidePaths::for_config(&peer_config);
+        let peer_listen = self.peer_listen.as_deref().unwrap_or(DEFAULT_LISTEN);
+        let own_config = side(
+            &own,
+            listen,
+            peer.public_key.clone(),
+            self.peer_endpoint.clone(),
+            wg_for(&self.peer_wireguard_pubkey)?,
+        )?;
+        let peer_side_config = side(
+            &peer,
+            peer_listen,
+            own.public_key.clone(),
+            self.endpoint.clone(),
+            wg_for(&self.wireguard_pubkey)?,
+        )?;
+        Ok(vec![
+            (config_file.to_owned(), own_config),
+            (peer_config, peer_side_config),
+        ])
+    }
+}
+
+/// Configuration of one end talking to a single peer
+fn side(
+    paths: &SidePaths,
+    listen: &str,
+    peer_public_key: PathBuf,
+    peer_endpoint: Option<String>,
+    wg: Option<WireGuard>,
+) -> Result<Rosenpass> {
+    let mut config = Rosenpass::new(&paths.public_key, &paths.secret_key);
+    config.listen = vec![listen.parse()?];
+    config.peers.push(RosenpassPeer {
+        public_key: peer_public_key,
+        endpoint: peer_endpoint,
+        key_out: wg.is_none().then(|| paths.key_out.clone()),
+        wg,
+        ..Default::default()
+    });
+    Ok(config)
+}
+
+/// Ask `question` on stderr unless `value` is already set
+fn ask(value: &mut Option<String>, question: &str, default: Option<&str>) -> Result<()> {
+    if value.is_some() {
+        return Ok(());
+    }
+    match default {
+        Some(d) => eprint!("{question} [{d}]: "),
+        None => eprint!("{question}: "),
+    }
+    io::stderr().flush()?;
+
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    let answer = answer.trim();
+    if !answer.is_empty() {
+        *value = Some(answer.to_string());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn point_to_point() {
+        let args = WizardArgs {
+            peer_config: Some("/etc/rp/b.toml".into()),
+            listen: Some("0.0.0.0:1001".into()),
+            endpoint: Some("a.test:1001".into()),
+            peer_endpoint: Some("b.test:1002".into()),
+            wireguard_device: Some("wg0".into()),
+            wireguard_pubkey: Some("WG_A".into()),
+            peer_wireguard_pubkey: Some("WG_B".into()),
+            ..Default::default()
+        };
+        let configs = args.build(Path::new("/etc/rp/a.toml")).unwrap();
+        assert_eq!(configs.len(), 2);
+
+        let (path_a, a) = &configs[0];
+        let (path_b, b) = &configs[1];
+        assert_eq!(path_a, Path::new("/etc/rp/a.toml"));
+        assert_eq!(path_b, Path::new("/etc/rp/b.toml"));
+        assert_eq!(a.secret_key, PathBuf::from("/etc/rp/a-secret-key"));
+        assert_eq!(a.peers[0].public_key, b.public_key);
+        assert_eq!(b.peers[0].public_key, a.public_key);
+        assert_eq!(a.peers[0].endpoint.as_deref(), Some("b.test:1002"));
+        assert_eq!(b.peers[0].endpoint.as_deref(), Some("a.test:1001"));
+        assert_eq!(a.peers[0].wg.as_ref().unwrap().peer, "WG_B");
+        assert_eq!(b.peers[0].wg.as_ref().unwrap().peer, "WG_A");
+        assert_eq!(b.listen, vec!["[::]:9999".parse().unwrap()]);
+        assert!(a.peers[0].key_out.is_none());
+    }
+
+    #[test]
+    fn wireguard_needs_peer_pubkey() {
+        let args = WizardArgs {
+            wireguard_device: Some("wg0".into()),
+            ..Default::default()
+        };
+        assert!(args.build(Path::new("a.toml")).is_err());
+    }
+}
@@ -0,0 +1,369 @@
+//! Processing InitHello messages on multiple cores
+//!
+//! Answering an InitHello is the expensive part of being a responder: a
+//! Classic McEliece decapsulation plus a Kyber and a McEliece encapsulation.
+//! It is also stateless, because the responder keeps its handshake state in
+//! the biscuit it sends to the initiator rather than in memory. This makes it
+//! possible to hand InitHello messages to worker threads, each owning a
+//! replica of the static keys and peers, while all sessions stay with the
+//! [CryptoServer] of the event loop.
+//!
+//! The one piece of shared state is the biscuit key and biscuit number, which
+//! the event loop assigns to every job so that replay protection keeps working
+//! when the InitConf arrives. Jobs and results are passed over channels, which
+//! hand over messages without locking; jobs are distributed round robin.
+//!
+//! Peers added, removed or changing keys while running are replicated by
+//! sending the [PeerChange] to every worker over the same channel as the
+//! jobs, so any job submitted afterwards sees the change.
+
+use anyhow::{Context, Result};
+use log::warn;
+use std::{
+    net::SocketAddr,
+    sync::{
+        mpsc::{self, Receiver, Sender, TryRecvError},
+        Arc,
+    },
+    thread,
+};
+
+use crate::{
+    protocol::{BiscuitId, BiscuitKeyPtr, CryptoServer, MsgBuf, PeerPtr, SymKey, Timing, BCE},
+    sched::ThreadScheduling,
+    shared_pk::SharedPk,
+};
+
+/// A change made to the peers of the [CryptoServer] of the event loop, for
+/// the workers to make to their replicas
+#[derive(Debug, Clone)]
+pub enum PeerChange {
+    /// See [CryptoServer::add_peer]
+    Added { psk: SymKey, pk: SharedPk },
+    /// See [CryptoServer::add_rollover_key]
+    Rollover {
+        peer: PeerPtr,
+        old: SharedPk,
+        window: Timing,
+    },
+    /// See [PeerPtr::end_rollover]
+    RolloverEnded(PeerPtr),
+    /// See [CryptoServer::forget_peer]
+    Forgotten(PeerPtr),
+}
+
+impl PeerChange {
+    /// The change adding `peer` of `srv` as it is now
+    pub fn added(srv: &CryptoServer, peer: PeerPtr) -> Self {
+        let p = peer.get(srv);
+        Self::Added {
+            psk: p.psk.clone(),
+            pk: p.rollover_keys().0.clone(),
+        }
+    }
+
+    fn apply(self, srv: &mut CryptoServer) -> Result<()> {
+        match self {
+            Self::Added { psk, pk } => srv.add_peer(Some(psk), pk).map(|_| ()),
+            Self::Rollover { peer, old, window } => srv.add_rollover_key(peer, old, window),
+            Self::RolloverEnded(peer) => peer.end_rollover(srv),
+            Self::Forgotten(peer) => srv.forget_peer(peer),
+        }
+    }
+}
+
+/// What a worker is handed
+#[derive(Debug)]
+enum Task {
+    Answer(Job),
+    Replicate(PeerChange),
+}
+
+/// An InitHello to be answered
+#[derive(Debug)]
+pub struct Job {
+    pub msg: Vec<u8>,
+    /// Index of the socket the message arrived on
+    pub socket: usize,
+    pub addr: SocketAddr,
+    /// Whether the message arrived in fragments
+    pub fragmented: bool,
+    biscuit_key_no: usize,
+    biscuit_key: SymKey,
+    biscuit_no: BiscuitId,
+}
+
+/// The outcome of a [Job]
+#[derive(Debug)]
+pub struct Done {
+    pub msg: Vec<u8>,
+    pub socket: usize,
+    pub addr: SocketAddr,
+    pub fragmented: bool,
+    /// The peer the InitHello came from and the RespHello to send back
+    pub result: Result<(PeerPtr, Vec<u8>)>,
+}
+
+#[derive(Debug)]
+pub struct HandshakeWorkers {
+    jobs: Vec<Sender<Task>>,
+    next: usize,
+    done: Receiver<Done>,
+    /// The ids of the worker threads, in the order they were spawned
+    pub threads: Vec<thread::Thread>,
+}
+
+impl HandshakeWorkers {
+    /// Start `count` workers replicating the keys and peers of `srv`; `waker`
+    /// is woken whenever a job is done
+    ///
+    /// Each worker applies `sched` to itself before taking any jobs.
+    pub fn spawn(
+        count: usize,
+        srv: &CryptoServer,
+        waker: Arc<mio::Waker>,
+        sched: &ThreadScheduling,
+    ) -> Result<Self> {
+        let (done_tx, done) = mpsc::channel();
+        let mut jobs = Vec::with_capacity(count);
+        let mut threads = Vec::with_capacity(count);
+        for no in 0..count {
+            let mut replica = CryptoServer::new(srv.sskm.clone(), srv.spkm.clone());
+            replica.key_cache = srv.key_cache;
+            for (no, peer) in srv.peers.iter().enumerate() {
+                PeerChange::added(srv, PeerPtr(no)).apply(&mut replica)?;
+                if let (Some(old), Some(r)) = (peer.rollover_keys().1, peer.rollover.as_ref()) {
+                    // the replica has a clock of its own
+                    let window = r.until - srv.timebase.now();
+                    replica.add_rollover_key(PeerPtr(no), old.clone(), window)?;
+                }
+            }
+            let (job_tx, job_rx) = mpsc::channel();
+            let (done_tx, waker, sched) = (done_tx.clone(), waker.clone(), sched.clone());
+            let (started_tx, started) = mpsc::channel();
+            let handle = thread::Builder::new()
+                .name(format!("handshake-{no}"))
+                .spawn(move || {
+                    let res = sched.apply();
+                    let ok = res.is_ok();
+                    let _ = started_tx.send(res);
+                    if ok {
+                        work(replica, job_rx, done_tx, waker)
+                    }
+                })
+                .context("could not start handshake worker")?;
+            started.recv().context("handshake worker exited")??;
+            threads.push(handle.thread().clone());
+            jobs.push(job_tx);
+        }
+        Ok(Self {
+            jobs,
+            next: 0,
+            done,
+            threads,
+        })
+    }
+
+    /// Hand the InitHello `msg` to the next worker
+    ///
+    /// Takes the biscuit key and number from `srv`, just like answering the
+    /// message in place would.
+    pub fn submit(
+        &mut self,
+        srv: &mut CryptoServer,
+        msg: &[u8],
+        socket: usize,
+        addr: SocketAddr,
+        fragmented: bool,
+    ) -> Result<()> {
+        let bk = srv.active_biscuit_key();
+        let biscuit_no = srv.biscuit_ctr;
+        rosenpass_sodium::helpers::increment(&mut *srv.biscuit_ctr);
+        let job = Job {
+            msg: msg.to_vec(),
+            socket,
+            addr,
+            fragmented,
+            biscuit_key_no: bk.0,
+            biscuit_key: bk.get(srv).key.clone(),
+            biscuit_no,
+        };
+        self.jobs[self.next]
+            .send(Task::Answer(job))
+            .map_err(|_| anyhow::anyhow!("handshake worker exited"))?;
+        self.next = (self.next + 1) % self.jobs.len();
+        Ok(())
+    }
+
+    /// Make `change`, just made to the peers of the event loop, to those of
+    /// every worker too, before any of them takes another job
+    pub fn replicate(&self, change: PeerChange) -> Result<()> {
+        for jobs in self.jobs.iter() {
+            jobs.send(Task::Replicate(change.clone()))
+                .map_err(|_| anyhow::anyhow!("handshake worker exited"))?;
+        }
+        Ok(())
+    }
+
+    /// The number of worker threads
+    pub fn count(&self) -> usize {
+        self.jobs.len()
+    }
+
+    /// A finished job, if there is one
+    pub fn try_done(&self) -> Result<Option<Done>> {
+        match self.done.try_recv() {
+            Ok(done) => Ok(Some(done)),
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => anyhow::bail!("all handshake workers exited"),
+        }
+    }
+}
+
+fn work(
+    mut replica: CryptoServer,
+    tasks: Receiver<Task>,
+    done: Sender<Done>,
+    waker: Arc<mio::Waker>,
+) {
+    let mut tx = MsgBuf::zero();
+    for task in tasks {
+        let job = match task {
+            Task::Answer(job) => job,
+            Task::Replicate(change) => {
+                if let Err(e) = change.apply(&mut replica) {
+                    warn!("Handshake worker could not replicate a peer change: {e:#}");
+                }
+                continue;
+            }
+        };
+        // make the assigned biscuit key the only usable one
+        let now = replica.timebase.now();
+        let bk = BiscuitKeyPtr(job.biscuit_key_no);
+        BiscuitKeyPtr(1 - bk.0).get_mut(&mut replica).created_at = BCE;
+        let key = bk.get_mut(&mut replica);
+        key.key = job.biscuit_key;
+        key.created_at = now;
+        replica.biscuit_ctr = job.biscuit_no;
+
+        let result = replica
+            .handle_msg(&job.msg, &mut *tx)
+            .map(|res| (res.peer, tx[..res.resp.unwrap_or(0)].to_vec()));
+        let finished = Done {
+            msg: job.msg,
+            socket: job.socket,
+            addr: job.addr,
+            fragmented: job.fragmented,
+            result,
+        };
+        if done.send(finished).is_err() {
+            return;
+        }
+        let _ = waker.wake();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pqkem::{StaticKEM, KEM};
+    use crate::protocol::{SPk, SSk};
+
+    fn server() -> (CryptoServer, SPk) {
+        let (mut sk, mut pk) = (SSk::zero(), SPk::zero());
+        StaticKEM::keygen(sk.secret_mut(), pk.secret_mut()).unwrap();
+        (CryptoServer::new(sk, pk.clone()), pk)
+    }
+
+    #[test]
+    fn handshake_answered_by_worker() {
+        rosenpass_sodium::init().unwrap();
+        let (mut a, a_pk) = server();
+        let (mut b, b_pk) = server();
+        let b_at_a = a.add_peer(None, b_pk).unwrap();
+        let a_at_b = b.add_peer(None, a_pk).unwrap();
+
+        let poll = mio::Poll::new().unwrap();
+        let waker = Arc::new(mio::Waker::new(poll.registry(), mio::Token(0)).unwrap());
+        let mut workers =
+            HandshakeWorkers::spawn(2, &b, waker, &ThreadScheduling::default()).unwrap();
+        let addr: SocketAddr = "192.0.2.1:9999".parse().unwrap();
+
+        let (mut tx, mut rx) = (MsgBuf::zero(), MsgBuf::zero());
+        let len = a.initiate_handshake(b_at_a, &mut *tx).unwrap();
+        workers.submit(&mut b, &tx[..len], 0, addr, false).unwrap();
+        let done = loop {
+            if let Some(done) = workers.try_done().unwrap() {
+                break done;
+            }
+            thread::sleep(std::time::Duration::from_millis(10));
+        };
+        assert_eq!((done.socket, done.addr), (0, addr));
+        let (peer, resp) = done.result.unwrap();
+        assert_eq!(peer, a_at_b);
+
+        // the InitConf is handled by the server the job was submitted from
+        let conf = a.handle_msg(&resp, &mut *rx).unwrap();
+        let len = conf.resp.unwrap();
+        let res = b.handle_msg(&rx[..len], &mut *tx).unwrap();
+        assert_eq!(res.exchanged_with, Some(a_at_b));
+        assert_eq!(
+            a.osk(b_at_a).unwrap().secret(),
+            b.osk(a_at_b).unwrap().secret()
+        );
+    }
+
+    fn answer(workers: &mut HandshakeWorkers, srv: &mut CryptoServer, msg: &[u8]) -> Done {
+        let addr: SocketAddr = "192.0.2.1:9999".parse().unwrap();
+        workers.submit(srv, msg, 0, addr, false).unwrap();
+        loop {
+            if let Some(done) = workers.try_done().unwrap() {
+                break done;
+            }
+            thread::sleep(std::time::Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn peer_changes_reach_the_workers() {
+        rosenpass_sodium::init().unwrap();
+        let (mut a, a_pk) = server();
+        let (mut b, b_pk) = server();
+        let b_at_a = a.add_peer(None, b_pk).unwrap();
+
+        let poll = mio::Poll::new().unwrap();
+        let waker = Arc::new(mio::Waker::new(poll.registry(), mio::Token(0)).unwrap());
+        let mut workers =
+            HandshakeWorkers::spawn(2, &b, waker, &ThreadScheduling::default()).unwrap();
+
+        // a is added only once the workers run
+        let a_at_b = b.add_peer(None, a_pk).unwrap();
+        workers.replicate(PeerChange::added(&b, a_at_b)).unwrap();
+
+        // answered by both workers, as jobs go round robin
+        let (mut tx, mut rx) = (MsgBuf::zero(), MsgBuf::zero());
+        for _ in 0..2 {
+            let len = a.initiate_handshake(b_at_a, &mut *tx).unwrap();
+            let done = answer(&mut workers, &mut b, &tx[..len]);
+            let (peer, resp) = done.result.unwrap();
+            assert_eq!(peer, a_at_b);
+
+            let conf = a.handle_msg(&resp, &mut *rx).unwrap();
+            let len = conf.resp.unwrap();
+            let res = b.handle_msg(&rx[..len], &mut *tx).unwrap();
+            assert_eq!(res.exchanged_with, Some(a_at_b));
+            assert_eq!(
+                a.osk(b_at_a).unwrap().secret(),
+                b.osk(a_at_b).unwrap().secret()
+            );
+        }
+
+        // once forgotten, its InitHello is refused by either worker
+        b.forget_peer(a_at_b).unwrap();
+        workers.replicate(PeerChange::Forgotten(a_at_b)).unwrap();
+        for _ in 0..2 {
+            let len = a.initiate_handshake(b_at_a, &mut *tx).unwrap();
+            assert!(answer(&mut workers, &mut b, &tx[..len]).result.is_err());
+        }
+    }
+}
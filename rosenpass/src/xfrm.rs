@@ -0,0 +1,354 @@
+//! Keying IPsec security associations
+//!
+//! Instead of, or along with, a WireGuard peer, a peer can protect an IPsec
+//! tunnel over ESP: each exchanged key yields a pair of AES-GCM keys, one
+//! for each direction, which are installed as XFRM states through `ip`, with
+//! no IKE daemon involved. Policies are left alone; they do not change with
+//! the keys and are set up like for any manually keyed tunnel.
+//!
+//! ```toml
+//! [[peers]]
+//! public_key = "peer.rosenpass-public/pqpk"
+//!
+//! [peers.xfrm]
+//! local = "192.0.2.1"
+//! remote = "198.51.100.2"
+//! spi_out = 0x1001
+//! spi_in = 0x1002
+//! ```
+//!
+//! The key of a security association is exported under a label naming its
+//! SPI, see [crate::exporter], so the other end needs the two SPIs the other
+//! way round. `mode` is `tunnel` or `transport`, and `reqid` ties the states
+//! to the policies using them. `netns` runs `ip` in another network
+//! namespace, see [crate::netns].
+//!
+//! IKE-less setups managing the states themselves set `command` instead,
+//! which is run for every key and given a line for each direction on stdin:
+//!
+//! ```text
+//! out 192.0.2.1 198.51.100.2 0x00001001 rfc4106(gcm(aes)) 0x<key and salt>
+//! in 198.51.100.2 192.0.2.1 0x00001002 rfc4106(gcm(aes)) 0x<key and salt>
+//! ```
+//!
+//! Stale keys are replaced by random ones, which stops the traffic just like
+//! it does for WireGuard. Keys are never put on the command line, where
+//! other users could see them.
+
+use anyhow::{ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::Write as _,
+    io::Write,
+    net::IpAddr,
+    process::{Command, Stdio},
+};
+
+use crate::{consumer::KeyConsumer, netns, protocol::SymKey};
+
+/// The AEAD of the security associations, as the kernel names it
+pub const AEAD: &str = "rfc4106(gcm(aes))";
+
+/// Length of the key of [AEAD] in bytes, followed by [SALT_LEN] bytes of salt
+pub const KEY_LEN: usize = 32;
+pub const SALT_LEN: usize = 4;
+
+/// Length of the authentication tag of [AEAD] in bits
+pub const ICV_BITS: u32 = 128;
+
+/// SPIs below this are reserved
+pub const MIN_SPI: u32 = 256;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    #[default]
+    Tunnel,
+    Transport,
+}
+
+impl Mode {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Mode::Tunnel => "tunnel",
+            Mode::Transport => "transport",
+        }
+    }
+}
+
+/// The IPsec tunnel keyed with the keys of a peer
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Xfrm {
+    /// Address of this end of the tunnel
+    pub local: IpAddr,
+    /// Address of the other end
+    pub remote: IpAddr,
+    /// SPI of the traffic sent to the other end
+    pub spi_out: u32,
+    /// SPI of the traffic received from it
+    pub spi_in: u32,
+    #[serde(default)]
+    pub reqid: u32,
+    #[serde(default)]
+    pub mode: Mode,
+    #[serde(default)]
+    pub netns: Option<String>,
+    /// Hand the keys to this command instead of installing them
+    #[serde(default)]
+    pub command: Vec<String>,
+}
+
+/// A security association, in one direction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Sa {
+    dir: &'static str,
+    src: IpAddr,
+    dst: IpAddr,
+    spi: u32,
+}
+
+/// Label the key of the security association with `spi` is exported under
+pub fn key_label(spi: u32) -> String {
+    format!("rosenpass.eu/xfrm v1 esp spi {spi:#010x}")
+}
+
+/// Label the salt of the security association with `spi` is exported under
+pub fn salt_label(spi: u32) -> String {
+    format!("{} salt", key_label(spi))
+}
+
+impl Xfrm {
+    pub fn validate(&self) -> Result<()> {
+        ensure!(
+            self.local.is_ipv4() == self.remote.is_ipv4(),
+            "xfrm local and remote addresses are of different families"
+        );
+        ensure!(
+            self.spi_out >= MIN_SPI && self.spi_in >= MIN_SPI,
+            "xfrm SPIs below {MIN_SPI} are reserved"
+        );
+        ensure!(
+            self.spi_out != self.spi_in,
+            "xfrm spi_out and spi_in must differ"
+        );
+        if let Some(name) = self.netns.as_ref() {
+            netns::validate_name(name)?;
+        }
+        Ok(())
+    }
+
+    fn sas(&self) -> [Sa; 2] {
+        [
+            Sa {
+                dir: "out",
+                src: self.local,
+                dst: self.remote,
+                spi: self.spi_out,
+            },
+            Sa {
+                dir: "in",
+                src: self.remote,
+                dst: self.local,
+                spi: self.spi_in,
+            },
+        ]
+    }
+
+    /// The key and salt of each of [Xfrm::sas] in hex, from the keys exported
+    /// under [KeyConsumer::labels]
+    fn keys(exported: &[SymKey]) -> Vec<String> {
+        exported
+            .chunks(2)
+            .map(|pair| {
+                let (key, salt) = (pair[0].secret(), &pair[1].secret()[..SALT_LEN]);
+                let mut hex = "0x".to_string();
+                for b in key[..KEY_LEN].iter().chain(salt) {
+                    write!(hex, "{b:02x}").unwrap();
+                }
+                hex
+            })
+            .collect()
+    }
+
+    /// Commands for `ip -batch` setting `keys`, adding the states in `missing`
+    /// and updating the others
+    fn batch(&self, keys: &[String], missing: &[bool]) -> String {
+        let mut batch = String::new();
+        for ((sa, key), missing) in self.sas().iter().zip(keys).zip(missing) {
+            let verb = match missing {
+                true => "add",
+                false => "update",
+            };
+            writeln!(
+                batch,
+                "xfrm state {verb} src {} dst {} proto esp spi {:#010x} reqid {} mode {} aead {AEAD} {key} {ICV_BITS}",
+                sa.src, sa.dst, sa.spi, self.reqid, self.mode.as_str()
+            )
+            .unwrap();
+        }
+        batch
+    }
+
+    fn ip(&self) -> Command {
+        let mut cmd = Command::new("ip");
+        cmd.stderr(Stdio::null());
+        cmd
+    }
+
+    /// The state of `sa` as `ip` lists it, if there is one
+    fn state(&self, sa: &Sa) -> Result<Option<String>> {
+        let out = self
+            .ip()
+            .args(["xfrm", "state", "get", "src", &sa.src.to_string()])
+            .args(["dst", &sa.dst.to_string(), "proto", "esp", "spi"])
+            .arg(format!("{:#010x}", sa.spi))
+            .output()
+            .context("could not run ip")?;
+        Ok(out
+            .status
+            .success()
+            .then(|| String::from_utf8_lossy(&out.stdout).into_owned()))
+    }
+
+    fn install_states(&self, keys: &[String]) -> Result<()> {
+        let sas = self.sas();
+        let missing: Vec<bool> = sas
+            .iter()
+            .map(|sa| self.state(sa).map(|s| s.is_none()))
+            .collect::<Result<_>>()?;
+        let mut child = self
+            .ip()
+            .args(["-batch", "-"])
+            .stdin(Stdio::piped())
+            .spawn()
+            .context("could not run ip")?;
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(self.batch(keys, &missing).as_bytes())?;
+        let status = child.wait()?;
+        ensure!(status.success(), "ip xfrm state failed with {status}");
+
+        for (sa, key) in sas.iter().zip(keys) {
+            let applied = self.state(sa)?.is_some_and(|s| s.contains(key.as_str()));
+            ensure!(
+                applied,
+                "the {} state with spi {:#010x} has a different key after setting it",
+                sa.dir,
+                sa.spi
+            );
+        }
+        Ok(())
+    }
+
+    fn run_command(&self, keys: &[String]) -> Result<()> {
+        let mut lines = String::new();
+        for (sa, key) in self.sas().iter().zip(keys) {
+            writeln!(
+                lines,
+                "{} {} {} {:#010x} {AEAD} {key}",
+                sa.dir, sa.src, sa.dst, sa.spi
+            )
+            .unwrap();
+        }
+        let mut child = Command::new(&self.command[0])
+            .args(&self.command[1..])
+            .stdin(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("could not run {:?}", self.command[0]))?;
+        child.stdin.take().unwrap().write_all(lines.as_bytes())?;
+        let status = child.wait()?;
+        ensure!(
+            status.success(),
+            "{:?} failed with {status}",
+            self.command[0]
+        );
+        Ok(())
+    }
+}
+
+impl KeyConsumer for Xfrm {
+    fn target(&self) -> String {
+        format!(
+            "the IPsec tunnel from {} to {} (spi {:#010x})",
+            self.local, self.remote, self.spi_out
+        )
+    }
+
+    fn labels(&self) -> Vec<String> {
+        self.sas()
+            .iter()
+            .flat_map(|sa| [key_label(sa.spi), salt_label(sa.spi)])
+            .collect()
+    }
+
+    fn install(&self, _key: &SymKey, exported: &[SymKey]) -> Result<()> {
+        // like the key file, these copies of the keys are not erased
+        let keys = Self::keys(exported);
+        netns::within(self.netns.as_deref(), || match self.command.is_empty() {
+            true => self.install_states(&keys),
+            false => self.run_command(&keys),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn xfrm() -> Xfrm {
+        Xfrm {
+            local: "192.0.2.1".parse().unwrap(),
+            remote: "198.51.100.2".parse().unwrap(),
+            spi_out: 0x1001,
+            spi_in: 0x1002,
+            reqid: 7,
+            mode: Mode::Tunnel,
+            netns: None,
+            command: vec![],
+        }
+    }
+
+    #[test]
+    fn both_ends_agree() {
+        let (a, mut b) = (xfrm(), xfrm());
+        (b.local, b.remote) = (a.remote, a.local);
+        (b.spi_out, b.spi_in) = (a.spi_in, a.spi_out);
+        let (mut la, mut lb) = (a.labels(), b.labels());
+        assert_eq!(la.len(), 4);
+        la.sort();
+        lb.sort();
+        assert_eq!(la, lb);
+
+        b.spi_in = a.spi_in;
+        assert!(b.validate().is_err());
+        assert!(a.validate().is_ok());
+    }
+
+    #[test]
+    fn states_are_batched() {
+        let mut exported = Vec::new();
+        for i in 0..4u8 {
+            let mut k = SymKey::zero();
+            k.secret_mut().fill(i);
+            exported.push(k);
+        }
+        let keys = Xfrm::keys(&exported);
+        assert_eq!(keys[1], format!("0x{}{}", "02".repeat(32), "03".repeat(4)));
+
+        let batch = xfrm().batch(&keys, &[true, false]);
+        let lines: Vec<&str> = batch.lines().collect();
+        assert_eq!(
+            lines[0],
+            format!(
+                "xfrm state add src 192.0.2.1 dst 198.51.100.2 proto esp spi 0x00001001 \
+                 reqid 7 mode tunnel aead rfc4106(gcm(aes)) {} 128",
+                keys[0]
+            )
+        );
+        assert!(lines[1].starts_with(
+            "xfrm state update src 198.51.100.2 dst 192.0.2.1 proto esp spi 0x00001002"
+        ));
+    }
+}
@@ -0,0 +1,224 @@
+//! FIPS-constrained mode is process wide, so these tests get a process of
+//! their own, running in that mode
+
+use std::{
+    collections::HashSet,
+    fs,
+    net::UdpSocket,
+    path::{Path, PathBuf},
+    process::{Child, Stdio},
+    time::Duration,
+};
+
+use rosenpass::{
+    labeled_prf,
+    prftree::PrfTree,
+    protocol::{BiscuitKeyPtr, XAEADNonce},
+};
+use rosenpass_ciphers::{fips, xaead};
+use rosenpass_sodium::aead::aes256gcm;
+
+const BIN: &str = "rosenpass";
+
+fn fips_mode() -> bool {
+    rosenpass_sodium::init().unwrap();
+    if !aes256gcm::is_available() {
+        eprintln!("skipping, this CPU does not support AES-256-GCM");
+        return false;
+    }
+    fips::select(true).unwrap();
+    true
+}
+
+// check that AES-256-GCM decrypts what it encrypted, and nothing else
+#[test]
+fn aes256gcm_round_trip() {
+    if !fips_mode() {
+        return;
+    }
+    let (key, nonce) = ([7u8; aes256gcm::KEY_LEN], [9u8; aes256gcm::NONCE_LEN]);
+    let (ad, plain) = (b"header", b"attack at dawn");
+    let mut ct = [0u8; 14 + aes256gcm::TAG_LEN];
+    aes256gcm::encrypt(&mut ct, &key, &nonce, ad, plain).unwrap();
+    assert_ne!(&ct[..plain.len()], plain);
+
+    let mut out = [0u8; 14];
+    aes256gcm::decrypt(&mut out, &key, &nonce, ad, &ct).unwrap();
+    assert_eq!(&out, plain);
+
+    for i in 0..ct.len() {
+        let mut bad = ct;
+        bad[i] ^= 1;
+        assert!(aes256gcm::decrypt(&mut out, &key, &nonce, ad, &bad).is_err());
+    }
+    let mut other = nonce;
+    other[0] ^= 1;
+    assert!(aes256gcm::decrypt(&mut out, &key, &other, ad, &ct).is_err());
+    assert!(aes256gcm::decrypt(&mut out, &key, &nonce, b"Header", &ct).is_err());
+    assert!(aes256gcm::decrypt(&mut out, &[8u8; 32], &nonce, ad, &ct).is_err());
+}
+
+// check that the xaead of FIPS-constrained mode authenticates every byte,
+// including those of the nonce AES-256-GCM does not take
+#[test]
+fn xaead_round_trip() {
+    if !fips_mode() {
+        return;
+    }
+    let key = [3u8; xaead::KEY_LEN];
+    let nonce = XAEADNonce::random();
+    let (ad, plain) = (b"biscuit", [5u8; 40]);
+    let mut ct = [0u8; xaead::NONCE_LEN + 40 + xaead::TAG_LEN];
+    xaead::encrypt(&mut ct, &key, &*nonce, ad, &plain).unwrap();
+    assert_eq!(&ct[..xaead::NONCE_LEN], &*nonce);
+
+    let mut out = [0u8; 40];
+    xaead::decrypt(&mut out, &key, ad, &ct).unwrap();
+    assert_eq!(out, plain);
+
+    for i in 0..ct.len() {
+        let mut bad = ct;
+        bad[i] ^= 0x80;
+        assert!(
+            xaead::decrypt(&mut out, &key, ad, &bad).is_err(),
+            "flipping byte {i} went unnoticed"
+        );
+    }
+    assert!(xaead::decrypt(&mut out, &key, b"Biscuit", &ct).is_err());
+}
+
+// check that the 96 bits of the nonce AES-256-GCM takes stay random, even
+// with the biscuit key marked in the nonce
+#[test]
+fn xaead_nonces_stay_unique() {
+    if !fips_mode() {
+        return;
+    }
+    let mut seen = HashSet::new();
+    for i in 0..10_000 {
+        let mut nonce = XAEADNonce::random();
+        let before = xaead::split_nonce(&*nonce).1.to_vec();
+        BiscuitKeyPtr(i % 2).mark_nonce(&mut *nonce);
+        let (_, gcm_nonce) = xaead::split_nonce(&*nonce);
+        assert_eq!(gcm_nonce, before);
+        assert_eq!(gcm_nonce.len(), aes256gcm::NONCE_LEN);
+        assert!(seen.insert(gcm_nonce.to_vec()));
+    }
+}
+
+// check that peers in FIPS-constrained mode use a protocol of their own
+#[test]
+fn protocol_label_differs() {
+    if !fips_mode() {
+        return;
+    }
+    let classic = "Rosenpass v1 mceliece460896 Kyber512 ChaChaPoly1305 BLAKE2s";
+    let classic = PrfTree::zero().mix(classic.as_bytes()).unwrap();
+    assert_ne!(
+        labeled_prf::protocol().unwrap().into_value(),
+        classic.into_value()
+    );
+}
+
+fn find_udp_port() -> u16 {
+    (1025..=u16::MAX)
+        .find(|&port| UdpSocket::bind(("127.0.0.1", port)).is_ok())
+        .expect("no free UDP port found")
+}
+
+fn gen_keys(dir: &Path, name: &str) -> (PathBuf, PathBuf) {
+    let (sk, pk) = (
+        dir.join(format!("{name}.sk")),
+        dir.join(format!("{name}.pk")),
+    );
+    let status = test_bin::get_test_bin(BIN)
+        .args(["gen-keys", "--secret-key"])
+        .arg(&sk)
+        .arg("--public-key")
+        .arg(&pk)
+        .status()
+        .expect("Failed to start {BIN}");
+    assert!(status.success());
+    (sk, pk)
+}
+
+/// Start exchanging keys as `me` with `peer`, listening on `port` unless it
+/// is the peer who listens
+#[allow(clippy::too_many_arguments)]
+fn exchange(
+    dir: &Path,
+    name: &str,
+    me: &(PathBuf, PathBuf),
+    peer: &(PathBuf, PathBuf),
+    port: u16,
+    listen: bool,
+    fips: bool,
+) -> Child {
+    let (listen, endpoint) = match listen {
+        true => (format!("listen = [\"127.0.0.1:{port}\"]"), String::new()),
+        false => (
+            "listen = []".to_string(),
+            format!("endpoint = \"127.0.0.1:{port}\""),
+        ),
+    };
+    let config = dir.join(format!("{name}.toml"));
+    fs::write(
+        &config,
+        format!(
+            "public_key = {:?}\nsecret_key = {:?}\n{listen}\nverbosity = \"Verbose\"\n\
+             fips = {fips}\n[[peers]]\npublic_key = {:?}\nkey_out = {:?}\n{endpoint}\n",
+            me.1,
+            me.0,
+            peer.1,
+            dir.join(format!("{name}.key")),
+        ),
+    )
+    .unwrap();
+    test_bin::get_test_bin(BIN)
+        .arg("exchange-config")
+        .arg(&config)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("Failed to start {BIN}")
+}
+
+// check that FIPS peers exchange keys with each other, but not with others,
+// which neither side crashes over
+#[test]
+fn fips_peers_only_talk_to_each_other() {
+    if !fips_mode() {
+        return;
+    }
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("fips-exchange");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let (a, b) = (gen_keys(&dir, "a"), gen_keys(&dir, "b"));
+
+    let run = |b_fips: bool| {
+        let port = find_udp_port();
+        let mut server = exchange(&dir, "a", &a, &b, port, true, true);
+        std::thread::sleep(Duration::from_millis(500));
+        let mut client = exchange(&dir, "b", &b, &a, port, false, b_fips);
+        std::thread::sleep(Duration::from_secs(3));
+        let running = [&mut server, &mut client].map(|c| c.try_wait().unwrap().is_none());
+        server.kill().unwrap();
+        client.kill().unwrap();
+        let keys = ["a", "b"].map(|n| fs::read_to_string(dir.join(format!("{n}.key"))).ok());
+        for n in ["a", "b"] {
+            let _ = fs::remove_file(dir.join(format!("{n}.key")));
+        }
+        (running, keys)
+    };
+
+    let (running, [ka, kb]) = run(true);
+    assert_eq!(running, [true, true]);
+    assert!(ka.is_some());
+    assert_eq!(ka, kb);
+
+    let (running, keys) = run(false);
+    assert_eq!(running, [true, true]);
+    assert_eq!(keys, [None, None]);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
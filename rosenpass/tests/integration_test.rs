@@ -28,6 +28,150 @@ fn generate_keys() {
     fs::remove_dir_all(&tmpdir).unwrap();
 }
 
+// check that the version can be told apart in text and as JSON
+#[test]
+fn version_report() {
+    let output = test_bin::get_test_bin(BIN)
+        .arg("--version")
+        .output()
+        .expect("Failed to start {BIN}");
+    let text = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(
+        text.trim(),
+        format!("rosenpass {}", env!("CARGO_PKG_VERSION"))
+    );
+
+    let output = test_bin::get_test_bin(BIN)
+        .args(["--version", "--json"])
+        .output()
+        .expect("Failed to start {BIN}");
+    assert!(output.status.success());
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(report["version"], env!("CARGO_PKG_VERSION"));
+    assert_eq!(report["crypto"]["kems"]["ephemeral"], "Kyber512");
+    assert!(report["protocol"]["versions"][0] == 1);
+
+    // a command is still needed otherwise
+    let status = test_bin::get_test_bin(BIN).arg("--json").status().unwrap();
+    assert_eq!(status.code(), Some(2));
+}
+
+// check that key pairs are verified and mixed up key files are detected
+#[test]
+fn verify_keys() {
+    let tmpdir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("verify-keys");
+    fs::create_dir_all(&tmpdir).unwrap();
+
+    let secret_key_paths = [tmpdir.join("secret-key-0"), tmpdir.join("secret-key-1")];
+    let public_key_paths = [tmpdir.join("public-key-0"), tmpdir.join("public-key-1")];
+    for (sk, pk) in secret_key_paths.iter().zip(public_key_paths.iter()) {
+        let status = test_bin::get_test_bin(BIN)
+            .args(["gen-keys", "--secret-key"])
+            .arg(sk)
+            .arg("--public-key")
+            .arg(pk)
+            .status()
+            .expect("Failed to start {BIN}");
+        assert!(status.success());
+    }
+
+    let verify = |sk: &PathBuf, pk: &PathBuf| {
+        test_bin::get_test_bin(BIN)
+            .args(["verify-keys", "--secret-key"])
+            .arg(sk)
+            .arg("--public-key")
+            .arg(pk)
+            .stderr(Stdio::null())
+            .status()
+            .expect("Failed to start {BIN}")
+            .success()
+    };
+    assert!(verify(&secret_key_paths[0], &public_key_paths[0]));
+    assert!(!verify(&secret_key_paths[0], &public_key_paths[1]));
+    assert!(!verify(&public_key_paths[0], &secret_key_paths[0]));
+
+    // cleanup
+    fs::remove_dir_all(&tmpdir).unwrap();
+}
+
+// check that keys derived from a master seed depend on the seed and label only
+#[test]
+fn seeded_keys() {
+    let tmpdir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("seeded-keys");
+    fs::create_dir_all(&tmpdir).unwrap();
+
+    let seed = tmpdir.join("master-seed");
+    fs::write(&seed, "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=\n").unwrap();
+
+    let gen = |name: &str, label: &str| {
+        let (sk, pk) = (
+            tmpdir.join(format!("{name}.sk")),
+            tmpdir.join(format!("{name}.pk")),
+        );
+        let status = test_bin::get_test_bin(BIN)
+            .args(["gen-keys", "--from-seed"])
+            .arg(&seed)
+            .args(["--label", label, "--secret-key"])
+            .arg(&sk)
+            .arg("--public-key")
+            .arg(&pk)
+            .status()
+            .expect("Failed to start {BIN}");
+        assert!(status.success());
+        (fs::read(sk).unwrap(), fs::read(pk).unwrap())
+    };
+    let a = gen("a", "device 1");
+    assert!(a == gen("b", "device 1"));
+    assert!(a.1 != gen("c", "device 2").1);
+
+    // cleanup
+    fs::remove_dir_all(&tmpdir).unwrap();
+}
+
+// check that secret-key files stay as every release reads them, and that the
+// public key can be derived again from the seed file kept along
+#[test]
+fn public_key_from_seed_file() {
+    use rosenpass::pqkem::{StaticKEM, KEM};
+
+    let tmpdir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("pubkey");
+    fs::create_dir_all(&tmpdir).unwrap();
+    let (sk, pk, seed) = (
+        tmpdir.join("secret-key"),
+        tmpdir.join("public-key"),
+        tmpdir.join("seed"),
+    );
+
+    let status = test_bin::get_test_bin(BIN)
+        .args(["gen-keys", "--force", "--secret-key"])
+        .arg(&sk)
+        .arg("--public-key")
+        .arg(&pk)
+        .arg("--seed-file")
+        .arg(&seed)
+        .status()
+        .expect("Failed to start {BIN}");
+    assert!(status.success());
+    assert_eq!(fs::metadata(&sk).unwrap().len() as usize, StaticKEM::SK_LEN);
+
+    let pubkey = |seed_file: Option<&PathBuf>| {
+        let mut cmd = test_bin::get_test_bin(BIN);
+        cmd.arg("pubkey").arg(&sk);
+        if let Some(f) = seed_file {
+            cmd.arg("--seed-file").arg(f);
+        }
+        cmd.stderr(Stdio::null()).output().unwrap()
+    };
+    let derived = pubkey(Some(&seed));
+    assert!(derived.status.success());
+    assert!(derived.stdout == fs::read(&pk).unwrap());
+    assert!(!pubkey(None).status.success());
+    assert!(!pubkey(Some(&pk)).status.success());
+
+    // cleanup
+    fs::remove_dir_all(&tmpdir).unwrap();
+}
+
 fn find_udp_socket() -> u16 {
     for port in 1025..=u16::MAX {
         match UdpSocket::bind(("127.0.0.1", port)) {
@@ -43,7 +187,27 @@ fn find_udp_socket() -> u16 {
 // check that we can exchange keys
 #[test]
 fn check_exchange() {
-    let tmpdir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("exchange");
+    let port = find_udp_socket();
+    let listen_addr = format!("localhost:{port}");
+    exchange_keys("exchange", &listen_addr, &listen_addr);
+}
+
+// check that we can exchange keys over QUIC
+#[cfg(feature = "quic")]
+#[test]
+fn check_exchange_over_quic() {
+    let port = find_udp_socket();
+    exchange_keys(
+        "exchange-quic",
+        &format!("quic:127.0.0.1:{port}"),
+        &format!("quic:localhost:{port}"),
+    );
+}
+
+/// Let a server listening on `listen_addr` and a client with the endpoint
+/// `endpoint` exchange keys in the directory `name`
+fn exchange_keys(name: &str, listen_addr: &str, endpoint: &str) {
+    let tmpdir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(name);
     fs::create_dir_all(&tmpdir).unwrap();
 
     let secret_key_paths = [tmpdir.join("secret-key-0"), tmpdir.join("secret-key-1")];
@@ -66,14 +230,12 @@ fn check_exchange() {
     }
 
     // start first process, the server
-    let port = find_udp_socket();
-    let listen_addr = format!("localhost:{port}");
     let mut server = test_bin::get_test_bin(BIN)
         .args(["exchange", "secret-key"])
         .arg(&secret_key_paths[0])
         .arg("public-key")
         .arg(&public_key_paths[0])
-        .args(["listen", &listen_addr, "verbose", "peer", "public-key"])
+        .args(["listen", listen_addr, "verbose", "peer", "public-key"])
         .arg(&public_key_paths[1])
         .arg("outfile")
         .arg(&shared_key_paths[0])
@@ -92,7 +254,7 @@ fn check_exchange() {
         .arg(&public_key_paths[1])
         .args(["verbose", "peer", "public-key"])
         .arg(&public_key_paths[0])
-        .args(["endpoint", &listen_addr])
+        .args(["endpoint", endpoint])
         .arg("outfile")
         .arg(&shared_key_paths[1])
         .stdout(Stdio::null())
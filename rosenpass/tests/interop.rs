@@ -0,0 +1,119 @@
+//! Key exchange between this build and a previous release
+//!
+//! The test is ignored by default; run it with
+//! `ROSENPASS_PREVIOUS=<rosenpass binary of the release> cargo test --test interop -- --ignored`.
+//! Both builds take turns as the responder, and the keys they output must be
+//! the same, which fails as soon as the wire format or the key derivation
+//! changed. Each build runs with the key files the other one generated, so
+//! changes to the key file format are caught as well. Only the `exchange`
+//! arguments every release understands are used.
+
+use std::{
+    ffi::OsString,
+    fs,
+    net::UdpSocket,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    time::{Duration, Instant},
+};
+
+const BIN: &str = "rosenpass";
+
+/// How long to wait for both keys
+const TIMEOUT: Duration = Duration::from_secs(20);
+
+fn find_udp_port() -> u16 {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.local_addr().unwrap().port()
+}
+
+fn command(bin: &Option<OsString>) -> Command {
+    match bin {
+        Some(bin) => Command::new(bin),
+        None => test_bin::get_test_bin(BIN),
+    }
+}
+
+fn gen_keys(bin: &Option<OsString>, sk: &Path, pk: &Path) {
+    let status = command(bin)
+        .args(["gen-keys", "--secret-key"])
+        .arg(sk)
+        .arg("--public-key")
+        .arg(pk)
+        .status()
+        .expect("Failed to start rosenpass");
+    assert!(status.success(), "{bin:?} could not generate keys");
+}
+
+/// Exchange a key between `responder` and `initiator`, `None` standing for
+/// this build; returns the keys both of them wrote
+///
+/// The responder's keys are generated by the initiator and the other way
+/// round.
+fn exchange(dir: &Path, responder: &Option<OsString>, initiator: &Option<OsString>) -> [String; 2] {
+    fs::create_dir_all(dir).unwrap();
+    let sk = [dir.join("secret-key-0"), dir.join("secret-key-1")];
+    let pk = [dir.join("public-key-0"), dir.join("public-key-1")];
+    let osk = [dir.join("shared-key-0"), dir.join("shared-key-1")];
+    gen_keys(initiator, &sk[0], &pk[0]);
+    gen_keys(responder, &sk[1], &pk[1]);
+
+    let addr = format!("127.0.0.1:{}", find_udp_port());
+    let mut children = Vec::new();
+    for (i, bin) in [responder, initiator].into_iter().enumerate() {
+        let mut cmd = command(bin);
+        cmd.args(["exchange", "secret-key"])
+            .arg(&sk[i])
+            .arg("public-key")
+            .arg(&pk[i]);
+        match i {
+            0 => cmd.args(["listen", &addr, "peer", "public-key"]),
+            _ => cmd.args(["peer", "public-key"]),
+        };
+        cmd.arg(&pk[1 - i]);
+        if i == 1 {
+            cmd.args(["endpoint", &addr]);
+        }
+        let child = cmd
+            .arg("outfile")
+            .arg(&osk[i])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Failed to start rosenpass");
+        children.push(child);
+    }
+
+    let start = Instant::now();
+    while !osk.iter().all(|f| f.is_file()) && start.elapsed() < TIMEOUT {
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    // a key file may exist before it is written completely
+    std::thread::sleep(Duration::from_millis(200));
+    for mut child in children {
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+
+    let keys = osk.map(|f| {
+        fs::read_to_string(&f).unwrap_or_else(|_| panic!("no key was exchanged, {f:?} is missing"))
+    });
+    fs::remove_dir_all(dir).unwrap();
+    keys
+}
+
+#[test]
+#[ignore = "needs ROSENPASS_PREVIOUS, the binary of a previous release"]
+fn previous_release() {
+    let previous = std::env::var_os("ROSENPASS_PREVIOUS");
+    assert!(
+        previous.is_some(),
+        "set ROSENPASS_PREVIOUS to the rosenpass binary to test against"
+    );
+    let tmpdir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("interop");
+
+    let [a, b] = exchange(&tmpdir.join("previous-responds"), &previous, &None);
+    assert_eq!(a, b, "keys differ with the previous release responding");
+    let [a, b] = exchange(&tmpdir.join("current-responds"), &None, &previous);
+    assert_eq!(a, b, "keys differ with this build responding");
+}
@@ -1,2 +1,3 @@
+pub mod aes256gcm;
 pub mod chacha20poly1305_ietf;
 pub mod xchacha20poly1305_ietf;
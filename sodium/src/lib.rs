@@ -15,5 +15,11 @@ pub fn init() -> anyhow::Result<()> {
     sodium_call!(sodium_init)
 }
 
+/// Version of the libsodium in use
+pub fn version() -> &'static str {
+    let version = unsafe { std::ffi::CStr::from_ptr(libsodium::sodium_version_string()) };
+    version.to_str().unwrap_or("unknown")
+}
+
 pub mod aead;
 pub mod helpers;
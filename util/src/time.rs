@@ -1,20 +1,109 @@
-use std::time::{Duration, Instant};
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
+/// Source of the time used for all timers
+///
+/// On Linux, time is taken from `CLOCK_BOOTTIME`, which keeps counting while
+/// the system is suspended, so keys and handshakes age during a suspend just
+/// like they do on the peer's side. Elsewhere, [Instant] is used. Tests can
+/// use a [ManualClock] instead.
 #[derive(Clone, Debug)]
-pub struct Timebase(Instant);
+pub struct Timebase {
+    boot: f64,
+    mono: Instant,
+    manual: Option<ManualClock>,
+}
+
+/// A clock which only moves when told to, shared by all its clones
+#[derive(Clone, Debug, Default)]
+pub struct ManualClock(Arc<Mutex<f64>>);
+
+impl ManualClock {
+    pub fn now(&self) -> f64 {
+        *self.0.lock().unwrap()
+    }
+
+    /// Move the clock forward by `secs` seconds
+    pub fn advance(&self, secs: f64) {
+        *self.0.lock().unwrap() += secs.max(0.0);
+    }
+}
 
 impl Default for Timebase {
     fn default() -> Self {
-        Self(Instant::now())
+        Self {
+            boot: boottime(),
+            mono: Instant::now(),
+            manual: None,
+        }
     }
 }
 
 impl Timebase {
+    /// A timebase following `clock`, on which the system is never suspended
+    /// and the wall clock never set
+    pub fn manual(clock: ManualClock) -> Self {
+        Self {
+            // the offset of the wall clock, fixed at creation
+            boot: wall_clock() - clock.now(),
+            mono: Instant::now(),
+            manual: Some(clock),
+        }
+    }
+
     pub fn now(&self) -> f64 {
-        self.0.elapsed().as_secs_f64()
+        match self.manual.as_ref() {
+            Some(clock) => clock.now(),
+            None => boottime() - self.boot,
+        }
     }
 
     pub fn dur(&self, t: f64) -> Duration {
         Duration::from_secs_f64(t)
     }
+
+    /// Seconds the system spent suspended since the timebase was created;
+    /// always zero where this can not be told
+    pub fn suspended(&self) -> f64 {
+        match cfg!(target_os = "linux") && self.manual.is_none() {
+            true => (self.now() - self.mono.elapsed().as_secs_f64()).max(0.0),
+            false => 0.0,
+        }
+    }
+
+    /// Seconds between the Unix epoch on the wall clock and the creation of
+    /// the timebase; changes whenever the wall clock is set
+    pub fn wall_clock_offset(&self) -> f64 {
+        match self.manual {
+            Some(_) => self.boot,
+            None => wall_clock() - self.now(),
+        }
+    }
+}
+
+fn wall_clock() -> f64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs_f64(),
+        Err(e) => -e.duration().as_secs_f64(),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn boottime() -> f64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // can only fail for invalid clocks or pointers
+    unsafe { libc::clock_gettime(libc::CLOCK_BOOTTIME, &mut ts) };
+    ts.tv_sec as f64 + ts.tv_nsec as f64 * 1e-9
+}
+
+#[cfg(not(target_os = "linux"))]
+fn boottime() -> f64 {
+    use std::sync::OnceLock;
+    static ORIGIN: OnceLock<Instant> = OnceLock::new();
+    ORIGIN.get_or_init(Instant::now).elapsed().as_secs_f64()
 }